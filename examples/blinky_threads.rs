@@ -0,0 +1,89 @@
+//! Two threads blink two LEDs at different rates; a third waits for both.
+//!
+//! Exercises `spawn`, `sleep` and `JoinHandle::join`. On the Pi Zero 2 W
+//! the green activity LED (GPIO 29) and an LED on GPIO 17 blink; QEMU has
+//! no GPIO, so under `qemu-virt` the LED states are printed instead.
+//!
+//! # Running in QEMU
+//!
+//! ```bash
+//! make run-example EXAMPLE=blinky_threads
+//! ```
+//!
+//! # Building for the Pi
+//!
+//! ```bash
+//! cargo +nightly build --release --example blinky_threads --target aarch64-unknown-none
+//! rust-objcopy -O binary target/aarch64-unknown-none/release/examples/blinky_threads kernel8.img
+//! ```
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+mod common;
+
+use preemptive_threads::{
+    arch::DefaultArch,
+    pl011_println,
+    sched::priority,
+    time::Duration,
+    Kernel, RoundRobinScheduler,
+};
+use spin::Lazy;
+
+static KERNEL: Lazy<Kernel<DefaultArch, RoundRobinScheduler>> =
+    Lazy::new(|| Kernel::new(RoundRobinScheduler::new(1)));
+
+/// Activity LED on the Pi Zero 2 W
+const ACT_LED: u32 = 29;
+const EXTERNAL_LED: u32 = 17;
+
+fn set_led(pin: u32, on: bool) {
+    #[cfg(not(feature = "qemu-virt"))]
+    preemptive_threads::drivers::gpio::write(pin, on).expect("bad LED pin");
+
+    #[cfg(feature = "qemu-virt")]
+    pl011_println!("[LED {:2}] {}", pin, if on { "on" } else { "off" });
+}
+
+/// Blink `pin` `times` times, `period` per blink.
+fn blink(pin: u32, period: Duration, times: u32) {
+    #[cfg(not(feature = "qemu-virt"))]
+    preemptive_threads::drivers::gpio::set_function(pin, preemptive_threads::drivers::gpio::Function::Output)
+        .expect("bad LED pin");
+
+    let half = Duration::from_nanos(period.as_nanos() / 2);
+    for _ in 0..times {
+        set_led(pin, true);
+        common::sleep(half);
+        set_led(pin, false);
+        common::sleep(half);
+    }
+}
+
+#[no_mangle]
+pub fn kernel_main() -> ! {
+    common::boot("blinky threads");
+
+    common::run(&KERNEL, || {
+        let fast = KERNEL
+            .spawn(|| blink(ACT_LED, Duration::from_millis(250), 20), priority::NORMAL)
+            .expect("spawn failed");
+        let slow = KERNEL
+            .spawn(|| blink(EXTERNAL_LED, Duration::from_millis(1000), 5), priority::NORMAL)
+            .expect("spawn failed");
+
+        KERNEL
+            .spawn(
+                move || {
+                    let fast = fast.join();
+                    let slow = slow.join();
+                    pl011_println!("[MAIN] blinkers done: fast {:?}, slow {:?}", fast, slow);
+                },
+                priority::LOW,
+            )
+            .expect("spawn failed");
+    })
+}
@@ -0,0 +1,133 @@
+//! Pieces the example kernels share: a heap over the linker script's heap
+//! region, `sleep`, and the boot sequence from `kernel_main` to the first
+//! thread.
+//!
+//! Each example pulls this in with `mod common;`. Cargo only builds files
+//! directly in `examples/` as examples, so this directory isn't one.
+
+#![allow(dead_code)]
+
+use preemptive_threads::arch::DefaultArch;
+use preemptive_threads::sync::{Selector, Timeout};
+use preemptive_threads::time::{Duration, Instant};
+use preemptive_threads::{pl011_println, Kernel, Scheduler};
+
+/// Power-of-two size classes over the heap the linker script reserves
+/// between `__heap_start` and `__heap_end`.
+///
+/// Freed blocks go on their class's free list and are reused by the next
+/// allocation of that class, so threads that are spawned and joined, or
+/// loops that allocate, run indefinitely. Fresh blocks are cut from the
+/// region front to back.
+mod heap {
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::ptr::null_mut;
+    use preemptive_threads::arch::{self, aarch64_boot};
+
+    /// Smallest class, 16 bytes
+    const MIN_SHIFT: usize = 4;
+    const CLASSES: usize = 28;
+
+    struct State {
+        next: usize,
+        free: [usize; CLASSES],
+    }
+
+    static STATE: spin::Mutex<State> = spin::Mutex::new(State { next: 0, free: [0; CLASSES] });
+
+    fn class(layout: Layout) -> usize {
+        let size = layout.size().max(layout.align()).max(1 << MIN_SHIFT);
+        size.next_power_of_two().trailing_zeros() as usize - MIN_SHIFT
+    }
+
+    pub struct ClassAllocator;
+
+    unsafe impl GlobalAlloc for ClassAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let class = class(layout);
+            if class >= CLASSES {
+                return null_mut();
+            }
+            let size = 1usize << (class + MIN_SHIFT);
+            arch::without_interrupts(|| {
+                let mut state = STATE.lock();
+                let head = state.free[class];
+                if head != 0 {
+                    // A free block stores the next one in its first word
+                    state.free[class] = unsafe { *(head as *const usize) };
+                    return head as *mut u8;
+                }
+                if state.next == 0 {
+                    state.next = aarch64_boot::heap_start();
+                }
+                // Blocks are aligned to their size, which covers `layout`
+                let start = (state.next + size - 1) & !(size - 1);
+                if start + size > aarch64_boot::heap_end() {
+                    return null_mut();
+                }
+                state.next = start + size;
+                start as *mut u8
+            })
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let class = class(layout);
+            arch::without_interrupts(|| {
+                let mut state = STATE.lock();
+                unsafe { *(ptr as *mut usize) = state.free[class] };
+                state.free[class] = ptr as usize;
+            });
+        }
+    }
+
+    #[cfg(not(feature = "leak-check"))]
+    #[global_allocator]
+    static ALLOCATOR: ClassAllocator = ClassAllocator;
+
+    // Counted for the kernel's leak checks
+    #[cfg(feature = "leak-check")]
+    #[global_allocator]
+    static ALLOCATOR: preemptive_threads::kernel::leak::CountingAlloc<ClassAllocator> =
+        preemptive_threads::kernel::leak::CountingAlloc::new(ClassAllocator);
+}
+
+/// Block the calling thread for `duration`.
+pub fn sleep(duration: Duration) {
+    sleep_until(Instant::now() + duration);
+}
+
+/// Block the calling thread until `deadline`.
+///
+/// Without the high-resolution timer (outside QEMU virt) the selector
+/// yields until the deadline passes instead of blocking.
+pub fn sleep_until(deadline: Instant) {
+    let timeout = Timeout::at(deadline);
+    let mut selector = Selector::new();
+    selector.add(&timeout);
+    selector.wait();
+}
+
+/// Bring up the UART and print the example's banner.
+pub fn boot(name: &str) {
+    unsafe { preemptive_threads::arch::uart_pl011::init() };
+    pl011_println!("");
+    pl011_println!("========================================");
+    pl011_println!("  preemptive-threads: {}", name);
+    pl011_println!("========================================");
+}
+
+/// Initialize `kernel` and its timers, then run its threads. Never returns.
+pub fn run<S: Scheduler + 'static>(kernel: &'static Kernel<DefaultArch, S>, spawn: impl FnOnce()) -> ! {
+    kernel.init().expect("kernel init failed");
+    unsafe { kernel.register_global() };
+
+    // Sleeping threads are woken by the virtual timer, routed through the
+    // GIC only QEMU virt brings up
+    #[cfg(feature = "qemu-virt")]
+    preemptive_threads::time::hrtimer::init().expect("hrtimer init failed");
+
+    spawn();
+    pl011_println!("[BOOT] starting threads");
+    kernel.start_first_thread().expect("failed to start threads");
+    unreachable!("start_first_thread returned");
+}
@@ -1,7 +1,8 @@
 //! Preemptive Multithreading Kernel for Raspberry Pi Zero 2 W
 //!
-//! This example demonstrates true preemptive multithreading on bare metal ARM64.
-//! Three threads run concurrently, switched automatically by timer interrupts.
+//! This example runs the first-come-first-serve scheduler cooperatively on
+//! bare metal ARM64: three threads take turns when they yield, with no
+//! preemption tick armed.
 //!
 //! # Quick Test (QEMU)
 //!
@@ -40,7 +41,7 @@ use preemptive_threads::{
     arch::DefaultArch,
     sched::FirstComeFirstServeScheduler,
     pl011_println,
-    Kernel,
+    Kernel, PreemptionMode,
 };
 use spin::Lazy;
 
@@ -104,7 +105,7 @@ mod allocator {
 
 /// The kernel instance.
 static KERNEL: Lazy<Kernel<DefaultArch, FirstComeFirstServeScheduler>> =
-    Lazy::new(|| Kernel::new(FirstComeFirstServeScheduler::new()));
+    Lazy::new(|| Kernel::new(FirstComeFirstServeScheduler::new()).with_preemption_mode(PreemptionMode::None));
 
 /// Kernel entry point - called from boot code after hardware init.
 #[no_mangle]
@@ -124,7 +125,11 @@ pub fn kernel_main() -> ! {
 
     // Initialize the kernel
     pl011_println!("[BOOT] Initializing kernel... [FIRST COME FIRST SERVE]");
-    KERNEL.init().expect("Failed to initialize kernel");
+    // Kernel::init would arm the preemption tick; run the other stages
+    // without it
+    KERNEL.init_memory().expect("Failed to initialize memory");
+    KERNEL.init_interrupts().expect("Failed to initialize interrupts");
+    KERNEL.init_scheduler().expect("Failed to initialize kernel");
     pl011_println!("[BOOT] Kernel initialized!");
 
     // Register kernel globally for interrupt handlers
@@ -194,14 +199,6 @@ pub fn kernel_main() -> ! {
         .expect("Failed to spawn thread 3");
     pl011_println!("[BOOT] 3 threads spawned!");
 
-    // this should be disabled here, when you enable it, the cpu tries to execute some garbage & brreaks the whole program
-    pl011_println!("[BOOT] Setting up preemption timer (1ms)...");
-    //unsafe {
-    //    preemptive_threads::arch::aarch64::setup_preemption_timer(1000)
-  //          .expect("Failed to setup timer");
-  //  }
-  //  pl011_println!("[BOOT] Timer configured!");
-
     // NOTE: Do NOT enable interrupts here - start_first_thread() handles that
     // after setting up the current thread. This prevents an IRQ from firing
     // before we have a thread context to save to.
@@ -213,7 +210,7 @@ pub fn kernel_main() -> ! {
 
     // Start running the first thread - this never returns
     // (also enables interrupts after setting up the thread context)
-    KERNEL.start_first_thread();
+    KERNEL.start_first_thread().expect("Failed to start scheduler");
 
     // If we somehow get here, halt
     pl011_println!("[ERROR] Scheduler returned unexpectedly!");
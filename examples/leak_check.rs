@@ -0,0 +1,106 @@
+//! On-target leak checks for the thread lifecycle.
+//!
+//! Runs each scenario a number of rounds between two snapshots of the
+//! kernel's resource counts (stacks, thread table, sleep queue, timers
+//! and heap) and reports PASS, or FAIL with the counts that grew. One
+//! round of each runs before its baseline, so first-use allocations
+//! don't count against it.
+//!
+//! # Running in QEMU
+//!
+//! ```bash
+//! make run-example EXAMPLE=leak_check EXAMPLE_FEATURES=leak-check
+//! ```
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+mod common;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use preemptive_threads::{
+    arch::DefaultArch,
+    kernel::sleep_for,
+    pl011_println,
+    sched::priority,
+    time::Duration,
+    Kernel, RoundRobinScheduler, ThreadBuilder,
+};
+use spin::Lazy;
+
+static KERNEL: Lazy<Kernel<DefaultArch, RoundRobinScheduler>> =
+    Lazy::new(|| Kernel::new(RoundRobinScheduler::new(1)));
+
+const ROUNDS: usize = 50;
+
+static FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+/// Spawn a thread that returns, and join it.
+fn exit_round() {
+    let handle = KERNEL.spawn(|| {}, priority::NORMAL).expect("spawn failed");
+    handle.join().expect("thread failed");
+}
+
+/// Spawn a thread below the caller's priority and kill it before it runs.
+fn kill_round() {
+    let handle = KERNEL.spawn(|| {}, priority::LOW).expect("spawn failed");
+    KERNEL.kill(handle.thread_id()).expect("kill failed");
+    assert!(handle.join().is_err());
+}
+
+/// Spawn a thread that sleeps through a few ticks, and join it.
+fn sleep_round() {
+    let handle = KERNEL
+        .spawn(|| sleep_for(Duration::from_millis(3)), priority::NORMAL)
+        .expect("spawn failed");
+    handle.join().expect("thread failed");
+}
+
+/// Spawn and join a batch of threads with a name and a heap allocation.
+fn batch_round() {
+    let config = ThreadBuilder::new().name("worker").priority(priority::NORMAL).validate().unwrap();
+    let handles = KERNEL
+        .spawn_batch(&config, 4, |i| move || drop(alloc::vec![i; 64]))
+        .expect("spawn failed");
+    for handle in handles {
+        handle.join().expect("thread failed");
+    }
+}
+
+fn scenario(name: &str, round: fn()) {
+    round();
+    let detector = KERNEL.leak_detector();
+    for _ in 0..ROUNDS {
+        round();
+    }
+    match KERNEL.check_leaks(&detector) {
+        Ok(()) => pl011_println!("[LEAK] {:<6} PASS", name),
+        Err(leaks) => {
+            FAILURES.fetch_add(1, Ordering::Relaxed);
+            pl011_println!("[LEAK] {:<6} FAIL: {}", name, leaks);
+        }
+    }
+}
+
+fn runner() {
+    scenario("exit", exit_round);
+    scenario("kill", kill_round);
+    scenario("sleep", sleep_round);
+    scenario("batch", batch_round);
+
+    match FAILURES.load(Ordering::Relaxed) {
+        0 => pl011_println!("[LEAK] all scenarios passed"),
+        n => pl011_println!("[LEAK] {} scenario(s) leaked", n),
+    }
+}
+
+#[no_mangle]
+pub fn kernel_main() -> ! {
+    common::boot("leak check");
+
+    common::run(&KERNEL, || {
+        KERNEL.spawn(runner, priority::NORMAL).expect("spawn failed");
+    })
+}
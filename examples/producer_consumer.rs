@@ -0,0 +1,97 @@
+//! Three producers feed one consumer through a bounded channel.
+//!
+//! The channel holds fewer items than the producers send, so producers
+//! block when it fills and the consumer blocks when it drains. The
+//! consumer keeps per-producer totals behind a mutex that a reporter
+//! thread reads; a coordinator joins every thread and checks the totals.
+//!
+//! # Running in QEMU
+//!
+//! ```bash
+//! make run-example EXAMPLE=producer_consumer
+//! ```
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::vec::Vec;
+use preemptive_threads::{
+    arch::DefaultArch,
+    pl011_println,
+    sched::priority,
+    sync::PriorityChannel,
+    time::Duration,
+    Kernel, RoundRobinScheduler,
+};
+use spin::{Lazy, Mutex};
+
+static KERNEL: Lazy<Kernel<DefaultArch, RoundRobinScheduler>> =
+    Lazy::new(|| Kernel::new(RoundRobinScheduler::new(1)));
+
+const PRODUCERS: usize = 3;
+const ITEMS_PER_PRODUCER: u32 = 200;
+
+/// (producer, sequence number)
+static QUEUE: Lazy<PriorityChannel<(usize, u32)>> = Lazy::new(|| PriorityChannel::new(8));
+
+/// Items and checksum received from each producer
+static TOTALS: Mutex<[(u32, u64); PRODUCERS]> = Mutex::new([(0, 0); PRODUCERS]);
+
+fn producer(id: usize) {
+    for seq in 0..ITEMS_PER_PRODUCER {
+        // Equal priorities keep the channel first in, first out
+        QUEUE.send(priority::NORMAL, (id, seq));
+        if seq % 50 == 0 {
+            common::sleep(Duration::from_millis(5));
+        }
+    }
+}
+
+fn consumer() {
+    for _ in 0..PRODUCERS as u32 * ITEMS_PER_PRODUCER {
+        let (id, seq) = QUEUE.recv();
+        let mut totals = TOTALS.lock();
+        totals[id].0 += 1;
+        totals[id].1 += seq as u64;
+    }
+}
+
+fn report() {
+    let totals = *TOTALS.lock();
+    for (id, (count, sum)) in totals.iter().enumerate() {
+        pl011_println!("[REPORT] producer {}: {} items, checksum {}", id, count, sum);
+    }
+}
+
+fn coordinator() {
+    let mut handles: Vec<_> = (0..PRODUCERS)
+        .map(|id| KERNEL.spawn(move || producer(id), priority::NORMAL).expect("spawn failed"))
+        .collect();
+    handles.push(KERNEL.spawn(consumer, priority::NORMAL).expect("spawn failed"));
+
+    while handles.iter().any(|handle| handle.try_join().is_none()) {
+        report();
+        common::sleep(Duration::from_millis(20));
+    }
+    for handle in handles {
+        handle.join().expect("thread failed");
+    }
+
+    report();
+    let expected = (0..ITEMS_PER_PRODUCER as u64).sum::<u64>();
+    let ok = TOTALS.lock().iter().all(|&(count, sum)| count == ITEMS_PER_PRODUCER && sum == expected);
+    pl011_println!("[MAIN] {}", if ok { "all items delivered" } else { "ITEMS LOST" });
+}
+
+#[no_mangle]
+pub fn kernel_main() -> ! {
+    common::boot("producer / consumer");
+
+    common::run(&KERNEL, || {
+        KERNEL.spawn(coordinator, priority::LOW).expect("spawn failed");
+    })
+}
@@ -122,7 +122,7 @@ pub fn kernel_main() -> ! {
     pl011_println!("========================================");
     pl011_println!("");
 
-    // Initialize the kernel
+    // Initialize the kernel; this also arms the preemption tick
     pl011_println!("[BOOT] Initializing kernel... [RPI]");
     KERNEL.init().expect("Failed to initialize kernel");
     pl011_println!("[BOOT] Kernel initialized!");
@@ -196,14 +196,6 @@ pub fn kernel_main() -> ! {
         .expect("Failed to spawn thread 3");
     pl011_println!("[BOOT] 3 threads spawned!");
 
-    // Set up the preemption timer (1ms time slices)
-    pl011_println!("[BOOT] Setting up preemption timer (1ms)...");
-    unsafe {
-        preemptive_threads::arch::aarch64::setup_preemption_timer(1000)
-            .expect("Failed to setup timer");
-    }
-    pl011_println!("[BOOT] Timer configured!");
-
     // NOTE: Do NOT enable interrupts here - start_first_thread() handles that
     // after setting up the current thread. This prevents an IRQ from firing
     // before we have a thread context to save to.
@@ -215,7 +207,7 @@ pub fn kernel_main() -> ! {
 
     // Start running the first thread - this never returns
     // (also enables interrupts after setting up the thread context)
-    KERNEL.start_first_thread();
+    KERNEL.start_first_thread().expect("Failed to start scheduler");
 
     // If we somehow get here, halt
     pl011_println!("[ERROR] Scheduler returned unexpectedly!");
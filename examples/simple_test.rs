@@ -111,7 +111,7 @@ pub fn kernel_main() -> ! {
     ).expect("Spawn 2 failed");
 
     pl011_println!("Starting scheduler...");
-    KERNEL.start_first_thread();
+    KERNEL.start_first_thread().expect("Failed to start scheduler");
 
     pl011_println!("ERROR: Should never reach here!");
     loop {
@@ -0,0 +1,68 @@
+//! Links in every enabled driver, to measure what each adds to the image.
+//!
+//! The linker drops code nothing calls, so a driver only counts towards
+//! `.text` once something uses it. This kernel calls one entry point of
+//! each driver whose feature is enabled, behind a flag that is never set,
+//! so it links them without touching hardware. `scripts/size-matrix.sh`
+//! builds it with no drivers, then with each one, and reports the growth.
+//!
+//! # Measuring
+//!
+//! ```bash
+//! make size-matrix
+//! ```
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+mod common;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use preemptive_threads::{arch::DefaultArch, sched::priority, Kernel, RoundRobinScheduler};
+use spin::Lazy;
+
+static KERNEL: Lazy<Kernel<DefaultArch, RoundRobinScheduler>> =
+    Lazy::new(|| Kernel::new(RoundRobinScheduler::new(1)));
+
+/// Never set; keeps the calls below from being optimized out
+static PROBE: AtomicBool = AtomicBool::new(false);
+
+#[allow(unused_imports)]
+fn use_drivers() {
+    use preemptive_threads::drivers::*;
+
+    #[cfg(feature = "gpio")]
+    let _ = gpio::set_function(17, gpio::Function::Output);
+    #[cfg(feature = "i2c")]
+    let _ = i2c::bus(i2c::Bus::Bsc1).init(100_000);
+    #[cfg(feature = "spi")]
+    let _ = spi::spi0().init(spi::Config::default());
+    #[cfg(feature = "sdhost")]
+    let _ = sdhost::sdhost().init(sdhost::Mode::Interrupt);
+    #[cfg(feature = "dma")]
+    let _ = dma::init();
+    #[cfg(feature = "pwm")]
+    let _ = pwm::init(1_000_000);
+    #[cfg(feature = "framebuffer")]
+    let _ = framebuffer::Framebuffer::new(640, 480);
+}
+
+fn idle() {
+    if PROBE.load(Ordering::Relaxed) {
+        use_drivers();
+    }
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[no_mangle]
+pub fn kernel_main() -> ! {
+    common::boot("size probe");
+
+    common::run(&KERNEL, || {
+        KERNEL.spawn(idle, priority::LOW).expect("spawn failed");
+    })
+}
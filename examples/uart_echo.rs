@@ -0,0 +1,96 @@
+//! Echoes the serial console, a line at a time, through a channel.
+//!
+//! A reader thread takes bytes off the UART and sends them to a line
+//! editor thread over a [`PriorityChannel`]. Ctrl-C is sent at the top
+//! priority, so it overtakes any backlog and discards the line being
+//! typed. Finished lines are echoed back in upper case.
+//!
+//! Under `qemu-virt` the UART receives by interrupt and the reader blocks;
+//! elsewhere it polls every millisecond.
+//!
+//! # Running in QEMU
+//!
+//! ```bash
+//! make run-example EXAMPLE=uart_echo
+//! ```
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::vec::Vec;
+use preemptive_threads::{
+    arch::{uart_pl011, DefaultArch},
+    pl011_print, pl011_println,
+    sched::priority,
+    sync::PriorityChannel,
+    Kernel, RoundRobinScheduler,
+};
+use spin::Lazy;
+
+static KERNEL: Lazy<Kernel<DefaultArch, RoundRobinScheduler>> =
+    Lazy::new(|| Kernel::new(RoundRobinScheduler::new(1)));
+
+static INPUT: Lazy<PriorityChannel<u8>> = Lazy::new(|| PriorityChannel::new(64));
+
+const CTRL_C: u8 = 0x03;
+
+fn read_byte() -> u8 {
+    #[cfg(feature = "qemu-virt")]
+    return uart_pl011::read_byte();
+
+    #[cfg(not(feature = "qemu-virt"))]
+    loop {
+        if let Some(byte) = uart_pl011::try_read_byte() {
+            return byte;
+        }
+        common::sleep(preemptive_threads::time::Duration::from_millis(1));
+    }
+}
+
+fn reader() {
+    loop {
+        let byte = read_byte();
+        let urgency = if byte == CTRL_C { priority::REALTIME } else { priority::NORMAL };
+        INPUT.send(urgency, byte);
+    }
+}
+
+fn line_editor() {
+    let mut line = Vec::new();
+    pl011_print!("> ");
+    loop {
+        match INPUT.recv() {
+            CTRL_C => {
+                line.clear();
+                pl011_print!("^C\n> ");
+            }
+            b'\r' | b'\n' => {
+                pl011_println!("");
+                let text = core::str::from_utf8(&line).unwrap_or("<not utf-8>");
+                pl011_print!("{}\n> ", text.to_ascii_uppercase());
+                line.clear();
+            }
+            byte => {
+                uart_pl011::send_byte(byte);
+                line.push(byte);
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub fn kernel_main() -> ! {
+    common::boot("uart echo");
+
+    common::run(&KERNEL, || {
+        #[cfg(feature = "qemu-virt")]
+        unsafe { uart_pl011::init_rx() }.expect("UART receive interrupt");
+
+        KERNEL.spawn(reader, priority::HIGH).expect("spawn failed");
+        KERNEL.spawn(line_editor, priority::NORMAL).expect("spawn failed");
+    })
+}
@@ -53,7 +53,7 @@ fn fuzz_memory_management(operations: Vec<MemoryOperation>) {
                         // Verify stack properties
                         assert!(stack.size() >= size_class.size());
                         assert!(!stack.base().is_null());
-                        assert!(stack.top() > stack.base());
+                        assert!(stack.end() > stack.base());
                         
                         allocated_stacks.push(stack);
                         
@@ -0,0 +1,50 @@
+#!/bin/sh
+# .text each driver feature adds to examples/size_probe.
+#
+#   scripts/size-matrix.sh                print the table
+#   scripts/size-matrix.sh --record FILE  also save each feature's growth to FILE
+#   scripts/size-matrix.sh --check FILE   fail if a feature grew more than
+#                                         SLACK bytes (default 256) past FILE
+set -eu
+
+TARGET=aarch64-unknown-none
+BASE_FEATURES=full-fpu,qemu-virt
+FEATURES="gpio i2c spi sdhost dma pwm framebuffer drivers"
+ELF=target/$TARGET/release/examples/size_probe
+SIZE=${SIZE:-rust-size}
+SLACK=${SLACK:-256}
+
+mode=${1:-}
+file=${2:-}
+
+text_size() {
+    RUSTFLAGS="-C link-arg=-Tqemu_virt.ld" \
+        cargo +nightly build --release --quiet --example size_probe --target $TARGET \
+        --no-default-features --features "$1"
+    $SIZE -A "$ELF" | awk '$1 == ".text" { print $2 }'
+}
+
+base=$(text_size "$BASE_FEATURES")
+printf '%-12s %8s %8s\n' feature .text growth
+printf '%-12s %8d %8s\n' none "$base" -
+[ "$mode" = --record ] && : > "$file"
+
+failed=0
+for feature in $FEATURES; do
+    text=$(text_size "$BASE_FEATURES,$feature")
+    growth=$((text - base))
+    printf '%-12s %8d %+8d\n' "$feature" "$text" "$growth"
+    case $mode in
+    --record)
+        echo "$feature $growth" >> "$file"
+        ;;
+    --check)
+        recorded=$(awk -v f="$feature" '$1 == f { print $2 }' "$file")
+        if [ -n "$recorded" ] && [ "$growth" -gt $((recorded + SLACK)) ]; then
+            echo "  $feature grew by $((growth - recorded)) bytes over the recorded $recorded" >&2
+            failed=1
+        fi
+        ;;
+    esac
+done
+exit $failed
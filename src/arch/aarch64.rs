@@ -4,32 +4,57 @@
 //! FPU/NEON management, and SVE support for high-performance computing.
 
 use super::Arch;
+use crate::errors::TimerError;
 use core::arch::asm;
-use portable_atomic::{AtomicU64, AtomicPtr, Ordering};
 use core::ptr::null_mut;
+use portable_atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, Ordering};
 
 pub static IRQ_SAVE_CTX: AtomicPtr<Aarch64Context> = AtomicPtr::new(null_mut());
 
-
 pub static IRQ_LOAD_CTX: AtomicPtr<Aarch64Context> = AtomicPtr::new(null_mut());
 
+/// Size of the built-in interrupt stack in bytes.
+pub const IRQ_STACK_SIZE: usize = 4096;
 
 #[repr(C, align(16))]
 pub struct IrqStack {
-    data: [u8; 4096],
+    data: [u8; IRQ_STACK_SIZE],
 }
 
 #[no_mangle]
-pub static mut IRQ_STACK: IrqStack = IrqStack { data: [0; 4096] };
+pub static mut IRQ_STACK: IrqStack = IrqStack {
+    data: [0; IRQ_STACK_SIZE],
+};
+
+/// Top of the stack the IRQ vector switches to, loaded on every interrupt.
+pub static IRQ_STACK_TOP: AtomicPtr<u8> = AtomicPtr::new(null_mut());
 
+/// Top of the built-in interrupt stack.
 #[inline]
-pub fn irq_stack_top() -> *mut u8 {
+pub fn builtin_irq_stack_top() -> *mut u8 {
     unsafe {
         let ptr = core::ptr::addr_of_mut!(IRQ_STACK);
-        (*ptr).data.as_mut_ptr().add(4096)
+        (*ptr).data.as_mut_ptr().add(IRQ_STACK_SIZE)
     }
 }
 
+/// Top of the stack interrupt handlers currently run on.
+#[inline]
+pub fn irq_stack_top() -> *mut u8 {
+    IRQ_STACK_TOP.load(Ordering::Acquire)
+}
+
+/// Make interrupt handlers run on the stack ending at `top`.
+///
+/// # Safety
+///
+/// `top` must be 16-byte aligned and the end of a region that stays
+/// valid, and unused by anything else, for as long as interrupts can be
+/// taken. Must be called with interrupts disabled.
+pub unsafe fn set_irq_stack_top(top: *mut u8) {
+    IRQ_STACK_TOP.store(top, Ordering::Release);
+}
+
 pub struct Aarch64Arch;
 
 #[repr(C)]
@@ -65,6 +90,19 @@ impl Default for Aarch64Context {
     }
 }
 
+impl Aarch64Context {
+    /// x0-x30, sp, pc and pstate: all a context holds without `full-fpu`.
+    pub const GPR_SIZE: usize = 272;
+    /// q0-q31, fpcr and fpsr, padded to 16 bytes; zero without `full-fpu`.
+    pub const FPU_SIZE: usize = if cfg!(feature = "full-fpu") { 528 } else { 0 };
+}
+
+// context_switch, save_fpu and restore_fpu address fields by fixed
+// offsets, so the layout must not drift from these sizes
+const _: () = assert!(
+    core::mem::size_of::<Aarch64Context>() == Aarch64Context::GPR_SIZE + Aarch64Context::FPU_SIZE
+);
+
 unsafe impl Send for Aarch64Context {}
 unsafe impl Sync for Aarch64Context {}
 
@@ -130,6 +168,15 @@ impl Arch for Aarch64Arch {
                 "ldp x28,x29, [x13, #224]",
                 "ldr x30,     [x13, #240]",
 
+                // Stamp the switch time if the switch benchmark armed it
+                "adrp x11, {sync_exit}",
+                "add x11, x11, :lo12:{sync_exit}",
+                "ldr x10, [x11]",
+                "cbnz x10, 2f",
+                "mrs x10, cntpct_el0",
+                "str x10, [x11]",
+                "2:",
+
                 // Now load the PC into x11 and x10,x12,x13 from context
                 "ldr x11, [x13, #256]",  // PC
                 "ldp x10,x12, [x13, #80]",  // x10, x11 (but x11 will be overwritten by PC)
@@ -141,6 +188,7 @@ impl Arch for Aarch64Arch {
                 "1:",
                 prev = in(reg) prev,
                 next = in(reg) next,
+                sync_exit = sym crate::time::switch_latency::SWITCH_BENCH_SYNC_EXIT,
                 out("x10") _,
                 out("x11") _,
                 out("x12") _,
@@ -149,8 +197,6 @@ impl Arch for Aarch64Arch {
         }
     }
 
-
-
     #[cfg(feature = "full-fpu")]
     unsafe fn save_fpu(ctx: &mut Self::SavedContext) {
         unsafe {
@@ -216,20 +262,22 @@ impl Arch for Aarch64Arch {
     }
 
     fn enable_interrupts() {
+        if !Self::interrupts_enabled() {
+            crate::time::latency::irq_enabled();
+        }
         unsafe {
-            asm!(
-                "msr daifclr, #2",
-                options(nomem, nostack)
-            );
+            asm!("msr daifclr, #2", options(nomem, nostack));
         }
     }
 
+    #[track_caller]
     fn disable_interrupts() {
+        let was_enabled = Self::interrupts_enabled();
         unsafe {
-            asm!(
-                "msr daifset, #2",
-                options(nomem, nostack)
-            );
+            asm!("msr daifset, #2", options(nomem, nostack));
+        }
+        if was_enabled {
+            crate::time::latency::irq_disabled(core::panic::Location::caller());
         }
     }
 
@@ -244,18 +292,61 @@ impl Arch for Aarch64Arch {
         }
         (daif & 0x80) == 0
     }
+
+    fn wait_for_interrupt() {
+        unsafe {
+            asm!("wfi", options(nomem, nostack));
+        }
+    }
+
+    fn fpu_trapped() -> bool {
+        let cpacr: u64;
+        unsafe {
+            asm!("mrs {cpacr}, cpacr_el1", cpacr = out(reg) cpacr, options(nomem, nostack));
+        }
+        cpacr & (0b11 << 20) != 0b11 << 20
+    }
+
+    fn set_fpu_trap(trap: bool) {
+        // CPACR_EL1.FPEN: 0b11 lets EL1 and EL0 use the FPU, 0b00 traps both
+        const FPEN: u64 = 0b11 << 20;
+        let mut cpacr: u64;
+        unsafe {
+            asm!("mrs {cpacr}, cpacr_el1", cpacr = out(reg) cpacr, options(nomem, nostack));
+            cpacr = if trap { cpacr & !FPEN } else { cpacr | FPEN };
+            asm!(
+                "msr cpacr_el1, {cpacr}",
+                "isb",
+                cpacr = in(reg) cpacr,
+                options(nostack)
+            );
+        }
+    }
 }
 
 static TIMER_FREQ: AtomicU64 = AtomicU64::new(0);
 
-pub fn init() {
+/// Preemption tick period in microseconds, as last armed.
+static TICK_INTERVAL_US: AtomicU32 = AtomicU32::new(1000);
+
+/// The period the preemption timer is re-armed with on every tick.
+pub fn tick_interval_us() -> u32 {
+    TICK_INTERVAL_US.load(Ordering::Relaxed)
+}
+
+/// Whether the preemption tick is armed: set by the first
+/// [`setup_preemption_timer`], cleared by [`stop_timer`].
+static TICK_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Enable the physical timer, counting at `freq` Hz.
+///
+/// Fails with [`TimerError::AlreadyRunning`] while the tick is armed,
+/// rather than changing its frequency under it; [`stop_timer`] first.
+pub fn init(freq: u64) -> Result<(), TimerError> {
+    if TICK_RUNNING.load(Ordering::Acquire) {
+        return Err(TimerError::AlreadyRunning);
+    }
     unsafe {
-        let freq: u64;
-        asm!(
-            "mrs {freq}, cntfrq_el0",
-            freq = out(reg) freq,
-            options(nostack, readonly)
-        );
         TIMER_FREQ.store(freq, Ordering::Relaxed);
 
         asm!(
@@ -264,6 +355,30 @@ pub fn init() {
             options(nomem, nostack)
         );
     }
+    Ok(())
+}
+
+/// Disable the physical timer and stop the tick re-arming itself, so the
+/// timer can be [`init`]ialized again.
+///
+/// Fails with [`TimerError::NotRunning`] if no tick was armed.
+pub fn stop_timer() -> Result<(), TimerError> {
+    if !TICK_RUNNING.swap(false, Ordering::AcqRel) {
+        return Err(TimerError::NotRunning);
+    }
+    unsafe {
+        asm!(
+            "msr cntp_ctl_el0, {val}",
+            val = in(reg) 2u64, // Disabled, interrupt masked
+            options(nomem, nostack)
+        );
+    }
+    Ok(())
+}
+
+/// Whether the preemption tick is armed.
+pub fn timer_running() -> bool {
+    TICK_RUNNING.load(Ordering::Acquire)
 }
 
 /// Set up ARM64 timer for preemption with specified interval in microseconds.
@@ -277,6 +392,7 @@ pub unsafe fn setup_preemption_timer(interval_us: u32) -> Result<(), &'static st
         return Err("Timer frequency not initialized");
     }
 
+    TICK_INTERVAL_US.store(interval_us, Ordering::Relaxed);
     let ticks = (freq * interval_us as u64) / 1_000_000;
 
     unsafe {
@@ -301,6 +417,7 @@ pub unsafe fn setup_preemption_timer(interval_us: u32) -> Result<(), &'static st
         );
     }
 
+    TICK_RUNNING.store(true, Ordering::Release);
     Ok(())
 }
 
@@ -351,19 +468,82 @@ pub unsafe fn timer_interrupt_handler() {
             options(nomem, nostack)
         );
 
-        use crate::arch::DefaultArch;
-        use crate::sched::RoundRobinScheduler;
-        use crate::kernel::get_global_kernel;
+        let fired: u64;
+        asm!("mrs {}, cntp_cval_el0", out(reg) fired, options(nomem, nostack));
+        crate::time::switch_latency::timer_fired(fired);
+
+        tick_work();
 
-        if let Some(kernel) = get_global_kernel::<DefaultArch, RoundRobinScheduler>() {
-            // Handle preemption via IRQ context switching
-            kernel.handle_irq_preemption();
+        // A tick already pending when the timer was stopped mustn't
+        // restart it
+        if timer_running() {
+            let _ = setup_preemption_timer(tick_interval_us());
         }
+    }
+}
+
+/// Everything a tick does besides re-arming the timer: sampling, the stall
+/// heartbeat and the scheduling decision.
+///
+/// # Safety
+///
+/// Same as [`timer_interrupt_handler`]: IRQ context only.
+pub(crate) unsafe fn tick_work() {
+    let now = crate::time::Instant::now();
+    crate::kernel::metrics::sample(0, now);
+    crate::kernel::watch::sample(now);
+    crate::kernel::stall::heartbeat();
+    if let Some(kernel) = crate::kernel::global_ops() {
+        // Handle preemption via IRQ context switching
+        kernel.handle_irq_preemption();
+    }
+}
+
+/// Set by an FIQ tick, taken by the reschedule SGI it raises.
+static FIQ_TICK_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Timer tick delivered as an FIQ, with `TickRoute::Fiq`.
+///
+/// Masking IRQs doesn't hold this off, so the timer keeps being re-armed
+/// and the [stall detector](crate::kernel::stall) keeps checking even
+/// while the interrupted code has IRQs masked. It can't safely switch
+/// threads from there, so it hands the rest of the tick to the IRQ path
+/// as a reschedule SGI to this CPU: taken as soon as the FIQ returns if
+/// IRQs were unmasked, or the moment they are unmasked if not. A core
+/// that never unmasks them stops heartbeating, and the check made here on
+/// the following ticks reports it.
+///
+/// # Safety
+///
+/// Must only be called from the FIQ exception handler, with the GIC set up
+/// by `aarch64_gic::route_timer_to_fiq`.
+pub unsafe fn fiq_tick() {
+    unsafe {
+        asm!(
+            "msr cntp_ctl_el0, {val}",
+            val = in(reg) 2u64,
+            options(nomem, nostack)
+        );
 
-        let _ = setup_preemption_timer(1000);
+        let fired: u64;
+        asm!("mrs {}, cntp_cval_el0", out(reg) fired, options(nomem, nostack));
+        crate::time::switch_latency::timer_fired(fired);
+
+        crate::kernel::stall::check(crate::time::Instant::now());
+        FIQ_TICK_PENDING.store(true, Ordering::Release);
+        super::send_reschedule_ipi(1 << super::cpu_id());
+
+        if timer_running() {
+            let _ = setup_preemption_timer(tick_interval_us());
+        }
     }
 }
 
+/// Claim the tick left by [`fiq_tick`], if one is pending.
+pub(crate) fn take_fiq_tick() -> bool {
+    FIQ_TICK_PENDING.swap(false, Ordering::AcqRel)
+}
+
 /// Set up the IRQ context pointers for a thread that's about to run.
 ///
 /// This must be called before enabling interrupts so that when an IRQ occurs,
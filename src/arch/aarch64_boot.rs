@@ -139,8 +139,14 @@ pub unsafe extern "C" fn _start() -> ! {
 #[cfg(target_arch = "aarch64")]
 unsafe fn boot_rust() -> ! {
     unsafe {
-        // Install exception vector table
-        super::aarch64_vectors::install_vector_table();
+        // Install exception vector table. Nothing can report a failure
+        // this early, and the first exception would go astray anyway
+        if super::aarch64_vectors::install_vector_table().is_err() {
+            loop {
+                core::arch::asm!("wfe", options(nomem, nostack));
+            }
+        }
+        super::aarch64::set_irq_stack_top(super::aarch64::builtin_irq_stack_top());
 
         // Initialize GIC (only on qemu-virt where it's properly emulated)
         // QEMU raspi3b does NOT emulate BCM2837's GIC - accessing it causes data abort.
@@ -156,8 +162,10 @@ unsafe fn boot_rust() -> ! {
             }
         }
 
-        // Initialize architecture-specific features
-        super::aarch64::init();
+        // Initialize architecture-specific features; Kernel::init_timer
+        // replaces the frequency with the calibrated one. Nothing is armed
+        // yet, so this can't fail
+        let _ = super::aarch64::init(crate::time::calibration::counter_frequency());
 
         // Call user's kernel_main
         extern "Rust" {
@@ -21,6 +21,7 @@
 //! ARM Generic Interrupt Controller Architecture Specification v2.0
 
 use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
 
 // GIC base addresses - platform dependent
 #[cfg(feature = "qemu-virt")]
@@ -34,31 +35,38 @@ const GICD_BASE: usize = 0xFF84_1000; // BCM2837 GIC Distributor
 const GICC_BASE: usize = 0xFF84_2000; // BCM2837 GIC CPU Interface
 
 // Distributor registers (offsets from GICD_BASE)
-const GICD_CTLR: usize = 0x000;       // Distributor Control Register
-const GICD_TYPER: usize = 0x004;      // Interrupt Controller Type Register
-const GICD_ISENABLER: usize = 0x100;  // Interrupt Set-Enable Registers
-const GICD_ICENABLER: usize = 0x180;  // Interrupt Clear-Enable Registers
-const GICD_ISPENDR: usize = 0x200;    // Interrupt Set-Pending Registers
-const GICD_ICPENDR: usize = 0x280;    // Interrupt Clear-Pending Registers
+const GICD_CTLR: usize = 0x000; // Distributor Control Register
+const GICD_TYPER: usize = 0x004; // Interrupt Controller Type Register
+const GICD_IGROUPR: usize = 0x080; // Interrupt Group Registers
+const GICD_ISENABLER: usize = 0x100; // Interrupt Set-Enable Registers
+const GICD_ICENABLER: usize = 0x180; // Interrupt Clear-Enable Registers
+const GICD_ISPENDR: usize = 0x200; // Interrupt Set-Pending Registers
+const GICD_ICPENDR: usize = 0x280; // Interrupt Clear-Pending Registers
 const GICD_IPRIORITYR: usize = 0x400; // Interrupt Priority Registers
-const GICD_ITARGETSR: usize = 0x800;  // Interrupt Processor Targets Registers
-const GICD_ICFGR: usize = 0xC00;      // Interrupt Configuration Registers
+const GICD_ITARGETSR: usize = 0x800; // Interrupt Processor Targets Registers
+const GICD_ICFGR: usize = 0xC00; // Interrupt Configuration Registers
+const GICD_SGIR: usize = 0xF00; // Software Generated Interrupt Register
 
 // CPU Interface registers (offsets from GICC_BASE)
-const GICC_CTLR: usize = 0x000;  // CPU Interface Control Register
-const GICC_PMR: usize = 0x004;   // Interrupt Priority Mask Register
-const GICC_BPR: usize = 0x008;   // Binary Point Register
-const GICC_IAR: usize = 0x00C;   // Interrupt Acknowledge Register
-const GICC_EOIR: usize = 0x010;  // End of Interrupt Register
-const GICC_RPR: usize = 0x014;   // Running Priority Register
+const GICC_CTLR: usize = 0x000; // CPU Interface Control Register
+const GICC_PMR: usize = 0x004; // Interrupt Priority Mask Register
+const GICC_BPR: usize = 0x008; // Binary Point Register
+const GICC_IAR: usize = 0x00C; // Interrupt Acknowledge Register
+const GICC_EOIR: usize = 0x010; // End of Interrupt Register
+const GICC_RPR: usize = 0x014; // Running Priority Register
 const GICC_HPPIR: usize = 0x018; // Highest Priority Pending Interrupt Register
 
 // Interrupt numbers
 /// Physical Timer interrupt (EL1 Physical Timer)
 pub const TIMER_IRQ: u32 = 30;
+/// GIC priority of the scheduler timer interrupt
+pub const TIMER_PRIORITY: u8 = 0x80;
 /// Virtual Timer interrupt
 pub const VTIMER_IRQ: u32 = 27;
 
+/// SGI used to ask another CPU to run its scheduler
+pub const RESCHEDULE_SGI: u32 = 0;
+
 // Special interrupt IDs
 /// Spurious interrupt ID
 pub const SPURIOUS_IRQ: u32 = 1023;
@@ -66,19 +74,30 @@ pub const SPURIOUS_IRQ: u32 = 1023;
 /// GIC-400 Interrupt Controller for Raspberry Pi Zero 2 W.
 pub struct Gic400;
 
+/// Set by [`Gic400::init`], cleared by [`shutdown`].
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
 impl Gic400 {
     /// Initialize the GIC-400 interrupt controller.
     ///
     /// This sets up both the Distributor and CPU Interface for handling
     /// interrupts on CPU 0.
     ///
+    /// Only the first call programs the GIC; later ones, such as the
+    /// kernel's after boot's, return true and leave whatever drivers have
+    /// enabled since alone. After [`shutdown`] it programs it afresh.
+    ///
     /// # Safety
     ///
-    /// Must be called once during system initialization with interrupts
-    /// disabled. The GIC memory regions must be mapped and accessible.
+    /// Must be called with interrupts disabled. The GIC memory regions
+    /// must be mapped and accessible.
     ///
     /// Returns false if GIC is not accessible (e.g., QEMU without full GIC emulation).
     pub unsafe fn init() -> bool {
+        if INITIALIZED.load(Ordering::Acquire) {
+            return true;
+        }
+
         // First, check if GIC is accessible by reading GICD_TYPER
         // If this returns 0xFFFFFFFF or causes issues, GIC is not present
         let typer = unsafe { read_volatile((GICD_BASE + GICD_TYPER) as *const u32) };
@@ -157,6 +176,7 @@ impl Gic400 {
             Self::init_cpu_interface();
         }
 
+        INITIALIZED.store(true, Ordering::Release);
         true
     }
 
@@ -191,10 +211,7 @@ impl Gic400 {
         let reg_offset = (irq / 32) as usize * 4;
         let bit = 1u32 << (irq % 32);
         unsafe {
-            write_volatile(
-                (GICD_BASE + GICD_ISENABLER + reg_offset) as *mut u32,
-                bit,
-            );
+            write_volatile((GICD_BASE + GICD_ISENABLER + reg_offset) as *mut u32, bit);
         }
     }
 
@@ -211,10 +228,7 @@ impl Gic400 {
         let reg_offset = (irq / 32) as usize * 4;
         let bit = 1u32 << (irq % 32);
         unsafe {
-            write_volatile(
-                (GICD_BASE + GICD_ICENABLER + reg_offset) as *mut u32,
-                bit,
-            );
+            write_volatile((GICD_BASE + GICD_ICENABLER + reg_offset) as *mut u32, bit);
         }
     }
 
@@ -251,7 +265,7 @@ impl Gic400 {
     pub unsafe fn enable_timer_interrupt() {
         // Set medium priority for timer
         unsafe {
-            Self::set_priority(TIMER_IRQ, 0x80);
+            Self::set_priority(TIMER_IRQ, TIMER_PRIORITY);
         }
 
         // Enable the interrupt
@@ -307,6 +321,24 @@ impl Gic400 {
         }
     }
 
+    /// Current priority mask: only interrupts with a priority value below
+    /// it are signalled to this CPU.
+    pub fn priority_mask() -> u8 {
+        unsafe { read_volatile((GICC_BASE + GICC_PMR) as *const u32) as u8 }
+    }
+
+    /// Set this CPU's priority mask.
+    ///
+    /// # Safety
+    ///
+    /// Must be called after GIC initialization. Masking the scheduler
+    /// timer stops preemption until the mask is lowered again.
+    pub unsafe fn set_priority_mask(mask: u8) {
+        unsafe {
+            write_volatile((GICC_BASE + GICC_PMR) as *mut u32, mask as u32);
+        }
+    }
+
     /// Get the currently running interrupt priority.
     pub fn running_priority() -> u32 {
         unsafe { read_volatile((GICC_BASE + GICC_RPR) as *const u32) & 0xFF }
@@ -333,10 +365,22 @@ impl Gic400 {
     pub unsafe fn set_pending(irq: u32) {
         let reg_offset = (irq / 32) as usize * 4;
         let bit = 1u32 << (irq % 32);
+        unsafe {
+            write_volatile((GICD_BASE + GICD_ISPENDR + reg_offset) as *mut u32, bit);
+        }
+    }
+
+    /// Send software-generated interrupt `sgi` (0-15) to the CPUs in
+    /// `target_mask` (bit n = CPU n).
+    ///
+    /// # Safety
+    ///
+    /// Must be called after GIC initialization.
+    pub unsafe fn send_sgi(sgi: u32, target_mask: u8) {
         unsafe {
             write_volatile(
-                (GICD_BASE + GICD_ISPENDR + reg_offset) as *mut u32,
-                bit,
+                (GICD_BASE + GICD_SGIR) as *mut u32,
+                (target_mask as u32) << 16 | (sgi & 0xF),
             );
         }
     }
@@ -350,10 +394,7 @@ impl Gic400 {
         let reg_offset = (irq / 32) as usize * 4;
         let bit = 1u32 << (irq % 32);
         unsafe {
-            write_volatile(
-                (GICD_BASE + GICD_ICPENDR + reg_offset) as *mut u32,
-                bit,
-            );
+            write_volatile((GICD_BASE + GICD_ICPENDR + reg_offset) as *mut u32, bit);
         }
     }
 }
@@ -362,7 +403,7 @@ impl Gic400 {
 ///
 /// # Safety
 ///
-/// Must be called once during system initialization.
+/// Must be called during system initialization, with interrupts disabled.
 /// Returns true if GIC was initialized, false if GIC is not available.
 pub unsafe fn init() -> bool {
     unsafe {
@@ -374,3 +415,86 @@ pub unsafe fn init() -> bool {
         }
     }
 }
+
+/// Whether the GIC has been initialized since boot or the last
+/// [`shutdown`].
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::Acquire)
+}
+
+/// Return the GIC to its reset state so that [`init`] programs it again.
+///
+/// Turns off the CPU interface and the distributor, disables and clears
+/// every interrupt and puts them all back in group 0. Priorities, targets
+/// and trigger modes are left for `init` to rewrite.
+///
+/// # Safety
+///
+/// Must be called with interrupts disabled, and nothing may rely on an
+/// interrupt being delivered until the next `init`.
+pub unsafe fn shutdown() {
+    if !INITIALIZED.swap(false, Ordering::AcqRel) {
+        return;
+    }
+    let typer = unsafe { read_volatile((GICD_BASE + GICD_TYPER) as *const u32) };
+    let num_irqs = ((typer & 0x1F) + 1) * 32;
+
+    unsafe {
+        write_volatile((GICC_BASE + GICC_CTLR) as *mut u32, 0);
+        write_volatile((GICD_BASE + GICD_CTLR) as *mut u32, 0);
+        for i in (0..num_irqs).step_by(32) {
+            let offset = (i / 32) as usize * 4;
+            write_volatile(
+                (GICD_BASE + GICD_ICENABLER + offset) as *mut u32,
+                0xFFFF_FFFF,
+            );
+            write_volatile((GICD_BASE + GICD_ICPENDR + offset) as *mut u32, 0xFFFF_FFFF);
+            // Ignored where the groups are secure-only
+            write_volatile((GICD_BASE + GICD_IGROUPR + offset) as *mut u32, 0);
+        }
+    }
+}
+
+/// Deliver the timer as an FIQ and every other interrupt as an IRQ.
+///
+/// Puts the timer in group 0 and the rest in group 1, enables both groups
+/// in the distributor, and has the CPU interface signal group 0 as FIQ
+/// (`FIQEn`) while acknowledging either group through `GICC_IAR`
+/// (`AckCtl`). Then unmasks FIQ on the calling CPU.
+///
+/// Interrupt groups are only writable from the secure side. Where the
+/// kernel runs non-secure the writes are ignored; that is detected by
+/// reading the groups back, and false is returned with the GIC untouched
+/// otherwise.
+///
+/// # Safety
+///
+/// Must be called after [`init`], with interrupts disabled.
+pub unsafe fn route_timer_to_fiq() -> bool {
+    let typer = unsafe { read_volatile((GICD_BASE + GICD_TYPER) as *const u32) };
+    let num_irqs = ((typer & 0x1F) + 1) * 32;
+    let timer_bit = 1u32 << (TIMER_IRQ % 32);
+
+    unsafe {
+        let first = (GICD_BASE + GICD_IGROUPR) as *mut u32;
+        write_volatile(first, !timer_bit);
+        if read_volatile(first) != !timer_bit {
+            write_volatile(first, 0);
+            return false;
+        }
+        for i in (32..num_irqs).step_by(32) {
+            write_volatile(
+                (GICD_BASE + GICD_IGROUPR + (i / 32) as usize * 4) as *mut u32,
+                0xFFFF_FFFF,
+            );
+        }
+
+        // EnableGrp0 | EnableGrp1
+        write_volatile((GICD_BASE + GICD_CTLR) as *mut u32, 0b11);
+        // EnableGrp0 | EnableGrp1 | AckCtl | FIQEn
+        write_volatile((GICC_BASE + GICC_CTLR) as *mut u32, 0b1111);
+
+        core::arch::asm!("msr daifclr, #1", options(nomem, nostack));
+    }
+    true
+}
@@ -8,7 +8,7 @@
 //!
 //! - Synchronous: Instruction aborts, data aborts, SVCs, etc.
 //! - IRQ: Normal interrupts (used for timer preemption)
-//! - FIQ: Fast interrupts (not used)
+//! - FIQ: Fast interrupts (the scheduler tick, with `TickRoute::Fiq`)
 //! - SError: System errors
 //!
 //! # Exception Levels
@@ -18,9 +18,12 @@
 //! - Lower EL (AArch64): User mode exceptions (not used in bare-metal)
 //! - Lower EL (AArch32): 32-bit mode exceptions (not supported)
 
+use crate::errors::ArchError;
+#[cfg(target_arch = "aarch64")]
 use core::arch::asm;
 #[cfg(target_arch = "aarch64")]
 use core::arch::naked_asm;
+use core::sync::atomic::{AtomicU32, Ordering};
 
 /// Exception context saved on the stack during exception handling.
 #[repr(C)]
@@ -34,10 +37,7 @@ pub struct ExceptionContext {
 
 macro_rules! vector_entry {
     ($handler:ident) => {
-        concat!(
-            ".align 7\n",
-            "b ", stringify!($handler), "\n",
-        )
+        concat!(".align 7\n", "b ", stringify!($handler), "\n",)
     };
 }
 
@@ -56,26 +56,22 @@ macro_rules! vector_entry {
 #[unsafe(naked)]
 pub unsafe extern "C" fn _vectors() {
     naked_asm!(
-        ".align 11",  // 2048-byte alignment (2^11)
-
+        ".align 11", // 2048-byte alignment (2^11)
         // Current EL with SP0 (EL1t)
         vector_entry!(sync_el1t),
         vector_entry!(irq_el1t),
         vector_entry!(fiq_el1t),
         vector_entry!(serror_el1t),
-
         // Current EL with SPx (EL1h) - This is what we use
         vector_entry!(sync_el1h),
         vector_entry!(irq_el1h),
         vector_entry!(fiq_el1h),
         vector_entry!(serror_el1h),
-
         // Lower EL using AArch64 (EL0)
         vector_entry!(sync_el0_64),
         vector_entry!(irq_el0_64),
         vector_entry!(fiq_el0_64),
         vector_entry!(serror_el0_64),
-
         // Lower EL using AArch32 (not supported)
         vector_entry!(sync_el0_32),
         vector_entry!(irq_el0_32),
@@ -135,21 +131,17 @@ unsafe extern "C" fn sync_el1h() {
         "stp x26, x27, [sp, #208]",
         "stp x28, x29, [sp, #224]",
         "str x30, [sp, #240]",
-
         "mrs x0, elr_el1",
         "mrs x1, spsr_el1",
         "mrs x2, esr_el1",
         "mrs x3, far_el1",
         "stp x0, x1, [sp, #248]",
         "stp x2, x3, [sp, #264]",
-
         "mov x0, sp",
         "bl sync_exception_handler",
-
         "ldp x0, x1, [sp, #248]",
         "msr elr_el1, x0",
         "msr spsr_el1, x1",
-
         "ldp x0, x1, [sp, #0]",
         "ldp x2, x3, [sp, #16]",
         "ldp x4, x5, [sp, #32]",
@@ -167,7 +159,6 @@ unsafe extern "C" fn sync_el1h() {
         "ldp x28, x29, [sp, #224]",
         "ldr x30, [sp, #240]",
         "add sp, sp, #272",
-
         "eret",
     );
 }
@@ -205,9 +196,9 @@ unsafe extern "C" fn irq_el1h() {
 
         "add x0, sp, #64",
 
-        "adrp x29, {irq_stack}",
-        "add x29, x29, :lo12:{irq_stack}",
-        "add x29, x29, #4096",
+        "adrp x29, {irq_stack_top}",
+        "add x29, x29, :lo12:{irq_stack_top}",
+        "ldr x29, [x29]",
         "mov x2, sp",
         "mov sp, x29",
 
@@ -260,6 +251,15 @@ unsafe extern "C" fn irq_el1h() {
 
         "cbz x29, 3f",
 
+        // Stamp the switch time if the switch benchmark armed it
+        "adrp x1, {irq_exit}",
+        "add x1, x1, :lo12:{irq_exit}",
+        "ldr x0, [x1]",
+        "cbnz x0, 4f",
+        "mrs x0, cntpct_el0",
+        "str x0, [x1]",
+        "4:",
+
         "ldr x0, [x29, #264]",
         "msr spsr_el1, x0",
         "ldr x0, [x29, #256]",
@@ -294,15 +294,54 @@ unsafe extern "C" fn irq_el1h() {
 
         irq_save_ctx = sym super::aarch64::IRQ_SAVE_CTX,
         irq_load_ctx = sym super::aarch64::IRQ_LOAD_CTX,
-        irq_stack = sym super::aarch64::IRQ_STACK,
+        irq_stack_top = sym super::aarch64::IRQ_STACK_TOP,
+        irq_exit = sym crate::time::switch_latency::SWITCH_BENCH_IRQ_EXIT,
     );
 }
 
+/// FIQ handler - the scheduler tick when it is routed to FIQ.
+///
+/// Deliberately minimal: saves the caller-saved registers on the
+/// interrupted stack, calls `fiq_handler`, and returns to exactly where
+/// it left off. It never switches threads, since an FIQ can land inside
+/// code that masked IRQs to update the IRQ context pointers; preemption
+/// is passed on to the IRQ path instead.
 #[cfg(target_arch = "aarch64")]
 #[no_mangle]
 #[unsafe(naked)]
 unsafe extern "C" fn fiq_el1h() {
-    naked_asm!("b .");
+    naked_asm!(
+        "sub sp, sp, #176",
+        "stp x0, x1, [sp, #0]",
+        "stp x2, x3, [sp, #16]",
+        "stp x4, x5, [sp, #32]",
+        "stp x6, x7, [sp, #48]",
+        "stp x8, x9, [sp, #64]",
+        "stp x10, x11, [sp, #80]",
+        "stp x12, x13, [sp, #96]",
+        "stp x14, x15, [sp, #112]",
+        "stp x16, x17, [sp, #128]",
+        "stp x18, x30, [sp, #144]",
+        "mrs x0, elr_el1",
+        "mrs x1, spsr_el1",
+        "stp x0, x1, [sp, #160]",
+        "bl fiq_handler",
+        "ldp x0, x1, [sp, #160]",
+        "msr elr_el1, x0",
+        "msr spsr_el1, x1",
+        "ldp x0, x1, [sp, #0]",
+        "ldp x2, x3, [sp, #16]",
+        "ldp x4, x5, [sp, #32]",
+        "ldp x6, x7, [sp, #48]",
+        "ldp x8, x9, [sp, #64]",
+        "ldp x10, x11, [sp, #80]",
+        "ldp x12, x13, [sp, #96]",
+        "ldp x14, x15, [sp, #112]",
+        "ldp x16, x17, [sp, #128]",
+        "ldp x18, x30, [sp, #144]",
+        "add sp, sp, #176",
+        "eret",
+    );
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -370,13 +409,24 @@ unsafe extern "C" fn serror_el0_32() {
 
 #[no_mangle]
 extern "C" fn sync_exception_handler(ctx: *mut ExceptionContext) {
-    let ctx = unsafe { &*ctx };
+    let ctx = unsafe { &mut *ctx };
 
     let esr = ctx.esr;
     let ec = (esr >> 26) & 0x3F;
+    crate::kernel::metrics::record_exception(crate::kernel::metrics::ExceptionKind::from_esr(esr));
 
     match ec {
         0b010101 => {
+            // SVC
+            #[cfg(feature = "irq-inject")]
+            super::inject::on_svc(ctx);
+        }
+        0b000111 => {
+            // FP/SIMD access trapped by CPACR_EL1: a `no_fpu` thread, or
+            // kernel code running while one was current
+            if crate::kernel::fpu_access_trapped() {
+                ctx.elr = crate::kernel::exit_fpu_violation as usize as u64;
+            }
         }
         0b100000 | 0b100001 => {
             // Instruction abort
@@ -389,7 +439,9 @@ extern "C" fn sync_exception_handler(ctx: *mut ExceptionContext) {
         _ => {
             // Unknown exception - hang
             loop {
-                unsafe { asm!("wfe"); }
+                unsafe {
+                    asm!("wfe");
+                }
             }
         }
     }
@@ -399,7 +451,7 @@ extern "C" fn sync_exception_handler(ctx: *mut ExceptionContext) {
 extern "C" fn irq_handler() {
     #[cfg(target_arch = "aarch64")]
     {
-        use super::aarch64_gic::{Gic400, TIMER_IRQ, SPURIOUS_IRQ};
+        use super::aarch64_gic::{Gic400, RESCHEDULE_SGI, SPURIOUS_IRQ, TIMER_IRQ};
 
         let irq = unsafe { Gic400::acknowledge_interrupt() };
 
@@ -407,16 +459,59 @@ extern "C" fn irq_handler() {
             return;
         }
 
+        let _irq_context = crate::irq::IrqGuard::enter();
+        #[cfg(feature = "irq-inject")]
+        super::inject::on_irq_entry(irq);
+
         match irq {
             TIMER_IRQ => {
                 timer_interrupt_handler();
             }
+            RESCHEDULE_SGI => {
+                // request_reschedule(), another CPU, or an FIQ tick: run
+                // the scheduler as the tick would, without re-arming the
+                // timer
+                if super::aarch64::take_fiq_tick() {
+                    unsafe { super::aarch64::tick_work() };
+                } else if let Some(kernel) = crate::kernel::global_ops() {
+                    kernel.handle_irq_preemption();
+                }
+            }
             _ => {
-                // Unknown interrupt - just acknowledge and return
+                // Device interrupt - hand off to the registered driver, if any
+                crate::irq::dispatch(irq);
+                // and straight on to a thread it woke, if that outranks the
+                // interrupted one
+                if let Some(kernel) = crate::kernel::global_ops() {
+                    kernel.handle_irq_handoff();
+                }
             }
         }
 
-        unsafe { Gic400::end_interrupt(irq); }
+        #[cfg(feature = "irq-inject")]
+        super::inject::on_irq_exit();
+        unsafe {
+            Gic400::end_interrupt(irq);
+        }
+    }
+}
+
+#[no_mangle]
+extern "C" fn fiq_handler() {
+    #[cfg(target_arch = "aarch64")]
+    {
+        use super::aarch64_gic::{Gic400, SPURIOUS_IRQ, TIMER_IRQ};
+
+        let irq = unsafe { Gic400::acknowledge_interrupt() };
+        if irq == SPURIOUS_IRQ {
+            return;
+        }
+        if irq == TIMER_IRQ {
+            unsafe { super::aarch64::fiq_tick() };
+        }
+        unsafe {
+            Gic400::end_interrupt(irq);
+        }
     }
 }
 
@@ -430,28 +525,97 @@ fn timer_interrupt_handler() {
     }
 }
 
-/// Install the exception vector table.
+/// Alignment `VBAR_EL1` requires of the vector table: its low 11 bits
+/// are reserved.
+pub const VECTOR_TABLE_ALIGN: usize = 2048;
+
+/// CPUs that have installed the vector table, bit n for CPU n.
+static INSTALLED_ON: AtomicU32 = AtomicU32::new(0);
+
+/// Address of the vector table in the kernel image.
+pub fn vector_table_base() -> usize {
+    _vectors as *const () as usize
+}
+
+/// Exception level the calling CPU runs at, from `CurrentEL`.
+pub fn current_el() -> u8 {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let el: u64;
+        unsafe { asm!("mrs {}, CurrentEL", out(reg) el, options(nomem, nostack, preserves_flags)) };
+        ((el >> 2) & 0b11) as u8
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        1
+    }
+}
+
+/// Check that a table at `base` can be installed from exception level `el`.
+fn validate(base: usize, el: u8) -> Result<(), ArchError> {
+    if el != 1 {
+        return Err(ArchError::WrongExceptionLevel);
+    }
+    if base % VECTOR_TABLE_ALIGN != 0 {
+        return Err(ArchError::MisalignedVectorTable);
+    }
+    Ok(())
+}
+
+/// Install the exception vector table on the calling CPU and return its
+/// base address.
+///
+/// `VBAR_EL1` is banked per core, so each CPU brought up must call this
+/// itself before unmasking interrupts; [`installed_on`] tells which have.
+/// Fails with [`ArchError::WrongExceptionLevel`] outside EL1, with
+/// [`ArchError::MisalignedVectorTable`] if the linker script didn't
+/// align `.vectors`, and with [`ArchError::VectorTableNotInstalled`] if
+/// `VBAR_EL1` doesn't read back as written. Any of these would otherwise
+/// surface as a hang or a jump to garbage at the first exception.
 ///
 /// # Safety
 ///
-/// Must be called once during system initialization with interrupts disabled.
-#[cfg(target_arch = "aarch64")]
-pub unsafe fn install_vector_table() {
-    unsafe {
-        asm!(
-            "adr {tmp}, _vectors",
-            "msr vbar_el1, {tmp}",
-            "isb",
-            tmp = out(reg) _,
-            options(nomem, nostack)
-        );
+/// Must be called with interrupts disabled.
+pub unsafe fn install_vector_table() -> Result<usize, ArchError> {
+    let base = vector_table_base();
+    #[cfg(target_arch = "aarch64")]
+    {
+        validate(base, current_el())?;
+        unsafe { asm!("msr vbar_el1, {}", "isb", in(reg) base, options(nomem, nostack)) };
+        if installed_vector_base() != base {
+            return Err(ArchError::VectorTableNotInstalled);
+        }
     }
+    INSTALLED_ON.fetch_or(1 << (super::cpu_id() % 32), Ordering::AcqRel);
+    Ok(base)
 }
 
-/// Placeholder for non-ARM64 targets (testing).
-#[cfg(not(target_arch = "aarch64"))]
-pub unsafe fn install_vector_table() {
-    // No-op for testing
+/// Vector table base the calling CPU takes exceptions through, read from
+/// `VBAR_EL1`. Zero (its reset value on QEMU) means none was installed.
+pub fn installed_vector_base() -> usize {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let vbar: u64;
+        unsafe {
+            asm!("mrs {}, vbar_el1", out(reg) vbar, options(nomem, nostack, preserves_flags))
+        };
+        vbar as usize
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        if INSTALLED_ON.load(Ordering::Acquire) & (1 << (super::cpu_id() % 32)) != 0 {
+            vector_table_base()
+        } else {
+            0
+        }
+    }
+}
+
+/// CPUs that have installed the vector table, bit n for CPU n.
+pub fn installed_on() -> u32 {
+    INSTALLED_ON.load(Ordering::Acquire)
 }
 
 #[cfg(not(target_arch = "aarch64"))]
@@ -0,0 +1,177 @@
+//! Sanity checks on a saved context before it is switched to.
+//!
+//! A context overwritten by a stray write, e.g. a buffer overrun in a
+//! neighbouring heap block, otherwise shows up only after the switch, as a
+//! fault at some unrelated address or a silent jump into data. With the
+//! `context-check` feature the kernel checks every context it is about to
+//! load and panics at the switch site, naming the thread and the register
+//! that is off:
+//!
+//! - the PC must be 4-byte aligned and inside the kernel's text section;
+//! - the SP must be 16-byte aligned and inside the thread's stack;
+//! - PSTATE must describe AArch64 at EL1, or hold only the NZCV flags, as
+//!   a context saved by a voluntary switch does.
+//!
+//! The checks cost a few comparisons per switch and are meant for debug
+//! builds.
+
+use core::fmt;
+use core::ops::Range;
+
+/// Condition flags, the only PSTATE bits a voluntary switch saves
+const NZCV: u64 = 0xF000_0000;
+/// Exception level and stack pointer selection, plus the AArch32 bit
+const MODE: u64 = 0x1F;
+const EL1T: u64 = 0b0_0100;
+const EL1H: u64 = 0b0_0101;
+
+/// Which part of a saved context is implausible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContextProblem {
+    /// PC outside the kernel's text section.
+    PcOutsideText,
+    /// PC not 4-byte aligned.
+    PcMisaligned,
+    /// SP outside the thread's stack.
+    SpOutsideStack,
+    /// SP not 16-byte aligned.
+    SpMisaligned,
+    /// PSTATE for another mode or with reserved bits set.
+    BadPstate,
+}
+
+/// A thread's saved context failed a [check](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptContext {
+    /// The thread the context belongs to.
+    pub thread: crate::thread::ThreadId,
+    /// What is wrong with it.
+    pub problem: ContextProblem,
+    /// Saved program counter.
+    pub pc: u64,
+    /// Saved stack pointer.
+    pub sp: u64,
+    /// Saved PSTATE.
+    pub pstate: u64,
+}
+
+impl fmt::Display for CorruptContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let problem = match self.problem {
+            ContextProblem::PcOutsideText => "PC outside kernel text",
+            ContextProblem::PcMisaligned => "misaligned PC",
+            ContextProblem::SpOutsideStack => "SP outside its stack",
+            ContextProblem::SpMisaligned => "misaligned SP",
+            ContextProblem::BadPstate => "implausible PSTATE",
+        };
+        write!(
+            f,
+            "thread {} has a corrupt saved context ({}): pc={:#x} sp={:#x} pstate={:#x}",
+            self.thread, problem, self.pc, self.sp, self.pstate
+        )
+    }
+}
+
+/// Check saved registers against the kernel text and the thread's stack.
+/// Either range may be `None` when it isn't known, skipping that check.
+pub fn check(
+    thread: crate::thread::ThreadId,
+    pc: u64,
+    sp: u64,
+    pstate: u64,
+    text: Option<Range<usize>>,
+    stack: Option<Range<usize>>,
+) -> Result<(), CorruptContext> {
+    let fail = |problem| {
+        Err(CorruptContext {
+            thread,
+            problem,
+            pc,
+            sp,
+            pstate,
+        })
+    };
+    if pc % 4 != 0 {
+        return fail(ContextProblem::PcMisaligned);
+    }
+    if text.is_some_and(|text| !text.contains(&(pc as usize))) {
+        return fail(ContextProblem::PcOutsideText);
+    }
+    if sp % 16 != 0 {
+        return fail(ContextProblem::SpMisaligned);
+    }
+    // The initial SP is one past the highest stack address
+    if stack.is_some_and(|stack| (sp as usize) < stack.start || (sp as usize) > stack.end) {
+        return fail(ContextProblem::SpOutsideStack);
+    }
+    let flags_only = pstate & !NZCV == 0;
+    let el1 = pstate >> 32 == 0 && matches!(pstate & MODE, EL1T | EL1H);
+    if !flags_only && !el1 {
+        return fail(ContextProblem::BadPstate);
+    }
+    Ok(())
+}
+
+/// Address range of the kernel's code, from the linker script.
+pub fn kernel_text() -> Option<Range<usize>> {
+    #[cfg(target_arch = "aarch64")]
+    {
+        extern "C" {
+            static __text_start: u8;
+            static __text_end: u8;
+        }
+        // Only the addresses of the linker symbols are used
+        let (start, end) = unsafe {
+            (
+                core::ptr::addr_of!(__text_start) as usize,
+                core::ptr::addr_of!(__text_end) as usize,
+            )
+        };
+        Some(start..end)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread::ThreadId;
+
+    #[test]
+    fn test_corrupt_contexts_are_caught() {
+        let id = unsafe { ThreadId::new_unchecked(5) };
+        let text = || Some(0x8_0000..0x9_0000);
+        let stack = || Some(0x10_0000..0x10_4000);
+        let check =
+            |pc, sp, pstate| check(id, pc, sp, pstate, text(), stack()).map_err(|bad| bad.problem);
+
+        // Fresh thread, voluntary switch, interrupted thread
+        assert_eq!(check(0x8_1000, 0x10_4000, 0x3c5), Ok(()));
+        assert_eq!(check(0x8_1000, 0x10_3f00, 0x6000_0000), Ok(()));
+        assert_eq!(check(0x8_1000, 0x10_3f00, 0x2000_0005), Ok(()));
+
+        assert_eq!(
+            check(0x4141_4141, 0x10_4000, 0x3c5),
+            Err(ContextProblem::PcMisaligned)
+        );
+        assert_eq!(
+            check(0x4141_4140, 0x10_4000, 0x3c5),
+            Err(ContextProblem::PcOutsideText)
+        );
+        assert_eq!(
+            check(0x8_1000, 0x10_3ff8, 0x3c5),
+            Err(ContextProblem::SpMisaligned)
+        );
+        assert_eq!(
+            check(0x8_1000, 0x10_4010, 0x3c5),
+            Err(ContextProblem::SpOutsideStack)
+        );
+        // AArch32 user mode
+        assert_eq!(
+            check(0x8_1000, 0x10_4000, 0x10),
+            Err(ContextProblem::BadPstate)
+        );
+    }
+}
@@ -0,0 +1,279 @@
+//! Synthetic exceptions for on-target tests of the vector code.
+//!
+//! The IRQ and SVC entry paths are hand-written assembly that save and
+//! restore every register of the interrupted thread, and a mistake there
+//! shows up as a corrupted variable somewhere far away. With the
+//! `irq-inject` feature, a test thread can take those paths on purpose and
+//! check the result:
+//!
+//! - [`raise_timer_irq`] pends the scheduler timer interrupt at the GIC
+//!   and waits for it with known values in the caller-saved and
+//!   callee-saved registers. The tick may switch to other threads before
+//!   this one resumes, so it exercises the full save/switch/restore path.
+//! - [`raise_svc`] executes `svc` with known values in the argument and
+//!   callee-saved registers, and checks that the handler saw them in its
+//!   exception frame and that they survived the return.
+//!
+//! [`stats`] counts handler entries and exits, so a test can also assert
+//! that every interrupt taken ran to completion. Setting PPIs pending
+//! through the distributor is implementation defined on GICv2; QEMU's
+//! `virt` machine allows it.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::arch::inject;
+//!
+//! kernel.spawn(|| {
+//!     for _ in 0..1000 {
+//!         inject::raise_timer_irq().expect("IRQ path corrupted a register");
+//!         inject::raise_svc().expect("SVC path corrupted a register");
+//!     }
+//!     let stats = inject::stats();
+//!     assert_eq!(stats.irq_entries, stats.irq_exits);
+//! }, 128)?;
+//! ```
+
+// Only the counters are reachable off AArch64
+#![cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+
+use crate::errors::InjectError;
+use portable_atomic::{AtomicU64, Ordering};
+
+/// Immediate of the SVC [`raise_svc`] executes.
+pub const INJECT_SVC: u16 = 0x5A;
+
+/// Registers loaded with patterns: x0-x7 for arguments, x9-x15 scratch,
+/// x20-x27 callee-saved. x18/x19 are reserved, x29/x30 hold the frame and
+/// the rest are left for the wait loop's operands.
+const CHECKED: [u8; 23] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 9, 10, 11, 12, 13, 14, 15, 20, 21, 22, 23, 24, 25, 26, 27,
+];
+
+/// How long [`raise_timer_irq`] waits for the interrupt, in loop turns.
+const SPINS: u64 = 10_000_000;
+
+/// Exception counts since boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InjectStats {
+    /// IRQ handler entries, any line.
+    pub irq_entries: u64,
+    /// IRQ handler exits; equal to `irq_entries` outside IRQ context.
+    pub irq_exits: u64,
+    /// Scheduler timer interrupts taken.
+    pub timer_irqs: u64,
+    /// [`INJECT_SVC`] calls taken.
+    pub svcs: u64,
+}
+
+static IRQ_ENTRIES: AtomicU64 = AtomicU64::new(0);
+static IRQ_EXITS: AtomicU64 = AtomicU64::new(0);
+static TIMER_IRQS: AtomicU64 = AtomicU64::new(0);
+static SVCS: AtomicU64 = AtomicU64::new(0);
+/// x0-x30 as the SVC handler found them in its frame
+static SVC_FRAME: spin::Mutex<[u64; 31]> = spin::Mutex::new([0; 31]);
+
+/// Handler entry and exit counts.
+pub fn stats() -> InjectStats {
+    InjectStats {
+        irq_entries: IRQ_ENTRIES.load(Ordering::Acquire),
+        irq_exits: IRQ_EXITS.load(Ordering::Acquire),
+        timer_irqs: TIMER_IRQS.load(Ordering::Acquire),
+        svcs: SVCS.load(Ordering::Acquire),
+    }
+}
+
+/// The value `raise_*` loads into register `reg`.
+fn pattern(reg: u8) -> u64 {
+    0xA5C3_0000_0000_0000 ^ (reg as u64).wrapping_mul(0x0101_0101_0101_0101)
+}
+
+fn patterns() -> [u64; CHECKED.len()] {
+    let mut values = [0; CHECKED.len()];
+    for (value, &reg) in values.iter_mut().zip(CHECKED.iter()) {
+        *value = pattern(reg);
+    }
+    values
+}
+
+/// Check that `found[i]` holds the pattern of register `CHECKED[i]`.
+fn verify(found: &[u64; CHECKED.len()]) -> Result<(), InjectError> {
+    for (&reg, &found) in CHECKED.iter().zip(found.iter()) {
+        let expected = pattern(reg);
+        if found != expected {
+            return Err(InjectError::Corrupted {
+                reg,
+                expected,
+                found,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Called by the IRQ vector after acknowledging `irq`.
+pub(crate) fn on_irq_entry(irq: u32) {
+    IRQ_ENTRIES.fetch_add(1, Ordering::AcqRel);
+    #[cfg(target_arch = "aarch64")]
+    if irq == super::aarch64_gic::TIMER_IRQ {
+        TIMER_IRQS.fetch_add(1, Ordering::AcqRel);
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    let _ = irq;
+}
+
+/// Called by the IRQ vector before signalling end of interrupt.
+pub(crate) fn on_irq_exit() {
+    IRQ_EXITS.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Called by the synchronous exception handler for every SVC.
+#[cfg(target_arch = "aarch64")]
+pub(crate) fn on_svc(ctx: &super::aarch64_vectors::ExceptionContext) {
+    if ctx.esr & 0xFFFF != INJECT_SVC as u64 {
+        return;
+    }
+    // The caller waits for the count, so the frame is never contended
+    if let Some(mut frame) = SVC_FRAME.try_lock() {
+        *frame = ctx.x;
+    }
+    SVCS.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Take the timer interrupt with every checked register holding a known
+/// value, and check they all still do afterwards.
+///
+/// Must be called from a thread with interrupts enabled.
+#[cfg(target_arch = "aarch64")]
+pub fn raise_timer_irq() -> Result<(), InjectError> {
+    use super::aarch64_gic::{Gic400, TIMER_IRQ};
+    use super::{Arch, DefaultArch};
+
+    if !DefaultArch::interrupts_enabled() || crate::irq::in_irq() {
+        return Err(InjectError::InterruptsMasked);
+    }
+    // Masked until the registers are loaded, so the IRQ can't come early
+    DefaultArch::disable_interrupts();
+    let before = TIMER_IRQS.load(Ordering::Acquire);
+    unsafe { Gic400::set_pending(TIMER_IRQ) };
+
+    let p = patterns();
+    let mut r = [0u64; CHECKED.len()];
+    let remaining: u64;
+    unsafe {
+        core::arch::asm!(
+            "msr daifclr, #2",
+            "2:",
+            "ldr {seen}, [{counter}]",
+            "cmp {seen}, {before}",
+            "b.ne 3f",
+            "subs {spins}, {spins}, #1",
+            "b.ne 2b",
+            "3:",
+            "msr daifset, #2",
+            counter = in(reg) TIMER_IRQS.as_ptr(),
+            before = in(reg) before,
+            seen = out(reg) _,
+            spins = inout(reg) SPINS => remaining,
+            inout("x0") p[0] => r[0], inout("x1") p[1] => r[1],
+            inout("x2") p[2] => r[2], inout("x3") p[3] => r[3],
+            inout("x4") p[4] => r[4], inout("x5") p[5] => r[5],
+            inout("x6") p[6] => r[6], inout("x7") p[7] => r[7],
+            inout("x9") p[8] => r[8], inout("x10") p[9] => r[9],
+            inout("x11") p[10] => r[10], inout("x12") p[11] => r[11],
+            inout("x13") p[12] => r[12], inout("x14") p[13] => r[13],
+            inout("x15") p[14] => r[14], inout("x20") p[15] => r[15],
+            inout("x21") p[16] => r[16], inout("x22") p[17] => r[17],
+            inout("x23") p[18] => r[18], inout("x24") p[19] => r[19],
+            inout("x25") p[20] => r[20], inout("x26") p[21] => r[21],
+            inout("x27") p[22] => r[22],
+            options(nostack)
+        );
+    }
+    DefaultArch::enable_interrupts();
+
+    if remaining == 0 {
+        return Err(InjectError::NotDelivered);
+    }
+    verify(&r)
+}
+
+/// Execute [`INJECT_SVC`] with every checked register holding a known
+/// value, and check the handler's frame and the registers after return.
+#[cfg(target_arch = "aarch64")]
+pub fn raise_svc() -> Result<(), InjectError> {
+    let before = SVCS.load(Ordering::Acquire);
+    let p = patterns();
+    let mut r = [0u64; CHECKED.len()];
+    unsafe {
+        core::arch::asm!(
+            "svc #0x5A", // INJECT_SVC
+            inout("x0") p[0] => r[0], inout("x1") p[1] => r[1],
+            inout("x2") p[2] => r[2], inout("x3") p[3] => r[3],
+            inout("x4") p[4] => r[4], inout("x5") p[5] => r[5],
+            inout("x6") p[6] => r[6], inout("x7") p[7] => r[7],
+            inout("x9") p[8] => r[8], inout("x10") p[9] => r[9],
+            inout("x11") p[10] => r[10], inout("x12") p[11] => r[11],
+            inout("x13") p[12] => r[12], inout("x14") p[13] => r[13],
+            inout("x15") p[14] => r[14], inout("x20") p[15] => r[15],
+            inout("x21") p[16] => r[16], inout("x22") p[17] => r[17],
+            inout("x23") p[18] => r[18], inout("x24") p[19] => r[19],
+            inout("x25") p[20] => r[20], inout("x26") p[21] => r[21],
+            inout("x27") p[22] => r[22],
+        );
+    }
+
+    if SVCS.load(Ordering::Acquire) == before {
+        return Err(InjectError::NotDelivered);
+    }
+    // What the handler saw, then what came back
+    let frame = *SVC_FRAME.lock();
+    let mut seen = [0u64; CHECKED.len()];
+    for (value, &reg) in seen.iter_mut().zip(CHECKED.iter()) {
+        *value = frame[reg as usize];
+    }
+    verify(&seen)?;
+    verify(&r)
+}
+
+/// There are no vectors to exercise off AArch64.
+#[cfg(not(target_arch = "aarch64"))]
+pub fn raise_timer_irq() -> Result<(), InjectError> {
+    Err(InjectError::Unsupported)
+}
+
+/// There are no vectors to exercise off AArch64.
+#[cfg(not(target_arch = "aarch64"))]
+pub fn raise_svc() -> Result<(), InjectError> {
+    Err(InjectError::Unsupported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_names_corrupted_register() {
+        let mut values = patterns();
+        assert_eq!(verify(&values), Ok(()));
+        values[16] ^= 1;
+        assert_eq!(
+            verify(&values),
+            Err(InjectError::Corrupted {
+                reg: 21,
+                expected: pattern(21),
+                found: pattern(21) ^ 1
+            })
+        );
+
+        let before = stats();
+        on_irq_entry(200);
+        on_irq_exit();
+        let after = stats();
+        assert_eq!(
+            after.irq_entries - before.irq_entries,
+            after.irq_exits - before.irq_exits
+        );
+        assert_eq!(raise_svc(), Err(InjectError::Unsupported));
+    }
+}
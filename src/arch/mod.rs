@@ -23,6 +23,10 @@ pub trait Arch {
     /// restore a thread's execution context.
     type SavedContext: Send + Sync + Default;
 
+    /// Bytes a saved context takes in each thread's record; see
+    /// [`footprint`](crate::kernel::footprint).
+    const CONTEXT_SIZE: usize = core::mem::size_of::<Self::SavedContext>();
+
     /// Switch from one thread context to another.
     ///
     /// # Safety
@@ -71,6 +75,27 @@ pub trait Arch {
     ///
     /// Returns `true` if interrupts are enabled, `false` otherwise.
     fn interrupts_enabled() -> bool;
+
+    /// Put the CPU into a low-power state until the next interrupt arrives.
+    ///
+    /// Used when no thread is runnable. The default implementation just
+    /// issues a spin-loop hint.
+    fn wait_for_interrupt() {
+        core::hint::spin_loop();
+    }
+
+    /// Make FPU and SIMD instructions trap (`true`) or execute normally.
+    ///
+    /// Set on every switch so that threads spawned with
+    /// [`no_fpu`](crate::ThreadBuilder::no_fpu) are caught using the FPU.
+    /// The default implementation does nothing.
+    fn set_fpu_trap(_trap: bool) {}
+
+    /// Whether FPU and SIMD instructions currently trap. The default
+    /// implementation never traps.
+    fn fpu_trapped() -> bool {
+        false
+    }
 }
 
 /// A no-op architecture implementation for testing and fallback purposes.
@@ -88,7 +113,7 @@ impl Arch for NoOpArch {
 
     #[cfg(feature = "full-fpu")]
     unsafe fn save_fpu(_ctx: &mut Self::SavedContext) {
-        // No-op for testing  
+        // No-op for testing
     }
 
     #[cfg(feature = "full-fpu")]
@@ -116,16 +141,16 @@ pub mod aarch64;
 #[path = "aarch64_stub.rs"]
 pub mod aarch64;
 
-
-
 // RPi Zero 2 W specific hardware support
 #[cfg(target_arch = "aarch64")]
+pub mod aarch64_boot;
+#[cfg(target_arch = "aarch64")]
 pub mod aarch64_gic;
 #[cfg(target_arch = "aarch64")]
 pub mod aarch64_vectors;
-#[cfg(target_arch = "aarch64")]
-pub mod aarch64_boot;
-#[cfg(target_arch = "aarch64")]
+pub mod context_check;
+#[cfg(feature = "irq-inject")]
+pub mod inject;
 pub mod uart_pl011;
 
 // Always use AArch64 - single target (Raspberry Pi Zero 2 W)
@@ -136,6 +161,73 @@ pub use aarch64::Aarch64Arch as DefaultArch;
 #[cfg(all(not(target_arch = "aarch64"), feature = "std-shim"))]
 pub use NoOpArch as DefaultArch;
 
+/// Run `f` with interrupts disabled on the current CPU.
+///
+/// The previous interrupt state is restored afterwards, so this nests
+/// correctly inside other critical sections.
+#[track_caller]
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let was_enabled = DefaultArch::interrupts_enabled();
+    DefaultArch::disable_interrupts();
+    let result = f();
+    if was_enabled {
+        DefaultArch::enable_interrupts();
+    }
+    result
+}
+
+/// Read the current stack pointer.
+#[inline(always)]
+pub fn stack_pointer() -> usize {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let sp: usize;
+        unsafe {
+            core::arch::asm!("mov {}, sp", out(reg) sp, options(nomem, nostack, preserves_flags));
+        }
+        sp
+    }
+
+    // Close enough for depth checks: a local lives in the caller's frame
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        let marker = 0u8;
+        core::ptr::addr_of!(marker) as usize
+    }
+}
+
+/// Number of the calling CPU: affinity level 0 of `MPIDR_EL1`, which is
+/// the core number on the Cortex-A53 clusters this runs on.
+#[inline(always)]
+pub fn cpu_id() -> usize {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let mpidr: u64;
+        unsafe {
+            core::arch::asm!("mrs {}, mpidr_el1", out(reg) mpidr, options(nomem, nostack, preserves_flags));
+        }
+        (mpidr & 0xFF) as usize
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        0
+    }
+}
+
+/// Ask the CPUs in `cpu_mask` (bit n = CPU n) to run their scheduler.
+pub fn send_reschedule_ipi(cpu_mask: u32) {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        aarch64_gic::Gic400::send_sgi(aarch64_gic::RESCHEDULE_SGI, cpu_mask as u8);
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        let _ = cpu_mask;
+    }
+}
+
 // Compile error for unsupported configurations
 #[cfg(all(not(target_arch = "aarch64"), not(feature = "std-shim")))]
-compile_error!("This library only supports Raspberry Pi Zero 2 W (aarch64). Use --target aarch64-unknown-none or enable std-shim feature for testing.");
\ No newline at end of file
+compile_error!("This library only supports Raspberry Pi Zero 2 W (aarch64). Use --target aarch64-unknown-none or enable std-shim feature for testing.");
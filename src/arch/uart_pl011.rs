@@ -8,9 +8,31 @@
 //! - **QEMU virt machine**: PL011 @ 0x09000000
 //!
 //! Use the `qemu-virt` feature to target the virt machine.
+//!
+//! # Receive
+//!
+//! After [`init_rx`], received bytes are moved from the RX FIFO into a ring
+//! buffer by the UART interrupt handler. Threads read them with [`read_byte`]
+//! and [`read_line`], which block on a wait queue until data arrives.
+//!
+//! # Transmit
+//!
+//! Output is written byte by byte, spinning while the 32-byte TX FIFO is
+//! full, until [`init_tx`] switches to buffered transmission: writers then
+//! copy into a [`TX_BUFFER_SIZE`] ring and return, and the UART interrupt
+//! refills the FIFO each time it drains below 1/8. A writer that finds the
+//! ring full blocks until the interrupt has made room, or, with interrupts
+//! disabled or in an interrupt handler, feeds the FIFO itself. [`tx_stats`]
+//! reports how much went through and how often writers had to wait.
 
+use crate::arch::{Arch, DefaultArch};
+use crate::errors::ArchError;
+use crate::sync::{SpscRing, WaitQueue};
 use core::fmt::{self, Write};
-use core::ptr::{read_volatile, write_volatile};
+#[cfg(any(target_arch = "aarch64", not(feature = "qemu-virt")))]
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+use portable_atomic::{AtomicBool, AtomicUsize, Ordering};
 
 // Platform-dependent UART base address
 #[cfg(feature = "qemu-virt")]
@@ -20,13 +42,23 @@ const UART0_BASE: usize = 0x0900_0000; // QEMU virt PL011
 const UART0_BASE: usize = 0x3F20_1000; // BCM2837 PL011
 
 // PL011 UART registers (offsets from base)
-const UART0_DR: usize = UART0_BASE;     // Data Register
-const UART0_FR: usize = UART0_BASE + 0x18;     // Flag Register
-const UART0_IBRD: usize = UART0_BASE + 0x24;   // Integer Baud Rate Divisor
-const UART0_FBRD: usize = UART0_BASE + 0x28;   // Fractional Baud Rate Divisor
-const UART0_LCRH: usize = UART0_BASE + 0x2C;   // Line Control Register
-const UART0_CR: usize = UART0_BASE + 0x30;     // Control Register
-const UART0_ICR: usize = UART0_BASE + 0x44;    // Interrupt Clear Register
+const UART0_DR: usize = UART0_BASE; // Data Register
+const UART0_FR: usize = UART0_BASE + 0x18; // Flag Register
+const UART0_IBRD: usize = UART0_BASE + 0x24; // Integer Baud Rate Divisor
+const UART0_FBRD: usize = UART0_BASE + 0x28; // Fractional Baud Rate Divisor
+const UART0_LCRH: usize = UART0_BASE + 0x2C; // Line Control Register
+const UART0_CR: usize = UART0_BASE + 0x30; // Control Register
+const UART0_IFLS: usize = UART0_BASE + 0x34; // Interrupt FIFO Level Select
+const UART0_IMSC: usize = UART0_BASE + 0x38; // Interrupt Mask Set/Clear
+const UART0_MIS: usize = UART0_BASE + 0x40; // Masked Interrupt Status
+const UART0_ICR: usize = UART0_BASE + 0x44; // Interrupt Clear Register
+
+/// PL011 UART interrupt line
+#[cfg(feature = "qemu-virt")]
+pub const UART_IRQ: u32 = 33; // SPI 1 on QEMU virt
+/// PL011 UART interrupt line
+#[cfg(not(feature = "qemu-virt"))]
+pub const UART_IRQ: u32 = 153; // VideoCore IRQ 57 on the GIC-400 SPI map
 
 // GPIO registers for pin configuration (only used on real Pi)
 #[cfg(not(feature = "qemu-virt"))]
@@ -34,16 +66,77 @@ const PERIPHERAL_BASE: usize = 0x3F00_0000;
 #[cfg(not(feature = "qemu-virt"))]
 const GPIO_BASE: usize = PERIPHERAL_BASE + 0x20_0000;
 #[cfg(not(feature = "qemu-virt"))]
-const GPFSEL1: usize = GPIO_BASE + 0x04;       // GPIO Function Select 1 (pins 10-19)
+const GPFSEL1: usize = GPIO_BASE + 0x04; // GPIO Function Select 1 (pins 10-19)
 #[cfg(not(feature = "qemu-virt"))]
-const GPPUD: usize = GPIO_BASE + 0x94;         // GPIO Pull-up/down Enable
+const GPPUD: usize = GPIO_BASE + 0x94; // GPIO Pull-up/down Enable
 #[cfg(not(feature = "qemu-virt"))]
-const GPPUDCLK0: usize = GPIO_BASE + 0x98;     // GPIO Pull-up/down Clock 0
+const GPPUDCLK0: usize = GPIO_BASE + 0x98; // GPIO Pull-up/down Clock 0
 
 // Flag register bits
-const FR_TXFF: u32 = 1 << 5;  // Transmit FIFO full
-#[allow(dead_code)] // Reserved for future RX support
-const FR_RXFE: u32 = 1 << 4;  // Receive FIFO empty
+const FR_TXFF: u32 = 1 << 5; // Transmit FIFO full
+const FR_RXFE: u32 = 1 << 4; // Receive FIFO empty
+
+// Interrupt bits (IMSC / ICR)
+const INT_RX: u32 = 1 << 4; // Receive
+const INT_TX: u32 = 1 << 5; // Transmit
+const INT_RT: u32 = 1 << 6; // Receive timeout
+const INT_OE: u32 = 1 << 10; // Overrun error
+
+/// Size of the software receive buffer in bytes.
+pub const RX_BUFFER_SIZE: usize = 256;
+
+static RX_RING: SpscRing<u8, RX_BUFFER_SIZE> = SpscRing::new();
+static RX_WAITERS: WaitQueue = WaitQueue::new();
+/// Serializes consumers so the ring keeps a single reader.
+static RX_READER: spin::Mutex<()> = spin::Mutex::new(());
+static RX_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Size of the software transmit buffer in bytes.
+pub const TX_BUFFER_SIZE: usize = 4096;
+
+static TX_RING: SpscRing<u8, TX_BUFFER_SIZE> = SpscRing::new();
+static TX_WAITERS: WaitQueue = WaitQueue::new();
+/// Serializes producers so the ring keeps a single writer.
+static TX_WRITER: spin::Mutex<()> = spin::Mutex::new(());
+/// Set by [`init_tx`]; writers go through the ring while it is.
+static TX_BUFFERED: AtomicBool = AtomicBool::new(false);
+static TX_BYTES: AtomicUsize = AtomicUsize::new(0);
+static TX_INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+static TX_STALLS: AtomicUsize = AtomicUsize::new(0);
+static TX_HIGH_WATER: AtomicUsize = AtomicUsize::new(0);
+
+/// Read a UART register.
+#[inline]
+fn read_reg(addr: usize) -> u32 {
+    #[cfg(target_arch = "aarch64")]
+    {
+        unsafe { read_volatile(addr as *const u32) }
+    }
+
+    // Host builds have no PL011: present an idle UART (TX ready, RX empty)
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        if addr == UART0_FR {
+            FR_RXFE
+        } else {
+            0
+        }
+    }
+}
+
+/// Write a UART register.
+#[inline]
+fn write_reg(addr: usize, value: u32) {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        write_volatile(addr as *mut u32, value);
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        let _ = (addr, value);
+    }
+}
 
 /// Initialize the PL011 UART for 115200 baud output.
 ///
@@ -87,14 +180,14 @@ pub unsafe fn init() {
         // Note: QEMU doesn't care about baud rate, but real hardware needs correct values
         // For 3MHz base clock (QEMU default): 3000000 / (16 * 115200) = 1.627
         // Just use values that work on QEMU
-        write_volatile(UART0_IBRD as *mut u32, 1);   // Integer divisor
-        write_volatile(UART0_FBRD as *mut u32, 40);  // Fractional divisor
+        write_volatile(UART0_IBRD as *mut u32, 1); // Integer divisor
+        write_volatile(UART0_FBRD as *mut u32, 40); // Fractional divisor
 
         // 8 bits, no parity, 1 stop bit, enable FIFOs
-        write_volatile(UART0_LCRH as *mut u32, (1 << 4) | (1 << 5) | (1 << 6));  // WLEN=8, FEN=1
+        write_volatile(UART0_LCRH as *mut u32, (1 << 4) | (1 << 5) | (1 << 6)); // WLEN=8, FEN=1
 
         // Enable UART0, TX, and RX
-        write_volatile(UART0_CR as *mut u32, (1 << 0) | (1 << 8) | (1 << 9));  // UARTEN, TXE, RXE
+        write_volatile(UART0_CR as *mut u32, (1 << 0) | (1 << 8) | (1 << 9)); // UARTEN, TXE, RXE
     }
 }
 
@@ -111,20 +204,237 @@ fn delay_cycles(count: u32) {
 #[inline]
 fn can_transmit() -> bool {
     // FR_TXFF is set when FIFO is full, so we can transmit when it's NOT set
-    unsafe { (read_volatile(UART0_FR as *const u32) & FR_TXFF) == 0 }
+    (read_reg(UART0_FR) & FR_TXFF) == 0
 }
 
 /// Send a single byte over UART.
 pub fn send_byte(byte: u8) {
+    if TX_BUFFERED.load(Ordering::Acquire) {
+        queue_byte(byte);
+        return;
+    }
+
     // Wait until transmitter is ready
     while !can_transmit() {
         core::hint::spin_loop();
     }
-    unsafe {
-        write_volatile(UART0_DR as *mut u32, byte as u32);
+    write_reg(UART0_DR, byte as u32);
+    TX_BYTES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Append a byte to the transmit ring, waiting for room if it is full.
+fn queue_byte(byte: u8) {
+    loop {
+        let queued = crate::arch::without_interrupts(|| {
+            let _writer = TX_WRITER.lock();
+            if !TX_RING.push(byte) {
+                return false;
+            }
+            TX_HIGH_WATER.fetch_max(TX_RING.len(), Ordering::Relaxed);
+            // The TX interrupt only fires when the FIFO level drops past the
+            // threshold, so an idle FIFO has to be primed from here
+            fill_fifo();
+            true
+        });
+        if queued {
+            return;
+        }
+
+        TX_STALLS.fetch_add(1, Ordering::Relaxed);
+        if crate::irq::in_irq() || !DefaultArch::interrupts_enabled() {
+            // Nobody else will drain the ring: do it by hand
+            crate::arch::without_interrupts(|| {
+                while !can_transmit() {
+                    core::hint::spin_loop();
+                }
+                fill_fifo();
+            });
+        } else {
+            TX_WAITERS.wait_until(|| TX_RING.len() < TX_BUFFER_SIZE);
+        }
+    }
+}
+
+/// Move bytes from the transmit ring into the FIFO until one runs out.
+///
+/// The ring's only consumer: callers run with interrupts disabled.
+fn fill_fifo() {
+    let mut sent = 0;
+    while can_transmit() {
+        match TX_RING.pop() {
+            Some(byte) => write_reg(UART0_DR, byte as u32),
+            None => break,
+        }
+        sent += 1;
+    }
+    if sent != 0 {
+        TX_BYTES.fetch_add(sent, Ordering::Relaxed);
+    }
+}
+
+/// Enable buffered, interrupt-driven transmission.
+///
+/// Registers the UART interrupt handler (shared with [`init_rx`]), raises
+/// the TX interrupt when the FIFO drains to 1/8 full and routes all output
+/// through the transmit ring from then on.
+///
+/// # Safety
+///
+/// Must be called after [`init`] and after the GIC has been initialized.
+pub unsafe fn init_tx() -> Result<(), ArchError> {
+    crate::irq::register_handler(UART_IRQ, handle_interrupt)?;
+
+    // TXIFLSEL = 0b000: interrupt at 1/8 full (RX level bits left alone)
+    write_reg(UART0_IFLS, read_reg(UART0_IFLS) & !0b111);
+    write_reg(UART0_ICR, INT_TX);
+    write_reg(UART0_IMSC, read_reg(UART0_IMSC) | INT_TX);
+    TX_BUFFERED.store(true, Ordering::Release);
+
+    crate::irq::enable(UART_IRQ)
+}
+
+/// Drain the transmit ring with interrupts disabled, spinning on the FIFO.
+///
+/// For paths that must not lose output, such as the panic handler.
+pub fn flush() {
+    crate::arch::without_interrupts(|| {
+        while !TX_RING.is_empty() {
+            fill_fifo();
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// Go back to unbuffered output after writing out whatever is queued.
+pub fn stop_tx() {
+    TX_BUFFERED.store(false, Ordering::Release);
+    write_reg(UART0_IMSC, read_reg(UART0_IMSC) & !INT_TX);
+    flush();
+}
+
+/// Transmit throughput counters, see [`tx_stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxStats {
+    /// Bytes written to the TX FIFO.
+    pub bytes: usize,
+    /// TX interrupts handled.
+    pub interrupts: usize,
+    /// Times a writer found the ring full and had to wait.
+    pub stalls: usize,
+    /// Bytes waiting in the ring now.
+    pub queued: usize,
+    /// Most bytes ever waiting in the ring.
+    pub high_water: usize,
+}
+
+/// Snapshot of the transmit counters.
+pub fn tx_stats() -> TxStats {
+    TxStats {
+        bytes: TX_BYTES.load(Ordering::Relaxed),
+        interrupts: TX_INTERRUPTS.load(Ordering::Relaxed),
+        stalls: TX_STALLS.load(Ordering::Relaxed),
+        queued: TX_RING.len(),
+        high_water: TX_HIGH_WATER.load(Ordering::Relaxed),
+    }
+}
+
+/// Enable interrupt-driven reception.
+///
+/// Registers the UART interrupt handler, unmasks the receive and receive
+/// timeout interrupts in the UART and enables the line at the GIC.
+///
+/// # Safety
+///
+/// Must be called after [`init`] and after the GIC has been initialized.
+pub unsafe fn init_rx() -> Result<(), ArchError> {
+    crate::irq::register_handler(UART_IRQ, handle_interrupt)?;
+
+    // Raise the RX interrupt when the FIFO is 1/8 full; the receive timeout
+    // interrupt covers bytes that arrive below that threshold.
+    write_reg(UART0_IFLS, 0);
+    write_reg(UART0_ICR, INT_RX | INT_RT | INT_OE);
+    write_reg(UART0_IMSC, read_reg(UART0_IMSC) | INT_RX | INT_RT);
+
+    crate::irq::enable(UART_IRQ)
+}
+
+/// UART interrupt handler: drain the RX FIFO into the receive ring and
+/// refill the TX FIFO from the transmit ring.
+fn handle_interrupt(_irq: u32) {
+    let status = read_reg(UART0_MIS);
+    if status & INT_TX != 0 {
+        TX_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+        // Clear first: refilling above the threshold keeps it from re-asserting
+        write_reg(UART0_ICR, INT_TX);
+        fill_fifo();
+        if !TX_WAITERS.is_empty() {
+            TX_WAITERS.notify_all();
+        }
+    }
+    if status & (INT_RX | INT_RT | INT_OE) == 0 {
+        return;
+    }
+
+    while (read_reg(UART0_FR) & FR_RXFE) == 0 {
+        let byte = (read_reg(UART0_DR) & 0xFF) as u8;
+        receive_byte(byte);
+    }
+
+    write_reg(UART0_ICR, INT_RX | INT_RT | INT_OE);
+    RX_WAITERS.notify_all();
+}
+
+/// Buffer a received byte, counting it as dropped if the ring is full.
+fn receive_byte(byte: u8) {
+    if !RX_RING.push(byte) {
+        RX_DROPPED.fetch_add(1, Ordering::Relaxed);
     }
 }
 
+/// Read a received byte without blocking.
+pub fn try_read_byte() -> Option<u8> {
+    let _reader = RX_READER.lock();
+    RX_RING.pop()
+}
+
+/// Read a received byte, blocking the calling thread until one arrives.
+pub fn read_byte() -> u8 {
+    loop {
+        RX_WAITERS.wait_until(|| !RX_RING.is_empty());
+
+        // Another reader may have taken the byte first
+        if let Some(byte) = try_read_byte() {
+            return byte;
+        }
+    }
+}
+
+/// Read a line into `buf`, blocking until a line terminator arrives.
+///
+/// Both `\r` and `\n` terminate the line and are not stored, so a `\r\n`
+/// pair yields an extra empty line. Reading also stops when `buf` is full.
+///
+/// # Returns
+///
+/// The number of bytes stored in `buf`.
+pub fn read_line(buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    while len < buf.len() {
+        let byte = read_byte();
+        if byte == b'\r' || byte == b'\n' {
+            break;
+        }
+        buf[len] = byte;
+        len += 1;
+    }
+    len
+}
+
+/// Number of received bytes discarded because the receive buffer was full.
+pub fn rx_dropped() -> usize {
+    RX_DROPPED.load(Ordering::Relaxed)
+}
+
 /// Send a string over UART.
 pub fn send_str(s: &str) {
     for byte in s.bytes() {
@@ -141,6 +451,8 @@ pub struct UartWriter;
 impl Write for UartWriter {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         send_str(s);
+        #[cfg(feature = "fb-console")]
+        crate::drivers::framebuffer::console::mirror_log(s);
         Ok(())
     }
 }
@@ -165,3 +477,37 @@ macro_rules! pl011_println {
         $crate::pl011_print!("\n");
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rx_ring_line_reader() {
+        for &byte in b"ps\rx" {
+            receive_byte(byte);
+        }
+
+        let mut buf = [0u8; 16];
+        let len = read_line(&mut buf);
+        assert_eq!(&buf[..len], b"ps");
+
+        assert_eq!(try_read_byte(), Some(b'x'));
+        assert_eq!(try_read_byte(), None);
+        assert_eq!(rx_dropped(), 0);
+    }
+
+    #[test]
+    fn test_buffered_tx_drains_through_fifo() {
+        unsafe { init_tx().unwrap() };
+        let before = tx_stats();
+        send_str("ok\n");
+        flush();
+
+        let after = tx_stats();
+        assert_eq!(after.queued, 0);
+        assert!(after.high_water >= 1);
+        assert!(after.bytes - before.bytes >= 4);
+        stop_tx();
+    }
+}
@@ -0,0 +1,50 @@
+//! Block device abstraction shared by storage drivers and filesystems.
+
+use crate::errors::DeviceError;
+
+/// Size of one block in bytes.
+pub const BLOCK_SIZE: usize = 512;
+
+/// A device addressed in fixed-size [`BLOCK_SIZE`] blocks.
+///
+/// Buffers passed to the transfer methods must be a whole number of blocks
+/// long; the transfer covers `buf.len() / BLOCK_SIZE` consecutive blocks
+/// starting at `lba`.
+pub trait BlockDevice: Send + Sync {
+    /// Read blocks starting at `lba` into `buf`.
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError>;
+
+    /// Write `buf` to blocks starting at `lba`.
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), DeviceError>;
+
+    /// Number of blocks on the device.
+    fn block_count(&self) -> u64;
+}
+
+/// Check that a transfer of `len` bytes at `lba` is whole blocks and lies
+/// within a device of `block_count` blocks.
+#[cfg_attr(not(feature = "sdhost"), allow(dead_code))]
+pub(crate) fn check_transfer(lba: u64, len: usize, block_count: u64) -> Result<u64, DeviceError> {
+    if len % BLOCK_SIZE != 0 {
+        return Err(DeviceError::InvalidArgument);
+    }
+    let blocks = (len / BLOCK_SIZE) as u64;
+    match lba.checked_add(blocks) {
+        Some(end) if end <= block_count => Ok(blocks),
+        _ => Err(DeviceError::InvalidArgument),
+    }
+}
+
+impl<T: BlockDevice + ?Sized> BlockDevice for &T {
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        (**self).read_blocks(lba, buf)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        (**self).write_blocks(lba, buf)
+    }
+
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+}
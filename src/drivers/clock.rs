@@ -0,0 +1,125 @@
+//! Clock manager for the peripheral clocks (general purpose and PWM).
+
+use super::{mmio_read, mmio_write, PERIPHERAL_BASE};
+use crate::errors::DeviceError;
+
+const CM_BASE: usize = PERIPHERAL_BASE + 0x10_1000;
+
+/// Every clock manager write must carry this password.
+const CM_PASSWD: u32 = 0x5A << 24;
+
+// Clock control register bits
+const CTL_ENAB: u32 = 1 << 4;
+const CTL_KILL: u32 = 1 << 5;
+const CTL_BUSY: u32 = 1 << 7;
+
+/// Polls of the BUSY flag before giving up on a clock stopping.
+const BUSY_TIMEOUT_SPINS: u32 = 100_000;
+
+/// Largest integer divisor (12-bit DIVI field).
+pub const MAX_DIVISOR: u32 = 4095;
+
+/// A peripheral clock generated by the clock manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    /// General purpose clock 0 (GPIO 4)
+    Gp0,
+    /// General purpose clock 1
+    Gp1,
+    /// General purpose clock 2
+    Gp2,
+    /// PWM peripheral clock
+    Pwm,
+}
+
+impl Clock {
+    fn ctl(self) -> usize {
+        CM_BASE
+            + match self {
+                Clock::Gp0 => 0x70,
+                Clock::Gp1 => 0x78,
+                Clock::Gp2 => 0x80,
+                Clock::Pwm => 0xA0,
+            }
+    }
+
+    fn div(self) -> usize {
+        self.ctl() + 4
+    }
+}
+
+/// Clock source feeding a peripheral clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// 19.2 MHz crystal oscillator
+    Oscillator,
+    /// 500 MHz PLLD
+    PllD,
+}
+
+impl Source {
+    /// Source frequency in Hz.
+    pub const fn frequency_hz(self) -> u32 {
+        match self {
+            Source::Oscillator => 19_200_000,
+            Source::PllD => 500_000_000,
+        }
+    }
+
+    fn bits(self) -> u32 {
+        match self {
+            Source::Oscillator => 1,
+            Source::PllD => 6,
+        }
+    }
+}
+
+/// Run `clock` from `source` divided by the integer `divisor`.
+pub fn configure(clock: Clock, source: Source, divisor: u32) -> Result<(), DeviceError> {
+    if !(2..=MAX_DIVISOR).contains(&divisor) {
+        return Err(DeviceError::InvalidArgument);
+    }
+
+    // The divisor may only be changed while the generator is stopped
+    stop(clock)?;
+    mmio_write(clock.div(), CM_PASSWD | (divisor << 12));
+    mmio_write(clock.ctl(), CM_PASSWD | source.bits());
+    mmio_write(clock.ctl(), CM_PASSWD | source.bits() | CTL_ENAB);
+    Ok(())
+}
+
+/// Run `clock` from `source` at the closest frequency at or above `hz`.
+///
+/// # Returns
+///
+/// The frequency actually produced.
+pub fn configure_frequency(clock: Clock, source: Source, hz: u32) -> Result<u32, DeviceError> {
+    let divisor = divisor_for(source, hz).ok_or(DeviceError::InvalidArgument)?;
+    configure(clock, source, divisor)?;
+    Ok(source.frequency_hz() / divisor)
+}
+
+/// Stop a clock generator and wait for it to go idle.
+pub fn stop(clock: Clock) -> Result<(), DeviceError> {
+    let ctl = mmio_read(clock.ctl()) & 0xFF;
+    mmio_write(clock.ctl(), CM_PASSWD | (ctl & !CTL_ENAB));
+
+    for _ in 0..BUSY_TIMEOUT_SPINS {
+        if mmio_read(clock.ctl()) & CTL_BUSY == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+
+    // A generator that won't stop can be killed, at the cost of a glitch
+    mmio_write(clock.ctl(), CM_PASSWD | CTL_KILL);
+    Err(DeviceError::Timeout)
+}
+
+fn divisor_for(source: Source, hz: u32) -> Option<u32> {
+    if hz == 0 {
+        return None;
+    }
+    let divisor = source.frequency_hz() / hz;
+    (2..=MAX_DIVISOR).contains(&divisor).then_some(divisor)
+}
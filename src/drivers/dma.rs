@@ -0,0 +1,407 @@
+//! BCM2837 DMA controller driver.
+//!
+//! The DMA engine walks a chain of [`ControlBlock`]s, each describing one
+//! transfer between memory and memory or between memory and a peripheral
+//! FIFO, paced by the peripheral's DREQ line. A thread claims a [`Channel`],
+//! hands it a chain with [`Channel::transfer`] and blocks on a wait queue
+//! until the channel's completion interrupt wakes it; the CPU is free for
+//! other threads meanwhile.
+//!
+//! Only the channels the firmware leaves to the ARM ([`ARM_CHANNELS`]) are
+//! handed out. Like the [mailbox](super::mailbox), buffers are given to the
+//! controller through the GPU's uncached alias, so they must not sit dirty
+//! in a data cache the DMA engine cannot see.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::drivers::dma;
+//!
+//! dma::init()?;
+//! let mut channel = dma::request_channel()?;
+//! channel.copy(&mut frame_back, &frame_front)?;
+//! ```
+
+use super::{mmio_read, mmio_write, IrqEvent, PERIPHERAL_BASE, VC_IRQ_BASE};
+use crate::errors::{DeviceError, ThreadError};
+use portable_atomic::{AtomicBool, AtomicU16, Ordering};
+
+const DMA_BASE: usize = PERIPHERAL_BASE + 0x7000;
+const CHANNEL_STRIDE: usize = 0x100;
+const INT_STATUS: usize = DMA_BASE + 0xFE0; // Interrupt status of each channel
+const ENABLE: usize = DMA_BASE + 0xFF0; // Global enable bits
+
+// Channel registers (offsets from channel base)
+const CS: usize = 0x00; // Control and Status
+const CONBLK_AD: usize = 0x04; // Control Block Address
+const DEBUG: usize = 0x20; // Debug
+
+// Control and status bits
+const CS_ACTIVE: u32 = 1 << 0;
+const CS_END: u32 = 1 << 1;
+const CS_INT: u32 = 1 << 2;
+const CS_ERROR: u32 = 1 << 8;
+const CS_WAIT_FOR_OUTSTANDING_WRITES: u32 = 1 << 28;
+const CS_ABORT: u32 = 1 << 30;
+const CS_RESET: u32 = 1 << 31;
+
+/// Read last not set, FIFO and slave read errors (write 1 to clear)
+const DEBUG_ERRORS: u32 = 0b111;
+
+// Transfer information bits
+const TI_INTEN: u32 = 1 << 0;
+const TI_WAIT_RESP: u32 = 1 << 3;
+const TI_DEST_INC: u32 = 1 << 4;
+const TI_DEST_WIDTH: u32 = 1 << 5;
+const TI_DEST_DREQ: u32 = 1 << 6;
+const TI_SRC_INC: u32 = 1 << 8;
+const TI_SRC_WIDTH: u32 = 1 << 9;
+const TI_SRC_DREQ: u32 = 1 << 10;
+const TI_BURST_SHIFT: u32 = 12;
+const TI_PERMAP_SHIFT: u32 = 16;
+
+/// Longest transfer one control block can describe, in bytes.
+pub const MAX_BLOCK_LEN: usize = (1 << 30) - 1;
+
+/// Number of channels in the main DMA block.
+pub const CHANNELS: usize = 15;
+
+/// Channels the firmware leaves to the ARM (the rest belong to the GPU).
+pub const ARM_CHANNELS: u16 = 0x7F35;
+
+/// Interrupt line of channel 0; channels 1-10 follow, and 11-14 share the
+/// line after channel 10's.
+const DMA_IRQ_BASE: u32 = VC_IRQ_BASE + 16;
+const DMA_IRQ_LINES: u32 = 12;
+
+/// Alias the GPU uses for uncached access to ARM memory.
+const GPU_UNCACHED_ALIAS: u32 = 0xC000_0000;
+/// Where the GPU sees the peripheral window.
+const PERIPHERAL_BUS_BASE: u32 = 0x7E00_0000;
+const PERIPHERAL_WINDOW: usize = 0x0100_0000;
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+/// Channels not handed out yet
+static FREE: AtomicU16 = AtomicU16::new(ARM_CHANNELS);
+// Only used to initialise EVENTS and ERRORS
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_EVENT: IrqEvent = IrqEvent::new();
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ERROR: AtomicBool = AtomicBool::new(false);
+static EVENTS: [IrqEvent; CHANNELS] = [NO_EVENT; CHANNELS];
+static ERRORS: [AtomicBool; CHANNELS] = [NO_ERROR; CHANNELS];
+
+/// Peripheral pacing a transfer through its DREQ line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Dreq {
+    PcmTx = 2,
+    PcmRx = 3,
+    Pwm = 5,
+    SpiTx = 6,
+    SpiRx = 7,
+    Emmc = 11,
+    UartTx = 12,
+    SdHost = 13,
+    UartRx = 14,
+}
+
+/// One transfer in a chain, in the layout the controller reads.
+///
+/// Addresses are bus addresses; the constructors translate from ARM
+/// addresses. [`Channel::transfer`] links consecutive blocks and asks for
+/// an interrupt after the last one.
+#[repr(C, align(32))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlBlock {
+    /// Transfer information: widths, increments, DREQ, burst length.
+    pub ti: u32,
+    pub source: u32,
+    pub dest: u32,
+    pub length: u32,
+    /// 2D stride; unused by these constructors.
+    pub stride: u32,
+    next: u32,
+    _reserved: [u32; 2],
+}
+
+impl ControlBlock {
+    fn new(ti: u32, source: u32, dest: u32, len: usize) -> Result<Self, DeviceError> {
+        if len == 0 || len > MAX_BLOCK_LEN {
+            return Err(DeviceError::InvalidArgument);
+        }
+        Ok(Self {
+            ti,
+            source,
+            dest,
+            length: len as u32,
+            stride: 0,
+            next: 0,
+            _reserved: [0; 2],
+        })
+    }
+
+    /// Copy `len` bytes from memory at `src` to memory at `dst`.
+    pub fn copy(src: *const u8, dst: *mut u8, len: usize) -> Result<Self, DeviceError> {
+        let ti = TI_SRC_INC
+            | TI_DEST_INC
+            | TI_SRC_WIDTH
+            | TI_DEST_WIDTH
+            | TI_WAIT_RESP
+            | 4 << TI_BURST_SHIFT;
+        Self::new(
+            ti,
+            bus_address(src as usize)?,
+            bus_address(dst as usize)?,
+            len,
+        )
+    }
+
+    /// Feed `len` bytes from memory at `src` into the peripheral register
+    /// `fifo`, as fast as `dreq` asks for them.
+    pub fn to_device(
+        src: *const u8,
+        fifo: usize,
+        dreq: Dreq,
+        len: usize,
+    ) -> Result<Self, DeviceError> {
+        let ti = TI_SRC_INC | TI_DEST_DREQ | TI_WAIT_RESP | (dreq as u32) << TI_PERMAP_SHIFT;
+        Self::new(ti, bus_address(src as usize)?, bus_address(fifo)?, len)
+    }
+
+    /// Drain `len` bytes from the peripheral register `fifo` into memory at
+    /// `dst`, as fast as `dreq` provides them.
+    pub fn from_device(
+        fifo: usize,
+        dst: *mut u8,
+        dreq: Dreq,
+        len: usize,
+    ) -> Result<Self, DeviceError> {
+        let ti = TI_DEST_INC | TI_SRC_DREQ | TI_WAIT_RESP | (dreq as u32) << TI_PERMAP_SHIFT;
+        Self::new(ti, bus_address(fifo)?, bus_address(dst as usize)?, len)
+    }
+}
+
+/// Translate an ARM physical address into the bus address the DMA engine
+/// uses for it.
+fn bus_address(addr: usize) -> Result<u32, DeviceError> {
+    if (PERIPHERAL_BASE..PERIPHERAL_BASE + PERIPHERAL_WINDOW).contains(&addr) {
+        return Ok(PERIPHERAL_BUS_BASE + (addr - PERIPHERAL_BASE) as u32);
+    }
+    if addr >= PERIPHERAL_BASE {
+        return Err(DeviceError::InvalidArgument);
+    }
+    Ok(addr as u32 | GPU_UNCACHED_ALIAS)
+}
+
+/// Link `blocks` into a chain ending in an interrupt.
+///
+/// # Returns
+///
+/// The bus address of the first block.
+fn link(blocks: &mut [ControlBlock]) -> Result<u32, DeviceError> {
+    let Some(last) = blocks.len().checked_sub(1) else {
+        return Err(DeviceError::InvalidArgument);
+    };
+    for i in 0..last {
+        blocks[i].ti &= !TI_INTEN;
+        blocks[i].next = bus_address(&blocks[i + 1] as *const ControlBlock as usize)?;
+    }
+    blocks[last].ti |= TI_INTEN;
+    blocks[last].next = 0;
+    bus_address(blocks.as_ptr() as usize)
+}
+
+/// Register the completion interrupts and enable the ARM's channels.
+pub fn init() -> Result<(), ThreadError> {
+    for line in 0..DMA_IRQ_LINES {
+        crate::irq::register_handler(DMA_IRQ_BASE + line, handle_interrupt)?;
+    }
+    mmio_write(ENABLE, mmio_read(ENABLE) | ARM_CHANNELS as u32);
+    for line in 0..DMA_IRQ_LINES {
+        crate::irq::enable(DMA_IRQ_BASE + line)?;
+    }
+
+    INITIALIZED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Claim a free channel; it is given back when dropped.
+///
+/// Fails with [`DeviceError::Busy`] if every ARM channel is taken.
+pub fn request_channel() -> Result<Channel, DeviceError> {
+    let mut free = FREE.load(Ordering::Acquire);
+    loop {
+        if free == 0 {
+            return Err(DeviceError::Busy);
+        }
+        let index = free.trailing_zeros() as usize;
+        match FREE.compare_exchange_weak(
+            free,
+            free & !(1 << index),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => return Ok(Channel { index }),
+            Err(now) => free = now,
+        }
+    }
+}
+
+/// Exclusive use of one DMA channel.
+#[derive(Debug)]
+pub struct Channel {
+    index: usize,
+}
+
+impl Channel {
+    /// Channel number in the DMA block.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Run the chain `blocks` and block until the controller finishes it.
+    ///
+    /// Fails with [`DeviceError::Io`] if the controller reports an error,
+    /// in which case the channel is reset.
+    pub fn transfer(&mut self, blocks: &mut [ControlBlock]) -> Result<(), DeviceError> {
+        if !INITIALIZED.load(Ordering::Acquire) {
+            return Err(DeviceError::NotInitialized);
+        }
+        let first = link(blocks)?;
+        ERRORS[self.index].store(false, Ordering::Relaxed);
+
+        // Make the chain and the source data visible to the controller
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        EVENTS[self.index].wait(|| {
+            self.write_reg(CONBLK_AD, first);
+            self.write_reg(CS, CS_ACTIVE | CS_WAIT_FOR_OUTSTANDING_WRITES);
+        });
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+
+        if ERRORS[self.index].load(Ordering::Relaxed) {
+            self.write_reg(DEBUG, DEBUG_ERRORS);
+            self.write_reg(CS, CS_RESET);
+            return Err(DeviceError::Io);
+        }
+        Ok(())
+    }
+
+    /// Copy `src` into `dst` with the DMA engine.
+    pub fn copy(&mut self, dst: &mut [u8], src: &[u8]) -> Result<(), DeviceError> {
+        if dst.len() != src.len() {
+            return Err(DeviceError::InvalidArgument);
+        }
+        if src.is_empty() {
+            return Ok(());
+        }
+        let mut blocks = [ControlBlock::copy(
+            src.as_ptr(),
+            dst.as_mut_ptr(),
+            src.len(),
+        )?];
+        self.transfer(&mut blocks)
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        channel_read(self.index, offset)
+    }
+
+    fn write_reg(&self, offset: usize, value: u32) {
+        channel_write(self.index, offset, value);
+    }
+}
+
+fn channel_read(index: usize, offset: usize) -> u32 {
+    mmio_read(DMA_BASE + index * CHANNEL_STRIDE + offset)
+}
+
+fn channel_write(index: usize, offset: usize, value: u32) {
+    mmio_write(DMA_BASE + index * CHANNEL_STRIDE + offset, value);
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        if self.read_reg(CS) & CS_ACTIVE != 0 {
+            self.write_reg(CS, CS_ABORT);
+        }
+        FREE.fetch_or(1 << self.index, Ordering::Release);
+    }
+}
+
+/// Service every ARM channel with an interrupt pending; the last line is
+/// shared, so the status register says which ones fired.
+fn handle_interrupt(_irq: u32) {
+    let pending = mmio_read(INT_STATUS) & ARM_CHANNELS as u32;
+    for index in (0..CHANNELS).filter(|index| pending & 1 << index != 0) {
+        let cs = channel_read(index, CS);
+        if cs & CS_INT == 0 {
+            continue;
+        }
+        if cs & CS_ERROR != 0 {
+            ERRORS[index].store(true, Ordering::Relaxed);
+        }
+        // INT and END are write-one-to-clear
+        channel_write(index, CS, CS_INT | CS_END);
+        EVENTS[index].raise();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_blocks_link_into_chain() {
+        let src = 0x10_0000 as *const u8;
+        let dst = 0x20_0000 as *mut u8;
+        let block = ControlBlock::copy(src, dst, 32).unwrap();
+        assert_eq!(
+            (block.source, block.dest, block.length),
+            (0xC010_0000, 0xC020_0000, 32)
+        );
+        assert_eq!(core::mem::size_of::<ControlBlock>(), 32);
+        assert_eq!(bus_address(PERIPHERAL_BASE + 0x20_1000), Ok(0x7E20_1000));
+        assert_eq!(
+            ControlBlock::copy(src, dst, 0),
+            Err(DeviceError::InvalidArgument)
+        );
+
+        let mut blocks = [block; 2];
+        // Host addresses are out of the GPU's reach
+        if (blocks.as_ptr() as usize) < PERIPHERAL_BASE {
+            let first = link(&mut blocks).unwrap();
+            assert_eq!(first, blocks.as_ptr() as u32 | GPU_UNCACHED_ALIAS);
+            assert_eq!(
+                blocks[0].next,
+                &blocks[1] as *const ControlBlock as u32 | GPU_UNCACHED_ALIAS
+            );
+            assert_eq!(
+                (
+                    blocks[0].ti & TI_INTEN,
+                    blocks[1].ti & TI_INTEN,
+                    blocks[1].next
+                ),
+                (0, TI_INTEN, 0)
+            );
+        } else {
+            assert_eq!(link(&mut blocks), Err(DeviceError::InvalidArgument));
+        }
+        assert_eq!(link(&mut []), Err(DeviceError::InvalidArgument));
+    }
+
+    #[test]
+    fn test_channels_are_handed_out_once() {
+        let mut channels = alloc::vec::Vec::new();
+        while let Ok(channel) = request_channel() {
+            assert!(ARM_CHANNELS & 1 << channel.index() != 0);
+            channels.push(channel);
+        }
+        assert_eq!(channels.len(), ARM_CHANNELS.count_ones() as usize);
+        assert_eq!(request_channel().unwrap_err(), DeviceError::Busy);
+
+        channels.pop();
+        assert!(request_channel().is_ok());
+        assert_eq!(channels[0].index(), 0);
+    }
+}
@@ -0,0 +1,216 @@
+//! Text console on the framebuffer.
+//!
+//! Renders an 8x8 font in a character grid and scrolls when the last line
+//! fills. Once [`init`] has installed the global console it can also mirror
+//! everything printed to the UART ([`set_log_mirroring`]) and show panic
+//! messages ([`set_panic_output`]).
+
+use super::font::{glyph, GLYPH_SIZE};
+use super::{Color, Framebuffer};
+use crate::arch;
+use core::fmt;
+use portable_atomic::{AtomicBool, Ordering};
+
+static CONSOLE: spin::Mutex<Option<Console>> = spin::Mutex::new(None);
+static MIRROR_LOG: AtomicBool = AtomicBool::new(false);
+static PANIC_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// A character console drawing into a framebuffer.
+pub struct Console {
+    fb: Framebuffer,
+    col: u32,
+    row: u32,
+    cols: u32,
+    rows: u32,
+    fg: Color,
+    bg: Color,
+}
+
+impl Console {
+    /// Create a console covering `fb`, cleared to black.
+    pub fn new(mut fb: Framebuffer) -> Self {
+        fb.clear(Color::BLACK);
+        Self {
+            cols: fb.width() / GLYPH_SIZE,
+            rows: fb.height() / GLYPH_SIZE,
+            fb,
+            col: 0,
+            row: 0,
+            fg: Color::WHITE,
+            bg: Color::BLACK,
+        }
+    }
+
+    /// Set the colours used for subsequent text.
+    pub fn set_colors(&mut self, fg: Color, bg: Color) {
+        self.fg = fg;
+        self.bg = bg;
+    }
+
+    /// Clear the screen and move the cursor home.
+    pub fn clear(&mut self) {
+        self.fb.clear(self.bg);
+        self.col = 0;
+        self.row = 0;
+    }
+
+    /// Write one byte, handling `\n`, `\r` and line wrap.
+    pub fn write_byte(&mut self, byte: u8) {
+        if self.cols == 0 || self.rows == 0 {
+            return;
+        }
+
+        match byte {
+            b'\n' => self.newline(),
+            b'\r' => self.col = 0,
+            _ => {
+                if self.col == self.cols {
+                    self.newline();
+                }
+                self.draw_glyph(byte);
+                self.col += 1;
+            }
+        }
+    }
+
+    fn draw_glyph(&mut self, byte: u8) {
+        let x0 = self.col * GLYPH_SIZE;
+        let y0 = self.row * GLYPH_SIZE;
+        for (dy, bits) in glyph(byte).iter().enumerate() {
+            for dx in 0..GLYPH_SIZE {
+                let color = if bits & (1 << dx) != 0 {
+                    self.fg
+                } else {
+                    self.bg
+                };
+                self.fb.set_pixel(x0 + dx, y0 + dy as u32, color);
+            }
+        }
+    }
+
+    fn newline(&mut self) {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.fb.scroll_up(GLYPH_SIZE, self.bg);
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Install `fb` as the global console.
+pub fn init(fb: Framebuffer) {
+    let console = Console::new(fb);
+    arch::without_interrupts(|| *CONSOLE.lock() = Some(console));
+}
+
+/// Mirror everything printed through `pl011_print!` to the console.
+pub fn set_log_mirroring(enabled: bool) {
+    MIRROR_LOG.store(enabled, Ordering::Release);
+}
+
+/// Print panic messages on the console before halting.
+pub fn set_panic_output(enabled: bool) {
+    PANIC_OUTPUT.store(enabled, Ordering::Release);
+}
+
+/// Write a string to the global console, if one is installed.
+pub fn write_str(s: &str) {
+    arch::without_interrupts(|| {
+        if let Some(console) = CONSOLE.lock().as_mut() {
+            let _ = fmt::Write::write_str(console, s);
+        }
+    });
+}
+
+/// Write formatted text to the global console, if one is installed.
+pub fn write_fmt(args: fmt::Arguments<'_>) {
+    arch::without_interrupts(|| {
+        if let Some(console) = CONSOLE.lock().as_mut() {
+            let _ = fmt::Write::write_fmt(console, args);
+        }
+    });
+}
+
+/// UART output hook: copy `s` to the console when mirroring is on.
+pub(crate) fn mirror_log(s: &str) {
+    if MIRROR_LOG.load(Ordering::Acquire) {
+        write_str(s);
+    }
+}
+
+/// Show a panic message on the console when panic output is enabled.
+///
+/// The crate's bare-metal panic handler calls this; applications with their
+/// own panic handler can call it too.
+pub fn show_panic(info: &core::panic::PanicInfo<'_>) {
+    if !PANIC_OUTPUT.load(Ordering::Acquire) {
+        return;
+    }
+
+    // The panic may have hit while the console was locked; nothing else
+    // will run after this, so take it regardless
+    if CONSOLE.is_locked() {
+        unsafe { CONSOLE.force_unlock() };
+    }
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        console.set_colors(Color::WHITE, Color::RED);
+        let _ = fmt::Write::write_fmt(console, format_args!("\nPANIC: {}\n", info));
+    }
+}
+
+/// Print to the framebuffer console.
+#[macro_export]
+macro_rules! fb_print {
+    ($($arg:tt)*) => {
+        $crate::drivers::framebuffer::console::write_fmt(format_args!($($arg)*))
+    };
+}
+
+/// Print to the framebuffer console with a newline.
+#[macro_export]
+macro_rules! fb_println {
+    () => {
+        $crate::fb_print!("\n")
+    };
+    ($($arg:tt)*) => {{
+        $crate::fb_print!($($arg)*);
+        $crate::fb_print!("\n");
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use core::fmt::Write;
+
+    #[test]
+    fn test_console_wraps_and_scrolls() {
+        // 2 columns x 2 rows of glyphs
+        let mut pixels = vec![0u32; 16 * 16];
+        let fb = unsafe { Framebuffer::from_raw(pixels.as_mut_ptr(), 16, 16, 16) };
+        let mut console = Console::new(fb);
+
+        write!(console, "ab").unwrap();
+        assert_eq!((console.col, console.row), (2, 0));
+
+        // Wraps onto the second row, then scrolls
+        write!(console, "c\n|").unwrap();
+        assert_eq!((console.col, console.row), (1, 1));
+
+        // '|' column 3 is lit in the top row of the bottom-left cell,
+        // and 'c' has scrolled up to the top-left cell
+        assert_eq!(pixels[8 * 16 + 3], Color::WHITE.0);
+        assert_eq!(pixels[2 * 16 + 1], Color::WHITE.0);
+    }
+}
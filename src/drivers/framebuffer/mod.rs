@@ -0,0 +1,230 @@
+//! HDMI framebuffer allocated through the mailbox property interface.
+//!
+//! [`Framebuffer::new`] asks the GPU firmware for a 32-bit-per-pixel
+//! framebuffer of the requested size and gives direct pixel access. With the
+//! `fb-console` feature, [`console`] adds a text console on top of it that
+//! can mirror UART log output and show panic messages, which is handy for
+//! demos on a headless HDMI display.
+
+use super::mailbox::{self, PropertyBuffer, TAG_END};
+use crate::errors::DeviceError;
+use crate::mem::fast::{copy_fast_raw, fill_words_fast};
+
+#[cfg(feature = "fb-console")]
+pub mod console;
+#[cfg(feature = "fb-console")]
+mod font;
+
+// Property tags
+const TAG_SET_PHYSICAL_SIZE: u32 = 0x0004_8003;
+const TAG_SET_VIRTUAL_SIZE: u32 = 0x0004_8004;
+const TAG_SET_DEPTH: u32 = 0x0004_8005;
+const TAG_SET_PIXEL_ORDER: u32 = 0x0004_8006;
+const TAG_ALLOCATE_BUFFER: u32 = 0x0004_0001;
+const TAG_GET_PITCH: u32 = 0x0004_0008;
+
+const PIXEL_ORDER_BGR: u32 = 0;
+
+/// Mask from a GPU bus address to an ARM physical address.
+const BUS_ADDRESS_MASK: u32 = 0x3FFF_FFFF;
+
+/// A 24-bit colour, `0x00RRGGBB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color(pub u32);
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+    pub const WHITE: Color = Color::rgb(0xFF, 0xFF, 0xFF);
+    pub const RED: Color = Color::rgb(0xFF, 0, 0);
+    pub const GREEN: Color = Color::rgb(0, 0xFF, 0);
+    pub const BLUE: Color = Color::rgb(0, 0, 0xFF);
+
+    /// Colour from red, green and blue components.
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Color(((r as u32) << 16) | ((g as u32) << 8) | b as u32)
+    }
+}
+
+/// A linear 32-bit-per-pixel framebuffer.
+pub struct Framebuffer {
+    base: *mut u32,
+    width: u32,
+    height: u32,
+    /// Pixels per row, including any padding
+    stride: usize,
+    /// Firmware gave us RGB rather than the requested BGR byte order
+    rgb_order: bool,
+}
+
+// The framebuffer is plain memory owned by whoever holds this value
+unsafe impl Send for Framebuffer {}
+
+impl Framebuffer {
+    /// Allocate a `width` x `height` framebuffer from the GPU firmware.
+    pub fn new(width: u32, height: u32) -> Result<Self, DeviceError> {
+        if width == 0 || height == 0 {
+            return Err(DeviceError::InvalidArgument);
+        }
+
+        let mut msg = PropertyBuffer::<32>::new();
+        let tags = [
+            TAG_SET_PHYSICAL_SIZE,
+            8,
+            0,
+            width,
+            height,
+            TAG_SET_VIRTUAL_SIZE,
+            8,
+            0,
+            width,
+            height,
+            TAG_SET_DEPTH,
+            4,
+            0,
+            32,
+            TAG_SET_PIXEL_ORDER,
+            4,
+            0,
+            PIXEL_ORDER_BGR,
+            TAG_ALLOCATE_BUFFER,
+            8,
+            0,
+            16,
+            0,
+            TAG_GET_PITCH,
+            4,
+            0,
+            0,
+            TAG_END,
+        ];
+        msg.0[2..2 + tags.len()].copy_from_slice(&tags);
+
+        mailbox::call(&mut msg)?;
+
+        let words = &msg.0;
+        let (width, height, depth, order) = (words[5], words[6], words[15], words[19]);
+        let (base, pitch) = (words[23] & BUS_ADDRESS_MASK, words[28]);
+        if depth != 32 || base == 0 || pitch < width * 4 {
+            return Err(DeviceError::Nack);
+        }
+
+        Ok(Self {
+            base: base as usize as *mut u32,
+            width,
+            height,
+            stride: pitch as usize / 4,
+            rgb_order: order != PIXEL_ORDER_BGR,
+        })
+    }
+
+    /// Wrap existing pixel memory as a framebuffer.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be valid for writes of `stride * height` `u32`s for as
+    /// long as the framebuffer is used, and `stride >= width`.
+    pub unsafe fn from_raw(base: *mut u32, width: u32, height: u32, stride: usize) -> Self {
+        Self {
+            base,
+            width,
+            height,
+            stride,
+            rgb_order: false,
+        }
+    }
+
+    /// Width in pixels.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Height in pixels.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Set one pixel; coordinates outside the framebuffer are ignored.
+    pub fn set_pixel(&mut self, x: u32, y: u32, color: Color) {
+        if x < self.width && y < self.height {
+            let pixel = self.native(color);
+            unsafe {
+                core::ptr::write_volatile(self.base.add(self.offset(x, y)), pixel);
+            }
+        }
+    }
+
+    /// Fill a rectangle, clipped to the framebuffer.
+    pub fn fill_rect(&mut self, x: u32, y: u32, width: u32, height: u32, color: Color) {
+        let pixel = self.native(color);
+        let x_end = x.saturating_add(width).min(self.width);
+        let y_end = y.saturating_add(height).min(self.height);
+
+        if x >= x_end {
+            return;
+        }
+        for row in y..y_end {
+            unsafe {
+                fill_words_fast(
+                    self.base.add(self.offset(x, row)),
+                    pixel,
+                    (x_end - x) as usize,
+                );
+            }
+        }
+    }
+
+    /// Fill the whole framebuffer.
+    pub fn clear(&mut self, color: Color) {
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+
+    /// Move the contents up by `rows` pixel rows and fill the exposed
+    /// bottom rows with `fill`.
+    pub fn scroll_up(&mut self, rows: u32, fill: Color) {
+        let rows = rows.min(self.height);
+        let kept = (self.height - rows) as usize;
+        // Destination below source, so a front-to-back copy is safe
+        unsafe {
+            copy_fast_raw(
+                self.base as *mut u8,
+                self.base.add(rows as usize * self.stride) as *const u8,
+                kept * self.stride * 4,
+            );
+        }
+        self.fill_rect(0, kept as u32, self.width, rows, fill);
+    }
+
+    fn offset(&self, x: u32, y: u32) -> usize {
+        y as usize * self.stride + x as usize
+    }
+
+    fn native(&self, color: Color) -> u32 {
+        if self.rgb_order {
+            let c = color.0;
+            ((c & 0xFF) << 16) | (c & 0xFF00) | ((c >> 16) & 0xFF)
+        } else {
+            color.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_fill_and_scroll() {
+        let mut pixels = vec![0u32; 8 * 4];
+        let mut fb = unsafe { Framebuffer::from_raw(pixels.as_mut_ptr(), 6, 4, 8) };
+
+        fb.fill_rect(4, 2, 10, 10, Color::RED);
+        fb.set_pixel(100, 0, Color::WHITE);
+        fb.scroll_up(1, Color::BLUE);
+
+        assert_eq!(pixels[8 + 4], Color::RED.0);
+        assert_eq!(pixels[8 + 6], 0); // stride padding untouched
+        assert_eq!(pixels[3 * 8], Color::BLUE.0);
+        assert_eq!(pixels[0], 0);
+    }
+}
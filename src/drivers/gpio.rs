@@ -0,0 +1,129 @@
+//! GPIO pin function selection and digital I/O.
+
+use super::{mmio_read, mmio_write, PERIPHERAL_BASE};
+use crate::arch;
+use crate::errors::DeviceError;
+
+const GPIO_BASE: usize = PERIPHERAL_BASE + 0x20_0000;
+const GPFSEL0: usize = GPIO_BASE; // Function Select 0 (pins 0-9)
+const GPSET0: usize = GPIO_BASE + 0x1C; // Pin Output Set 0
+const GPCLR0: usize = GPIO_BASE + 0x28; // Pin Output Clear 0
+const GPLEV0: usize = GPIO_BASE + 0x34; // Pin Level 0
+const GPPUD: usize = GPIO_BASE + 0x94; // Pull-up/down Enable
+const GPPUDCLK0: usize = GPIO_BASE + 0x98; // Pull-up/down Clock 0
+
+/// Cycles the pull-up/down control signal must be held (datasheet: 150).
+const PULL_SETUP_CYCLES: u32 = 150;
+
+/// Number of GPIO pins on the BCM2837.
+pub const NUM_PINS: u32 = 54;
+
+/// GPIO pin function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Function {
+    Input = 0b000,
+    Output = 0b001,
+    Alt0 = 0b100,
+    Alt1 = 0b101,
+    Alt2 = 0b110,
+    Alt3 = 0b111,
+    Alt4 = 0b011,
+    Alt5 = 0b010,
+}
+
+/// Pin pull-up/pull-down state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Pull {
+    None = 0b00,
+    Down = 0b01,
+    Up = 0b10,
+}
+
+/// Register address and bit shift of a pin's function select field.
+fn fsel_location(pin: u32) -> (usize, u32) {
+    (GPFSEL0 + (pin / 10) as usize * 4, (pin % 10) * 3)
+}
+
+/// Register address and bit of a pin in a SET/CLR/LEV register bank.
+fn bank_location(bank: usize, pin: u32) -> (usize, u32) {
+    (bank + (pin / 32) as usize * 4, 1 << (pin % 32))
+}
+
+fn check_pin(pin: u32) -> Result<(), DeviceError> {
+    if pin < NUM_PINS {
+        Ok(())
+    } else {
+        Err(DeviceError::InvalidArgument)
+    }
+}
+
+/// Select the function of a pin.
+pub fn set_function(pin: u32, function: Function) -> Result<(), DeviceError> {
+    check_pin(pin)?;
+    let (reg, shift) = fsel_location(pin);
+
+    // Read-modify-write shared with the other nine pins in this register
+    arch::without_interrupts(|| {
+        let mut val = mmio_read(reg);
+        val &= !(0b111 << shift);
+        val |= (function as u32) << shift;
+        mmio_write(reg, val);
+    });
+    Ok(())
+}
+
+/// Set the pull-up/pull-down resistor of a pin.
+pub fn set_pull(pin: u32, pull: Pull) -> Result<(), DeviceError> {
+    check_pin(pin)?;
+    let (clk_reg, bit) = bank_location(GPPUDCLK0, pin);
+
+    // The control value is latched into the pins whose clock bit is pulsed
+    arch::without_interrupts(|| {
+        mmio_write(GPPUD, pull as u32);
+        delay_cycles(PULL_SETUP_CYCLES);
+        mmio_write(clk_reg, bit);
+        delay_cycles(PULL_SETUP_CYCLES);
+        mmio_write(GPPUD, 0);
+        mmio_write(clk_reg, 0);
+    });
+    Ok(())
+}
+
+fn delay_cycles(cycles: u32) {
+    for _ in 0..cycles {
+        core::hint::spin_loop();
+    }
+}
+
+/// Drive an output pin high or low.
+pub fn write(pin: u32, high: bool) -> Result<(), DeviceError> {
+    check_pin(pin)?;
+    let (reg, bit) = bank_location(if high { GPSET0 } else { GPCLR0 }, pin);
+    mmio_write(reg, bit);
+    Ok(())
+}
+
+/// Read the current level of a pin.
+pub fn read(pin: u32) -> Result<bool, DeviceError> {
+    check_pin(pin)?;
+    let (reg, bit) = bank_location(GPLEV0, pin);
+    Ok(mmio_read(reg) & bit != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_locations() {
+        assert_eq!(fsel_location(0), (GPFSEL0, 0));
+        assert_eq!(fsel_location(14), (GPFSEL0 + 4, 12));
+        assert_eq!(bank_location(GPSET0, 33), (GPSET0 + 4, 1 << 1));
+        assert_eq!(
+            set_function(NUM_PINS, Function::Output),
+            Err(DeviceError::InvalidArgument)
+        );
+    }
+}
@@ -0,0 +1,381 @@
+//! I2C master driver for the BCM2837 BSC (Broadcom Serial Controller).
+//!
+//! Transfers are interrupt driven: the calling thread fills or drains the
+//! 16-byte FIFO, unmasks the controller's interrupts and blocks on the bus's
+//! wait queue until the hardware needs more data or signals completion.
+//! Only one transfer runs on a bus at a time; other callers block until the
+//! bus is free.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::drivers::i2c::{self, Bus};
+//!
+//! let bus = i2c::bus(Bus::Bsc1);
+//! bus.init(i2c::FAST_MODE_HZ)?;
+//!
+//! let mut temp = [0u8; 2];
+//! bus.write_read(0x48, &[0x00], &mut temp)?;
+//! ```
+
+use super::{
+    clock_divider, gpio, mmio_read, mmio_write, DeviceLock, IrqEvent, PERIPHERAL_BASE, VC_IRQ_BASE,
+};
+use crate::errors::{DeviceError, ThreadError};
+use portable_atomic::{AtomicBool, Ordering};
+
+const BSC0_BASE: usize = PERIPHERAL_BASE + 0x20_5000;
+const BSC1_BASE: usize = PERIPHERAL_BASE + 0x80_4000;
+
+// BSC registers (offsets from controller base)
+const C: usize = 0x00; // Control
+const S: usize = 0x04; // Status
+const DLEN: usize = 0x08; // Data Length
+const A: usize = 0x0C; // Slave Address
+const FIFO: usize = 0x10; // Data FIFO
+const DIV: usize = 0x14; // Clock Divider
+const DEL: usize = 0x18; // Data Delay
+const CLKT: usize = 0x1C; // Clock Stretch Timeout
+
+// Control register bits
+const C_I2CEN: u32 = 1 << 15;
+const C_INTR: u32 = 1 << 10;
+const C_INTT: u32 = 1 << 9;
+const C_INTD: u32 = 1 << 8;
+const C_ST: u32 = 1 << 7;
+const C_CLEAR: u32 = 0b11 << 4;
+const C_READ: u32 = 1 << 0;
+const C_INT_MASK: u32 = C_INTR | C_INTT | C_INTD;
+
+// Status register bits
+const S_CLKT: u32 = 1 << 9;
+const S_ERR: u32 = 1 << 8;
+const S_RXR: u32 = 1 << 3;
+const S_TXW: u32 = 1 << 2;
+const S_RXD: u32 = 1 << 5;
+const S_TXD: u32 = 1 << 4;
+const S_DONE: u32 = 1 << 1;
+const S_TA: u32 = 1 << 0;
+
+/// Depth of the BSC transmit and receive FIFOs.
+const FIFO_DEPTH: usize = 16;
+
+/// Shared interrupt line of all BSC controllers.
+pub const I2C_IRQ: u32 = VC_IRQ_BASE + 53;
+
+/// Largest transfer the controller can perform in one transaction.
+pub const MAX_TRANSFER_LEN: usize = 0xFFFF;
+
+/// Standard mode bus speed (100 kHz).
+pub const STANDARD_MODE_HZ: u32 = 100_000;
+
+/// Fast mode bus speed (400 kHz).
+pub const FAST_MODE_HZ: u32 = 400_000;
+
+/// Default clock stretch timeout in SCL cycles (hardware reset value).
+pub const DEFAULT_CLOCK_STRETCH_TIMEOUT: u16 = 0x40;
+
+static HANDLER_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+static BSC0: I2c = I2c::new(BSC0_BASE, [0, 1]);
+static BSC1: I2c = I2c::new(BSC1_BASE, [2, 3]);
+
+/// BSC controllers usable as I2C masters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    /// BSC0 on GPIO 0/1 (HAT ID EEPROM pins)
+    Bsc0,
+    /// BSC1 on GPIO 2/3 (the header's SDA/SCL)
+    Bsc1,
+}
+
+/// Get the driver for a bus.
+pub fn bus(bus: Bus) -> &'static I2c {
+    match bus {
+        Bus::Bsc0 => &BSC0,
+        Bus::Bsc1 => &BSC1,
+    }
+}
+
+/// An I2C master on one BSC controller.
+pub struct I2c {
+    base: usize,
+    pins: [u32; 2],
+    initialized: AtomicBool,
+    /// Raised by the interrupt handler when the controller needs attention
+    event: IrqEvent,
+    lock: DeviceLock,
+}
+
+impl I2c {
+    const fn new(base: usize, pins: [u32; 2]) -> Self {
+        Self {
+            base,
+            pins,
+            initialized: AtomicBool::new(false),
+            event: IrqEvent::new(),
+            lock: DeviceLock::new(),
+        }
+    }
+
+    /// Route the bus pins to the controller, set the bus speed and enable
+    /// the shared BSC interrupt.
+    pub fn init(&self, speed_hz: u32) -> Result<(), ThreadError> {
+        for pin in self.pins {
+            gpio::set_function(pin, gpio::Function::Alt0)?;
+        }
+
+        self.write_reg(C, C_CLEAR);
+        self.write_reg(S, S_CLKT | S_ERR | S_DONE);
+        self.set_speed(speed_hz)?;
+        self.set_clock_stretch_timeout(DEFAULT_CLOCK_STRETCH_TIMEOUT);
+        self.write_reg(C, C_I2CEN);
+
+        if !HANDLER_REGISTERED.swap(true, Ordering::AcqRel) {
+            crate::irq::register_handler(I2C_IRQ, handle_interrupt)?;
+            crate::irq::enable(I2C_IRQ)?;
+        }
+
+        self.initialized.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Set the SCL frequency.
+    ///
+    /// The actual frequency is the nearest one at or below `speed_hz` that
+    /// the clock divider can produce.
+    pub fn set_speed(&self, speed_hz: u32) -> Result<(), DeviceError> {
+        let div = clock_divider(speed_hz).ok_or(DeviceError::InvalidArgument)?;
+
+        // Sample/launch data a fraction of a clock period after each edge
+        let fedl = (div / 16).max(1);
+        let redl = (div / 4).max(1);
+
+        self.write_reg(DIV, div);
+        self.write_reg(DEL, (fedl << 16) | redl);
+        Ok(())
+    }
+
+    /// Set how many SCL cycles a device may stretch the clock before the
+    /// transfer fails with [`DeviceError::ClockStretchTimeout`]. Zero disables
+    /// the timeout.
+    pub fn set_clock_stretch_timeout(&self, scl_cycles: u16) {
+        self.write_reg(CLKT, scl_cycles as u32);
+    }
+
+    /// Write `data` to the device at 7-bit address `addr`.
+    pub fn write(&self, addr: u8, data: &[u8]) -> Result<(), DeviceError> {
+        self.check_transfer(addr, data.len())?;
+        let _bus = self.lock.lock();
+
+        self.start(addr, data.len());
+        let sent = self.fill_fifo(data);
+        self.write_reg(C, C_I2CEN | C_ST);
+        self.transmit(data, sent)
+    }
+
+    /// Read `buf.len()` bytes from the device at 7-bit address `addr`.
+    pub fn read(&self, addr: u8, buf: &mut [u8]) -> Result<(), DeviceError> {
+        self.check_transfer(addr, buf.len())?;
+        let _bus = self.lock.lock();
+
+        self.start(addr, buf.len());
+        self.write_reg(C, C_I2CEN | C_ST | C_READ);
+        self.receive(buf)
+    }
+
+    /// Write `data` and then read into `buf` from the same device.
+    ///
+    /// When `data` fits in the FIFO the read follows with a repeated start,
+    /// as register-oriented devices expect; longer writes are issued as a
+    /// separate write and read transaction.
+    pub fn write_read(&self, addr: u8, data: &[u8], buf: &mut [u8]) -> Result<(), DeviceError> {
+        self.check_transfer(addr, data.len())?;
+        self.check_transfer(addr, buf.len())?;
+
+        if data.is_empty() || data.len() > FIFO_DEPTH {
+            self.write(addr, data)?;
+            return self.read(addr, buf);
+        }
+
+        let _bus = self.lock.lock();
+
+        self.start(addr, data.len());
+        self.fill_fifo(data);
+        self.write_reg(C, C_I2CEN | C_ST);
+
+        // The BSC has no repeated-start control: once the write is under way,
+        // reprogramming DLEN and starting a read makes the controller issue a
+        // repeated start instead of a stop when the write completes. TA goes
+        // high within one SCL cycle, so a short spin is cheaper than blocking.
+        loop {
+            let status = self.read_reg(S);
+            if status & (S_ERR | S_CLKT) != 0 {
+                return self.finish(status);
+            }
+            if status & (S_TA | S_DONE) != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+
+        self.write_reg(DLEN, buf.len() as u32);
+        self.write_reg(C, C_I2CEN | C_ST | C_READ);
+        self.receive(buf)
+    }
+
+    fn check_transfer(&self, addr: u8, len: usize) -> Result<(), DeviceError> {
+        if !self.initialized.load(Ordering::Acquire) {
+            return Err(DeviceError::NotInitialized);
+        }
+        if addr > 0x7F || len > MAX_TRANSFER_LEN {
+            return Err(DeviceError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    /// Reset FIFO and status and program address and length.
+    fn start(&self, addr: u8, len: usize) {
+        self.write_reg(C, C_I2CEN | C_CLEAR);
+        self.write_reg(S, S_CLKT | S_ERR | S_DONE);
+        self.write_reg(A, addr as u32);
+        self.write_reg(DLEN, len as u32);
+    }
+
+    /// Push bytes into the TX FIFO until it is full; returns how many fit.
+    fn fill_fifo(&self, data: &[u8]) -> usize {
+        let mut sent = 0;
+        while sent < data.len() && self.read_reg(S) & S_TXD != 0 {
+            self.write_reg(FIFO, data[sent] as u32);
+            sent += 1;
+        }
+        sent
+    }
+
+    fn transmit(&self, data: &[u8], mut sent: usize) -> Result<(), DeviceError> {
+        loop {
+            sent += self.fill_fifo(&data[sent..]);
+
+            let status = self.read_reg(S);
+            if status & (S_DONE | S_ERR | S_CLKT) != 0 {
+                return self.finish(status);
+            }
+
+            let wanted = if sent < data.len() {
+                C_INTT | C_INTD
+            } else {
+                C_INTD
+            };
+            self.wait_event(wanted);
+        }
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> Result<(), DeviceError> {
+        let mut received = 0;
+        loop {
+            while received < buf.len() && self.read_reg(S) & S_RXD != 0 {
+                buf[received] = self.read_reg(FIFO) as u8;
+                received += 1;
+            }
+
+            let status = self.read_reg(S);
+            if status & (S_ERR | S_CLKT) != 0 {
+                return self.finish(status);
+            }
+            if status & S_DONE != 0 {
+                // DONE can be set while the tail is still in the FIFO
+                while received < buf.len() && self.read_reg(S) & S_RXD != 0 {
+                    buf[received] = self.read_reg(FIFO) as u8;
+                    received += 1;
+                }
+                return self.finish(status);
+            }
+
+            self.wait_event(C_INTR | C_INTD);
+        }
+    }
+
+    /// Unmask `int_bits` and block until the interrupt handler reports that
+    /// one of them fired.
+    fn wait_event(&self, int_bits: u32) {
+        self.event.wait(|| {
+            let control = self.read_reg(C) & !(C_ST | C_CLEAR);
+            self.write_reg(C, control | int_bits);
+        });
+    }
+
+    /// Clear status and FIFO after a transfer and map the outcome.
+    fn finish(&self, status: u32) -> Result<(), DeviceError> {
+        self.write_reg(S, S_CLKT | S_ERR | S_DONE);
+        self.write_reg(C, C_I2CEN | C_CLEAR);
+
+        if status & S_CLKT != 0 {
+            Err(DeviceError::ClockStretchTimeout)
+        } else if status & S_ERR != 0 {
+            Err(DeviceError::Nack)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Mask this controller's interrupts and wake its waiter if it raised one.
+    fn service_interrupt(&self) {
+        let control = self.read_reg(C);
+        if control & C_INT_MASK == 0 {
+            return;
+        }
+
+        let status = self.read_reg(S);
+        let pending = (control & C_INTD != 0 && status & (S_DONE | S_ERR | S_CLKT) != 0)
+            || (control & C_INTT != 0 && status & S_TXW != 0)
+            || (control & C_INTR != 0 && status & S_RXR != 0);
+
+        if pending {
+            self.write_reg(C, control & !(C_INT_MASK | C_ST | C_CLEAR));
+            self.event.raise();
+        }
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        mmio_read(self.base + offset)
+    }
+
+    fn write_reg(&self, offset: usize, value: u32) {
+        mmio_write(self.base + offset, value);
+    }
+}
+
+/// BSC interrupt handler; the line is shared by all controllers.
+fn handle_interrupt(_irq: u32) {
+    for i2c in [&BSC0, &BSC1] {
+        if i2c.initialized.load(Ordering::Acquire) {
+            i2c.service_interrupt();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::CORE_CLOCK_HZ;
+
+    #[test]
+    fn test_clock_divider() {
+        assert_eq!(clock_divider(STANDARD_MODE_HZ), Some(2500));
+        assert_eq!(clock_divider(FAST_MODE_HZ), Some(626));
+        assert_eq!(clock_divider(0), None);
+        assert_eq!(clock_divider(1_000), None);
+        assert_eq!(clock_divider(CORE_CLOCK_HZ), Some(2));
+        assert_eq!(clock_divider(u32::MAX), Some(2));
+    }
+
+    #[test]
+    fn test_transfer_validation() {
+        let bus = I2c::new(BSC0_BASE, [0, 1]);
+        assert_eq!(bus.write(0x48, &[0]), Err(DeviceError::NotInitialized));
+
+        bus.initialized.store(true, Ordering::Release);
+        assert_eq!(bus.write(0x80, &[0]), Err(DeviceError::InvalidArgument));
+        assert_eq!(bus.set_speed(0), Err(DeviceError::InvalidArgument));
+    }
+}
@@ -0,0 +1,120 @@
+//! VideoCore mailbox property interface.
+//!
+//! The property channel is how the ARM asks the GPU firmware for things it
+//! owns: framebuffers, clock rates, board information. A request is a buffer
+//! of 32-bit words holding a header and a list of tags; the firmware writes
+//! its responses back into the same buffer.
+
+use super::{mmio_read, mmio_write, DeviceLock, PERIPHERAL_BASE};
+use crate::errors::DeviceError;
+
+const MBOX_BASE: usize = PERIPHERAL_BASE + 0xB880;
+const MBOX_READ: usize = MBOX_BASE; // Mailbox 0 Read
+const MBOX_STATUS: usize = MBOX_BASE + 0x18; // Mailbox 0 Status
+const MBOX_WRITE: usize = MBOX_BASE + 0x20; // Mailbox 1 Write
+
+const STATUS_FULL: u32 = 1 << 31;
+const STATUS_EMPTY: u32 = 1 << 30;
+
+/// Property tags channel (ARM to VideoCore).
+const CHANNEL_PROPERTY: u32 = 8;
+
+/// Request code in the buffer header.
+pub const REQUEST: u32 = 0;
+
+/// Response code the firmware writes on success.
+pub const RESPONSE_SUCCESS: u32 = 0x8000_0000;
+
+/// Terminating tag.
+pub const TAG_END: u32 = 0;
+
+/// Alias the GPU uses for uncached access to ARM memory.
+const GPU_UNCACHED_ALIAS: u32 = 0xC000_0000;
+
+/// Polls of the status register before a call is abandoned.
+const POLL_TIMEOUT_SPINS: u32 = 10_000_000;
+
+static LOCK: DeviceLock = DeviceLock::new();
+
+/// A property request buffer.
+///
+/// Word 0 is the total size in bytes and word 1 the request/response code;
+/// tags follow, ending with [`TAG_END`]. The firmware requires 16-byte
+/// alignment.
+#[repr(C, align(16))]
+pub struct PropertyBuffer<const N: usize>(pub [u32; N]);
+
+impl<const N: usize> PropertyBuffer<N> {
+    /// An empty request: header filled in, all tags zero.
+    pub const fn new() -> Self {
+        let mut words = [0; N];
+        words[0] = (N * 4) as u32;
+        words[1] = REQUEST;
+        Self(words)
+    }
+}
+
+impl<const N: usize> Default for PropertyBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Send a property request and wait for the firmware's response.
+///
+/// Fails with [`DeviceError::Nack`] if the firmware rejects the request and
+/// [`DeviceError::Timeout`] if it never answers.
+pub fn call<const N: usize>(buf: &mut PropertyBuffer<N>) -> Result<(), DeviceError> {
+    let addr = buf.0.as_mut_ptr() as usize;
+    if addr > u32::MAX as usize {
+        return Err(DeviceError::InvalidArgument);
+    }
+
+    let _mbox = LOCK.lock();
+    let message = (addr as u32 | GPU_UNCACHED_ALIAS) & !0xF | CHANNEL_PROPERTY;
+
+    // Make the request visible to the GPU before handing it over
+    core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+
+    wait_status(STATUS_FULL)?;
+    mmio_write(MBOX_WRITE, message);
+
+    loop {
+        wait_status(STATUS_EMPTY)?;
+        if mmio_read(MBOX_READ) == message {
+            break;
+        }
+    }
+
+    core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+    let response = unsafe { core::ptr::read_volatile(&buf.0[1]) };
+    if response == RESPONSE_SUCCESS {
+        Ok(())
+    } else {
+        Err(DeviceError::Nack)
+    }
+}
+
+/// Wait for `flag` to clear in the status register.
+fn wait_status(flag: u32) -> Result<(), DeviceError> {
+    for _ in 0..POLL_TIMEOUT_SPINS {
+        if mmio_read(MBOX_STATUS) & flag == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(DeviceError::Timeout)
+}
+
+/// Clock ID of the EMMC controller's base clock.
+pub const CLOCK_EMMC: u32 = 1;
+
+const TAG_GET_CLOCK_RATE: u32 = 0x0003_0002;
+
+/// Query the current rate of a firmware-managed clock in Hz.
+pub fn clock_rate(clock_id: u32) -> Result<u32, DeviceError> {
+    let mut msg = PropertyBuffer::<8>::new();
+    msg.0[2..8].copy_from_slice(&[TAG_GET_CLOCK_RATE, 8, 0, clock_id, 0, TAG_END]);
+    call(&mut msg)?;
+    Ok(msg.0[6])
+}
@@ -0,0 +1,185 @@
+//! Peripheral drivers for the BCM2837.
+//!
+//! Drivers that wait on hardware block the calling thread on a
+//! [`WaitQueue`](crate::sync::WaitQueue) and are woken from the peripheral's
+//! interrupt handler, so other threads keep running during transfers.
+//!
+//! Each driver is behind a cargo feature of the same name (`sdhost` for
+//! the SD card), all enabled by the default `drivers` feature. Building
+//! with only the ones a board uses keeps the others out of the image;
+//! `scripts/size-matrix.sh` reports what each one adds. [`block`] and
+//! [`pm`] are always built, since the filesystem, crash log and panic
+//! reboot rely on them.
+
+use crate::kernel;
+use crate::sync::WaitQueue;
+use crate::thread::ThreadId;
+use portable_atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub mod block;
+#[cfg(feature = "clock")]
+pub mod clock;
+#[cfg(feature = "dma")]
+pub mod dma;
+#[cfg(feature = "framebuffer")]
+pub mod framebuffer;
+#[cfg(feature = "gpio")]
+pub mod gpio;
+#[cfg(feature = "i2c")]
+pub mod i2c;
+#[cfg(feature = "mailbox")]
+pub mod mailbox;
+pub mod pm;
+#[cfg(feature = "pwm")]
+pub mod pwm;
+#[cfg(feature = "sdhost")]
+pub mod sdhost;
+#[cfg(feature = "spi")]
+pub mod spi;
+
+/// Base address of the BCM2837 peripheral window (ARM physical).
+pub(crate) const PERIPHERAL_BASE: usize = 0x3F00_0000;
+
+/// GIC SPI number of VideoCore interrupt 0 on the GIC-400.
+#[cfg_attr(
+    not(any(feature = "i2c", feature = "spi", feature = "sdhost", feature = "dma")),
+    allow(dead_code)
+)]
+pub(crate) const VC_IRQ_BASE: u32 = 96;
+
+/// VPU core clock, which drives the SPI and BSC controllers.
+#[cfg_attr(not(any(feature = "i2c", feature = "spi")), allow(dead_code))]
+pub(crate) const CORE_CLOCK_HZ: u32 = 250_000_000;
+
+/// Compute the SPI or BSC clock divider for `speed_hz`.
+///
+/// The divider is rounded up (so the bus never runs faster than requested)
+/// to the even values both controllers support.
+#[cfg_attr(not(any(feature = "i2c", feature = "spi")), allow(dead_code))]
+pub(crate) fn clock_divider(speed_hz: u32) -> Option<u32> {
+    if speed_hz == 0 {
+        return None;
+    }
+    let div = CORE_CLOCK_HZ / speed_hz + (CORE_CLOCK_HZ % speed_hz != 0) as u32;
+    let div = div + (div & 1);
+    (2..=0xFFFE).contains(&div).then_some(div)
+}
+
+/// Read a peripheral register.
+#[inline]
+pub(crate) fn mmio_read(addr: usize) -> u32 {
+    #[cfg(target_arch = "aarch64")]
+    {
+        unsafe { core::ptr::read_volatile(addr as *const u32) }
+    }
+
+    // Host builds have no peripherals: every register reads as zero
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        let _ = addr;
+        0
+    }
+}
+
+/// Write a peripheral register.
+#[inline]
+pub(crate) fn mmio_write(addr: usize, value: u32) {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        core::ptr::write_volatile(addr as *mut u32, value);
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        let _ = (addr, value);
+    }
+}
+
+/// Exclusive ownership of a controller, held for a whole transfer.
+///
+/// Contending threads block on a wait queue rather than spinning, since a
+/// transfer can take milliseconds.
+pub(crate) struct DeviceLock {
+    busy: AtomicBool,
+    /// Holder's thread ID, 0 if unknown, for starvation diagnostics
+    owner: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+pub(crate) struct DeviceGuard<'a>(&'a DeviceLock);
+
+impl DeviceLock {
+    pub(crate) const fn new() -> Self {
+        Self {
+            busy: AtomicBool::new(false),
+            owner: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub(crate) fn lock(&self) -> DeviceGuard<'_> {
+        let current = kernel::global_ops().and_then(|ops| ops.current_thread());
+        self.waiters.wait_until(|| {
+            let acquired = self
+                .busy
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok();
+            if let Some(thread) = &current {
+                let owner = self.owner.load(Ordering::Relaxed);
+                thread
+                    .set_waiting_on((!acquired && owner != 0).then(|| ThreadId::new(owner as u64)));
+            }
+            acquired
+        });
+        let id = current.map_or(0, |thread| thread.id().get());
+        self.owner.store(id, Ordering::Relaxed);
+        DeviceGuard(self)
+    }
+}
+
+impl Drop for DeviceGuard<'_> {
+    fn drop(&mut self) {
+        self.0.owner.store(0, Ordering::Relaxed);
+        self.0.busy.store(false, Ordering::Release);
+        self.0.waiters.notify_one();
+        crate::platform_timer::preemption_checkpoint();
+    }
+}
+
+/// Completion flag raised by an interrupt handler and awaited by a thread.
+#[cfg_attr(
+    not(any(feature = "i2c", feature = "spi", feature = "sdhost", feature = "dma")),
+    allow(dead_code)
+)]
+pub(crate) struct IrqEvent {
+    raised: AtomicBool,
+    waiters: WaitQueue,
+}
+
+#[cfg_attr(
+    not(any(feature = "i2c", feature = "spi", feature = "sdhost", feature = "dma")),
+    allow(dead_code)
+)]
+impl IrqEvent {
+    pub(crate) const fn new() -> Self {
+        Self {
+            raised: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Clear the flag, run `arm` (which unmasks the device interrupt) and
+    /// block until [`IrqEvent::raise`] is called.
+    pub(crate) fn wait(&self, arm: impl FnOnce()) {
+        self.raised.store(false, Ordering::Release);
+        crate::arch::without_interrupts(arm);
+        self.waiters
+            .wait_until(|| self.raised.load(Ordering::Acquire));
+    }
+
+    /// Signal the event from interrupt context.
+    pub(crate) fn raise(&self) {
+        self.raised.store(true, Ordering::Release);
+        self.waiters.notify_one();
+    }
+}
@@ -0,0 +1,53 @@
+//! BCM2837 power management watchdog, used to reset the board.
+//!
+//! The PM block's watchdog counts down in ticks of 1/65536 s and resets
+//! the SoC when it expires. [`start_watchdog`] arms it, [`stop_watchdog`]
+//! disarms it and [`reboot`] resets right away. On the QEMU virt machine,
+//! which has no PM block, [`reboot`] asks the firmware through PSCI instead.
+
+use super::{mmio_read, mmio_write, PERIPHERAL_BASE};
+use crate::time::Duration;
+
+const PM_BASE: usize = PERIPHERAL_BASE + 0x10_0000;
+const PM_RSTC: usize = PM_BASE + 0x1C; // Reset Control
+const PM_WDOG: usize = PM_BASE + 0x24; // Watchdog Timer
+
+/// Every PM register write must carry this in its top byte.
+const PM_PASSWORD: u32 = 0x5A00_0000;
+const RSTC_WRCFG_MASK: u32 = 0x30;
+const RSTC_WRCFG_FULL_RESET: u32 = 0x20;
+const RSTC_RESET: u32 = 0x102;
+
+/// Watchdog ticks per second.
+const TICKS_PER_SEC: u64 = 65_536;
+/// Largest count the 20-bit timer holds.
+const MAX_TICKS: u64 = 0xF_FFFF;
+
+/// Reset the board once `timeout` passes, unless stopped or restarted
+/// first. The timeout is clamped to the 16 seconds the timer can count.
+pub fn start_watchdog(timeout: Duration) {
+    let ticks = (timeout.as_nanos().saturating_mul(TICKS_PER_SEC) / 1_000_000_000)
+        .clamp(1, MAX_TICKS) as u32;
+    let rstc = mmio_read(PM_RSTC) & !RSTC_WRCFG_MASK;
+    mmio_write(PM_WDOG, PM_PASSWORD | ticks);
+    mmio_write(PM_RSTC, PM_PASSWORD | rstc | RSTC_WRCFG_FULL_RESET);
+}
+
+/// Disarm the watchdog.
+pub fn stop_watchdog() {
+    mmio_write(PM_RSTC, PM_PASSWORD | RSTC_RESET);
+}
+
+/// Reset the board.
+pub fn reboot() -> ! {
+    #[cfg(all(target_arch = "aarch64", feature = "qemu-virt"))]
+    unsafe {
+        // PSCI SYSTEM_RESET
+        core::arch::asm!("hvc #0", in("x0") 0x8400_0009u64, options(nomem, nostack));
+    }
+    #[cfg(not(feature = "qemu-virt"))]
+    start_watchdog(Duration::from_micros(20));
+    loop {
+        core::hint::spin_loop();
+    }
+}
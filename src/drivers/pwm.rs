@@ -0,0 +1,351 @@
+//! PWM driver for servo and LED control.
+//!
+//! The BCM2837 PWM peripheral has two channels, clocked from the clock
+//! manager and routable to GPIO 12/18 (channel 0) and 13/19 (channel 1).
+//! Each channel runs in mark-space mode: the output is high for the duty
+//! portion of every period.
+//!
+//! [`SoftPwm`] drives any GPIO pin from high-resolution timer callbacks,
+//! for when more outputs are needed than the hardware has. Its edges are
+//! subject to interrupt latency, which is fine for LEDs and hobby servos
+//! but not for precise waveforms.
+//!
+//! Duty cycles are given as a fraction of [`DUTY_MAX`].
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::drivers::pwm::{self, Channel};
+//! use preemptive_threads::time::Duration;
+//!
+//! pwm::init(pwm::DEFAULT_CLOCK_HZ)?;
+//! pwm::enable(Channel::Pwm0, 18, 50)?;                            // 50 Hz servo frame
+//! pwm::set_pulse_width(Channel::Pwm0, Duration::from_micros(1500))?; // centre
+//! ```
+
+use super::clock::{self, Clock, Source};
+use super::gpio::{self, Function};
+use super::{mmio_read, mmio_write, PERIPHERAL_BASE};
+use crate::arch;
+use crate::errors::{DeviceError, ResourceError, ThreadError};
+use crate::time::hrtimer::{self, HrTimerId};
+use crate::time::{Duration, Instant};
+use portable_atomic::{AtomicU32, Ordering};
+
+const PWM_BASE: usize = PERIPHERAL_BASE + 0x20_C000;
+
+// PWM registers
+const CTL: usize = PWM_BASE; // Control
+const RNG1: usize = PWM_BASE + 0x10; // Channel 0 Range
+const DAT1: usize = PWM_BASE + 0x14; // Channel 0 Data
+const RNG2: usize = PWM_BASE + 0x20; // Channel 1 Range
+const DAT2: usize = PWM_BASE + 0x24; // Channel 1 Data
+
+// Per-channel control bits (channel 1 is shifted up by 8)
+const CTL_PWEN: u32 = 1 << 0;
+const CTL_MSEN: u32 = 1 << 7;
+
+/// Full duty cycle.
+pub const DUTY_MAX: u16 = u16::MAX;
+
+/// Default PWM clock: 1 MHz gives 1 µs pulse-width resolution.
+pub const DEFAULT_CLOCK_HZ: u32 = 1_000_000;
+
+/// Number of software PWM outputs that can run at once.
+pub const MAX_SOFT_CHANNELS: usize = 8;
+
+/// PWM clock frequency; zero until [`init`] is called.
+static CLOCK_HZ: AtomicU32 = AtomicU32::new(0);
+static RANGE: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+static DUTY: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+
+/// Hardware PWM channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// Channel 0, on GPIO 12 or 18
+    Pwm0,
+    /// Channel 1, on GPIO 13 or 19
+    Pwm1,
+}
+
+impl Channel {
+    fn index(self) -> usize {
+        match self {
+            Channel::Pwm0 => 0,
+            Channel::Pwm1 => 1,
+        }
+    }
+
+    fn ctl_shift(self) -> u32 {
+        self.index() as u32 * 8
+    }
+
+    fn rng(self) -> usize {
+        [RNG1, RNG2][self.index()]
+    }
+
+    fn dat(self) -> usize {
+        [DAT1, DAT2][self.index()]
+    }
+
+    /// GPIO function that routes this channel to `pin`, if it can be.
+    fn pin_function(self, pin: u32) -> Option<Function> {
+        match (self, pin) {
+            (Channel::Pwm0, 12) | (Channel::Pwm1, 13) => Some(Function::Alt0),
+            (Channel::Pwm0, 18) | (Channel::Pwm1, 19) => Some(Function::Alt5),
+            _ => None,
+        }
+    }
+}
+
+/// Start the PWM clock at `clock_hz` and stop both channels.
+///
+/// The clock sets the resolution of every channel: a channel at frequency
+/// `f` has `clock_hz / f` duty steps.
+pub fn init(clock_hz: u32) -> Result<(), ThreadError> {
+    mmio_write(CTL, 0);
+    let actual = clock::configure_frequency(Clock::Pwm, Source::PllD, clock_hz)?;
+    CLOCK_HZ.store(actual, Ordering::Release);
+    Ok(())
+}
+
+/// Route `channel` to `pin` and start it at `freq_hz` with its current duty.
+pub fn enable(channel: Channel, pin: u32, freq_hz: u32) -> Result<(), DeviceError> {
+    let function = channel
+        .pin_function(pin)
+        .ok_or(DeviceError::InvalidArgument)?;
+    set_frequency(channel, freq_hz)?;
+    gpio::set_function(pin, function)?;
+
+    update_ctl(|ctl| ctl | ((CTL_PWEN | CTL_MSEN) << channel.ctl_shift()));
+    Ok(())
+}
+
+/// Stop `channel`; its output idles low.
+pub fn disable(channel: Channel) {
+    update_ctl(|ctl| ctl & !(CTL_PWEN << channel.ctl_shift()));
+}
+
+/// Set the period of `channel`, keeping its duty cycle.
+pub fn set_frequency(channel: Channel, freq_hz: u32) -> Result<(), DeviceError> {
+    let clock_hz = CLOCK_HZ.load(Ordering::Acquire);
+    if clock_hz == 0 {
+        return Err(DeviceError::NotInitialized);
+    }
+    if freq_hz == 0 || clock_hz / freq_hz < 2 {
+        return Err(DeviceError::InvalidArgument);
+    }
+
+    let range = clock_hz / freq_hz;
+    RANGE[channel.index()].store(range, Ordering::Release);
+    mmio_write(channel.rng(), range);
+    write_duty(
+        channel,
+        DUTY[channel.index()].load(Ordering::Acquire) as u16,
+    );
+    Ok(())
+}
+
+/// Set the duty cycle of `channel` as a fraction of [`DUTY_MAX`].
+pub fn set_duty(channel: Channel, duty: u16) -> Result<(), DeviceError> {
+    if CLOCK_HZ.load(Ordering::Acquire) == 0 {
+        return Err(DeviceError::NotInitialized);
+    }
+    write_duty(channel, duty);
+    Ok(())
+}
+
+/// Set how long `channel` stays high each period (e.g. a servo pulse).
+pub fn set_pulse_width(channel: Channel, width: Duration) -> Result<(), DeviceError> {
+    let range = RANGE[channel.index()].load(Ordering::Acquire);
+    if range == 0 {
+        return Err(DeviceError::NotInitialized);
+    }
+
+    let period_ns = 1_000_000_000 * range as u64 / CLOCK_HZ.load(Ordering::Acquire) as u64;
+    if width.as_nanos() > period_ns {
+        return Err(DeviceError::InvalidArgument);
+    }
+    write_duty(channel, duty_for_width(width.as_nanos(), period_ns));
+    Ok(())
+}
+
+fn write_duty(channel: Channel, duty: u16) {
+    DUTY[channel.index()].store(duty as u32, Ordering::Release);
+    let range = RANGE[channel.index()].load(Ordering::Acquire);
+    mmio_write(channel.dat(), scale_duty(range as u64, duty) as u32);
+}
+
+fn update_ctl(f: impl FnOnce(u32) -> u32) {
+    // Both channels share CTL
+    arch::without_interrupts(|| mmio_write(CTL, f(mmio_read(CTL))));
+}
+
+/// `duty` as a fraction of [`DUTY_MAX`] applied to `total`.
+fn scale_duty(total: u64, duty: u16) -> u64 {
+    total * duty as u64 / DUTY_MAX as u64
+}
+
+fn duty_for_width(width_ns: u64, period_ns: u64) -> u16 {
+    (width_ns.min(period_ns) * DUTY_MAX as u64 / period_ns) as u16
+}
+
+#[derive(Clone, Copy)]
+struct SoftChannel {
+    pin: u32,
+    period_ns: u64,
+    high_ns: u64,
+    /// Start of the current period, in nanoseconds since boot
+    period_start: u64,
+    /// The one edge timer this channel has pending
+    timer: Option<HrTimerId>,
+}
+
+static SOFT_CHANNELS: spin::Mutex<[Option<SoftChannel>; MAX_SOFT_CHANNELS]> =
+    spin::Mutex::new([None; MAX_SOFT_CHANNELS]);
+
+/// Software PWM output on an arbitrary GPIO pin.
+///
+/// Dropping it stops the output and drives the pin low.
+pub struct SoftPwm {
+    index: usize,
+}
+
+impl SoftPwm {
+    /// Configure `pin` as an output and start it at `freq_hz`, 0% duty.
+    pub fn new(pin: u32, freq_hz: u32) -> Result<Self, ThreadError> {
+        let period_ns = period_ns(freq_hz)?;
+        gpio::set_function(pin, Function::Output)?;
+        gpio::write(pin, false)?;
+
+        let index = arch::without_interrupts(|| {
+            let mut channels = SOFT_CHANNELS.lock();
+            let index = channels
+                .iter()
+                .position(Option::is_none)
+                .ok_or(ResourceError::ResourceUnavailable)?;
+            channels[index] = Some(SoftChannel {
+                pin,
+                period_ns,
+                high_ns: 0,
+                period_start: Instant::now().as_nanos(),
+                timer: None,
+            });
+            Ok::<_, ResourceError>(index)
+        })?;
+
+        // The first rising edge arms the timer chain
+        arch::without_interrupts(|| soft_edge(index << 1));
+        Ok(Self { index })
+    }
+
+    /// Change the period, keeping the duty cycle. Takes effect next period.
+    pub fn set_frequency(&self, freq_hz: u32) -> Result<(), DeviceError> {
+        let period = period_ns(freq_hz)?;
+        self.update(|ch| {
+            let duty = duty_for_width(ch.high_ns, ch.period_ns);
+            ch.period_ns = period;
+            ch.high_ns = scale_duty(period, duty);
+        });
+        Ok(())
+    }
+
+    /// Set the duty cycle as a fraction of [`DUTY_MAX`]. Takes effect next period.
+    pub fn set_duty(&self, duty: u16) {
+        self.update(|ch| ch.high_ns = scale_duty(ch.period_ns, duty));
+    }
+
+    /// Set how long the pin stays high each period. Takes effect next period.
+    pub fn set_pulse_width(&self, width: Duration) {
+        self.update(|ch| ch.high_ns = width.as_nanos().min(ch.period_ns));
+    }
+
+    fn update(&self, f: impl FnOnce(&mut SoftChannel)) {
+        arch::without_interrupts(|| {
+            if let Some(ch) = SOFT_CHANNELS.lock()[self.index].as_mut() {
+                f(ch);
+            }
+        });
+    }
+}
+
+impl Drop for SoftPwm {
+    fn drop(&mut self) {
+        let channel = arch::without_interrupts(|| SOFT_CHANNELS.lock()[self.index].take());
+        if let Some(ch) = channel {
+            if let Some(timer) = ch.timer {
+                hrtimer::cancel(timer);
+            }
+            let _ = gpio::write(ch.pin, false);
+        }
+    }
+}
+
+fn period_ns(freq_hz: u32) -> Result<u64, DeviceError> {
+    if freq_hz == 0 || freq_hz > 1_000_000 {
+        return Err(DeviceError::InvalidArgument);
+    }
+    Ok(1_000_000_000 / freq_hz as u64)
+}
+
+/// Software PWM edge timer callback.
+///
+/// `arg` is the channel index shifted left by one, with bit 0 set for a
+/// falling edge. Each channel keeps exactly one edge timer pending.
+fn soft_edge(arg: usize) {
+    let index = arg >> 1;
+    let falling = arg & 1 != 0;
+
+    let mut channels = SOFT_CHANNELS.lock();
+    let Some(ch) = channels[index].as_mut() else {
+        return;
+    };
+
+    let (level, next_edge, next_arg) = if falling {
+        (false, ch.period_start + ch.period_ns, index << 1)
+    } else {
+        let now = Instant::now().as_nanos();
+        if ch.timer.is_some() {
+            ch.period_start += ch.period_ns;
+        }
+        // Fell more than a period behind (long IRQ-off section): resync
+        // instead of firing a burst of catch-up edges
+        if ch.period_start + ch.period_ns < now {
+            ch.period_start = now;
+        }
+
+        if ch.high_ns == 0 || ch.high_ns >= ch.period_ns {
+            (ch.high_ns > 0, ch.period_start + ch.period_ns, index << 1)
+        } else {
+            (true, ch.period_start + ch.high_ns, (index << 1) | 1)
+        }
+    };
+
+    let _ = gpio::write(ch.pin, level);
+    ch.timer = hrtimer::start_at(Instant::from_nanos(next_edge), soft_edge, next_arg).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duty_scaling() {
+        assert_eq!(scale_duty(20_000, DUTY_MAX), 20_000);
+        assert_eq!(scale_duty(20_000, 0), 0);
+        assert_eq!(scale_duty(20_000, DUTY_MAX / 2), 9_999);
+
+        // 1.5 ms servo pulse in a 20 ms frame
+        let duty = duty_for_width(1_500_000, 20_000_000);
+        assert_eq!(scale_duty(20_000, duty), 1_499);
+    }
+
+    #[test]
+    fn test_pin_routing() {
+        assert_eq!(Channel::Pwm0.pin_function(18), Some(Function::Alt5));
+        assert_eq!(Channel::Pwm1.pin_function(13), Some(Function::Alt0));
+        assert_eq!(Channel::Pwm0.pin_function(13), None);
+        assert_eq!(period_ns(0), Err(DeviceError::InvalidArgument));
+        assert_eq!(period_ns(50), Ok(20_000_000));
+    }
+}
@@ -0,0 +1,321 @@
+//! SPI master driver for the BCM2837 SPI0 controller.
+//!
+//! Transfers are full duplex and interrupt driven: the calling thread keeps
+//! the 64-byte FIFOs topped up, then blocks until the controller raises an
+//! interrupt because the RX FIFO is filling up or the transfer is done. No
+//! DMA channel is used.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::drivers::spi::{self, Config, Mode};
+//!
+//! let spi = spi::spi0();
+//! spi.init(Config { mode: Mode::Mode3, speed_hz: 8_000_000, ..Config::default() })?;
+//!
+//! let tx = [0x80 | 0x0F, 0x00];
+//! let mut rx = [0u8; 2];
+//! spi.transfer(&tx, &mut rx)?;
+//! ```
+
+use super::{
+    clock_divider, gpio, mmio_read, mmio_write, DeviceLock, IrqEvent, PERIPHERAL_BASE, VC_IRQ_BASE,
+};
+use crate::errors::{DeviceError, ThreadError};
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+
+const SPI0_BASE: usize = PERIPHERAL_BASE + 0x20_4000;
+
+// SPI0 registers (offsets from controller base)
+const CS: usize = 0x00; // Control and Status
+const FIFO: usize = 0x04; // TX and RX FIFOs
+const CLK: usize = 0x08; // Clock Divider
+
+// Control and status bits
+const CS_CPHA: u32 = 1 << 2;
+const CS_CPOL: u32 = 1 << 3;
+const CS_CLEAR: u32 = 0b11 << 4;
+const CS_CSPOL: u32 = 1 << 6;
+const CS_TA: u32 = 1 << 7;
+const CS_INTD: u32 = 1 << 9;
+const CS_INTR: u32 = 1 << 10;
+const CS_DONE: u32 = 1 << 16;
+const CS_RXD: u32 = 1 << 17;
+const CS_TXD: u32 = 1 << 18;
+const CS_RXR: u32 = 1 << 19;
+const CS_INT_MASK: u32 = CS_INTR | CS_INTD;
+
+/// Depth of the SPI0 transmit and receive FIFOs.
+const FIFO_DEPTH: usize = 64;
+
+/// SPI0 interrupt line.
+pub const SPI_IRQ: u32 = VC_IRQ_BASE + 54;
+
+/// GPIO pins used by SPI0 (CE1, CE0, MISO, MOSI, SCLK).
+const SPI0_PINS: [u32; 5] = [7, 8, 9, 10, 11];
+
+static SPI0: Spi = Spi::new(SPI0_BASE);
+
+/// Get the SPI0 driver.
+pub fn spi0() -> &'static Spi {
+    &SPI0
+}
+
+/// Clock polarity and phase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// CPOL = 0, CPHA = 0
+    Mode0,
+    /// CPOL = 0, CPHA = 1
+    Mode1,
+    /// CPOL = 1, CPHA = 0
+    Mode2,
+    /// CPOL = 1, CPHA = 1
+    Mode3,
+}
+
+impl Mode {
+    fn cs_bits(self) -> u32 {
+        match self {
+            Mode::Mode0 => 0,
+            Mode::Mode1 => CS_CPHA,
+            Mode::Mode2 => CS_CPOL,
+            Mode::Mode3 => CS_CPOL | CS_CPHA,
+        }
+    }
+}
+
+/// Chip select line asserted during transfers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChipSelect {
+    /// CE0 on GPIO 8
+    Ce0,
+    /// CE1 on GPIO 7
+    Ce1,
+}
+
+/// SPI bus configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Clock polarity and phase
+    pub mode: Mode,
+    /// SCLK frequency; rounded down to one the divider can produce
+    pub speed_hz: u32,
+    /// Chip select line
+    pub chip_select: ChipSelect,
+    /// Whether chip select is active high
+    pub cs_active_high: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Mode0,
+            speed_hz: 1_000_000,
+            chip_select: ChipSelect::Ce0,
+            cs_active_high: false,
+        }
+    }
+}
+
+/// An SPI master.
+pub struct Spi {
+    base: usize,
+    initialized: AtomicBool,
+    /// Mode and chip select bits applied to CS at the start of a transfer
+    cs_config: AtomicU32,
+    /// Raised by the interrupt handler when the controller needs attention
+    event: IrqEvent,
+    lock: DeviceLock,
+}
+
+impl Spi {
+    const fn new(base: usize) -> Self {
+        Self {
+            base,
+            initialized: AtomicBool::new(false),
+            cs_config: AtomicU32::new(0),
+            event: IrqEvent::new(),
+            lock: DeviceLock::new(),
+        }
+    }
+
+    /// Route the SPI0 pins, apply `config` and enable the SPI interrupt.
+    pub fn init(&self, config: Config) -> Result<(), ThreadError> {
+        for pin in SPI0_PINS {
+            gpio::set_function(pin, gpio::Function::Alt0)?;
+        }
+
+        self.write_reg(CS, CS_CLEAR);
+        self.apply_config(&config)?;
+
+        crate::irq::register_handler(SPI_IRQ, handle_interrupt)?;
+        crate::irq::enable(SPI_IRQ)?;
+
+        self.initialized.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Change mode, speed or chip select.
+    ///
+    /// Waits for any transfer in progress to finish first.
+    pub fn set_config(&self, config: Config) -> Result<(), DeviceError> {
+        let _bus = self.lock.lock();
+        self.apply_config(&config)
+    }
+
+    /// Send `tx` while receiving the same number of bytes into `rx`.
+    pub fn transfer(&self, tx: &[u8], rx: &mut [u8]) -> Result<(), DeviceError> {
+        if tx.len() != rx.len() {
+            return Err(DeviceError::InvalidArgument);
+        }
+        self.run(tx.len(), |i| tx[i], |i, byte| rx[i] = byte)
+    }
+
+    /// Send `buf` and replace its contents with the bytes received.
+    pub fn transfer_in_place(&self, buf: &mut [u8]) -> Result<(), DeviceError> {
+        let len = buf.len();
+        let buf = core::cell::Cell::from_mut(buf).as_slice_of_cells();
+        self.run(len, |i| buf[i].get(), |i, byte| buf[i].set(byte))
+    }
+
+    /// Send `tx`, discarding received bytes.
+    pub fn write(&self, tx: &[u8]) -> Result<(), DeviceError> {
+        self.run(tx.len(), |i| tx[i], |_, _| {})
+    }
+
+    /// Receive into `rx` while sending zeros.
+    pub fn read(&self, rx: &mut [u8]) -> Result<(), DeviceError> {
+        self.run(rx.len(), |_| 0, |i, byte| rx[i] = byte)
+    }
+
+    fn apply_config(&self, config: &Config) -> Result<(), DeviceError> {
+        let div = clock_divider(config.speed_hz).ok_or(DeviceError::InvalidArgument)?;
+
+        let mut cs = config.mode.cs_bits();
+        cs |= match config.chip_select {
+            ChipSelect::Ce0 => 0,
+            ChipSelect::Ce1 => 1,
+        };
+        if config.cs_active_high {
+            cs |= CS_CSPOL;
+        }
+
+        self.write_reg(CLK, div);
+        self.cs_config.store(cs, Ordering::Release);
+        Ok(())
+    }
+
+    /// Run a full-duplex transfer of `len` bytes.
+    ///
+    /// `next_tx(i)` supplies byte `i` to send and `store_rx(i, byte)` takes
+    /// byte `i` received. At most one FIFO's worth of bytes is in flight so
+    /// the RX FIFO can never overflow.
+    fn run(
+        &self,
+        len: usize,
+        mut next_tx: impl FnMut(usize) -> u8,
+        mut store_rx: impl FnMut(usize, u8),
+    ) -> Result<(), DeviceError> {
+        if !self.initialized.load(Ordering::Acquire) {
+            return Err(DeviceError::NotInitialized);
+        }
+        if len == 0 {
+            return Ok(());
+        }
+
+        let _bus = self.lock.lock();
+        let cs = self.cs_config.load(Ordering::Acquire);
+
+        self.write_reg(CS, cs | CS_CLEAR);
+        self.write_reg(CS, cs | CS_TA);
+
+        let mut sent = 0;
+        let mut received = 0;
+        while received < len {
+            while received < len && self.read_reg(CS) & CS_RXD != 0 {
+                store_rx(received, self.read_reg(FIFO) as u8);
+                received += 1;
+            }
+
+            while sent < len && sent - received < FIFO_DEPTH && self.read_reg(CS) & CS_TXD != 0 {
+                self.write_reg(FIFO, next_tx(sent) as u32);
+                sent += 1;
+            }
+
+            if received < len {
+                // INTR fires once the RX FIFO is 3/4 full, INTD once everything
+                // queued has been clocked out
+                self.event.wait(|| {
+                    self.write_reg(CS, cs | CS_TA | CS_INT_MASK);
+                });
+            }
+        }
+
+        self.write_reg(CS, cs);
+        Ok(())
+    }
+
+    /// Mask the controller's interrupts and wake the waiter if one fired.
+    fn service_interrupt(&self) {
+        let cs = self.read_reg(CS);
+        if cs & CS_INT_MASK == 0 {
+            return;
+        }
+
+        let pending =
+            (cs & CS_INTD != 0 && cs & CS_DONE != 0) || (cs & CS_INTR != 0 && cs & CS_RXR != 0);
+
+        if pending {
+            self.write_reg(CS, cs & !(CS_INT_MASK | CS_CLEAR));
+            self.event.raise();
+        }
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        mmio_read(self.base + offset)
+    }
+
+    fn write_reg(&self, offset: usize, value: u32) {
+        mmio_write(self.base + offset, value);
+    }
+}
+
+fn handle_interrupt(_irq: u32) {
+    if SPI0.initialized.load(Ordering::Acquire) {
+        SPI0.service_interrupt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_divider() {
+        assert_eq!(clock_divider(1_000_000), Some(250));
+        assert_eq!(clock_divider(3_000_000), Some(84));
+        assert_eq!(clock_divider(0), None);
+        assert_eq!(clock_divider(1_000), None);
+    }
+
+    #[test]
+    fn test_transfer_validation() {
+        let spi = Spi::new(SPI0_BASE);
+        assert_eq!(spi.write(&[1, 2]), Err(DeviceError::NotInitialized));
+
+        spi.initialized.store(true, Ordering::Release);
+        assert_eq!(
+            spi.transfer(&[1, 2], &mut [0]),
+            Err(DeviceError::InvalidArgument)
+        );
+        assert_eq!(spi.read(&mut []), Ok(()));
+        assert_eq!(
+            spi.apply_config(&Config {
+                speed_hz: 0,
+                ..Config::default()
+            }),
+            Err(DeviceError::InvalidArgument)
+        );
+    }
+}
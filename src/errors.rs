@@ -1,15 +1,18 @@
-
-
 #![allow(clippy::uninlined_format_args)]
 
+//! Error types.
+//!
+//! None of the errors allocate, so they can be built and returned from
+//! interrupt handlers and `no_alloc` paths. Every variant has a stable
+//! numeric code (`as_code()`) for logging and FFI: the high byte names the
+//! category, the low byte the variant.
+
 use core::fmt;
-extern crate alloc;
-use alloc::string::String;
 
 /// Result type for threading operations.
 pub type ThreadResult<T> = Result<T, ThreadError>;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ThreadError {
     Spawn(SpawnError),
     Join(JoinError),
@@ -17,13 +20,15 @@ pub enum ThreadError {
     Memory(MemoryError),
 
     Arch(ArchError),
+    Device(DeviceError),
+    Fs(FsError),
     Tls(TlsError),
     Permission(PermissionError),
     Resource(ResourceError),
     InvalidOperation(InvalidOperationError),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SpawnError {
     NotInitialized,
     OutOfMemory,
@@ -31,13 +36,89 @@ pub enum SpawnError {
     InvalidStackSize(usize),
     InvalidPriority(u8),
     InvalidAffinity(u64),
-    InvalidName(String),
-    UnsupportedFeature(String),
+    InvalidName(NameError),
+    UnsupportedFeature(&'static str),
     SchedulerRejected,
 }
 
+/// Why a thread name was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameError {
+    /// The name is empty
+    Empty,
+    /// The name is longer than the given number of bytes
+    TooLong(usize),
+    /// The name contains a control character
+    ControlCharacter,
+}
+
+/// Why a thread could not be checkpointed or restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointError {
+    /// No live thread has the given id
+    NoSuchThread,
+    /// The thread must be suspended while its state is copied
+    NotSuspended,
+    /// The thread runs on a stack the kernel doesn't own
+    NoStack,
+    /// The buffer can't hold the stack image, which needs this many bytes
+    BufferTooSmall { needed: usize },
+    /// The restored thread could not be created
+    Spawn(SpawnError),
+}
+
+/// Why a synthetic exception from [`arch::inject`](crate::arch) failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InjectError {
+    /// Not available on this target
+    Unsupported,
+    /// Called with interrupts masked or from an interrupt handler
+    InterruptsMasked,
+    /// The handler never ran
+    NotDelivered,
+    /// A register did not hold its pattern; `reg` is its number
+    Corrupted { reg: u8, expected: u64, found: u64 },
+}
+
+/// Why kernel initialization or a run-state change failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelError {
+    /// The kernel was already initialized
+    AlreadyInitialized,
+    /// The global allocator could not satisfy a probe allocation
+    HeapNotConfigured,
+    /// No interrupt controller responded at its expected address
+    InterruptControllerMissing,
+    /// The preemption timer could not be set up
+    TimerInitFailed(&'static str),
+    /// `CNTFRQ_EL0` disagrees with the frequency measured against the
+    /// system timer
+    TimerFrequency { reported: u64, measured: u64 },
+    /// The kernel hasn't been initialized yet
+    NotInitialized,
+    /// Threads are already being scheduled
+    AlreadyStarted,
+    /// The kernel is shutting down
+    ShuttingDown,
+    /// There was no thread to start
+    NoRunnableThread,
+    /// The timer couldn't be moved to interrupt group 0 for FIQ delivery
+    FiqUnavailable,
+    /// The preemption timer was started or stopped out of turn
+    Timer(TimerError),
+}
+
+/// Errors from starting and stopping the preemption timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// The tick is already armed; stop it before starting it again
+    AlreadyRunning,
+    /// The tick isn't armed
+    NotRunning,
+}
+
 /// Errors that can occur during thread joining.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum JoinError {
     /// Thread has already been joined
     AlreadyJoined,
@@ -51,10 +132,12 @@ pub enum JoinError {
     StillRunning,
     /// Invalid thread handle
     InvalidHandle,
+    /// The calling thread tried to join itself
+    JoinSelf,
 }
 
 /// Errors related to scheduling operations.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ScheduleError {
     /// No schedulable threads available
     NoThreadsAvailable,
@@ -68,10 +151,14 @@ pub enum ScheduleError {
     QueueFull,
     /// Preemption is disabled
     PreemptionDisabled,
+    /// Gang would have more members than there are CPUs
+    GangTooLarge,
+    /// No live thread has the given id
+    NoSuchThread,
 }
 
 /// Memory-related errors.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MemoryError {
     /// Out of memory
     OutOfMemory,
@@ -89,9 +176,8 @@ pub enum MemoryError {
     InvalidLayout,
 }
 
-
 /// Architecture-specific errors.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ArchError {
     /// Unsupported architecture
     UnsupportedArchitecture,
@@ -105,10 +191,50 @@ pub enum ArchError {
     FpuError,
     /// Invalid instruction
     InvalidInstruction,
+    /// Exception vector table not aligned as `VBAR_EL1` requires
+    MisalignedVectorTable,
+    /// Not running at the exception level the kernel expects (EL1)
+    WrongExceptionLevel,
+    /// `VBAR_EL1` didn't read back as the vector table just installed
+    VectorTableNotInstalled,
+}
+
+/// Peripheral driver errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceError {
+    /// Device did not acknowledge its address or data (I2C NACK)
+    Nack,
+    /// Device held the clock low longer than the configured timeout
+    ClockStretchTimeout,
+    /// Operation did not complete in time
+    Timeout,
+    /// Device reported a transfer error (CRC, end bit, protocol)
+    Io,
+    /// Argument out of range for this device (pin, length, speed)
+    InvalidArgument,
+    /// Controller has not been initialized
+    NotInitialized,
+    /// Every channel or slot of the controller is in use
+    Busy,
+}
+
+/// Filesystem errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// The underlying block device failed
+    Device(DeviceError),
+    /// No supported filesystem found, or its structures are corrupt
+    InvalidFilesystem,
+    /// Path does not exist
+    NotFound,
+    /// A path component that must be a directory is a file
+    NotADirectory,
+    /// Expected a file but found a directory
+    IsADirectory,
 }
 
 /// Thread-local storage errors.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TlsError {
     /// TLS key not found
     KeyNotFound,
@@ -123,7 +249,7 @@ pub enum TlsError {
 }
 
 /// Permission and security errors.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PermissionError {
     /// Operation not permitted
     NotPermitted,
@@ -138,7 +264,7 @@ pub enum PermissionError {
 }
 
 /// Resource limit errors.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ResourceError {
     /// Maximum threads per process exceeded
     MaxThreadsPerProcess,
@@ -155,14 +281,14 @@ pub enum ResourceError {
 }
 
 /// Invalid operation errors.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InvalidOperationError {
     /// Operation called on wrong thread
     WrongThread,
     /// Operation called in wrong state
     WrongState,
     /// Invalid parameter provided
-    InvalidParameter(String),
+    InvalidParameter(&'static str),
     /// Operation not supported in current context
     NotSupported,
     /// Deadlock would occur
@@ -181,6 +307,8 @@ impl fmt::Display for ThreadError {
             ThreadError::Schedule(e) => write!(f, "Scheduling error: {}", e),
             ThreadError::Memory(e) => write!(f, "Memory error: {}", e),
             ThreadError::Arch(e) => write!(f, "Architecture error: {}", e),
+            ThreadError::Device(e) => write!(f, "Device error: {}", e),
+            ThreadError::Fs(e) => write!(f, "Filesystem error: {}", e),
             ThreadError::Tls(e) => write!(f, "Thread-local storage error: {}", e),
             ThreadError::Permission(e) => write!(f, "Permission error: {}", e),
             ThreadError::Resource(e) => write!(f, "Resource error: {}", e),
@@ -197,14 +325,102 @@ impl fmt::Display for SpawnError {
             SpawnError::TooManyThreads => write!(f, "Maximum number of threads reached"),
             SpawnError::InvalidStackSize(size) => write!(f, "Invalid stack size: {}", size),
             SpawnError::InvalidPriority(prio) => write!(f, "Invalid priority: {}", prio),
-            SpawnError::InvalidAffinity(affinity) => write!(f, "Invalid CPU affinity: {:#x}", affinity),
-            SpawnError::InvalidName(name) => write!(f, "Invalid thread name: {}", name),
-            SpawnError::UnsupportedFeature(feature) => write!(f, "Unsupported feature: {}", feature),
+            SpawnError::InvalidAffinity(affinity) => {
+                write!(f, "Invalid CPU affinity: {:#x}", affinity)
+            }
+            SpawnError::InvalidName(reason) => write!(f, "Invalid thread name: {}", reason),
+            SpawnError::UnsupportedFeature(feature) => {
+                write!(f, "Unsupported feature: {}", feature)
+            }
             SpawnError::SchedulerRejected => write!(f, "Scheduler rejected thread creation"),
         }
     }
 }
 
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameError::Empty => write!(f, "name is empty"),
+            NameError::TooLong(max) => write!(f, "name is longer than {} bytes", max),
+            NameError::ControlCharacter => write!(f, "name contains a control character"),
+        }
+    }
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointError::NoSuchThread => write!(f, "no such thread"),
+            CheckpointError::NotSuspended => write!(f, "thread is not suspended"),
+            CheckpointError::NoStack => write!(f, "thread has no kernel stack"),
+            CheckpointError::BufferTooSmall { needed } => {
+                write!(f, "buffer too small, {} bytes needed", needed)
+            }
+            CheckpointError::Spawn(e) => write!(f, "restored thread not spawned: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for InjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InjectError::Unsupported => {
+                write!(f, "exception injection not supported on this target")
+            }
+            InjectError::InterruptsMasked => write!(f, "interrupts are masked"),
+            InjectError::NotDelivered => write!(f, "injected exception was not taken"),
+            InjectError::Corrupted {
+                reg,
+                expected,
+                found,
+            } => {
+                write!(
+                    f,
+                    "x{} corrupted: expected {:#x}, found {:#x}",
+                    reg, expected, found
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KernelError::AlreadyInitialized => write!(f, "Kernel already initialized"),
+            KernelError::HeapNotConfigured => {
+                write!(f, "Heap not configured: register a #[global_allocator] backed by memory")
+            }
+            KernelError::InterruptControllerMissing => {
+                write!(f, "Interrupt controller not responding; check the platform feature (qemu-virt)")
+            }
+            KernelError::TimerInitFailed(reason) => write!(f, "Timer initialization failed: {}", reason),
+            KernelError::TimerFrequency { reported, measured } => write!(
+                f,
+                "Generic timer reports {} Hz but counts at about {} Hz; set KernelConfig::TIMER_FREQ_HZ",
+                reported, measured
+            ),
+            KernelError::NotInitialized => write!(f, "Kernel not initialized"),
+            KernelError::AlreadyStarted => write!(f, "Kernel already started scheduling threads"),
+            KernelError::ShuttingDown => write!(f, "Kernel is shutting down"),
+            KernelError::NoRunnableThread => write!(f, "No thread to start: spawn one first"),
+            KernelError::FiqUnavailable => {
+                write!(f, "Cannot route the tick to FIQ: GIC interrupt groups are not writable from here")
+            }
+            KernelError::Timer(e) => write!(f, "Preemption timer: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for TimerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimerError::AlreadyRunning => write!(f, "already running"),
+            TimerError::NotRunning => write!(f, "not running"),
+        }
+    }
+}
+
 impl fmt::Display for JoinError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -214,6 +430,7 @@ impl fmt::Display for JoinError {
             JoinError::Timeout => write!(f, "Join operation timed out"),
             JoinError::StillRunning => write!(f, "Thread is still running"),
             JoinError::InvalidHandle => write!(f, "Invalid thread handle"),
+            JoinError::JoinSelf => write!(f, "Thread cannot join itself"),
         }
     }
 }
@@ -227,6 +444,8 @@ impl fmt::Display for ScheduleError {
             ScheduleError::PriorityChangeNotAllowed => write!(f, "Priority change not allowed"),
             ScheduleError::QueueFull => write!(f, "Scheduler queue is full"),
             ScheduleError::PreemptionDisabled => write!(f, "Preemption is disabled"),
+            ScheduleError::GangTooLarge => write!(f, "Gang has more members than CPUs"),
+            ScheduleError::NoSuchThread => write!(f, "No such thread"),
         }
     }
 }
@@ -245,8 +464,6 @@ impl fmt::Display for MemoryError {
     }
 }
 
-
-
 impl fmt::Display for ArchError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -256,6 +473,35 @@ impl fmt::Display for ArchError {
             ArchError::InterruptError => write!(f, "Interrupt handling error"),
             ArchError::FpuError => write!(f, "FPU operation error"),
             ArchError::InvalidInstruction => write!(f, "Invalid instruction"),
+            ArchError::MisalignedVectorTable => write!(f, "Exception vector table misaligned"),
+            ArchError::WrongExceptionLevel => write!(f, "Not running at EL1"),
+            ArchError::VectorTableNotInstalled => write!(f, "Exception vector table not installed"),
+        }
+    }
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceError::Nack => write!(f, "Device did not acknowledge"),
+            DeviceError::ClockStretchTimeout => write!(f, "Clock stretch timeout"),
+            DeviceError::Timeout => write!(f, "Device operation timed out"),
+            DeviceError::Io => write!(f, "Device I/O error"),
+            DeviceError::InvalidArgument => write!(f, "Invalid device argument"),
+            DeviceError::NotInitialized => write!(f, "Device not initialized"),
+            DeviceError::Busy => write!(f, "Device busy"),
+        }
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::Device(e) => write!(f, "Block device error: {}", e),
+            FsError::InvalidFilesystem => write!(f, "Invalid or unsupported filesystem"),
+            FsError::NotFound => write!(f, "No such file or directory"),
+            FsError::NotADirectory => write!(f, "Not a directory"),
+            FsError::IsADirectory => write!(f, "Is a directory"),
         }
     }
 }
@@ -287,7 +533,9 @@ impl fmt::Display for PermissionError {
 impl fmt::Display for ResourceError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ResourceError::MaxThreadsPerProcess => write!(f, "Maximum threads per process exceeded"),
+            ResourceError::MaxThreadsPerProcess => {
+                write!(f, "Maximum threads per process exceeded")
+            }
             ResourceError::MaxThreadsPerUser => write!(f, "Maximum threads per user exceeded"),
             ResourceError::MaxMemoryUsage => write!(f, "Maximum memory usage exceeded"),
             ResourceError::MaxCpuTime => write!(f, "Maximum CPU time exceeded"),
@@ -302,8 +550,12 @@ impl fmt::Display for InvalidOperationError {
         match self {
             InvalidOperationError::WrongThread => write!(f, "Operation called on wrong thread"),
             InvalidOperationError::WrongState => write!(f, "Operation called in wrong state"),
-            InvalidOperationError::InvalidParameter(param) => write!(f, "Invalid parameter: {}", param),
-            InvalidOperationError::NotSupported => write!(f, "Operation not supported in current context"),
+            InvalidOperationError::InvalidParameter(param) => {
+                write!(f, "Invalid parameter: {}", param)
+            }
+            InvalidOperationError::NotSupported => {
+                write!(f, "Operation not supported in current context")
+            }
             InvalidOperationError::WouldDeadlock => write!(f, "Operation would cause deadlock"),
             InvalidOperationError::AlreadyInProgress => write!(f, "Operation already in progress"),
         }
@@ -336,14 +588,36 @@ impl From<MemoryError> for ThreadError {
     }
 }
 
-
-
 impl From<ArchError> for ThreadError {
     fn from(error: ArchError) -> Self {
         ThreadError::Arch(error)
     }
 }
 
+impl From<DeviceError> for ThreadError {
+    fn from(error: DeviceError) -> Self {
+        ThreadError::Device(error)
+    }
+}
+
+impl From<FsError> for ThreadError {
+    fn from(error: FsError) -> Self {
+        ThreadError::Fs(error)
+    }
+}
+
+impl From<TimerError> for KernelError {
+    fn from(error: TimerError) -> Self {
+        KernelError::Timer(error)
+    }
+}
+
+impl From<DeviceError> for FsError {
+    fn from(error: DeviceError) -> Self {
+        FsError::Device(error)
+    }
+}
+
 impl From<TlsError> for ThreadError {
     fn from(error: TlsError) -> Self {
         ThreadError::Tls(error)
@@ -368,10 +642,6 @@ impl From<InvalidOperationError> for ThreadError {
     }
 }
 
-
-
-
-
 // Convenience constructors for common error patterns
 impl ThreadError {
     /// Create a memory error.
@@ -395,12 +665,321 @@ impl ThreadError {
     }
 
     /// Create an unsupported operation error.
-    pub fn unsupported_operation(msg: String) -> Self {
+    pub fn unsupported_operation(msg: &'static str) -> Self {
         ThreadError::InvalidOperation(InvalidOperationError::InvalidParameter(msg))
     }
 
     /// Create a generic error with a message.
-    pub fn other(msg: String) -> Self {
+    pub fn other(msg: &'static str) -> Self {
         ThreadError::InvalidOperation(InvalidOperationError::InvalidParameter(msg))
     }
-}
\ No newline at end of file
+}
+
+// Error codes: category in the high byte, variant in the low byte
+
+impl ThreadError {
+    /// Stable numeric code for logging and FFI.
+    pub fn as_code(&self) -> u16 {
+        match self {
+            ThreadError::Spawn(e) => e.as_code(),
+            ThreadError::Join(e) => e.as_code(),
+            ThreadError::Schedule(e) => e.as_code(),
+            ThreadError::Memory(e) => e.as_code(),
+            ThreadError::Arch(e) => e.as_code(),
+            ThreadError::Device(e) => e.as_code(),
+            ThreadError::Fs(e) => e.as_code(),
+            ThreadError::Tls(e) => e.as_code(),
+            ThreadError::Permission(e) => e.as_code(),
+            ThreadError::Resource(e) => e.as_code(),
+            ThreadError::InvalidOperation(e) => e.as_code(),
+        }
+    }
+}
+
+impl SpawnError {
+    /// Stable numeric code for logging and FFI.
+    pub fn as_code(&self) -> u16 {
+        0x0100
+            | match self {
+                SpawnError::NotInitialized => 1,
+                SpawnError::OutOfMemory => 2,
+                SpawnError::TooManyThreads => 3,
+                SpawnError::InvalidStackSize(_) => 4,
+                SpawnError::InvalidPriority(_) => 5,
+                SpawnError::InvalidAffinity(_) => 6,
+                SpawnError::InvalidName(_) => 7,
+                SpawnError::UnsupportedFeature(_) => 8,
+                SpawnError::SchedulerRejected => 9,
+            }
+    }
+}
+
+impl KernelError {
+    /// Stable numeric code for logging and FFI.
+    pub fn as_code(&self) -> u16 {
+        0x0C00
+            | match self {
+                KernelError::AlreadyInitialized => 1,
+                KernelError::HeapNotConfigured => 2,
+                KernelError::InterruptControllerMissing => 3,
+                KernelError::TimerInitFailed(_) => 4,
+                KernelError::NotInitialized => 5,
+                KernelError::AlreadyStarted => 6,
+                KernelError::ShuttingDown => 7,
+                KernelError::NoRunnableThread => 8,
+                KernelError::TimerFrequency { .. } => 9,
+                KernelError::FiqUnavailable => 10,
+                KernelError::Timer(TimerError::AlreadyRunning) => 11,
+                KernelError::Timer(TimerError::NotRunning) => 12,
+            }
+    }
+}
+
+impl JoinError {
+    /// Stable numeric code for logging and FFI.
+    pub fn as_code(&self) -> u16 {
+        0x0200
+            | match self {
+                JoinError::AlreadyJoined => 1,
+                JoinError::ThreadPanicked => 2,
+                JoinError::Terminated => 3,
+                JoinError::Timeout => 4,
+                JoinError::StillRunning => 5,
+                JoinError::InvalidHandle => 6,
+                JoinError::JoinSelf => 7,
+            }
+    }
+}
+
+impl ScheduleError {
+    /// Stable numeric code for logging and FFI.
+    pub fn as_code(&self) -> u16 {
+        0x0300
+            | match self {
+                ScheduleError::NoThreadsAvailable => 1,
+                ScheduleError::InvalidState => 2,
+                ScheduleError::InvalidCpu(_) => 3,
+                ScheduleError::PriorityChangeNotAllowed => 4,
+                ScheduleError::QueueFull => 5,
+                ScheduleError::PreemptionDisabled => 6,
+                ScheduleError::GangTooLarge => 7,
+                ScheduleError::NoSuchThread => 8,
+            }
+    }
+}
+
+impl MemoryError {
+    /// Stable numeric code for logging and FFI.
+    pub fn as_code(&self) -> u16 {
+        0x0400
+            | match self {
+                MemoryError::OutOfMemory => 1,
+                MemoryError::StackOverflow => 2,
+                MemoryError::StackUnderflow => 3,
+                MemoryError::InvalidAddress(_) => 4,
+                MemoryError::AlignmentError => 5,
+                MemoryError::PoolExhausted => 6,
+                MemoryError::InvalidLayout => 7,
+            }
+    }
+}
+
+impl ArchError {
+    /// Stable numeric code for logging and FFI.
+    pub fn as_code(&self) -> u16 {
+        0x0500
+            | match self {
+                ArchError::UnsupportedArchitecture => 1,
+                ArchError::ContextSwitchFailed => 2,
+                ArchError::InvalidCpuState => 3,
+                ArchError::InterruptError => 4,
+                ArchError::FpuError => 5,
+                ArchError::InvalidInstruction => 6,
+                ArchError::MisalignedVectorTable => 7,
+                ArchError::WrongExceptionLevel => 8,
+                ArchError::VectorTableNotInstalled => 9,
+            }
+    }
+}
+
+impl DeviceError {
+    /// Stable numeric code for logging and FFI.
+    pub fn as_code(&self) -> u16 {
+        0x0600
+            | match self {
+                DeviceError::Nack => 1,
+                DeviceError::ClockStretchTimeout => 2,
+                DeviceError::Timeout => 3,
+                DeviceError::Io => 4,
+                DeviceError::InvalidArgument => 5,
+                DeviceError::NotInitialized => 6,
+                DeviceError::Busy => 7,
+            }
+    }
+}
+
+impl FsError {
+    /// Stable numeric code for logging and FFI.
+    ///
+    /// Block device failures report the device error's own code.
+    pub fn as_code(&self) -> u16 {
+        match self {
+            FsError::Device(e) => e.as_code(),
+            FsError::InvalidFilesystem => 0x0701,
+            FsError::NotFound => 0x0702,
+            FsError::NotADirectory => 0x0703,
+            FsError::IsADirectory => 0x0704,
+        }
+    }
+}
+
+impl TlsError {
+    /// Stable numeric code for logging and FFI.
+    pub fn as_code(&self) -> u16 {
+        0x0800
+            | match self {
+                TlsError::KeyNotFound => 1,
+                TlsError::StorageExhausted => 2,
+                TlsError::InvalidKey => 3,
+                TlsError::DataCorrupted => 4,
+                TlsError::NotSupported => 5,
+            }
+    }
+}
+
+impl PermissionError {
+    /// Stable numeric code for logging and FFI.
+    pub fn as_code(&self) -> u16 {
+        0x0900
+            | match self {
+                PermissionError::NotPermitted => 1,
+                PermissionError::AccessDenied => 2,
+                PermissionError::InsufficientPrivileges => 3,
+                PermissionError::SecurityViolation => 4,
+                PermissionError::SecurityRisk => 5,
+            }
+    }
+}
+
+impl ResourceError {
+    /// Stable numeric code for logging and FFI.
+    pub fn as_code(&self) -> u16 {
+        0x0A00
+            | match self {
+                ResourceError::MaxThreadsPerProcess => 1,
+                ResourceError::MaxThreadsPerUser => 2,
+                ResourceError::MaxMemoryUsage => 3,
+                ResourceError::MaxCpuTime => 4,
+                ResourceError::MaxFileDescriptors => 5,
+                ResourceError::ResourceUnavailable => 6,
+            }
+    }
+}
+
+impl InvalidOperationError {
+    /// Stable numeric code for logging and FFI.
+    pub fn as_code(&self) -> u16 {
+        0x0B00
+            | match self {
+                InvalidOperationError::WrongThread => 1,
+                InvalidOperationError::WrongState => 2,
+                InvalidOperationError::InvalidParameter(_) => 3,
+                InvalidOperationError::NotSupported => 4,
+                InvalidOperationError::WouldDeadlock => 5,
+                InvalidOperationError::AlreadyInProgress => 6,
+            }
+    }
+}
+
+// `core::error::Error` needs Rust 1.81, newer than the MSRV, so it sits
+// behind the `core-error` feature; host builds get `std::error::Error`
+
+#[cfg(feature = "core-error")]
+use core::error::Error;
+#[cfg(all(feature = "std-shim", not(feature = "core-error")))]
+extern crate std;
+#[cfg(all(feature = "std-shim", not(feature = "core-error")))]
+use std::error::Error;
+
+#[cfg(any(feature = "core-error", feature = "std-shim"))]
+macro_rules! impl_error {
+    ($($ty:ty),*) => {
+        $(impl Error for $ty {})*
+    };
+}
+
+#[cfg(any(feature = "core-error", feature = "std-shim"))]
+impl_error!(
+    SpawnError,
+    NameError,
+    CheckpointError,
+    InjectError,
+    KernelError,
+    TimerError,
+    JoinError,
+    ScheduleError,
+    MemoryError,
+    ArchError,
+    DeviceError,
+    TlsError,
+    PermissionError,
+    ResourceError,
+    InvalidOperationError
+);
+
+#[cfg(any(feature = "core-error", feature = "std-shim"))]
+impl Error for FsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FsError::Device(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(any(feature = "core-error", feature = "std-shim"))]
+impl Error for ThreadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(match self {
+            ThreadError::Spawn(e) => e,
+            ThreadError::Join(e) => e,
+            ThreadError::Schedule(e) => e,
+            ThreadError::Memory(e) => e,
+            ThreadError::Arch(e) => e,
+            ThreadError::Device(e) => e,
+            ThreadError::Fs(e) => e,
+            ThreadError::Tls(e) => e,
+            ThreadError::Permission(e) => e,
+            ThreadError::Resource(e) => e,
+            ThreadError::InvalidOperation(e) => e,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes_are_categorised() {
+        let spawn: ThreadError = SpawnError::InvalidName(NameError::Empty).into();
+        assert_eq!(spawn.as_code(), 0x0107);
+        let fs: ThreadError = FsError::Device(DeviceError::Timeout).into();
+        assert_eq!(fs.as_code(), DeviceError::Timeout.as_code());
+        assert_eq!(ThreadError::resource_exhaustion().as_code() >> 8, 0x0A);
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_error_source_chain() {
+        use alloc::string::ToString;
+
+        let error = ThreadError::Fs(FsError::Device(DeviceError::Nack));
+        let fs = error.source().unwrap();
+        assert_eq!(
+            fs.to_string(),
+            "Block device error: Device did not acknowledge"
+        );
+        assert!(fs.source().is_some());
+    }
+}
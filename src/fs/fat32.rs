@@ -0,0 +1,892 @@
+//! Read-only FAT32.
+//!
+//! A [`Fat32`] volume owns its block device and a one-sector cache behind a
+//! lock, so it can be shared between threads (in a `static` or an
+//! [`ArcLite`](crate::mem::ArcLite)). [`File`] handles borrow the volume and
+//! keep their read position behind a lock of their own: several threads can
+//! read through one handle and each gets the next chunk of the file.
+//!
+//! The volume is either the whole device or the first FAT32 partition of an
+//! MBR partition table. Long file names are supported, and path components
+//! match either the long or the 8.3 name, ignoring ASCII case.
+//!
+//! Locks block the calling thread, so none of this may be used from
+//! interrupt context.
+
+use crate::drivers::block::{BlockDevice, BLOCK_SIZE};
+use crate::drivers::DeviceLock;
+use crate::errors::FsError;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+
+/// MBR partition types for FAT32 (CHS and LBA addressed).
+const PART_FAT32_CHS: u8 = 0x0B;
+const PART_FAT32_LBA: u8 = 0x0C;
+const MBR_PARTITION_TABLE: usize = 446;
+
+const DIR_ENTRY_SIZE: usize = 32;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+/// Stored in place of a leading 0xE5 byte of a short name.
+const ENTRY_KANJI_E5: u8 = 0x05;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const ATTR_LONG_NAME_MASK: u8 = 0x3F;
+
+/// Short name case flags (Windows NT extension).
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+const LFN_LAST_ENTRY: u8 = 0x40;
+const LFN_SEQ_MASK: u8 = 0x1F;
+/// Offsets of the 13 UTF-16 name units within a long name entry.
+const LFN_UNIT_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// A 255-character name takes 20 entries.
+const LFN_MAX_UNITS: usize = 20 * 13;
+
+const FAT_ENTRY_MASK: u32 = 0x0FFF_FFFF;
+const FAT_END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+/// Highest cluster count whose numbers don't collide with reserved values.
+const MAX_CLUSTERS: u64 = 0x0FFF_FFF5;
+const FIRST_CLUSTER: u32 = 2;
+
+fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+/// Volume layout from the boot sector.
+struct Geometry {
+    /// First sector of the first FAT
+    fat_start: u64,
+    /// Sector holding cluster 2
+    data_start: u64,
+    sectors_per_cluster: u32,
+    root_cluster: u32,
+    /// Number of data clusters
+    cluster_count: u32,
+}
+
+impl Geometry {
+    /// Parse a FAT32 boot sector read from sector `start`.
+    fn parse(sector: &[u8], start: u64, block_count: u64) -> Option<Self> {
+        if sector[510..512] != [0x55, 0xAA] {
+            return None;
+        }
+
+        let bytes_per_sector = le16(sector, 11) as usize;
+        let sectors_per_cluster = sector[13] as u32;
+        let reserved_sectors = le16(sector, 14) as u64;
+        let num_fats = sector[16] as u64;
+        let root_entries = le16(sector, 17);
+        let fat_size16 = le16(sector, 22);
+        let fat_size = le32(sector, 36) as u64;
+        let total_sectors = match le16(sector, 19) {
+            0 => le32(sector, 32) as u64,
+            n => n as u64,
+        };
+
+        // FAT12/16 have a fixed root directory and a 16-bit FAT size
+        if bytes_per_sector != BLOCK_SIZE
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || num_fats == 0
+            || root_entries != 0
+            || fat_size16 != 0
+            || fat_size == 0
+            || start + total_sectors > block_count
+        {
+            return None;
+        }
+
+        let fat_start = start + reserved_sectors;
+        let data_start = fat_start + num_fats * fat_size;
+        let data_sectors = (start + total_sectors).checked_sub(data_start)?;
+
+        // Clusters beyond what the FAT can describe are unusable
+        let fat_entries = fat_size * (BLOCK_SIZE / 4) as u64 - FIRST_CLUSTER as u64;
+        let cluster_count = (data_sectors / sectors_per_cluster as u64)
+            .min(fat_entries)
+            .min(MAX_CLUSTERS) as u32;
+
+        let geometry = Self {
+            fat_start,
+            data_start,
+            sectors_per_cluster,
+            root_cluster: le32(sector, 44),
+            cluster_count,
+        };
+        if geometry.is_valid_cluster(geometry.root_cluster) {
+            Some(geometry)
+        } else {
+            None
+        }
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= FIRST_CLUSTER && cluster - FIRST_CLUSTER < self.cluster_count
+    }
+
+    fn cluster_bytes(&self) -> u32 {
+        self.sectors_per_cluster * BLOCK_SIZE as u32
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - FIRST_CLUSTER) as u64 * self.sectors_per_cluster as u64
+    }
+}
+
+/// Start sector of the first FAT32 partition in an MBR.
+fn find_partition(mbr: &[u8]) -> Option<u64> {
+    if mbr[510..512] != [0x55, 0xAA] {
+        return None;
+    }
+    mbr[MBR_PARTITION_TABLE..MBR_PARTITION_TABLE + 64]
+        .chunks_exact(16)
+        .find(|entry| matches!(entry[4], PART_FAT32_CHS | PART_FAT32_LBA))
+        .map(|entry| le32(entry, 8) as u64)
+}
+
+struct SectorCache {
+    lba: Option<u64>,
+    data: [u8; BLOCK_SIZE],
+}
+
+/// A mounted FAT32 volume.
+pub struct Fat32<D: BlockDevice> {
+    dev: D,
+    geometry: Geometry,
+    io: DeviceLock,
+    /// Most recently read metadata sector; only touched with `io` held
+    cache: UnsafeCell<SectorCache>,
+}
+
+// The cache is only accessed with `io` held.
+unsafe impl<D: BlockDevice> Sync for Fat32<D> {}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Mount the FAT32 volume on `dev`.
+    ///
+    /// Accepts a device formatted without a partition table as well as one
+    /// whose MBR lists a FAT32 partition. Fails with
+    /// [`FsError::InvalidFilesystem`] if neither is found.
+    pub fn mount(dev: D) -> Result<Self, FsError> {
+        let block_count = dev.block_count();
+        let mut sector = [0u8; BLOCK_SIZE];
+        dev.read_blocks(0, &mut sector)?;
+
+        let geometry = match Geometry::parse(&sector, 0, block_count) {
+            Some(geometry) => geometry,
+            None => {
+                let start = find_partition(&sector).ok_or(FsError::InvalidFilesystem)?;
+                if start >= block_count {
+                    return Err(FsError::InvalidFilesystem);
+                }
+                dev.read_blocks(start, &mut sector)?;
+                Geometry::parse(&sector, start, block_count).ok_or(FsError::InvalidFilesystem)?
+            }
+        };
+
+        Ok(Self {
+            dev,
+            geometry,
+            io: DeviceLock::new(),
+            cache: UnsafeCell::new(SectorCache {
+                lba: None,
+                data: [0; BLOCK_SIZE],
+            }),
+        })
+    }
+
+    /// Open the file at `path` for reading.
+    ///
+    /// Paths are `/`-separated and relative to the volume root; a leading
+    /// `/` is optional.
+    pub fn open(&self, path: &str) -> Result<File<'_, D>, FsError> {
+        let entry = self.resolve(path)?;
+        if entry.is_dir {
+            return Err(FsError::IsADirectory);
+        }
+        if entry.size > 0 && !self.geometry.is_valid_cluster(entry.cluster) {
+            return Err(FsError::InvalidFilesystem);
+        }
+
+        Ok(File {
+            fs: self,
+            first_cluster: entry.cluster,
+            size: entry.size,
+            lock: DeviceLock::new(),
+            cursor: UnsafeCell::new(Cursor {
+                pos: 0,
+                cluster: entry.cluster,
+                index: 0,
+            }),
+        })
+    }
+
+    /// List the directory at `path`, without its `.` and `..` entries.
+    pub fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let dir = self.resolve(path)?;
+        if !dir.is_dir {
+            return Err(FsError::NotADirectory);
+        }
+
+        let mut entries = Vec::new();
+        self.walk_dir(dir.cluster, |entry| {
+            if entry.short_name != "." && entry.short_name != ".." {
+                entries.push(entry);
+            }
+            false
+        })?;
+        Ok(entries)
+    }
+
+    /// Look up the entry for `path`.
+    pub fn metadata(&self, path: &str) -> Result<DirEntry, FsError> {
+        self.resolve(path)
+    }
+
+    fn resolve(&self, path: &str) -> Result<DirEntry, FsError> {
+        let mut current = DirEntry::root(self.geometry.root_cluster);
+
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            if !current.is_dir {
+                return Err(FsError::NotADirectory);
+            }
+
+            let mut found = None;
+            self.walk_dir(current.cluster, |entry| {
+                if entry.matches(component) {
+                    found = Some(entry);
+                    true
+                } else {
+                    false
+                }
+            })?;
+            current = found.ok_or(FsError::NotFound)?;
+
+            // ".." entries of first-level directories point at cluster 0
+            if current.is_dir && current.cluster == 0 {
+                current.cluster = self.geometry.root_cluster;
+            }
+            if current.is_dir && !self.geometry.is_valid_cluster(current.cluster) {
+                return Err(FsError::InvalidFilesystem);
+            }
+        }
+        Ok(current)
+    }
+
+    /// Call `visit` on each entry of the directory starting at `cluster`
+    /// until it returns `true`.
+    ///
+    /// `visit` runs with the I/O lock held and must not touch the volume.
+    fn walk_dir(
+        &self,
+        cluster: u32,
+        mut visit: impl FnMut(DirEntry) -> bool,
+    ) -> Result<(), FsError> {
+        let mut long_name = LongName::new();
+        let mut next = Some(cluster);
+        // A corrupt FAT could link the chain into a loop
+        let mut budget = self.geometry.cluster_count;
+
+        while let Some(cluster) = next {
+            if budget == 0 {
+                return Err(FsError::InvalidFilesystem);
+            }
+            budget -= 1;
+            crate::preemption_point!();
+
+            let lba = self.geometry.cluster_lba(cluster);
+            for sector in 0..self.geometry.sectors_per_cluster as u64 {
+                let done = self.with_sector(lba + sector, |data| {
+                    for raw in data.chunks_exact(DIR_ENTRY_SIZE) {
+                        match raw[0] {
+                            ENTRY_END => return true,
+                            ENTRY_DELETED => {
+                                long_name.reset();
+                                continue;
+                            }
+                            _ => {}
+                        }
+
+                        let attr = raw[11];
+                        if attr & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME {
+                            long_name.push(raw);
+                        } else if attr & ATTR_VOLUME_ID != 0 {
+                            long_name.reset();
+                        } else if visit(DirEntry::parse(raw, &mut long_name)) {
+                            return true;
+                        }
+                    }
+                    false
+                })?;
+                if done {
+                    return Ok(());
+                }
+            }
+            next = self.next_cluster(cluster)?;
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, or `None` at the end.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        let offset = cluster as u64 * 4;
+        let lba = self.geometry.fat_start + offset / BLOCK_SIZE as u64;
+        let index = (offset % BLOCK_SIZE as u64) as usize;
+        let entry = self.with_sector(lba, |data| le32(data, index))? & FAT_ENTRY_MASK;
+
+        if entry >= FAT_END_OF_CHAIN {
+            Ok(None)
+        } else if self.geometry.is_valid_cluster(entry) {
+            Ok(Some(entry))
+        } else {
+            // Free or bad cluster in the middle of a chain
+            Err(FsError::InvalidFilesystem)
+        }
+    }
+
+    /// Run `f` on sector `lba`, reading it through the cache.
+    fn with_sector<R>(
+        &self,
+        lba: u64,
+        f: impl FnOnce(&[u8; BLOCK_SIZE]) -> R,
+    ) -> Result<R, FsError> {
+        let _io = self.io.lock();
+        let cache = unsafe { &mut *self.cache.get() };
+        if cache.lba != Some(lba) {
+            cache.lba = None;
+            self.dev.read_blocks(lba, &mut cache.data)?;
+            cache.lba = Some(lba);
+        }
+        Ok(f(&cache.data))
+    }
+
+    /// Read whole sectors straight into `buf`, bypassing the cache.
+    fn read_direct(&self, lba: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let _io = self.io.lock();
+        self.dev.read_blocks(lba, buf)?;
+        Ok(())
+    }
+}
+
+/// Long name fragments collected ahead of a short entry.
+struct LongName {
+    units: [u16; LFN_MAX_UNITS],
+    len: usize,
+    checksum: u8,
+    /// Sequence number of the next fragment expected; 0 once complete
+    expected: u8,
+}
+
+impl LongName {
+    fn new() -> Self {
+        Self {
+            units: [0; LFN_MAX_UNITS],
+            len: 0,
+            checksum: 0,
+            expected: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.len = 0;
+        self.expected = 0;
+    }
+
+    /// Add a long name entry. Fragments are stored last-first on disk.
+    fn push(&mut self, raw: &[u8]) {
+        let seq = raw[0] & LFN_SEQ_MASK;
+        if raw[0] & LFN_LAST_ENTRY != 0 {
+            self.len = seq as usize * LFN_UNIT_OFFSETS.len();
+            self.checksum = raw[13];
+            self.expected = seq;
+        }
+
+        if seq == 0 || seq != self.expected || raw[13] != self.checksum || self.len > LFN_MAX_UNITS
+        {
+            self.reset();
+            return;
+        }
+
+        let base = (seq as usize - 1) * LFN_UNIT_OFFSETS.len();
+        for (i, &offset) in LFN_UNIT_OFFSETS.iter().enumerate() {
+            self.units[base + i] = le16(raw, offset);
+        }
+        self.expected = seq - 1;
+    }
+
+    /// The collected name, if it is complete and belongs to `short_name`.
+    fn take(&mut self, short_name: &[u8]) -> Option<String> {
+        let complete =
+            self.len > 0 && self.expected == 0 && self.checksum == short_name_checksum(short_name);
+        let len = self.len;
+        self.reset();
+        if !complete {
+            return None;
+        }
+
+        let units = &self.units[..len];
+        let end = units
+            .iter()
+            .position(|&unit| unit == 0x0000 || unit == 0xFFFF)
+            .unwrap_or(len);
+        Some(
+            char::decode_utf16(units[..end].iter().copied())
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+        )
+    }
+}
+
+/// Checksum of an 11-byte short name, stored in its long name entries.
+fn short_name_checksum(name: &[u8]) -> u8 {
+    name[..11]
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// Format the 8.3 name of a directory entry as `NAME.EXT`.
+fn format_short_name(raw: &[u8]) -> String {
+    let lower_base = raw[12] & CASE_LOWER_BASE != 0;
+    let lower_ext = raw[12] & CASE_LOWER_EXT != 0;
+    let trim = |field: &[u8]| field.len() - field.iter().rev().take_while(|&&b| b == b' ').count();
+
+    let mut name = String::new();
+    for (i, &byte) in raw[..trim(&raw[..8])].iter().enumerate() {
+        let byte = if i == 0 && byte == ENTRY_KANJI_E5 {
+            ENTRY_DELETED
+        } else {
+            byte
+        };
+        name.push(char::from(if lower_base {
+            byte.to_ascii_lowercase()
+        } else {
+            byte
+        }));
+    }
+
+    let ext = &raw[8..8 + trim(&raw[8..11])];
+    if !ext.is_empty() {
+        name.push('.');
+        for &byte in ext {
+            name.push(char::from(if lower_ext {
+                byte.to_ascii_lowercase()
+            } else {
+                byte
+            }));
+        }
+    }
+    name
+}
+
+/// A file or directory in a directory listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    name: String,
+    short_name: String,
+    is_dir: bool,
+    size: u32,
+    cluster: u32,
+}
+
+impl DirEntry {
+    fn root(cluster: u32) -> Self {
+        Self {
+            name: String::from("/"),
+            short_name: String::from("/"),
+            is_dir: true,
+            size: 0,
+            cluster,
+        }
+    }
+
+    fn parse(raw: &[u8], long_name: &mut LongName) -> Self {
+        let short_name = format_short_name(raw);
+        Self {
+            name: long_name
+                .take(&raw[..11])
+                .unwrap_or_else(|| short_name.clone()),
+            short_name,
+            is_dir: raw[11] & ATTR_DIRECTORY != 0,
+            size: le32(raw, 28),
+            cluster: (le16(raw, 20) as u32) << 16 | le16(raw, 26) as u32,
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.short_name.eq_ignore_ascii_case(name)
+    }
+
+    /// The long name, or the 8.3 name if the entry has none.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The 8.3 name.
+    pub fn short_name(&self) -> &str {
+        &self.short_name
+    }
+
+    /// Whether this is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    /// File size in bytes (0 for directories).
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Cursor {
+    pos: u32,
+    /// Cluster holding `pos`, once `index` has caught up with it
+    cluster: u32,
+    /// Position of `cluster` in the file's chain
+    index: u32,
+}
+
+/// An open file.
+///
+/// Reads through one handle are serialized; concurrent callers each get the
+/// next unread chunk. Use [`File::read_at`] to read without moving the
+/// shared position.
+pub struct File<'a, D: BlockDevice> {
+    fs: &'a Fat32<D>,
+    first_cluster: u32,
+    size: u32,
+    lock: DeviceLock,
+    /// Only touched with `lock` held
+    cursor: UnsafeCell<Cursor>,
+}
+
+// The cursor is only accessed with `lock` held.
+unsafe impl<D: BlockDevice> Sync for File<'_, D> {}
+
+impl<D: BlockDevice> File<'_, D> {
+    /// File size in bytes.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Current read position.
+    pub fn position(&self) -> u32 {
+        let _cursor = self.lock.lock();
+        unsafe { (*self.cursor.get()).pos }
+    }
+
+    /// Move the read position, clamped to the end of the file.
+    pub fn seek(&self, pos: u32) {
+        let _cursor = self.lock.lock();
+        unsafe { (*self.cursor.get()).pos = pos.min(self.size) };
+    }
+
+    /// Read from the current position, advancing it.
+    ///
+    /// Returns the number of bytes read, which is 0 at end of file.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let _cursor = self.lock.lock();
+        let cursor = unsafe { &mut *self.cursor.get() };
+        self.read_with(cursor, buf)
+    }
+
+    /// Read from `offset` without using or moving the current position.
+    pub fn read_at(&self, offset: u32, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut cursor = Cursor {
+            pos: offset.min(self.size),
+            cluster: self.first_cluster,
+            index: 0,
+        };
+        self.read_with(&mut cursor, buf)
+    }
+
+    /// Read everything from the current position to the end, appending it
+    /// to `buf`.
+    pub fn read_to_end(&self, buf: &mut Vec<u8>) -> Result<usize, FsError> {
+        let _cursor = self.lock.lock();
+        let cursor = unsafe { &mut *self.cursor.get() };
+        let start = buf.len();
+        buf.resize(start + (self.size - cursor.pos) as usize, 0);
+        let n = self.read_with(cursor, &mut buf[start..])?;
+        buf.truncate(start + n);
+        Ok(n)
+    }
+
+    fn read_with(&self, cursor: &mut Cursor, buf: &mut [u8]) -> Result<usize, FsError> {
+        let geometry = &self.fs.geometry;
+        let cluster_bytes = geometry.cluster_bytes();
+        let end = (cursor.pos as u64 + buf.len() as u64).min(self.size as u64) as u32;
+        let mut done = 0;
+
+        while cursor.pos < end {
+            self.seek_cluster(cursor, cursor.pos / cluster_bytes)?;
+            let in_cluster = cursor.pos % cluster_bytes;
+            let lba =
+                geometry.cluster_lba(cursor.cluster) + (in_cluster / BLOCK_SIZE as u32) as u64;
+            let in_sector = in_cluster as usize % BLOCK_SIZE;
+            let remaining = (end - cursor.pos) as usize;
+
+            let n = if in_sector == 0 && remaining >= BLOCK_SIZE {
+                // Whole sectors go straight into the caller's buffer
+                let n =
+                    remaining.min((cluster_bytes - in_cluster) as usize) / BLOCK_SIZE * BLOCK_SIZE;
+                self.fs.read_direct(lba, &mut buf[done..done + n])?;
+                n
+            } else {
+                let n = remaining.min(BLOCK_SIZE - in_sector);
+                self.fs.with_sector(lba, |data| {
+                    buf[done..done + n].copy_from_slice(&data[in_sector..in_sector + n]);
+                })?;
+                n
+            };
+
+            done += n;
+            cursor.pos += n as u32;
+            crate::preemption_point!();
+        }
+        Ok(done)
+    }
+
+    /// Point `cursor` at the `index`th cluster of the file.
+    fn seek_cluster(&self, cursor: &mut Cursor, index: u32) -> Result<(), FsError> {
+        if index < cursor.index {
+            cursor.cluster = self.first_cluster;
+            cursor.index = 0;
+        }
+        while cursor.index < index {
+            cursor.cluster = self
+                .fs
+                .next_cluster(cursor.cluster)?
+                .ok_or(FsError::InvalidFilesystem)?;
+            cursor.index += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::DeviceError;
+    use alloc::vec;
+
+    struct RamDisk(spin::Mutex<Vec<u8>>);
+
+    impl BlockDevice for RamDisk {
+        fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+            let start = lba as usize * BLOCK_SIZE;
+            let data = self.0.lock();
+            let src = data
+                .get(start..start + buf.len())
+                .ok_or(DeviceError::InvalidArgument)?;
+            buf.copy_from_slice(src);
+            Ok(())
+        }
+
+        fn write_blocks(&self, _lba: u64, _buf: &[u8]) -> Result<(), DeviceError> {
+            Err(DeviceError::InvalidArgument)
+        }
+
+        fn block_count(&self) -> u64 {
+            (self.0.lock().len() / BLOCK_SIZE) as u64
+        }
+    }
+
+    const SECTORS: usize = 64;
+    const EOC: u32 = 0x0FFF_FFFF;
+
+    fn short_entry(name: &[u8; 11], attr: u8, case: u8, cluster: u32, size: u32) -> [u8; 32] {
+        let mut raw = [0u8; 32];
+        raw[..11].copy_from_slice(name);
+        raw[11] = attr;
+        raw[12] = case;
+        raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        raw[28..32].copy_from_slice(&size.to_le_bytes());
+        raw
+    }
+
+    /// Long name entries for `name`, in on-disk order.
+    fn long_entries(name: &str, short: &[u8; 11]) -> Vec<[u8; 32]> {
+        let mut units: Vec<u16> = name.encode_utf16().collect();
+        units.push(0);
+        while units.len() % 13 != 0 {
+            units.push(0xFFFF);
+        }
+
+        let count = units.len() / 13;
+        (1..=count)
+            .rev()
+            .map(|seq| {
+                let mut raw = [0u8; 32];
+                raw[0] = seq as u8 | if seq == count { LFN_LAST_ENTRY } else { 0 };
+                raw[11] = ATTR_LONG_NAME;
+                raw[13] = short_name_checksum(short);
+                for (i, &offset) in LFN_UNIT_OFFSETS.iter().enumerate() {
+                    raw[offset..offset + 2]
+                        .copy_from_slice(&units[(seq - 1) * 13 + i].to_le_bytes());
+                }
+                raw
+            })
+            .collect()
+    }
+
+    /// A volume of one-sector clusters starting at sector `start`:
+    /// `HELLO.TXT` (600 bytes, clusters 3 -> 6) and `config/Boot Settings.cfg`.
+    fn image(start: usize) -> Vec<u8> {
+        let mut disk = vec![0u8; (start + SECTORS) * BLOCK_SIZE];
+        let sector = |n: usize| (start + n) * BLOCK_SIZE;
+
+        if start > 0 {
+            let entry = MBR_PARTITION_TABLE + 16;
+            disk[entry + 4] = PART_FAT32_LBA;
+            disk[entry + 8..entry + 12].copy_from_slice(&(start as u32).to_le_bytes());
+            disk[510..512].copy_from_slice(&[0x55, 0xAA]);
+        }
+
+        // Boot sector: 2 reserved sectors, 2 FATs of 1 sector, root at cluster 2
+        let boot = sector(0);
+        disk[boot + 11..boot + 13].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+        disk[boot + 13] = 1;
+        disk[boot + 14] = 2;
+        disk[boot + 16] = 2;
+        disk[boot + 32..boot + 36].copy_from_slice(&(SECTORS as u32).to_le_bytes());
+        disk[boot + 36] = 1;
+        disk[boot + 44] = 2;
+        disk[boot + 510..boot + 512].copy_from_slice(&[0x55, 0xAA]);
+
+        let fat = [0x0FFF_FFF8, EOC, EOC, 6, EOC, EOC, EOC];
+        for (i, entry) in fat.iter().enumerate() {
+            disk[sector(2) + i * 4..][..4].copy_from_slice(&entry.to_le_bytes());
+        }
+
+        // Clusters 2.. start at sector 4
+        let cluster = |c: usize| sector(4 + c - 2);
+        let mut root = vec![short_entry(b"PIBOOT     ", ATTR_VOLUME_ID, 0, 0, 0)];
+        let mut deleted = short_entry(b"OLD     TXT", 0, 0, 5, 10);
+        deleted[0] = ENTRY_DELETED;
+        root.push(deleted);
+        root.push(short_entry(b"HELLO   TXT", 0, 0, 3, 600));
+        root.push(short_entry(
+            b"CONFIG     ",
+            ATTR_DIRECTORY,
+            CASE_LOWER_BASE,
+            4,
+            0,
+        ));
+
+        let short = *b"BOOTSE~1CFG";
+        let mut config = vec![
+            short_entry(b".          ", ATTR_DIRECTORY, 0, 4, 0),
+            short_entry(b"..         ", ATTR_DIRECTORY, 0, 0, 0),
+        ];
+        config.extend(long_entries("Boot Settings.cfg", &short));
+        config.push(short_entry(&short, 0, 0, 5, 10));
+
+        for (dir, entries) in [(2, root), (4, config)] {
+            for (i, raw) in entries.iter().enumerate() {
+                disk[cluster(dir) + i * 32..][..32].copy_from_slice(raw);
+            }
+        }
+
+        for i in 0..600 {
+            let c = if i < BLOCK_SIZE { 3 } else { 6 };
+            disk[cluster(c) + i % BLOCK_SIZE] = (i % 251) as u8;
+        }
+        disk[cluster(5)..cluster(5) + 10].copy_from_slice(b"mode=fast\n");
+        disk
+    }
+
+    #[test]
+    fn test_read_files_and_directories() {
+        let fs = Fat32::mount(RamDisk(spin::Mutex::new(image(0)))).unwrap();
+
+        let root = fs.read_dir("/").unwrap();
+        let names: Vec<&str> = root.iter().map(|e| e.name()).collect();
+        assert_eq!(names, ["HELLO.TXT", "config"]);
+        assert!(root[1].is_dir());
+
+        let config = fs.read_dir("config").unwrap();
+        assert_eq!(config.len(), 1);
+        assert_eq!(config[0].name(), "Boot Settings.cfg");
+        assert_eq!(config[0].short_name(), "BOOTSE~1.CFG");
+
+        let mut text = Vec::new();
+        let cfg = fs.open("/CONFIG/boot settings.cfg").unwrap();
+        assert_eq!(cfg.read_to_end(&mut text), Ok(10));
+        assert_eq!(text, b"mode=fast\n");
+        assert_eq!(fs.open("config/../config/bootse~1.cfg").unwrap().size(), 10);
+
+        // Sequential reads cross from cluster 3 to cluster 6
+        let hello = fs.open("hello.txt").unwrap();
+        let mut buf = [0u8; 256];
+        let mut contents = Vec::new();
+        loop {
+            let n = hello.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            contents.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(contents.len(), 600);
+        assert!(contents
+            .iter()
+            .enumerate()
+            .all(|(i, &b)| b == (i % 251) as u8));
+
+        assert_eq!(hello.read_at(510, &mut buf[..4]), Ok(4));
+        assert!(buf[..4]
+            .iter()
+            .zip(510..)
+            .all(|(&b, i)| b == (i % 251) as u8));
+        assert_eq!(hello.position(), 600);
+
+        // Two threads sharing a handle each get distinct chunks
+        hello.seek(0);
+        let total: usize = std::thread::scope(|s| {
+            let readers: Vec<_> = (0..2)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut buf = [0u8; 100];
+                        let mut total = 0;
+                        while let Ok(n @ 1..) = hello.read(&mut buf) {
+                            total += n;
+                        }
+                        total
+                    })
+                })
+                .collect();
+            readers.into_iter().map(|r| r.join().unwrap()).sum()
+        });
+        assert_eq!(total, 600);
+    }
+
+    #[test]
+    fn test_partitioned_volume_and_errors() {
+        let fs = Fat32::mount(RamDisk(spin::Mutex::new(image(8)))).unwrap();
+        assert_eq!(fs.metadata("/hello.txt").unwrap().size(), 600);
+        assert_eq!(fs.open("missing.txt").err(), Some(FsError::NotFound));
+        assert_eq!(fs.open("config").err(), Some(FsError::IsADirectory));
+        assert_eq!(
+            fs.read_dir("hello.txt/x").err(),
+            Some(FsError::NotADirectory)
+        );
+
+        let blank = RamDisk(spin::Mutex::new(vec![0u8; SECTORS * BLOCK_SIZE]));
+        assert_eq!(Fat32::mount(blank).err(), Some(FsError::InvalidFilesystem));
+    }
+}
@@ -0,0 +1,22 @@
+//! Filesystems on top of [`BlockDevice`](crate::drivers::block::BlockDevice).
+//!
+//! Currently a read-only FAT32 implementation, enough to load configuration
+//! and assets from the boot SD card.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::drivers::sdhost::{self, Mode};
+//! use preemptive_threads::fs::Fat32;
+//!
+//! sdhost::sdhost().init(Mode::Interrupt)?;
+//! let volume = Fat32::mount(sdhost::sdhost())?;
+//!
+//! let config = volume.open("/config/boot.cfg")?;
+//! let mut buf = [0u8; 256];
+//! let n = config.read(&mut buf)?;
+//! ```
+
+pub mod fat32;
+
+pub use fat32::{DirEntry, Fat32, File};
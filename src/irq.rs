@@ -0,0 +1,968 @@
+//! Interrupt handler registration and dispatch.
+//!
+//! The low-level IRQ vector acknowledges interrupts at the GIC and hands every
+//! line other than the scheduler tick to [`dispatch`], which calls the handler
+//! a driver registered for it.
+//!
+//! While an interrupt is being handled [`in_irq`] returns `true`. Wrapping
+//! the global allocator in [`IrqCheckedAlloc`] turns that into a debug check
+//! that catches heap allocation from interrupt context:
+//!
+//! ```ignore
+//! use preemptive_threads::irq::IrqCheckedAlloc;
+//!
+//! #[global_allocator]
+//! static ALLOCATOR: IrqCheckedAlloc<BumpAllocator> = IrqCheckedAlloc::logging(BumpAllocator);
+//! ```
+//!
+//! # Interrupt storms
+//!
+//! [`dispatch`] counts how often each line fires. A line that raises more
+//! than the [`StormPolicy`] threshold within one window, typically a level
+//! interrupt its device never deasserts, is masked so it cannot starve
+//! every thread. The kernel logs a warning, publishes
+//! [`KernelEvent::IrqStorm`] and unmasks the line again after a backoff
+//! from a [`hrtimer`]; each consecutive storm doubles the backoff.
+//! [`unthrottle`] unmasks a line early, e.g. once its driver has reset the
+//! device.
+//!
+//! # Interrupt classes
+//!
+//! Drivers that care which interrupts may interrupt theirs register with
+//! [`register_handler_with_class`], naming an [`IrqClass`]. The class
+//! becomes the line's GIC priority, real-time lines above the scheduler
+//! tick and normal and background lines below it; lines without a class
+//! keep the lowest priority.
+//!
+//! A thread can then hold off only the less important interrupts instead
+//! of all of them. [`mask_below`] raises the CPU's priority mask until the
+//! returned guard is dropped:
+//!
+//! ```ignore
+//! use preemptive_threads::irq::{self, IrqClass};
+//!
+//! // Real-time interrupts still arrive; the tick and everything below don't
+//! let _mask = irq::mask_below(IrqClass::RealTime);
+//! update_control_output();
+//! ```
+//!
+//! Masking below [`IrqClass::RealTime`] also holds off the tick, so the
+//! section is not preempted; masking below [`IrqClass::Normal`] or
+//! [`IrqClass::Background`] is not. [`IrqClass::for_thread_priority`] picks
+//! the class matching a thread's priority.
+//!
+//! # Unregistering
+//!
+//! [`unregister_handler`] returns only once no CPU is still running the
+//! handler it removed, so a driver may free whatever the handler touches
+//! as soon as it returns. [`dispatch`] counts the invocations in flight
+//! on each line, and [`synchronize_irq`] waits for that count to drain:
+//! the grace period after which the old handler can't be running, as with
+//! [RCU](crate::sync::rcu) readers and a context switch.
+//!
+//! # Waking threads
+//!
+//! A handler that wakes a thread, by signalling a semaphore or sending on
+//! a channel, doesn't switch to it; the thread it interrupted may be
+//! holding anything. If the woken thread outranks that one, the IRQ
+//! vector switches to it on the way out of the interrupt instead
+//! ([`Kernel::handle_irq_handoff`](crate::Kernel::handle_irq_handoff)),
+//! so an I/O thread runs within microseconds of its interrupt rather than
+//! after a second exception or the next tick. Platforms with their own
+//! vectors should call it after dispatch too.
+//!
+//! # Handler budgets
+//!
+//! [`set_handler_budget`] gives a line's handler a time budget. [`dispatch`]
+//! then times the handler and counts every run over budget in the line's
+//! [`BudgetStats`], logging a warning whenever a run is the slowest over
+//! budget so far, so a handler that creeps up shows in the log without one
+//! line per interrupt.
+
+use crate::errors::ArchError;
+use crate::kernel::events::{self, KernelEvent};
+use crate::kernel::log::Level;
+use crate::sync::ordering::{self, Edge};
+use crate::thread::ThreadId;
+use crate::time::hrtimer::{self, HrTimerId};
+use crate::time::{Duration, Instant};
+use core::alloc::{GlobalAlloc, Layout};
+use portable_atomic::{
+    AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering,
+};
+
+/// Interrupt handler function, called with the interrupt number.
+///
+/// Handlers run in IRQ context: they must not block or allocate and should
+/// defer real work to a thread (e.g. via a [`WaitQueue`](crate::sync::WaitQueue)).
+pub type IrqHandler = fn(u32);
+
+/// Number of interrupt lines that can have handlers registered.
+pub const MAX_IRQS: usize = 256;
+
+// Only used to initialise HANDLERS
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLER: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+static HANDLERS: [AtomicPtr<()>; MAX_IRQS] = [NO_HANDLER; MAX_IRQS];
+
+/// Rate limit applied to every interrupt line, see the
+/// [module docs](self#interrupt-storms).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StormPolicy {
+    /// Interrupts a line may raise per window; zero disables detection.
+    pub threshold: u32,
+    /// Length of the counting window.
+    pub window: Duration,
+    /// How long a storming line stays masked, doubled for each consecutive
+    /// storm up to 64 times.
+    pub backoff: Duration,
+}
+
+impl Default for StormPolicy {
+    /// 500,000 interrupts per second, well above any BCM2837 peripheral
+    /// that is working, masked for 100 ms.
+    fn default() -> Self {
+        Self {
+            threshold: 5_000,
+            window: Duration::from_millis(10),
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Storm history of one interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StormStats {
+    /// Times the line was masked for storming.
+    pub storms: u32,
+    /// Whether it is masked right now.
+    pub throttled: bool,
+}
+
+/// Handler time against budget for one interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BudgetStats {
+    /// The budget, `None` if the handler isn't timed.
+    pub budget: Option<Duration>,
+    /// Runs that took longer than the budget.
+    pub overruns: u32,
+    /// Longest run seen while timed.
+    pub worst: Duration,
+}
+
+struct Line {
+    window_start: AtomicU64,
+    count: AtomicU32,
+    storms: AtomicU32,
+    /// Storms since the last quiet window
+    strikes: AtomicU32,
+    throttled: AtomicBool,
+    /// Handler budget in nanoseconds, zero if untimed
+    budget_ns: AtomicU64,
+    overruns: AtomicU32,
+    worst_ns: AtomicU64,
+    /// Handler invocations in flight, on any CPU
+    running: AtomicU32,
+}
+
+impl Line {
+    const fn new() -> Self {
+        Self {
+            window_start: AtomicU64::new(0),
+            count: AtomicU32::new(0),
+            storms: AtomicU32::new(0),
+            strikes: AtomicU32::new(0),
+            throttled: AtomicBool::new(false),
+            budget_ns: AtomicU64::new(0),
+            overruns: AtomicU32::new(0),
+            worst_ns: AtomicU64::new(0),
+            running: AtomicU32::new(0),
+        }
+    }
+}
+
+// Only used to initialise LINES
+#[allow(clippy::declare_interior_mutable_const)]
+const QUIET_LINE: Line = Line::new();
+static LINES: [Line; MAX_IRQS] = [QUIET_LINE; MAX_IRQS];
+static REENABLE: spin::Mutex<[Option<HrTimerId>; MAX_IRQS]> = spin::Mutex::new([None; MAX_IRQS]);
+
+static STORM_THRESHOLD: AtomicU32 = AtomicU32::new(5_000);
+static STORM_WINDOW_NS: AtomicU64 = AtomicU64::new(10_000_000);
+static STORM_BACKOFF_NS: AtomicU64 = AtomicU64::new(100_000_000);
+
+/// Most doublings of the storm backoff
+const MAX_BACKOFF_SHIFT: u32 = 6;
+
+/// Register the handler for an interrupt line.
+///
+/// Replaces any previously registered handler. The line itself is not
+/// unmasked; call [`enable`] once the device is ready to raise it.
+pub fn register_handler(irq: u32, handler: IrqHandler) -> Result<(), ArchError> {
+    let slot = HANDLERS
+        .get(irq as usize)
+        .ok_or(ArchError::InterruptError)?;
+    slot.store(
+        handler as *mut (),
+        ordering::release(Edge::IrqHandler, Ordering::Release),
+    );
+    Ok(())
+}
+
+/// Remove the handler for an interrupt line.
+///
+/// From a thread, waits with [`synchronize_irq`] until the handler has
+/// finished on every CPU. From interrupt context, where waiting could be
+/// waiting on itself, it only removes the handler.
+pub fn unregister_handler(irq: u32) -> Result<(), ArchError> {
+    let slot = HANDLERS
+        .get(irq as usize)
+        .ok_or(ArchError::InterruptError)?;
+    slot.store(
+        core::ptr::null_mut(),
+        ordering::release(Edge::IrqHandler, Ordering::Release),
+    );
+    if !in_irq() {
+        synchronize_irq(irq)?;
+    }
+    Ok(())
+}
+
+/// Wait until no handler invocation for `irq` that started before the
+/// call is still running, on any CPU.
+///
+/// Invocations starting afterwards see whatever handler is registered by
+/// then. Spins, since handlers are short; must not be called from
+/// interrupt context.
+pub fn synchronize_irq(irq: u32) -> Result<(), ArchError> {
+    let line = LINES.get(irq as usize).ok_or(ArchError::InterruptError)?;
+    debug_assert!(!in_irq(), "synchronize_irq from interrupt context");
+    // Pairs with the fence in dispatch: either it sees the handler gone or
+    // its invocation is counted here
+    core::sync::atomic::fence(Ordering::SeqCst);
+    while line.running.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Unmask an interrupt line at the interrupt controller.
+pub fn enable(irq: u32) -> Result<(), ArchError> {
+    if irq as usize >= MAX_IRQS {
+        return Err(ArchError::InterruptError);
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        crate::arch::aarch64_gic::Gic400::enable_irq(irq);
+    }
+
+    Ok(())
+}
+
+/// Mask an interrupt line at the interrupt controller.
+pub fn disable(irq: u32) -> Result<(), ArchError> {
+    if irq as usize >= MAX_IRQS {
+        return Err(ArchError::InterruptError);
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        crate::arch::aarch64_gic::Gic400::disable_irq(irq);
+    }
+
+    Ok(())
+}
+
+/// How important an interrupt line is, see the
+/// [module docs](self#interrupt-classes).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum IrqClass {
+    /// Below everything else, e.g. bulk transfers.
+    Background = 1,
+    /// Most devices.
+    Normal = 2,
+    /// Devices with deadlines shorter than a time slice; above the tick.
+    RealTime = 3,
+}
+
+impl IrqClass {
+    /// GIC priority of lines in this class (lower is more important).
+    pub const fn gic_priority(self) -> u8 {
+        match self {
+            IrqClass::RealTime => 0x40,
+            IrqClass::Normal => 0xA0,
+            IrqClass::Background => 0xE0,
+        }
+    }
+
+    /// The class a thread of `priority` should mask below: real-time for
+    /// [`HIGH`](crate::sched::priority::HIGH) and above, normal for
+    /// [`LOW`](crate::sched::priority::LOW) and above, background otherwise.
+    pub const fn for_thread_priority(priority: u8) -> Self {
+        use crate::sched::priority::{HIGH, LOW};
+        if priority >= HIGH {
+            IrqClass::RealTime
+        } else if priority >= LOW {
+            IrqClass::Normal
+        } else {
+            IrqClass::Background
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(IrqClass::Background),
+            2 => Some(IrqClass::Normal),
+            3 => Some(IrqClass::RealTime),
+            _ => None,
+        }
+    }
+}
+
+/// Class of each line, `IrqClass as u8` or zero for none
+static CLASSES: [AtomicU8; MAX_IRQS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const UNCLASSED: AtomicU8 = AtomicU8::new(0);
+    [UNCLASSED; MAX_IRQS]
+};
+
+/// Register the handler for an interrupt line and set its class.
+pub fn register_handler_with_class(
+    irq: u32,
+    handler: IrqHandler,
+    class: IrqClass,
+) -> Result<(), ArchError> {
+    set_class(irq, class)?;
+    register_handler(irq, handler)
+}
+
+/// Set the class, and with it the GIC priority, of an interrupt line.
+pub fn set_class(irq: u32, class: IrqClass) -> Result<(), ArchError> {
+    let slot = CLASSES.get(irq as usize).ok_or(ArchError::InterruptError)?;
+    slot.store(class as u8, Ordering::Relaxed);
+
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        crate::arch::aarch64_gic::Gic400::set_priority(irq, class.gic_priority());
+    }
+
+    Ok(())
+}
+
+/// Class of an interrupt line, if one was set.
+pub fn class(irq: u32) -> Option<IrqClass> {
+    IrqClass::from_u8(CLASSES.get(irq as usize)?.load(Ordering::Relaxed))
+}
+
+/// Mask every interrupt less important than `class` on this CPU until the
+/// guard is dropped; see the [module docs](self#interrupt-classes).
+///
+/// Never unmasks anything: inside a stricter mask this changes nothing.
+pub fn mask_below(class: IrqClass) -> IrqMask {
+    let previous = priority_mask();
+    // Signalled are priorities below the mask, so the class itself passes
+    set_priority_mask(previous.min(class.gic_priority() + 1));
+    IrqMask { previous }
+}
+
+/// Restores the priority mask [`mask_below`] replaced when dropped.
+#[must_use = "the mask is lifted when the guard is dropped"]
+pub struct IrqMask {
+    previous: u8,
+}
+
+impl Drop for IrqMask {
+    fn drop(&mut self) {
+        set_priority_mask(self.previous);
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn priority_mask() -> u8 {
+    crate::arch::aarch64_gic::Gic400::priority_mask()
+}
+
+#[cfg(target_arch = "aarch64")]
+fn set_priority_mask(mask: u8) {
+    unsafe { crate::arch::aarch64_gic::Gic400::set_priority_mask(mask) }
+}
+
+// No GIC off AArch64; keep the value so the nesting logic still runs
+#[cfg(not(target_arch = "aarch64"))]
+static PRIORITY_MASK: AtomicU8 = AtomicU8::new(0xFF);
+
+#[cfg(not(target_arch = "aarch64"))]
+fn priority_mask() -> u8 {
+    PRIORITY_MASK.load(Ordering::Relaxed)
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn set_priority_mask(mask: u8) {
+    PRIORITY_MASK.store(mask, Ordering::Relaxed);
+}
+
+/// Set the rate limit for every line.
+pub fn set_storm_policy(policy: StormPolicy) {
+    STORM_WINDOW_NS.store(policy.window.as_nanos().max(1), Ordering::Relaxed);
+    STORM_BACKOFF_NS.store(policy.backoff.as_nanos(), Ordering::Relaxed);
+    STORM_THRESHOLD.store(policy.threshold, Ordering::Relaxed);
+}
+
+/// The rate limit in effect.
+pub fn storm_policy() -> StormPolicy {
+    StormPolicy {
+        threshold: STORM_THRESHOLD.load(Ordering::Relaxed),
+        window: Duration::from_nanos(STORM_WINDOW_NS.load(Ordering::Relaxed)),
+        backoff: Duration::from_nanos(STORM_BACKOFF_NS.load(Ordering::Relaxed)),
+    }
+}
+
+/// Storm history of a line, `None` if out of range.
+pub fn storm_stats(irq: u32) -> Option<StormStats> {
+    LINES.get(irq as usize).map(|line| StormStats {
+        storms: line.storms.load(Ordering::Relaxed),
+        throttled: line.throttled.load(Ordering::Relaxed),
+    })
+}
+
+/// Unmask a line masked for storming without waiting out its backoff.
+///
+/// Does nothing to a line that isn't throttled.
+pub fn unthrottle(irq: u32) -> Result<(), ArchError> {
+    let line = LINES.get(irq as usize).ok_or(ArchError::InterruptError)?;
+    let timer = crate::arch::without_interrupts(|| REENABLE.lock()[irq as usize].take());
+    if let Some(timer) = timer {
+        hrtimer::cancel(timer);
+    }
+    if line.throttled.load(Ordering::Acquire) {
+        restore(irq);
+    }
+    Ok(())
+}
+
+/// Time the handler of `irq` against `budget`, or stop timing it with
+/// `None`. Resets the line's [`BudgetStats`].
+pub fn set_handler_budget(irq: u32, budget: Option<Duration>) -> Result<(), ArchError> {
+    let line = LINES.get(irq as usize).ok_or(ArchError::InterruptError)?;
+    line.overruns.store(0, Ordering::Relaxed);
+    line.worst_ns.store(0, Ordering::Relaxed);
+    // A zero budget still times the handler
+    let ns = budget.map_or(0, |budget| budget.as_nanos().max(1));
+    line.budget_ns.store(ns, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Handler timing of a line, `None` if out of range.
+pub fn budget_stats(irq: u32) -> Option<BudgetStats> {
+    LINES.get(irq as usize).map(|line| BudgetStats {
+        budget: match line.budget_ns.load(Ordering::Relaxed) {
+            0 => None,
+            ns => Some(Duration::from_nanos(ns)),
+        },
+        overruns: line.overruns.load(Ordering::Relaxed),
+        worst: Duration::from_nanos(line.worst_ns.load(Ordering::Relaxed)),
+    })
+}
+
+/// Check one run of the handler of `irq`, which took `elapsed` ns, against
+/// its budget of `budget` ns.
+fn charge_handler(irq: u32, elapsed: u64, budget: u64) {
+    let line = &LINES[irq as usize];
+    let worst = line.worst_ns.fetch_max(elapsed, Ordering::Relaxed);
+    if elapsed <= budget {
+        return;
+    }
+    line.overruns.fetch_add(1, Ordering::Relaxed);
+    if elapsed > worst {
+        crate::klog!(
+            Level::Warn,
+            "irq {} handler took {} ns, budget {} ns",
+            irq,
+            elapsed,
+            budget
+        );
+    }
+}
+
+/// Count an interrupt on `irq` at `now`.
+///
+/// # Returns
+///
+/// `true` exactly once per window, when the line goes over the threshold.
+fn account(irq: u32, now: u64) -> bool {
+    let threshold = STORM_THRESHOLD.load(Ordering::Relaxed);
+    if threshold == 0 {
+        return false;
+    }
+    let line = &LINES[irq as usize];
+    let window = STORM_WINDOW_NS.load(Ordering::Relaxed);
+    if now.wrapping_sub(line.window_start.load(Ordering::Relaxed)) >= window {
+        if line.count.load(Ordering::Relaxed) <= threshold {
+            line.strikes.store(0, Ordering::Relaxed);
+        }
+        line.window_start.store(now, Ordering::Relaxed);
+        line.count.store(1, Ordering::Relaxed);
+        return false;
+    }
+    line.count.fetch_add(1, Ordering::Relaxed) == threshold
+}
+
+/// Mask a storming line and arm its re-enable timer.
+fn throttle(irq: u32) {
+    let line = &LINES[irq as usize];
+    let _ = disable(irq);
+    line.throttled.store(true, Ordering::Release);
+    line.storms.fetch_add(1, Ordering::Relaxed);
+
+    let shift = line
+        .strikes
+        .fetch_add(1, Ordering::Relaxed)
+        .min(MAX_BACKOFF_SHIFT);
+    let backoff = Duration::from_nanos(STORM_BACKOFF_NS.load(Ordering::Relaxed) << shift);
+    crate::klog!(
+        Level::Warn,
+        "irq {} storming, masked for {} ms",
+        irq,
+        backoff.as_millis()
+    );
+    events::publish(KernelEvent::IrqStorm { irq });
+
+    let timer = hrtimer::start_after(backoff, reenable, irq as usize).ok();
+    if timer.is_none() {
+        crate::klog!(
+            Level::Warn,
+            "irq {} stays masked: no timer to re-enable it",
+            irq
+        );
+    }
+    crate::arch::without_interrupts(|| REENABLE.lock()[irq as usize] = timer);
+}
+
+fn reenable(irq: usize) {
+    REENABLE.lock()[irq] = None;
+    restore(irq as u32);
+}
+
+/// Unmask a throttled line with a fresh window.
+fn restore(irq: u32) {
+    let line = &LINES[irq as usize];
+    line.count.store(0, Ordering::Relaxed);
+    line.window_start
+        .store(Instant::now().as_nanos(), Ordering::Relaxed);
+    line.throttled.store(false, Ordering::Release);
+    let _ = enable(irq);
+}
+
+#[cfg(not(all(not(target_arch = "aarch64"), feature = "std-shim")))]
+crate::percpu! {
+    /// Interrupt handlers currently running on each CPU (more than one if
+    /// nested).
+    static IRQ_DEPTH: AtomicUsize = AtomicUsize::new(0);
+}
+
+// On the host every OS thread stands in for a CPU, so threads entering
+// interrupt context (tests, in parallel) don't see each other's
+// interrupt depth.
+#[cfg(all(not(target_arch = "aarch64"), feature = "std-shim"))]
+extern crate std;
+#[cfg(all(not(target_arch = "aarch64"), feature = "std-shim"))]
+std::thread_local! {
+    static IRQ_DEPTH: AtomicUsize = const { AtomicUsize::new(0) };
+}
+
+/// Run `f` on the calling CPU's interrupt depth.
+fn irq_depth<R>(f: impl FnOnce(&AtomicUsize) -> R) -> R {
+    #[cfg(not(all(not(target_arch = "aarch64"), feature = "std-shim")))]
+    return f(IRQ_DEPTH.get());
+
+    #[cfg(all(not(target_arch = "aarch64"), feature = "std-shim"))]
+    IRQ_DEPTH.with(f)
+}
+
+/// Whether the CPU is handling an interrupt.
+pub fn in_irq() -> bool {
+    irq_depth(|depth| depth.load(Ordering::Relaxed) != 0)
+}
+
+/// Marks the CPU as in interrupt context until dropped.
+///
+/// The IRQ vector holds one around handler dispatch; platforms with their
+/// own vectors should do the same.
+pub struct IrqGuard(());
+
+impl IrqGuard {
+    pub fn enter() -> Self {
+        irq_depth(|depth| depth.fetch_add(1, Ordering::Relaxed));
+        IrqGuard(())
+    }
+}
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        irq_depth(|depth| depth.fetch_sub(1, Ordering::Relaxed));
+    }
+}
+
+/// A thread woken by the handler running on a CPU, for the IRQ exit to
+/// switch to.
+struct Handoff {
+    /// Raw id, 0 for none
+    thread: AtomicU64,
+    priority: AtomicU8,
+}
+
+#[cfg(not(all(not(target_arch = "aarch64"), feature = "std-shim")))]
+crate::percpu! {
+    static HANDOFFS: Handoff = Handoff { thread: AtomicU64::new(0), priority: AtomicU8::new(0) };
+}
+
+// Per OS thread on the host, like the interrupt depth
+#[cfg(all(not(target_arch = "aarch64"), feature = "std-shim"))]
+std::thread_local! {
+    static HANDOFFS: Handoff = const { Handoff { thread: AtomicU64::new(0), priority: AtomicU8::new(0) } };
+}
+
+/// Run `f` on the calling CPU's handoff slot.
+fn handoff<R>(f: impl FnOnce(&Handoff) -> R) -> R {
+    #[cfg(not(all(not(target_arch = "aarch64"), feature = "std-shim")))]
+    return f(HANDOFFS.get());
+
+    #[cfg(all(not(target_arch = "aarch64"), feature = "std-shim"))]
+    HANDOFFS.with(f)
+}
+
+/// Have the IRQ exit switch straight to `thread`, which the handler
+/// running now just woke and which outranks the thread it interrupted.
+///
+/// Of several threads woken by one interrupt the highest priority wins,
+/// the first on a tie. Returns `false` outside interrupt context, where
+/// the caller has to ask for a reschedule instead.
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+pub(crate) fn hand_off_to(thread: ThreadId, priority: u8) -> bool {
+    if !in_irq() {
+        return false;
+    }
+    // Only this CPU's handler touches its slot, with interrupts masked
+    handoff(|handoff| {
+        if handoff.thread.load(Ordering::Relaxed) == 0
+            || priority > handoff.priority.load(Ordering::Relaxed)
+        {
+            handoff.priority.store(priority, Ordering::Relaxed);
+            handoff.thread.store(thread.as_u64(), Ordering::Relaxed);
+        }
+    });
+    true
+}
+
+/// Claim the thread [`hand_off_to`] left for this CPU, if any.
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+pub(crate) fn take_handoff() -> Option<ThreadId> {
+    match handoff(|handoff| handoff.thread.swap(0, Ordering::Relaxed)) {
+        0 => None,
+        raw => Some(ThreadId::new(raw)),
+    }
+}
+
+/// Global allocator wrapper that flags allocations made in interrupt
+/// context.
+///
+/// Handlers must not allocate: the allocator may be mid-operation in the
+/// interrupted thread, and allocation time is unbounded. Frees are not
+/// checked.
+pub struct IrqCheckedAlloc<A> {
+    inner: A,
+    panic: bool,
+    violations: AtomicUsize,
+}
+
+impl<A> IrqCheckedAlloc<A> {
+    /// Panic on any allocation in interrupt context.
+    pub const fn panicking(inner: A) -> Self {
+        Self {
+            inner,
+            panic: true,
+            violations: AtomicUsize::new(0),
+        }
+    }
+
+    /// Log and count allocations in interrupt context, then serve them.
+    ///
+    /// Useful while known offenders remain, e.g. the run queue node
+    /// allocated when the tick requeues a preempted thread.
+    pub const fn logging(inner: A) -> Self {
+        Self {
+            inner,
+            panic: false,
+            violations: AtomicUsize::new(0),
+        }
+    }
+
+    /// Allocations seen in interrupt context so far.
+    pub fn violations(&self) -> usize {
+        self.violations.load(Ordering::Relaxed)
+    }
+
+    fn check(&self, layout: Layout) {
+        if !in_irq() {
+            return;
+        }
+        self.violations.fetch_add(1, Ordering::Relaxed);
+        if self.panic {
+            panic!(
+                "heap allocation of {} bytes in interrupt context",
+                layout.size()
+            );
+        }
+        crate::klog!(
+            crate::kernel::log::Level::Warn,
+            "heap allocation of {} bytes in interrupt context",
+            layout.size()
+        );
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for IrqCheckedAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.check(layout);
+        unsafe { self.inner.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.check(layout);
+        unsafe { self.inner.alloc_zeroed(layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.check(layout);
+        unsafe { self.inner.realloc(ptr, layout, new_size) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.inner.dealloc(ptr, layout) }
+    }
+}
+
+/// Call the registered handler for `irq`, masking the line if it is
+/// [storming](self#interrupt-storms).
+///
+/// # Returns
+///
+/// `true` if a handler was registered and called.
+pub fn dispatch(irq: u32) -> bool {
+    let Some(slot) = HANDLERS.get(irq as usize) else {
+        return false;
+    };
+
+    let line = &LINES[irq as usize];
+    line.running.fetch_add(1, Ordering::Relaxed);
+    core::sync::atomic::fence(Ordering::SeqCst);
+    let ptr = slot.load(ordering::acquire(Edge::IrqHandler, Ordering::Acquire));
+    if ptr.is_null() {
+        line.running.fetch_sub(1, Ordering::Release);
+        return false;
+    }
+
+    let handler: IrqHandler = unsafe { core::mem::transmute::<*mut (), IrqHandler>(ptr) };
+    let budget = line.budget_ns.load(Ordering::Relaxed);
+    let start = if budget != 0 {
+        Instant::now().as_nanos()
+    } else {
+        0
+    };
+    handler(irq);
+    line.running.fetch_sub(1, Ordering::Release);
+    let now = Instant::now().as_nanos();
+    if budget != 0 {
+        charge_handler(irq, now.saturating_sub(start), budget);
+    }
+    if account(irq, now) {
+        throttle(irq);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use portable_atomic::AtomicU32;
+
+    static LAST_IRQ: AtomicU32 = AtomicU32::new(0);
+
+    fn record_irq(irq: u32) {
+        LAST_IRQ.store(irq, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_register_and_dispatch() {
+        assert!(!dispatch(200));
+
+        register_handler(200, record_irq).unwrap();
+        assert!(dispatch(200));
+        assert_eq!(LAST_IRQ.load(Ordering::SeqCst), 200);
+
+        unregister_handler(200).unwrap();
+        assert!(!dispatch(200));
+    }
+
+    #[test]
+    fn test_class_sets_priority_and_masks_nest() {
+        assert_eq!(class(201), None);
+        register_handler_with_class(201, record_irq, IrqClass::RealTime).unwrap();
+        assert_eq!(class(201), Some(IrqClass::RealTime));
+        assert!(set_class(MAX_IRQS as u32, IrqClass::Normal).is_err());
+        unregister_handler(201).unwrap();
+
+        assert_eq!(
+            IrqClass::for_thread_priority(crate::sched::priority::REALTIME),
+            IrqClass::RealTime
+        );
+        assert_eq!(
+            IrqClass::for_thread_priority(crate::sched::priority::NORMAL),
+            IrqClass::Normal
+        );
+
+        let outer = mask_below(IrqClass::RealTime);
+        let strict = priority_mask();
+        assert!(strict > IrqClass::RealTime.gic_priority());
+        #[cfg(target_arch = "aarch64")]
+        assert!(strict <= crate::arch::aarch64_gic::TIMER_PRIORITY);
+        {
+            let _inner = mask_below(IrqClass::Background);
+            assert_eq!(priority_mask(), strict);
+        }
+        drop(outer);
+        assert_eq!(priority_mask(), 0xFF);
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_checked_alloc_counts_irq_allocations() {
+        extern crate std;
+
+        let allocator = IrqCheckedAlloc::logging(std::alloc::System);
+        let layout = Layout::new::<u64>();
+        unsafe {
+            allocator.dealloc(allocator.alloc(layout), layout);
+            assert_eq!(allocator.violations(), 0);
+
+            let irq = IrqGuard::enter();
+            assert!(in_irq());
+            allocator.dealloc(allocator.alloc(layout), layout);
+            drop(irq);
+        }
+        assert_eq!(allocator.violations(), 1);
+    }
+
+    #[test]
+    fn test_handoff_keeps_highest_priority() {
+        let (low, high, tie) = (ThreadId::new(31), ThreadId::new(32), ThreadId::new(33));
+        assert!(!hand_off_to(low, 10));
+
+        let irq = IrqGuard::enter();
+        assert!(hand_off_to(low, 10));
+        assert!(hand_off_to(high, 200));
+        assert!(hand_off_to(tie, 200));
+        drop(irq);
+        assert_eq!(take_handoff(), Some(high));
+        assert_eq!(take_handoff(), None);
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_unregister_waits_for_running_handler() {
+        extern crate std;
+        use std::time::Duration as StdDuration;
+
+        static ENTERED: AtomicBool = AtomicBool::new(false);
+        static RELEASE: AtomicBool = AtomicBool::new(false);
+        fn slow(_irq: u32) {
+            ENTERED.store(true, Ordering::SeqCst);
+            while !RELEASE.load(Ordering::SeqCst) {
+                core::hint::spin_loop();
+            }
+        }
+
+        let irq = 203;
+        register_handler(irq, slow).unwrap();
+        let cpu = std::thread::spawn(move || dispatch(irq));
+        while !ENTERED.load(Ordering::SeqCst) {
+            std::thread::yield_now();
+        }
+
+        let unregister = std::thread::spawn(move || unregister_handler(irq));
+        std::thread::sleep(StdDuration::from_millis(20));
+        assert!(!unregister.is_finished());
+        RELEASE.store(true, Ordering::SeqCst);
+        assert_eq!(unregister.join().unwrap(), Ok(()));
+        assert!(cpu.join().unwrap());
+        assert!(!dispatch(irq));
+    }
+
+    #[test]
+    fn test_storming_line_is_throttled() {
+        // Long backoff so the re-enable timer can't fire during other tests
+        set_storm_policy(StormPolicy {
+            threshold: 3,
+            window: Duration::from_millis(1),
+            backoff: Duration::from_millis(10_000),
+        });
+        let irq = 201;
+        let base = LINES[irq as usize].window_start.load(Ordering::Relaxed) + 5_000_000;
+        assert!(!(0..3).any(|i| account(irq, base + i)));
+        assert!(account(irq, base + 3));
+        assert!(!account(irq, base + 4));
+
+        throttle(irq);
+        assert_eq!(
+            storm_stats(irq),
+            Some(StormStats {
+                storms: 1,
+                throttled: true
+            })
+        );
+        unthrottle(irq).unwrap();
+        assert_eq!(
+            storm_stats(irq),
+            Some(StormStats {
+                storms: 1,
+                throttled: false
+            })
+        );
+
+        // A quiet window resets the count
+        assert!(!account(irq, base + 2_000_000));
+        set_storm_policy(StormPolicy::default());
+    }
+
+    #[test]
+    fn test_handler_budget_counts_overruns() {
+        let irq = 202;
+        assert_eq!(budget_stats(irq).unwrap().budget, None);
+        set_handler_budget(irq, Some(Duration::from_micros(10))).unwrap();
+        charge_handler(irq, 4_000, 10_000);
+        charge_handler(irq, 25_000, 10_000);
+        charge_handler(irq, 12_000, 10_000);
+        assert_eq!(
+            budget_stats(irq),
+            Some(BudgetStats {
+                budget: Some(Duration::from_micros(10)),
+                overruns: 2,
+                worst: Duration::from_nanos(25_000),
+            })
+        );
+        set_handler_budget(irq, None).unwrap();
+        assert_eq!(budget_stats(irq), Some(BudgetStats::default()));
+        assert!(set_handler_budget(MAX_IRQS as u32, None).is_err());
+    }
+
+    #[test]
+    fn test_out_of_range_irq_rejected() {
+        assert_eq!(
+            register_handler(MAX_IRQS as u32, record_irq),
+            Err(ArchError::InterruptError)
+        );
+        assert!(!dispatch(MAX_IRQS as u32));
+    }
+}
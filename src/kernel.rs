@@ -1,56 +1,658 @@
-
-
 use crate::arch::Arch;
-use crate::sched::Scheduler;
-use crate::thread::{JoinHandle, ReadyRef, RunningRef, Thread, ThreadId};
-use crate::mem::{StackPool, StackSizeClass};
-use crate::errors::SpawnError;
-use core::marker::PhantomData;
-use portable_atomic::{AtomicBool, AtomicUsize, AtomicPtr, Ordering};
+use crate::errors::{CheckpointError, KernelError, ScheduleError, SpawnError, TimerError};
+use crate::mem::{Stack, StackPool, StackSizeClass};
+use crate::platform_timer::{self, PreemptionMode};
+use crate::sched::{priority, Scheduler};
+use crate::sync::ordering::{self, Edge};
+use crate::thread::{
+    Checkpoint, JoinHandle, ReadyRef, RunningRef, StackOverflow, Thread, ThreadBuilder,
+    ThreadConfig, ThreadId, ThreadSlab, ThreadState,
+};
+use crate::time::switch_latency::{self, SwitchPath};
+use crate::time::Duration;
+use crate::time::Instant;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::ops::RangeInclusive;
+use portable_atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+pub mod config;
+pub mod crash_log;
+pub mod embed;
+pub mod events;
+pub mod footprint;
+#[cfg(feature = "leak-check")]
+pub mod leak;
+pub mod log;
+pub mod metrics;
+pub mod panic;
+pub mod sleep;
+pub mod slo;
+pub mod stall;
+pub mod supervisor;
+pub mod watch;
+
+pub use config::{DefaultConfig, KernelConfig, TickRoute};
+pub use crash_log::CrashReport;
+pub use embed::{PollDriver, PollStatus};
+pub use panic::PanicPolicy;
+pub use sleep::{sleep_for, sleep_for_with_slack, sleep_until, sleep_until_with_slack};
 
 static GLOBAL_KERNEL: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+static GLOBAL_OPS: AtomicPtr<&'static dyn KernelOps> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Type-erased view of a registered kernel.
+///
+/// Interrupt handlers and synchronization primitives cannot name the concrete
+/// `Kernel<A, S>` type, so they reach the running kernel through this trait
+/// (see [`global_ops`]).
+pub trait KernelOps: Sync {
+    /// Yield the current thread.
+    fn yield_now(&self);
+    /// Mark the current thread finished and switch away from it.
+    fn finish_and_yield(&self);
+    /// Terminate the current thread; see [`Kernel::kill_current`].
+    fn kill_current(&self);
+    /// Block the current thread; see [`Kernel::block_current`].
+    fn block_current(&self);
+    /// Make a blocked thread runnable; see [`Kernel::wake`].
+    fn wake(&self, thread: Thread);
+    /// Get the currently running thread.
+    fn current_thread(&self) -> Option<Thread>;
+    /// The live thread with `id`; see [`Kernel::thread`].
+    fn thread(&self, id: ThreadId) -> Option<Thread>;
+    /// Hand the CPU to a specific thread; see [`Kernel::yield_to`].
+    fn yield_to(&self, target: ThreadId) -> bool;
+    /// Wake the sleepers whose deadlines have passed; see
+    /// [`Kernel::wake_sleepers`].
+    fn wake_sleepers(&self);
+    /// Run the scheduler from the timer interrupt; see
+    /// [`Kernel::handle_irq_preemption`].
+    #[cfg(target_arch = "aarch64")]
+    fn handle_irq_preemption(&self);
+    /// Switch to a thread woken by the interrupt being handled; see
+    /// [`Kernel::handle_irq_handoff`].
+    #[cfg(target_arch = "aarch64")]
+    fn handle_irq_handoff(&self);
+}
+
+/// Where the kernel is in its lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RunState {
+    /// Threads may be spawned but none has been started.
+    NotStarted = 0,
+    /// [`Kernel::start_first_thread`] handed the CPU to the threads.
+    Running = 1,
+    /// [`Kernel::begin_shutdown`] was called.
+    ShuttingDown = 2,
+}
+
+impl RunState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => RunState::Running,
+            2 => RunState::ShuttingDown,
+            _ => RunState::NotStarted,
+        }
+    }
+}
+
+/// Called around every context switch with the outgoing thread (`None`
+/// when starting the first one) and the incoming one; see
+/// [`Kernel::set_switch_hooks`].
+pub type SwitchHook = fn(from: Option<ThreadId>, to: ThreadId);
 
-pub struct Kernel<A: Arch, S: Scheduler> {
+pub struct Kernel<A: Arch, S: Scheduler, C: KernelConfig = DefaultConfig> {
     scheduler: S,
     stack_pool: StackPool,
     _arch: PhantomData<A>,
+    _config: PhantomData<C>,
+    /// Threads spawned and not yet finished, bounded by `C::MAX_THREADS`
+    live_threads: AtomicUsize,
     initialized: AtomicBool,
-    next_thread_id: AtomicUsize,
+    /// A `RunState`
+    run_state: AtomicU8,
+    /// Live threads by id, created with the first spawn
+    threads: spin::Once<ThreadSlab>,
+    /// Only locked with interrupts disabled. On the single scheduling CPU
+    /// that means the tick can never find it held by the code it
+    /// interrupted; the tick still only `try_lock`s it.
     current_thread: spin::Mutex<Option<RunningRef>>,
+    preemption_mode: PreemptionMode,
+    /// Set once the preemption tick is armed
+    timer_armed: AtomicBool,
+    /// Why the kernel fell back to cooperative scheduling, if it did
+    degraded: spin::Mutex<Option<KernelError>>,
+    /// Report left by a panic on the previous boot
+    last_crash: spin::Mutex<Option<CrashReport>>,
+    /// `SwitchHook`s as addresses, 0 when unset
+    pre_switch: AtomicUsize,
+    post_switch: AtomicUsize,
+    /// Threads run inside `poll` rather than after `start_first_thread`
+    embedded: AtomicBool,
+    /// End of the running `poll` in nanoseconds, 0 outside `poll`
+    poll_deadline: AtomicU64,
+    /// A `PollStatus`, set by whoever switches back to the `poll` caller
+    poll_status: AtomicU8,
+    /// Where the caller of `poll` is resumed; written by the switch into
+    /// the first thread before anything reads it
+    host_context: UnsafeCell<MaybeUninit<A::SavedContext>>,
 }
 
-impl<A: Arch, S: Scheduler> Kernel<A, S> {
+impl<A: Arch, S: Scheduler, C: KernelConfig> Kernel<A, S, C> {
     pub const fn new(scheduler: S) -> Self {
         Self {
             scheduler,
             stack_pool: StackPool::new(),
             _arch: PhantomData,
+            _config: PhantomData,
+            live_threads: AtomicUsize::new(0),
             initialized: AtomicBool::new(false),
-            next_thread_id: AtomicUsize::new(1),
+            run_state: AtomicU8::new(RunState::NotStarted as u8),
+            threads: spin::Once::new(),
             current_thread: spin::Mutex::new(None),
+            preemption_mode: PreemptionMode::Full,
+            timer_armed: AtomicBool::new(false),
+            degraded: spin::Mutex::new(None),
+            last_crash: spin::Mutex::new(None),
+            pre_switch: AtomicUsize::new(0),
+            post_switch: AtomicUsize::new(0),
+            embedded: AtomicBool::new(false),
+            poll_deadline: AtomicU64::new(0),
+            poll_status: AtomicU8::new(PollStatus::Idle as u8),
+            host_context: UnsafeCell::new(MaybeUninit::uninit()),
         }
     }
 
-    pub fn init(&self) -> Result<(), ()> {
-        if self
-            .initialized
-            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
-            .is_ok()
+    /// Select the preemption mode [`init_scheduler`](Self::init_scheduler)
+    /// applies.
+    ///
+    /// It can be changed at runtime with
+    /// [`platform_timer::set_preemption_mode`].
+    pub const fn with_preemption_mode(mut self, mode: PreemptionMode) -> Self {
+        self.preemption_mode = mode;
+        self
+    }
+
+    /// Run every initialization stage in order: memory, interrupts, timer,
+    /// scheduler.
+    ///
+    /// Once memory is up, a [crash report](crash_log) left by the previous
+    /// boot is collected and made available through
+    /// [`last_crash`](Self::last_crash).
+    ///
+    /// If the interrupt controller or timer can't be brought up the kernel
+    /// falls back to cooperative scheduling (see
+    /// [`fall_back_to_cooperative`](Self::fall_back_to_cooperative)) rather
+    /// than failing.
+    ///
+    /// Platforms that need a different order or extra steps in between can
+    /// call the stages themselves.
+    pub fn init(&self) -> Result<(), KernelError> {
+        if self.is_initialized() {
+            return Err(KernelError::AlreadyInitialized);
+        }
+        self.init_memory()?;
+        *self.last_crash.lock() = crash_log::take();
+        match self.init_interrupts().and_then(|()| self.init_timer()) {
+            Ok(()) => {}
+            Err(
+                e @ (KernelError::InterruptControllerMissing
+                | KernelError::TimerInitFailed(_)
+                | KernelError::TimerFrequency { .. }),
+            ) => {
+                self.fall_back_to_cooperative(e);
+            }
+            Err(e) => return Err(e),
+        }
+        self.init_scheduler()
+    }
+
+    /// Run without a preemption tick: threads only switch when they yield,
+    /// block or reach a preemption point, and every preemption point
+    /// yields.
+    ///
+    /// [`init`](Self::init) calls this when the interrupt controller or
+    /// timer fails; platforms using the stages directly can call it with
+    /// the error themselves, before [`init_scheduler`](Self::init_scheduler).
+    pub fn fall_back_to_cooperative(&self, reason: KernelError) {
+        *self.degraded.lock() = Some(reason);
+        self.timer_armed.store(false, Ordering::Release);
+        crate::pl011_println!("[KERNEL] ************************************************");
+        crate::pl011_println!("[KERNEL] WARNING: no preemption tick ({})", reason);
+        crate::pl011_println!("[KERNEL] Running cooperatively: threads that never yield,");
+        crate::pl011_println!("[KERNEL] block or hit a preemption point will starve others");
+        crate::pl011_println!("[KERNEL] ************************************************");
+    }
+
+    /// Whether the timer tick is preempting threads.
+    ///
+    /// `false` after a [cooperative fallback](Self::fall_back_to_cooperative),
+    /// with [`PreemptionMode::None`], and on targets without a tick.
+    pub fn preemption_active(&self) -> bool {
+        self.timer_armed.load(Ordering::Acquire)
+            && platform_timer::preemption_mode() != PreemptionMode::None
+    }
+
+    /// Why the system went down on the previous boot, if it panicked with
+    /// a [crash log](crash_log) configured.
+    pub fn last_crash(&self) -> Option<CrashReport> {
+        self.last_crash.lock().clone()
+    }
+
+    /// Choose what a panic does from now on; see [`panic`](mod@panic).
+    pub fn set_panic_policy(&self, policy: PanicPolicy) {
+        panic::set_policy(policy);
+    }
+
+    /// What a panic does.
+    pub fn panic_policy(&self) -> PanicPolicy {
+        panic::policy()
+    }
+
+    /// Why the kernel is running cooperatively, if it fell back.
+    pub fn degradation(&self) -> Option<KernelError> {
+        *self.degraded.lock()
+    }
+
+    /// Check that the global allocator can serve thread stacks and
+    /// closures, and allocate the thread table.
+    pub fn init_memory(&self) -> Result<(), KernelError> {
+        let layout = core::alloc::Layout::new::<u64>();
+        let probe = unsafe { alloc::alloc::alloc(layout) };
+        if probe.is_null() {
+            return Err(KernelError::HeapNotConfigured);
+        }
+        unsafe { alloc::alloc::dealloc(probe, layout) };
+        self.threads();
+        Ok(())
+    }
+
+    /// Set up the interrupt stack and bring up the interrupt controller.
+    ///
+    /// An interrupt stack larger than the built-in one is allocated from the
+    /// heap. Only QEMU virt routes the timer through the GIC; elsewhere the
+    /// controller needs no setup. With [`TickRoute::Fiq`] the timer is
+    /// moved to FIQ, failing with [`KernelError::FiqUnavailable`] where
+    /// that can't be done.
+    pub fn init_interrupts(&self) -> Result<(), KernelError> {
+        // After a shutdown the stack allocated the first time is still in use
+        #[cfg(target_arch = "aarch64")]
+        if C::IRQ_STACK_SIZE > crate::arch::aarch64::IRQ_STACK_SIZE
+            && crate::arch::aarch64::irq_stack_top()
+                == crate::arch::aarch64::builtin_irq_stack_top()
         {
-            Ok(())
-        } else {
-            Err(())
+            let layout = core::alloc::Layout::from_size_align(C::IRQ_STACK_SIZE, 16)
+                .map_err(|_| KernelError::HeapNotConfigured)?;
+            let base = unsafe { alloc::alloc::alloc(layout) };
+            if base.is_null() {
+                return Err(KernelError::HeapNotConfigured);
+            }
+            // Never freed: interrupts use it for as long as the system runs
+            unsafe { crate::arch::aarch64::set_irq_stack_top(base.add(C::IRQ_STACK_SIZE)) };
+        }
+
+        #[cfg(all(target_arch = "aarch64", feature = "qemu-virt"))]
+        if !unsafe { crate::arch::aarch64_gic::init() } {
+            return Err(KernelError::InterruptControllerMissing);
+        }
+
+        if C::TICK_ROUTE == TickRoute::Fiq {
+            #[cfg(all(target_arch = "aarch64", feature = "qemu-virt"))]
+            if !unsafe { crate::arch::aarch64_gic::route_timer_to_fiq() } {
+                return Err(KernelError::FiqUnavailable);
+            }
+            #[cfg(not(all(target_arch = "aarch64", feature = "qemu-virt")))]
+            return Err(KernelError::FiqUnavailable);
+        }
+        Ok(())
+    }
+
+    /// Validate the counter frequency, start the generic timer and arm the
+    /// first preemption tick.
+    ///
+    /// Fails with [`KernelError::TimerFrequency`] if `CNTFRQ_EL0`
+    /// disagrees with the system timer; see [`calibration`](crate::time::calibration).
+    /// The tick is only delivered once interrupts are enabled by
+    /// [`start_first_thread`](Self::start_first_thread). Fails with
+    /// [`TimerError::AlreadyRunning`] if the tick is armed already.
+    pub fn init_timer(&self) -> Result<(), KernelError> {
+        if self.timer_armed.load(Ordering::Acquire) {
+            return Err(TimerError::AlreadyRunning.into());
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            use crate::arch::aarch64;
+            aarch64::init(crate::time::calibration::calibrate(C::TIMER_FREQ_HZ)?)?;
+            unsafe { aarch64::setup_preemption_timer(C::tick_interval_us()) }
+                .map_err(KernelError::TimerInitFailed)?;
+            self.timer_armed.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Disarm the preemption tick armed by [`init_timer`](Self::init_timer).
+    ///
+    /// Threads keep running cooperatively until it is armed again. Fails
+    /// with [`TimerError::NotRunning`] if it isn't armed, as on targets
+    /// without a tick.
+    pub fn stop_timer(&self) -> Result<(), KernelError> {
+        if !self.timer_armed.swap(false, Ordering::AcqRel) {
+            return Err(TimerError::NotRunning.into());
         }
+        #[cfg(target_arch = "aarch64")]
+        crate::arch::aarch64::stop_timer()?;
+        Ok(())
+    }
+
+    /// Apply the preemption mode and allow threads to be spawned.
+    ///
+    /// After a cooperative fallback the mode is [`PreemptionMode::None`]
+    /// whatever was configured.
+    pub fn init_scheduler(&self) -> Result<(), KernelError> {
+        self.initialized
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .map_err(|_| KernelError::AlreadyInitialized)?;
+        let cooperative = self.degradation().is_some();
+        platform_timer::set_cooperative_fallback(cooperative);
+        platform_timer::set_preemption_mode(if cooperative {
+            PreemptionMode::None
+        } else {
+            self.preemption_mode
+        });
+        Ok(())
     }
 
     pub fn is_initialized(&self) -> bool {
         self.initialized.load(Ordering::Acquire)
     }
 
-    pub fn next_thread_id(&self) -> ThreadId {
-        let id = self.next_thread_id.fetch_add(1, Ordering::AcqRel);
-        unsafe { ThreadId::new_unchecked(id) }
+    pub fn run_state(&self) -> RunState {
+        RunState::from_u8(self.run_state.load(Ordering::Acquire))
+    }
+
+    /// Move from `Running` to `ShuttingDown`; [`shutdown`](Self::shutdown)
+    /// finishes the job.
+    ///
+    /// Fails with [`KernelError::NotInitialized`] if threads were never
+    /// started and [`KernelError::ShuttingDown`] if already called.
+    pub fn begin_shutdown(&self) -> Result<(), KernelError> {
+        self.run_state
+            .compare_exchange(
+                RunState::Running as u8,
+                RunState::ShuttingDown as u8,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .map(|_| ())
+            .map_err(|state| match RunState::from_u8(state) {
+                RunState::ShuttingDown => KernelError::ShuttingDown,
+                _ => KernelError::NotInitialized,
+            })
+    }
+
+    /// Finish a shutdown: stop the tick, reset the interrupt controller and
+    /// return the kernel to its state before [`init`](Self::init), which
+    /// may then be called again.
+    ///
+    /// Threads still alive stay queued and run again after the next
+    /// [`start_first_thread`](Self::start_first_thread). Publishes
+    /// [`CpuOffline`](events::KernelEvent::CpuOffline) for the calling CPU.
+    ///
+    /// Fails with [`KernelError::NotInitialized`] before `init`, and with
+    /// [`KernelError::AlreadyStarted`] while threads are running and
+    /// [`begin_shutdown`](Self::begin_shutdown) hasn't been called.
+    pub fn shutdown(&self) -> Result<(), KernelError> {
+        if !self.is_initialized() {
+            return Err(KernelError::NotInitialized);
+        }
+        if self.run_state() == RunState::Running {
+            return Err(KernelError::AlreadyStarted);
+        }
+        match self.stop_timer() {
+            Ok(()) | Err(KernelError::Timer(TimerError::NotRunning)) => {}
+            Err(e) => return Err(e),
+        }
+        #[cfg(all(target_arch = "aarch64", feature = "qemu-virt"))]
+        crate::arch::without_interrupts(|| unsafe { crate::arch::aarch64_gic::shutdown() });
+
+        *self.degraded.lock() = None;
+        self.run_state
+            .store(RunState::NotStarted as u8, Ordering::Release);
+        self.initialized.store(false, Ordering::Release);
+        events::publish(events::KernelEvent::CpuOffline(crate::arch::cpu_id()));
+        Ok(())
+    }
+
+    /// The thread table, sized for `C::MAX_THREADS`.
+    fn threads(&self) -> &ThreadSlab {
+        self.threads
+            .call_once(|| ThreadSlab::new(C::MAX_THREADS.min(ThreadId::MAX_SLOTS)))
+    }
+
+    /// Claim a thread table slot for a thread about to be created.
+    ///
+    /// Every caller has reserved a live thread first, and the table has a
+    /// slot per allowed live thread, so one is always free.
+    fn next_thread_id(&self) -> ThreadId {
+        self.threads()
+            .allocate()
+            .expect("thread table full despite live thread reservation")
+    }
+
+    /// Count the current thread's exit and free its table slot.
+    fn retire(&self, id: ThreadId) {
+        if let Some(table) = self.threads.get() {
+            table.remove(id);
+        }
+        self.scheduler.on_exit(id);
+        self.release_threads(1);
+    }
+
+    /// Retire a thread that has run and announce its exit.
+    fn exited(&self, id: ThreadId, completed: bool) {
+        self.retire(id);
+        events::publish(events::KernelEvent::ThreadExited {
+            thread: id,
+            completed,
+        });
+    }
+
+    /// The live thread with `id`, found in constant time.
+    ///
+    /// Returns `None` once the thread has exited, even if a new thread has
+    /// taken over its slot. Safe to call from interrupt context.
+    pub fn thread(&self, id: ThreadId) -> Option<Thread> {
+        self.threads.get()?.get(id)
+    }
+
+    /// Wake the thread `id` if it is blocked.
+    ///
+    /// Returns whether it was woken; see [`wake`](Self::wake).
+    pub fn wake_by_id(&self, id: ThreadId) -> bool {
+        self.thread(id).is_some_and(|thread| self.try_wake(thread))
+    }
+
+    /// Change the priority of thread `id`.
+    ///
+    /// Fails with [`ScheduleError::NoSuchThread`] if it has exited and with
+    /// [`ScheduleError::PriorityChangeNotAllowed`] if `priority` is outside
+    /// [`priority_range`](Self::priority_range).
+    pub fn set_priority(&self, id: ThreadId, priority: u8) -> Result<(), ScheduleError> {
+        if !self.priority_range().contains(&priority) {
+            return Err(ScheduleError::PriorityChangeNotAllowed);
+        }
+        let thread = self.thread(id).ok_or(ScheduleError::NoSuchThread)?;
+        thread.set_priority(priority);
+        self.scheduler.set_priority(id, priority);
+        Ok(())
+    }
+
+    /// Terminate thread `id`, which must not be the running thread.
+    ///
+    /// A blocked or suspended thread is marked finished where it is; a ready one is
+    /// taken off the run queue first, which needs a scheduler that supports
+    /// [`pick_specific`](Scheduler::pick_specific). Joiners see the thread
+    /// as having failed.
+    pub fn kill(&self, id: ThreadId) -> Result<(), ScheduleError> {
+        crate::arch::without_interrupts(|| {
+            let thread = self.thread(id).ok_or(ScheduleError::NoSuchThread)?;
+            let killed = thread.compare_and_set_state(ThreadState::Blocked, ThreadState::Finished)
+                || thread.compare_and_set_state(ThreadState::Suspended, ThreadState::Finished)
+                || self.scheduler.pick_specific(id).is_some_and(|ready| {
+                    ready.0.set_state(ThreadState::Finished);
+                    true
+                });
+            if !killed {
+                return Err(ScheduleError::InvalidState);
+            }
+            thread.mark_exited();
+            thread.wake_joiners();
+            self.exited(id, false);
+            Ok(())
+        })
+    }
+
+    /// Keep thread `id` off the CPU until [`resume`](Self::resume)d.
+    ///
+    /// A ready thread is taken off the run queue, which needs a scheduler
+    /// that supports [`pick_specific`](Scheduler::pick_specific). A blocked
+    /// thread keeps waiting, and is parked instead of made ready when its
+    /// wake-up comes; once resumed it sees the wake-up as usual. The
+    /// running thread may suspend itself, in which case this returns once
+    /// another thread resumes it. Suspending a suspended thread does
+    /// nothing.
+    ///
+    /// Fails with [`ScheduleError::NoSuchThread`] if the thread has exited,
+    /// and with [`ScheduleError::InvalidState`] if it can't be taken off
+    /// the run queue or is the running thread and this is called from an
+    /// interrupt handler.
+    pub fn suspend(&self, id: ThreadId) -> Result<(), ScheduleError> {
+        let thread = self.thread(id).ok_or(ScheduleError::NoSuchThread)?;
+        if self
+            .current_thread()
+            .is_some_and(|current| current.id() == id)
+        {
+            if crate::irq::in_irq() {
+                return Err(ScheduleError::InvalidState);
+            }
+            let was_enabled = A::interrupts_enabled();
+            thread.request_suspend(true);
+            // Parked as Suspended rather than Blocked, so only resume() wakes it
+            self.block_current();
+            if was_enabled {
+                A::enable_interrupts();
+            }
+            return Ok(());
+        }
+
+        crate::arch::without_interrupts(|| match thread.state() {
+            ThreadState::Finished => Err(ScheduleError::NoSuchThread),
+            ThreadState::Suspended => Ok(()),
+            ThreadState::Blocked => {
+                thread.request_suspend(true);
+                Ok(())
+            }
+            ThreadState::Ready | ThreadState::Running => {
+                let ready = self
+                    .scheduler
+                    .pick_specific(id)
+                    .ok_or(ScheduleError::InvalidState)?;
+                thread.request_suspend(true);
+                ready.0.set_state(ThreadState::Suspended);
+                Ok(())
+            }
+        })
+    }
+
+    /// Let a thread [`suspend`](Self::suspend)ed run again.
+    ///
+    /// Fails with [`ScheduleError::NoSuchThread`] if it has exited and with
+    /// [`ScheduleError::InvalidState`] if it wasn't suspended.
+    pub fn resume(&self, id: ThreadId) -> Result<(), ScheduleError> {
+        crate::arch::without_interrupts(|| {
+            let thread = self.thread(id).ok_or(ScheduleError::NoSuchThread)?;
+            if !thread.request_suspend(false) {
+                return Err(ScheduleError::InvalidState);
+            }
+            // Still blocked if its wake-up hasn't come yet
+            if thread.compare_and_set_state(ThreadState::Suspended, ThreadState::Ready) {
+                self.scheduler.wake_up(ReadyRef(thread));
+            }
+            Ok(())
+        })
+    }
+
+    /// Copy the registers and stack of suspended thread `id` into `buf`
+    /// (experimental; read the soundness rules in
+    /// [`checkpoint`](crate::thread::checkpoint) first).
+    ///
+    /// Fails with [`CheckpointError::BufferTooSmall`] saying how big `buf`
+    /// must be if the stack image doesn't fit.
+    pub fn checkpoint<'a>(
+        &self,
+        id: ThreadId,
+        buf: &'a mut [u8],
+    ) -> Result<Checkpoint<'a>, CheckpointError> {
+        let thread = self.thread(id).ok_or(CheckpointError::NoSuchThread)?;
+        crate::arch::without_interrupts(|| Checkpoint::capture(&thread, buf))
+    }
+
+    /// Start a new thread resuming from `checkpoint`, at `priority`.
+    ///
+    /// The thread gets a fresh stack of the checkpoint's size class and a
+    /// new id.
+    ///
+    /// # Safety
+    ///
+    /// The rules in [`checkpoint`](crate::thread::checkpoint) must hold:
+    ///
+    /// - the original thread has been killed and never runs again;
+    /// - `checkpoint` is restored at most once;
+    /// - heap data the thread referenced at the checkpoint is still there,
+    ///   unmoved and in a state it can resume with;
+    /// - no value on the saved stack merely looks like an address inside
+    ///   it, and nothing outside it points into it;
+    /// - the thread was checkpointed outside any driver or spinlock.
+    pub unsafe fn restore(
+        &self,
+        checkpoint: &Checkpoint<'_>,
+        priority: u8,
+    ) -> Result<JoinHandle, CheckpointError> {
+        if !self.is_initialized() {
+            return Err(CheckpointError::Spawn(SpawnError::NotInitialized));
+        }
+        self.check_priority(priority)
+            .map_err(CheckpointError::Spawn)?;
+        self.reserve_threads(1).map_err(CheckpointError::Spawn)?;
+        let Some(stack) = self.stack_pool.allocate(checkpoint.class()) else {
+            self.release_threads(1);
+            return Err(CheckpointError::Spawn(SpawnError::OutOfMemory));
+        };
+
+        let thread_id = self.next_thread_id();
+        let (thread, join_handle) = Thread::new(thread_id, stack, || {}, priority);
+        thread.set_no_fpu(checkpoint.no_fpu());
+        thread.set_fp_config(checkpoint.fp_config());
+        // The caller upholds the checkpoint rules
+        unsafe { checkpoint.restore_into(&thread) };
+        thread.set_slice_curves(self.scheduler.slice_curves());
+        self.threads().insert(thread.clone());
+        self.scheduler.on_spawn(thread_id);
+
+        if self.scheduler.try_enqueue(ReadyRef(thread)).is_err() {
+            self.retire(thread_id);
+            return Err(CheckpointError::Spawn(SpawnError::SchedulerRejected));
+        }
+        events::publish(events::KernelEvent::ThreadCreated {
+            thread: thread_id,
+            priority,
+        });
+        Ok(join_handle)
     }
 
     /// Get a reference to the scheduler.
@@ -58,143 +660,362 @@ impl<A: Arch, S: Scheduler> Kernel<A, S> {
         &self.scheduler
     }
 
+    /// The pool thread stacks are allocated from, e.g. to set a size
+    /// class's [`StackScrub`](crate::mem::StackScrub) policy.
+    pub fn stack_pool(&self) -> &StackPool {
+        &self.stack_pool
+    }
+
+    /// Spawn a thread running `entry_point` at `priority`, on the
+    /// [default stack](KernelConfig::DEFAULT_STACK). What it returns is
+    /// handed over by [`JoinHandle::join`].
+    pub fn spawn<F, T>(&self, entry_point: F, priority: u8) -> Result<JoinHandle<T>, SpawnError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.spawn_with(
+            ThreadBuilder::new()
+                .stack_size(C::DEFAULT_STACK)
+                .priority(priority),
+            entry_point,
+        )
+    }
+
+    /// Spawn a thread running `entry_point` with every option set on
+    /// `builder`: stack size or class, scrub policy, priority, name, FPU
+    /// use and floating-point settings.
+    pub fn spawn_with<F, T>(
+        &self,
+        builder: ThreadBuilder,
+        entry_point: F,
+    ) -> Result<JoinHandle<T>, SpawnError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.spawn_configured(&builder.validate()?, entry_point)
+    }
 
-    pub fn spawn<F>(&self, entry_point: F, priority: u8) -> Result<JoinHandle, SpawnError>
+    /// [`spawn_with`](Self::spawn_with) for options already validated,
+    /// e.g. one config reused as a template.
+    pub fn spawn_configured<F, T>(
+        &self,
+        config: &ThreadConfig,
+        entry_point: F,
+    ) -> Result<JoinHandle<T>, SpawnError>
     where
-        F: FnOnce() + Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
     {
         if !self.is_initialized() {
             return Err(SpawnError::NotInitialized);
         }
+        self.check_priority(config.priority())?;
+
+        self.reserve_threads(1)?;
+        let stack = match config.allocate_stack(&self.stack_pool) {
+            Ok(stack) => stack,
+            Err(e) => {
+                self.release_threads(1);
+                return Err(e);
+            }
+        };
+
+        self.spawn_on(stack, entry_point, config)
+    }
 
-        let stack = self
+    /// Like [`spawn`](Self::spawn), but if no stack is available waits up
+    /// to `timeout` (or indefinitely if `None`) for one to be returned to
+    /// the pool, failing with [`SpawnError::OutOfMemory`] once it expires.
+    ///
+    /// Blocks the calling thread, so it must not be called from interrupt
+    /// context.
+    pub fn spawn_blocking<F, T>(
+        &self,
+        entry_point: F,
+        priority: u8,
+        timeout: Option<Duration>,
+    ) -> Result<JoinHandle<T>, SpawnError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        if !self.is_initialized() {
+            return Err(SpawnError::NotInitialized);
+        }
+        let config = ThreadBuilder::new()
+            .stack_size(C::DEFAULT_STACK)
+            .priority(priority)
+            .validate()?;
+        self.check_priority(priority)?;
+
+        self.reserve_threads(1)?;
+        let Some(stack) = self
             .stack_pool
-            .allocate(StackSizeClass::Medium)
-            .ok_or(SpawnError::OutOfMemory)?;
+            .allocate_blocking(config.stack_size(), timeout)
+        else {
+            self.release_threads(1);
+            return Err(SpawnError::OutOfMemory);
+        };
+
+        self.spawn_on(stack, entry_point, &config)
+    }
+
+    /// Spawn `n` identically configured threads, building the `i`th one's
+    /// entry closure with `factory(i)`.
+    ///
+    /// All stacks are taken from the pool in one pass before any thread is
+    /// created, so running out of stacks spawns none of them. If the
+    /// scheduler rejects a thread, the ones before it have already been
+    /// queued and keep running; the rest are not created.
+    pub fn spawn_batch<F, W, T>(
+        &self,
+        config: &ThreadConfig,
+        n: usize,
+        mut factory: F,
+    ) -> Result<Vec<JoinHandle<T>>, SpawnError>
+    where
+        F: FnMut(usize) -> W,
+        W: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        if !self.is_initialized() {
+            return Err(SpawnError::NotInitialized);
+        }
+        self.check_priority(config.priority())?;
+
+        self.reserve_threads(n)?;
+        let (class, scrub) = match config.stack_class(&self.stack_pool) {
+            Ok(stack_class) => stack_class,
+            Err(e) => {
+                self.release_threads(n);
+                return Err(e);
+            }
+        };
+        let Some(stacks) = self.stack_pool.allocate_batch_with(class, n, scrub) else {
+            self.release_threads(n);
+            return Err(SpawnError::OutOfMemory);
+        };
+
+        let mut handles = Vec::with_capacity(n);
+        for (i, stack) in stacks.into_iter().enumerate() {
+            match self.spawn_on(stack, factory(i), config) {
+                Ok(handle) => handles.push(handle),
+                Err(e) => {
+                    // Drop the reservations of the threads never created
+                    self.release_threads(n - i - 1);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(handles)
+    }
+
+    /// Priorities application threads may be spawned at: the scheduler's
+    /// [`priority_range`](Scheduler::priority_range) without the range
+    /// reserved for the kernel (see [`priority`](crate::sched::priority)).
+    pub fn priority_range(&self) -> RangeInclusive<u8> {
+        let range = self.scheduler.priority_range();
+        *range.start()..=(*range.end()).min(priority::MAX_USER)
+    }
+
+    fn check_priority(&self, priority: u8) -> Result<(), SpawnError> {
+        if self.priority_range().contains(&priority) {
+            Ok(())
+        } else {
+            Err(SpawnError::InvalidPriority(priority))
+        }
+    }
+
+    /// Count `n` more live threads, failing if that would exceed
+    /// `C::MAX_THREADS`.
+    fn reserve_threads(&self, n: usize) -> Result<(), SpawnError> {
+        self.live_threads
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |live| {
+                live.checked_add(n).filter(|&total| total <= C::MAX_THREADS)
+            })
+            .map(|_| ())
+            .map_err(|_| SpawnError::TooManyThreads)
+    }
+
+    fn release_threads(&self, n: usize) {
+        self.live_threads.fetch_sub(n, Ordering::AcqRel);
+    }
+
+    /// Threads spawned and not yet finished.
+    pub fn live_threads(&self) -> usize {
+        self.live_threads.load(Ordering::Acquire)
+    }
 
+    /// What the kernel holds right now, for [leak checks](leak).
+    #[cfg(feature = "leak-check")]
+    pub fn resource_counts(&self) -> leak::ResourceCounts {
+        let (heap_blocks, heap_bytes) = leak::heap_usage().unzip();
+        leak::ResourceCounts {
+            stacks_in_use: self.stack_pool.stats().2,
+            live_threads: self.live_threads(),
+            registered_threads: self.threads.get().map_or(0, ThreadSlab::len),
+            sleeping_threads: sleep::sleeping(),
+            hrtimers: crate::time::hrtimer::pending(),
+            heap_blocks,
+            heap_bytes,
+        }
+    }
+
+    /// A [`LeakDetector`](leak::LeakDetector) with the current counts as
+    /// its baseline.
+    #[cfg(feature = "leak-check")]
+    pub fn leak_detector(&self) -> leak::LeakDetector {
+        leak::LeakDetector::new(self.resource_counts())
+    }
+
+    /// Compare the current counts with `detector`'s baseline.
+    #[cfg(feature = "leak-check")]
+    pub fn check_leaks(&self, detector: &leak::LeakDetector) -> Result<(), leak::LeakReport> {
+        detector.check(&self.resource_counts())
+    }
+
+    /// Create a thread running `entry_point` on `stack`, set up as
+    /// `config` says, and queue it.
+    ///
+    /// Consumes the caller's live thread reservation either way.
+    fn spawn_on<F, T>(
+        &self,
+        stack: Stack,
+        entry_point: F,
+        config: &ThreadConfig,
+    ) -> Result<JoinHandle<T>, SpawnError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
         let thread_id = self.next_thread_id();
 
         let closure_box = Box::new(entry_point);
         let closure_ptr = Box::into_raw(closure_box);
 
-        fn thread_trampoline<F: FnOnce() + Send + 'static>(closure_ptr: *mut F) {
+        fn thread_trampoline<F, T>(closure_ptr: *mut F)
+        where
+            F: FnOnce() -> T + Send + 'static,
+            T: Send + 'static,
+        {
             crate::arch::DefaultArch::enable_interrupts();
 
             let closure = unsafe { Box::from_raw(closure_ptr) };
-            closure();
-
-            {
-                use crate::thread::current_thread_id;
-                let tid = current_thread_id().get();
-                crate::pl011_println!(r#"{{"id":"log_trampoline_finish","timestamp":0,"location":"kernel.rs:86","message":"Thread finished execution","data":{{"thread_id":{}}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,B,D"}}"#, tid);
+            let value = closure();
+            // Kept on the thread for its JoinHandle to take
+            if let Some(current) = global_ops().and_then(|ops| ops.current_thread()) {
+                current.set_result(Box::new(value));
             }
-            crate::pl011_println!("[THREAD] Finished, calling finish_current()");
-            
+
+            crate::klog_trace!("thread {} returned", crate::thread::current_thread_id());
             crate::kernel::finish_current();
-            
+
             loop {
+                #[cfg(target_arch = "aarch64")]
                 unsafe {
                     core::arch::asm!("wfe", options(nomem, nostack));
                 }
+                #[cfg(not(target_arch = "aarch64"))]
+                core::hint::spin_loop();
             }
         }
 
         let stack_bottom = stack.stack_bottom();
 
+        let priority = config.priority();
         let entry_fn: fn() = || {};
         let (thread, join_handle) = Thread::new(thread_id, stack, entry_fn, priority);
+        config.apply(&thread);
 
         thread.setup_initial_context(
-            thread_trampoline::<F> as *const () as usize,
+            thread_trampoline::<F, T> as *const () as usize,
             stack_bottom as usize,
             closure_ptr as usize,
         );
+        thread.set_slice_curves(self.scheduler.slice_curves());
+        self.threads().insert(thread.clone());
+        self.scheduler.on_spawn(thread_id);
+
+        if self.scheduler.try_enqueue(ReadyRef(thread)).is_err() {
+            self.retire(thread_id);
+            // The thread never ran, so the closure is still ours to drop
+            drop(unsafe { Box::from_raw(closure_ptr) });
+            return Err(SpawnError::SchedulerRejected);
+        }
+        events::publish(events::KernelEvent::ThreadCreated {
+            thread: thread_id,
+            priority,
+        });
 
-        let ready_ref = ReadyRef(thread);
-        self.scheduler.enqueue(ready_ref);
-
-        Ok(join_handle)
+        Ok(join_handle.returning())
     }
 
-    /// Spawn a thread with a simple function pointer (no closure).
-    ///
-    /// This is simpler than spawn() and useful for threads that don't capture state.
+    /// Spawn a thread running a plain function on a small stack.
     pub fn spawn_fn(&self, entry_point: fn(), priority: u8) -> Result<JoinHandle, SpawnError> {
-        if !self.is_initialized() {
-            return Err(SpawnError::NotInitialized);
-        }
-
-        let stack = self
-            .stack_pool
-            .allocate(StackSizeClass::Small)
-            .ok_or(SpawnError::OutOfMemory)?;
-
-        let thread_id = self.next_thread_id();
-        let stack_bottom = stack.stack_bottom();
-
-        let (thread, join_handle) = Thread::new(thread_id, stack, entry_point, priority);
-
-        thread.setup_initial_context(entry_point as usize, stack_bottom as usize, 0);
-
-        let ready_ref = ReadyRef(thread);
-        self.scheduler.enqueue(ready_ref);
-
-        Ok(join_handle)
+        self.spawn_with(
+            ThreadBuilder::new()
+                .stack_size(StackSizeClass::Small)
+                .priority(priority),
+            entry_point,
+        )
     }
 
     #[inline(never)]
     pub fn finish_and_yield(&self) {
-        {
-            crate::pl011_println!(r#"{{"id":"log_finish_and_yield_entry","timestamp":0,"location":"kernel.rs:155","message":"finish_and_yield method entry","data":{{"initialized":{}}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,C"}}"#, self.is_initialized());
-        }
+        self.exit_current(false);
+    }
+
+    /// Terminate the current thread without a result, so joiners see it as
+    /// failed, and switch away from it.
+    #[inline(never)]
+    pub fn kill_current(&self) {
+        self.exit_current(true);
+    }
+
+    fn exit_current(&self, killed: bool) {
         if !self.is_initialized() {
-            {
-                crate::pl011_println!(r#"{{"id":"log_finish_and_yield_not_init","timestamp":0,"location":"kernel.rs:158","message":"Kernel not initialized, returning","data":{{}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,C"}}"#);
-            }
             return;
         }
 
+        // Back to the heap now rather than when the last handle goes
+        if let Some(current) = self.current_thread() {
+            drop(current.arena().lock().take());
+        }
+
         A::disable_interrupts();
 
         let mut current_guard = self.current_thread.lock();
 
         if let Some(current) = current_guard.take() {
-            let prev_id = current.id().get();
+            let prev_thread = current.id();
             let prev_ctx = current.0.context_ptr();
 
-            {
-                crate::pl011_println!(r#"{{"id":"log_finish_and_yield","timestamp":0,"location":"kernel.rs:180","message":"finish_and_yield called","data":{{"thread_id":{}}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,C"}}"#, prev_id);
-            }
-
-            {
-                crate::pl011_println!(r#"{{"id":"log_finish_after_get_current","timestamp":0,"location":"kernel.rs:184","message":"Got current thread, about to finish","data":{{"thread_id":{}}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,C"}}"#, prev_id);
+            if killed {
+                current.kill();
+            } else {
+                // Records the join result, so join() reports a normal exit
+                current.finish();
             }
+            self.exited(prev_thread, !killed);
 
-            current.0.set_state(crate::thread::ThreadState::Finished);
-            crate::pl011_println!("[DEBUG] Set thread {} state to Finished", prev_id);
-            crate::pl011_println!("[DEBUG] About to drop current RunningRef");
-
-            {
-                let _ = current;
-            }
-            crate::pl011_println!("[DEBUG] Thread {} dropped, ready to pick next", prev_id);
-            
-            {
-                crate::pl011_println!(r#"{{"id":"log_finish_after_finish","timestamp":0,"location":"kernel.rs:210","message":"After marking thread as finished","data":{{"thread_id":{}}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,C"}}"#, prev_id);
+            if self.poll_expired() {
+                self.return_to_host(prev_ctx, PollStatus::BudgetExhausted, current_guard);
+                A::enable_interrupts();
+                return;
             }
 
-            {
-                crate::pl011_println!(r#"{{"id":"log_finish_before_pick_next","timestamp":0,"location":"kernel.rs:181","message":"About to call pick_next","data":{{"thread_id":{}}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"B,E"}}"#, prev_id);
+            let next = self.scheduler.pick_next(0);
+            if next.is_none() && self.polling() {
+                self.return_to_host(prev_ctx, PollStatus::Idle, current_guard);
+                A::enable_interrupts();
+                return;
             }
-            if let Some(next) = self.scheduler.pick_next(0) {
-                let next_id = next.id().get();
+            if let Some(next) = next {
                 let next_ctx = next.0.context_ptr();
-                {
-                    crate::pl011_println!(r#"{{"id":"log_finish_pick_next","timestamp":0,"location":"kernel.rs:165","message":"pick_next after finish","data":{{"finished_thread":{},"next_thread":{}}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"B,E"}}"#, prev_id, next_id);
-                }
-                crate::pl011_println!("[FINISH] T{} finished, switching to T{}", prev_id, next_id);
-                let running = next.start_running();
-                *current_guard = Some(running);
+                crate::klog_trace!("finish {} -> {}", prev_thread, next.id());
+                self.install_next(Some(prev_thread), next, &mut current_guard);
                 drop(current_guard);
 
                 if !prev_ctx.is_null() && !next_ctx.is_null() {
@@ -209,9 +1030,6 @@ impl<A: Arch, S: Scheduler> Kernel<A, S> {
                     A::enable_interrupts();
                 }
             } else {
-                {
-                    crate::pl011_println!(r#"{{"id":"log_finish_no_next","timestamp":0,"location":"kernel.rs:185","message":"No next thread after finish","data":{{"finished_thread":{}}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"B,E"}}"#, prev_id);
-                }
                 A::enable_interrupts();
             }
         } else {
@@ -226,52 +1044,39 @@ impl<A: Arch, S: Scheduler> Kernel<A, S> {
             return;
         }
 
+        // Any requested preemption is satisfied by this switch
+        platform_timer::clear_preemption_pending();
+
         A::disable_interrupts();
 
         let mut current_guard = self.current_thread.lock();
 
         if let Some(current) = current_guard.take() {
-            let prev_id = current.id().get();
-            let prev_ctx = current.0.context_ptr();
-            let prev_state = current.0.state();
-
-            {
-                let state_val = prev_state as u8;
-                crate::pl011_println!(r#"{{"id":"log_yield_entry","timestamp":0,"location":"kernel.rs:200","message":"yield_now called","data":{{"thread_id":{},"state":{}}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,B,C"}}"#, prev_id, state_val);
+            if let Some(overflow) = Self::overflowed(&current, crate::arch::stack_pointer()) {
+                self.exit_overflowed(current_guard, current, overflow);
             }
 
-            let current_sp: u64;
-            unsafe { core::arch::asm!("mov {}, sp", out(reg) current_sp); }
-            crate::pl011_println!("[DEBUG] T{} yielding, actual SP={:#x}, ctx_addr={:#x}",
-                prev_id, current_sp, prev_ctx as usize);
+            let prev_thread = current.id();
+            let prev_ctx = current.0.context_ptr();
+            Self::save_fpu(&current.0);
 
             let ready = current.stop_running();
-            {
-                let after_state = ready.0.state();
-                let state_val = after_state as u8;
-                crate::pl011_println!(r#"{{"id":"log_yield_after_stop","timestamp":0,"location":"kernel.rs:215","message":"After stop_running, before enqueue","data":{{"thread_id":{},"state":{}}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,C"}}"#, prev_id, state_val);
-            }
             self.scheduler.enqueue(ready);
 
+            if self.poll_expired() {
+                self.return_to_host(prev_ctx, PollStatus::BudgetExhausted, current_guard);
+                A::enable_interrupts();
+                return;
+            }
+
             if let Some(next) = self.scheduler.pick_next(0) {
-                let next_id = next.id().get();
                 let next_ctx = next.0.context_ptr();
-                {
-                    crate::pl011_println!(r#"{{"id":"log_yield_pick_next","timestamp":0,"location":"kernel.rs:158","message":"pick_next returned thread","data":{{"yielding_thread":{},"next_thread":{}}},"sessionId":"debug-session","runId":"run1","hypothesisId":"B,E"}}"#, prev_id, next_id);
-                }
-                crate::pl011_println!("[YIELD] {} -> {}: next_ctx_addr={:#x}",
-                    prev_id, next_id, next_ctx as usize);
-                let next_pc = unsafe { (*next_ctx).pc };
-                let next_sp = unsafe { (*next_ctx).sp };
-                let next_x30 = unsafe { (*next_ctx).x[30] };
-                crate::pl011_println!("        next_pc={:#x}, next_sp={:#x}, next_x30={:#x}",
-                    next_pc, next_sp, next_x30);
-                let running = next.start_running();
-                *current_guard = Some(running);
+                crate::klog_trace!("yield {} -> {}", prev_thread, next.id());
+                self.install_next(Some(prev_thread), next, &mut current_guard);
                 drop(current_guard);
 
-
                 if !prev_ctx.is_null() && !next_ctx.is_null() {
+                    switch_latency::switching(SwitchPath::Synchronous);
                     unsafe {
                         A::context_switch(
                             prev_ctx as *mut A::SavedContext,
@@ -279,15 +1084,10 @@ impl<A: Arch, S: Scheduler> Kernel<A, S> {
                         );
                     }
                     A::enable_interrupts();
-                    let my_saved_sp = unsafe { (*prev_ctx).sp };
-                    crate::pl011_println!("[RESUMED] saved_sp in my ctx = {:#x}", my_saved_sp);
                 } else {
                     A::enable_interrupts();
                 }
             } else {
-                {
-                    crate::pl011_println!(r#"{{"id":"log_yield_no_next","timestamp":0,"location":"kernel.rs:185","message":"pick_next returned None","data":{{"yielding_thread":{}}},"sessionId":"debug-session","runId":"run1","hypothesisId":"B,E"}}"#, prev_id);
-                }
                 A::enable_interrupts();
             }
         } else {
@@ -296,43 +1096,123 @@ impl<A: Arch, S: Scheduler> Kernel<A, S> {
         }
     }
 
-    /// Start the first thread (bootstrap the scheduler).
+    /// Yield directly to `target`, donating the rest of the time slice.
     ///
-    /// This picks the first thread from the scheduler and starts running it.
-    /// Called once during kernel initialization.
+    /// The target runs next, in place of whatever the scheduler would have
+    /// picked, and its slice ends when the caller's would have. Useful for
+    /// handing a request to a server thread and getting the reply back
+    /// with minimal latency.
     ///
-    /// Note: This function handles interrupt enabling internally - do NOT enable
-    /// interrupts before calling this function.
+    /// Returns `false` without yielding if `target` isn't ready to run (or
+    /// the scheduler can't pick a specific thread), and `true` once the
+    /// caller has been scheduled again.
     #[inline(never)]
-    pub fn start_first_thread(&self) {
+    pub fn yield_to(&self, target: ThreadId) -> bool {
         if !self.is_initialized() {
-            return;
+            return false;
         }
 
+        let was_enabled = A::interrupts_enabled();
         A::disable_interrupts();
-
         let mut current_guard = self.current_thread.lock();
 
-        if current_guard.is_some() {
-            A::enable_interrupts();
-            return;
+        let next = match current_guard.as_ref() {
+            Some(current) if current.id() != target => self.scheduler.pick_specific(target),
+            _ => None,
+        };
+        let Some(next) = next else {
+            drop(current_guard);
+            if was_enabled {
+                A::enable_interrupts();
+            }
+            return false;
+        };
+        // Only picked with a thread running
+        let current = current_guard
+            .take()
+            .expect("yield_to without a current thread");
+
+        if let Some(overflow) = Self::overflowed(&current, crate::arch::stack_pointer()) {
+            self.scheduler.enqueue(next);
+            self.exit_overflowed(current_guard, current, overflow);
         }
 
+        platform_timer::clear_preemption_pending();
+        let slice_start = current.quantum().slice_start();
+        let prev_thread = current.id();
+        let prev_ctx = current.0.context_ptr();
+        Self::save_fpu(&current.0);
+        self.scheduler.enqueue(current.stop_running());
+
+        let next_ctx = next.0.context_ptr();
+        self.install_next(Some(prev_thread), next, &mut current_guard);
+        if let Some(running) = current_guard.as_ref() {
+            running.quantum().start_slice(slice_start);
+        }
+        drop(current_guard);
+
+        if !prev_ctx.is_null() && !next_ctx.is_null() {
+            unsafe {
+                A::context_switch(
+                    prev_ctx as *mut A::SavedContext,
+                    next_ctx as *const A::SavedContext,
+                );
+            }
+        }
+        A::enable_interrupts();
+        true
+    }
+
+    /// Start the first thread (bootstrap the scheduler).
+    ///
+    /// This picks the first thread from the scheduler and starts running it.
+    /// Called once during kernel initialization; on success it only returns
+    /// on targets that can't switch stacks.
+    ///
+    /// Note: This function handles interrupt enabling internally - do NOT enable
+    /// interrupts before calling this function.
+    ///
+    /// # Errors
+    ///
+    /// - [`KernelError::NotInitialized`] before [`init`](Self::init).
+    /// - [`KernelError::AlreadyStarted`] or [`KernelError::ShuttingDown`]
+    ///   if called again, e.g. from a thread or an interrupt handler.
+    /// - [`KernelError::NoRunnableThread`] if nothing was spawned; the
+    ///   kernel stays `NotStarted`, so spawn and call again.
+    #[inline(never)]
+    pub fn start_first_thread(&self) -> Result<(), KernelError> {
+        if !self.is_initialized() {
+            return Err(KernelError::NotInitialized);
+        }
+        self.run_state
+            .compare_exchange(
+                RunState::NotStarted as u8,
+                RunState::Running as u8,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .map_err(|state| match RunState::from_u8(state) {
+                RunState::ShuttingDown => KernelError::ShuttingDown,
+                _ => KernelError::AlreadyStarted,
+            })?;
+
+        let was_enabled = A::interrupts_enabled();
+        A::disable_interrupts();
+
+        let mut current_guard = self.current_thread.lock();
+
         if let Some(next) = self.scheduler.pick_next(0) {
             let next_ctx = next.0.context_ptr();
 
-            let running = next.start_running();
-            *current_guard = Some(running);
+            self.install_next(None, next, &mut current_guard);
             drop(current_guard);
+            events::publish(events::KernelEvent::CpuOnline(crate::arch::cpu_id()));
 
             #[cfg(target_arch = "aarch64")]
             unsafe {
-                crate::arch::aarch64::set_current_irq_context(
-                    next_ctx
-                );
+                crate::arch::aarch64::set_current_irq_context(next_ctx);
             }
 
-
             if !next_ctx.is_null() {
                 unsafe {
                     let mut dummy_ctx = A::SavedContext::default();
@@ -342,9 +1222,177 @@ impl<A: Arch, S: Scheduler> Kernel<A, S> {
                     );
                 }
             }
+            Ok(())
+        } else {
+            drop(current_guard);
+            self.run_state
+                .store(RunState::NotStarted as u8, Ordering::Release);
+            if was_enabled {
+                A::enable_interrupts();
+            }
+            Err(KernelError::NoRunnableThread)
+        }
+    }
+
+    /// Run ready threads for at most `budget`, then return.
+    ///
+    /// For firmware that keeps its own main loop instead of calling
+    /// [`start_first_thread`](Self::start_first_thread); see
+    /// [`embed`](self::embed). The first call starts the kernel in this
+    /// mode. Threads that are mid-slice when the budget runs out carry on
+    /// from where they were on the next call.
+    ///
+    /// # Errors
+    ///
+    /// - [`KernelError::NotInitialized`] before [`init`](Self::init).
+    /// - [`KernelError::AlreadyStarted`] if the kernel was started with
+    ///   `start_first_thread`, or when called from a thread.
+    /// - [`KernelError::ShuttingDown`] after
+    ///   [`begin_shutdown`](Self::begin_shutdown).
+    #[inline(never)]
+    pub fn poll(&self, budget: Duration) -> Result<PollStatus, KernelError> {
+        if !self.is_initialized() {
+            return Err(KernelError::NotInitialized);
+        }
+        let started = self.run_state.compare_exchange(
+            RunState::NotStarted as u8,
+            RunState::Running as u8,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+        match started {
+            Ok(_) => {
+                self.embedded.store(true, Ordering::Release);
+                events::publish(events::KernelEvent::CpuOnline(crate::arch::cpu_id()));
+            }
+            Err(state) if RunState::from_u8(state) == RunState::ShuttingDown => {
+                return Err(KernelError::ShuttingDown);
+            }
+            Err(_) if !self.embedded.load(Ordering::Acquire) => {
+                return Err(KernelError::AlreadyStarted)
+            }
+            Err(_) => {}
+        }
+
+        let was_enabled = A::interrupts_enabled();
+        A::disable_interrupts();
+        let mut current_guard = self.current_thread.lock();
+
+        let next = if current_guard.is_some() {
+            None
         } else {
+            self.scheduler.pick_next(0)
+        };
+        let Some(next) = next else {
+            let called_from_thread = current_guard.is_some();
+            drop(current_guard);
+            if was_enabled {
+                A::enable_interrupts();
+            }
+            return if called_from_thread {
+                Err(KernelError::AlreadyStarted)
+            } else {
+                Ok(PollStatus::Idle)
+            };
+        };
+
+        let deadline = Instant::now()
+            .as_nanos()
+            .saturating_add(budget.as_nanos())
+            .max(1);
+        self.poll_deadline.store(deadline, Ordering::Release);
+        self.poll_status
+            .store(PollStatus::Idle as u8, Ordering::Release);
+
+        let next_ctx = next.0.context_ptr();
+        self.install_next(None, next, &mut current_guard);
+        drop(current_guard);
+
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            crate::arch::aarch64::set_current_irq_context(next_ctx);
+        }
+
+        if !next_ctx.is_null() {
+            unsafe {
+                A::context_switch(
+                    self.host_context.get().cast(),
+                    next_ctx as *const A::SavedContext,
+                );
+            }
+        }
+
+        // Resumed by `return_to_host`, or straight through on targets
+        // that can't switch stacks
+        self.poll_deadline.store(0, Ordering::Release);
+        let mut current_guard = self.current_thread.lock();
+        if let Some(current) = current_guard.take() {
+            // Never left; ready again for the next call
+            self.scheduler.enqueue(current.stop_running());
+            self.poll_status
+                .store(PollStatus::BudgetExhausted as u8, Ordering::Release);
+        }
+        drop(current_guard);
+        if was_enabled {
             A::enable_interrupts();
         }
+        Ok(PollStatus::from_u8(
+            self.poll_status.load(Ordering::Acquire),
+        ))
+    }
+
+    /// Whether threads are running inside [`poll`](Self::poll).
+    fn polling(&self) -> bool {
+        self.poll_deadline.load(Ordering::Acquire) != 0
+    }
+
+    /// Whether a running [`poll`](Self::poll) has spent its budget.
+    pub(crate) fn poll_expired(&self) -> bool {
+        match self.poll_deadline.load(Ordering::Acquire) {
+            0 => false,
+            deadline => Instant::now().as_nanos() >= deadline,
+        }
+    }
+
+    /// Switch from the thread whose context is `prev_ctx` back to the
+    /// caller of [`poll`](Self::poll), which returns `status`.
+    ///
+    /// The thread has already been queued, blocked or retired and the slot
+    /// emptied. Returns when the thread is dispatched again.
+    fn return_to_host(
+        &self,
+        prev_ctx: *mut <crate::arch::DefaultArch as Arch>::SavedContext,
+        status: PollStatus,
+        current_guard: spin::MutexGuard<'_, Option<RunningRef>>,
+    ) {
+        self.poll_status.store(status as u8, Ordering::Release);
+        drop(current_guard);
+
+        // The caller's code isn't a thread the tick could switch away from
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            crate::arch::aarch64::set_current_irq_context(core::ptr::null_mut());
+        }
+
+        if !prev_ctx.is_null() {
+            unsafe {
+                A::context_switch(
+                    prev_ctx as *mut A::SavedContext,
+                    self.host_context.get().cast(),
+                );
+            }
+        }
+    }
+
+    /// Wake the threads [sleeping](sleep) past their deadline.
+    ///
+    /// Called from interrupt context, by the tick and by sleep timers, so
+    /// a sleeper the scheduler can't queue without allocating stays asleep
+    /// until the next call.
+    pub fn wake_sleepers(&self) {
+        if self.is_initialized() {
+            sleep::wake_expired(Instant::now(), |thread| self.wake_sleeper(thread));
+        }
     }
 
     /// Handle preemption from an IRQ context.
@@ -352,7 +1400,8 @@ impl<A: Arch, S: Scheduler> Kernel<A, S> {
     /// This method is called from the timer interrupt handler. Instead of doing
     /// a context_switch (which doesn't work from interrupt context), it updates
     /// the IRQ_LOAD_CTX pointer so that the IRQ handler's return sequence
-    /// restores the new thread's context.
+    /// restores the new thread's context. Threads [sleeping](sleep) past
+    /// their deadline are woken first.
     ///
     /// # Safety
     ///
@@ -360,6 +1409,37 @@ impl<A: Arch, S: Scheduler> Kernel<A, S> {
     /// The IRQ handler must have saved the current context to IRQ_SAVE_CTX.
     #[cfg(target_arch = "aarch64")]
     pub fn handle_irq_preemption(&self) {
+        self.wake_sleepers();
+        // This pass answers any handoff too, including one to a sleeper
+        // just woken
+        let target = crate::irq::take_handoff();
+        self.switch_in_irq(target);
+    }
+
+    /// Switch to the thread an interrupt handler woke, if it outranks the
+    /// one it interrupted.
+    ///
+    /// Called by the IRQ vector after the handler returns. Without this a
+    /// thread woken by a device interrupt would wait for the reschedule
+    /// interrupt its wake-up raises to be taken; here the IRQ return
+    /// sequence restores it directly. Preemption mode and
+    /// [`preempt_disable`](crate::preempt_disable) are honoured as for a
+    /// tick.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`handle_irq_preemption`](Self::handle_irq_preemption).
+    #[cfg(target_arch = "aarch64")]
+    pub fn handle_irq_handoff(&self) {
+        if let Some(target) = crate::irq::take_handoff() {
+            self.switch_in_irq(Some(target));
+        }
+    }
+
+    /// Queue the interrupted thread and point the IRQ return at the next
+    /// one: `target` if it is still queued, else the scheduler's choice.
+    #[cfg(target_arch = "aarch64")]
+    fn switch_in_irq(&self, target: Option<ThreadId>) {
         if !self.is_initialized() {
             return;
         }
@@ -369,35 +1449,77 @@ impl<A: Arch, S: Scheduler> Kernel<A, S> {
             None => return,
         };
 
+        // Checked on every tick, whatever the preemption mode
+        let irq_ctx = crate::arch::aarch64::get_irq_save_context();
+        let overflow = current_guard
+            .as_ref()
+            .filter(|_| !irq_ctx.is_null())
+            .and_then(|current| Self::overflowed(current, unsafe { (*irq_ctx).sp } as usize));
+        if let Some(overflow) = overflow {
+            let current = current_guard
+                .take()
+                .expect("overflow found on the running thread");
+            self.replace_overflowed(current, overflow, &mut current_guard);
+            let next_ctx = current_guard
+                .as_ref()
+                .map_or(core::ptr::null_mut(), |next| next.0.context_ptr());
+            drop(current_guard);
+            crate::arch::aarch64::set_irq_load_context(next_ctx);
+            unsafe { crate::arch::aarch64::set_current_irq_context(next_ctx) };
+            return;
+        }
+
+        match platform_timer::preemption_mode() {
+            PreemptionMode::Full if platform_timer::preemption_disabled() => {
+                // Taken by preempt_enable
+                platform_timer::request_preemption();
+                return;
+            }
+            PreemptionMode::Full => {}
+            PreemptionMode::Voluntary => {
+                // Switch at the thread's next preemption point instead
+                platform_timer::request_preemption();
+                return;
+            }
+            PreemptionMode::None => return,
+        }
+
         if let Some(ref _current) = *current_guard {
             let should_switch = true;
 
             if should_switch {
                 if let Some(current) = current_guard.take() {
-
-
-                    let old_id = current.id().get();
+                    let old_id = current.id();
+                    Self::save_fpu(&current.0);
+                    // This switch answers any deferred request
+                    platform_timer::clear_preemption_pending();
 
                     let ready = current.stop_running();
                     self.scheduler.enqueue(ready);
 
-                    if let Some(next) = self.scheduler.pick_next(0) {
+                    if self.poll_expired() {
+                        // Back to the caller of `poll` on the IRQ return
+                        self.poll_status
+                            .store(PollStatus::BudgetExhausted as u8, Ordering::Release);
+                        drop(current_guard);
+                        unsafe {
+                            crate::arch::aarch64::set_current_irq_context(core::ptr::null_mut())
+                        };
+                        crate::arch::aarch64::set_irq_load_context(self.host_context.get().cast());
+                        return;
+                    }
+
+                    if let Some(next) = self.next_after_irq(target) {
                         let next_ctx = next.0.context_ptr();
-                        let _old_id = old_id; // Suppress unused warning
-                        let _new_id = next.id().get();
 
-                        let running = next.start_running();
-                        *current_guard = Some(running);
+                        self.install_next(Some(old_id), next, &mut current_guard);
                         drop(current_guard);
 
                         if !next_ctx.is_null() {
-                            crate::arch::aarch64::set_irq_load_context(
-                                next_ctx
-                            );
+                            switch_latency::switching(SwitchPath::Irq);
+                            crate::arch::aarch64::set_irq_load_context(next_ctx);
                             unsafe {
-                                crate::arch::aarch64::set_current_irq_context(
-                                    next_ctx
-                                );
+                                crate::arch::aarch64::set_current_irq_context(next_ctx);
                             }
                         }
                     } else {
@@ -410,6 +1532,334 @@ impl<A: Arch, S: Scheduler> Kernel<A, S> {
         }
     }
 
+    /// Sample the running thread's stack pointer and check its red zone.
+    fn overflowed(current: &RunningRef, sp: usize) -> Option<StackOverflow> {
+        current.0.record_sp(sp);
+        current.0.check_stack().err()
+    }
+
+    /// Kill a thread whose stack overflowed and install the thread to run
+    /// instead in `slot`.
+    ///
+    /// Panics if nothing else is runnable, since the overflowed thread can't
+    /// safely continue.
+    fn replace_overflowed(
+        &self,
+        current: RunningRef,
+        overflow: StackOverflow,
+        slot: &mut Option<RunningRef>,
+    ) {
+        crate::klog!(log::Level::Error, "{}", overflow);
+        let from = current.id();
+        current.kill();
+        self.exited(from, false);
+        match self.scheduler.pick_next(0) {
+            Some(next) => self.install_next(Some(from), next, slot),
+            None => panic!("{}; no other thread to run", overflow),
+        }
+    }
+
+    /// Make `next` the running thread in `slot`, calling the switch hooks
+    /// around it.
+    ///
+    /// Hooks are skipped when `next` is the thread already running, since
+    /// no switch happens.
+    fn install_next(&self, from: Option<ThreadId>, next: ReadyRef, slot: &mut Option<RunningRef>) {
+        let to = next.id();
+        // Its context was saved before it was published as ready
+        ordering::check(Edge::ContextPublish, next.0.state() == ThreadState::Ready);
+        let switching = from != Some(to);
+        if switching {
+            Self::call_switch_hook(&self.pre_switch, from, to);
+            slo::observe(to, next.priority(), next.0.state_since());
+        }
+        #[cfg(feature = "context-check")]
+        if switching {
+            if let Err(corrupt) = next.0.check_context() {
+                panic!("{}", corrupt);
+            }
+        }
+        if switching {
+            // Last before the switch: from here on FPU use by the outgoing
+            // thread's path would clobber the incoming thread's registers
+            Self::load_fpu(&next.0);
+        }
+        *slot = Some(next.start_running());
+        crate::thread::set_current_thread_id(to);
+        if switching {
+            Self::call_switch_hook(&self.post_switch, from, to);
+        }
+    }
+
+    /// Save the FPU registers of `thread`, which is being switched away
+    /// from, unless it doesn't use the FPU.
+    fn save_fpu(thread: &Thread) {
+        #[cfg(feature = "full-fpu")]
+        if thread.uses_fpu() {
+            // The thread's context stays put while it is switched out
+            unsafe { A::save_fpu(&mut *(thread.context_ptr() as *mut A::SavedContext)) };
+        }
+        #[cfg(not(feature = "full-fpu"))]
+        let _ = thread;
+    }
+
+    /// Give `thread`, about to be switched to, its FPU registers back, or
+    /// make the FPU trap if it promised not to use it.
+    fn load_fpu(thread: &Thread) {
+        if !thread.uses_fpu() {
+            A::set_fpu_trap(true);
+            return;
+        }
+        A::set_fpu_trap(false);
+        #[cfg(feature = "full-fpu")]
+        unsafe {
+            A::restore_fpu(&*(thread.context_ptr() as *const A::SavedContext))
+        };
+    }
+
+    fn call_switch_hook(hook: &AtomicUsize, from: Option<ThreadId>, to: ThreadId) {
+        let hook = hook.load(Ordering::Acquire);
+        if hook != 0 {
+            // Only ever stored from a SwitchHook
+            let hook: SwitchHook = unsafe { core::mem::transmute(hook) };
+            hook(from, to);
+        }
+    }
+
+    /// Call `pre` and `post` around every context switch from now on.
+    ///
+    /// `pre` runs once the scheduler has chosen the next thread, while the
+    /// outgoing one is still current; `post` runs once the incoming thread
+    /// is current, just before its registers are loaded. Both run in the
+    /// switching path with interrupts disabled and scheduler state locked,
+    /// often in interrupt context, so they must be short and must not
+    /// allocate, block, yield or spawn.
+    ///
+    /// Useful for tracing, switching per-thread hardware state such as a
+    /// debug GPIO line, or re-programming watchpoints.
+    pub fn set_switch_hooks(&self, pre: SwitchHook, post: SwitchHook) {
+        self.pre_switch.store(pre as usize, Ordering::Release);
+        self.post_switch.store(post as usize, Ordering::Release);
+    }
+
+    /// Stop calling the switch hooks.
+    pub fn clear_switch_hooks(&self) {
+        self.pre_switch.store(0, Ordering::Release);
+        self.post_switch.store(0, Ordering::Release);
+    }
+
+    /// Switch away from a thread whose stack overflowed, for good.
+    fn exit_overflowed(
+        &self,
+        mut current_guard: spin::MutexGuard<'_, Option<RunningRef>>,
+        current: RunningRef,
+        overflow: StackOverflow,
+    ) -> ! {
+        let prev_ctx = current.0.context_ptr();
+        self.replace_overflowed(current, overflow, &mut current_guard);
+        let next_ctx = current_guard
+            .as_ref()
+            .map_or(core::ptr::null_mut(), |next| next.0.context_ptr());
+        drop(current_guard);
+
+        unsafe {
+            A::context_switch(
+                prev_ctx as *mut A::SavedContext,
+                next_ctx as *const A::SavedContext,
+            );
+        }
+        unreachable!("killed thread was scheduled again");
+    }
+
+    /// Get the currently running thread, if any.
+    ///
+    /// Safe to call from interrupt handlers.
+    pub fn current_thread(&self) -> Option<Thread> {
+        let was_enabled = A::interrupts_enabled();
+        A::disable_interrupts();
+        let current = self
+            .current_thread
+            .lock()
+            .as_ref()
+            .map(|running| running.0.clone());
+        if was_enabled {
+            A::enable_interrupts();
+        }
+        current
+    }
+
+    /// Block the current thread and switch to the next runnable one.
+    ///
+    /// The caller must already have recorded the current thread somewhere it
+    /// will be woken from (see [`crate::sync::WaitQueue`]), and must call this
+    /// with interrupts disabled so a wake-up cannot slip in between. Returns
+    /// once the thread has been woken and scheduled again, with interrupts
+    /// still disabled.
+    ///
+    /// If no other thread is runnable the CPU idles until an interrupt wakes
+    /// the blocked thread.
+    #[inline(never)]
+    pub fn block_current(&self) {
+        if !self.is_initialized() {
+            return;
+        }
+
+        A::disable_interrupts();
+
+        let mut current_guard = self.current_thread.lock();
+
+        let Some(current) = current_guard.take() else {
+            return;
+        };
+        if let Some(overflow) = Self::overflowed(&current, crate::arch::stack_pointer()) {
+            self.exit_overflowed(current_guard, current, overflow);
+        }
+
+        let blocked = current.0.clone();
+        let prev_ctx = blocked.context_ptr();
+        Self::save_fpu(&blocked);
+        current.block();
+        if blocked.suspend_requested() {
+            blocked.set_state(ThreadState::Suspended);
+        }
+
+        loop {
+            if self.poll_expired() {
+                self.return_to_host(prev_ctx, PollStatus::BudgetExhausted, current_guard);
+                return;
+            }
+
+            if let Some(next) = self.scheduler.pick_next(0) {
+                if next.id() == blocked.id() {
+                    // Woken before anything else ran - keep going
+                    self.install_next(Some(blocked.id()), next, &mut current_guard);
+                    return;
+                }
+
+                let next_ctx = next.0.context_ptr();
+                self.install_next(Some(blocked.id()), next, &mut current_guard);
+                drop(current_guard);
+
+                if !prev_ctx.is_null() && !next_ctx.is_null() {
+                    unsafe {
+                        A::context_switch(
+                            prev_ctx as *mut A::SavedContext,
+                            next_ctx as *const A::SavedContext,
+                        );
+                    }
+                }
+                return;
+            }
+
+            if self.polling() {
+                // Idle in the caller's loop, not here
+                self.return_to_host(prev_ctx, PollStatus::Idle, current_guard);
+                return;
+            }
+
+            drop(current_guard);
+            let idle_start = crate::time::Instant::now();
+            A::enable_interrupts();
+            A::wait_for_interrupt();
+            A::disable_interrupts();
+            metrics::record_idle(0, idle_start, crate::time::Instant::now());
+            current_guard = self.current_thread.lock();
+        }
+    }
+
+    /// Make a blocked thread runnable again.
+    ///
+    /// Threads that are not blocked are left untouched, so redundant wake-ups
+    /// are harmless. Safe to call from interrupt context.
+    pub fn wake(&self, thread: Thread) {
+        self.try_wake(thread);
+    }
+
+    fn try_wake(&self, thread: Thread) -> bool {
+        let woken = self.wake_with(thread, |ready| {
+            self.scheduler.wake_up(ready);
+            Ok(())
+        });
+        woken.unwrap_or(false)
+    }
+
+    /// Wake a sleeper whose deadline has passed, from interrupt context.
+    ///
+    /// Queued through [`try_wake_up`](Scheduler::try_wake_up), since
+    /// interrupt handlers must not allocate. If the scheduler can't take
+    /// the thread that way it is handed back, still blocked, for a later
+    /// call.
+    fn wake_sleeper(&self, thread: Thread) -> Result<(), Thread> {
+        self.wake_with(thread, |ready| self.scheduler.try_wake_up(ready))
+            .map(drop)
+    }
+
+    /// Make `thread` ready if it is blocked, queueing it with `queue`.
+    ///
+    /// Returns whether it was blocked. If `queue` hands the thread back it
+    /// is left blocked and returned.
+    fn wake_with(
+        &self,
+        thread: Thread,
+        queue: impl FnOnce(ReadyRef) -> Result<(), ReadyRef>,
+    ) -> Result<bool, Thread> {
+        crate::arch::without_interrupts(|| {
+            if thread.suspend_requested() {
+                // Ready once resumed
+                return Ok(
+                    thread.compare_and_set_state(ThreadState::Blocked, ThreadState::Suspended)
+                );
+            }
+            if !thread.compare_and_set_state(ThreadState::Blocked, ThreadState::Ready) {
+                return Ok(false);
+            }
+            let (id, priority) = (thread.id(), thread.priority());
+            if let Err(ReadyRef(thread)) = queue(ReadyRef(thread)) {
+                thread.set_state(ThreadState::Blocked);
+                return Err(thread);
+            }
+            // The waker may hold locks or be an interrupt handler, so
+            // don't switch here. A handler's IRQ exit switches straight
+            // to the thread; anyone else raises a reschedule.
+            if self.outranks_current(priority) && !Self::hand_off_from_irq(id, priority) {
+                platform_timer::request_reschedule();
+            }
+            Ok(true)
+        })
+    }
+
+    /// The thread the IRQ exit switches to: `target` if it is still
+    /// queued, taken from where it sits, else the scheduler's choice.
+    #[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+    fn next_after_irq(&self, target: Option<ThreadId>) -> Option<ReadyRef> {
+        target
+            .and_then(|target| self.scheduler.pick_specific(target))
+            .or_else(|| self.scheduler.pick_next(0))
+    }
+
+    /// Leave `id` for the IRQ exit to switch to, if called from an
+    /// interrupt handler on a target whose vector takes it.
+    fn hand_off_from_irq(id: ThreadId, priority: u8) -> bool {
+        #[cfg(target_arch = "aarch64")]
+        return crate::irq::hand_off_to(id, priority);
+
+        #[cfg(not(target_arch = "aarch64"))]
+        {
+            let _ = (id, priority);
+            false
+        }
+    }
+
+    /// Whether a thread of `priority` should take the CPU from the running
+    /// one. `false` while the running thread can't be looked at.
+    fn outranks_current(&self, priority: u8) -> bool {
+        self.current_thread.try_lock().is_some_and(|current| {
+            current
+                .as_ref()
+                .is_some_and(|current| priority > current.priority())
+        })
+    }
+
     pub fn thread_stats(&self) -> (usize, usize, usize) {
         self.scheduler.stats()
     }
@@ -419,22 +1869,71 @@ impl<A: Arch, S: Scheduler> Kernel<A, S> {
     /// TODO:  try to find another way
     pub unsafe fn register_global(&'static self) {
         GLOBAL_KERNEL.store(self as *const _ as *mut (), Ordering::Release);
+
+        // The boxed trait object is intentionally leaked: registration happens
+        // once at boot and the kernel lives forever.
+        let ops: &'static dyn KernelOps = self;
+        GLOBAL_OPS.store(Box::into_raw(Box::new(ops)), Ordering::Release);
     }
 }
 
+impl<A: Arch, S: Scheduler, C: KernelConfig> KernelOps for Kernel<A, S, C> {
+    fn yield_now(&self) {
+        Kernel::yield_now(self);
+    }
+
+    fn finish_and_yield(&self) {
+        Kernel::finish_and_yield(self);
+    }
 
+    fn kill_current(&self) {
+        Kernel::kill_current(self);
+    }
 
-unsafe impl<A: Arch, S: Scheduler> Send for Kernel<A, S> {}
-unsafe impl<A: Arch, S: Scheduler> Sync for Kernel<A, S> {}
+    fn block_current(&self) {
+        Kernel::block_current(self);
+    }
+
+    fn wake(&self, thread: Thread) {
+        Kernel::wake(self, thread);
+    }
+
+    fn current_thread(&self) -> Option<Thread> {
+        Kernel::current_thread(self)
+    }
+
+    fn thread(&self, id: ThreadId) -> Option<Thread> {
+        Kernel::thread(self, id)
+    }
+
+    fn yield_to(&self, target: ThreadId) -> bool {
+        Kernel::yield_to(self, target)
+    }
+
+    fn wake_sleepers(&self) {
+        Kernel::wake_sleepers(self);
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn handle_irq_preemption(&self) {
+        Kernel::handle_irq_preemption(self);
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn handle_irq_handoff(&self) {
+        Kernel::handle_irq_handoff(self);
+    }
+}
+
+unsafe impl<A: Arch, S: Scheduler, C: KernelConfig> Send for Kernel<A, S, C> {}
+unsafe impl<A: Arch, S: Scheduler, C: KernelConfig> Sync for Kernel<A, S, C> {}
 
 /// Get the global kernel reference (for interrupt handlers).
 ///
-/// Returns None if no kernel has been registered.
+/// Returns None if no kernel has been registered. Only valid for kernels
+/// using [`DefaultConfig`]; prefer [`global_ops`], which works for any.
 pub fn get_global_kernel<A: Arch, S: Scheduler>() -> Option<&'static Kernel<A, S>> {
     let ptr = GLOBAL_KERNEL.load(Ordering::Acquire);
-    {
-        crate::pl011_println!(r#"{{"id":"log_get_global_kernel","timestamp":0,"location":"kernel.rs:433","message":"get_global_kernel called","data":{{"ptr_is_null":{}}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,C"}}"#, ptr.is_null());
-    }
     if ptr.is_null() {
         None
     } else {
@@ -442,56 +1941,462 @@ pub fn get_global_kernel<A: Arch, S: Scheduler>() -> Option<&'static Kernel<A, S
     }
 }
 
+/// Get the registered kernel as a type-erased [`KernelOps`].
+///
+/// Returns None if no kernel has been registered.
+pub fn global_ops() -> Option<&'static dyn KernelOps> {
+    let ptr = GLOBAL_OPS.load(Ordering::Acquire);
+    if ptr.is_null() {
+        None
+    } else {
+        Some(unsafe { *ptr })
+    }
+}
+
 /// Yield the current thread (convenience function).
 ///
 /// This uses the global kernel if registered, otherwise does nothing.
 pub fn yield_current() {
-    use crate::arch::DefaultArch;
-    use crate::sched::RoundRobinScheduler;
-
-    if let Some(kernel) = get_global_kernel::<DefaultArch, RoundRobinScheduler>() {
+    if let Some(kernel) = global_ops() {
         kernel.yield_now();
     }
 }
 
-/
-    use crate::arch::DefaultArch;
+/// Finish the current thread (convenience function).
+///
+/// This uses the global kernel if registered, otherwise does nothing.
+pub fn finish_current() {
+    if let Some(kernel) = global_ops() {
+        kernel.finish_and_yield();
+    }
+}
+
+/// Called on a trapped FPU access with interrupts masked.
+///
+/// Lifts the trap so the access can go ahead, and returns `true` if the
+/// running thread was spawned [`no_fpu`](crate::ThreadBuilder::no_fpu), in
+/// which case the caller must send it to [`exit_fpu_violation`] instead.
+/// Interrupt handlers interrupting such a thread may use the FPU; the
+/// trap stays lifted for the thread until its next switch.
+#[cfg(target_arch = "aarch64")]
+pub(crate) fn fpu_access_trapped() -> bool {
+    use crate::arch::{Arch, DefaultArch};
+
+    DefaultArch::set_fpu_trap(false);
+    if crate::irq::in_irq() {
+        return false;
+    }
+    let Some(thread) = global_ops().and_then(|kernel| kernel.current_thread()) else {
+        return false;
+    };
+    if thread.uses_fpu() {
+        return false;
+    }
+    crate::klog!(
+        log::Level::Error,
+        "thread {}: {}",
+        thread.id(),
+        crate::errors::ArchError::FpuError
+    );
+    true
+}
+
+/// Where a `no_fpu` thread caught using the FPU resumes: terminated, so
+/// joiners see it fail.
+#[cfg(target_arch = "aarch64")]
+pub(crate) extern "C" fn exit_fpu_violation() -> ! {
+    if let Some(kernel) = global_ops() {
+        kernel.kill_current();
+    }
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(all(test, feature = "std-shim"))]
+mod tests {
+    use super::*;
+    use crate::arch::NoOpArch;
     use crate::sched::RoundRobinScheduler;
-    use crate::sched::FirstComeFirstServeScheduler;
 
-    {
-        crate::pl011_println!(r#"{{"id":"log_finish_current_entry","timestamp":0,"location":"kernel.rs:458","message":"finish_current called","data":{{}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,C"}}"#);
+    #[test]
+    fn test_staged_init() {
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        assert!(matches!(
+            kernel.spawn(|| {}, 128),
+            Err(SpawnError::NotInitialized)
+        ));
+
+        kernel.init_memory().unwrap();
+        kernel.init_timer().unwrap();
+        kernel.init_scheduler().unwrap();
+        assert!(kernel.spawn(|| {}, 128).is_ok());
+
+        assert_eq!(
+            kernel.init_scheduler(),
+            Err(KernelError::AlreadyInitialized)
+        );
+        assert_eq!(kernel.init(), Err(KernelError::AlreadyInitialized));
     }
 
-    if let Some(kernel) = get_global_kernel::<DefaultArch, FirstComeFirstServeScheduler>() {
-        {
-            crate::pl011_println!(r#"{{"id":"log_finish_current_found_fcfs","timestamp":0,"location":"kernel.rs:475","message":"Found FirstComeFirstServeScheduler kernel","data":{{}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,C"}}"#);
-        }
-        {
-            crate::pl011_println!(r#"{{"id":"log_finish_current_calling_finish","timestamp":0,"location":"kernel.rs:481","message":"About to call finish_and_yield","data":{{}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,C"}}"#);
-        }
-        kernel.finish_and_yield();
-        {
-            crate::pl011_println!(r#"{{"id":"log_finish_current_after_call","timestamp":0,"location":"kernel.rs:483","message":"Returned from finish_and_yield","data":{{}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,C"}}"#);
+    #[test]
+    fn test_switch_hooks_see_every_switch() {
+        use portable_atomic::AtomicU64;
+
+        // Each switch packed as from << 32 | to, oldest in the high half
+        static PRE: AtomicU64 = AtomicU64::new(0);
+        static POST: AtomicU64 = AtomicU64::new(0);
+        fn record(log: &AtomicU64, from: Option<ThreadId>, to: ThreadId) {
+            let entry = (from.map_or(0, |id| id.get() as u64) << 8) | to.get() as u64;
+            log.store(
+                (log.load(Ordering::Relaxed) << 16) | entry,
+                Ordering::Relaxed,
+            );
         }
-        return;
+
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        kernel.init().unwrap();
+        kernel.set_switch_hooks(
+            |from, to| record(&PRE, from, to),
+            |from, to| record(&POST, from, to),
+        );
+        let first = kernel.spawn(|| {}, 128).unwrap().thread().id().get() as u64;
+        let second = kernel.spawn(|| {}, 128).unwrap().thread().id().get() as u64;
+
+        kernel.start_first_thread().unwrap();
+        kernel.yield_now();
+        let expected = (first << 16) | (first << 8) | second;
+        assert_eq!(PRE.load(Ordering::Relaxed), expected);
+        assert_eq!(POST.load(Ordering::Relaxed), expected);
+
+        kernel.clear_switch_hooks();
+        kernel.yield_now();
+        assert_eq!(PRE.load(Ordering::Relaxed), expected);
     }
-    
-    if let Some(kernel) = get_global_kernel::<DefaultArch, RoundRobinScheduler>() {
-        {
-            crate::pl011_println!(r#"{{"id":"log_finish_current_found_rr","timestamp":0,"location":"kernel.rs:490","message":"Found RoundRobinScheduler kernel","data":{{}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,C"}}"#);
-        }
-        {
-            crate::pl011_println!(r#"{{"id":"log_finish_current_calling_finish","timestamp":0,"location":"kernel.rs:496","message":"About to call finish_and_yield","data":{{}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,C"}}"#);
-        }
-        kernel.finish_and_yield();
-        {
-            crate::pl011_println!(r#"{{"id":"log_finish_current_after_call","timestamp":0,"location":"kernel.rs:500","message":"Returned from finish_and_yield","data":{{}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,C"}}"#);
+
+    #[test]
+    fn test_run_state_transitions() {
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        assert_eq!(
+            kernel.start_first_thread(),
+            Err(KernelError::NotInitialized)
+        );
+        kernel.init().unwrap();
+        assert_eq!(
+            kernel.start_first_thread(),
+            Err(KernelError::NoRunnableThread)
+        );
+        assert_eq!(kernel.run_state(), RunState::NotStarted);
+        assert_eq!(kernel.begin_shutdown(), Err(KernelError::NotInitialized));
+
+        kernel.spawn(|| {}, 128).unwrap();
+        assert_eq!(kernel.start_first_thread(), Ok(()));
+        assert_eq!(kernel.run_state(), RunState::Running);
+        assert_eq!(
+            kernel.start_first_thread(),
+            Err(KernelError::AlreadyStarted)
+        );
+        assert_eq!(
+            kernel.poll(Duration::from_millis(1)),
+            Err(KernelError::AlreadyStarted)
+        );
+
+        assert_eq!(kernel.begin_shutdown(), Ok(()));
+        assert_eq!(kernel.begin_shutdown(), Err(KernelError::ShuttingDown));
+        assert_eq!(kernel.start_first_thread(), Err(KernelError::ShuttingDown));
+    }
+
+    #[test]
+    fn test_shutdown_allows_reinit() {
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        assert_eq!(kernel.shutdown(), Err(KernelError::NotInitialized));
+        kernel.init().unwrap();
+        // No tick on the host
+        assert_eq!(
+            kernel.stop_timer(),
+            Err(KernelError::Timer(TimerError::NotRunning))
+        );
+
+        kernel.spawn(|| {}, 128).unwrap();
+        kernel.start_first_thread().unwrap();
+        assert_eq!(kernel.shutdown(), Err(KernelError::AlreadyStarted));
+        kernel.begin_shutdown().unwrap();
+        assert_eq!(kernel.shutdown(), Ok(()));
+        assert!(!kernel.is_initialized());
+        assert_eq!(kernel.run_state(), RunState::NotStarted);
+
+        kernel.init().unwrap();
+        kernel.spawn(|| {}, 128).unwrap();
+        assert_eq!(kernel.start_first_thread(), Ok(()));
+        assert_eq!(kernel.run_state(), RunState::Running);
+    }
+
+    #[test]
+    fn test_poll_returns_to_caller() {
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        let budget = Duration::from_millis(1);
+        assert_eq!(kernel.poll(budget), Err(KernelError::NotInitialized));
+        kernel.init().unwrap();
+        assert_eq!(kernel.poll(budget), Ok(PollStatus::Idle));
+        assert_eq!(kernel.run_state(), RunState::Running);
+        assert_eq!(
+            kernel.start_first_thread(),
+            Err(KernelError::AlreadyStarted)
+        );
+
+        // No stack switch on the host, so the thread comes straight back
+        let handle = kernel.spawn(|| {}, 128).unwrap();
+        assert_eq!(kernel.poll(budget), Ok(PollStatus::BudgetExhausted));
+        assert_eq!(handle.thread().state(), ThreadState::Ready);
+        assert!(kernel.current_thread().is_none());
+
+        let driver = PollDriver::new(&kernel, budget);
+        assert_eq!(driver.service(), Ok(None));
+        driver.on_timer();
+        assert_eq!(driver.service(), Ok(Some(PollStatus::BudgetExhausted)));
+    }
+
+    #[test]
+    fn test_cooperative_fallback_recorded() {
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        kernel.init_memory().unwrap();
+        kernel.fall_back_to_cooperative(KernelError::TimerInitFailed("no counter frequency"));
+        kernel.init_scheduler().unwrap();
+
+        assert!(!kernel.preemption_active());
+        assert_eq!(
+            kernel.degradation(),
+            Some(KernelError::TimerInitFailed("no counter frequency"))
+        );
+        assert!(kernel.spawn(|| {}, 128).is_ok());
+    }
+
+    #[test]
+    fn test_reserved_priorities_rejected() {
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        kernel.init().unwrap();
+        assert_eq!(kernel.priority_range(), priority::IDLE..=priority::MAX_USER);
+
+        assert!(kernel.spawn(|| {}, priority::REALTIME).is_ok());
+        assert!(kernel.spawn_fn(|| {}, priority::IDLE).is_ok());
+        assert!(matches!(
+            kernel.spawn(|| {}, priority::KERNEL_MIN),
+            Err(SpawnError::InvalidPriority(priority::KERNEL_MIN))
+        ));
+        assert!(matches!(
+            kernel.spawn_fn(|| {}, u8::MAX),
+            Err(SpawnError::InvalidPriority(u8::MAX))
+        ));
+        assert_eq!(kernel.live_threads(), 2);
+    }
+
+    #[test]
+    fn test_lookup_by_id() {
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        kernel.init().unwrap();
+        let ready = kernel.spawn(|| {}, 128).unwrap().thread().id();
+        let blocked = kernel.spawn(|| {}, 128).unwrap().thread();
+        assert!(kernel.thread(ready).is_some());
+
+        assert_eq!(kernel.set_priority(ready, 200), Ok(()));
+        assert_eq!(kernel.thread(ready).unwrap().priority(), 200);
+        assert_eq!(
+            kernel.set_priority(ready, u8::MAX),
+            Err(ScheduleError::PriorityChangeNotAllowed)
+        );
+
+        blocked.set_state(ThreadState::Blocked);
+        assert!(kernel.wake_by_id(blocked.id()));
+        assert!(!kernel.wake_by_id(blocked.id()));
+
+        assert_eq!(kernel.kill(ready), Ok(()));
+        assert!(kernel.thread(ready).is_none());
+        assert_eq!(kernel.kill(ready), Err(ScheduleError::NoSuchThread));
+        // The freed slot is reused under a new generation
+        let reused = kernel.spawn(|| {}, 128).unwrap().thread().id();
+        assert_eq!(reused.slot(), ready.slot());
+        assert_ne!(reused, ready);
+        assert_eq!(kernel.live_threads(), 2);
+    }
+
+    #[test]
+    fn test_scheduler_counts_live_threads() {
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        kernel.init().unwrap();
+        let ready = kernel.spawn(|| {}, 128).unwrap().thread();
+        let blocked = kernel.spawn(|| {}, 128).unwrap().thread();
+        assert_eq!(kernel.scheduler.stats(), (2, 2, 0));
+
+        assert!(kernel.scheduler.pick_specific(blocked.id()).is_some());
+        blocked.set_state(ThreadState::Blocked);
+        assert_eq!(kernel.scheduler.stats(), (2, 1, 1));
+
+        assert_eq!(kernel.kill(blocked.id()), Ok(()));
+        assert_eq!(kernel.scheduler.stats(), (1, 1, 0));
+        assert_eq!(kernel.kill(ready.id()), Ok(()));
+        assert_eq!(kernel.scheduler.stats(), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_irq_handoff_takes_woken_thread_in_place() {
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        kernel.init().unwrap();
+        let ids: alloc::vec::Vec<ThreadId> = (0..4)
+            .map(|_| kernel.spawn(|| {}, 128).unwrap().thread().id())
+            .collect();
+        let woken = kernel.thread(ids[1]).unwrap();
+        assert!(kernel.scheduler.pick_specific(ids[1]).is_some());
+        woken.set_state(ThreadState::Blocked);
+
+        // A device handler wakes it behind the others, and the IRQ exit
+        // takes it from there
+        let irq = crate::irq::IrqGuard::enter();
+        assert!(kernel.wake_by_id(ids[1]));
+        let late = kernel.spawn(|| {}, 128).unwrap().thread().id();
+        assert!(crate::irq::hand_off_to(ids[1], woken.priority()));
+        let next = kernel.next_after_irq(crate::irq::take_handoff());
+        drop(irq);
+        assert_eq!(next.map(|next| next.id()), Some(ids[1]));
+
+        let rest: alloc::vec::Vec<ThreadId> =
+            core::iter::from_fn(|| kernel.scheduler.pick_next(0).map(|ready| ready.id())).collect();
+        assert_eq!(rest, [ids[0], ids[2], ids[3], late]);
+        assert_eq!(
+            kernel.next_after_irq(Some(ids[1])).map(|next| next.id()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_yield_to() {
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        kernel.init().unwrap();
+        let ids: alloc::vec::Vec<ThreadId> = (0..4)
+            .map(|_| kernel.spawn(|| {}, 128).unwrap().thread().id())
+            .collect();
+        kernel.start_first_thread().unwrap();
+        let current = || kernel.current_thread().map(|thread| thread.id());
+        assert_eq!(current(), Some(ids[0]));
+
+        // Neither the caller itself nor a thread that isn't queued can be
+        // yielded to, and the caller keeps the CPU
+        assert!(!kernel.yield_to(ids[0]));
+        assert_eq!(current(), Some(ids[0]));
+        assert_eq!(kernel.suspend(ids[3]), Ok(()));
+        assert!(!kernel.yield_to(ids[3]));
+        assert_eq!(current(), Some(ids[0]));
+
+        // The target jumps the queue; the caller goes to the back
+        assert!(kernel.yield_to(ids[2]));
+        assert_eq!(current(), Some(ids[2]));
+        let rest: alloc::vec::Vec<ThreadId> =
+            core::iter::from_fn(|| kernel.scheduler.pick_next(0).map(|ready| ready.id())).collect();
+        assert_eq!(rest, [ids[1], ids[0]]);
+    }
+
+    #[test]
+    fn test_suspend_and_resume() {
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        kernel.init().unwrap();
+        let ready = kernel.spawn(|| {}, 128).unwrap().thread();
+        let blocked = kernel.spawn(|| {}, 128).unwrap().thread();
+        assert_eq!(
+            kernel.scheduler.pick_specific(blocked.id()).map(|r| r.id()),
+            Some(blocked.id())
+        );
+        blocked.set_state(ThreadState::Blocked);
+
+        assert_eq!(kernel.suspend(ready.id()), Ok(()));
+        assert_eq!(ready.state(), ThreadState::Suspended);
+        assert!(kernel.scheduler.pick_specific(ready.id()).is_none());
+
+        // A wake-up while suspended parks the thread until it is resumed
+        assert_eq!(kernel.suspend(blocked.id()), Ok(()));
+        assert!(kernel.wake_by_id(blocked.id()));
+        assert_eq!(blocked.state(), ThreadState::Suspended);
+
+        assert_eq!(kernel.resume(ready.id()), Ok(()));
+        assert_eq!(kernel.resume(ready.id()), Err(ScheduleError::InvalidState));
+        assert_eq!(kernel.resume(blocked.id()), Ok(()));
+        assert_eq!(
+            kernel.scheduler.pick_next(0).map(|r| r.id()),
+            Some(ready.id())
+        );
+        assert_eq!(
+            kernel.scheduler.pick_next(0).map(|r| r.id()),
+            Some(blocked.id())
+        );
+
+        blocked.set_state(ThreadState::Suspended);
+        assert_eq!(kernel.kill(blocked.id()), Ok(()));
+        assert_eq!(
+            kernel.suspend(blocked.id()),
+            Err(ScheduleError::NoSuchThread)
+        );
+    }
+
+    #[test]
+    fn test_max_threads_enforced() {
+        struct Two;
+        impl KernelConfig for Two {
+            const MAX_THREADS: usize = 2;
         }
-        return;
+
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler, Two> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        kernel.init().unwrap();
+        kernel.spawn(|| {}, 128).unwrap();
+        let config = crate::ThreadBuilder::new().validate().unwrap();
+        assert!(matches!(
+            kernel.spawn_batch(&config, 2, |_| || {}),
+            Err(SpawnError::TooManyThreads)
+        ));
+        kernel.spawn(|| {}, 128).unwrap();
+        assert!(matches!(
+            kernel.spawn(|| {}, 128),
+            Err(SpawnError::TooManyThreads)
+        ));
+        assert_eq!(kernel.live_threads(), 2);
     }
-    
-    {
-        crate::pl011_println!(r#"{{"id":"log_finish_current_not_found","timestamp":0,"location":"kernel.rs:477","message":"Global kernel not found","data":{{}},"sessionId":"debug-session","runId":"post-fix","hypothesisId":"A,C"}}"#);
+
+    #[cfg(feature = "leak-check")]
+    #[test]
+    fn test_resource_counts_follow_threads() {
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        kernel.init().unwrap();
+        let detector = kernel.leak_detector();
+
+        let id = kernel.spawn(|| {}, 128).unwrap().thread().id();
+        let counts = kernel.resource_counts();
+        assert_eq!((counts.live_threads, counts.registered_threads), (1, 1));
+        assert!(kernel.check_leaks(&detector).is_err());
+
+        kernel.kill(id).unwrap();
+        let after = kernel.resource_counts();
+        assert_eq!((after.live_threads, after.registered_threads), (0, 0));
+    }
+
+    #[test]
+    fn test_no_fpu_threads_spawned_without_fpu() {
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        kernel.init().unwrap();
+        let config = crate::ThreadBuilder::new().no_fpu(true).validate().unwrap();
+        let workers = kernel.spawn_batch(&config, 2, |_| || {}).unwrap();
+        assert!(workers.iter().all(|worker| !worker.thread().uses_fpu()));
+        assert!(kernel.spawn(|| {}, 128).unwrap().thread().uses_fpu());
     }
 }
@@ -0,0 +1,101 @@
+//! Compile-time kernel configuration.
+//!
+//! The tunables a platform is most likely to want to change are collected
+//! in the [`KernelConfig`] trait. Implement it on a marker type, override
+//! the constants that differ from the defaults and name the type as the
+//! kernel's third parameter:
+//!
+//! ```ignore
+//! use preemptive_threads::kernel::{Kernel, KernelConfig};
+//! use preemptive_threads::StackSizeClass;
+//!
+//! struct Tiny;
+//!
+//! impl KernelConfig for Tiny {
+//!     const MAX_THREADS: usize = 8;
+//!     const DEFAULT_STACK: StackSizeClass = StackSizeClass::Small;
+//!     const TICK_HZ: u32 = 250;
+//! }
+//!
+//! static KERNEL: Lazy<Kernel<DefaultArch, RoundRobinScheduler, Tiny>> =
+//!     Lazy::new(|| Kernel::new(RoundRobinScheduler::new(1)));
+//! ```
+//!
+//! Kernels that don't name a config use [`DefaultConfig`].
+
+use crate::mem::StackSizeClass;
+
+/// Compile-time tunables for a [`Kernel`](super::Kernel).
+pub trait KernelConfig: 'static {
+    /// Threads that may be alive at once; further spawns fail with
+    /// [`SpawnError::TooManyThreads`](crate::errors::SpawnError::TooManyThreads).
+    const MAX_THREADS: usize = 64;
+
+    /// Stack class for threads spawned with [`Kernel::spawn`](super::Kernel::spawn).
+    const DEFAULT_STACK: StackSizeClass = StackSizeClass::Medium;
+
+    /// Preemption ticks per second.
+    const TICK_HZ: u32 = 1000;
+
+    /// Generic timer frequency in Hz to use instead of `CNTFRQ_EL0`, for
+    /// firmware that sets it wrong. Used without
+    /// [calibration](crate::time::calibration).
+    const TIMER_FREQ_HZ: Option<u64> = None;
+
+    /// Size of the stack interrupt handlers run on, in bytes.
+    ///
+    /// Sizes up to the built-in 4 KiB stack use it; larger ones are
+    /// allocated from the heap by
+    /// [`Kernel::init_interrupts`](super::Kernel::init_interrupts).
+    const IRQ_STACK_SIZE: usize = 4096;
+
+    /// How the preemption tick is delivered; see [`TickRoute`].
+    const TICK_ROUTE: TickRoute = TickRoute::Irq;
+
+    /// Tick period in microseconds, derived from [`TICK_HZ`](Self::TICK_HZ).
+    fn tick_interval_us() -> u32 {
+        1_000_000 / Self::TICK_HZ.max(1)
+    }
+}
+
+/// Which exception the timer tick arrives as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickRoute {
+    /// An IRQ, like every other interrupt. Code that masks IRQs holds the
+    /// tick off until it unmasks them.
+    Irq,
+    /// An FIQ, which masking IRQs doesn't hold off. The tick keeps the
+    /// timer running and the [stall detector](super::stall) checking
+    /// while a thread has IRQs masked, and preempts it the moment it
+    /// unmasks them. Meant for debugging code suspected of masking IRQs
+    /// for too long.
+    ///
+    /// Needs a GIC whose interrupt groups the kernel may set, as under
+    /// QEMU with `qemu-virt`; elsewhere
+    /// [`Kernel::init_interrupts`](super::Kernel::init_interrupts) fails
+    /// with [`KernelError::FiqUnavailable`](crate::errors::KernelError::FiqUnavailable).
+    Fiq,
+}
+
+/// The configuration used when none is named.
+pub struct DefaultConfig;
+
+impl KernelConfig for DefaultConfig {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overrides_keep_other_defaults() {
+        struct Slow;
+        impl KernelConfig for Slow {
+            const TICK_HZ: u32 = 250;
+        }
+
+        assert_eq!(Slow::tick_interval_us(), 4000);
+        assert_eq!(Slow::MAX_THREADS, DefaultConfig::MAX_THREADS);
+        assert_eq!(DefaultConfig::tick_interval_us(), 1000);
+        assert_eq!(Slow::TICK_ROUTE, TickRoute::Irq);
+    }
+}
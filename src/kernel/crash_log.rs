@@ -0,0 +1,280 @@
+//! Crash report kept on a block device across reboots.
+//!
+//! Reserve one block on a storage device (a raw sector outside any
+//! partition works well) and [`configure`] it early during boot. When the
+//! kernel panics, the panic handler writes a [`CrashReport`] there; the next
+//! boot's [`Kernel::init`](super::Kernel::init) reads and clears it, and
+//! [`Kernel::last_crash`](super::Kernel::last_crash) tells the application
+//! why the device went down.
+//!
+//! The report is written from the panic handler with interrupts masked, so
+//! the device must be able to transfer without them (the SD driver in
+//! [`Mode::Polling`](crate::drivers::sdhost::Mode::Polling)). A panic raised
+//! while the device itself was mid-transfer can't be recorded.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::drivers::sdhost::{self, Mode};
+//! use preemptive_threads::kernel::crash_log;
+//!
+//! sdhost::sdhost().init(Mode::Polling)?;
+//! crash_log::configure(sdhost::sdhost(), CRASH_LOG_LBA);
+//! KERNEL.init()?;
+//! if let Some(report) = KERNEL.last_crash() {
+//!     report_to_server(report.message());
+//! }
+//! ```
+
+use crate::drivers::block::{BlockDevice, BLOCK_SIZE};
+use crate::errors::DeviceError;
+use crate::thread::ThreadId;
+use crate::time::Instant;
+use core::fmt::{self, Write};
+
+const MAGIC: [u8; 8] = *b"PTCRASH1";
+const HEADER_LEN: usize = 32;
+
+/// Longest source path kept in a report, in bytes.
+pub const MAX_FILE_LEN: usize = 96;
+
+/// Longest panic message kept in a report, in bytes.
+pub const MAX_MESSAGE_LEN: usize = BLOCK_SIZE - HEADER_LEN - MAX_FILE_LEN;
+
+static REGION: spin::Mutex<Option<(&'static dyn BlockDevice, u64)>> = spin::Mutex::new(None);
+
+/// Why the system went down on a previous boot.
+#[derive(Clone)]
+pub struct CrashReport {
+    timestamp: Instant,
+    thread: u64,
+    line: u32,
+    file_len: usize,
+    file: [u8; MAX_FILE_LEN],
+    message_len: usize,
+    message: [u8; MAX_MESSAGE_LEN],
+}
+
+impl CrashReport {
+    fn new(timestamp: Instant, thread: ThreadId, file: &str, line: u32) -> Self {
+        let mut report = Self {
+            timestamp,
+            thread: thread.get() as u64,
+            line,
+            file_len: 0,
+            file: [0; MAX_FILE_LEN],
+            message_len: 0,
+            message: [0; MAX_MESSAGE_LEN],
+        };
+        report.file_len = copy_truncated(&mut report.file, file.as_bytes());
+        report
+    }
+
+    /// Time since boot when the panic happened.
+    pub fn timestamp(&self) -> Instant {
+        self.timestamp
+    }
+
+    /// Thread that was running.
+    pub fn thread(&self) -> ThreadId {
+        ThreadId::new(self.thread)
+    }
+
+    /// Source file of the panic, possibly truncated.
+    pub fn file(&self) -> &str {
+        utf8_prefix(&self.file[..self.file_len])
+    }
+
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    /// The panic message, possibly truncated.
+    pub fn message(&self) -> &str {
+        utf8_prefix(&self.message[..self.message_len])
+    }
+
+    fn encode(&self) -> [u8; BLOCK_SIZE] {
+        let mut block = [0u8; BLOCK_SIZE];
+        block[..8].copy_from_slice(&MAGIC);
+        block[8..16].copy_from_slice(&self.timestamp.as_nanos().to_le_bytes());
+        block[16..24].copy_from_slice(&self.thread.to_le_bytes());
+        block[24..28].copy_from_slice(&self.line.to_le_bytes());
+        block[28] = self.file_len as u8;
+        block[29..31].copy_from_slice(&(self.message_len as u16).to_le_bytes());
+        block[HEADER_LEN..HEADER_LEN + MAX_FILE_LEN].copy_from_slice(&self.file);
+        block[HEADER_LEN + MAX_FILE_LEN..].copy_from_slice(&self.message);
+        block[31] = checksum(&block);
+        block
+    }
+
+    fn decode(block: &[u8; BLOCK_SIZE]) -> Option<Self> {
+        if block[..8] != MAGIC || block[31] != checksum(block) {
+            return None;
+        }
+        let file_len = block[28] as usize;
+        let message_len = u16::from_le_bytes([block[29], block[30]]) as usize;
+        if file_len > MAX_FILE_LEN || message_len > MAX_MESSAGE_LEN {
+            return None;
+        }
+
+        let mut report = Self::new(
+            Instant::from_nanos(u64::from_le_bytes(block[8..16].try_into().ok()?)),
+            ThreadId::new(u64::from_le_bytes(block[16..24].try_into().ok()?)),
+            "",
+            u32::from_le_bytes(block[24..28].try_into().ok()?),
+        );
+        report.file_len = file_len;
+        report
+            .file
+            .copy_from_slice(&block[HEADER_LEN..HEADER_LEN + MAX_FILE_LEN]);
+        report.message_len = message_len;
+        report
+            .message
+            .copy_from_slice(&block[HEADER_LEN + MAX_FILE_LEN..]);
+        Some(report)
+    }
+}
+
+impl fmt::Debug for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CrashReport")
+            .field("timestamp", &self.timestamp)
+            .field("thread", &self.thread)
+            .field("file", &self.file())
+            .field("line", &self.line)
+            .field("message", &self.message())
+            .finish()
+    }
+}
+
+impl Write for CrashReport {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let written = copy_truncated(&mut self.message[self.message_len..], s.as_bytes());
+        self.message_len += written;
+        Ok(())
+    }
+}
+
+/// Keep crash reports in block `lba` of `device`.
+pub fn configure(device: &'static dyn BlockDevice, lba: u64) {
+    crate::arch::without_interrupts(|| *REGION.lock() = Some((device, lba)));
+}
+
+/// Write a report for `info` to the configured block.
+///
+/// Called by the panic handler. Does nothing if no block is configured.
+pub fn record_panic(info: &core::panic::PanicInfo<'_>) {
+    let Some(Some((device, lba))) = REGION.try_lock().map(|region| *region) else {
+        return;
+    };
+    let (file, line) = info.location().map_or(("", 0), |l| (l.file(), l.line()));
+    let mut report = CrashReport::new(
+        Instant::now(),
+        crate::thread::current_thread_id(),
+        file,
+        line,
+    );
+    let _ = write!(report, "{}", info);
+    let _ = store(device, lba, &report);
+}
+
+/// Read the report left by the previous boot and clear it, so each crash
+/// is reported once.
+pub fn take() -> Option<CrashReport> {
+    let (device, lba) = crate::arch::without_interrupts(|| *REGION.lock())?;
+    let report = load(device, lba).ok()??;
+    let _ = device.write_blocks(lba, &[0u8; BLOCK_SIZE]);
+    Some(report)
+}
+
+fn store(device: &dyn BlockDevice, lba: u64, report: &CrashReport) -> Result<(), DeviceError> {
+    device.write_blocks(lba, &report.encode())
+}
+
+fn load(device: &dyn BlockDevice, lba: u64) -> Result<Option<CrashReport>, DeviceError> {
+    let mut block = [0u8; BLOCK_SIZE];
+    device.read_blocks(lba, &mut block)?;
+    Ok(CrashReport::decode(&block))
+}
+
+/// Copy as much of `src` into `dst` as fits, returning the bytes copied.
+fn copy_truncated(dst: &mut [u8], src: &[u8]) -> usize {
+    let len = src.len().min(dst.len());
+    dst[..len].copy_from_slice(&src[..len]);
+    len
+}
+
+/// The valid UTF-8 prefix of `bytes`; truncation may have split a character.
+fn utf8_prefix(bytes: &[u8]) -> &str {
+    match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+    }
+}
+
+/// XOR of every byte except the checksum itself.
+fn checksum(block: &[u8; BLOCK_SIZE]) -> u8 {
+    block
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 31)
+        .fold(0, |acc, (_, &b)| acc ^ b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    struct RamDisk(spin::Mutex<Vec<u8>>);
+
+    impl BlockDevice for RamDisk {
+        fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+            let start = lba as usize * BLOCK_SIZE;
+            buf.copy_from_slice(&self.0.lock()[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), DeviceError> {
+            let start = lba as usize * BLOCK_SIZE;
+            self.0.lock()[start..start + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn block_count(&self) -> u64 {
+            (self.0.lock().len() / BLOCK_SIZE) as u64
+        }
+    }
+
+    #[test]
+    fn test_report_roundtrip_and_truncation() {
+        let disk = RamDisk(spin::Mutex::new(vec![0; 4 * BLOCK_SIZE]));
+        assert!(load(&disk, 2).unwrap().is_none());
+
+        let mut report = CrashReport::new(
+            Instant::from_nanos(42),
+            ThreadId::new(7),
+            "src/sensor.rs",
+            12,
+        );
+        write!(
+            report,
+            "index out of bounds: {}",
+            "é".repeat(MAX_MESSAGE_LEN)
+        )
+        .unwrap();
+        store(&disk, 2, &report).unwrap();
+
+        let read = load(&disk, 2).unwrap().unwrap();
+        assert_eq!(read.thread().get(), 7);
+        assert_eq!((read.file(), read.line()), ("src/sensor.rs", 12));
+        assert!(read.message().starts_with("index out of bounds: é"));
+        // Cut mid-character, so the partial one is dropped
+        assert_eq!(read.message().len(), MAX_MESSAGE_LEN - 1);
+
+        disk.0.lock()[2 * BLOCK_SIZE + 40] ^= 1;
+        assert!(load(&disk, 2).unwrap().is_none());
+    }
+}
@@ -0,0 +1,100 @@
+//! Running threads from inside an existing main loop.
+//!
+//! Firmware built around a super-loop can host the kernel instead of
+//! handing it the CPU: rather than
+//! [`start_first_thread`](super::Kernel::start_first_thread), which never
+//! returns, the loop calls [`Kernel::poll`](super::Kernel::poll) with a
+//! time budget. Ready threads run until the budget is spent or none is
+//! left ready, and `poll` returns to the loop:
+//!
+//! ```ignore
+//! KERNEL.init()?;
+//! KERNEL.spawn(telemetry_task, priority::NORMAL)?;
+//! loop {
+//!     poll_sensors();
+//!     KERNEL.poll(Duration::from_millis(2))?;
+//!     update_outputs();
+//! }
+//! ```
+//!
+//! The budget is checked whenever a thread yields, blocks or exits, and on
+//! every scheduler tick if the kernel's timer is running. In
+//! [`PreemptionMode::Full`](crate::PreemptionMode::Full) the tick sends the
+//! CPU back to the loop directly; otherwise the running thread goes back
+//! at its next preemption point.
+//!
+//! Firmware that paces its loop with a timer of its own can let a
+//! [`PollDriver`] keep time instead: the timer's interrupt handler calls
+//! [`on_timer`](PollDriver::on_timer) and the loop calls
+//! [`service`](PollDriver::service), which runs threads once per period.
+
+use super::{Kernel, KernelConfig};
+use crate::arch::Arch;
+use crate::errors::KernelError;
+use crate::platform_timer;
+use crate::sched::Scheduler;
+use crate::time::Duration;
+use portable_atomic::{AtomicBool, Ordering};
+
+/// Why [`Kernel::poll`](super::Kernel::poll) returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PollStatus {
+    /// No thread was ready to run.
+    Idle = 0,
+    /// The budget ran out; threads may still be ready.
+    BudgetExhausted = 1,
+}
+
+impl PollStatus {
+    pub(crate) fn from_u8(value: u8) -> Self {
+        match value {
+            1 => PollStatus::BudgetExhausted,
+            _ => PollStatus::Idle,
+        }
+    }
+}
+
+/// Polls a kernel once per period of a timer the firmware owns, see the
+/// [module docs](self).
+pub struct PollDriver<'k, A: Arch, S: Scheduler, C: KernelConfig> {
+    kernel: &'k Kernel<A, S, C>,
+    budget: Duration,
+    due: AtomicBool,
+}
+
+impl<'k, A: Arch, S: Scheduler, C: KernelConfig> PollDriver<'k, A, S, C> {
+    /// Give `kernel` up to `budget` of every timer period.
+    pub const fn new(kernel: &'k Kernel<A, S, C>, budget: Duration) -> Self {
+        Self {
+            kernel,
+            budget,
+            due: AtomicBool::new(false),
+        }
+    }
+
+    /// Call from the timer's interrupt handler.
+    ///
+    /// Marks a poll due and, if threads are still running past their
+    /// budget, asks them to return to the loop at their next preemption
+    /// point.
+    pub fn on_timer(&self) {
+        self.due.store(true, Ordering::Release);
+        if self.kernel.poll_expired() {
+            platform_timer::request_preemption();
+        }
+    }
+
+    /// Call from the main loop: polls the kernel if the timer fired since
+    /// the last call.
+    ///
+    /// # Returns
+    ///
+    /// `None` if no poll was due.
+    pub fn service(&self) -> Result<Option<PollStatus>, KernelError> {
+        if !self.due.swap(false, Ordering::AcqRel) {
+            return Ok(None);
+        }
+        self.kernel.poll(self.budget).map(Some)
+    }
+}
@@ -0,0 +1,264 @@
+//! Broadcast bus for kernel lifecycle events.
+//!
+//! The kernel [publishes](publish) a [`KernelEvent`] whenever a thread is
+//! created or exits, a panic is being reported, a core starts scheduling,
+//! the starvation watchdog finds something or an interrupt line is masked
+//! for [storming](crate::irq#interrupt-storms). Every [`Subscription`] gets
+//! its own copy through a bounded channel, so monitors, loggers and
+//! supervisors can follow what the kernel does without hooks of their own
+//! in the kernel paths.
+//!
+//! Publishing never blocks and never allocates, so it happens right in the
+//! switch path and in interrupt handlers. A subscriber whose channel is
+//! full misses the event; [`Subscription::dropped`] counts how many. More
+//! urgent events (panics, watchdog findings) are received first.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::kernel::events::{self, EventKind, KernelEvent};
+//!
+//! KERNEL.spawn(|| {
+//!     let exits = events::subscribe_to(&[EventKind::ThreadExited], 16);
+//!     loop {
+//!         if let KernelEvent::ThreadExited { thread, completed: false } = exits.recv() {
+//!             klog!(Level::Warn, "thread {} was terminated", thread);
+//!         }
+//!     }
+//! }, 10)?;
+//! ```
+
+use crate::mem::ArcLite;
+use crate::sched::{CpuId, Diagnostic};
+use crate::sync::{PriorityChannel, Rcu, Selectable, WaitQueue};
+use crate::thread::ThreadId;
+use alloc::vec::Vec;
+use portable_atomic::{AtomicUsize, Ordering};
+
+/// Something that happened in the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelEvent {
+    /// A thread was spawned and queued.
+    ThreadCreated { thread: ThreadId, priority: u8 },
+    /// A thread finished; `completed` is `false` if the kernel terminated it.
+    ThreadExited { thread: ThreadId, completed: bool },
+    /// The panic handler is running on behalf of `thread`.
+    ThreadPanicked { thread: ThreadId },
+    /// A core started scheduling threads.
+    CpuOnline(CpuId),
+    /// A core stopped scheduling threads.
+    CpuOffline(CpuId),
+    /// The starvation watchdog reported a finding.
+    Watchdog(Diagnostic),
+    /// An interrupt line exceeded its rate limit and was masked.
+    IrqStorm { irq: u32 },
+}
+
+/// The kind of a [`KernelEvent`], for filtering subscriptions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EventKind {
+    ThreadCreated = 0,
+    ThreadExited = 1,
+    ThreadPanicked = 2,
+    CpuOnline = 3,
+    CpuOffline = 4,
+    Watchdog = 5,
+    IrqStorm = 6,
+}
+
+impl KernelEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            KernelEvent::ThreadCreated { .. } => EventKind::ThreadCreated,
+            KernelEvent::ThreadExited { .. } => EventKind::ThreadExited,
+            KernelEvent::ThreadPanicked { .. } => EventKind::ThreadPanicked,
+            KernelEvent::CpuOnline(_) => EventKind::CpuOnline,
+            KernelEvent::CpuOffline(_) => EventKind::CpuOffline,
+            KernelEvent::Watchdog(_) => EventKind::Watchdog,
+            KernelEvent::IrqStorm { .. } => EventKind::IrqStorm,
+        }
+    }
+
+    /// Channel priority: problems overtake routine lifecycle events.
+    fn urgency(&self) -> u8 {
+        match self {
+            KernelEvent::ThreadPanicked { .. } => 255,
+            KernelEvent::Watchdog(_) | KernelEvent::IrqStorm { .. } => 192,
+            KernelEvent::ThreadExited {
+                completed: false, ..
+            } => 160,
+            _ => 64,
+        }
+    }
+}
+
+struct Subscriber {
+    channel: PriorityChannel<KernelEvent>,
+    /// Bit per wanted EventKind
+    kinds: u32,
+    dropped: AtomicUsize,
+}
+
+impl Subscriber {
+    fn deliver(&self, event: KernelEvent) {
+        if self.kinds & 1 << event.kind() as u32 == 0 {
+            return;
+        }
+        if self.channel.try_send(event.urgency(), event).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A subscriber's end of the bus. Dropping it unsubscribes.
+pub struct Subscription {
+    subscriber: ArcLite<Subscriber>,
+}
+
+impl Subscription {
+    /// Receive the next event, blocking while there is none.
+    pub fn recv(&self) -> KernelEvent {
+        self.subscriber.channel.recv()
+    }
+
+    /// Receive the next event if there is one.
+    pub fn try_recv(&self) -> Option<KernelEvent> {
+        self.subscriber.channel.try_recv()
+    }
+
+    /// Events missed because the channel was full.
+    pub fn dropped(&self) -> usize {
+        self.subscriber.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Ready while an event is waiting.
+impl Selectable for Subscription {
+    fn is_ready(&self) -> bool {
+        self.subscriber.channel.is_ready()
+    }
+
+    fn wait_queue(&self) -> &WaitQueue {
+        self.subscriber.channel.wait_queue()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let ours = &self.subscriber;
+        SUBSCRIBERS.update(|subscribers| {
+            subscribers
+                .into_iter()
+                .flatten()
+                .filter(|s| !s.ptr_eq(ours))
+                .cloned()
+                .collect()
+        });
+    }
+}
+
+/// Read on every publish, so changed by copying rather than under a lock
+static SUBSCRIBERS: Rcu<Vec<ArcLite<Subscriber>>> = Rcu::empty();
+
+/// Receive every event, up to `capacity` of them buffered.
+///
+/// # Panics
+///
+/// Panics if `capacity` is zero.
+pub fn subscribe(capacity: usize) -> Subscription {
+    subscribe_with(u32::MAX, capacity)
+}
+
+/// Receive only events of the given kinds.
+///
+/// # Panics
+///
+/// Panics if `capacity` is zero.
+pub fn subscribe_to(kinds: &[EventKind], capacity: usize) -> Subscription {
+    subscribe_with(
+        kinds.iter().fold(0, |mask, &kind| mask | 1 << kind as u32),
+        capacity,
+    )
+}
+
+fn subscribe_with(kinds: u32, capacity: usize) -> Subscription {
+    let subscriber = ArcLite::new(Subscriber {
+        channel: PriorityChannel::new(capacity),
+        kinds,
+        dropped: AtomicUsize::new(0),
+    });
+    SUBSCRIBERS.update(|subscribers| {
+        let mut subscribers = subscribers.cloned().unwrap_or_default();
+        subscribers.push(subscriber.clone());
+        subscribers
+    });
+    Subscription { subscriber }
+}
+
+/// Send `event` to every interested subscriber.
+///
+/// Never blocks or allocates, so it is safe anywhere, interrupt handlers
+/// and the switch path included.
+pub fn publish(event: KernelEvent) {
+    // Interrupts off so dropping the read isn't a preemption point here
+    crate::arch::without_interrupts(|| {
+        if let Some(subscribers) = SUBSCRIBERS.read() {
+            subscribers
+                .iter()
+                .for_each(|subscriber| subscriber.deliver(event));
+        }
+    });
+}
+
+/// Events published while the subscriber list was being changed, which no
+/// one received.
+///
+/// Always zero: publishing reads the list without a lock, so a change in
+/// progress can't make it skip an event.
+pub fn missed() -> u32 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscribers_get_their_kinds() {
+        let id = unsafe { ThreadId::new_unchecked(7) };
+        let subscriber = Subscriber {
+            channel: PriorityChannel::new(2),
+            kinds: 1 << EventKind::ThreadExited as u32 | 1 << EventKind::ThreadPanicked as u32,
+            dropped: AtomicUsize::new(0),
+        };
+        subscriber.deliver(KernelEvent::ThreadCreated {
+            thread: id,
+            priority: 128,
+        });
+        subscriber.deliver(KernelEvent::ThreadExited {
+            thread: id,
+            completed: true,
+        });
+        subscriber.deliver(KernelEvent::ThreadPanicked { thread: id });
+        subscriber.deliver(KernelEvent::ThreadExited {
+            thread: id,
+            completed: false,
+        });
+
+        // The panic overtakes the exit, and the second exit didn't fit
+        assert_eq!(
+            subscriber.channel.try_recv(),
+            Some(KernelEvent::ThreadPanicked { thread: id })
+        );
+        assert_eq!(
+            subscriber.channel.try_recv(),
+            Some(KernelEvent::ThreadExited {
+                thread: id,
+                completed: true
+            })
+        );
+        assert_eq!(subscriber.channel.try_recv(), None);
+        assert_eq!(subscriber.dropped.load(Ordering::Relaxed), 1);
+    }
+}
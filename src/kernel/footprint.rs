@@ -0,0 +1,111 @@
+//! What each thread costs in RAM, known at compile time.
+//!
+//! Besides its stack, a thread holds a record on the heap with its state,
+//! accounting and saved registers, and a slot in the kernel's thread
+//! table, which is allocated for
+//! [`MAX_THREADS`](super::KernelConfig::MAX_THREADS) threads at init.
+//! The saved context ([`Arch::CONTEXT_SIZE`]) is most of the record: on
+//! AArch64 it is 800 bytes with the `full-fpu` feature and 272 without,
+//! since the 32 NEON registers and FPCR/FPSR are then neither switched
+//! nor stored.
+//!
+//! [`THREAD_FOOTPRINT`] adds these up for the build. A memory-constrained
+//! application can print it, or pin it with [`assert_thread_overhead!`]
+//! so that a feature or kernel change that makes every thread heavier
+//! fails to compile instead of running out of heap on the board:
+//!
+//! ```ignore
+//! // At most 1 KiB of kernel state per thread, stack not included
+//! preemptive_threads::assert_thread_overhead!(1024);
+//!
+//! pl011_println!("{}", preemptive_threads::kernel::footprint::THREAD_FOOTPRINT);
+//! ```
+//!
+//! Allocated on use and not counted: a thread's name, its
+//! [arena](crate::mem::arena), and the run queue node it holds while
+//! ready.
+
+use crate::arch::{Arch, DefaultArch};
+use crate::mem::ArcLite;
+use crate::thread::{ThreadInner, ThreadSlab};
+use core::fmt;
+
+/// Per-thread kernel memory, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadFootprint {
+    /// Saved registers, part of `record`.
+    pub context: usize,
+    /// The heap record a thread's handles share.
+    pub record: usize,
+    /// The thread's entry in the thread table.
+    pub table_slot: usize,
+}
+
+impl ThreadFootprint {
+    /// Kernel memory per thread, without its stack.
+    pub const fn total(&self) -> usize {
+        self.record + self.table_slot
+    }
+
+    /// Memory per thread with a stack of `stack` bytes.
+    pub const fn with_stack(&self, stack: usize) -> usize {
+        self.total() + stack
+    }
+}
+
+/// The footprint of a thread in this build.
+pub const THREAD_FOOTPRINT: ThreadFootprint = ThreadFootprint {
+    context: DefaultArch::CONTEXT_SIZE,
+    record: ArcLite::<ThreadInner>::ALLOCATION_SIZE,
+    table_slot: ThreadSlab::SLOT_SIZE,
+};
+
+impl fmt::Display for ThreadFootprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} B per thread + stack: {} B record ({} B context), {} B table slot",
+            self.total(),
+            self.record,
+            self.context,
+            self.table_slot,
+        )
+    }
+}
+
+/// Fail compilation if a thread costs more than `max` bytes of kernel
+/// memory besides its stack; see [`footprint`](crate::kernel::footprint).
+#[macro_export]
+macro_rules! assert_thread_overhead {
+    ($max:expr) => {
+        const _: () = assert!(
+            $crate::kernel::footprint::THREAD_FOOTPRINT.total() <= $max,
+            "per-thread kernel memory exceeds the budget"
+        );
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    crate::assert_thread_overhead!(4096);
+
+    #[test]
+    fn test_footprint_adds_up() {
+        assert_eq!(
+            THREAD_FOOTPRINT.with_stack(16384),
+            THREAD_FOOTPRINT.total() + 16384
+        );
+
+        let aarch64 = ThreadFootprint {
+            context: 800,
+            record: 1232,
+            table_slot: 24,
+        };
+        assert_eq!(
+            alloc::format!("{}", aarch64),
+            "1256 B per thread + stack: 1232 B record (800 B context), 24 B table slot"
+        );
+    }
+}
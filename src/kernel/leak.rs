@@ -0,0 +1,262 @@
+//! Leak detection for on-target tests (`leak-check` feature).
+//!
+//! A thread that exits, is killed or is torn down with the kernel should
+//! give back everything it held: its stack, its slot in the thread table,
+//! the heap it allocated. Host tests under `std-shim` can't see whether it
+//! does on hardware, where the allocator and the stack pool are the real
+//! ones. A [`LeakDetector`] snapshots those counts at one point and
+//! compares them with a later one:
+//!
+//! ```ignore
+//! use preemptive_threads::kernel::leak::CountingAlloc;
+//!
+//! #[global_allocator]
+//! static ALLOCATOR: CountingAlloc<MyAllocator> = CountingAlloc::new(MyAllocator);
+//!
+//! let detector = kernel.leak_detector();
+//! for _ in 0..100 {
+//!     kernel.spawn(work, 128)?.join().unwrap();
+//! }
+//! if let Err(leaks) = kernel.check_leaks(&detector) {
+//!     pl011_println!("FAIL: {}", leaks);
+//! }
+//! ```
+//!
+//! Heap counts need the global allocator wrapped in [`CountingAlloc`];
+//! without it they read as unknown and aren't compared. Anything the code
+//! under test caches on purpose, such as stacks parked in the pool's free
+//! lists, a first-use table, or log buffers, should be warmed up before
+//! the baseline is taken, or it shows up as a leak.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use portable_atomic::{AtomicBool, AtomicUsize, Ordering};
+
+static HEAP_BLOCKS: AtomicUsize = AtomicUsize::new(0);
+static HEAP_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Set once a `CountingAlloc` has served an allocation
+static HEAP_COUNTED: AtomicBool = AtomicBool::new(false);
+
+/// Global allocator wrapper that counts live heap blocks and bytes for
+/// [`ResourceCounts`].
+pub struct CountingAlloc<A> {
+    inner: A,
+}
+
+impl<A> CountingAlloc<A> {
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+fn counted(ptr: *mut u8, size: usize) -> *mut u8 {
+    if !ptr.is_null() {
+        HEAP_COUNTED.store(true, Ordering::Relaxed);
+        HEAP_BLOCKS.fetch_add(1, Ordering::Relaxed);
+        HEAP_BYTES.fetch_add(size, Ordering::Relaxed);
+    }
+    ptr
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        counted(unsafe { self.inner.alloc(layout) }, layout.size())
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        counted(unsafe { self.inner.alloc_zeroed(layout) }, layout.size())
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let moved = unsafe { self.inner.realloc(ptr, layout, new_size) };
+        if !moved.is_null() {
+            HEAP_BYTES.fetch_add(new_size, Ordering::Relaxed);
+            HEAP_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        moved
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.inner.dealloc(ptr, layout) };
+        HEAP_BLOCKS.fetch_sub(1, Ordering::Relaxed);
+        HEAP_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+/// Live heap blocks and bytes, if a [`CountingAlloc`] is installed.
+pub fn heap_usage() -> Option<(usize, usize)> {
+    HEAP_COUNTED.load(Ordering::Relaxed).then(|| {
+        (
+            HEAP_BLOCKS.load(Ordering::Relaxed),
+            HEAP_BYTES.load(Ordering::Relaxed),
+        )
+    })
+}
+
+/// What the kernel holds at one moment; see
+/// [`Kernel::resource_counts`](crate::Kernel::resource_counts).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ResourceCounts {
+    /// Stacks handed out by the pool and not given back.
+    pub stacks_in_use: usize,
+    /// Threads spawned and not yet retired.
+    pub live_threads: usize,
+    /// Entries in the thread table.
+    pub registered_threads: usize,
+    /// Threads in the [sleep](super::sleep) queue.
+    pub sleeping_threads: usize,
+    /// Pending [high-resolution timers](crate::time::hrtimer).
+    pub hrtimers: usize,
+    /// Live heap blocks, `None` without a [`CountingAlloc`].
+    pub heap_blocks: Option<usize>,
+    /// Live heap bytes, `None` without a [`CountingAlloc`].
+    pub heap_bytes: Option<usize>,
+}
+
+impl ResourceCounts {
+    /// Each count with its name, `None` where unknown.
+    fn entries(&self) -> [(&'static str, Option<usize>); 7] {
+        [
+            ("stacks", Some(self.stacks_in_use)),
+            ("live threads", Some(self.live_threads)),
+            ("thread table entries", Some(self.registered_threads)),
+            ("sleeping threads", Some(self.sleeping_threads)),
+            ("hrtimers", Some(self.hrtimers)),
+            ("heap blocks", self.heap_blocks),
+            ("heap bytes", self.heap_bytes),
+        ]
+    }
+
+    /// (name, before, after) for every count known in both `self` and
+    /// `later`.
+    fn compare(&self, later: &Self) -> impl Iterator<Item = (&'static str, usize, usize)> {
+        self.entries()
+            .into_iter()
+            .zip(later.entries())
+            .filter_map(|((name, before), (_, after))| Some((name, before?, after?)))
+    }
+}
+
+/// One count that grew between the baseline and a check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Leak {
+    pub resource: &'static str,
+    pub before: usize,
+    pub after: usize,
+}
+
+/// The counts that grew, as found by [`LeakDetector::check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeakReport {
+    pub leaks: alloc::vec::Vec<Leak>,
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("leaked")?;
+        for (i, leak) in self.leaks.iter().enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            write!(
+                f,
+                "{}{} {} ({} -> {})",
+                sep,
+                leak.after - leak.before,
+                leak.resource,
+                leak.before,
+                leak.after
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Resource counts taken at a baseline, to compare later ones against; see
+/// the [module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeakDetector {
+    baseline: ResourceCounts,
+}
+
+impl LeakDetector {
+    pub const fn new(baseline: ResourceCounts) -> Self {
+        Self { baseline }
+    }
+
+    pub fn baseline(&self) -> ResourceCounts {
+        self.baseline
+    }
+
+    /// How much each count changed from the baseline to `now`, as
+    /// (name, change), e.g. to assert that a scenario holds exactly one
+    /// more thread while it runs.
+    pub fn deltas(&self, now: &ResourceCounts) -> impl Iterator<Item = (&'static str, isize)> {
+        self.baseline
+            .compare(now)
+            .map(|(name, before, after)| (name, after as isize - before as isize))
+    }
+
+    /// `Err` listing every count that is higher in `now` than at the
+    /// baseline. Counts that fell are fine: something held at the baseline
+    /// was released.
+    pub fn check(&self, now: &ResourceCounts) -> Result<(), LeakReport> {
+        let leaks: alloc::vec::Vec<_> = self
+            .baseline
+            .compare(now)
+            .filter(|&(_, before, after)| after > before)
+            .map(|(resource, before, after)| Leak {
+                resource,
+                before,
+                after,
+            })
+            .collect();
+        if leaks.is_empty() {
+            Ok(())
+        } else {
+            Err(LeakReport { leaks })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_reports_growth_only() {
+        let baseline = ResourceCounts {
+            stacks_in_use: 2,
+            live_threads: 2,
+            heap_bytes: Some(4096),
+            ..Default::default()
+        };
+        let detector = LeakDetector::new(baseline);
+        assert_eq!(detector.check(&baseline), Ok(()));
+
+        let now = ResourceCounts {
+            stacks_in_use: 3,
+            live_threads: 1,
+            heap_bytes: Some(4160),
+            ..baseline
+        };
+        let report = detector.check(&now).unwrap_err();
+        assert_eq!(
+            alloc::format!("{}", report),
+            "leaked 1 stacks (2 -> 3), 64 heap bytes (4096 -> 4160)"
+        );
+        let deltas: alloc::vec::Vec<_> = detector
+            .deltas(&now)
+            .filter(|&(_, delta)| delta != 0)
+            .collect();
+        assert_eq!(
+            deltas,
+            [("stacks", 1), ("live threads", -1), ("heap bytes", 64)]
+        );
+
+        // Heap counts known on only one side aren't compared
+        let untracked = ResourceCounts {
+            heap_bytes: None,
+            ..baseline
+        };
+        assert_eq!(detector.check(&untracked), Ok(()));
+    }
+}
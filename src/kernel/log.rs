@@ -0,0 +1,291 @@
+//! Buffered kernel log.
+//!
+//! [`klog!`](crate::klog) formats a line into a lock-free ring and returns
+//! without touching the UART, so it is cheap enough for the switch path and
+//! safe in interrupt handlers. A low-priority logger thread running
+//! [`run_logger`] (or a housekeeping loop calling [`drain`]) writes the
+//! buffered lines out. Lines that don't fit are dropped whole and counted
+//! in [`dropped`].
+//!
+//! [`pl011_println!`](crate::pl011_println) stays the synchronous path for
+//! boot messages and the panic handler, which calls [`emergency_flush`] to
+//! get whatever was still buffered out before the panic message.
+//!
+//! Tracing of the scheduler hot paths goes through
+//! [`klog_trace!`](crate::klog_trace), which compiles to nothing unless the
+//! `trace-log` feature is enabled.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::kernel::log::{self, Level};
+//!
+//! KERNEL.spawn(|| log::run_logger(), 1)?;
+//! klog!(Level::Info, "sensor {} online", id);
+//! ```
+
+use crate::sync::SpscRing;
+use core::fmt::{self, Write};
+use portable_atomic::{AtomicU8, AtomicUsize, Ordering};
+
+/// Size of the log ring in bytes.
+pub const LOG_BUFFER_SIZE: usize = 4096;
+
+/// Longest line kept, in bytes; longer lines are truncated.
+pub const MAX_LINE_LEN: usize = 160;
+
+/// Severity of a log line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    Trace = 4,
+}
+
+impl Level {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            3 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+
+    fn tag(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// The ring lines are buffered in, with its producer and consumer sides
+/// each serialized.
+struct LogRing {
+    bytes: SpscRing<u8, LOG_BUFFER_SIZE>,
+    writer: spin::Mutex<()>,
+    reader: spin::Mutex<()>,
+    dropped: AtomicUsize,
+}
+
+impl LogRing {
+    const fn new() -> Self {
+        Self {
+            bytes: SpscRing::new(),
+            writer: spin::Mutex::new(()),
+            reader: spin::Mutex::new(()),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Buffer one formatted line, or drop it if the ring can't take all of it.
+    ///
+    /// Callers run with interrupts masked, so the writer lock is only ever
+    /// contended across cores; a line that would have to wait is dropped
+    /// instead.
+    fn push_line(&self, level: Level, args: fmt::Arguments<'_>) -> bool {
+        let mut line = Line::new();
+        let _ = write!(line, "[{}] {}", level.tag(), args);
+        line.terminate();
+
+        let pushed = match self.writer.try_lock() {
+            Some(_writer) if self.free() >= line.len => {
+                line.bytes[..line.len].iter().all(|&b| self.bytes.push(b))
+            }
+            _ => false,
+        };
+        if !pushed {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        pushed
+    }
+
+    fn free(&self) -> usize {
+        self.bytes.capacity() - self.bytes.len()
+    }
+
+    /// Hand every buffered byte to `out`, returning how many there were.
+    fn drain_into(&self, mut out: impl FnMut(u8)) -> usize {
+        let Some(_reader) = self.reader.try_lock() else {
+            return 0;
+        };
+        self.pop_all(&mut out)
+    }
+
+    fn pop_all(&self, out: &mut impl FnMut(u8)) -> usize {
+        let mut count = 0;
+        while let Some(byte) = self.bytes.pop() {
+            out(byte);
+            count += 1;
+        }
+        count
+    }
+}
+
+/// A line being formatted on the stack, truncated at [`MAX_LINE_LEN`].
+struct Line {
+    bytes: [u8; MAX_LINE_LEN],
+    len: usize,
+}
+
+impl Line {
+    fn new() -> Self {
+        Self {
+            bytes: [0; MAX_LINE_LEN],
+            len: 0,
+        }
+    }
+
+    /// End the line with a newline, overwriting the last byte if full.
+    fn terminate(&mut self) {
+        self.len = self.len.min(MAX_LINE_LEN - 1);
+        self.bytes[self.len] = b'\n';
+        self.len += 1;
+    }
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(MAX_LINE_LEN - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+static LOG: LogRing = LogRing::new();
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Trace as u8);
+
+/// Buffer a line at `level`. Use [`klog!`](crate::klog) rather than calling
+/// this directly.
+#[doc(hidden)]
+pub fn write(level: Level, args: fmt::Arguments<'_>) {
+    if level > max_level() {
+        return;
+    }
+    crate::arch::without_interrupts(|| LOG.push_line(level, args));
+}
+
+/// Discard lines less severe than `level`.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn max_level() -> Level {
+    Level::from_u8(MAX_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Lines dropped because the ring was full.
+pub fn dropped() -> usize {
+    LOG.dropped.load(Ordering::Relaxed)
+}
+
+/// Write every buffered line to the UART.
+///
+/// Returns the number of bytes written, or 0 if another thread is already
+/// draining.
+pub fn drain() -> usize {
+    LOG.drain_into(send)
+}
+
+/// Drain the log forever, yielding between passes. Spawn it at a low
+/// priority so logging never delays real work.
+pub fn run_logger() -> ! {
+    loop {
+        drain();
+        crate::yield_now();
+    }
+}
+
+/// Write out whatever is buffered, ignoring a drain in progress.
+///
+/// Only for the panic handler: with interrupts masked for good, whoever
+/// held the reader side will never run again.
+pub fn emergency_flush() {
+    LOG.pop_all(&mut send);
+}
+
+fn send(byte: u8) {
+    use crate::arch::uart_pl011::send_byte;
+    if byte == b'\n' {
+        send_byte(b'\r');
+    }
+    send_byte(byte);
+}
+
+/// Buffer a formatted line in the kernel log without blocking.
+#[macro_export]
+macro_rules! klog {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::kernel::log::write($level, format_args!($($arg)*))
+    };
+}
+
+/// Trace a hot-path event at [`Level::Trace`]; compiled in only with the
+/// `trace-log` feature.
+#[cfg(feature = "trace-log")]
+#[macro_export]
+macro_rules! klog_trace {
+    ($($arg:tt)*) => {
+        $crate::klog!($crate::kernel::log::Level::Trace, $($arg)*)
+    };
+}
+
+/// Trace a hot-path event at [`Level::Trace`]; compiled in only with the
+/// `trace-log` feature.
+#[cfg(not(feature = "trace-log"))]
+#[macro_export]
+macro_rules! klog_trace {
+    ($($arg:tt)*) => {{
+        // Keep the arguments type-checked and used
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_lines_buffered_whole_or_dropped() {
+        let log = LogRing::new();
+        assert!(log.push_line(Level::Warn, format_args!("tick {}", 1)));
+        let long = [b'x'; 2 * MAX_LINE_LEN];
+        assert!(log.push_line(
+            Level::Info,
+            format_args!("{}", core::str::from_utf8(&long).unwrap())
+        ));
+
+        let mut out = Vec::new();
+        log.drain_into(|b| out.push(b));
+        assert!(out.starts_with(b"[WARN] tick 1\n[INFO] xxx"));
+        assert_eq!(out.len(), "[WARN] tick 1\n".len() + MAX_LINE_LEN);
+        assert_eq!(out.last(), Some(&b'\n'));
+
+        // Fill the ring, then a line that only partly fits is dropped whole
+        while log.free() >= MAX_LINE_LEN {
+            log.push_line(
+                Level::Trace,
+                format_args!("{}", core::str::from_utf8(&long).unwrap()),
+            );
+        }
+        let before = log.bytes.len();
+        assert!(!log.push_line(
+            Level::Trace,
+            format_args!("{}", core::str::from_utf8(&long).unwrap())
+        ));
+        assert_eq!(log.bytes.len(), before);
+        assert_eq!(log.dropped.load(Ordering::Relaxed), 1);
+    }
+}
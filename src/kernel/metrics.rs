@@ -0,0 +1,583 @@
+//! Per-core metrics: how long each core idles in WFI, and how quickly it
+//! dispatches ready threads.
+//!
+//! The kernel idles in exactly one place, when nothing is runnable, and
+//! times every WFI there. From those samples each core keeps:
+//!
+//! - the number of WFI instructions executed and the total time spent in
+//!   them, against the time since the metrics were last reset;
+//! - a utilization histogram: time is cut into windows of
+//!   [`WINDOW`] and each finished window adds one to the bucket for its
+//!   busy percentage, in tenths.
+//!
+//! A histogram piled up in the top bucket with nothing runnable most of
+//! the time points at a thread busy-waiting; comparing WFI counts with and
+//! without a periodic tick shows what tickless operation saves.
+//!
+//! While a [latency objective](super::slo) is set, each core also counts
+//! the dispatches checked against it, the ones that missed it and the
+//! worst ready-to-running latency seen; see [`latency_stats`].
+//!
+//! [`node_alloc_stats`] counts run queue node allocations the heap failed,
+//! and how many of them the scheduler's emergency reserve absorbed.
+//!
+//! [`spawn_latency_stats`] aggregates the time from a thread's creation to
+//! its first dispatch, a measure of how quickly new work gets going; each
+//! thread's own timestamps are on [`Thread`](crate::thread::Thread) and
+//! its join handle.
+//!
+//! Synchronous CPU exceptions (aborts, undefined instructions, SVCs, FPU
+//! traps) are counted system-wide in [`exception_stats`] and per thread in
+//! [`Thread::exception_stats`](crate::thread::Thread::exception_stats), so
+//! a thread that keeps faulting on a flaky device shows up in the field.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::kernel::metrics;
+//!
+//! let stats = metrics::power_stats(0).unwrap();
+//! pl011_println!("cpu0 idle {}%, {} wfi", stats.idle_percent(), stats.wfi_count);
+//! pl011_println!("utilization deciles: {:?}", stats.histogram);
+//! ```
+
+use crate::sched::CpuId;
+use crate::time::{Duration, Instant};
+use core::fmt;
+use portable_atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// Cores metrics are kept for.
+pub use crate::mem::percpu::MAX_CPUS;
+
+/// Length of one utilization sample window.
+pub const WINDOW: Duration = Duration::from_nanos(100_000_000);
+
+/// Histogram buckets: 0-9 % busy, 10-19 %, ..., 90-100 %.
+pub const BUCKETS: usize = 10;
+
+/// Snapshot of one core's idle accounting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerStats {
+    /// WFI instructions executed.
+    pub wfi_count: u64,
+    /// Time spent waiting in WFI.
+    pub idle: Duration,
+    /// Time covered by the counters.
+    pub elapsed: Duration,
+    /// Finished windows per busy-percentage decile.
+    pub histogram: [u64; BUCKETS],
+}
+
+impl PowerStats {
+    /// Share of the elapsed time spent in WFI, in percent.
+    pub fn idle_percent(&self) -> u64 {
+        match self.elapsed.as_nanos() {
+            0 => 0,
+            elapsed => self.idle.as_nanos().min(elapsed) * 100 / elapsed,
+        }
+    }
+}
+
+/// Snapshot of one core's dispatch latency accounting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyStats {
+    /// Dispatches checked against the latency objective.
+    pub dispatches: u64,
+    /// Dispatches that missed it.
+    pub violations: u64,
+    /// Longest time from ready to running among checked dispatches.
+    pub worst: Duration,
+}
+
+struct CoreMetrics {
+    wfi_count: AtomicU64,
+    idle_ns: AtomicU64,
+    since: AtomicU64,
+    window_start: AtomicU64,
+    window_idle_ns: AtomicU64,
+    histogram: [AtomicU64; BUCKETS],
+    dispatches: AtomicU64,
+    violations: AtomicU64,
+    worst_latency_ns: AtomicU64,
+}
+
+impl CoreMetrics {
+    // Only used to initialise the per-core copies
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NEW: CoreMetrics = CoreMetrics {
+        wfi_count: AtomicU64::new(0),
+        idle_ns: AtomicU64::new(0),
+        since: AtomicU64::new(0),
+        window_start: AtomicU64::new(0),
+        window_idle_ns: AtomicU64::new(0),
+        histogram: {
+            const ZERO: AtomicU64 = AtomicU64::new(0);
+            [ZERO; BUCKETS]
+        },
+        dispatches: AtomicU64::new(0),
+        violations: AtomicU64::new(0),
+        worst_latency_ns: AtomicU64::new(0),
+    };
+
+    /// Account one WFI that lasted from `start` to `end`.
+    fn idled(&self, start: Instant, end: Instant) {
+        let idle = end.as_nanos().saturating_sub(start.as_nanos());
+        self.wfi_count.fetch_add(1, Ordering::Relaxed);
+        self.idle_ns.fetch_add(idle, Ordering::Relaxed);
+        self.window_idle_ns.fetch_add(idle, Ordering::Relaxed);
+        self.sample(end);
+    }
+
+    /// Close the current window if it has run its length.
+    ///
+    /// Only the core itself calls this, with interrupts off, so the window
+    /// fields aren't raced.
+    fn sample(&self, now: Instant) {
+        let now = now.as_nanos();
+        let start = self.window_start.load(Ordering::Relaxed);
+        let elapsed = now.saturating_sub(start);
+        if elapsed < WINDOW.as_nanos() {
+            return;
+        }
+        let idle = self.window_idle_ns.swap(0, Ordering::Relaxed).min(elapsed);
+        let busy_percent = (elapsed - idle) * 100 / elapsed;
+        let bucket = (busy_percent as usize * BUCKETS / 100).min(BUCKETS - 1);
+        self.histogram[bucket].fetch_add(1, Ordering::Relaxed);
+        self.window_start.store(now, Ordering::Relaxed);
+    }
+
+    fn dispatched(&self, latency: Duration, missed: bool) {
+        self.dispatches.fetch_add(1, Ordering::Relaxed);
+        if missed {
+            self.violations.fetch_add(1, Ordering::Relaxed);
+        }
+        self.worst_latency_ns
+            .fetch_max(latency.as_nanos(), Ordering::Relaxed);
+    }
+
+    fn latency_stats(&self) -> LatencyStats {
+        LatencyStats {
+            dispatches: self.dispatches.load(Ordering::Relaxed),
+            violations: self.violations.load(Ordering::Relaxed),
+            worst: Duration::from_nanos(self.worst_latency_ns.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn stats(&self, now: Instant) -> PowerStats {
+        PowerStats {
+            wfi_count: self.wfi_count.load(Ordering::Relaxed),
+            idle: Duration::from_nanos(self.idle_ns.load(Ordering::Relaxed)),
+            elapsed: Duration::from_nanos(
+                now.as_nanos()
+                    .saturating_sub(self.since.load(Ordering::Relaxed)),
+            ),
+            histogram: core::array::from_fn(|i| self.histogram[i].load(Ordering::Relaxed)),
+        }
+    }
+
+    fn reset(&self, now: Instant) {
+        self.wfi_count.store(0, Ordering::Relaxed);
+        self.idle_ns.store(0, Ordering::Relaxed);
+        self.window_idle_ns.store(0, Ordering::Relaxed);
+        self.histogram
+            .iter()
+            .for_each(|bucket| bucket.store(0, Ordering::Relaxed));
+        self.dispatches.store(0, Ordering::Relaxed);
+        self.violations.store(0, Ordering::Relaxed);
+        self.worst_latency_ns.store(0, Ordering::Relaxed);
+        self.since.store(now.as_nanos(), Ordering::Relaxed);
+        self.window_start.store(now.as_nanos(), Ordering::Relaxed);
+    }
+}
+
+crate::percpu! {
+    static CORES: CoreMetrics = CoreMetrics::NEW;
+}
+
+/// Record a WFI on `cpu` from `start` to `end`.
+///
+/// Called by the kernel's idle path; call it from a platform's own idle
+/// loop too so its WFIs are counted.
+pub fn record_idle(cpu: CpuId, start: Instant, end: Instant) {
+    if let Some(core) = CORES.get_for(cpu) {
+        core.idled(start, end);
+    }
+}
+
+/// Close `cpu`'s utilization window if it is due. Called from the tick, so
+/// windows of solid work are counted even though they never idle.
+pub fn sample(cpu: CpuId, now: Instant) {
+    if let Some(core) = CORES.get_for(cpu) {
+        core.sample(now);
+    }
+}
+
+/// Record a dispatch on `cpu` checked against the latency objective.
+pub(crate) fn record_dispatch(cpu: CpuId, latency: Duration, missed: bool) {
+    if let Some(core) = CORES.get_for(cpu) {
+        core.dispatched(latency, missed);
+    }
+}
+
+/// Dispatch latency accounting for `cpu`, or `None` past [`MAX_CPUS`].
+pub fn latency_stats(cpu: CpuId) -> Option<LatencyStats> {
+    CORES.get_for(cpu).map(CoreMetrics::latency_stats)
+}
+
+/// How the scheduler coped with failed run queue node allocations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NodeAllocStats {
+    /// Allocations the heap couldn't satisfy.
+    pub failures: u64,
+    /// Of those, the ones served from the scheduler's emergency reserve.
+    pub from_reserve: u64,
+}
+
+static NODE_ALLOC_FAILURES: AtomicU64 = AtomicU64::new(0);
+static NODE_ALLOC_RESERVED: AtomicU64 = AtomicU64::new(0);
+
+/// Count a failed run queue node allocation, `rescued` if the emergency
+/// reserve covered it.
+pub(crate) fn record_node_alloc_failure(rescued: bool) {
+    NODE_ALLOC_FAILURES.fetch_add(1, Ordering::Relaxed);
+    if rescued {
+        NODE_ALLOC_RESERVED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Run queue node allocation failures since boot.
+pub fn node_alloc_stats() -> NodeAllocStats {
+    NodeAllocStats {
+        failures: NODE_ALLOC_FAILURES.load(Ordering::Relaxed),
+        from_reserve: NODE_ALLOC_RESERVED.load(Ordering::Relaxed),
+    }
+}
+
+/// A class of synchronous CPU exception, by its ESR exception class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ExceptionKind {
+    /// Data abort: a load or store to a bad or faulting address.
+    DataAbort = 0,
+    /// Instruction abort: a fetch from a bad address.
+    InstructionAbort = 1,
+    /// Undefined instruction.
+    Undefined = 2,
+    /// Supervisor call.
+    Svc = 3,
+    /// FP/SIMD access trapped, e.g. by a `no_fpu` thread.
+    FpuTrap = 4,
+    /// Any other exception class.
+    Other = 5,
+}
+
+impl ExceptionKind {
+    const COUNT: usize = 6;
+
+    /// Classify an ESR_EL1 value.
+    pub fn from_esr(esr: u64) -> Self {
+        match (esr >> 26) & 0x3F {
+            0b100100 | 0b100101 => ExceptionKind::DataAbort,
+            0b100000 | 0b100001 => ExceptionKind::InstructionAbort,
+            0b000000 => ExceptionKind::Undefined,
+            0b010101 => ExceptionKind::Svc,
+            0b000111 => ExceptionKind::FpuTrap,
+            _ => ExceptionKind::Other,
+        }
+    }
+}
+
+/// Exceptions taken, by kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ExceptionStats {
+    pub data_aborts: u64,
+    pub instruction_aborts: u64,
+    pub undefined: u64,
+    pub svcs: u64,
+    pub fpu_traps: u64,
+    pub other: u64,
+}
+
+impl ExceptionStats {
+    /// All exceptions added up.
+    pub fn total(&self) -> u64 {
+        self.data_aborts
+            + self.instruction_aborts
+            + self.undefined
+            + self.svcs
+            + self.fpu_traps
+            + self.other
+    }
+}
+
+impl fmt::Display for ExceptionStats {
+    /// One `ps`-style column: `dabt=0 iabt=0 und=0 svc=12 fpu=0 other=0`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "dabt={} iabt={} und={} svc={} fpu={} other={}",
+            self.data_aborts,
+            self.instruction_aborts,
+            self.undefined,
+            self.svcs,
+            self.fpu_traps,
+            self.other
+        )
+    }
+}
+
+/// Exception counters of one thread or of the whole system.
+pub struct ExceptionCounters {
+    counts: [AtomicU32; ExceptionKind::COUNT],
+}
+
+impl ExceptionCounters {
+    pub const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU32 = AtomicU32::new(0);
+        Self {
+            counts: [ZERO; ExceptionKind::COUNT],
+        }
+    }
+
+    #[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+    pub(crate) fn record(&self, kind: ExceptionKind) {
+        self.counts[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counts so far.
+    pub fn snapshot(&self) -> ExceptionStats {
+        let count = |kind: ExceptionKind| self.counts[kind as usize].load(Ordering::Relaxed) as u64;
+        ExceptionStats {
+            data_aborts: count(ExceptionKind::DataAbort),
+            instruction_aborts: count(ExceptionKind::InstructionAbort),
+            undefined: count(ExceptionKind::Undefined),
+            svcs: count(ExceptionKind::Svc),
+            fpu_traps: count(ExceptionKind::FpuTrap),
+            other: count(ExceptionKind::Other),
+        }
+    }
+}
+
+impl Default for ExceptionCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static EXCEPTIONS: ExceptionCounters = ExceptionCounters::new();
+
+/// Count an exception system-wide and against the running thread, if
+/// there is one. Called from the synchronous exception vector.
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+pub(crate) fn record_exception(kind: ExceptionKind) {
+    EXCEPTIONS.record(kind);
+    if crate::irq::in_irq() {
+        return;
+    }
+    // Looked up by id, lock-free: the exception may have hit the kernel
+    // with its current-thread lock held
+    let id = crate::thread::current_thread_id();
+    if let Some(thread) = super::global_ops().and_then(|kernel| kernel.thread(id)) {
+        thread.record_exception(kind);
+    }
+}
+
+/// Exceptions taken since boot, system-wide.
+pub fn exception_stats() -> ExceptionStats {
+    EXCEPTIONS.snapshot()
+}
+
+/// Time from thread creation to first dispatch, over every thread
+/// dispatched since boot or the last [`reset`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SpawnLatencyStats {
+    /// Threads dispatched for the first time.
+    pub spawns: u64,
+    /// Their latencies added up.
+    pub total: Duration,
+    /// The longest latency.
+    pub worst: Duration,
+}
+
+impl SpawnLatencyStats {
+    /// Average latency.
+    pub fn mean(&self) -> Duration {
+        match self.spawns {
+            0 => Duration::from_nanos(0),
+            n => Duration::from_nanos(self.total.as_nanos() / n),
+        }
+    }
+}
+
+static SPAWNS: AtomicU64 = AtomicU64::new(0);
+static SPAWN_LATENCY_NS: AtomicU64 = AtomicU64::new(0);
+static WORST_SPAWN_LATENCY_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Count a thread's first dispatch, `latency` after it was created.
+pub(crate) fn record_spawn_latency(latency: Duration) {
+    SPAWNS.fetch_add(1, Ordering::Relaxed);
+    SPAWN_LATENCY_NS.fetch_add(latency.as_nanos(), Ordering::Relaxed);
+    WORST_SPAWN_LATENCY_NS.fetch_max(latency.as_nanos(), Ordering::Relaxed);
+}
+
+/// Spawn-to-first-run latency so far.
+pub fn spawn_latency_stats() -> SpawnLatencyStats {
+    SpawnLatencyStats {
+        spawns: SPAWNS.load(Ordering::Relaxed),
+        total: Duration::from_nanos(SPAWN_LATENCY_NS.load(Ordering::Relaxed)),
+        worst: Duration::from_nanos(WORST_SPAWN_LATENCY_NS.load(Ordering::Relaxed)),
+    }
+}
+
+/// How long threads sat ready before being picked, over every pick since
+/// boot or the last [`reset`], and how often aging had to step in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StarvationStats {
+    /// The longest any thread waited in a run queue.
+    pub worst_wait: Duration,
+    /// Times a waiting thread was moved up a priority level.
+    pub aging_boosts: u64,
+}
+
+static WORST_RUN_QUEUE_WAIT_NS: AtomicU64 = AtomicU64::new(0);
+static AGING_BOOSTS: AtomicU64 = AtomicU64::new(0);
+
+/// Note a thread picked to run after `wait` in a run queue.
+pub(crate) fn record_run_queue_wait(wait: Duration) {
+    WORST_RUN_QUEUE_WAIT_NS.fetch_max(wait.as_nanos(), Ordering::Relaxed);
+}
+
+/// Count a waiting thread aged up a priority level.
+pub(crate) fn record_aging_boost() {
+    AGING_BOOSTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Run queue waits and aging so far.
+pub fn starvation_stats() -> StarvationStats {
+    StarvationStats {
+        worst_wait: Duration::from_nanos(WORST_RUN_QUEUE_WAIT_NS.load(Ordering::Relaxed)),
+        aging_boosts: AGING_BOOSTS.load(Ordering::Relaxed),
+    }
+}
+
+/// Where ready threads were queued relative to where they last ran, since
+/// boot or the last [`reset`]; see [`sched::placement`](crate::sched::placement).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PlacementStats {
+    /// Threads queued on a CPU other than the one they last ran on.
+    pub migrations: u64,
+    /// Threads a CPU took from another CPU's run queue.
+    pub steals: u64,
+}
+
+static MIGRATIONS: AtomicU64 = AtomicU64::new(0);
+static STEALS: AtomicU64 = AtomicU64::new(0);
+
+/// Count a thread queued away from the CPU it last ran on.
+pub(crate) fn record_migration() {
+    MIGRATIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a thread stolen from another CPU's run queue.
+pub(crate) fn record_steal() {
+    STEALS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Migrations and steals so far.
+pub fn placement_stats() -> PlacementStats {
+    PlacementStats {
+        migrations: MIGRATIONS.load(Ordering::Relaxed),
+        steals: STEALS.load(Ordering::Relaxed),
+    }
+}
+
+/// Idle accounting for `cpu`, or `None` past [`MAX_CPUS`].
+pub fn power_stats(cpu: CpuId) -> Option<PowerStats> {
+    CORES.get_for(cpu).map(|core| core.stats(Instant::now()))
+}
+
+/// Start every core's counters, the spawn latency, the starvation and the
+/// placement figures afresh from now.
+pub fn reset() {
+    let now = Instant::now();
+    CORES.iter().for_each(|core| core.reset(now));
+    SPAWNS.store(0, Ordering::Relaxed);
+    SPAWN_LATENCY_NS.store(0, Ordering::Relaxed);
+    WORST_SPAWN_LATENCY_NS.store(0, Ordering::Relaxed);
+    WORST_RUN_QUEUE_WAIT_NS.store(0, Ordering::Relaxed);
+    AGING_BOOSTS.store(0, Ordering::Relaxed);
+    MIGRATIONS.store(0, Ordering::Relaxed);
+    STEALS.store(0, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_windows_fill_utilization_histogram() {
+        let core = CoreMetrics::NEW;
+        let at = |ms: u64| Instant::from_nanos(ms * 1_000_000);
+        core.reset(at(0));
+
+        // 75 ms of 100 idle: 25 % busy
+        core.idled(at(10), at(85));
+        core.idled(at(100), at(100));
+        // A window with no idle at all, closed by the tick
+        core.sample(at(150));
+        core.sample(at(200));
+
+        let stats = core.stats(at(200));
+        assert_eq!(stats.wfi_count, 2);
+        assert_eq!(stats.idle, Duration::from_millis(75));
+        assert_eq!(stats.idle_percent(), 37);
+        assert_eq!(stats.histogram[2], 1);
+        assert_eq!(stats.histogram[BUCKETS - 1], 1);
+        assert_eq!(stats.histogram.iter().sum::<u64>(), 2);
+    }
+
+    #[test]
+    fn test_dispatch_latency_counters() {
+        let core = CoreMetrics::NEW;
+        core.dispatched(Duration::from_micros(50), false);
+        core.dispatched(Duration::from_micros(300), true);
+        core.dispatched(Duration::from_micros(120), false);
+
+        let stats = core.latency_stats();
+        assert_eq!((stats.dispatches, stats.violations), (3, 1));
+        assert_eq!(stats.worst, Duration::from_micros(300));
+
+        core.reset(Instant::from_nanos(0));
+        assert_eq!(core.latency_stats().dispatches, 0);
+        assert_eq!(core.latency_stats().worst, Duration::from_nanos(0));
+    }
+
+    #[test]
+    fn test_exceptions_classified_and_counted() {
+        use alloc::string::ToString;
+
+        let counters = ExceptionCounters::new();
+        for esr in [
+            0x9600_0045u64,
+            0x5600_0000,
+            0x5600_0001,
+            0x1FE0_0000,
+            0x0200_0000,
+        ] {
+            counters.record(ExceptionKind::from_esr(esr));
+        }
+        let stats = counters.snapshot();
+        assert_eq!(
+            (
+                stats.data_aborts,
+                stats.svcs,
+                stats.fpu_traps,
+                stats.undefined
+            ),
+            (1, 2, 1, 1)
+        );
+        assert_eq!(stats.total(), 5);
+        assert_eq!(stats.to_string(), "dabt=1 iabt=0 und=1 svc=2 fpu=1 other=0");
+    }
+}
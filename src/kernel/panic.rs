@@ -0,0 +1,150 @@
+//! What the kernel does when a thread panics.
+//!
+//! The crate's panic handler always records the panic first: the
+//! [crash log](super::crash_log), a
+//! [`ThreadPanicked`](super::events::KernelEvent::ThreadPanicked) event and
+//! the panic message on the UART. What happens next depends on the
+//! [`PanicPolicy`] set with
+//! [`Kernel::set_panic_policy`](super::Kernel::set_panic_policy):
+//!
+//! - [`Halt`](PanicPolicy::Halt) stops the system with interrupts masked,
+//!   leaving it for a debugger;
+//! - [`Reboot`](PanicPolicy::Reboot) dumps the kernel log and resets the
+//!   board through the [watchdog](crate::drivers::pm);
+//! - [`KillThread`](PanicPolicy::KillThread) terminates only the panicking
+//!   thread, whose joiners see it fail, and schedules the others.
+//!
+//! Killing a thread is only safe when nothing but that thread's own state
+//! is inconsistent, so `KillThread` halts instead when the panic comes from
+//! an interrupt handler, with preemption disabled, before the kernel runs a
+//! thread, or while another panic is being handled. Locks the thread held
+//! stay held: the policy suits threads that keep to their own data and
+//! message passing.
+
+use crate::arch::Arch;
+use crate::time::Duration;
+use portable_atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// Reaction to a panic, see the [module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum PanicPolicy {
+    /// Stop everything.
+    #[default]
+    Halt = 0,
+    /// Dump the log and reset the board.
+    Reboot = 1,
+    /// Terminate the panicking thread and keep running the others.
+    KillThread = 2,
+}
+
+/// How long the watchdog waits before resetting, so the UART can drain.
+const REBOOT_DELAY: Duration = Duration::from_millis(100);
+
+static POLICY: AtomicU8 = AtomicU8::new(PanicPolicy::Halt as u8);
+/// Set while the handler runs, to catch panics raised by the handler itself
+static HANDLING: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_policy(policy: PanicPolicy) {
+    POLICY.store(policy as u8, Ordering::Release);
+}
+
+/// The policy in effect.
+pub fn policy() -> PanicPolicy {
+    match POLICY.load(Ordering::Acquire) {
+        1 => PanicPolicy::Reboot,
+        2 => PanicPolicy::KillThread,
+        _ => PanicPolicy::Halt,
+    }
+}
+
+/// Report a panic and apply the [`policy`].
+///
+/// Called by the crate's panic handler; platforms with a panic handler of
+/// their own can call it too. Interrupts must be masked.
+pub fn handle(info: &core::panic::PanicInfo<'_>) -> ! {
+    if HANDLING.swap(true, Ordering::AcqRel) {
+        // Panicked while handling a panic: don't trust anything further
+        crate::pl011_println!("[PANIC] while panicking: {}", info);
+        halt();
+    }
+
+    super::crash_log::record_panic(info);
+    super::events::publish(super::events::KernelEvent::ThreadPanicked {
+        thread: crate::thread::current_thread_id(),
+    });
+
+    let policy = policy();
+    if policy == PanicPolicy::KillThread {
+        if let Some(kernel) = recoverable() {
+            crate::pl011_println!(
+                "[PANIC] {}; killing thread {}",
+                info,
+                crate::thread::current_thread_id()
+            );
+            HANDLING.store(false, Ordering::Release);
+            #[cfg(target_arch = "aarch64")]
+            unsafe {
+                // Unmask debug, SError and FIQ again; IRQs come back on the switch
+                core::arch::asm!("msr daifclr, #0xd", options(nomem, nostack));
+            }
+            kernel.kill_current();
+            // Nothing else could run: halt after all
+            HANDLING.store(true, Ordering::Release);
+            crate::arch::DefaultArch::disable_interrupts();
+        }
+    }
+
+    // Interrupts stay off from here on: nothing would drain the TX ring
+    crate::arch::uart_pl011::stop_tx();
+    super::log::emergency_flush();
+    crate::pl011_println!("[PANIC] {}", info);
+    #[cfg(feature = "fb-console")]
+    crate::drivers::framebuffer::console::show_panic(info);
+
+    if policy == PanicPolicy::Reboot {
+        crate::pl011_println!("[PANIC] rebooting");
+        crate::arch::uart_pl011::flush();
+        crate::drivers::pm::start_watchdog(REBOOT_DELAY);
+    }
+    halt();
+}
+
+/// The kernel, if the panic can be contained to the running thread.
+fn recoverable() -> Option<&'static dyn super::KernelOps> {
+    if crate::irq::in_irq() || crate::platform_timer::preemption_disabled() {
+        return None;
+    }
+    let kernel = super::global_ops()?;
+    kernel.current_thread()?;
+    Some(kernel)
+}
+
+fn halt() -> ! {
+    loop {
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            core::arch::asm!("wfe", options(nomem, nostack));
+        }
+        #[cfg(not(target_arch = "aarch64"))]
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_roundtrip() {
+        assert_eq!(policy(), PanicPolicy::Halt);
+        for p in [
+            PanicPolicy::KillThread,
+            PanicPolicy::Reboot,
+            PanicPolicy::Halt,
+        ] {
+            set_policy(p);
+            assert_eq!(policy(), p);
+        }
+    }
+}
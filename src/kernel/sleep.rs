@@ -0,0 +1,243 @@
+//! Sleeping threads, woken by the scheduler tick.
+//!
+//! [`sleep_for`] and [`sleep_until`] block the calling thread instead of
+//! spinning: it is taken off the CPU as `Blocked` and entered in a queue
+//! ordered by wake deadline. Every tick's scheduling pass
+//! ([`Kernel::handle_irq_preemption`](crate::Kernel::handle_irq_preemption))
+//! wakes the threads whose deadlines have passed, so a sleep ends at the
+//! first tick at or after its deadline, up to one tick interval late.
+//!
+//! Once the [high-resolution timer](crate::time::hrtimer) is initialized, a
+//! sleep also arms a timer for its deadline, so the thread is made ready
+//! within the timer's slack instead of at the next tick. Sleeps whose deadlines fall within
+//! each other's slack are woken by one interrupt; [`sleep_until`] uses the
+//! default [`slack`](crate::time::hrtimer::slack), and
+//! [`sleep_until_with_slack`] lets a sleep that tolerates more lateness
+//! ride along with its neighbours (or one that tolerates none opt out).
+//!
+//! Where nothing wakes sleepers (in
+//! [cooperative fallback](crate::platform_timer::cooperative_fallback)
+//! without a high-resolution timer, or before a thread runs) the caller
+//! yields until the deadline instead.
+//! Where there is no clock to reach the deadline by (off AArch64, where
+//! [`Instant::now`] is always zero, or before the counter frequency is
+//! known; see [`clock_running`]) sleeps return at once.
+//!
+//! The tick runs in interrupt context, so it wakes sleepers through
+//! [`Scheduler::try_wake_up`](crate::sched::Scheduler::try_wake_up),
+//! which doesn't allocate. A sleeper the scheduler can't take that way
+//! stays queued and is woken by a later tick.
+
+use crate::arch::{Arch, DefaultArch};
+use crate::thread::{Thread, ThreadId};
+use crate::time::hrtimer;
+use crate::time::{Duration, Instant};
+use alloc::vec::Vec;
+
+/// Sleeping threads with their wake deadlines in nanoseconds, earliest
+/// first; threads with equal deadlines in the order they went to sleep.
+struct SleepQueue {
+    sleepers: spin::Mutex<Vec<(u64, Thread)>>,
+}
+
+impl SleepQueue {
+    const fn new() -> Self {
+        Self {
+            sleepers: spin::Mutex::new(Vec::new()),
+        }
+    }
+
+    fn insert(&self, deadline: Instant, thread: Thread) {
+        let deadline = deadline.as_nanos();
+        let mut sleepers = self.sleepers.lock();
+        let at = sleepers.partition_point(|&(other, _)| other <= deadline);
+        sleepers.insert(at, (deadline, thread));
+    }
+
+    /// Drop the entry of `id`, if it is still queued.
+    fn remove(&self, id: ThreadId) {
+        self.sleepers.lock().retain(|(_, thread)| thread.id() != id);
+    }
+
+    /// Hand every thread due by `now` to `wake`, earliest first, until
+    /// it hands one back; that one and the rest stay queued.
+    fn expire(&self, now: Instant, mut wake: impl FnMut(Thread) -> Result<(), Thread>) {
+        let now = now.as_nanos();
+        let mut sleepers = match self.sleepers.try_lock() {
+            Some(sleepers) => sleepers,
+            // Being changed by the thread this interrupted; next tick
+            None => return,
+        };
+        let due = sleepers.partition_point(|&(deadline, _)| deadline <= now);
+        let mut woken = 0;
+        for (_, thread) in &sleepers[..due] {
+            if wake(thread.clone()).is_err() {
+                break;
+            }
+            woken += 1;
+        }
+        sleepers.drain(..woken);
+    }
+
+    fn len(&self) -> usize {
+        self.sleepers.lock().len()
+    }
+}
+
+static SLEEPERS: SleepQueue = SleepQueue::new();
+
+/// Block the calling thread for at least `duration`.
+pub fn sleep_for(duration: Duration) {
+    sleep_until(Instant::now() + duration);
+}
+
+/// Like [`sleep_for`], woken up to `slack` after `duration` if that lets
+/// it share a timer interrupt.
+pub fn sleep_for_with_slack(duration: Duration, slack: Duration) {
+    sleep_until_with_slack(Instant::now() + duration, slack);
+}
+
+/// Block the calling thread until `deadline` has passed.
+///
+/// Returns at once if it already has, or if there is no running clock to
+/// tell (see [`clock_running`]). May only be called from a thread, not
+/// from interrupt context.
+pub fn sleep_until(deadline: Instant) {
+    sleep_until_with_slack(deadline, hrtimer::slack());
+}
+
+/// Like [`sleep_until`], woken up to `slack` after `deadline` if that
+/// lets it share a timer interrupt.
+pub fn sleep_until_with_slack(deadline: Instant, slack: Duration) {
+    debug_assert!(!crate::irq::in_irq(), "sleep from interrupt context");
+    if !clock_running() {
+        return;
+    }
+    while Instant::now() < deadline {
+        let was_enabled = DefaultArch::interrupts_enabled();
+        DefaultArch::disable_interrupts();
+
+        let slept = match super::global_ops() {
+            Some(ops) if hrtimer::initialized() || tick_wakes_sleepers() => {
+                match ops.current_thread() {
+                    Some(thread) => {
+                        let id = thread.id();
+                        SLEEPERS.insert(deadline, thread);
+                        let timer = arm_wake_timer(deadline, slack);
+                        let woken = timer.is_some() || tick_wakes_sleepers();
+                        if woken {
+                            ops.block_current();
+                        }
+                        if let Some(timer) = timer {
+                            hrtimer::cancel(timer);
+                        }
+                        // Woken early by someone else, or never blocked: don't
+                        // leave an entry behind to wake a later block
+                        SLEEPERS.remove(id);
+                        woken
+                    }
+                    None => false,
+                }
+            }
+            _ => false,
+        };
+
+        if was_enabled {
+            DefaultArch::enable_interrupts();
+        }
+        if !slept {
+            super::yield_current();
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Arm a high-resolution timer to wake the sleepers due by `deadline`,
+/// if the timer is running and has a slot free.
+fn arm_wake_timer(deadline: Instant, slack: Duration) -> Option<hrtimer::HrTimerId> {
+    if !hrtimer::initialized() {
+        return None;
+    }
+    hrtimer::start_at_with_slack(deadline, slack, wake_due_sleepers, 0).ok()
+}
+
+fn wake_due_sleepers(_: usize) {
+    if let Some(ops) = super::global_ops() {
+        ops.wake_sleepers();
+    }
+}
+
+/// Number of threads asleep.
+pub fn sleeping() -> usize {
+    SLEEPERS.len()
+}
+
+/// Wake the sleepers due by `now` with `wake`, which hands back a thread
+/// it can't wake yet. Called from the tick's scheduling pass and from
+/// sleep timers.
+pub(crate) fn wake_expired(now: Instant, wake: impl FnMut(Thread) -> Result<(), Thread>) {
+    SLEEPERS.expire(now, wake);
+}
+
+/// Whether [`Instant::now`] advances, so a deadline can be reached.
+///
+/// Not off AArch64, where it is always zero, nor before the counter
+/// frequency is known.
+pub fn clock_running() -> bool {
+    #[cfg(target_arch = "aarch64")]
+    return crate::time::calibration::counter_frequency() != 0;
+
+    #[cfg(not(target_arch = "aarch64"))]
+    false
+}
+
+fn tick_wakes_sleepers() -> bool {
+    cfg!(target_arch = "aarch64") && !crate::platform_timer::cooperative_fallback()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::{StackPool, StackSizeClass};
+
+    #[test]
+    fn test_expire_wakes_due_threads_in_deadline_order() {
+        let pool = StackPool::new();
+        let thread = |id| {
+            let stack = pool.allocate(StackSizeClass::Small).unwrap();
+            Thread::new(unsafe { ThreadId::new_unchecked(id) }, stack, || {}, 128).0
+        };
+        let at = |ms: u64| Instant::from_nanos(ms * 1_000_000);
+        let queue = SleepQueue::new();
+        queue.insert(at(30), thread(1));
+        queue.insert(at(10), thread(2));
+        queue.insert(at(20), thread(3));
+        queue.insert(at(10), thread(4));
+        queue.remove(unsafe { ThreadId::new_unchecked(3) });
+
+        let mut woken = Vec::new();
+        let mut wake = |thread: Thread| {
+            woken.push(thread.id().as_u64());
+            Ok(())
+        };
+        queue.expire(at(9), &mut wake);
+        queue.expire(at(25), |thread| match thread.id().as_u64() {
+            // The scheduler has no room for it yet
+            4 => Err(thread),
+            _ => wake(thread),
+        });
+        assert_eq!(queue.len(), 2);
+        queue.expire(at(25), &mut wake);
+        assert_eq!(woken, [2, 4]);
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    #[test]
+    fn test_sleep_returns_without_clock() {
+        // Instant::now() never moves here, so waiting for it would spin forever
+        assert!(!clock_running());
+        sleep_until(Instant::from_nanos(u64::MAX));
+        sleep_for(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,166 @@
+//! Scheduling latency objectives.
+//!
+//! Register a [`LatencySlo`] such as "any ready thread at priority 200 or
+//! above must run within 200 µs" and the kernel checks it on every
+//! dispatch: the time from a thread becoming ready to it being switched to
+//! is compared against the limit. A violation
+//!
+//! - is counted in the [metrics](super::metrics::latency_stats) of the core
+//!   it happened on, together with the worst latency seen;
+//! - is remembered as the [`last_violation`];
+//! - sets the [`violated`] event flag, which a monitoring thread can wait on;
+//! - calls the [callback](set_callback), if one is registered.
+//!
+//! The callback runs in the switching path with interrupts disabled, under
+//! the same rules as the kernel's switch hooks: keep it short, and don't
+//! allocate, block or yield in it.
+//!
+//! A long run of violations usually means something keeps interrupts or
+//! preemption disabled; [`time::latency`](crate::time::latency) points at
+//! the culprit.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::kernel::slo::{self, LatencySlo};
+//!
+//! slo::set_slo(LatencySlo::new(Duration::from_micros(200), 200));
+//! slo::violated().wait();
+//! let v = slo::last_violation().unwrap();
+//! pl011_println!("thread {} waited {} ns", v.thread, v.latency.as_nanos());
+//! ```
+
+use super::metrics;
+use crate::sync::EventFlag;
+use crate::thread::ThreadId;
+use crate::time::{Duration, Instant};
+use portable_atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+/// Longest a ready thread at or above some priority may wait to run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySlo {
+    /// Allowed time from ready to running.
+    pub max_latency: Duration,
+    /// Lowest priority the objective applies to.
+    pub min_priority: u8,
+}
+
+impl LatencySlo {
+    pub const fn new(max_latency: Duration, min_priority: u8) -> Self {
+        Self {
+            max_latency,
+            min_priority,
+        }
+    }
+}
+
+/// One dispatch that missed the objective.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SloViolation {
+    /// The thread that waited too long.
+    pub thread: ThreadId,
+    /// Its priority when it was dispatched.
+    pub priority: u8,
+    /// How long it waited between becoming ready and running.
+    pub latency: Duration,
+}
+
+/// Called with every violation, from the switching path.
+pub type SloCallback = fn(SloViolation);
+
+/// Limit in nanoseconds; 0 while no objective is set.
+static MAX_LATENCY_NS: AtomicU64 = AtomicU64::new(0);
+static MIN_PRIORITY: AtomicU8 = AtomicU8::new(0);
+static CALLBACK: AtomicUsize = AtomicUsize::new(0);
+static VIOLATED: EventFlag = EventFlag::new();
+
+/// The last violation; the thread id is written last.
+static LAST_THREAD: AtomicUsize = AtomicUsize::new(0);
+static LAST_PRIORITY: AtomicU8 = AtomicU8::new(0);
+static LAST_LATENCY_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Start checking dispatches against `slo`.
+pub fn set_slo(slo: LatencySlo) {
+    MIN_PRIORITY.store(slo.min_priority, Ordering::Relaxed);
+    // A zero limit would mean "off"; one nanosecond is as strict as it gets
+    MAX_LATENCY_NS.store(slo.max_latency.as_nanos().max(1), Ordering::Release);
+}
+
+/// Stop checking. Counters and the last violation are kept.
+pub fn clear_slo() {
+    MAX_LATENCY_NS.store(0, Ordering::Release);
+}
+
+/// The objective being checked, if any.
+pub fn slo() -> Option<LatencySlo> {
+    match MAX_LATENCY_NS.load(Ordering::Acquire) {
+        0 => None,
+        max => Some(LatencySlo::new(
+            Duration::from_nanos(max),
+            MIN_PRIORITY.load(Ordering::Relaxed),
+        )),
+    }
+}
+
+/// Call `callback` on every violation from now on.
+pub fn set_callback(callback: SloCallback) {
+    CALLBACK.store(callback as usize, Ordering::Release);
+}
+
+pub fn clear_callback() {
+    CALLBACK.store(0, Ordering::Release);
+}
+
+/// Flag set on every violation. It stays set until cleared, so a monitor
+/// should [`clear`](EventFlag::clear) it before handling what it found.
+pub fn violated() -> &'static EventFlag {
+    &VIOLATED
+}
+
+/// The most recent violation, if there has been one.
+pub fn last_violation() -> Option<SloViolation> {
+    let thread = LAST_THREAD.load(Ordering::Acquire);
+    if thread == 0 {
+        return None;
+    }
+    Some(SloViolation {
+        thread: ThreadId::new(thread as u64),
+        priority: LAST_PRIORITY.load(Ordering::Relaxed),
+        latency: Duration::from_nanos(LAST_LATENCY_NS.load(Ordering::Relaxed)),
+    })
+}
+
+/// Check one dispatch of `thread`, ready since `ready_since`, against the
+/// objective. Called by the kernel just before switching to a thread.
+pub(crate) fn observe(thread: ThreadId, priority: u8, ready_since: Instant) {
+    let max = MAX_LATENCY_NS.load(Ordering::Acquire);
+    if max == 0 || priority < MIN_PRIORITY.load(Ordering::Relaxed) {
+        return;
+    }
+    let latency = Instant::now()
+        .as_nanos()
+        .saturating_sub(ready_since.as_nanos());
+    let missed = latency > max;
+    metrics::record_dispatch(crate::arch::cpu_id(), Duration::from_nanos(latency), missed);
+    if missed {
+        violation(SloViolation {
+            thread,
+            priority,
+            latency: Duration::from_nanos(latency),
+        });
+    }
+}
+
+fn violation(violation: SloViolation) {
+    LAST_PRIORITY.store(violation.priority, Ordering::Relaxed);
+    LAST_LATENCY_NS.store(violation.latency.as_nanos(), Ordering::Relaxed);
+    LAST_THREAD.store(violation.thread.get(), Ordering::Release);
+    VIOLATED.set();
+
+    let callback = CALLBACK.load(Ordering::Acquire);
+    if callback != 0 {
+        // Only ever stored from an SloCallback
+        let callback: SloCallback = unsafe { core::mem::transmute(callback) };
+        callback(violation);
+    }
+}
@@ -0,0 +1,220 @@
+//! Stall detection: spotting a core stuck with interrupts masked.
+//!
+//! A core that spins with IRQs masked, on a lock taken in the wrong order
+//! or a device that never answers, stops taking scheduler ticks and goes
+//! quiet without a word. Every tick records a heartbeat for its core: a
+//! tick counter, plus the thread it interrupted and that thread's PC and
+//! SP. [`check`] looks at each core's counter; one that hasn't moved for
+//! longer than the [threshold](set_threshold) is reported once over the
+//! UART with the last state it was seen in, which usually points straight
+//! at the loop it's stuck in:
+//!
+//! ```text
+//! STALL cpu0: no tick for 250 ms after 1834 ticks; last in thread T0005 at pc 0x81a44 sp 0x2ff80
+//! ```
+//!
+//! `check` has to run where the stuck core can't hold it off: on another
+//! core, with [`monitor`] as its main loop, or from an FIQ, which masking
+//! IRQs doesn't mask. Cores that have never ticked, such as parked
+//! secondaries, aren't watched.
+
+use crate::mem::percpu::MAX_CPUS;
+use crate::thread::ThreadId;
+use crate::time::{Duration, Instant};
+use core::fmt;
+use portable_atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// Silence after which a core counts as stalled, unless changed (100 ms).
+pub const DEFAULT_THRESHOLD: Duration = Duration::from_millis(100);
+
+/// What a core was doing at its last tick.
+struct Heartbeat {
+    ticks: AtomicU64,
+    /// Raw id of the interrupted thread, 0 for none
+    thread: AtomicU64,
+    pc: AtomicU64,
+    sp: AtomicU64,
+}
+
+crate::percpu! {
+    static HEARTBEATS: Heartbeat = Heartbeat {
+        ticks: AtomicU64::new(0),
+        thread: AtomicU64::new(0),
+        pc: AtomicU64::new(0),
+        sp: AtomicU64::new(0),
+    };
+}
+
+// Only used to initialise SEEN_TICKS and SEEN_AT_NS
+#[allow(clippy::declare_interior_mutable_const)]
+const UNSEEN: AtomicU64 = AtomicU64::new(0);
+
+/// The checker's view of each core: the count it last saw and when it
+/// last saw it change
+static SEEN_TICKS: [AtomicU64; MAX_CPUS] = [UNSEEN; MAX_CPUS];
+static SEEN_AT_NS: [AtomicU64; MAX_CPUS] = [UNSEEN; MAX_CPUS];
+/// Cores reported and not ticking since, bit n for CPU n
+static STALLED: AtomicU32 = AtomicU32::new(0);
+static THRESHOLD_NS: AtomicU64 = AtomicU64::new(DEFAULT_THRESHOLD.as_nanos());
+
+/// A core found not ticking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stall {
+    pub cpu: usize,
+    /// Ticks the core took before it went quiet
+    pub ticks: u64,
+    /// How long it has been quiet, at least
+    pub silent_for: Duration,
+    /// The thread its last tick interrupted, if it was running one
+    pub thread: Option<ThreadId>,
+    /// Where that thread was
+    pub pc: usize,
+    pub sp: usize,
+}
+
+impl fmt::Display for Stall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "STALL cpu{}: no tick for {} ms after {} ticks; last in ",
+            self.cpu,
+            self.silent_for.as_millis(),
+            self.ticks
+        )?;
+        match self.thread {
+            Some(thread) => write!(f, "thread {}", thread)?,
+            None => f.write_str("no thread")?,
+        }
+        write!(f, " at pc {:#x} sp {:#x}", self.pc, self.sp)
+    }
+}
+
+/// Count a tick on the calling core. Called from the timer interrupt.
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+pub(crate) fn heartbeat() {
+    #[cfg(target_arch = "aarch64")]
+    let (pc, sp) = {
+        // The interrupted thread's registers, saved by the IRQ entry
+        let ctx = crate::arch::aarch64::get_irq_save_context();
+        if ctx.is_null() {
+            (0, 0)
+        } else {
+            unsafe { ((*ctx).pc, (*ctx).sp) }
+        }
+    };
+    #[cfg(not(target_arch = "aarch64"))]
+    let (pc, sp) = (0, 0);
+
+    record(HEARTBEATS.get(), crate::thread::current_thread_id(), pc, sp);
+}
+
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+fn record(beat: &Heartbeat, thread: ThreadId, pc: u64, sp: u64) {
+    beat.thread.store(thread.as_u64(), Ordering::Relaxed);
+    beat.pc.store(pc, Ordering::Relaxed);
+    beat.sp.store(sp, Ordering::Relaxed);
+    beat.ticks.fetch_add(1, Ordering::Release);
+}
+
+/// Report cores silent for longer than `threshold`. Zero disables
+/// reporting.
+pub fn set_threshold(threshold: Duration) {
+    THRESHOLD_NS.store(threshold.as_nanos(), Ordering::Relaxed);
+}
+
+pub fn threshold() -> Duration {
+    Duration::from_nanos(THRESHOLD_NS.load(Ordering::Relaxed))
+}
+
+/// Look for stalled cores as of `now`, printing each newly stalled one to
+/// the UART. Returns the cores currently stalled, bit n for CPU n.
+///
+/// A core is reported once per stall; once it ticks again it is watched
+/// afresh.
+pub fn check(now: Instant) -> u32 {
+    check_with(now, |stall| crate::pl011_println!("{}", stall))
+}
+
+fn check_with(now: Instant, mut report: impl FnMut(&Stall)) -> u32 {
+    let threshold = THRESHOLD_NS.load(Ordering::Relaxed);
+    for (cpu, beat) in HEARTBEATS.iter().enumerate() {
+        let bit = 1 << cpu;
+        let ticks = beat.ticks.load(Ordering::Acquire);
+        if ticks == 0 {
+            continue;
+        }
+        if ticks != SEEN_TICKS[cpu].swap(ticks, Ordering::Relaxed) {
+            SEEN_AT_NS[cpu].store(now.as_nanos(), Ordering::Relaxed);
+            STALLED.fetch_and(!bit, Ordering::Relaxed);
+            continue;
+        }
+
+        let silent_for = now.saturating_duration_since(Instant::from_nanos(
+            SEEN_AT_NS[cpu].load(Ordering::Relaxed),
+        ));
+        if threshold == 0 || silent_for.as_nanos() <= threshold {
+            continue;
+        }
+        if STALLED.fetch_or(bit, Ordering::Relaxed) & bit == 0 {
+            let thread = beat.thread.load(Ordering::Relaxed);
+            report(&Stall {
+                cpu,
+                ticks,
+                silent_for,
+                thread: (thread != 0).then(|| ThreadId::new(thread)),
+                pc: beat.pc.load(Ordering::Relaxed) as usize,
+                sp: beat.sp.load(Ordering::Relaxed) as usize,
+            });
+        }
+    }
+    STALLED.load(Ordering::Relaxed)
+}
+
+/// Watch the other cores forever, checking every `period`.
+///
+/// Meant as the main loop of a core given over to monitoring, which must
+/// not be one of the cores it watches.
+pub fn monitor(period: Duration) -> ! {
+    loop {
+        let now = Instant::now();
+        check(now);
+        let next = now + period;
+        while Instant::now() < next {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_silent_core_reported_once() {
+        let at = |ms: u64| Instant::from_nanos(ms * 1_000_000);
+        let thread = ThreadId::new(5);
+        let beat = HEARTBEATS.get_for(1).unwrap();
+        let mut stalls = alloc::vec::Vec::new();
+
+        record(beat, thread, 0x81a44, 0x2ff80);
+        check_with(at(1000), |stall| stalls.push(*stall));
+        record(beat, thread, 0x81a44, 0x2ff80);
+        check_with(at(1050), |stall| stalls.push(*stall));
+        assert!(stalls.is_empty());
+
+        // Quiet past the threshold: reported once
+        assert_ne!(check_with(at(1200), |stall| stalls.push(*stall)) & 0b10, 0);
+        check_with(at(1300), |stall| stalls.push(*stall));
+        assert_eq!(stalls.len(), 1);
+        assert_eq!(stalls[0].cpu, 1);
+        assert_eq!(stalls[0].thread, Some(thread));
+        assert_eq!(
+            alloc::format!("{}", stalls[0]),
+            "STALL cpu1: no tick for 150 ms after 2 ticks; last in thread T0005 at pc 0x81a44 sp 0x2ff80"
+        );
+
+        // Ticking again clears it
+        record(beat, thread, 0, 0);
+        assert_eq!(check_with(at(1400), |stall| stalls.push(*stall)) & 0b10, 0);
+    }
+}
@@ -0,0 +1,367 @@
+//! Supervisor that restarts workers when they exit.
+//!
+//! Register a worker with a [`ChildSpec`]: a factory that builds the
+//! worker's entry closure, the priority to run it at and a
+//! [`RestartPolicy`]. The supervisor spawns the worker straight away and,
+//! each time [`poll`](Supervisor::poll) runs, respawns workers that have
+//! finished, subject to the policy, a restart limit and an exponential
+//! backoff between consecutive restarts.
+//!
+//! Run [`Supervisor::run`] on a thread of its own (any priority works, the
+//! loop yields between passes) or call [`poll`](Supervisor::poll) from an
+//! existing housekeeping loop.
+//!
+//! The crate builds with `panic = "abort"`, so a panicking worker halts the
+//! system rather than exiting; supervision covers workers that return,
+//! whether deliberately or because they gave up on an error.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::kernel::supervisor::{ChildSpec, RestartPolicy, Supervisor};
+//! use preemptive_threads::time::Duration;
+//!
+//! static SUPERVISOR: Lazy<Supervisor<'static, DefaultArch, RoundRobinScheduler>> =
+//!     Lazy::new(|| Supervisor::new(&KERNEL));
+//!
+//! SUPERVISOR.supervise(
+//!     ChildSpec::new(|| move || sensor_loop())
+//!         .priority(200)
+//!         .max_restarts(5)
+//!         .backoff(Duration::from_millis(10)),
+//! )?;
+//! KERNEL.spawn(|| SUPERVISOR.run(), 1)?;
+//! ```
+
+use super::{DefaultConfig, Kernel, KernelConfig};
+use crate::arch::{self, Arch};
+use crate::errors::SpawnError;
+use crate::mem::ArcLite;
+use crate::sched::Scheduler;
+use crate::thread::JoinHandle;
+use crate::time::{Duration, Instant};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// Largest multiple of the base backoff a restart waits.
+const MAX_BACKOFF_SHIFT: u32 = 6;
+
+/// Shared so a poll can call it after letting go of the children lock
+type Factory = ArcLite<Box<dyn Fn() -> Box<dyn FnOnce() + Send> + Send + Sync>>;
+
+/// When a finished worker is restarted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Restart whenever the worker exits.
+    Always,
+    /// Restart only if the worker didn't run to completion, as reported by
+    /// [`JoinHandle::join`].
+    OnFailure,
+}
+
+/// How to run and restart one worker.
+pub struct ChildSpec {
+    factory: Factory,
+    priority: u8,
+    policy: RestartPolicy,
+    max_restarts: Option<u32>,
+    backoff: Duration,
+}
+
+impl ChildSpec {
+    /// A worker whose entry closure is built by `factory` on every start.
+    ///
+    /// Defaults to priority 128, [`RestartPolicy::Always`], no restart limit
+    /// and no backoff.
+    pub fn new<F, W>(factory: F) -> Self
+    where
+        F: Fn() -> W + Send + Sync + 'static,
+        W: FnOnce() + Send + 'static,
+    {
+        Self {
+            factory: ArcLite::new(Box::new(move || Box::new(factory()))),
+            priority: 128,
+            policy: RestartPolicy::Always,
+            max_restarts: None,
+            backoff: Duration::from_nanos(0),
+        }
+    }
+
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn policy(mut self, policy: RestartPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Give up on the worker after `max` restarts.
+    pub fn max_restarts(mut self, max: u32) -> Self {
+        self.max_restarts = Some(max);
+        self
+    }
+
+    /// Wait `delay` before the first restart, doubling on each further
+    /// restart up to 64 times `delay`.
+    pub fn backoff(mut self, delay: Duration) -> Self {
+        self.backoff = delay;
+        self
+    }
+}
+
+/// Handle to a worker registered with [`Supervisor::supervise`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChildId(usize);
+
+/// Restart counters for a supervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SupervisorStats {
+    /// Workers registered.
+    pub children: usize,
+    /// Workers currently running (or waiting to run).
+    pub running: usize,
+    /// Restarts performed across all workers.
+    pub restarts: u64,
+    /// Workers that exhausted their restart limit or exited for good.
+    pub stopped: usize,
+}
+
+enum ChildState {
+    Running(JoinHandle),
+    /// Waiting out the backoff before being spawned again
+    Restarting(Instant),
+    /// Being spawned again by a poll
+    Starting,
+    Stopped,
+}
+
+struct Child {
+    spec: ChildSpec,
+    state: ChildState,
+    restarts: u32,
+}
+
+/// Watches workers and restarts them according to their [`ChildSpec`].
+pub struct Supervisor<'k, A: Arch, S: Scheduler, C: KernelConfig = DefaultConfig> {
+    kernel: &'k Kernel<A, S, C>,
+    children: spin::Mutex<Vec<Child>>,
+}
+
+impl<'k, A: Arch, S: Scheduler, C: KernelConfig> Supervisor<'k, A, S, C> {
+    pub fn new(kernel: &'k Kernel<A, S, C>) -> Self {
+        Self {
+            kernel,
+            children: spin::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Spawn a worker and keep it running.
+    pub fn supervise(&self, spec: ChildSpec) -> Result<ChildId, SpawnError> {
+        let handle = self.kernel.spawn((spec.factory)(), spec.priority)?;
+        let child = Child {
+            spec,
+            state: ChildState::Running(handle),
+            restarts: 0,
+        };
+        arch::without_interrupts(|| {
+            let mut children = self.children.lock();
+            children.push(child);
+            Ok(ChildId(children.len() - 1))
+        })
+    }
+
+    /// Check every worker once, restarting those that are due.
+    ///
+    /// Returns the number of workers restarted.
+    pub fn poll(&self) -> usize {
+        self.poll_at(Instant::now())
+    }
+
+    /// Poll forever, yielding between passes.
+    pub fn run(&self) -> ! {
+        loop {
+            self.poll();
+            crate::yield_now();
+        }
+    }
+
+    /// How often `child` has been restarted.
+    pub fn restarts(&self, child: ChildId) -> u32 {
+        arch::without_interrupts(|| self.children.lock().get(child.0).map_or(0, |c| c.restarts))
+    }
+
+    pub fn stats(&self) -> SupervisorStats {
+        arch::without_interrupts(|| {
+            let children = self.children.lock();
+            let mut stats = SupervisorStats {
+                children: children.len(),
+                ..SupervisorStats::default()
+            };
+            for child in children.iter() {
+                stats.restarts += u64::from(child.restarts);
+                match child.state {
+                    ChildState::Running(_) => stats.running += 1,
+                    ChildState::Restarting(_) | ChildState::Starting => {}
+                    ChildState::Stopped => stats.stopped += 1,
+                }
+            }
+            stats
+        })
+    }
+
+    /// [`poll`](Self::poll) as of `now`.
+    ///
+    /// Which workers are due is decided under the children lock, but their
+    /// factories, which are user code, and the spawns, which allocate, run
+    /// after it is released.
+    fn poll_at(&self, now: Instant) -> usize {
+        let due: Vec<(usize, Factory, u8)> = arch::without_interrupts(|| {
+            let mut children = self.children.lock();
+            children
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(index, child)| {
+                    Self::due(child, now)
+                        .then(|| (index, child.spec.factory.clone(), child.spec.priority))
+                })
+                .collect()
+        });
+
+        due.into_iter()
+            .filter(|(index, factory, priority)| {
+                let spawned = self.kernel.spawn(factory(), *priority);
+                arch::without_interrupts(|| {
+                    Self::started(&mut self.children.lock()[*index], spawned, now)
+                })
+            })
+            .count()
+    }
+
+    /// Advance one worker's state machine; returns whether it is to be
+    /// respawned now, leaving it [`Starting`](ChildState::Starting) if so.
+    fn due(child: &mut Child, now: Instant) -> bool {
+        if let ChildState::Running(handle) = &child.state {
+            let completed = match handle.try_join() {
+                None => return false,
+                Some(result) => result.is_ok(),
+            };
+            let wanted = match child.spec.policy {
+                RestartPolicy::Always => true,
+                RestartPolicy::OnFailure => !completed,
+            };
+            let allowed = child
+                .spec
+                .max_restarts
+                .map_or(true, |max| child.restarts < max);
+            child.state = if wanted && allowed {
+                ChildState::Restarting(now + Self::backoff(&child.spec, child.restarts))
+            } else {
+                ChildState::Stopped
+            };
+        }
+
+        match child.state {
+            ChildState::Restarting(at) if now >= at => {
+                child.state = ChildState::Starting;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Record the outcome of respawning a [`due`](Self::due) worker;
+    /// returns whether it is running again.
+    fn started(child: &mut Child, spawned: Result<JoinHandle, SpawnError>, now: Instant) -> bool {
+        match spawned {
+            Ok(handle) => {
+                child.state = ChildState::Running(handle);
+                child.restarts += 1;
+                true
+            }
+            Err(_) => {
+                // Out of stacks or threads: try again after another backoff
+                child.state =
+                    ChildState::Restarting(now + Self::backoff(&child.spec, child.restarts));
+                false
+            }
+        }
+    }
+
+    fn backoff(spec: &ChildSpec, restarts: u32) -> Duration {
+        let shift = restarts.min(MAX_BACKOFF_SHIFT);
+        Duration::from_nanos(spec.backoff.as_nanos() << shift)
+    }
+}
+
+#[cfg(all(test, feature = "std-shim"))]
+mod tests {
+    use super::*;
+    use crate::arch::NoOpArch;
+    use crate::sched::RoundRobinScheduler;
+    use crate::thread::ThreadState;
+
+    fn exit_normally(child: &Child) {
+        if let ChildState::Running(handle) = &child.state {
+            crate::thread::RunningRef(handle.thread()).finish();
+        }
+    }
+
+    #[test]
+    fn test_restarts_until_limit() {
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        kernel.init().unwrap();
+        let supervisor = Supervisor::new(&kernel);
+
+        let always = supervisor
+            .supervise(ChildSpec::new(|| || {}).max_restarts(2))
+            .unwrap();
+        let on_failure = supervisor
+            .supervise(ChildSpec::new(|| || {}).policy(RestartPolicy::OnFailure))
+            .unwrap();
+        assert_eq!(supervisor.poll(), 0);
+
+        for _ in 0..3 {
+            supervisor.children.lock().iter().for_each(exit_normally);
+            supervisor.poll();
+        }
+        assert_eq!(supervisor.restarts(always), 2);
+        assert_eq!(supervisor.restarts(on_failure), 0);
+        assert_eq!(
+            supervisor.stats(),
+            SupervisorStats {
+                children: 2,
+                running: 0,
+                restarts: 2,
+                stopped: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_failed_worker_restarted_after_backoff() {
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        kernel.init().unwrap();
+        let supervisor = Supervisor::new(&kernel);
+        let child = supervisor
+            .supervise(
+                ChildSpec::new(|| || {})
+                    .policy(RestartPolicy::OnFailure)
+                    .backoff(Duration::from_millis(10)),
+            )
+            .unwrap();
+
+        // Ended without a join result
+        if let ChildState::Running(handle) = &supervisor.children.lock()[0].state {
+            handle.thread().set_state(ThreadState::Finished);
+        }
+        let start = Instant::from_nanos(0);
+        assert_eq!(supervisor.poll_at(start), 0);
+        assert_eq!(supervisor.poll_at(start + Duration::from_millis(5)), 0);
+        assert_eq!(supervisor.poll_at(start + Duration::from_millis(10)), 1);
+        assert_eq!(supervisor.restarts(child), 1);
+    }
+}
@@ -0,0 +1,278 @@
+//! Watched memory words: a poor man's watchpoint.
+//!
+//! Without a debugger attached there is no hardware watchpoint to tell who
+//! changed a piece of shared state. Registering the word with [`watch`]
+//! has the scheduler tick sample it instead: each time its value differs
+//! from the last sample, the kernel log gets a line with the old and new
+//! value, the time and the thread that was running.
+//!
+//! ```ignore
+//! use preemptive_threads::kernel::watch;
+//!
+//! static STATE: AtomicU32 = AtomicU32::new(0);
+//!
+//! let id = watch::watch(&STATE, "motor state")?;
+//! // ... log: "watch motor state: 0x0 -> 0x2 at 1250000 ns, thread 3"
+//! watch::unwatch(id);
+//! ```
+//!
+//! Words that aren't atomics, such as a device register or a field of a
+//! `static mut`, can be watched by address with [`watch_raw`].
+//!
+//! Sampling only sees the value at each tick: a change undone within one
+//! tick is missed, and the thread named is the one running when the tick
+//! noticed, which is not necessarily the one that wrote. [`MAX_WATCHES`]
+//! words can be watched at once.
+
+use crate::errors::ResourceError;
+use crate::kernel::log::Level;
+use crate::time::Instant;
+use portable_atomic::{AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+/// Words that can be watched at once.
+pub const MAX_WATCHES: usize = 16;
+
+/// Size of a watched word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum WatchWidth {
+    U8 = 1,
+    U16 = 2,
+    U32 = 4,
+    U64 = 8,
+}
+
+impl WatchWidth {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => WatchWidth::U8,
+            2 => WatchWidth::U16,
+            4 => WatchWidth::U32,
+            _ => WatchWidth::U64,
+        }
+    }
+
+    /// Read `addr` as a word of this width.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be readable and aligned for the width.
+    unsafe fn read(self, addr: usize) -> u64 {
+        unsafe {
+            match self {
+                WatchWidth::U8 => core::ptr::read_volatile(addr as *const u8) as u64,
+                WatchWidth::U16 => core::ptr::read_volatile(addr as *const u16) as u64,
+                WatchWidth::U32 => core::ptr::read_volatile(addr as *const u32) as u64,
+                WatchWidth::U64 => core::ptr::read_volatile(addr as *const u64),
+            }
+        }
+    }
+}
+
+/// A word [`watch`] accepts: an atomic, so changes by other threads are
+/// expected and reading it concurrently is sound.
+pub trait Watchable: Sync {
+    /// Size of the word.
+    const WIDTH: WatchWidth;
+}
+
+macro_rules! watchable {
+    ($($ty:ty => $width:ident),* $(,)?) => {
+        $(impl Watchable for $ty {
+            const WIDTH: WatchWidth = WatchWidth::$width;
+        })*
+    };
+}
+
+watchable! {
+    portable_atomic::AtomicBool => U8,
+    portable_atomic::AtomicU8 => U8,
+    portable_atomic::AtomicU16 => U16,
+    portable_atomic::AtomicU32 => U32,
+    portable_atomic::AtomicU64 => U64,
+    portable_atomic::AtomicI32 => U32,
+    portable_atomic::AtomicI64 => U64,
+}
+
+#[cfg(target_pointer_width = "64")]
+watchable! {
+    portable_atomic::AtomicUsize => U64,
+    portable_atomic::AtomicIsize => U64,
+}
+
+/// Handle to a registered watch, for [`unwatch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchId {
+    slot: u16,
+    generation: u16,
+}
+
+struct Slot {
+    /// Watched address, 0 while the slot is free
+    addr: AtomicUsize,
+    width: AtomicU8,
+    /// Label for the log, as pointer and length of a `&'static str`
+    name_ptr: AtomicUsize,
+    name_len: AtomicUsize,
+    last: AtomicU64,
+    changes: AtomicU32,
+    generation: AtomicU32,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            addr: AtomicUsize::new(0),
+            width: AtomicU8::new(0),
+            name_ptr: AtomicUsize::new(0),
+            name_len: AtomicUsize::new(0),
+            last: AtomicU64::new(0),
+            changes: AtomicU32::new(0),
+            generation: AtomicU32::new(0),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        let ptr = self.name_ptr.load(Ordering::Relaxed) as *const u8;
+        let len = self.name_len.load(Ordering::Relaxed);
+        // Stored from a &'static str before `addr` was published
+        unsafe { core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len)) }
+    }
+}
+
+// Only used to initialise SLOTS
+#[allow(clippy::declare_interior_mutable_const)]
+const UNWATCHED: Slot = Slot::new();
+static SLOTS: [Slot; MAX_WATCHES] = [UNWATCHED; MAX_WATCHES];
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Watch an atomic word, logging `name` with each change.
+///
+/// Fails with [`ResourceError::ResourceUnavailable`] if
+/// [`MAX_WATCHES`] words are watched already.
+pub fn watch<W: Watchable>(word: &'static W, name: &'static str) -> Result<WatchId, ResourceError> {
+    // A live, aligned atomic for the rest of the program
+    unsafe { watch_raw(word as *const W as usize, W::WIDTH, name) }
+}
+
+/// Watch the word of `width` at `addr`.
+///
+/// # Safety
+///
+/// `addr` must stay readable and aligned for `width` until the watch is
+/// removed, and reading it at any moment, from the tick, must be harmless
+/// (no read side effects).
+pub unsafe fn watch_raw(
+    addr: usize,
+    width: WatchWidth,
+    name: &'static str,
+) -> Result<WatchId, ResourceError> {
+    for (index, slot) in SLOTS.iter().enumerate() {
+        if slot.addr.load(Ordering::Relaxed) != 0 {
+            continue;
+        }
+        // Claim it with a placeholder so the tick skips it until set up
+        if slot
+            .addr
+            .compare_exchange(0, usize::MAX, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            continue;
+        }
+        slot.width.store(width as u8, Ordering::Relaxed);
+        slot.name_ptr
+            .store(name.as_ptr() as usize, Ordering::Relaxed);
+        slot.name_len.store(name.len(), Ordering::Relaxed);
+        slot.last
+            .store(unsafe { width.read(addr) }, Ordering::Relaxed);
+        slot.changes.store(0, Ordering::Relaxed);
+        let generation = slot.generation.load(Ordering::Relaxed) as u16;
+        slot.addr.store(addr, Ordering::Release);
+        ACTIVE.fetch_add(1, Ordering::Relaxed);
+        return Ok(WatchId {
+            slot: index as u16,
+            generation,
+        });
+    }
+    Err(ResourceError::ResourceUnavailable)
+}
+
+/// Stop watching. Returns `false` if the watch was already removed.
+pub fn unwatch(id: WatchId) -> bool {
+    let Some(slot) = SLOTS.get(id.slot as usize) else {
+        return false;
+    };
+    if slot.generation.load(Ordering::Relaxed) as u16 != id.generation {
+        return false;
+    }
+    let addr = slot.addr.load(Ordering::Relaxed);
+    if addr == 0 || addr == usize::MAX {
+        return false;
+    }
+    slot.generation.fetch_add(1, Ordering::Relaxed);
+    slot.addr.store(0, Ordering::Release);
+    ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    true
+}
+
+/// Changes seen on a watched word so far, `None` once it is unwatched.
+pub fn changes(id: WatchId) -> Option<u32> {
+    let slot = SLOTS.get(id.slot as usize)?;
+    let live = slot.generation.load(Ordering::Relaxed) as u16 == id.generation
+        && !matches!(slot.addr.load(Ordering::Acquire), 0 | usize::MAX);
+    live.then(|| slot.changes.load(Ordering::Relaxed))
+}
+
+/// Sample every watched word, logging the ones that changed. Called from
+/// the tick.
+pub fn sample(now: Instant) {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    for slot in &SLOTS {
+        let addr = slot.addr.load(Ordering::Acquire);
+        if addr == 0 || addr == usize::MAX {
+            continue;
+        }
+        let width = WatchWidth::from_u8(slot.width.load(Ordering::Relaxed));
+        // Readable while registered, by the contract of `watch_raw`
+        let value = unsafe { width.read(addr) };
+        let old = slot.last.swap(value, Ordering::Relaxed);
+        if old == value {
+            continue;
+        }
+        slot.changes.fetch_add(1, Ordering::Relaxed);
+        crate::klog!(
+            Level::Info,
+            "watch {}: {:#x} -> {:#x} at {} ns, thread {}",
+            slot.name(),
+            old,
+            value,
+            now.as_nanos(),
+            crate::thread::current_thread_id()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tick_sample_counts_changes() {
+        static WORD: AtomicU32 = AtomicU32::new(1);
+
+        let id = watch(&WORD, "test word").unwrap();
+        sample(Instant::from_nanos(10));
+        assert_eq!(changes(id), Some(0));
+
+        WORD.store(2, Ordering::Relaxed);
+        sample(Instant::from_nanos(20));
+        sample(Instant::from_nanos(30));
+        assert_eq!(changes(id), Some(1));
+
+        assert!(unwatch(id));
+        assert!(!unwatch(id));
+        assert_eq!(changes(id), None);
+    }
+}
@@ -35,7 +35,7 @@
 //!         loop { /* thread work */ }
 //!     }, 128).expect("Failed to spawn thread");
 //!
-//!     KERNEL.start_first_thread();
+//!     KERNEL.start_first_thread().expect("Failed to start scheduler");
 //! }
 //! ```
 //!
@@ -49,11 +49,15 @@
 
 // Core modules
 pub mod arch;
+pub mod drivers;
 pub mod errors;
+pub mod fs;
+pub mod irq;
 pub mod kernel;
 pub mod mem;
 pub mod platform_timer;
 pub mod sched;
+pub mod sync;
 pub mod thread;
 pub mod time;
 
@@ -68,18 +72,14 @@ use core::panic::PanicInfo;
 
 #[cfg(all(not(test), not(feature = "std-shim")))]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    // On panic, disable interrupts and halt
+fn panic(info: &PanicInfo) -> ! {
+    // Mask interrupts; the panic policy decides what happens next
     #[cfg(target_arch = "aarch64")]
     unsafe {
         core::arch::asm!("msr daifset, #0xf", options(nomem, nostack));
     }
-    loop {
-        #[cfg(target_arch = "aarch64")]
-        unsafe {
-            core::arch::asm!("wfe", options(nomem, nostack));
-        }
-    }
+
+    kernel::panic::handle(info)
 }
 
 // ============================================================================
@@ -91,21 +91,24 @@ pub use arch::{Arch, DefaultArch};
 
 // Kernel
 pub use kernel::Kernel;
+pub use platform_timer::{request_reschedule, PreemptionMode};
 
 // Scheduler
 pub use sched::{RoundRobinScheduler, Scheduler};
 
 // Threads
-pub use thread::{JoinHandle, Thread, ThreadBuilder, ThreadId, ThreadState};
+pub use thread::{
+    stack_remaining, JoinHandle, Thread, ThreadBuilder, ThreadConfig, ThreadId, ThreadState,
+};
 
 // Memory management
-pub use mem::{Stack, StackPool, StackSizeClass};
+pub use mem::{Stack, StackClass, StackPool, StackScrub, StackSizeClass, StackSpec};
 
 // Time
 pub use time::{Duration, Instant};
 
 // Errors
-pub use errors::{ThreadError, ThreadResult, SpawnError};
+pub use errors::{KernelError, SpawnError, ThreadError, ThreadResult};
 
 // ============================================================================
 // Convenience Functions
@@ -121,6 +124,15 @@ pub fn yield_now() {
     kernel::yield_current();
 }
 
+/// Yield the CPU directly to `target`, donating the rest of the time slice.
+///
+/// Returns `false` without yielding if `target` isn't ready to run or no
+/// kernel is registered. See [`Kernel::yield_to`].
+#[inline]
+pub fn yield_to(target: ThreadId) -> bool {
+    kernel::global_ops().is_some_and(|kernel| kernel.yield_to(target))
+}
+
 #[inline]
 pub fn finish_current() {
     kernel::finish_current();
@@ -2,12 +2,31 @@
 //!
 //! This provides an Arc-like abstraction using portable atomics that works
 //! in no_std environments and supports manual reference count management.
+//!
+//! # Ordering guarantees
+//!
+//! Count increments are `AcqRel` and decrements are `AcqRel`, so everything
+//! a thread did through its handle happens-before the value is dropped by
+//! whichever thread releases the last reference. [`ArcLite::get_mut`] and
+//! [`ArcLite::try_unwrap`] observe the count with `Acquire`, so once they
+//! succeed all writes made through other, since-dropped handles are
+//! visible.
+//!
+//! # Overflow
+//!
+//! The count saturates at `isize::MAX`; exceeding it (only possible by
+//! leaking handles) panics, which aborts under the crate's `panic = "abort"`
+//! profile, rather than wrapping and freeing the value while still in use.
 
 use core::alloc::Layout;
+use core::fmt;
 use core::ops::Deref;
 use core::ptr::NonNull;
 use portable_atomic::{AtomicUsize, Ordering};
 
+/// Reference count above which cloning is refused.
+const MAX_REFCOUNT: usize = isize::MAX as usize;
+
 /// A lightweight atomic reference counter similar to Arc but with manual control.
 ///
 /// This provides thread-safe reference counting without requiring std::sync::Arc.
@@ -23,6 +42,9 @@ struct ArcLiteInner<T> {
 }
 
 impl<T> ArcLite<T> {
+    /// Bytes each allocation takes: the data and its reference count.
+    pub(crate) const ALLOCATION_SIZE: usize = core::mem::size_of::<ArcLiteInner<T>>();
+
     /// Create a new ArcLite with the given data.
     ///
     /// # Arguments
@@ -37,7 +59,7 @@ impl<T> ArcLite<T> {
         // For now, we'll use a simple Box-like allocation approach
         // In a real implementation, we'd need a proper allocator
         let layout = Layout::new::<ArcLiteInner<T>>();
-        
+
         // TODO: Replace with proper no_std allocator
         // For now, this will only work with std-shim feature
         #[cfg(feature = "std-shim")]
@@ -51,17 +73,20 @@ impl<T> ArcLite<T> {
             }
 
             unsafe {
-                core::ptr::write(alloc_ptr, ArcLiteInner {
-                    count: AtomicUsize::new(1),
-                    data,
-                });
+                core::ptr::write(
+                    alloc_ptr,
+                    ArcLiteInner {
+                        count: AtomicUsize::new(1),
+                        data,
+                    },
+                );
             }
 
             Self {
                 ptr: unsafe { NonNull::new_unchecked(alloc_ptr) },
             }
         }
-        
+
         #[cfg(not(feature = "std-shim"))]
         {
             // Use the global allocator in bare-metal environments
@@ -74,10 +99,13 @@ impl<T> ArcLite<T> {
             }
 
             unsafe {
-                core::ptr::write(alloc_ptr, ArcLiteInner {
-                    count: AtomicUsize::new(1),
-                    data,
-                });
+                core::ptr::write(
+                    alloc_ptr,
+                    ArcLiteInner {
+                        count: AtomicUsize::new(1),
+                        data,
+                    },
+                );
             }
 
             Self {
@@ -85,7 +113,7 @@ impl<T> ArcLite<T> {
             }
         }
     }
-    
+
     /// Increment the reference count.
     ///
     /// This is useful for intrusive data structures where you need manual
@@ -97,12 +125,16 @@ impl<T> ArcLite<T> {
     pub fn try_inc(&self) -> bool {
         let inner = unsafe { self.ptr.as_ref() };
         let mut current = inner.count.load(Ordering::Acquire);
-        
+
         loop {
             if current == 0 {
                 return false; // Object is being destroyed
             }
-            
+
+            if current >= MAX_REFCOUNT {
+                refcount_overflow();
+            }
+
             match inner.count.compare_exchange_weak(
                 current,
                 current + 1,
@@ -114,7 +146,7 @@ impl<T> ArcLite<T> {
             }
         }
     }
-    
+
     /// Decrement the reference count.
     ///
     /// If the count reaches zero, the object will be deallocated.
@@ -125,17 +157,17 @@ impl<T> ArcLite<T> {
     pub fn dec(&self) -> usize {
         let inner = unsafe { self.ptr.as_ref() };
         let prev_count = inner.count.fetch_sub(1, Ordering::AcqRel);
-        
+
         if prev_count == 1 {
             // We were the last reference, deallocate
             unsafe {
                 self.deallocate();
             }
         }
-        
+
         prev_count
     }
-    
+
     /// Get the current reference count.
     ///
     /// Note that this value may change immediately after being read in
@@ -144,42 +176,106 @@ impl<T> ArcLite<T> {
         let inner = unsafe { self.ptr.as_ref() };
         inner.count.load(Ordering::Acquire)
     }
-    
+
+    /// Get the number of handles to the value.
+    ///
+    /// Same as [`ref_count`](Self::ref_count), named after `Arc`.
+    pub fn strong_count(&self) -> usize {
+        self.ref_count()
+    }
+
+    /// Get a pointer to the shared value.
+    pub fn as_ptr(&self) -> *const T {
+        let inner = unsafe { self.ptr.as_ref() };
+        &inner.data
+    }
+
+    /// Check whether two handles point to the same allocation.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+
+    /// Get mutable access to the value if this is the only handle.
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        if self.ref_count() == 1 {
+            // Unique: no other handle can observe the value
+            Some(unsafe { &mut self.ptr.as_mut().data })
+        } else {
+            None
+        }
+    }
+
+    /// Take the value out if this is the only handle.
+    ///
+    /// Returns the handle back unchanged otherwise.
+    pub fn try_unwrap(self) -> Result<T, Self> {
+        let inner = unsafe { self.ptr.as_ref() };
+        if inner
+            .count
+            .compare_exchange(1, 0, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(self);
+        }
+
+        let ptr = self.ptr;
+        core::mem::forget(self);
+        unsafe {
+            let data = core::ptr::read(&ptr.as_ref().data);
+            Self::free(ptr);
+            Ok(data)
+        }
+    }
+
     /// Deallocate the ArcLite.
     ///
     /// # Safety
     ///
     /// This must only be called when the reference count has reached zero.
     unsafe fn deallocate(&self) {
+        unsafe {
+            core::ptr::drop_in_place(&mut (*self.ptr.as_ptr()).data);
+            Self::free(self.ptr);
+        }
+    }
+
+    /// Release the allocation without dropping the value.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from [`ArcLite::new`] and not be used afterwards.
+    unsafe fn free(ptr: NonNull<ArcLiteInner<T>>) {
+        let layout = Layout::new::<ArcLiteInner<T>>();
+
         #[cfg(feature = "std-shim")]
         {
             extern crate std;
             use core::alloc::GlobalAlloc;
             use std::alloc::System;
-            let layout = Layout::new::<ArcLiteInner<T>>();
-
-            // Drop the data
-            unsafe {
-                core::ptr::drop_in_place(&mut self.ptr.as_ptr().as_mut().unwrap().data);
-
-                // Deallocate the memory
-                GlobalAlloc::dealloc(&System, self.ptr.as_ptr() as *mut u8, layout);
-            }
+            unsafe { GlobalAlloc::dealloc(&System, ptr.as_ptr() as *mut u8, layout) };
         }
-        
+
         #[cfg(not(feature = "std-shim"))]
         {
-            // In a real no_std environment, we'd use a custom allocator
-            unimplemented!("ArcLite deallocation requires a custom allocator in no_std environments")
+            extern crate alloc;
+            unsafe { alloc::alloc::dealloc(ptr.as_ptr() as *mut u8, layout) };
         }
     }
 }
 
+#[cold]
+fn refcount_overflow() -> ! {
+    panic!("ArcLite reference count overflow");
+}
+
 impl<T> Clone for ArcLite<T> {
     fn clone(&self) -> Self {
         let inner = unsafe { self.ptr.as_ref() };
-        let _prev_count = inner.count.fetch_add(1, Ordering::AcqRel);
-        
+        let prev_count = inner.count.fetch_add(1, Ordering::AcqRel);
+        if prev_count >= MAX_REFCOUNT {
+            refcount_overflow();
+        }
+
         Self { ptr: self.ptr }
     }
 }
@@ -192,47 +288,83 @@ impl<T> Drop for ArcLite<T> {
 
 impl<T> Deref for ArcLite<T> {
     type Target = T;
-    
+
     fn deref(&self) -> &Self::Target {
         let inner = unsafe { self.ptr.as_ref() };
         &inner.data
     }
 }
 
+impl<T: fmt::Debug> fmt::Debug for ArcLite<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for ArcLite<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
 unsafe impl<T: Send + Sync> Send for ArcLite<T> {}
 unsafe impl<T: Send + Sync> Sync for ArcLite<T> {}
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    
+
     #[test]
     fn test_arc_lite_basic() {
         let arc = ArcLite::new(42);
         assert_eq!(*arc, 42);
         assert_eq!(arc.ref_count(), 1);
     }
-    
+
     #[test]
     fn test_arc_lite_clone() {
         let arc1 = ArcLite::new(42);
         let arc2 = arc1.clone();
-        
+
         assert_eq!(*arc1, 42);
         assert_eq!(*arc2, 42);
         assert_eq!(arc1.ref_count(), 2);
         assert_eq!(arc2.ref_count(), 2);
     }
-    
-    #[test] 
+
+    #[test]
     fn test_arc_lite_try_inc() {
         let arc = ArcLite::new(42);
         assert_eq!(arc.ref_count(), 1);
-        
+
         assert!(arc.try_inc());
         assert_eq!(arc.ref_count(), 2);
-        
+
         arc.dec();
         assert_eq!(arc.ref_count(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_arc_lite_unique_access() {
+        extern crate std;
+        use std::format;
+        use std::string::String;
+
+        let mut arc = ArcLite::new(String::from("a"));
+        let other = ArcLite::new(String::from("a"));
+        assert!(!arc.ptr_eq(&other));
+        assert_eq!(format!("{arc} {arc:?}"), "a \"a\"");
+
+        let clone = arc.clone();
+        assert!(arc.ptr_eq(&clone));
+        assert_eq!(arc.as_ptr(), clone.as_ptr());
+        assert!(arc.get_mut().is_none());
+        let arc_back = ArcLite::try_unwrap(arc).unwrap_err();
+        drop(clone);
+
+        let mut arc = arc_back;
+        arc.get_mut().unwrap().push('b');
+        assert_eq!(arc.strong_count(), 1);
+        assert_eq!(ArcLite::try_unwrap(arc).unwrap(), "ab");
+    }
+}
@@ -0,0 +1,218 @@
+//! Per-thread bump arenas for transient allocations.
+//!
+//! A [`ThreadArena`] is one heap block handed out front to back: an
+//! allocation is a bounds check and an add, with no lock and no free list.
+//! Nothing is freed individually; [`reset`](ThreadArena::reset) makes the
+//! whole block available again at a point the program chooses, typically
+//! the top of a control loop:
+//!
+//! ```ignore
+//! use preemptive_threads::mem::arena;
+//!
+//! arena::install(16 * 1024)?;
+//! loop {
+//!     arena::with(|arena| {
+//!         arena.reset();
+//!         let samples = arena.alloc_slice_copy(&adc.read_all()).unwrap();
+//!         let filtered = arena.alloc(filter(samples)).ok().unwrap();
+//!         actuate(filtered);
+//!     });
+//!     sleep_until_next_period();
+//! }
+//! ```
+//!
+//! [`install`] gives the current thread its own arena, reached through
+//! [`with`] and returned to the heap when the thread exits. An arena can
+//! also be owned directly, e.g. by a driver.
+//!
+//! Values in an arena are never dropped, so they should not own other
+//! resources.
+
+use crate::errors::{InvalidOperationError, MemoryError, ThreadError, ThreadResult};
+use alloc::alloc::{alloc, dealloc};
+use core::alloc::Layout;
+use core::cell::Cell;
+use core::ptr::NonNull;
+
+/// Alignment of the arena's block; allocations aligned more strictly
+/// than this may waste up to their alignment in padding.
+pub const ARENA_ALIGN: usize = 16;
+
+/// A bump allocator over one heap block, see the [module docs](self).
+pub struct ThreadArena {
+    base: NonNull<u8>,
+    capacity: usize,
+    used: Cell<usize>,
+    high_water: Cell<usize>,
+}
+
+// The block is owned; sharing needs `&`, which `Cell` already rules out
+unsafe impl Send for ThreadArena {}
+
+impl ThreadArena {
+    /// Allocate a block of `capacity` bytes from the global heap.
+    pub fn with_capacity(capacity: usize) -> Result<Self, MemoryError> {
+        let layout = Self::layout(capacity)?;
+        let base = NonNull::new(unsafe { alloc(layout) }).ok_or(MemoryError::OutOfMemory)?;
+        Ok(Self {
+            base,
+            capacity,
+            used: Cell::new(0),
+            high_water: Cell::new(0),
+        })
+    }
+
+    fn layout(capacity: usize) -> Result<Layout, MemoryError> {
+        Layout::from_size_align(capacity.max(1), ARENA_ALIGN)
+            .map_err(|_| MemoryError::InvalidLayout)
+    }
+
+    /// Reserve memory for `layout`, `None` if the arena is full.
+    pub fn alloc_layout(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let base = self.base.as_ptr() as usize;
+        let mask = layout.align() - 1;
+        let start = ((base + self.used.get()).checked_add(mask)? & !mask) - base;
+        let end = start.checked_add(layout.size())?;
+        if end > self.capacity {
+            return None;
+        }
+        self.used.set(end);
+        self.high_water.set(self.high_water.get().max(end));
+        // In bounds of the block, checked above
+        Some(unsafe { NonNull::new_unchecked(self.base.as_ptr().add(start)) })
+    }
+
+    /// Move `value` into the arena, handing it back if the arena is full.
+    #[allow(clippy::mut_from_ref)] // Each allocation is disjoint until a reset
+    pub fn alloc<T>(&self, value: T) -> Result<&mut T, T> {
+        match self.alloc_layout(Layout::new::<T>()) {
+            Some(ptr) => {
+                let ptr = ptr.as_ptr() as *mut T;
+                // Fresh, aligned and never handed out again before a reset,
+                // which needs `&mut self`
+                unsafe {
+                    ptr.write(value);
+                    Ok(&mut *ptr)
+                }
+            }
+            None => Err(value),
+        }
+    }
+
+    /// Copy `src` into the arena, `None` if the arena is full.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_slice_copy<T: Copy>(&self, src: &[T]) -> Option<&mut [T]> {
+        let layout = Layout::array::<T>(src.len()).ok()?;
+        let ptr = self.alloc_layout(layout)?.as_ptr() as *mut T;
+        unsafe {
+            core::ptr::copy_nonoverlapping(src.as_ptr(), ptr, src.len());
+            Some(core::slice::from_raw_parts_mut(ptr, src.len()))
+        }
+    }
+
+    /// Make the whole block available again.
+    pub fn reset(&mut self) {
+        self.used.set(0);
+    }
+
+    /// Bytes handed out since the last reset, padding included.
+    pub fn used(&self) -> usize {
+        self.used.get()
+    }
+
+    /// Size of the block in bytes.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Most bytes ever in use at once, to size the arena with.
+    pub fn high_water(&self) -> usize {
+        self.high_water.get()
+    }
+}
+
+impl Drop for ThreadArena {
+    fn drop(&mut self) {
+        // Same layout `with_capacity` succeeded with
+        let layout =
+            unsafe { Layout::from_size_align_unchecked(self.capacity.max(1), ARENA_ALIGN) };
+        unsafe { dealloc(self.base.as_ptr(), layout) };
+    }
+}
+
+impl core::fmt::Debug for ThreadArena {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ThreadArena")
+            .field("used", &self.used())
+            .field("capacity", &self.capacity)
+            .field("high_water", &self.high_water())
+            .finish()
+    }
+}
+
+fn current_thread() -> ThreadResult<crate::thread::Thread> {
+    if crate::irq::in_irq() {
+        return Err(ThreadError::InvalidOperation(
+            InvalidOperationError::NotSupported,
+        ));
+    }
+    crate::kernel::global_ops()
+        .and_then(|ops| ops.current_thread())
+        .ok_or(ThreadError::InvalidOperation(
+            InvalidOperationError::WrongThread,
+        ))
+}
+
+/// Give the current thread an arena of `capacity` bytes, replacing any it
+/// had. The arena is freed when the thread exits.
+pub fn install(capacity: usize) -> ThreadResult<()> {
+    let thread = current_thread()?;
+    let arena = ThreadArena::with_capacity(capacity).map_err(ThreadError::Memory)?;
+    let old = thread.arena().lock().replace(arena);
+    drop(old);
+    Ok(())
+}
+
+/// Take the current thread's arena back, e.g. to free it early.
+pub fn uninstall() -> Option<ThreadArena> {
+    current_thread().ok()?.arena().lock().take()
+}
+
+/// Run `f` with the current thread's arena.
+///
+/// `None` without an arena, outside a thread, in interrupt context or
+/// when called from inside `f`.
+pub fn with<R>(f: impl FnOnce(&mut ThreadArena) -> R) -> Option<R> {
+    let thread = current_thread().ok()?;
+    // Out of the slot while in use, so nested calls can't alias it
+    let mut arena = thread.arena().lock().take()?;
+    let result = f(&mut arena);
+    let mut slot = thread.arena().lock();
+    if slot.is_none() {
+        *slot = Some(arena);
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bump_allocation_and_reset() {
+        let mut arena = ThreadArena::with_capacity(64).unwrap();
+        let byte = arena.alloc(7u8).unwrap();
+        *byte += 1;
+        let word = arena.alloc(0x1234u64).unwrap();
+        assert_eq!(word as *mut u64 as usize % 8, 0);
+        assert_eq!(arena.used(), 16);
+
+        let copied = arena.alloc_slice_copy(&[1u32; 8]).unwrap();
+        assert_eq!(copied, &[1; 8]);
+        assert_eq!(arena.alloc([0u8; 17]), Err([0u8; 17]));
+
+        arena.reset();
+        assert_eq!((arena.used(), arena.high_water()), (0, 48));
+        assert!(arena.alloc_slice_copy(&[0u8; 64]).is_some());
+    }
+}
@@ -0,0 +1,119 @@
+//! Cache-line padding against false sharing.
+//!
+//! Two atomics that share a cache line are effectively one as far as the
+//! cores are concerned: every write by one core evicts the line from the
+//! others, even when they only ever touch the other variable. Wrapping
+//! values that different cores update in [`CachePadded`] gives each its
+//! own line.
+
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+
+/// Cache line size of the Cortex-A53.
+pub const CACHE_LINE: usize = 64;
+
+/// A value aligned to, and padded out to, a whole cache line.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+#[repr(align(64))]
+pub struct CachePadded<T> {
+    value: T,
+}
+
+impl<T> CachePadded<T> {
+    pub const fn new(value: T) -> Self {
+        Self { value }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T> From<T> for CachePadded<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for CachePadded<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CachePadded").field(&self.value).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use portable_atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_values_get_their_own_line() {
+        assert_eq!(core::mem::align_of::<CachePadded<u8>>(), CACHE_LINE);
+        assert_eq!(core::mem::size_of::<CachePadded<u8>>(), CACHE_LINE);
+        assert_eq!(
+            core::mem::size_of::<[CachePadded<AtomicUsize>; 2]>(),
+            2 * CACHE_LINE
+        );
+
+        let mut counter = CachePadded::new(AtomicUsize::new(1));
+        counter.fetch_add(1, Ordering::Relaxed);
+        *counter.get_mut() += 1;
+        assert_eq!(counter.into_inner().into_inner(), 3);
+    }
+
+    /// Two threads hammering neighbouring counters, packed and then
+    /// padded; padding must not make it slower. Run on a multi-core machine
+    /// (a Pi under Linux works) with
+    /// `cargo test --features std-shim --release -- --ignored`.
+    #[cfg(feature = "std-shim")]
+    #[test]
+    #[ignore]
+    fn bench_false_sharing() {
+        extern crate std;
+        use std::time::Instant;
+
+        const ROUNDS: usize = 20_000_000;
+
+        fn hammer<C: Sync>(counters: &[C; 2], get: fn(&C) -> &AtomicUsize) -> std::time::Duration {
+            let start = Instant::now();
+            std::thread::scope(|scope| {
+                for counter in counters {
+                    scope.spawn(move || {
+                        for _ in 0..ROUNDS {
+                            get(counter).fetch_add(1, Ordering::Relaxed);
+                        }
+                    });
+                }
+            });
+            start.elapsed()
+        }
+
+        let packed = [AtomicUsize::new(0), AtomicUsize::new(0)];
+        let padded = [
+            CachePadded::new(AtomicUsize::new(0)),
+            CachePadded::new(AtomicUsize::new(0)),
+        ];
+        let packed_time = hammer(&packed, |c| c);
+        let padded_time = hammer(&padded, |c| c);
+        assert!(
+            padded_time <= packed_time,
+            "padded counters took {:?}, packed {:?}",
+            padded_time,
+            packed_time
+        );
+    }
+}
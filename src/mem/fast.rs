@@ -0,0 +1,188 @@
+//! NEON-accelerated memory copy and fill.
+//!
+//! [`copy_fast`] and [`fill_fast`] move 64 bytes per iteration through four
+//! NEON registers, finishing the last partial block with ordinary stores.
+//! They are used for stack scrubbing and framebuffer scrolling, where
+//! kilobytes move at a time.
+//!
+//! The routines work in any thread. In a thread spawned
+//! [`no_fpu`](crate::ThreadBuilder::no_fpu) the FPU traps, so they lift the
+//! trap for the duration of the operation and keep the thread from being
+//! preempted meanwhile, since switching back to it would set the trap
+//! again. The registers they use are declared clobbered, so the compiler
+//! preserves any live values of the calling thread around them.
+//!
+//! Off AArch64 they fall back to `core::ptr` copies.
+
+#[cfg(target_arch = "aarch64")]
+use crate::arch::{Arch, DefaultArch};
+
+/// Bytes moved per NEON iteration.
+const BLOCK: usize = 64;
+
+/// Copy `src` into `dst`.
+///
+/// # Panics
+///
+/// Panics if the slices differ in length, like
+/// [`copy_from_slice`](slice::copy_from_slice).
+pub fn copy_fast(dst: &mut [u8], src: &[u8]) {
+    assert_eq!(dst.len(), src.len(), "copy_fast: length mismatch");
+    unsafe { copy_fast_raw(dst.as_mut_ptr(), src.as_ptr(), src.len()) };
+}
+
+/// Set every byte of `dst` to `byte`.
+pub fn fill_fast(dst: &mut [u8], byte: u8) {
+    unsafe { fill_fast_raw(dst.as_mut_ptr(), byte, dst.len()) };
+}
+
+/// Copy `len` bytes from `src` to `dst`, front to back.
+///
+/// # Safety
+///
+/// `src` must be valid for reads and `dst` for writes of `len` bytes. The
+/// ranges may overlap only if `dst` is not above `src`.
+pub unsafe fn copy_fast_raw(dst: *mut u8, src: *const u8, len: usize) {
+    let blocks = len / BLOCK;
+    if blocks != 0 {
+        with_fpu(|| unsafe { copy_blocks(dst, src, blocks) });
+    }
+    let done = blocks * BLOCK;
+    unsafe { core::ptr::copy(src.add(done), dst.add(done), len - done) };
+}
+
+/// Set `len` bytes at `dst` to `byte`.
+///
+/// # Safety
+///
+/// `dst` must be valid for writes of `len` bytes.
+pub unsafe fn fill_fast_raw(dst: *mut u8, byte: u8, len: usize) {
+    unsafe { fill_pattern(dst, u32::from_ne_bytes([byte; 4]), len) };
+}
+
+/// Set `count` words at `dst` to `word`.
+///
+/// # Safety
+///
+/// `dst` must be aligned and valid for writes of `count` words.
+pub unsafe fn fill_words_fast(dst: *mut u32, word: u32, count: usize) {
+    unsafe { fill_pattern(dst as *mut u8, word, count * 4) };
+}
+
+/// Repeat the bytes of `pattern` over `len` bytes at `dst`, which must be
+/// a whole number of patterns unless all four bytes are equal.
+unsafe fn fill_pattern(dst: *mut u8, pattern: u32, len: usize) {
+    let blocks = len / BLOCK;
+    if blocks != 0 {
+        with_fpu(|| unsafe { fill_blocks(dst, pattern, blocks) });
+    }
+    // Blocks are a whole number of patterns, so the tail starts in phase
+    let bytes = pattern.to_ne_bytes();
+    for i in blocks * BLOCK..len {
+        unsafe { dst.add(i).write(bytes[i % 4]) };
+    }
+}
+
+/// Run `f` with the FPU usable, borrowing it if the running thread has it
+/// trapped.
+#[cfg(target_arch = "aarch64")]
+fn with_fpu<R>(f: impl FnOnce() -> R) -> R {
+    if !DefaultArch::fpu_trapped() {
+        return f();
+    }
+    crate::platform_timer::preempt_disable();
+    DefaultArch::set_fpu_trap(false);
+    let result = f();
+    DefaultArch::set_fpu_trap(true);
+    crate::platform_timer::preempt_enable();
+    result
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn with_fpu<R>(f: impl FnOnce() -> R) -> R {
+    f()
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn copy_blocks(dst: *mut u8, src: *const u8, blocks: usize) {
+    unsafe {
+        core::arch::asm!(
+            "2:",
+            "ldp q0, q1, [{src}], #32",
+            "ldp q2, q3, [{src}], #32",
+            "stp q0, q1, [{dst}], #32",
+            "stp q2, q3, [{dst}], #32",
+            "subs {n}, {n}, #1",
+            "b.ne 2b",
+            src = inout(reg) src => _,
+            dst = inout(reg) dst => _,
+            n = inout(reg) blocks => _,
+            out("v0") _, out("v1") _, out("v2") _, out("v3") _,
+            options(nostack)
+        );
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+unsafe fn copy_blocks(dst: *mut u8, src: *const u8, blocks: usize) {
+    unsafe { core::ptr::copy(src, dst, blocks * BLOCK) };
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn fill_blocks(dst: *mut u8, pattern: u32, blocks: usize) {
+    unsafe {
+        core::arch::asm!(
+            "dup v0.4s, {pattern:w}",
+            "2:",
+            "stp q0, q0, [{dst}], #32",
+            "stp q0, q0, [{dst}], #32",
+            "subs {n}, {n}, #1",
+            "b.ne 2b",
+            pattern = in(reg) pattern,
+            dst = inout(reg) dst => _,
+            n = inout(reg) blocks => _,
+            out("v0") _,
+            options(nostack)
+        );
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+unsafe fn fill_blocks(dst: *mut u8, pattern: u32, blocks: usize) {
+    let bytes = pattern.to_ne_bytes();
+    for i in 0..blocks * BLOCK {
+        unsafe { dst.add(i).write(bytes[i % 4]) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_copy_and_fill_fast() {
+        let src: Vec<u8> = (0..300u32).map(|i| i as u8).collect();
+        for len in [0, 1, 63, 64, 65, 200, 299] {
+            let mut dst = alloc::vec![0xAAu8; 300];
+            copy_fast(&mut dst[1..1 + len], &src[..len]);
+            assert_eq!(&dst[1..1 + len], &src[..len]);
+            assert_eq!(dst[1 + len..].iter().filter(|&&b| b != 0xAA).count(), 0);
+
+            fill_fast(&mut dst[..len], 0x5E);
+            assert!(dst[..len].iter().all(|&b| b == 0x5E));
+        }
+
+        // Scrolling: overlapping, destination below source
+        let mut buf = src.clone();
+        unsafe { copy_fast_raw(buf.as_mut_ptr(), buf.as_ptr().add(10), 290) };
+        assert_eq!(&buf[..290], &src[10..]);
+
+        let mut words = [0u32; 40];
+        unsafe { fill_words_fast(words.as_mut_ptr().add(1), 0x00FF_8000, 37) };
+        assert_eq!(
+            (words[0], words[1], words[37], words[38]),
+            (0, 0x00FF_8000, 0x00FF_8000, 0)
+        );
+    }
+}
@@ -0,0 +1,391 @@
+//! Hazard pointers for safe memory reclamation in lock-free structures.
+//!
+//! A thread that is about to dereference a shared pointer publishes it in a
+//! hazard slot first. Nodes unlinked from a structure are
+//! [retired](HazardDomain::retire) rather than freed, and only freed once no
+//! slot names them. This is what makes `pop` on a lock-free queue safe when
+//! another thread may still be reading the node it removed.
+//!
+//! Slots are handed out as RAII guards and released on drop:
+//!
+//! - [`HazardGuard`] protects one pointer, loading it from an `AtomicPtr` in
+//!   a retry loop until the published value is stable.
+//! - [`HazardArray`] holds several slots, for traversals that must keep the
+//!   previous node protected while moving to the next.
+//!
+//! Protecting a pointer is safe; dereferencing it is not. A slot only keeps
+//! a node alive if the node came from a user of the same domain and is
+//! freed through [`retire`](HazardDomain::retire) on it, which nothing here
+//! can check.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::mem::hazard::HazardDomain;
+//!
+//! let domain = HazardDomain::global();
+//! let guard = domain.protect(&queue.head)?;
+//! // Nodes in the queue are only freed through `domain.retire`
+//! if let Some(node) = unsafe { guard.as_ref() } {
+//!     // `node` can't be freed while `guard` lives
+//!     process(&node.value);
+//! }
+//! ```
+
+use crate::arch;
+use crate::errors::ResourceError;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use portable_atomic::{fence, AtomicBool, AtomicPtr, Ordering};
+
+/// Number of hazard slots per domain.
+pub const MAX_HAZARDS: usize = 64;
+
+/// Retired nodes that trigger an automatic reclaim pass.
+const RECLAIM_THRESHOLD: usize = 2 * MAX_HAZARDS;
+
+struct Slot {
+    in_use: AtomicBool,
+    ptr: AtomicPtr<()>,
+}
+
+struct Retired {
+    ptr: *mut (),
+    drop_fn: unsafe fn(*mut ()),
+}
+
+impl Slot {
+    // Only used to initialise the slot array
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Slot = Slot {
+        in_use: AtomicBool::new(false),
+        ptr: AtomicPtr::new(core::ptr::null_mut()),
+    };
+}
+
+// Retired pointers are only touched under the domain's lock
+unsafe impl Send for Retired {}
+
+/// A set of hazard slots and the nodes retired against them.
+pub struct HazardDomain {
+    slots: [Slot; MAX_HAZARDS],
+    /// Locked with interrupts disabled
+    retired: spin::Mutex<Vec<Retired>>,
+}
+
+static GLOBAL_DOMAIN: HazardDomain = HazardDomain::new();
+
+impl HazardDomain {
+    /// Create an empty domain.
+    pub const fn new() -> Self {
+        Self {
+            slots: [Slot::EMPTY; MAX_HAZARDS],
+            retired: spin::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The domain shared by the whole kernel.
+    pub fn global() -> &'static HazardDomain {
+        &GLOBAL_DOMAIN
+    }
+
+    /// Protect the pointer currently stored in `src`.
+    ///
+    /// Fails with [`ResourceError::ResourceUnavailable`] if every slot is in
+    /// use.
+    pub fn protect<T>(&self, src: &AtomicPtr<T>) -> Result<HazardGuard<'_, T>, ResourceError> {
+        let slot = self.acquire()?;
+        let ptr = Self::protect_in(slot, src);
+        Ok(HazardGuard {
+            slot,
+            ptr,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Reserve `N` slots for a traversal.
+    pub fn array<const N: usize>(&self) -> Result<HazardArray<'_, N>, ResourceError> {
+        let mut slots: [Option<&Slot>; N] = [None; N];
+        for entry in slots.iter_mut() {
+            match self.acquire() {
+                Ok(slot) => *entry = Some(slot),
+                Err(e) => {
+                    slots.iter().flatten().for_each(|slot| Self::release(slot));
+                    return Err(e);
+                }
+            }
+        }
+        Ok(HazardArray {
+            slots: slots.map(|slot| slot.expect("all slots acquired")),
+        })
+    }
+
+    /// Hand a node unlinked from a shared structure over for freeing.
+    ///
+    /// It is dropped once no hazard slot protects it, possibly during this
+    /// call.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from [`Box::into_raw`], must no longer be reachable
+    /// from the shared structure, and must not be retired twice.
+    pub unsafe fn retire<T: Send>(&self, ptr: *mut T) {
+        unsafe fn drop_box<T>(ptr: *mut ()) {
+            drop(unsafe { Box::from_raw(ptr as *mut T) });
+        }
+
+        unsafe { self.retire_erased(ptr as *mut (), drop_box::<T>) }
+    }
+
+    /// [`retire`](Self::retire) for a pointer whose type is only known to
+    /// `drop_fn`, which frees it.
+    ///
+    /// # Safety
+    ///
+    /// As for `retire`, and `drop_fn` must be the right one for `ptr`.
+    pub(crate) unsafe fn retire_erased(&self, ptr: *mut (), drop_fn: unsafe fn(*mut ())) {
+        let pending = arch::without_interrupts(|| {
+            let mut retired = self.retired.lock();
+            retired.push(Retired { ptr, drop_fn });
+            retired.len()
+        });
+        if pending >= RECLAIM_THRESHOLD {
+            self.reclaim();
+        }
+    }
+
+    /// Free every retired node no hazard slot protects.
+    ///
+    /// Returns the number of nodes freed.
+    pub fn reclaim(&self) -> usize {
+        fence(Ordering::SeqCst);
+        let free: Vec<Retired> = arch::without_interrupts(|| {
+            let mut retired = self.retired.lock();
+            let (free, keep) = core::mem::take(&mut *retired)
+                .into_iter()
+                .partition(|node| !self.is_protected(node.ptr));
+            *retired = keep;
+            free
+        });
+
+        let count = free.len();
+        for node in free {
+            unsafe { (node.drop_fn)(node.ptr) };
+        }
+        count
+    }
+
+    /// Number of retired nodes waiting to be freed.
+    pub fn pending(&self) -> usize {
+        arch::without_interrupts(|| self.retired.lock().len())
+    }
+
+    fn is_protected(&self, ptr: *mut ()) -> bool {
+        self.slots
+            .iter()
+            .any(|slot| slot.ptr.load(Ordering::Acquire) == ptr)
+    }
+
+    fn acquire(&self) -> Result<&Slot, ResourceError> {
+        self.slots
+            .iter()
+            .find(|slot| {
+                slot.in_use
+                    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            })
+            .ok_or(ResourceError::ResourceUnavailable)
+    }
+
+    fn release(slot: &Slot) {
+        slot.ptr.store(core::ptr::null_mut(), Ordering::Release);
+        slot.in_use.store(false, Ordering::Release);
+    }
+
+    /// Publish `src`'s value in `slot`, retrying until it is stable.
+    fn protect_in<T>(slot: &Slot, src: &AtomicPtr<T>) -> *mut T {
+        let mut ptr = src.load(Ordering::Acquire);
+        loop {
+            slot.ptr.store(ptr as *mut (), Ordering::Release);
+            // The hazard must be visible before re-checking the source
+            fence(Ordering::SeqCst);
+            let current = src.load(Ordering::Acquire);
+            if current == ptr {
+                return ptr;
+            }
+            ptr = current;
+        }
+    }
+}
+
+impl Drop for HazardDomain {
+    fn drop(&mut self) {
+        for node in self.retired.get_mut().drain(..) {
+            unsafe { (node.drop_fn)(node.ptr) };
+        }
+    }
+}
+
+impl Default for HazardDomain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One protected pointer; the slot is released on drop.
+pub struct HazardGuard<'d, T> {
+    slot: &'d Slot,
+    ptr: *mut T,
+    _marker: PhantomData<*const T>,
+}
+
+impl<T> HazardGuard<'_, T> {
+    /// The protected pointer.
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr
+    }
+
+    /// The protected value, or `None` if the pointer was null.
+    ///
+    /// # Safety
+    ///
+    /// The protected pointer must be null or point to a `T` published by
+    /// a user of this guard's domain, which is only freed through
+    /// [`retire`](HazardDomain::retire) on that domain.
+    pub unsafe fn as_ref(&self) -> Option<&T> {
+        // Retired nodes stay allocated while this slot names them
+        unsafe { self.ptr.as_ref() }
+    }
+
+    /// Protect the current value of `src` instead, reusing the slot.
+    pub fn reprotect(&mut self, src: &AtomicPtr<T>) {
+        self.ptr = HazardDomain::protect_in(self.slot, src);
+    }
+}
+
+impl<T> Drop for HazardGuard<'_, T> {
+    fn drop(&mut self) {
+        HazardDomain::release(self.slot);
+    }
+}
+
+/// `N` hazard slots held together, e.g. for hand-over-hand traversal.
+pub struct HazardArray<'d, const N: usize> {
+    slots: [&'d Slot; N],
+}
+
+impl<const N: usize> HazardArray<'_, N> {
+    /// Protect the current value of `src` in slot `index`, and return it.
+    ///
+    /// A node managed by this domain stays allocated until slot `index` is
+    /// reused or cleared; see the [module docs](self) for when it is safe
+    /// to dereference.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index >= N`.
+    pub fn protect<T>(&mut self, index: usize, src: &AtomicPtr<T>) -> *mut T {
+        HazardDomain::protect_in(self.slots[index], src)
+    }
+
+    /// Stop protecting whatever slot `index` holds.
+    pub fn clear(&mut self, index: usize) {
+        self.slots[index]
+            .ptr
+            .store(core::ptr::null_mut(), Ordering::Release);
+    }
+
+    /// Exchange the pointers protected by two slots.
+    ///
+    /// Both stay protected throughout, so a traversal can move its "current"
+    /// node into the "previous" slot before loading the next one.
+    pub fn swap(&mut self, a: usize, b: usize) {
+        self.slots.swap(a, b);
+    }
+}
+
+impl<const N: usize> Drop for HazardArray<'_, N> {
+    fn drop(&mut self) {
+        self.slots
+            .iter()
+            .for_each(|slot| HazardDomain::release(slot));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use portable_atomic::AtomicUsize;
+
+    struct Node {
+        value: u32,
+        next: AtomicPtr<Node>,
+        drops: &'static AtomicUsize,
+    }
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn node(value: u32, drops: &'static AtomicUsize) -> *mut Node {
+        Box::into_raw(Box::new(Node {
+            value,
+            next: AtomicPtr::new(core::ptr::null_mut()),
+            drops,
+        }))
+    }
+
+    #[test]
+    fn test_guard_defers_reclaim() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let domain = HazardDomain::new();
+        let head = AtomicPtr::new(node(1, &DROPS));
+
+        let guard = domain.protect(&head).unwrap();
+        let old = head.swap(node(2, &DROPS), Ordering::AcqRel);
+        unsafe { domain.retire(old) };
+        assert_eq!(domain.reclaim(), 0);
+        assert_eq!(unsafe { guard.as_ref() }.map(|n| n.value), Some(1));
+
+        drop(guard);
+        assert_eq!(domain.reclaim(), 1);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+
+        unsafe { domain.retire(head.load(Ordering::Acquire)) };
+        drop(domain);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_array_traversal_and_slot_exhaustion() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let domain = HazardDomain::new();
+        let second = node(2, &DROPS);
+        let first = node(1, &DROPS);
+        unsafe { (*first).next.store(second, Ordering::Release) };
+        let head = AtomicPtr::new(first);
+
+        let mut hazards = domain.array::<2>().unwrap();
+        let mut values = Vec::new();
+        let mut current = hazards.protect(0, &head);
+        while !current.is_null() {
+            values.push(unsafe { (*current).value });
+            hazards.swap(0, 1);
+            current = hazards.protect(0, unsafe { &(*current).next });
+        }
+        assert_eq!(values, [1, 2]);
+
+        assert!(domain.array::<{ MAX_HAZARDS - 1 }>().is_err());
+        assert!(domain.array::<{ MAX_HAZARDS - 2 }>().is_ok());
+        drop(hazards);
+
+        unsafe {
+            domain.retire(first);
+            domain.retire(second);
+        }
+        assert_eq!(domain.reclaim(), 2);
+    }
+}
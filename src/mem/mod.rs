@@ -1,10 +1,29 @@
 //! Memory management for thread stacks.
 //!
 //! Provides safe abstractions for managing thread stacks and
-//! reference counting in a no_std environment.
+//! reference counting in a no_std environment, per-thread bump arenas,
+//! hazard pointers and reclamation policies for lock-free structures,
+//! per-CPU variables, NEON copy and fill routines, plus a heap-free packet
+//! buffer pool for drivers.
 
 pub mod arc_lite;
+pub mod arena;
+pub mod cache_padded;
+pub mod fast;
+pub mod hazard;
+pub mod percpu;
+pub mod pktbuf;
+pub mod reclaim;
 pub mod stack_pool;
 
 pub use arc_lite::ArcLite;
-pub use stack_pool::{Stack, StackPool, StackSizeClass};
+pub use arena::ThreadArena;
+pub use cache_padded::CachePadded;
+pub use fast::{copy_fast, fill_fast};
+pub use hazard::{HazardArray, HazardDomain, HazardGuard};
+pub use percpu::PerCpu;
+pub use pktbuf::{PacketBuf, PacketPool};
+pub use stack_pool::{
+    Stack, StackClass, StackClassConfig, StackPool, StackScrub, StackSizeClass, StackSpec,
+    FREED_STACK_POISON, GUARD_SIZE, PRESET_CLASSES, RED_ZONE_SIZE,
+};
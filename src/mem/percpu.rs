@@ -0,0 +1,107 @@
+//! Per-CPU variables.
+//!
+//! A [`PerCpu<T>`] holds one `T` per core, each on its own cache line so
+//! cores updating their own copy don't contend. [`get`](PerCpu::get) picks
+//! the calling core's copy by the CPU number in `MPIDR_EL1` (see
+//! [`arch::cpu_id`](crate::arch::cpu_id)); [`with`](PerCpu::with) does the
+//! same with interrupts masked, for read-modify-write sequences an
+//! interrupt handler on the same core must not observe half-done.
+//!
+//! Declare them as statics with [`percpu!`](crate::percpu):
+//!
+//! ```ignore
+//! use preemptive_threads::percpu;
+//! use portable_atomic::{AtomicU64, Ordering};
+//!
+//! percpu! {
+//!     static WAKEUPS: AtomicU64 = AtomicU64::new(0);
+//! }
+//!
+//! WAKEUPS.get().fetch_add(1, Ordering::Relaxed);
+//! let total: u64 = WAKEUPS.iter().map(|w| w.load(Ordering::Relaxed)).sum();
+//! ```
+
+use super::CachePadded;
+
+/// Cores per-CPU variables have a copy for.
+pub const MAX_CPUS: usize = 4;
+
+/// A value with a separate copy for every CPU.
+pub struct PerCpu<T> {
+    slots: [CachePadded<T>; MAX_CPUS],
+}
+
+// Every core can reach every copy through `get_for` and `iter`
+unsafe impl<T: Sync> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    /// Per-CPU storage from one slot per CPU; see [`percpu!`](crate::percpu).
+    #[doc(hidden)]
+    pub const fn from_slots(slots: [CachePadded<T>; MAX_CPUS]) -> Self {
+        Self { slots }
+    }
+
+    /// The calling CPU's copy.
+    pub fn get(&self) -> &T {
+        // Secondary cores beyond MAX_CPUS are never started
+        &self.slots[crate::arch::cpu_id() % MAX_CPUS]
+    }
+
+    /// Run `f` on the calling CPU's copy with interrupts masked.
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        crate::arch::without_interrupts(|| f(self.get()))
+    }
+
+    /// CPU `cpu`'s copy, or `None` past [`MAX_CPUS`].
+    pub fn get_for(&self, cpu: usize) -> Option<&T> {
+        self.slots.get(cpu).map(|slot| &**slot)
+    }
+
+    /// Every CPU's copy, in CPU order.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter().map(|slot| &**slot)
+    }
+}
+
+/// Declare per-CPU statics, each CPU's copy starting from the same
+/// constant initializer.
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)+) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::mem::percpu::PerCpu<$ty> = {
+                #[allow(clippy::declare_interior_mutable_const)]
+                const INIT: $crate::mem::CachePadded<$ty> = $crate::mem::CachePadded::new($init);
+                $crate::mem::percpu::PerCpu::from_slots([INIT; $crate::mem::percpu::MAX_CPUS])
+            };
+        )+
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use portable_atomic::{AtomicUsize, Ordering};
+
+    percpu! {
+        static COUNTS: AtomicUsize = AtomicUsize::new(0);
+    }
+
+    #[test]
+    fn test_copies_are_separate_and_padded() {
+        COUNTS.with(|count| count.fetch_add(2, Ordering::Relaxed));
+        COUNTS.get_for(1).unwrap().fetch_add(5, Ordering::Relaxed);
+
+        assert_eq!(COUNTS.get().load(Ordering::Relaxed), 2);
+        let all: usize = COUNTS
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum();
+        assert_eq!(all, 7);
+        assert!(COUNTS.get_for(super::MAX_CPUS).is_none());
+
+        let first = COUNTS.get_for(0).unwrap() as *const AtomicUsize as usize;
+        let second = COUNTS.get_for(1).unwrap() as *const AtomicUsize as usize;
+        assert_eq!(second - first, crate::mem::cache_padded::CACHE_LINE);
+    }
+}
@@ -0,0 +1,330 @@
+//! Fixed-size packet buffer pool.
+//!
+//! A [`PacketPool`] is a static array of MTU-sized buffers. Allocation claims
+//! a free buffer with a single compare-and-swap on its reference count, so
+//! buffers can be allocated and released from IRQ context without a heap or
+//! locks. [`PacketBuf`] handles are reference counted: cloning one shares the
+//! same memory, which lets a driver hand a received frame to several threads
+//! (e.g. over channels) without copying.
+//!
+//! Byte order and checksum helpers for protocol headers live here too.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::mem::pktbuf::{PacketPool, write_be16};
+//!
+//! static RX_POOL: PacketPool<32> = PacketPool::new();
+//!
+//! // In the receive interrupt:
+//! if let Some(mut pkt) = RX_POOL.alloc() {
+//!     let data = pkt.get_mut().unwrap();
+//!     write_be16(data, 12, 0x0800);
+//!     pkt.set_len(60);
+//! }
+//! ```
+
+use core::cell::UnsafeCell;
+use portable_atomic::{AtomicUsize, Ordering};
+
+/// Capacity of every packet buffer: an Ethernet frame with VLAN tag and FCS,
+/// rounded up to a cache line multiple.
+pub const PKTBUF_SIZE: usize = 1536;
+
+struct Slot {
+    refs: AtomicUsize,
+    data: UnsafeCell<[u8; PKTBUF_SIZE]>,
+}
+
+impl Slot {
+    // Only used to initialise the slot array
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FREE: Slot = Slot {
+        refs: AtomicUsize::new(0),
+        data: UnsafeCell::new([0; PKTBUF_SIZE]),
+    };
+}
+
+/// A pool of `N` packet buffers, intended to live in a `static`.
+pub struct PacketPool<const N: usize> {
+    slots: [Slot; N],
+    /// Where the next allocation starts searching
+    next: AtomicUsize,
+    alloc_failures: AtomicUsize,
+}
+
+// Buffer contents are only reachable through PacketBuf, which enforces
+// exclusive access for writes.
+unsafe impl<const N: usize> Sync for PacketPool<N> {}
+
+impl<const N: usize> PacketPool<N> {
+    /// Create a pool with every buffer free.
+    pub const fn new() -> Self {
+        Self {
+            slots: [Slot::FREE; N],
+            next: AtomicUsize::new(0),
+            alloc_failures: AtomicUsize::new(0),
+        }
+    }
+
+    /// Allocate a buffer with length zero.
+    ///
+    /// Lock-free and safe to call from IRQ context. Returns `None` when
+    /// every buffer is in use.
+    pub fn alloc(&'static self) -> Option<PacketBuf> {
+        let start = self.next.load(Ordering::Relaxed);
+        for i in 0..N {
+            let index = (start + i) % N;
+            let slot = &self.slots[index];
+            if slot
+                .refs
+                .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                self.next.store(index + 1, Ordering::Relaxed);
+                return Some(PacketBuf { slot, len: 0 });
+            }
+        }
+
+        self.alloc_failures.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    /// Number of buffers currently free.
+    pub fn available(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.refs.load(Ordering::Relaxed) == 0)
+            .count()
+    }
+
+    /// Total number of buffers in the pool.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Number of allocations that failed because the pool was empty.
+    pub fn alloc_failures(&self) -> usize {
+        self.alloc_failures.load(Ordering::Relaxed)
+    }
+}
+
+impl<const N: usize> Default for PacketPool<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A reference-counted handle to a pool buffer.
+///
+/// The buffer returns to its pool when the last handle is dropped. Each
+/// handle carries its own length, so clones can describe different views of
+/// the same frame.
+pub struct PacketBuf {
+    slot: &'static Slot,
+    len: usize,
+}
+
+unsafe impl Send for PacketBuf {}
+unsafe impl Sync for PacketBuf {}
+
+impl PacketBuf {
+    /// Length of the packet data.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the packet is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Set the length of the packet data.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `len` exceeds [`PKTBUF_SIZE`].
+    pub fn set_len(&mut self, len: usize) {
+        assert!(len <= PKTBUF_SIZE, "packet length exceeds buffer size");
+        self.len = len;
+    }
+
+    /// The packet data.
+    pub fn as_slice(&self) -> &[u8] {
+        let data: &[u8; PKTBUF_SIZE] = unsafe { &*self.slot.data.get() };
+        &data[..self.len]
+    }
+
+    /// The whole buffer for writing, if this is the only handle to it.
+    pub fn get_mut(&mut self) -> Option<&mut [u8; PKTBUF_SIZE]> {
+        if self.slot.refs.load(Ordering::Acquire) == 1 {
+            Some(unsafe { &mut *self.slot.data.get() })
+        } else {
+            None
+        }
+    }
+
+    /// Number of handles sharing this buffer.
+    pub fn ref_count(&self) -> usize {
+        self.slot.refs.load(Ordering::Relaxed)
+    }
+}
+
+impl Clone for PacketBuf {
+    fn clone(&self) -> Self {
+        self.slot.refs.fetch_add(1, Ordering::Relaxed);
+        Self {
+            slot: self.slot,
+            len: self.len,
+        }
+    }
+}
+
+impl Drop for PacketBuf {
+    fn drop(&mut self) {
+        // Release pairs with the Acquire in alloc() and get_mut()
+        self.slot.refs.fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl core::ops::Deref for PacketBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl core::fmt::Debug for PacketBuf {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PacketBuf")
+            .field("len", &self.len)
+            .field("refs", &self.ref_count())
+            .finish()
+    }
+}
+
+/// Read a big-endian `u16` at `offset`.
+pub fn read_be16(buf: &[u8], offset: usize) -> Option<u16> {
+    let bytes = buf.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Read a big-endian `u32` at `offset`.
+pub fn read_be32(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Write a big-endian `u16` at `offset`; returns `false` if it doesn't fit.
+pub fn write_be16(buf: &mut [u8], offset: usize, value: u16) -> bool {
+    match offset
+        .checked_add(2)
+        .and_then(|end| buf.get_mut(offset..end))
+    {
+        Some(bytes) => {
+            bytes.copy_from_slice(&value.to_be_bytes());
+            true
+        }
+        None => false,
+    }
+}
+
+/// Write a big-endian `u32` at `offset`; returns `false` if it doesn't fit.
+pub fn write_be32(buf: &mut [u8], offset: usize, value: u32) -> bool {
+    match offset
+        .checked_add(4)
+        .and_then(|end| buf.get_mut(offset..end))
+    {
+        Some(bytes) => {
+            bytes.copy_from_slice(&value.to_be_bytes());
+            true
+        }
+        None => false,
+    }
+}
+
+/// Add `data` to a running ones'-complement sum (RFC 1071).
+///
+/// Use this to combine a pseudo-header with a payload, then pass the result
+/// to [`checksum_finish`]. An odd trailing byte is padded with zero, so only
+/// the last chunk may have odd length.
+pub fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for pair in &mut chunks {
+        sum += u16::from_be_bytes([pair[0], pair[1]]) as u32;
+        if sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+/// Fold a running sum into the final Internet checksum.
+pub fn checksum_finish(mut sum: u32) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Internet checksum (IPv4, ICMP, UDP, TCP) of `data`.
+pub fn internet_checksum(data: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static POOL: PacketPool<2> = PacketPool::new();
+
+    #[test]
+    fn test_pool_refcounting() {
+        let mut a = POOL.alloc().unwrap();
+        let b = POOL.alloc().unwrap();
+        assert!(POOL.alloc().is_none());
+        assert_eq!(POOL.alloc_failures(), 1);
+
+        a.get_mut().unwrap()[..3].copy_from_slice(b"abc");
+        a.set_len(3);
+
+        let shared = a.clone();
+        assert!(a.get_mut().is_none());
+        assert_eq!(&*shared, b"abc");
+
+        drop(a);
+        drop(b);
+        assert_eq!(POOL.available(), 1);
+        drop(shared);
+        assert_eq!(POOL.available(), 2);
+    }
+
+    #[test]
+    fn test_endian_and_checksum() {
+        let mut header = [0u8; 6];
+        assert!(write_be16(&mut header, 0, 0x0800));
+        assert!(write_be32(&mut header, 2, 0xC0A8_0001));
+        assert!(!write_be16(&mut header, 5, 0));
+        assert_eq!(read_be16(&header, 0), Some(0x0800));
+        assert_eq!(read_be32(&header, 2), Some(0xC0A8_0001));
+        assert_eq!(read_be32(&header, 4), None);
+        // Offsets near the top of the address space don't wrap around
+        assert_eq!(read_be16(&header, usize::MAX), None);
+        assert_eq!(read_be32(&header, usize::MAX - 2), None);
+        assert!(!write_be16(&mut header, usize::MAX - 1, 0));
+        assert!(!write_be32(&mut header, usize::MAX, 0));
+
+        // RFC 1071 section 3 example
+        let data = [0x00, 0x01, 0xF2, 0x03, 0xF4, 0xF5, 0xF6, 0xF7];
+        assert_eq!(internet_checksum(&data), !0xDDF2);
+        assert_eq!(
+            checksum_finish(checksum_add(checksum_add(0, &data[..4]), &data[4..])),
+            !0xDDF2
+        );
+        assert_eq!(internet_checksum(&[0xAB]), !0xAB00);
+    }
+}
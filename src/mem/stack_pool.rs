@@ -2,12 +2,35 @@
 //!
 //! This module provides a pool-based allocator for thread stacks with
 //! different size classes and optional guard page support.
-
-
-
-use portable_atomic::{AtomicUsize, Ordering};
-use spin::Mutex;
+//!
+//! # Size classes
+//!
+//! A pool serves a fixed table of size classes chosen at construction.
+//! [`StackPool::new`] uses the four [`StackSizeClass`] presets; a workload
+//! with other needs passes its own table, one [`StackClassConfig`] per
+//! class:
+//!
+//! ```ignore
+//! use preemptive_threads::mem::{StackClassConfig, StackPool};
+//!
+//! // Many tiny stacks for event handlers, two big ones for the parser
+//! static POOL: StackPool<2> = StackPool::with_classes([
+//!     StackClassConfig::new(2048).count(64),
+//!     StackClassConfig::new(128 * 1024).count(2).guard(true),
+//! ]);
+//! ```
+//!
+//! Stacks are requested with a [`StackSpec`]: either a [`StackClass`], an
+//! index into the table, or a size in bytes, which picks the smallest class
+//! that fits. A [`StackSizeClass`] converts to its size, so on a custom
+//! table it picks the smallest class at least that big.
+
+use crate::sync::{Selectable, Selector, Timeout, WaitQueue};
+use crate::time::Duration;
+use core::ops::Range;
 use core::ptr::NonNull;
+use portable_atomic::{AtomicU8, AtomicUsize, Ordering};
+use spin::Mutex;
 
 // Use Vec from alloc or std depending on features
 #[cfg(feature = "std-shim")]
@@ -64,6 +87,149 @@ impl StackSizeClass {
     }
 }
 
+/// A size class of one [`StackPool`], by its index in the pool's table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StackClass(usize);
+
+impl StackClass {
+    /// The class at `index` in a pool's table.
+    pub const fn new(index: usize) -> Self {
+        Self(index)
+    }
+
+    /// Index in the pool's table.
+    pub const fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// How a thread asks a [`StackPool`] for a stack, see the
+/// [module docs](self#size-classes).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackSpec {
+    /// A class of the pool's table.
+    Class(StackClass),
+    /// At least this many bytes, from the smallest class that fits.
+    Bytes(usize),
+}
+
+impl From<StackClass> for StackSpec {
+    fn from(class: StackClass) -> Self {
+        StackSpec::Class(class)
+    }
+}
+
+impl From<StackSizeClass> for StackSpec {
+    fn from(class: StackSizeClass) -> Self {
+        StackSpec::Bytes(class.size())
+    }
+}
+
+impl StackSpec {
+    /// The error for a spec no class satisfies: the byte size, or the
+    /// class index.
+    pub fn invalid(self) -> crate::errors::SpawnError {
+        match self {
+            StackSpec::Class(class) => crate::errors::SpawnError::InvalidStackSize(class.index()),
+            StackSpec::Bytes(bytes) => crate::errors::SpawnError::InvalidStackSize(bytes),
+        }
+    }
+}
+
+/// One entry of a [`StackPool`]'s size class table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackClassConfig {
+    /// Usable bytes per stack, red zone included.
+    pub size: usize,
+    /// Most stacks of the class that may exist at once.
+    pub count: usize,
+    /// Reserve a [`GUARD_SIZE`] region below each stack that the thread
+    /// never uses, so an overflow past the red zone lands there rather than
+    /// in a neighbouring allocation. Without an MMU it isn't protected.
+    pub guard: bool,
+}
+
+impl StackClassConfig {
+    /// A class of `size`-byte stacks, unlimited and without a guard.
+    pub const fn new(size: usize) -> Self {
+        Self {
+            size,
+            count: usize::MAX,
+            guard: false,
+        }
+    }
+
+    /// Allow at most `count` stacks of the class.
+    pub const fn count(self, count: usize) -> Self {
+        Self { count, ..self }
+    }
+
+    /// Reserve a guard region below each stack of the class.
+    pub const fn guard(self, guard: bool) -> Self {
+        Self { guard, ..self }
+    }
+}
+
+/// The table [`StackPool::new`] uses: one class per [`StackSizeClass`],
+/// smallest first.
+pub const PRESET_CLASSES: [StackClassConfig; 4] = [
+    StackClassConfig::new(StackSizeClass::Small as usize),
+    StackClassConfig::new(StackSizeClass::Medium as usize),
+    StackClassConfig::new(StackSizeClass::Large as usize),
+    StackClassConfig::new(StackSizeClass::ExtraLarge as usize),
+];
+
+/// Bytes reserved below a stack whose class has a guard.
+pub const GUARD_SIZE: usize = 4096;
+
+/// Bytes at the low end of every thread stack kept as a red zone.
+///
+/// The red zone is filled with a poison pattern when a thread is created
+/// and checked on every timer tick and context switch. Without an MMU there
+/// are no guard pages, so this is how an overflow is caught before it
+/// corrupts whatever lies below the stack.
+pub const RED_ZONE_SIZE: usize = 256;
+
+/// Pattern the red zone is filled with.
+const RED_ZONE_POISON: u64 = 0xA5A5_A5A5_A5A5_A5A5;
+
+/// Byte debug builds fill returned stacks with, unless they are zeroized.
+///
+/// A thread that reads this pattern out of an uninitialised local, or a
+/// dangling pointer into an old stack, stands out in a debugger.
+pub const FREED_STACK_POISON: u8 = 0xDD;
+
+/// When a stack is scrubbed of the data its previous thread left behind.
+///
+/// Zeroizing costs a write of the whole stack, so it trades spawn or exit
+/// latency for confidentiality. Set per size class with
+/// [`StackPool::set_scrub`] or per thread with
+/// [`ThreadBuilder::stack_scrub`](crate::ThreadBuilder::stack_scrub).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum StackScrub {
+    /// Reuse stacks as they are; debug builds still poison-fill them on
+    /// return.
+    #[default]
+    None = 0,
+    /// Zero the stack when it is returned, so secrets don't linger in the
+    /// free list.
+    OnDeallocate = 1,
+    /// Zero the stack when it is handed out, so a new thread never sees
+    /// old data, wherever it came from.
+    OnAllocate = 2,
+}
+
+impl StackScrub {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => StackScrub::OnDeallocate,
+            2 => StackScrub::OnAllocate,
+            _ => StackScrub::None,
+        }
+    }
+}
+
 /// A thread stack with optional guard pages.
 ///
 /// This structure represents a single allocated stack that can be
@@ -75,10 +241,12 @@ pub struct Stack {
     memory: NonNull<u8>,
     /// Usable stack size (excluding guard pages)
     usable_size: usize,
-    /// Size class this stack belongs to
-    size_class: StackSizeClass,
+    /// Class of the pool it came from
+    class: StackClass,
     /// Whether this stack has guard pages
     has_guard_pages: bool,
+    /// How the stack is scrubbed between threads
+    scrub: StackScrub,
 }
 
 impl Stack {
@@ -87,33 +255,30 @@ impl Stack {
         self.usable_size
     }
 
-    /// Get the stack size class.
-    pub fn size_class(&self) -> StackSizeClass {
-        self.size_class
+    /// Class of the pool the stack came from.
+    pub fn class(&self) -> StackClass {
+        self.class
     }
 
     /// Get a pointer to the bottom of the stack (highest address).
     pub fn stack_bottom(&self) -> *mut u8 {
         let mut sp = unsafe {
-            self.memory.as_ptr().add(
-                if self.has_guard_pages {
-                    4096 + self.usable_size
-                } else {
-                    self.usable_size
-                }
-            ) as usize
+            self.memory.as_ptr().add(if self.has_guard_pages {
+                GUARD_SIZE + self.usable_size
+            } else {
+                self.usable_size
+            }) as usize
         };
 
         sp &= !0xF;
         sp as *mut u8
     }
 
-
     /// Get a pointer to the top of the stack (lowest address).
     pub fn stack_top(&self) -> *const u8 {
         unsafe {
             if self.has_guard_pages {
-                self.memory.as_ptr().add(4096) // Skip guard page
+                self.memory.as_ptr().add(GUARD_SIZE) // Skip guard page
             } else {
                 self.memory.as_ptr()
             }
@@ -125,15 +290,78 @@ impl Stack {
         self.stack_bottom()
     }
 
+    /// Lowest usable address; the stack grows down towards it.
+    pub fn base(&self) -> *const u8 {
+        self.stack_top()
+    }
+
+    /// Highest address of the stack, where a new thread's stack pointer
+    /// starts.
+    pub fn end(&self) -> *const u8 {
+        self.stack_bottom()
+    }
+
     /// Get top pointer (alias for stack_top for compatibility).
     pub fn top(&self) -> *const u8 {
         self.stack_top()
     }
 
+    /// Address range of the usable stack, from [`base`](Self::base) to
+    /// [`end`](Self::end).
+    pub fn bounds(&self) -> Range<usize> {
+        self.base() as usize..self.end() as usize
+    }
+
+    /// Bytes left between the current stack pointer and the red zone.
+    ///
+    /// Only meaningful on the thread running on this stack; returns `None`
+    /// if the stack pointer is outside it.
+    pub fn remaining(&self) -> Option<usize> {
+        let sp = crate::arch::stack_pointer();
+        let bounds = self.bounds();
+        bounds
+            .contains(&sp)
+            .then(|| sp.saturating_sub(bounds.start + RED_ZONE_SIZE))
+    }
+
     pub fn has_guard_pages(&self) -> bool {
         self.has_guard_pages
     }
 
+    /// How the stack is scrubbed between threads.
+    pub fn scrub(&self) -> StackScrub {
+        self.scrub
+    }
+
+    /// Overwrite the whole stack, guard pages excluded, with zeros.
+    pub fn zeroize(&self) {
+        self.fill(0);
+    }
+
+    fn fill(&self, byte: u8) {
+        unsafe { super::fast::fill_fast_raw(self.stack_top() as *mut u8, byte, self.usable_size) };
+        // Keep the writes even though nothing reads the memory afterwards
+        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Apply `scrub` to a stack about to be handed out.
+    fn prepare(mut self, scrub: StackScrub) -> Self {
+        self.scrub = scrub;
+        if scrub == StackScrub::OnAllocate {
+            self.zeroize();
+        }
+        self
+    }
+
+    /// Clean a stack being returned to the free list.
+    fn retire(&self) {
+        if self.scrub == StackScrub::OnDeallocate {
+            self.zeroize();
+        } else if cfg!(debug_assertions) {
+            self.fill(FREED_STACK_POISON);
+        }
+    }
+
     /// Install a stack canary value for overflow detection.
     ///
     /// This writes a known pattern at the bottom of the usable stack
@@ -149,6 +377,29 @@ impl Stack {
         }
     }
 
+    /// Fill the red zone at the low end of the stack with the poison pattern.
+    pub fn install_red_zone(&self) {
+        let red_zone = self.stack_top() as *mut u64;
+        for word in 0..RED_ZONE_SIZE / 8 {
+            unsafe { red_zone.add(word).write_volatile(RED_ZONE_POISON) };
+        }
+    }
+
+    /// Find the deepest write into the red zone.
+    ///
+    /// # Returns
+    ///
+    /// The lowest address in the red zone that no longer holds the poison
+    /// pattern, or `None` if the red zone is intact.
+    pub fn red_zone_breach(&self) -> Option<usize> {
+        let red_zone = self.stack_top() as *const u64;
+        // The stack grows down, so the first clobbered word from the bottom
+        // is the deepest the thread reached
+        (0..RED_ZONE_SIZE / 8)
+            .find(|&word| unsafe { red_zone.add(word).read_volatile() } != RED_ZONE_POISON)
+            .map(|word| red_zone as usize + word * 8)
+    }
+
     /// Check if the stack canary is still intact.
     ///
     /// # Arguments
@@ -166,11 +417,23 @@ impl Stack {
 
 /// Pool-based allocator for thread stacks.
 ///
-/// This allocator maintains separate free lists for each stack size class
-/// to minimize fragmentation and allocation overhead.
-pub struct StackPool {
+/// This allocator maintains separate free lists for each of its `N` size
+/// classes to minimize fragmentation and allocation overhead.
+pub struct StackPool<const N: usize = 4> {
+    /// The size class table
+    classes: [StackClassConfig; N],
     /// Free stacks for each size class
-    free_stacks: [Mutex<Vec<Stack>>; 4],
+    free_stacks: [Mutex<Vec<Stack>>; N],
+    /// Threads in [`allocate_blocking`](Self::allocate_blocking), of every
+    /// size class: a returned stack of one class can make room under the
+    /// limit for another
+    returned: WaitQueue,
+    /// Most stacks handed out and not yet returned
+    limit: usize,
+    /// [`StackScrub`] per size class
+    scrub: [AtomicU8; N],
+    /// Stacks of each class in existence, free or in use
+    live: [AtomicUsize; N],
     /// Statistics counters
     stats: StackPoolStats,
 }
@@ -192,14 +455,54 @@ impl Default for StackPool {
 }
 
 impl StackPool {
+    /// A pool of the [`PRESET_CLASSES`].
     pub const fn new() -> Self {
+        Self::with_limit(usize::MAX)
+    }
+
+    /// A pool that hands out at most `limit` stacks at a time; further
+    /// allocations fail, or wait in
+    /// [`allocate_blocking`](Self::allocate_blocking), until one is
+    /// deallocated.
+    pub const fn with_limit(limit: usize) -> Self {
+        Self::with_classes_and_limit(PRESET_CLASSES, limit)
+    }
+}
+
+impl<const N: usize> StackPool<N> {
+    /// A pool serving the size classes in `classes`.
+    ///
+    /// # Panics
+    ///
+    /// If a class is too small to hold the red zone.
+    pub const fn with_classes(classes: [StackClassConfig; N]) -> Self {
+        Self::with_classes_and_limit(classes, usize::MAX)
+    }
+
+    /// [`with_classes`](Self::with_classes) with a limit on all classes
+    /// together, as in [`with_limit`](StackPool::with_limit).
+    pub const fn with_classes_and_limit(classes: [StackClassConfig; N], limit: usize) -> Self {
+        let mut i = 0;
+        while i < N {
+            assert!(
+                classes[i].size > RED_ZONE_SIZE,
+                "stack class smaller than the red zone"
+            );
+            i += 1;
+        }
+        #[allow(clippy::declare_interior_mutable_const)]
+        const NO_STACKS: Mutex<Vec<Stack>> = Mutex::new(Vec::new());
+        #[allow(clippy::declare_interior_mutable_const)]
+        const NO_SCRUB: AtomicU8 = AtomicU8::new(StackScrub::None as u8);
+        #[allow(clippy::declare_interior_mutable_const)]
+        const NONE_LIVE: AtomicUsize = AtomicUsize::new(0);
         Self {
-            free_stacks: [
-                Mutex::new(Vec::new()),
-                Mutex::new(Vec::new()),
-                Mutex::new(Vec::new()),
-                Mutex::new(Vec::new()),
-            ],
+            classes,
+            free_stacks: [NO_STACKS; N],
+            returned: WaitQueue::new(),
+            limit,
+            scrub: [NO_SCRUB; N],
+            live: [NONE_LIVE; N],
             stats: StackPoolStats {
                 allocated: AtomicUsize::new(0),
                 deallocated: AtomicUsize::new(0),
@@ -208,6 +511,25 @@ impl StackPool {
         }
     }
 
+    /// The size class table.
+    pub fn classes(&self) -> &[StackClassConfig; N] {
+        &self.classes
+    }
+
+    /// The class `spec` asks for, if the table has one.
+    pub fn resolve(&self, spec: impl Into<StackSpec>) -> Option<StackClass> {
+        match spec.into() {
+            StackSpec::Class(class) => (class.index() < N).then_some(class),
+            StackSpec::Bytes(bytes) => self
+                .classes
+                .iter()
+                .enumerate()
+                .filter(|(_, config)| config.size >= bytes)
+                .min_by_key(|(_, config)| config.size)
+                .map(|(index, _)| StackClass(index)),
+        }
+    }
+
     /// Allocate a stack of the given size class.
     ///
     /// This will first try to reuse a stack from the free list, and only
@@ -215,24 +537,132 @@ impl StackPool {
     ///
     /// # Arguments
     ///
-    /// * `size_class` - The desired stack size class
+    /// * `spec` - The desired size class, or a size in bytes
     ///
     /// # Returns
     ///
-    /// A new stack, or `None` if allocation fails.
-    pub fn allocate(&self, size_class: StackSizeClass) -> Option<Stack> {
-        let class_index = self.size_class_index(size_class);
+    /// A new stack, or `None` if allocation fails or no class fits.
+    pub fn allocate(&self, spec: impl Into<StackSpec>) -> Option<Stack> {
+        let class = self.resolve(spec)?;
+        self.allocate_with(class, self.scrub(class))
+    }
+
+    /// Allocate a stack, scrubbing it according to `scrub` rather than the
+    /// size class's policy.
+    pub fn allocate_with(&self, spec: impl Into<StackSpec>, scrub: StackScrub) -> Option<Stack> {
+        let class = self.resolve(spec)?;
 
         // Try to get a stack from the free list first
-        if let Some(mut free_list) = self.free_stacks[class_index].try_lock() {
+        if let Some(mut free_list) = self.free_stacks[class.0].try_lock() {
             if let Some(stack) = free_list.pop() {
                 self.stats.in_use.fetch_add(1, Ordering::AcqRel);
-                return Some(stack);
+                return Some(stack.prepare(scrub));
             }
         }
 
         // Need to allocate a new stack
-        self.allocate_new_stack(size_class)
+        self.allocate_new_stack(class)
+            .map(|stack| stack.prepare(scrub))
+    }
+
+    /// Scrub stacks of a size class according to `scrub` from now on.
+    pub fn set_scrub(&self, spec: impl Into<StackSpec>, scrub: StackScrub) {
+        if let Some(class) = self.resolve(spec) {
+            self.scrub[class.0].store(scrub as u8, Ordering::Relaxed);
+        }
+    }
+
+    /// The scrub policy for a size class.
+    pub fn scrub(&self, spec: impl Into<StackSpec>) -> StackScrub {
+        self.resolve(spec).map_or(StackScrub::None, |class| {
+            StackScrub::from_u8(self.scrub[class.0].load(Ordering::Relaxed))
+        })
+    }
+
+    /// Allocate a stack, waiting for one of the class to be deallocated if
+    /// the pool is exhausted.
+    ///
+    /// Gives up after `timeout`, or waits indefinitely if it is `None`.
+    /// Blocks the calling thread, so it must not be called from interrupt
+    /// context. Returns `None` straight away if no class fits.
+    pub fn allocate_blocking(
+        &self,
+        spec: impl Into<StackSpec>,
+        timeout: Option<Duration>,
+    ) -> Option<Stack> {
+        let class = self.resolve(spec)?;
+        let timeout = timeout.map(Timeout::after);
+        let returned = Returned { pool: self, class };
+
+        loop {
+            if let Some(stack) = self.allocate(class) {
+                return Some(stack);
+            }
+
+            let mut selector = Selector::new();
+            selector.add(&returned);
+            if let Some(timeout) = &timeout {
+                if selector.add(timeout) == selector.wait() {
+                    return self.allocate(class);
+                }
+            } else {
+                selector.wait();
+            }
+        }
+    }
+
+    /// Allocate `count` stacks of one size class at once.
+    ///
+    /// Reuses as many free stacks as possible under a single lock before
+    /// allocating new ones. If any allocation fails the stacks taken so
+    /// far go back to the pool and `None` is returned.
+    ///
+    /// Unlike [`allocate`](Self::allocate) this waits for the free list
+    /// rather than skipping it when contended, so call it from thread
+    /// context only.
+    pub fn allocate_batch(&self, spec: impl Into<StackSpec>, count: usize) -> Option<Vec<Stack>> {
+        let class = self.resolve(spec)?;
+        self.allocate_batch_with(class, count, self.scrub(class))
+    }
+
+    /// [`allocate_batch`](Self::allocate_batch) with an explicit scrub policy.
+    pub fn allocate_batch_with(
+        &self,
+        spec: impl Into<StackSpec>,
+        count: usize,
+        scrub: StackScrub,
+    ) -> Option<Vec<Stack>> {
+        let class = self.resolve(spec)?;
+        let mut stacks = Vec::with_capacity(count);
+
+        {
+            let mut free_list = self.free_stacks[class.0].lock();
+            let reused = free_list.len().min(count);
+            let start = free_list.len() - reused;
+            stacks.extend(free_list.drain(start..));
+            self.stats.in_use.fetch_add(reused, Ordering::AcqRel);
+        }
+
+        while stacks.len() < count {
+            match self.allocate_new_stack(class) {
+                Some(stack) => stacks.push(stack),
+                None => {
+                    stacks.into_iter().for_each(|stack| self.deallocate(stack));
+                    return None;
+                }
+            }
+            crate::preemption_point!();
+        }
+        // Scrubbing a large batch takes a while
+        Some(
+            stacks
+                .into_iter()
+                .map(|stack| {
+                    crate::preemption_point!();
+                    stack.prepare(scrub)
+                })
+                .collect(),
+        )
     }
 
     /// Return a stack to the pool for reuse.
@@ -241,14 +671,19 @@ impl StackPool {
     ///
     /// * `stack` - The stack to return to the pool
     pub fn deallocate(&self, stack: Stack) {
-        let class_index = self.size_class_index(stack.size_class);
+        let class_index = stack.class.0;
+        stack.retire();
 
         if let Some(mut free_list) = self.free_stacks[class_index].try_lock() {
             free_list.push(stack);
-            self.stats.in_use.fetch_sub(1, Ordering::AcqRel);
             self.stats.deallocated.fetch_add(1, Ordering::AcqRel);
+        } else {
+            // The stack will be dropped
+            self.live[class_index].fetch_sub(1, Ordering::AcqRel);
         }
-        // If we can't get the lock, the stack will be dropped
+        self.stats.in_use.fetch_sub(1, Ordering::AcqRel);
+        // Waiters of any class may now fit under the limit
+        self.returned.notify_all();
     }
 
     /// Get statistics about the stack pool.
@@ -260,74 +695,52 @@ impl StackPool {
         )
     }
 
-    /// Convert a size class to an array index.
-    fn size_class_index(&self, size_class: StackSizeClass) -> usize {
-        match size_class {
-            StackSizeClass::Small => 0,
-            StackSizeClass::Medium => 1,
-            StackSizeClass::Large => 2,
-            StackSizeClass::ExtraLarge => 3,
-        }
+    /// Whether a new stack of `class` would stay within the pool's limit
+    /// and the class's count.
+    fn has_room(&self, class: StackClass) -> bool {
+        self.stats.in_use.load(Ordering::Acquire) < self.limit
+            && self.live[class.0].load(Ordering::Acquire) < self.classes[class.0].count
     }
 
-    fn allocate_new_stack(&self, size_class: StackSizeClass,) -> Option<Stack> {
-        let usable_size = size_class.size();
-
-        #[cfg(feature = "std-shim")]
-        {
-            extern crate std;
-            use std::alloc::{alloc, Layout};
-
-            let total_size = usable_size;
-            let layout = Layout::from_size_align(total_size, 4096).ok()?;
-            let memory = unsafe { alloc(layout) };
-
-            if memory.is_null() {
-                return None;
-            }
-
-            let memory = unsafe { NonNull::new_unchecked(memory) };
-
-            let stack = Stack {
-                memory,
-                usable_size,
-                size_class,
-                has_guard_pages: false,
-            };
-
-
-            self.stats.allocated.fetch_add(1, Ordering::AcqRel);
-            self.stats.in_use.fetch_add(1, Ordering::AcqRel);
-
-            Some(stack)
+    fn allocate_new_stack(&self, class: StackClass) -> Option<Stack> {
+        if self.stats.in_use.load(Ordering::Acquire) >= self.limit {
+            return None;
+        }
+        let config = self.classes[class.0];
+        let live = &self.live[class.0];
+        if live.fetch_add(1, Ordering::AcqRel) >= config.count {
+            live.fetch_sub(1, Ordering::AcqRel);
+            return None;
         }
+        let usable_size = config.size;
+        let total_size = usable_size + if config.guard { GUARD_SIZE } else { 0 };
 
+        #[cfg(feature = "std-shim")]
+        use std::alloc::{alloc, Layout};
+        // In bare-metal mode, use the global allocator (e.g., bump allocator)
         #[cfg(not(feature = "std-shim"))]
-        {
-            // In bare-metal mode, use the global allocator (e.g., bump allocator)
-            use alloc::alloc::{alloc, Layout};
-
-            let layout = Layout::from_size_align(usable_size, 4096).ok()?;
-            let memory = unsafe { alloc(layout) };
-
-            if memory.is_null() {
-                return None;
-            }
-
-            let memory = unsafe { NonNull::new_unchecked(memory) };
+        use alloc::alloc::{alloc, Layout};
+
+        let memory = Layout::from_size_align(total_size, 4096)
+            .ok()
+            .and_then(|layout| NonNull::new(unsafe { alloc(layout) }));
+        let Some(memory) = memory else {
+            live.fetch_sub(1, Ordering::AcqRel);
+            return None;
+        };
 
-            let stack = Stack {
-                memory,
-                usable_size,
-                size_class,
-                has_guard_pages: false,
-            };
+        let stack = Stack {
+            memory,
+            usable_size,
+            class,
+            has_guard_pages: config.guard,
+            scrub: StackScrub::None,
+        };
 
-            self.stats.allocated.fetch_add(1, Ordering::AcqRel);
-            self.stats.in_use.fetch_add(1, Ordering::AcqRel);
+        self.stats.allocated.fetch_add(1, Ordering::AcqRel);
+        self.stats.in_use.fetch_add(1, Ordering::AcqRel);
 
-            Some(stack)
-        }
+        Some(stack)
     }
 }
 
@@ -338,7 +751,8 @@ impl Drop for Stack {
             extern crate std;
             use std::alloc::{dealloc, Layout};
 
-            if let Ok(layout) = Layout::from_size_align(self.usable_size, 4096) {
+            let total_size = self.usable_size + if self.has_guard_pages { GUARD_SIZE } else { 0 };
+            if let Ok(layout) = Layout::from_size_align(total_size, 4096) {
                 unsafe {
                     dealloc(self.memory.as_ptr(), layout);
                 }
@@ -350,6 +764,26 @@ impl Drop for Stack {
 unsafe impl Send for Stack {}
 unsafe impl Sync for Stack {}
 
+/// Ready when a stack of one class is waiting in the pool's free list, or
+/// a new one would fit.
+struct Returned<'a, const N: usize> {
+    pool: &'a StackPool<N>,
+    class: StackClass,
+}
+
+impl<const N: usize> Selectable for Returned<'_, N> {
+    fn is_ready(&self) -> bool {
+        self.pool.has_room(self.class)
+            || self.pool.free_stacks[self.class.0]
+                .try_lock()
+                .is_some_and(|free_list| !free_list.is_empty())
+    }
+
+    fn wait_queue(&self) -> &WaitQueue {
+        &self.pool.returned
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -360,7 +794,10 @@ mod tests {
         assert_eq!(StackSizeClass::for_size(4096), Some(StackSizeClass::Small));
         assert_eq!(StackSizeClass::for_size(8192), Some(StackSizeClass::Medium));
         assert_eq!(StackSizeClass::for_size(32768), Some(StackSizeClass::Large));
-        assert_eq!(StackSizeClass::for_size(131072), Some(StackSizeClass::ExtraLarge));
+        assert_eq!(
+            StackSizeClass::for_size(131072),
+            Some(StackSizeClass::ExtraLarge)
+        );
         assert_eq!(StackSizeClass::for_size(500000), None);
     }
 
@@ -370,7 +807,7 @@ mod tests {
         let pool = StackPool::new();
         let stack = pool.allocate(StackSizeClass::Small).unwrap();
 
-        assert_eq!(stack.size_class(), StackSizeClass::Small);
+        assert_eq!(stack.class(), StackClass::new(0));
         assert_eq!(stack.size(), StackSizeClass::Small.size());
 
         pool.deallocate(stack);
@@ -381,6 +818,147 @@ mod tests {
         assert_eq!(in_use, 0);
     }
 
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_custom_class_table() {
+        let pool = StackPool::with_classes([
+            StackClassConfig::new(8192).count(1).guard(true),
+            StackClassConfig::new(1024),
+        ]);
+        assert_eq!(
+            pool.resolve(StackSpec::Bytes(600)),
+            Some(StackClass::new(1))
+        );
+        assert_eq!(
+            pool.resolve(StackSizeClass::Small),
+            Some(StackClass::new(0))
+        );
+        assert_eq!(pool.resolve(StackSpec::Bytes(10_000)), None);
+        assert_eq!(pool.resolve(StackClass::new(2)), None);
+
+        let guarded = pool.allocate(StackClass::new(0)).unwrap();
+        assert!(guarded.has_guard_pages());
+        assert_eq!(guarded.size(), 8192);
+        assert_eq!(guarded.bounds().end - guarded.base() as usize, 8192);
+        // Only one stack of the class may exist
+        assert!(pool.allocate(StackSpec::Bytes(5000)).is_none());
+        pool.deallocate(guarded);
+        assert!(pool.allocate(StackSpec::Bytes(5000)).is_some());
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_allocate_batch_reuses_free_stacks() {
+        let pool = StackPool::new();
+        let first = pool.allocate(StackSizeClass::Small).unwrap();
+        pool.deallocate(first);
+
+        let stacks = pool.allocate_batch(StackSizeClass::Small, 3).unwrap();
+        assert_eq!(stacks.len(), 3);
+        assert_eq!(pool.stats(), (3, 1, 3));
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_allocate_batch_waits_for_contended_free_list() {
+        extern crate std;
+        use core::sync::atomic::AtomicBool;
+
+        let pool = StackPool::new();
+        let first = pool.allocate(StackSizeClass::Small).unwrap();
+        pool.deallocate(first);
+
+        let held = AtomicBool::new(false);
+        let stacks = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let class = pool.resolve(StackSizeClass::Small).unwrap();
+                let _free_list = pool.free_stacks[class.0].lock();
+                held.store(true, Ordering::Release);
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            });
+            while !held.load(Ordering::Acquire) {
+                core::hint::spin_loop();
+            }
+            pool.allocate_batch(StackSizeClass::Small, 2).unwrap()
+        });
+        assert_eq!(stacks.len(), 2);
+        // The freed stack was reused rather than skipped
+        assert_eq!(pool.stats(), (2, 1, 2));
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_allocate_blocking_takes_returned_stack() {
+        let pool = StackPool::with_limit(1);
+        let stack = pool.allocate(StackSizeClass::Small).unwrap();
+        assert!(pool.allocate(StackSizeClass::Small).is_none());
+        // Host time stands still, so a zero timeout is the only one that expires
+        assert!(pool
+            .allocate_blocking(StackSizeClass::Small, Some(Duration::from_nanos(0)))
+            .is_none());
+
+        pool.deallocate(stack);
+        assert!(pool
+            .allocate_blocking(StackSizeClass::Small, None)
+            .is_some());
+        assert_eq!(pool.stats(), (1, 1, 1));
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_returned_stack_makes_room_for_other_classes() {
+        let pool = StackPool::with_limit(1);
+        let medium = pool.allocate(StackSizeClass::Medium).unwrap();
+        let small = Returned {
+            pool: &pool,
+            class: pool.resolve(StackSizeClass::Small).unwrap(),
+        };
+        assert!(!small.is_ready());
+
+        // Returning a Medium stack leaves room for a new Small one
+        pool.deallocate(medium);
+        assert!(small.is_ready());
+        let stack = pool.allocate_blocking(StackSizeClass::Small, None).unwrap();
+
+        // A stack dropped because its free list is busy still counts as returned
+        let free_list = pool.free_stacks[stack.class.0].lock();
+        pool.deallocate(stack);
+        drop(free_list);
+        assert_eq!(pool.stats().2, 0);
+        assert!(pool.allocate(StackSizeClass::Large).is_some());
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_scrub_policies() {
+        let pool = StackPool::with_limit(1);
+        let reuse = |scrub: Option<StackScrub>| {
+            let stack = match scrub {
+                Some(scrub) => pool.allocate_with(StackSizeClass::Small, scrub).unwrap(),
+                None => pool.allocate(StackSizeClass::Small).unwrap(),
+            };
+            unsafe { core::ptr::write_bytes(stack.base() as *mut u8, 0x5E, stack.size()) };
+            pool.deallocate(stack);
+            let stack = pool.allocate(StackSizeClass::Small).unwrap();
+            let byte = unsafe { *stack.base().add(100) };
+            pool.deallocate(stack);
+            byte
+        };
+
+        let poisoned = if cfg!(debug_assertions) {
+            FREED_STACK_POISON
+        } else {
+            0x5E
+        };
+        assert_eq!(reuse(None), poisoned);
+        // A per-stack policy travels with the stack back to the pool
+        assert_eq!(reuse(Some(StackScrub::OnDeallocate)), 0);
+
+        pool.set_scrub(StackSizeClass::Small, StackScrub::OnAllocate);
+        assert_eq!(pool.scrub(StackSizeClass::Small), StackScrub::OnAllocate);
+        assert_eq!(reuse(None), 0);
+    }
+
     #[cfg(feature = "std-shim")]
     #[test]
     fn test_stack_canary() {
@@ -394,4 +972,34 @@ mod tests {
 
         pool.deallocate(stack);
     }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_stack_bounds() {
+        let pool = StackPool::new();
+        let stack = pool.allocate(StackSizeClass::Small).unwrap();
+        let bounds = stack.bounds();
+        assert_eq!(bounds.start, stack.base() as usize);
+        assert_eq!(bounds.end, stack.end() as usize);
+        assert_eq!(stack.top(), stack.stack_top());
+        assert!(bounds.len() <= stack.size() && bounds.len() > stack.size() - 16);
+        // The test runs on the host thread's stack, not this one
+        assert_eq!(stack.remaining(), None);
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_red_zone_breach() {
+        let pool = StackPool::new();
+        let stack = pool.allocate(StackSizeClass::Small).unwrap();
+        stack.install_red_zone();
+        assert_eq!(stack.red_zone_breach(), None);
+
+        let top = stack.stack_top() as usize;
+        unsafe { ((top + RED_ZONE_SIZE - 8) as *mut u64).write(0) };
+        unsafe { ((top + 64) as *mut u64).write(0) };
+        assert_eq!(stack.red_zone_breach(), Some(top + 64));
+
+        pool.deallocate(stack);
+    }
 }
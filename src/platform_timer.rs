@@ -1,30 +1,196 @@
 //! Platform-specific timer implementations for preemptive scheduling
+//!
+//! # Preemption points
+//!
+//! Outside [`PreemptionMode::Full`] the tick only sets a per-CPU
+//! "reschedule needed" flag, and the running thread gives up the CPU at its
+//! next preemption point: a lock release, a channel operation,
+//! [`preempt_enable`] or an explicit [`preemption_point!`](crate::preemption_point).
+//! A point costs a load and a branch when no switch is due, so code that
+//! can run for long without any of these should place one per iteration of
+//! its outer loop:
+//!
+//! ```ignore
+//! for block in image.chunks(4096) {
+//!     crc = crc32_update(crc, block);
+//!     preemption_point!();
+//! }
+//! ```
+//!
+//! Place them where the thread holds no spinlock and has interrupts
+//! enabled; elsewhere the point does nothing. The crate's own long loops,
+//! such as batch stack allocation and FAT32 directory walks, do the same.
+//!
+//! Each point that actually yields is counted by source location;
+//! [`yield_points`] lists them, busiest first, to show where threads
+//! really give up the CPU.
 
-use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::arch::{Arch, DefaultArch};
+use crate::sync::ordering::{self, Edge};
+use alloc::vec::Vec;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicU8, Ordering};
 
-static PREEMPTION_PENDING: AtomicBool = AtomicBool::new(false);
+crate::percpu! {
+    static PREEMPTION_PENDING: AtomicBool = AtomicBool::new(false);
+    static PREEMPT_DISABLE_DEPTH: AtomicU32 = AtomicU32::new(0);
+}
 static PREEMPTION_COUNT: AtomicU64 = AtomicU64::new(0);
+static PREEMPTION_MODE: AtomicU8 = AtomicU8::new(PreemptionMode::Full as u8);
+static COOPERATIVE_FALLBACK: AtomicBool = AtomicBool::new(false);
+
+/// When the timer tick may take the CPU away from a running thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PreemptionMode {
+    /// Switch threads from the timer interrupt, wherever the thread is.
+    Full = 0,
+    /// The timer tick only requests a switch; it happens at the next
+    /// preemption point (lock release, channel operation,
+    /// `preemption_point!`), yield or block.
+    Voluntary = 1,
+    /// Purely cooperative: threads only switch when they yield or block.
+    None = 2,
+}
+
+impl PreemptionMode {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => PreemptionMode::Voluntary,
+            2 => PreemptionMode::None,
+            _ => PreemptionMode::Full,
+        }
+    }
+}
+
+/// Select the kernel-wide preemption mode. Takes effect at the next tick.
+pub fn set_preemption_mode(mode: PreemptionMode) {
+    PREEMPTION_MODE.store(mode as u8, Ordering::Release);
+    if mode != PreemptionMode::Voluntary {
+        // A request left over from voluntary mode would fire at a random point
+        clear_preemption_pending();
+    }
+}
+
+/// The current preemption mode.
+pub fn preemption_mode() -> PreemptionMode {
+    PreemptionMode::from_u8(PREEMPTION_MODE.load(Ordering::Acquire))
+}
+
+/// Make every preemption point yield, standing in for a missing timer tick.
+pub(crate) fn set_cooperative_fallback(enabled: bool) {
+    COOPERATIVE_FALLBACK.store(enabled, Ordering::Release);
+}
+
+/// Whether the kernel runs cooperatively because no timer tick is
+/// available; see [`Kernel::fall_back_to_cooperative`](crate::Kernel::fall_back_to_cooperative).
+pub fn cooperative_fallback() -> bool {
+    COOPERATIVE_FALLBACK.load(Ordering::Acquire)
+}
+
+/// Ask the running thread to yield at its next preemption point.
+pub(crate) fn request_preemption() {
+    PREEMPTION_PENDING.get().store(true, Ordering::Release);
+    PREEMPTION_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Ask for the scheduler to run at the next moment it safely can.
+///
+/// Unlike [`yield_now`](crate::yield_now) this may be called from any
+/// context: an interrupt handler, with interrupts masked or preemption
+/// disabled. On AArch64 it raises the reschedule SGI on the calling CPU,
+/// which is taken once interrupts are unmasked and then handled like a
+/// timer tick, so it is deferred to [`preempt_enable`] or the next
+/// preemption point the same way. Elsewhere, and while the kernel runs
+/// without a tick, it sets the flag the next preemption point checks.
+pub fn request_reschedule() {
+    #[cfg(target_arch = "aarch64")]
+    if !cooperative_fallback() {
+        crate::arch::send_reschedule_ipi(1 << crate::arch::cpu_id());
+        return;
+    }
+    request_preemption();
+}
+
+/// Keep the timer tick from switching away from the running thread.
+///
+/// Interrupts stay enabled; a tick that arrives meanwhile is deferred to
+/// the matching [`preempt_enable`]. Calls nest.
+#[track_caller]
+pub fn preempt_disable() {
+    if PREEMPT_DISABLE_DEPTH
+        .get()
+        .fetch_add(1, ordering::acquire(Edge::PreemptDepth, Ordering::Acquire))
+        == 0
+    {
+        crate::time::latency::preempt_disabled(core::panic::Location::caller());
+    }
+}
+
+/// Undo one [`preempt_disable`], taking any deferred preemption once the
+/// outermost one is undone.
+///
+/// A call without a matching `preempt_disable` is logged and otherwise
+/// ignored.
+#[track_caller]
+pub fn preempt_enable() {
+    match decrement_depth(PREEMPT_DISABLE_DEPTH.get()) {
+        Some(1) => {
+            crate::time::latency::preempt_enabled();
+            preemption_checkpoint();
+        }
+        Some(_) => {}
+        None => {
+            let caller = Location::caller();
+            crate::klog!(
+                crate::kernel::log::Level::Error,
+                "preempt_enable without preempt_disable at {}:{}",
+                caller.file(),
+                caller.line()
+            );
+        }
+    }
+}
+
+/// Take one off a preemption disable depth, returning the depth before,
+/// or `None` if it was already zero.
+fn decrement_depth(depth: &AtomicU32) -> Option<u32> {
+    depth
+        .fetch_update(
+            ordering::release(Edge::PreemptDepth, Ordering::Release),
+            Ordering::Relaxed,
+            |depth| depth.checked_sub(1),
+        )
+        .ok()
+}
+
+/// Check whether preemption is currently disabled.
+pub fn preemption_disabled() -> bool {
+    PREEMPT_DISABLE_DEPTH
+        .get()
+        .load(ordering::acquire(Edge::PreemptDepth, Ordering::Acquire))
+        > 0
+}
 
 /// Signal handler that just sets a flag - actual scheduling happens outside signal context
-/// 
+///
 /// # Safety
 /// This function is called from signal context and only uses async-signal-safe operations.
 /// It only modifies atomic variables and performs no memory allocation or complex operations.
 #[cfg(target_os = "linux")]
 pub unsafe extern "C" fn signal_safe_handler(_sig: i32) {
     // Only use async-signal-safe operations here
-    PREEMPTION_PENDING.store(true, Ordering::Release);
-    PREEMPTION_COUNT.fetch_add(1, Ordering::Relaxed);
+    request_preemption();
 }
 
 /// Check if preemption is pending (called from normal context)
 pub fn is_preemption_pending() -> bool {
-    PREEMPTION_PENDING.load(Ordering::Acquire)
+    PREEMPTION_PENDING.get().load(Ordering::Acquire)
 }
 
 /// Clear preemption pending flag
 pub fn clear_preemption_pending() {
-    PREEMPTION_PENDING.store(false, Ordering::Release);
+    PREEMPTION_PENDING.get().store(false, Ordering::Release);
 }
 
 /// Get total preemption count for statistics
@@ -35,46 +201,57 @@ pub fn get_preemption_count() -> u64 {
 /// Platform-specific timer implementation for Linux using timerfd
 #[cfg(target_os = "linux")]
 pub mod linux_timer {
-    
+
     pub fn init_preemption_timer(_interval_ms: u64) -> Result<(), &'static str> {
         // For a complete implementation, you would:
         // 1. Create a timerfd using timerfd_create()
         // 2. Set it up with timerfd_settime()
         // 3. Use signalfd() or signal handlers
         // 4. Or use a separate thread with epoll/poll
-        
+
         // For now, return an error suggesting cooperative scheduling
         Err("Hardware timer preemption not implemented - use cooperative yield points")
     }
-    
+
     pub fn stop_preemption_timer() {
         // Would close the timerfd and clean up
     }
 }
 
 /// Platform-specific timer implementation for macOS/BSD
-#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd"))]
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
 pub mod bsd_timer {
-    
+
     pub fn init_preemption_timer(_interval_ms: u64) -> Result<(), &'static str> {
         // For BSD systems, you would use kqueue with EVFILT_TIMER
         // or setitimer() if available
         Err("Hardware timer preemption not implemented for BSD - use cooperative yield points")
     }
-    
+
     pub fn stop_preemption_timer() {
         // Would clean up kqueue timer
     }
 }
 
 /// Fallback implementation for other platforms
-#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd")))]
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
 pub mod generic_timer {
-    
+
     pub fn init_preemption_timer(_interval_ms: u64) -> Result<(), &'static str> {
         Err("Hardware timer preemption not supported on this platform")
     }
-    
+
     pub fn stop_preemption_timer() {
         // No-op
     }
@@ -84,11 +261,22 @@ pub mod generic_timer {
 pub fn init_preemption_timer(interval_ms: u64) -> Result<(), &'static str> {
     #[cfg(target_os = "linux")]
     return linux_timer::init_preemption_timer(interval_ms);
-    
-    #[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd"))]
+
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
     return bsd_timer::init_preemption_timer(interval_ms);
-    
-    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd")))]
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    )))]
     return generic_timer::init_preemption_timer(interval_ms);
 }
 
@@ -96,19 +284,39 @@ pub fn init_preemption_timer(interval_ms: u64) -> Result<(), &'static str> {
 pub fn stop_preemption_timer() {
     #[cfg(target_os = "linux")]
     linux_timer::stop_preemption_timer();
-    
-    #[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd"))]
+
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
     bsd_timer::stop_preemption_timer();
-    
-    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd")))]
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    )))]
     generic_timer::stop_preemption_timer();
 }
 
 /// Preemption checkpoint - should be called regularly from normal code
 /// This is where actual scheduling decisions are made, outside signal context
+///
+/// Does nothing inside critical sections (interrupts or preemption
+/// disabled), where a yield would re-enable interrupts under the caller.
+/// Without a timer tick ([`cooperative_fallback`]) every checkpoint
+/// yields, so lock releases and channel operations keep threads taking
+/// turns.
+#[track_caller]
 pub fn preemption_checkpoint() {
-    if is_preemption_pending() {
+    let due = is_preemption_pending() || cooperative_fallback();
+    if due && DefaultArch::interrupts_enabled() && !preemption_disabled() {
         clear_preemption_pending();
+        record_yield(Location::caller());
 
         // Safe to do complex operations here - we're not in signal context
         // Yield to scheduler
@@ -116,10 +324,157 @@ pub fn preemption_checkpoint() {
     }
 }
 
-/// Cooperative preemption points - insert these in long-running code
+/// Number of distinct source locations [`yield_points`] keeps counts for.
+pub const YIELD_SITES: usize = 32;
+
+/// A preemption point that has yielded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct YieldPoint {
+    /// Where the point is in the source.
+    pub location: &'static Location<'static>,
+    /// Times it gave up the CPU.
+    pub yields: u64,
+}
+
+struct YieldSite {
+    location: AtomicPtr<Location<'static>>,
+    yields: AtomicU64,
+}
+
+static YIELD_SITE_TABLE: [YieldSite; YIELD_SITES] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FREE: YieldSite = YieldSite {
+        location: AtomicPtr::new(core::ptr::null_mut()),
+        yields: AtomicU64::new(0),
+    };
+    [FREE; YIELD_SITES]
+};
+
+/// Count a yield at `location`, claiming a free entry for a new one.
+/// Yields at further locations once the table is full are not counted.
+fn record_yield(location: &'static Location<'static>) {
+    let wanted = location as *const Location<'static> as *mut Location<'static>;
+    for site in &YIELD_SITE_TABLE {
+        let mut current = site.location.load(Ordering::Acquire);
+        if current.is_null() {
+            current = match site.location.compare_exchange(
+                core::ptr::null_mut(),
+                wanted,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => wanted,
+                Err(other) => other,
+            };
+        }
+        // The same call site may have more than one `Location` copy
+        if current == wanted || unsafe { *current == *location } {
+            site.yields.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    }
+}
+
+/// The preemption points that have yielded since boot or the last
+/// [`reset_yield_points`], busiest first.
+pub fn yield_points() -> Vec<YieldPoint> {
+    let mut points: Vec<YieldPoint> = YIELD_SITE_TABLE
+        .iter()
+        .filter_map(|site| {
+            let location = site.location.load(Ordering::Acquire);
+            // Only ever set from a &'static Location
+            let location = unsafe { location.as_ref() }?;
+            Some(YieldPoint {
+                location,
+                yields: site.yields.load(Ordering::Relaxed),
+            })
+        })
+        .filter(|point| point.yields > 0)
+        .collect();
+    points.sort_by_key(|point| core::cmp::Reverse(point.yields));
+    points
+}
+
+/// Zero every yield count. Locations stay in the table.
+pub fn reset_yield_points() {
+    for site in &YIELD_SITE_TABLE {
+        site.yields.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Cooperative preemption point: yields if a reschedule is due, see the
+/// [module docs](crate::platform_timer#preemption-points).
 #[macro_export]
 macro_rules! preemption_point {
     () => {
         $crate::platform_timer::preemption_checkpoint();
     };
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preemption_mode_roundtrip() {
+        for mode in [
+            PreemptionMode::Voluntary,
+            PreemptionMode::None,
+            PreemptionMode::Full,
+        ] {
+            set_preemption_mode(mode);
+            assert_eq!(preemption_mode(), mode);
+        }
+        assert_eq!(PreemptionMode::from_u8(0xFF), PreemptionMode::Full);
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    #[test]
+    fn test_request_reschedule_defers_to_checkpoint() {
+        preempt_disable();
+        request_reschedule();
+        assert!(is_preemption_pending());
+        // Inside the critical section the checkpoint leaves it pending
+        preemption_checkpoint();
+        assert!(is_preemption_pending());
+        preempt_enable();
+        assert!(!is_preemption_pending());
+    }
+
+    #[test]
+    fn test_yields_counted_by_location() {
+        let here = Location::caller();
+        let elsewhere = Location::caller();
+        record_yield(here);
+        record_yield(here);
+        record_yield(elsewhere);
+        let points = yield_points();
+        let count = |location: &Location<'_>| {
+            points
+                .iter()
+                .find(|point| *point.location == *location)
+                .map(|point| point.yields)
+        };
+        assert_eq!(count(here), Some(2));
+        assert_eq!(count(elsewhere), Some(1));
+    }
+
+    #[test]
+    fn test_preempt_disable_nests() {
+        preempt_disable();
+        preempt_disable();
+        preempt_enable();
+        assert!(preemption_disabled());
+        preempt_enable();
+        assert!(!preemption_disabled());
+    }
+
+    #[test]
+    fn test_unmatched_enable_does_not_wrap() {
+        // A local depth, since the real one is shared with other tests
+        let depth = AtomicU32::new(1);
+        assert_eq!(decrement_depth(&depth), Some(1));
+        assert_eq!(decrement_depth(&depth), None);
+        assert_eq!(depth.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,318 @@
+//! Invariants every [`Scheduler`] implementation must uphold.
+//!
+//! Each check takes a freshly built, empty scheduler, drives it through the
+//! trait the way the kernel does and panics with a description of the
+//! broken invariant. [`check_all`] runs the whole battery; a new policy
+//! gets covered by adding one test that calls it:
+//!
+//! ```ignore
+//! #[test]
+//! fn test_conformance() {
+//!     preemptive_threads::sched::conformance::check_all(|| MyScheduler::new());
+//! }
+//! ```
+//!
+//! Threads are always picked on CPU 0, the CPU the kernel dispatches on,
+//! so multi-CPU schedulers must hand it work queued on other CPUs.
+
+use super::trait_def::Scheduler;
+use crate::mem::{StackPool, StackSizeClass};
+use crate::thread::{JoinHandle, ReadyRef, Thread, ThreadId, ThreadState};
+use alloc::vec::Vec;
+
+/// Priorities the checks spawn at, one in each common band.
+const PRIORITIES: [u8; 6] = [0, 32, 128, 128, 200, 255];
+
+/// Run every check, each against a new scheduler from `make`.
+pub fn check_all<S: Scheduler>(make: impl Fn() -> S) {
+    check_no_thread_lost(&make());
+    check_wake_up_makes_runnable(&make());
+    check_yield_requeues(&make());
+    check_stats_consistent(&make());
+    check_lifecycle_stats(&make());
+    check_snapshot_matches_queues(&make());
+}
+
+/// Every enqueued thread is picked exactly once, then the queues are empty.
+pub fn check_no_thread_lost<S: Scheduler>(scheduler: &S) {
+    let pool = StackPool::new();
+    let threads = spawn_all(&pool, &PRIORITIES);
+    for thread in &threads {
+        scheduler.enqueue(ReadyRef(thread.clone()));
+    }
+
+    let mut picked = drain(scheduler);
+    picked.sort_unstable();
+    let mut expected: Vec<usize> = threads.iter().map(|t| t.id().get()).collect();
+    expected.sort_unstable();
+    assert_eq!(
+        picked, expected,
+        "enqueued threads not each picked exactly once"
+    );
+}
+
+/// A thread that blocked isn't picked until it is woken, and is picked
+/// once it has been.
+pub fn check_wake_up_makes_runnable<S: Scheduler>(scheduler: &S) {
+    let pool = StackPool::new();
+    let thread = spawn(&pool, 1, 128).0;
+    scheduler.enqueue(ReadyRef(thread.clone()));
+
+    let running = scheduler
+        .pick_next(0)
+        .expect("enqueued thread not picked")
+        .start_running();
+    scheduler.on_block(running);
+    assert_eq!(
+        thread.state(),
+        ThreadState::Blocked,
+        "on_block left the thread runnable"
+    );
+    assert!(
+        scheduler.pick_next(0).is_none(),
+        "blocked thread was picked"
+    );
+
+    // As the kernel's wake path does
+    assert!(thread.compare_and_set_state(ThreadState::Blocked, ThreadState::Ready));
+    scheduler.wake_up(ReadyRef(thread.clone()));
+    assert_eq!(
+        drain(scheduler),
+        [thread.id().get()],
+        "woken thread not runnable"
+    );
+}
+
+/// A yielding thread goes back on the run queue, Ready, alongside the
+/// threads already there.
+///
+/// Where it lands relative to its peers is policy: per-CPU schedulers run
+/// their own queue before stealing, so it may well be picked first.
+pub fn check_yield_requeues<S: Scheduler>(scheduler: &S) {
+    let pool = StackPool::new();
+    let threads = spawn_all(&pool, &[128, 128]);
+    for thread in &threads {
+        scheduler.enqueue(ReadyRef(thread.clone()));
+    }
+
+    let first = scheduler.pick_next(0).expect("enqueued thread not picked");
+    let yielded = first.0.clone();
+    scheduler.on_yield(first.start_running());
+    assert_eq!(
+        yielded.state(),
+        ThreadState::Ready,
+        "on_yield didn't make the thread Ready"
+    );
+
+    let mut order = drain(scheduler);
+    order.sort_unstable();
+    assert_eq!(order, [1, 2], "yielding thread lost or duplicated");
+}
+
+/// `stats` counts exactly the queued threads as runnable, never reports
+/// more runnable threads than it knows of and splits the rest as blocked.
+pub fn check_stats_consistent<S: Scheduler>(scheduler: &S) {
+    let pool = StackPool::new();
+    let threads = spawn_all(&pool, &PRIORITIES);
+
+    assert_stats(scheduler, 0);
+    for (queued, thread) in threads.iter().enumerate() {
+        scheduler.enqueue(ReadyRef(thread.clone()));
+        assert_stats(scheduler, queued + 1);
+    }
+    for queued in (0..threads.len()).rev() {
+        scheduler.pick_next(0).expect("enqueued thread not picked");
+        assert_stats(scheduler, queued);
+    }
+}
+
+/// Through spawns, blocking, waking and exits, `stats` counts every
+/// spawned thread that hasn't exited, and those not queued as blocked.
+pub fn check_lifecycle_stats<S: Scheduler>(scheduler: &S) {
+    let pool = StackPool::new();
+    let threads = spawn_all(&pool, &[128, 128, 128, 128]);
+    for thread in &threads {
+        scheduler.on_spawn(thread.id());
+        scheduler.enqueue(ReadyRef(thread.clone()));
+    }
+    assert_lifecycle(scheduler, 4, 4);
+
+    // One runs and blocks
+    let blocked = scheduler.pick_next(0).expect("enqueued thread not picked");
+    let blocked_thread = blocked.0.clone();
+    assert_lifecycle(scheduler, 4, 3);
+    scheduler.on_block(blocked.start_running());
+    assert_lifecycle(scheduler, 4, 3);
+
+    // Another runs to completion
+    let exiting = scheduler
+        .pick_next(0)
+        .expect("enqueued thread not picked")
+        .start_running();
+    let exited = exiting.id();
+    exiting.finish();
+    scheduler.on_exit(exited);
+    assert_lifecycle(scheduler, 3, 2);
+
+    assert!(blocked_thread.compare_and_set_state(ThreadState::Blocked, ThreadState::Ready));
+    scheduler.wake_up(ReadyRef(blocked_thread));
+    assert_lifecycle(scheduler, 3, 3);
+
+    // Killing a queued thread takes it out first, where supported
+    let queued = threads
+        .iter()
+        .map(|t| t.id())
+        .find(|&id| id != exited)
+        .expect("a queued thread");
+    if scheduler.pick_specific(queued).is_some() {
+        scheduler.on_exit(queued);
+        assert_lifecycle(scheduler, 2, 2);
+    }
+
+    for thread in drain_threads(scheduler) {
+        scheduler.on_exit(thread.id());
+    }
+    assert_lifecycle(scheduler, 0, 0);
+}
+
+/// `debug_snapshot`, if the scheduler supports it, lists each queued
+/// thread exactly once, in a queue covering its priority, and nothing
+/// once the queues are drained.
+pub fn check_snapshot_matches_queues<S: Scheduler>(scheduler: &S) {
+    let pool = StackPool::new();
+    let threads = spawn_all(&pool, &PRIORITIES);
+    for thread in &threads {
+        scheduler.enqueue(ReadyRef(thread.clone()));
+    }
+
+    let snapshot = scheduler.debug_snapshot();
+    if snapshot.is_empty() {
+        // Not introspectable
+        drain(scheduler);
+        return;
+    }
+    let mut listed = Vec::new();
+    for queue in &snapshot {
+        for &id in &queue.threads {
+            let thread = threads
+                .iter()
+                .find(|t| t.id() == id)
+                .expect("snapshot lists an unknown thread");
+            assert!(
+                queue.priorities.contains(&thread.priority()),
+                "thread {} at priority {} listed in a queue for {:?}",
+                id,
+                thread.priority(),
+                queue.priorities
+            );
+            listed.push(id.get());
+        }
+    }
+    listed.sort_unstable();
+    let mut expected: Vec<usize> = threads.iter().map(|t| t.id().get()).collect();
+    expected.sort_unstable();
+    assert_eq!(
+        listed, expected,
+        "snapshot doesn't list each queued thread exactly once"
+    );
+
+    drain(scheduler);
+    assert!(
+        scheduler
+            .debug_snapshot()
+            .iter()
+            .all(|queue| queue.threads.is_empty()),
+        "snapshot lists threads after the queues were drained"
+    );
+}
+
+fn assert_lifecycle<S: Scheduler>(scheduler: &S, live: usize, queued: usize) {
+    let (total, runnable, blocked) = scheduler.stats();
+    assert_eq!(
+        (total, runnable, blocked),
+        (live, queued, live - queued),
+        "stats don't match {} live threads with {} queued",
+        live,
+        queued
+    );
+}
+
+fn assert_stats<S: Scheduler>(scheduler: &S, queued: usize) {
+    let (total, runnable, blocked) = scheduler.stats();
+    assert_eq!(
+        runnable, queued,
+        "runnable count doesn't match the queued threads"
+    );
+    assert!(
+        total >= runnable,
+        "more runnable threads ({}) than total ({})",
+        runnable,
+        total
+    );
+    assert_eq!(
+        blocked,
+        total - runnable,
+        "blocked isn't total minus runnable"
+    );
+}
+
+/// Pick until the scheduler runs dry, returning the thread ids in order.
+fn drain<S: Scheduler>(scheduler: &S) -> Vec<usize> {
+    drain_threads(scheduler)
+        .iter()
+        .map(|thread| thread.id().get())
+        .collect()
+}
+
+fn drain_threads<S: Scheduler>(scheduler: &S) -> Vec<ReadyRef> {
+    let mut picked = Vec::new();
+    while let Some(thread) = scheduler.pick_next(0) {
+        picked.push(thread);
+        assert!(picked.len() <= 64, "scheduler keeps returning threads");
+    }
+    picked
+}
+
+fn spawn_all(pool: &StackPool, priorities: &[u8]) -> Vec<Thread> {
+    priorities
+        .iter()
+        .enumerate()
+        .map(|(i, &priority)| spawn(pool, i + 1, priority).0)
+        .collect()
+}
+
+/// A do-nothing thread with a fixed id on a small stack from `pool`, for
+/// driving schedulers by hand here and in their own tests.
+pub(crate) fn spawn(pool: &StackPool, id: usize, priority: u8) -> (Thread, JoinHandle) {
+    let stack = pool
+        .allocate(StackSizeClass::Small)
+        .expect("stack for conformance thread");
+    Thread::new(
+        unsafe { ThreadId::new_unchecked(id) },
+        stack,
+        || {},
+        priority,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::reclaim::{Hazard, Qsbr};
+    use crate::sched::{
+        DynScheduler, FirstComeFirstServeScheduler, GangScheduler, RoundRobinScheduler,
+    };
+
+    #[test]
+    fn test_builtin_schedulers_conform() {
+        check_all(|| RoundRobinScheduler::new(1));
+        // Work lands on the other CPUs' queues and has to be stolen
+        check_all(|| RoundRobinScheduler::new(4));
+        check_all(|| RoundRobinScheduler::<Hazard>::with_reclamation(1));
+        check_all(|| RoundRobinScheduler::<Qsbr>::with_reclamation(1));
+        check_all(FirstComeFirstServeScheduler::new);
+        check_all(|| FirstComeFirstServeScheduler::new().with_priority_order());
+        check_all(|| GangScheduler::new(RoundRobinScheduler::new(1), 1));
+        check_all(|| DynScheduler::new(alloc::boxed::Box::new(RoundRobinScheduler::new(1))));
+    }
+}
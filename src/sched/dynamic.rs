@@ -0,0 +1,242 @@
+//! Switching the scheduling policy at runtime.
+//!
+//! The kernel's scheduler is fixed by its type. [`DynScheduler`] is a
+//! scheduler that forwards to a boxed policy which can be replaced while
+//! threads are running, e.g. to compare policies in an experiment or to
+//! move to a latency-oriented policy when a real-time workload starts:
+//!
+//! ```ignore
+//! use preemptive_threads::sched::{DynScheduler, FirstComeFirstServeScheduler, RoundRobinScheduler};
+//!
+//! static KERNEL: Lazy<Kernel<DefaultArch, DynScheduler>> =
+//!     Lazy::new(|| Kernel::new(DynScheduler::new(Box::new(RoundRobinScheduler::new(1)))));
+//!
+//! // Later, from any thread
+//! KERNEL.scheduler().switch_to(Box::new(FirstComeFirstServeScheduler::new()))?;
+//! ```
+//!
+//! [`switch_to`](DynScheduler::switch_to) holds off preemption and
+//! interrupts, drains every ready thread from the old policy and queues it
+//! in the new one, then swaps the two. Threads that are running or blocked
+//! at the time aren't in any queue; they reach the new policy the next time
+//! they yield, are preempted or are woken. Per-thread state the old policy
+//! kept, such as accumulated time slices, is lost; each thread takes on the
+//! new policy's [slice curves](crate::time::SliceCurves) as it is queued.
+//! The thread count in [`stats`](Scheduler::stats) is kept across
+//! switches, since a new policy only sees threads spawned after it.
+
+use super::trait_def::{CpuId, QueueSnapshot, Scheduler};
+use crate::arch;
+use crate::kernel::log::Level;
+use crate::mem::percpu::MAX_CPUS;
+use crate::platform_timer::{preempt_disable, preempt_enable};
+use crate::thread::{ReadyRef, RunningRef, ThreadId};
+use crate::time::SliceCurves;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A boxed scheduling policy.
+pub type BoxedScheduler = Box<dyn Scheduler>;
+
+/// A scheduler whose policy can be replaced at runtime, see the
+/// [module docs](self).
+pub struct DynScheduler {
+    /// Locked with interrupts disabled
+    policy: spin::Mutex<BoxedScheduler>,
+    /// Live threads, kept here since each policy only knows of those
+    /// spawned while it was in place
+    total_threads: AtomicUsize,
+}
+
+impl DynScheduler {
+    /// Start out with `policy`.
+    pub fn new(policy: BoxedScheduler) -> Self {
+        Self {
+            policy: spin::Mutex::new(policy),
+            total_threads: AtomicUsize::new(0),
+        }
+    }
+
+    /// Replace the policy with `new`, moving every ready thread into it.
+    ///
+    /// Returns the old policy, now empty. If `new` rejects a thread, the
+    /// threads go back to the old policy, which stays in place, and `new`
+    /// is handed back as the error.
+    pub fn switch_to(&self, new: BoxedScheduler) -> Result<BoxedScheduler, BoxedScheduler> {
+        preempt_disable();
+        let result = arch::without_interrupts(|| {
+            let mut policy = self.policy.lock();
+            let ready = drain(&**policy);
+            let moved = ready.len();
+            if let Err(rejected) = migrate(ready, &*new) {
+                // Everything back where it was, including what `new` took
+                let mut back = drain(&*new);
+                back.extend(rejected);
+                for thread in back {
+                    thread.0.set_slice_curves(policy.slice_curves());
+                    policy.enqueue(thread);
+                }
+                return Err(new);
+            }
+            crate::klog!(
+                Level::Info,
+                "scheduler switched, {} ready threads migrated",
+                moved
+            );
+            Ok(core::mem::replace(&mut *policy, new))
+        });
+        preempt_enable();
+        result
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&dyn Scheduler) -> R) -> R {
+        arch::without_interrupts(|| f(&**self.policy.lock()))
+    }
+}
+
+/// Take every queued thread out of `policy`.
+fn drain(policy: &dyn Scheduler) -> Vec<ReadyRef> {
+    let mut ready = Vec::new();
+    for cpu in 0..MAX_CPUS {
+        while let Some(thread) = policy.pick_next(cpu) {
+            ready.push(thread);
+        }
+    }
+    ready
+}
+
+/// Queue `ready` in `policy`, handing back whatever it didn't take.
+fn migrate(ready: Vec<ReadyRef>, policy: &dyn Scheduler) -> Result<(), Vec<ReadyRef>> {
+    let mut ready = ready.into_iter();
+    while let Some(thread) = ready.next() {
+        thread.0.set_slice_curves(policy.slice_curves());
+        if let Err(thread) = policy.try_enqueue(thread) {
+            let mut rejected = Vec::with_capacity(ready.len() + 1);
+            rejected.push(thread);
+            rejected.extend(ready);
+            return Err(rejected);
+        }
+    }
+    Ok(())
+}
+
+impl Scheduler for DynScheduler {
+    fn enqueue(&self, thread: ReadyRef) {
+        self.with(|policy| {
+            thread.0.set_slice_curves(policy.slice_curves());
+            policy.enqueue(thread)
+        })
+    }
+
+    fn try_enqueue(&self, thread: ReadyRef) -> Result<(), ReadyRef> {
+        self.with(|policy| {
+            thread.0.set_slice_curves(policy.slice_curves());
+            policy.try_enqueue(thread)
+        })
+    }
+
+    fn on_spawn(&self, thread_id: ThreadId) {
+        self.total_threads.fetch_add(1, Ordering::AcqRel);
+        self.with(|policy| policy.on_spawn(thread_id))
+    }
+
+    fn on_exit(&self, thread_id: ThreadId) {
+        let _ = self
+            .total_threads
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+        self.with(|policy| policy.on_exit(thread_id))
+    }
+
+    fn pick_next(&self, cpu_id: CpuId) -> Option<ReadyRef> {
+        self.with(|policy| policy.pick_next(cpu_id))
+    }
+
+    fn on_tick(&self, current: &RunningRef) -> Option<ReadyRef> {
+        self.with(|policy| policy.on_tick(current))
+    }
+
+    fn pick_specific(&self, thread_id: ThreadId) -> Option<ReadyRef> {
+        self.with(|policy| policy.pick_specific(thread_id))
+    }
+
+    fn set_priority(&self, thread_id: ThreadId, priority: u8) {
+        self.with(|policy| policy.set_priority(thread_id, priority))
+    }
+
+    fn on_yield(&self, current: RunningRef) {
+        self.with(|policy| policy.on_yield(current))
+    }
+
+    fn on_block(&self, current: RunningRef) {
+        self.with(|policy| policy.on_block(current))
+    }
+
+    fn wake_up(&self, thread: ReadyRef) {
+        self.with(|policy| {
+            thread.0.set_slice_curves(policy.slice_curves());
+            policy.wake_up(thread)
+        })
+    }
+
+    fn try_wake_up(&self, thread: ReadyRef) -> Result<(), ReadyRef> {
+        self.with(|policy| {
+            thread.0.set_slice_curves(policy.slice_curves());
+            policy.try_wake_up(thread)
+        })
+    }
+
+    fn stats(&self) -> (usize, usize, usize) {
+        // A policy switched to mid-run never saw earlier threads spawn
+        let (_, runnable, _) = self.with(|policy| policy.stats());
+        let total = self.total_threads.load(Ordering::Acquire).max(runnable);
+        (total, runnable, total - runnable)
+    }
+
+    fn debug_snapshot(&self) -> Vec<QueueSnapshot> {
+        preempt_disable();
+        let snapshot = self.with(|policy| policy.debug_snapshot());
+        preempt_enable();
+        snapshot
+    }
+
+    fn priority_range(&self) -> RangeInclusive<u8> {
+        self.with(|policy| policy.priority_range())
+    }
+
+    fn slice_curves(&self) -> &'static SliceCurves {
+        self.with(|policy| policy.slice_curves())
+    }
+}
+
+#[cfg(all(test, feature = "std-shim"))]
+mod tests {
+    use super::*;
+    use crate::mem::StackPool;
+    use crate::sched::conformance::spawn;
+    use crate::sched::{FirstComeFirstServeScheduler, RoundRobinScheduler};
+
+    fn ready(pool: &StackPool, id: usize, priority: u8) -> ReadyRef {
+        ReadyRef(spawn(pool, id, priority).0)
+    }
+
+    #[test]
+    fn test_switch_migrates_ready_threads() {
+        let pool = StackPool::new();
+        let scheduler = DynScheduler::new(Box::new(RoundRobinScheduler::new(1)));
+        scheduler.enqueue(ready(&pool, 1, 64));
+        scheduler.enqueue(ready(&pool, 2, 200));
+
+        let old = scheduler
+            .switch_to(Box::new(FirstComeFirstServeScheduler::new()))
+            .ok()
+            .unwrap();
+        assert!(old.pick_next(0).is_none());
+
+        // RR drained by priority, so FCFS now holds 2 before 1
+        assert_eq!(scheduler.pick_next(0).map(|t| t.id().get()), Some(2));
+        assert_eq!(scheduler.pick_next(0).map(|t| t.id().get()), Some(1));
+        assert!(scheduler.pick_next(0).is_none());
+    }
+}
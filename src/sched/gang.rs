@@ -0,0 +1,478 @@
+//! Gang scheduling: threads that run together or not at all.
+//!
+//! [`GangScheduler`] adds a coscheduling layer on top of another scheduler.
+//! Threads registered in a gang are held back when they become ready. Once
+//! every member is ready and there is a free CPU for each, the whole gang
+//! is dispatched at once, one member per CPU, and those CPUs get a
+//! reschedule IPI so they switch to their member straight away. A CPU with
+//! a gang member waiting runs it before anything from the inner scheduler;
+//! threads outside any gang are scheduled by the inner scheduler unchanged.
+//!
+//! A member that blocks, yields or is preempted waits for the rest of its
+//! gang to be ready again before the gang is next dispatched, so the
+//! members of a parallel phase always start together. Members that are
+//! about to exit should [`leave_gang`](GangScheduler::leave_gang) so the
+//! others don't wait for them.
+//!
+//! The kernel currently dispatches on CPU 0 only, so by default that is the
+//! only CPU gang members are dispatched to. A gang with more members than
+//! there are dispatching CPUs can't run in parallel; once all its members
+//! are ready they take turns on those CPUs instead, ahead of other threads.
+//! Use [`with_dispatching_cpus`](GangScheduler::with_dispatching_cpus)
+//! once the secondary cores run the scheduler as well.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::sched::{GangScheduler, RoundRobinScheduler};
+//!
+//! let scheduler = GangScheduler::new(RoundRobinScheduler::new(4), 4);
+//! let gang = scheduler.create_gang()?;
+//! for worker in &workers {
+//!     scheduler.join_gang(gang, worker.id())?;
+//! }
+//! ```
+
+use super::trait_def::{CpuId, QueueSnapshot, Scheduler};
+use crate::arch;
+use crate::errors::ScheduleError;
+use crate::thread::{ReadyRef, RunningRef, ThreadId};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// Maximum number of gangs that can exist at once.
+pub const MAX_GANGS: usize = 16;
+
+/// Largest CPU count the IPI mask can address.
+const MAX_CPUS: usize = 32;
+
+/// Sends a reschedule IPI to every CPU in `cpu_mask` (bit n = CPU n).
+pub type IpiSender = fn(cpu_mask: u32);
+
+/// Handle to a gang created with [`GangScheduler::create_gang`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GangId(usize);
+
+#[derive(Default)]
+struct Gang {
+    members: Vec<ThreadId>,
+    /// Members that are ready and waiting for the rest
+    ready: Vec<ReadyRef>,
+    /// Members at the front of `ready` still to be handed out in turn,
+    /// for a gang wider than the dispatching CPUs
+    releasing: usize,
+}
+
+/// A scheduler wrapper that dispatches gangs of threads together.
+pub struct GangScheduler<S: Scheduler> {
+    inner: S,
+    num_cpus: usize,
+    /// Locked with interrupts disabled; taken before any slot lock
+    gangs: spin::Mutex<[Option<Gang>; MAX_GANGS]>,
+    /// Gang member dispatched to each CPU and not yet picked up
+    slots: Box<[spin::Mutex<Option<ReadyRef>>]>,
+    /// CPUs running the scheduler, counted from CPU 0; only their slots
+    /// are dispatched to
+    dispatching_cpus: usize,
+    send_ipi: IpiSender,
+}
+
+impl<S: Scheduler> GangScheduler<S> {
+    /// Wrap `inner` for a machine with `num_cpus` CPUs (at most 32).
+    pub fn new(inner: S, num_cpus: usize) -> Self {
+        let num_cpus = num_cpus.clamp(1, MAX_CPUS);
+        Self {
+            inner,
+            num_cpus,
+            gangs: spin::Mutex::new(core::array::from_fn(|_| None)),
+            slots: (0..num_cpus).map(|_| spin::Mutex::new(None)).collect(),
+            dispatching_cpus: 1,
+            send_ipi: arch::send_reschedule_ipi,
+        }
+    }
+
+    /// Dispatch gang members to the first `cpus` CPUs rather than CPU 0
+    /// alone, for when those CPUs all run the scheduler.
+    pub fn with_dispatching_cpus(mut self, cpus: usize) -> Self {
+        self.dispatching_cpus = cpus.clamp(1, self.num_cpus);
+        self
+    }
+
+    /// Use `send_ipi` instead of the GIC to notify CPUs of a dispatched gang.
+    pub fn with_ipi_sender(mut self, send_ipi: IpiSender) -> Self {
+        self.send_ipi = send_ipi;
+        self
+    }
+
+    /// The wrapped scheduler.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Create an empty gang.
+    ///
+    /// Fails with [`ScheduleError::QueueFull`] if [`MAX_GANGS`] gangs exist.
+    pub fn create_gang(&self) -> Result<GangId, ScheduleError> {
+        arch::without_interrupts(|| {
+            let mut gangs = self.gangs.lock();
+            let index = gangs
+                .iter()
+                .position(Option::is_none)
+                .ok_or(ScheduleError::QueueFull)?;
+            gangs[index] = Some(Gang::default());
+            Ok(GangId(index))
+        })
+    }
+
+    /// Add thread `id` to `gang`.
+    ///
+    /// Best done right after spawning, before the thread has run. Fails
+    /// with [`ScheduleError::GangTooLarge`] if the gang already has a
+    /// member per CPU and [`ScheduleError::InvalidState`] if the gang
+    /// doesn't exist or the thread is already in one.
+    pub fn join_gang(&self, gang: GangId, id: ThreadId) -> Result<(), ScheduleError> {
+        arch::without_interrupts(|| {
+            let mut gangs = self.gangs.lock();
+            if Self::gang_of(&gangs[..], id).is_some() {
+                return Err(ScheduleError::InvalidState);
+            }
+            let gang = gangs[gang.0].as_mut().ok_or(ScheduleError::InvalidState)?;
+            if gang.members.len() >= self.num_cpus {
+                return Err(ScheduleError::GangTooLarge);
+            }
+            gang.members.push(id);
+            // Room to hold every member, so one woken by an interrupt
+            // handler is held without allocating
+            gang.ready.reserve(gang.members.len());
+            Ok(())
+        })
+    }
+
+    /// Remove thread `id` from its gang.
+    ///
+    /// The rest of the gang no longer waits for it. Returns `false` if the
+    /// thread wasn't in a gang.
+    pub fn leave_gang(&self, id: ThreadId) -> bool {
+        arch::without_interrupts(|| {
+            let mut gangs = self.gangs.lock();
+            let Some(index) = Self::gang_of(&gangs[..], id) else {
+                return false;
+            };
+
+            let Some(gang) = gangs[index].as_mut() else {
+                return false;
+            };
+            gang.members.retain(|&member| member != id);
+            if let Some(pos) = gang.ready.iter().position(|t| t.id() == id) {
+                self.inner.enqueue(gang.ready.remove(pos));
+            }
+            self.try_dispatch(gang);
+            true
+        })
+    }
+
+    /// Delete `gang`, handing any held members back to the inner scheduler.
+    pub fn dissolve_gang(&self, gang: GangId) {
+        arch::without_interrupts(|| {
+            if let Some(gang) = self.gangs.lock()[gang.0].take() {
+                for thread in gang.ready {
+                    self.inner.enqueue(thread);
+                }
+            }
+        })
+    }
+
+    fn gang_of(gangs: &[Option<Gang>], id: ThreadId) -> Option<usize> {
+        gangs
+            .iter()
+            .position(|gang| gang.as_ref().is_some_and(|g| g.members.contains(&id)))
+    }
+
+    /// Dispatch `gang` if all its members are ready and enough CPUs are
+    /// free, or hand out its next members if it is taking turns.
+    fn try_dispatch(&self, gang: &mut Gang) {
+        // Members may have left while it was taking turns
+        gang.releasing = gang.releasing.min(gang.ready.len());
+        if gang.releasing == 0 {
+            if gang.ready.is_empty() || gang.ready.len() < gang.members.len() {
+                return;
+            }
+            if gang.ready.len() > self.dispatching_cpus {
+                // Can never all run at once, so they run in turn
+                gang.releasing = gang.ready.len();
+            }
+        }
+
+        // Bit n = CPU n; a mask rather than a list, as wake-ups from
+        // interrupt handlers come through here
+        let free = self.slots[..self.dispatching_cpus]
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.lock().is_none())
+            .fold(0u32, |mask, (cpu, _)| mask | 1 << cpu);
+        let free_cpus = free.count_ones() as usize;
+        let count = match gang.releasing {
+            0 if free_cpus < gang.ready.len() => return,
+            0 => gang.ready.len(),
+            releasing => releasing.min(free_cpus),
+        };
+        if count == 0 {
+            return;
+        }
+
+        let mut cpu_mask = 0;
+        let cpus = (0..self.dispatching_cpus).filter(|cpu| free & 1 << cpu != 0);
+        for (cpu, thread) in cpus.zip(gang.ready.drain(..count)) {
+            *self.slots[cpu].lock() = Some(thread);
+            cpu_mask |= 1 << cpu;
+        }
+        gang.releasing = gang.releasing.saturating_sub(count);
+        (self.send_ipi)(cpu_mask);
+    }
+
+    /// Dispatch any gangs that were waiting for CPUs to free up.
+    fn dispatch_waiting(&self) {
+        let mut gangs = self.gangs.lock();
+        for gang in gangs.iter_mut().flatten() {
+            self.try_dispatch(gang);
+        }
+    }
+
+    /// Number of gang members held back waiting for their gang.
+    fn held(&self) -> usize {
+        let gangs = self.gangs.lock();
+        let waiting: usize = gangs.iter().flatten().map(|g| g.ready.len()).sum();
+        waiting
+            + self
+                .slots
+                .iter()
+                .filter(|slot| slot.lock().is_some())
+                .count()
+    }
+}
+
+impl<S: Scheduler> Scheduler for GangScheduler<S> {
+    fn enqueue(&self, thread: ReadyRef) {
+        arch::without_interrupts(|| {
+            let mut gangs = self.gangs.lock();
+            match Self::gang_of(&gangs[..], thread.id()) {
+                Some(index) => {
+                    if let Some(gang) = gangs[index].as_mut() {
+                        gang.ready.push(thread);
+                        self.try_dispatch(gang);
+                    }
+                }
+                None => {
+                    drop(gangs);
+                    self.inner.enqueue(thread);
+                }
+            }
+        })
+    }
+
+    fn try_enqueue(&self, thread: ReadyRef) -> Result<(), ReadyRef> {
+        let in_gang = arch::without_interrupts(|| {
+            Self::gang_of(&self.gangs.lock()[..], thread.id()).is_some()
+        });
+        if in_gang {
+            self.enqueue(thread);
+            Ok(())
+        } else {
+            self.inner.try_enqueue(thread)
+        }
+    }
+
+    fn on_spawn(&self, thread_id: ThreadId) {
+        self.inner.on_spawn(thread_id);
+    }
+
+    fn on_exit(&self, thread_id: ThreadId) {
+        self.inner.on_exit(thread_id);
+    }
+
+    fn pick_next(&self, cpu_id: CpuId) -> Option<ReadyRef> {
+        arch::without_interrupts(|| loop {
+            if let Some(thread) = self.slots.get(cpu_id).and_then(|slot| slot.lock().take()) {
+                self.dispatch_waiting();
+                return Some(thread);
+            }
+
+            let thread = self.inner.pick_next(cpu_id)?;
+            let in_gang = Self::gang_of(&self.gangs.lock()[..], thread.id()).is_some();
+            if !in_gang {
+                return Some(thread);
+            }
+            // Queued before it joined its gang: hold it with the others
+            self.enqueue(thread);
+        })
+    }
+
+    fn on_tick(&self, current: &RunningRef) -> Option<ReadyRef> {
+        self.inner.on_tick(current)
+    }
+
+    fn pick_specific(&self, thread_id: ThreadId) -> Option<ReadyRef> {
+        // Gang members only run when dispatched with their gang
+        let in_gang =
+            arch::without_interrupts(|| Self::gang_of(&self.gangs.lock()[..], thread_id).is_some());
+        if in_gang {
+            return None;
+        }
+        self.inner.pick_specific(thread_id)
+    }
+
+    fn set_priority(&self, thread_id: ThreadId, priority: u8) {
+        self.inner.set_priority(thread_id, priority);
+    }
+
+    fn priority_range(&self) -> core::ops::RangeInclusive<u8> {
+        self.inner.priority_range()
+    }
+
+    fn slice_curves(&self) -> &'static crate::time::SliceCurves {
+        self.inner.slice_curves()
+    }
+
+    fn wake_up(&self, thread: ReadyRef) {
+        self.enqueue(thread);
+    }
+
+    /// Gang members are held with their gang as usual; others are left to
+    /// the inner scheduler.
+    fn try_wake_up(&self, thread: ReadyRef) -> Result<(), ReadyRef> {
+        let in_gang = arch::without_interrupts(|| {
+            Self::gang_of(&self.gangs.lock()[..], thread.id()).is_some()
+        });
+        if in_gang {
+            self.enqueue(thread);
+            Ok(())
+        } else {
+            self.inner.try_wake_up(thread)
+        }
+    }
+
+    fn stats(&self) -> (usize, usize, usize) {
+        // The inner scheduler counts held members as not runnable
+        let (total, runnable, _) = self.inner.stats();
+        let held = arch::without_interrupts(|| self.held());
+        let runnable = runnable + held;
+        let total = total.max(runnable);
+        (total, runnable, total - runnable)
+    }
+
+    /// The inner scheduler's queues, then one entry per CPU with a gang
+    /// member dispatched to it and one per gang with members waiting.
+    fn debug_snapshot(&self) -> Vec<QueueSnapshot> {
+        let mut snapshot = self.inner.debug_snapshot();
+        arch::without_interrupts(|| {
+            let gangs = self.gangs.lock();
+            for (cpu, slot) in self.slots.iter().enumerate() {
+                if let Some(thread) = slot.lock().as_ref() {
+                    snapshot.push(QueueSnapshot {
+                        cpu: Some(cpu),
+                        priorities: 0..=u8::MAX,
+                        threads: alloc::vec![thread.id()],
+                    });
+                }
+            }
+            for gang in gangs.iter().flatten().filter(|gang| !gang.ready.is_empty()) {
+                snapshot.push(QueueSnapshot {
+                    cpu: None,
+                    priorities: 0..=u8::MAX,
+                    threads: gang.ready.iter().map(|thread| thread.id()).collect(),
+                });
+            }
+        });
+        snapshot
+    }
+}
+
+#[cfg(all(test, feature = "std-shim"))]
+mod tests {
+    use super::*;
+    use crate::mem::StackPool;
+    use crate::sched::conformance::spawn;
+    use crate::sched::RoundRobinScheduler;
+    use portable_atomic::{AtomicU32, Ordering};
+
+    static IPI_MASK: AtomicU32 = AtomicU32::new(0);
+
+    fn record_ipi(cpu_mask: u32) {
+        IPI_MASK.store(cpu_mask, Ordering::Relaxed);
+    }
+
+    fn ready(pool: &StackPool, id: usize) -> ReadyRef {
+        ReadyRef(spawn(pool, id, 128).0)
+    }
+
+    #[test]
+    fn test_gang_dispatched_together() {
+        let pool = StackPool::new();
+        let scheduler = GangScheduler::new(RoundRobinScheduler::new(2), 2)
+            .with_dispatching_cpus(2)
+            .with_ipi_sender(record_ipi);
+        let (a, b, c) = (ready(&pool, 1), ready(&pool, 2), ready(&pool, 3));
+
+        let gang = scheduler.create_gang().unwrap();
+        scheduler.join_gang(gang, a.id()).unwrap();
+        scheduler.join_gang(gang, b.id()).unwrap();
+        assert_eq!(
+            scheduler.join_gang(gang, c.id()),
+            Err(ScheduleError::GangTooLarge)
+        );
+
+        // A alone is held back; the ungrouped thread runs
+        scheduler.enqueue(a);
+        scheduler.enqueue(c);
+        assert_eq!(scheduler.pick_next(0).map(|t| t.id().get()), Some(3));
+        assert!(scheduler.pick_next(1).is_none());
+
+        scheduler.enqueue(b);
+        assert_eq!(IPI_MASK.load(Ordering::Relaxed), 0b11);
+        assert_eq!(scheduler.pick_next(0).map(|t| t.id().get()), Some(1));
+        assert_eq!(scheduler.pick_next(1).map(|t| t.id().get()), Some(2));
+    }
+
+    #[test]
+    fn test_gang_takes_turns_on_one_cpu() {
+        let pool = StackPool::new();
+        let scheduler = GangScheduler::new(RoundRobinScheduler::new(2), 2).with_ipi_sender(|_| {});
+        let (a, b, c) = (ready(&pool, 1), ready(&pool, 2), ready(&pool, 3));
+        let gang = scheduler.create_gang().unwrap();
+        scheduler.join_gang(gang, a.id()).unwrap();
+        scheduler.join_gang(gang, b.id()).unwrap();
+
+        // Only CPU 0 dispatches: both members run there, one after the other
+        scheduler.enqueue(a);
+        scheduler.enqueue(c);
+        scheduler.enqueue(b);
+        let picked: Vec<usize> =
+            core::iter::from_fn(|| scheduler.pick_next(0).map(|t| t.id().get())).collect();
+        assert_eq!(picked, [1, 2, 3]);
+        assert!(scheduler.pick_next(1).is_none());
+    }
+
+    #[test]
+    fn test_late_join_and_leave() {
+        let pool = StackPool::new();
+        let scheduler = GangScheduler::new(RoundRobinScheduler::new(1), 1).with_ipi_sender(|_| {});
+        let (a, b) = (ready(&pool, 1), ready(&pool, 2));
+        let (a_id, b_id) = (a.id(), b.id());
+
+        // Already queued in the inner scheduler when it joins
+        scheduler.enqueue(a);
+        let gang = scheduler.create_gang().unwrap();
+        scheduler.join_gang(gang, a_id).unwrap();
+        assert_eq!(scheduler.pick_next(0).map(|t| t.id()), Some(a_id));
+
+        // A second member can't fit on one CPU; leaving frees the gang
+        assert_eq!(
+            scheduler.join_gang(gang, b_id),
+            Err(ScheduleError::GangTooLarge)
+        );
+        scheduler.dissolve_gang(gang);
+        assert!(!scheduler.leave_gang(a_id));
+        scheduler.enqueue(b);
+        assert_eq!(scheduler.pick_next(0).map(|t| t.id()), Some(b_id));
+    }
+}
@@ -1,14 +1,44 @@
 //! Thread scheduler implementations.
 //!
-//! Provides the round-robin scheduler for managing thread execution.
+//! Provides the round-robin scheduler for managing thread execution, a
+//! gang scheduling layer that can wrap any scheduler, a [`DynScheduler`]
+//! whose policy can be switched at runtime, and a watchdog that detects
+//! starvation and priority inversion. Host builds also get the
+//! [`conformance`] checks every scheduler is tested against.
+//!
+//! # Writing a scheduler
+//!
+//! [`Scheduler`] can be implemented outside the crate. The kernel hands a
+//! policy [`ReadyRef`](crate::thread::ReadyRef)s to queue and takes them
+//! back from [`pick_next`](Scheduler::pick_next); a policy can't create
+//! or copy them, so a thread is never queued twice or dispatched from a
+//! state it isn't in. A policy reads what it orders by through
+//! [`ReadyRef::thread`](crate::thread::ReadyRef::thread) (priority,
+//! vruntime, time in the current state) and moves threads between states
+//! only through the consuming transitions the trait's hooks need:
+//! [`RunningRef::stop_running`](crate::thread::RunningRef::stop_running)
+//! in [`on_yield`](Scheduler::on_yield) and
+//! [`RunningRef::block`](crate::thread::RunningRef::block) in
+//! [`on_block`](Scheduler::on_block). Test it with
+//! [`conformance::check_all`], which mints the handles a test can't.
 
+#[cfg(feature = "std-shim")]
+pub mod conformance;
+pub mod dynamic;
+pub mod gang;
+pub mod placement;
 pub mod rr;
 pub mod trait_def;
+pub mod watchdog;
 
-pub use rr::RoundRobinScheduler;
+pub use dynamic::DynScheduler;
+pub use gang::{GangId, GangScheduler};
+pub use placement::PlacementPolicy;
 pub use rr::FirstComeFirstServeScheduler;
+pub use rr::{RoundRobinScheduler, SchedulerTuning};
 
-pub use trait_def::{priority, CpuId, Scheduler};
+pub use trait_def::{priority, CpuId, QueueSnapshot, Scheduler};
+pub use watchdog::{Diagnostic, StarvationDetector};
 
 /// Default scheduler type.
 pub type DefaultScheduler = RoundRobinScheduler;
@@ -0,0 +1,182 @@
+//! Which CPU's run queue a ready thread joins.
+//!
+//! [`RoundRobinScheduler`](super::RoundRobinScheduler) keeps a run queue
+//! per CPU and asks a [`PlacementPolicy`] where each thread that becomes
+//! ready should go. The choice trades balance against cache warmth: a
+//! thread queued on the CPU it last ran on finds its working set still in
+//! that core's 32 KiB L1, while one moved elsewhere starts cold. The crate
+//! provides:
+//!
+//! | Policy | Queues a thread on |
+//! |---|---|
+//! | [`LastCpu`] | the CPU it last ran on, or the least loaded before its first run (the default) |
+//! | [`LeastLoaded`] | the CPU with the fewest queued threads |
+//! | [`RoundRobinPlacement`] | each CPU in turn |
+//! | [`RandomPlacement`] | a pseudo-random CPU |
+//!
+//! ```ignore
+//! use preemptive_threads::sched::{placement::LeastLoaded, RoundRobinScheduler};
+//!
+//! let scheduler = RoundRobinScheduler::new(4).with_placement(LeastLoaded);
+//! ```
+//!
+//! How well a policy does shows in
+//! [`placement_stats`](crate::kernel::metrics::placement_stats):
+//! migrations count threads queued away from their last CPU, steals count
+//! threads a CPU with an empty queue took from another's.
+
+use super::trait_def::CpuId;
+use crate::thread::Thread;
+use portable_atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Picks the CPU a ready thread is queued on.
+///
+/// Called on every enqueue, with interrupts possibly enabled and from
+/// interrupt context, so it must be quick and must not block or allocate.
+pub trait PlacementPolicy: Send + Sync {
+    /// The CPU for `thread`, below [`loads.cpus()`](CpuLoads::cpus).
+    /// Out-of-range answers are wrapped into range.
+    fn select_cpu(&self, thread: &Thread, loads: &CpuLoads<'_>) -> CpuId;
+}
+
+/// The scheduler's per-CPU queue lengths, as seen at one enqueue.
+pub struct CpuLoads<'a> {
+    cpus: usize,
+    queued: &'a dyn Fn(CpuId) -> usize,
+}
+
+impl<'a> CpuLoads<'a> {
+    /// `cpus` run queues, the one for CPU n holding `queued(n)` threads.
+    pub fn new(cpus: usize, queued: &'a dyn Fn(CpuId) -> usize) -> Self {
+        Self { cpus, queued }
+    }
+
+    /// Number of CPUs with a run queue.
+    pub fn cpus(&self) -> usize {
+        self.cpus
+    }
+
+    /// Threads queued on `cpu`.
+    pub fn queued(&self, cpu: CpuId) -> usize {
+        (self.queued)(cpu)
+    }
+
+    /// The CPU with the fewest queued threads, the lowest numbered on a tie.
+    pub fn least_loaded(&self) -> CpuId {
+        (0..self.cpus)
+            .min_by_key(|&cpu| self.queued(cpu))
+            .unwrap_or(0)
+    }
+}
+
+/// The CPU the thread last ran on; before its first run, the least
+/// loaded. The default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LastCpu;
+
+impl PlacementPolicy for LastCpu {
+    fn select_cpu(&self, thread: &Thread, loads: &CpuLoads<'_>) -> CpuId {
+        match thread.last_cpu() {
+            Some(cpu) if cpu < loads.cpus() => cpu,
+            _ => loads.least_loaded(),
+        }
+    }
+}
+
+/// The CPU with the fewest queued threads.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LeastLoaded;
+
+impl PlacementPolicy for LeastLoaded {
+    fn select_cpu(&self, _thread: &Thread, loads: &CpuLoads<'_>) -> CpuId {
+        loads.least_loaded()
+    }
+}
+
+/// Each CPU in turn, whatever its load.
+#[derive(Debug, Default)]
+pub struct RoundRobinPlacement {
+    next: AtomicUsize,
+}
+
+impl RoundRobinPlacement {
+    pub const fn new() -> Self {
+        Self {
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl PlacementPolicy for RoundRobinPlacement {
+    fn select_cpu(&self, _thread: &Thread, loads: &CpuLoads<'_>) -> CpuId {
+        self.next.fetch_add(1, Ordering::Relaxed) % loads.cpus().max(1)
+    }
+}
+
+/// A pseudo-random CPU, from a xorshift generator.
+#[derive(Debug)]
+pub struct RandomPlacement {
+    state: AtomicU64,
+}
+
+impl RandomPlacement {
+    /// Start the sequence from `seed`; zero is replaced, since xorshift
+    /// would stay there.
+    pub const fn new(seed: u64) -> Self {
+        Self {
+            state: AtomicU64::new(if seed == 0 {
+                0x9E37_79B9_7F4A_7C15
+            } else {
+                seed
+            }),
+        }
+    }
+}
+
+impl Default for RandomPlacement {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl PlacementPolicy for RandomPlacement {
+    fn select_cpu(&self, _thread: &Thread, loads: &CpuLoads<'_>) -> CpuId {
+        // Racing callers may draw the same number, which only costs balance
+        let mut x = self.state.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state.store(x, Ordering::Relaxed);
+        (x % loads.cpus().max(1) as u64) as CpuId
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::{StackPool, StackSizeClass};
+    use crate::thread::ThreadId;
+
+    #[test]
+    fn test_policies_pick_in_range() {
+        let pool = StackPool::new();
+        let stack = pool.allocate(StackSizeClass::Small).unwrap();
+        let (thread, _) = Thread::new(unsafe { ThreadId::new_unchecked(1) }, stack, || {}, 128);
+        let queued = [3, 1, 1, 2];
+        let count = |cpu: CpuId| queued[cpu];
+        let loads = CpuLoads::new(queued.len(), &count);
+
+        assert_eq!(loads.least_loaded(), 1);
+        // Never run: falls back to the least loaded
+        assert_eq!(thread.last_cpu(), None);
+        assert_eq!(LastCpu.select_cpu(&thread, &loads), 1);
+        assert_eq!(LeastLoaded.select_cpu(&thread, &loads), 1);
+
+        let turns = RoundRobinPlacement::new();
+        let picks: alloc::vec::Vec<_> = (0..5).map(|_| turns.select_cpu(&thread, &loads)).collect();
+        assert_eq!(picks, [0, 1, 2, 3, 0]);
+
+        let random = RandomPlacement::default();
+        assert!((0..32).all(|_| random.select_cpu(&thread, &loads) < 4));
+    }
+}
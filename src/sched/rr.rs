@@ -1,34 +1,96 @@
-use super::trait_def::{CpuId, Scheduler};
-use crate::thread::{ReadyRef, RunningRef, ThreadId};
-use portable_atomic::{AtomicPtr, AtomicUsize, Ordering};
+use super::placement::{CpuLoads, LastCpu, PlacementPolicy};
+use super::trait_def::{CpuId, QueueSnapshot, Scheduler};
+use crate::kernel::metrics;
+use crate::mem::reclaim::{Immediate, ReclamationPolicy};
+use crate::mem::CachePadded;
+use crate::thread::{ReadyRef, RunningRef, Thread, ThreadId};
+use crate::time::{
+    Duration, Instant, SliceCurves, DEFAULT_AGING_INTERVAL_NS, DEFAULT_MIN_GRANULARITY_NS,
+    DEFAULT_TARGET_LATENCY_NS,
+};
+use core::marker::PhantomData;
 use core::ptr;
+use portable_atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
 extern crate alloc;
 use alloc::{boxed::Box, vec::Vec};
 
-pub struct RoundRobinScheduler {
+/// Round-robin scheduler with four priority levels per CPU.
+///
+/// `R` decides when run queue nodes are freed; see [`crate::mem::reclaim`].
+pub struct RoundRobinScheduler<R: ReclamationPolicy = Immediate> {
     num_cpus: usize,
-    run_queues: Box<[CpuRunQueue]>,
-    total_threads: AtomicUsize,
-    runnable_threads: AtomicUsize,
+    /// Each CPU's queue on its own cache lines, so one core enqueueing
+    /// doesn't invalidate the line another is dequeuing from
+    run_queues: Box<[CachePadded<CpuRunQueue<R>>]>,
+    /// Counters every core updates, kept off the lines of the read-mostly
+    /// tuning fields below
+    total_threads: CachePadded<AtomicUsize>,
+    runnable_threads: CachePadded<AtomicUsize>,
+    /// Period in which every runnable thread should get to run
+    target_latency_ns: AtomicU64,
+    /// Floor for the adaptive quantum
+    min_granularity_ns: AtomicU64,
+    /// Wait per priority level a queued thread is aged up; zero is off
+    aging_interval_ns: AtomicU64,
+    /// Priority-based quanta and vruntime weights
+    slice_curves: &'static SliceCurves,
+    /// Picks the CPU a ready thread is queued on
+    placement: Box<dyn PlacementPolicy>,
 }
 
+/// The round-robin scheduler's tuning, read and applied at once with
+/// [`RoundRobinScheduler::tuning`] and [`RoundRobinScheduler::set_tuning`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchedulerTuning {
+    /// See [`RoundRobinScheduler::set_target_latency`]
+    pub target_latency: Duration,
+    /// See [`RoundRobinScheduler::set_min_granularity`]
+    pub min_granularity: Duration,
+    /// See [`RoundRobinScheduler::set_aging_interval`]
+    pub aging_interval: Duration,
+}
+
+impl Default for SchedulerTuning {
+    fn default() -> Self {
+        Self {
+            target_latency: Duration::from_nanos(DEFAULT_TARGET_LATENCY_NS),
+            min_granularity: Duration::from_nanos(DEFAULT_MIN_GRANULARITY_NS),
+            aging_interval: Duration::from_nanos(DEFAULT_AGING_INTERVAL_NS),
+        }
+    }
+}
 
-pub struct FirstComeFirstServeScheduler {
-    queue: LockFreeQueue,
-    runnable_threads: AtomicUsize,
+/// Runs threads to completion, or until they yield or block, in the order
+/// they became ready. Never preempts.
+///
+/// By default priorities are ignored. [`with_priority_order`] keeps one
+/// queue per priority band instead, so a ready thread in a higher band
+/// always goes first, with arrival order deciding within a band.
+///
+/// [`with_priority_order`]: FirstComeFirstServeScheduler::with_priority_order
+pub struct FirstComeFirstServeScheduler<R: ReclamationPolicy = Immediate> {
+    /// Indexed by [`FirstComeFirstServeScheduler::band`], highest band
+    /// first; only the first is used without priority ordering
+    queues: [LockFreeQueue<R>; 4],
+    by_priority: bool,
+    total_threads: CachePadded<AtomicUsize>,
+    runnable_threads: CachePadded<AtomicUsize>,
 }
 
-pub struct CpuRunQueue {
-    high_priority: LockFreeQueue,
-    normal_priority: LockFreeQueue,
-    low_priority: LockFreeQueue,
-    idle_priority: LockFreeQueue,
+pub struct CpuRunQueue<R: ReclamationPolicy = Immediate> {
+    high_priority: LockFreeQueue<R>,
+    normal_priority: LockFreeQueue<R>,
+    low_priority: LockFreeQueue<R>,
+    idle_priority: LockFreeQueue<R>,
     thread_count: AtomicUsize,
 }
 
-struct LockFreeQueue {
-    head: AtomicPtr<QueueNode>,
-    tail: AtomicPtr<QueueNode>,
+struct LockFreeQueue<R: ReclamationPolicy> {
+    /// Dequeuers and enqueuers work on different ends; padding keeps them
+    /// from contending for the same line
+    head: CachePadded<AtomicPtr<QueueNode>>,
+    tail: CachePadded<AtomicPtr<QueueNode>>,
+    _reclaim: PhantomData<R>,
 }
 
 struct QueueNode {
@@ -36,21 +98,199 @@ struct QueueNode {
     next: AtomicPtr<QueueNode>,
 }
 
-impl Scheduler for FirstComeFirstServeScheduler {
+/// Nodes kept aside for when the heap can't supply one.
+pub const RESERVE_NODES: usize = 16;
+
+/// Run queue nodes allocated up front, handed out when the allocator fails
+/// so a thread that is already running never loses its place in the queue
+/// to a transient out-of-memory condition.
+///
+/// Reserve nodes are ordinary heap nodes and are freed like any other once
+/// dequeued; the reserve is topped up again on the next allocation that
+/// succeeds.
+struct NodeReserve {
+    nodes: [AtomicPtr<QueueNode>; RESERVE_NODES],
+    len: AtomicUsize,
+}
+
+impl NodeReserve {
+    const fn new() -> Self {
+        Self {
+            nodes: {
+                #[allow(clippy::declare_interior_mutable_const)]
+                const EMPTY: AtomicPtr<QueueNode> = AtomicPtr::new(ptr::null_mut());
+                [EMPTY; RESERVE_NODES]
+            },
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// Allocate nodes until the reserve is full or the heap runs out.
+    fn fill(&self) {
+        if self.len.load(Ordering::Relaxed) >= RESERVE_NODES {
+            return;
+        }
+        for slot in &self.nodes {
+            if !slot.load(Ordering::Relaxed).is_null() {
+                continue;
+            }
+            let Some(node) = QueueNode::alloc() else {
+                return;
+            };
+            if slot
+                .compare_exchange(ptr::null_mut(), node, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                self.len.fetch_add(1, Ordering::Relaxed);
+            } else {
+                unsafe { QueueNode::free(node) };
+            }
+        }
+    }
+
+    fn take(&self) -> Option<*mut QueueNode> {
+        for slot in &self.nodes {
+            let node = slot.swap(ptr::null_mut(), Ordering::AcqRel);
+            if !node.is_null() {
+                self.len.fetch_sub(1, Ordering::Relaxed);
+                return Some(node);
+            }
+        }
+        None
+    }
+}
+
+static RESERVE: NodeReserve = NodeReserve::new();
+
+/// Priorities of the high, normal, low and idle bands, in the order
+/// they are served, as mapped by `priority_level`.
+const BAND_PRIORITIES: [core::ops::RangeInclusive<u8>; 4] = [192..=255, 64..=191, 1..=63, 0..=0];
+
+/// Run `f` with preemption and interrupts held off, so no queue changes
+/// under it.
+fn held_off<T>(f: impl FnOnce() -> T) -> T {
+    crate::platform_timer::preempt_disable();
+    let result = crate::arch::without_interrupts(f);
+    crate::platform_timer::preempt_enable();
+    result
+}
+
+/// Count a thread out of `total`, which may not have counted it in: a
+/// policy switched to mid-run sees exits of threads spawned before.
+fn release(total: &AtomicUsize) {
+    let _ = total.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+}
+
+/// No node for a thread that must be queued, even from the reserve.
+///
+/// Dropping the thread would leave it neither running nor queued, so treat
+/// this as the out-of-memory condition it is.
+fn node_alloc_failed(thread: ReadyRef) -> ! {
+    crate::klog!(
+        crate::kernel::log::Level::Error,
+        "no run queue node for thread {}",
+        thread.id()
+    );
+    alloc::alloc::handle_alloc_error(core::alloc::Layout::new::<QueueNode>())
+}
+
+impl QueueNode {
+    /// An empty node from the heap, or `None` if allocation failed.
+    fn alloc() -> Option<*mut QueueNode> {
+        let layout = core::alloc::Layout::new::<QueueNode>();
+        // QueueNode isn't zero-sized
+        let node = unsafe { alloc::alloc::alloc(layout) } as *mut QueueNode;
+        if node.is_null() {
+            return None;
+        }
+        unsafe {
+            node.write(QueueNode {
+                thread: None,
+                next: AtomicPtr::new(ptr::null_mut()),
+            })
+        };
+        Some(node)
+    }
+
+    /// Free a node that was never linked into a queue.
+    unsafe fn free(node: *mut QueueNode) {
+        drop(unsafe { Box::from_raw(node) });
+    }
+
+    /// A node holding `thread`, from the heap or else the reserve. Hands
+    /// `thread` back if neither has one.
+    fn with_thread(thread: ReadyRef) -> Result<*mut QueueNode, ReadyRef> {
+        let node = match QueueNode::alloc() {
+            Some(node) => {
+                RESERVE.fill();
+                node
+            }
+            None => match RESERVE.take() {
+                Some(node) => {
+                    crate::kernel::metrics::record_node_alloc_failure(true);
+                    node
+                }
+                None => {
+                    crate::kernel::metrics::record_node_alloc_failure(false);
+                    return Err(thread);
+                }
+            },
+        };
+        unsafe { (*node).thread = Some(thread) };
+        Ok(node)
+    }
+
+    /// A node holding `thread` from the reserve alone, for callers that
+    /// must not allocate. Hands `thread` back if the reserve is empty.
+    fn reserved(thread: ReadyRef) -> Result<*mut QueueNode, ReadyRef> {
+        let Some(node) = RESERVE.take() else {
+            return Err(thread);
+        };
+        unsafe { (*node).thread = Some(thread) };
+        Ok(node)
+    }
+}
+
+/// Top up the node reserve, unless in interrupt context where allocating
+/// is off limits.
+///
+/// Dispatch calls this: every thread that blocks passes through it, and
+/// its wake-up may come from an interrupt handler, which only draws on
+/// the reserve.
+fn replenish_reserve() {
+    if !crate::irq::in_irq() {
+        RESERVE.fill();
+    }
+}
+
+impl<R: ReclamationPolicy> Scheduler for FirstComeFirstServeScheduler<R> {
     fn enqueue(&self, thread: ReadyRef) {
-        let tid = thread.id().get();
-        crate::pl011_println!("[FCFS] enqueue: thread {} (queue before: {:?})", tid, self.queue.debug_list_threads());
-        self.queue.push(thread);
-        crate::pl011_println!("[FCFS] enqueue done: (queue after: {:?})", self.queue.debug_list_threads());
-        self.runnable_threads.fetch_add(1, Ordering::AcqRel);
+        if let Err(thread) = self.try_enqueue(thread) {
+            node_alloc_failed(thread);
+        }
+    }
+
+    fn try_enqueue(&self, thread: ReadyRef) -> Result<(), ReadyRef> {
+        self.queue_with(thread, LockFreeQueue::try_push)
+    }
+
+    fn on_spawn(&self, _thread_id: ThreadId) {
+        self.total_threads.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn on_exit(&self, _thread_id: ThreadId) {
+        release(&self.total_threads);
     }
 
     fn pick_next(&self, _cpu_id: CpuId) -> Option<ReadyRef> {
-        crate::pl011_println!("[FCFS] pick_next: (queue before: {:?})", self.queue.debug_list_threads());
-        let thread = self.queue.try_pop()?;
-        let tid = thread.id().get();
-        crate::pl011_println!("[FCFS] pick_next: got thread {} (queue after: {:?})", tid, self.queue.debug_list_threads());
-        self.runnable_threads.fetch_sub(1, Ordering::AcqRel);
+        replenish_reserve();
+        let thread = self.queues.iter().find_map(|queue| queue.try_pop())?;
+        let runnable = self.runnable_threads.fetch_sub(1, Ordering::AcqRel) - 1;
+        crate::klog_trace!(
+            "fcfs: picked thread {} ({} runnable)",
+            thread.id(),
+            runnable
+        );
         Some(thread)
     }
 
@@ -58,6 +298,15 @@ impl Scheduler for FirstComeFirstServeScheduler {
         None
     }
 
+    fn pick_specific(&self, thread_id: ThreadId) -> Option<ReadyRef> {
+        let thread = self
+            .queues
+            .iter()
+            .find_map(|queue| queue.remove(thread_id))?;
+        self.runnable_threads.fetch_sub(1, Ordering::AcqRel);
+        Some(thread)
+    }
+
     fn on_yield(&self, current: RunningRef) {
         let ready = current.stop_running();
         self.enqueue(ready);
@@ -70,105 +319,291 @@ impl Scheduler for FirstComeFirstServeScheduler {
     fn wake_up(&self, thread: ReadyRef) {
         self.enqueue(thread);
     }
-    fn set_priority(&self, _thread_id: ThreadId, _priority: u8) {}
 
+    /// Queued in a node from the reserve, which dispatch keeps topped up.
+    fn try_wake_up(&self, thread: ReadyRef) -> Result<(), ReadyRef> {
+        self.queue_with(thread, LockFreeQueue::try_push_reserved)
+    }
+
+    /// Move a queued thread to the back of its new band. Called after the
+    /// thread's own priority has changed, so re-queueing it picks the band.
+    fn set_priority(&self, thread_id: ThreadId, priority: u8) {
+        if !self.by_priority {
+            return;
+        }
+        let new_band = self.band(priority);
+        for (band, queue) in self.queues.iter().enumerate() {
+            if band == new_band {
+                continue;
+            }
+            if let Some(thread) = queue.remove(thread_id) {
+                crate::klog_trace!(
+                    "fcfs: thread {} moves from band {} to {}",
+                    thread_id,
+                    band,
+                    new_band
+                );
+                self.queues[new_band].push(thread);
+                return;
+            }
+        }
+    }
+
+    fn stats(&self) -> (usize, usize, usize) {
+        let runnable = self.runnable_threads.load(Ordering::Acquire);
+        // Queued threads count towards the total even if never spawned here
+        let total = self.total_threads.load(Ordering::Acquire).max(runnable);
+        (total, runnable, total - runnable)
+    }
+
+    fn debug_snapshot(&self) -> Vec<QueueSnapshot> {
+        held_off(|| {
+            if !self.by_priority {
+                return alloc::vec![QueueSnapshot {
+                    cpu: None,
+                    priorities: 0..=u8::MAX,
+                    threads: self.queues[0].thread_ids(),
+                }];
+            }
+            self.queues
+                .iter()
+                .zip(BAND_PRIORITIES)
+                .map(|(queue, priorities)| QueueSnapshot {
+                    cpu: None,
+                    priorities,
+                    threads: queue.thread_ids(),
+                })
+                .collect()
+        })
+    }
 }
+
 impl FirstComeFirstServeScheduler {
     pub fn new() -> Self {
+        Self::with_reclamation()
+    }
+}
+
+impl<R: ReclamationPolicy> FirstComeFirstServeScheduler<R> {
+    /// Create a scheduler whose queue frees nodes according to `R`.
+    pub fn with_reclamation() -> Self {
+        RESERVE.fill();
         Self {
-            queue: LockFreeQueue::new(),
-            runnable_threads: AtomicUsize::new(0),
+            queues: [
+                LockFreeQueue::new(),
+                LockFreeQueue::new(),
+                LockFreeQueue::new(),
+                LockFreeQueue::new(),
+            ],
+            by_priority: false,
+            total_threads: CachePadded::new(AtomicUsize::new(0)),
+            runnable_threads: CachePadded::new(AtomicUsize::new(0)),
         }
     }
+
+    /// Serve higher priority bands first, in arrival order within each.
+    ///
+    /// The bands are those of [`RoundRobinScheduler`]: idle, low, normal
+    /// and high. Without this every thread shares one queue.
+    pub fn with_priority_order(mut self) -> Self {
+        self.by_priority = true;
+        self
+    }
+
+    /// Index into `queues` for a thread of `priority`.
+    fn band(&self, priority: u8) -> usize {
+        if !self.by_priority {
+            return 0;
+        }
+        match RoundRobinScheduler::<R>::priority_level(priority) {
+            PriorityLevel::High => 0,
+            PriorityLevel::Normal => 1,
+            PriorityLevel::Low => 2,
+            PriorityLevel::Idle => 3,
+        }
+    }
+
+    /// Queue `thread` in its band with `push`, counting it as runnable.
+    fn queue_with(
+        &self,
+        thread: ReadyRef,
+        push: impl FnOnce(&LockFreeQueue<R>, ReadyRef) -> Result<(), ReadyRef>,
+    ) -> Result<(), ReadyRef> {
+        let band = self.band(thread.priority());
+        let id = thread.id();
+        push(&self.queues[band], thread)?;
+        let runnable = self.runnable_threads.fetch_add(1, Ordering::AcqRel) + 1;
+        crate::klog_trace!(
+            "fcfs: queued thread {} in band {} ({} runnable)",
+            id,
+            band,
+            runnable
+        );
+        Ok(())
+    }
 }
 
+impl Default for FirstComeFirstServeScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl RoundRobinScheduler {
     /// Create a new round-robin scheduler for the given number of CPUs.
     pub fn new(num_cpus: usize) -> Self {
+        Self::with_reclamation(num_cpus)
+    }
+}
+
+impl<R: ReclamationPolicy> RoundRobinScheduler<R> {
+    /// Create a scheduler whose run queues free nodes according to `R`.
+    pub fn with_reclamation(num_cpus: usize) -> Self {
         // Allocate per-CPU run queues
         let mut run_queues = Vec::with_capacity(num_cpus);
         for _ in 0..num_cpus {
-            run_queues.push(CpuRunQueue::new());
+            run_queues.push(CachePadded::new(CpuRunQueue::new()));
         }
+        RESERVE.fill();
 
         Self {
             num_cpus,
             run_queues: run_queues.into_boxed_slice(),
-            total_threads: AtomicUsize::new(0),
-            runnable_threads: AtomicUsize::new(0),
+            total_threads: CachePadded::new(AtomicUsize::new(0)),
+            runnable_threads: CachePadded::new(AtomicUsize::new(0)),
+            target_latency_ns: AtomicU64::new(DEFAULT_TARGET_LATENCY_NS),
+            min_granularity_ns: AtomicU64::new(DEFAULT_MIN_GRANULARITY_NS),
+            aging_interval_ns: AtomicU64::new(DEFAULT_AGING_INTERVAL_NS),
+            slice_curves: &SliceCurves::DEFAULT,
+            placement: Box::new(LastCpu),
         }
     }
 
-    fn priority_level(priority: u8) -> PriorityLevel {
-        match priority {
-            0 => PriorityLevel::Idle,
-            1..=63 => PriorityLevel::Low,
-            64..=191 => PriorityLevel::Normal,
-            192..=255 => PriorityLevel::High,
-        }
+    /// Queue ready threads on the CPU `policy` picks instead of the one
+    /// they last ran on; see [`placement`](super::placement).
+    pub fn with_placement(mut self, policy: impl PlacementPolicy + 'static) -> Self {
+        self.placement = Box::new(policy);
+        self
     }
 
-    fn select_cpu(&self) -> CpuId {
-        let mut best_cpu = 0;
-        let mut min_threads = self.run_queues[0].thread_count.load(Ordering::Acquire);
+    /// Size quanta and weight vruntime with `curves` instead of
+    /// [`SliceCurves::DEFAULT`].
+    pub fn with_slice_curves(mut self, curves: &'static SliceCurves) -> Self {
+        self.slice_curves = curves;
+        self
+    }
 
-        for (cpu_id, queue) in self.run_queues.iter().enumerate().skip(1) {
-            let thread_count = queue.thread_count.load(Ordering::Acquire);
-            if thread_count < min_threads {
-                min_threads = thread_count;
-                best_cpu = cpu_id;
-            }
-        }
+    /// Set the period in which every runnable thread should get to run.
+    ///
+    /// Time slices are capped at this divided by the number of runnable
+    /// threads, so the quantum shrinks as the run queue grows.
+    pub fn set_target_latency(&self, latency: Duration) {
+        self.target_latency_ns
+            .store(latency.as_nanos(), Ordering::Relaxed);
+    }
 
-        best_cpu
+    pub fn target_latency(&self) -> Duration {
+        Duration::from_nanos(self.target_latency_ns.load(Ordering::Relaxed))
     }
 
-    fn try_steal_work(&self, requesting_cpu: CpuId) -> Option<ReadyRef> {
-        let start_cpu = (requesting_cpu + 1) % self.num_cpus;
+    /// Set the shortest slice the adaptive quantum shrinks to, bounding
+    /// context switch overhead under heavy load.
+    pub fn set_min_granularity(&self, granularity: Duration) {
+        self.min_granularity_ns
+            .store(granularity.as_nanos(), Ordering::Relaxed);
+    }
 
-        for i in 0..self.num_cpus {
-            let victim_cpu = (start_cpu + i) % self.num_cpus;
-            if victim_cpu == requesting_cpu {
-                continue; // Don't steal from ourselves
-            }
+    pub fn min_granularity(&self) -> Duration {
+        Duration::from_nanos(self.min_granularity_ns.load(Ordering::Relaxed))
+    }
 
-            let victim_queue = &self.run_queues[victim_cpu];
+    /// Age queued threads up a priority level for each `interval` they
+    /// have waited, up to the high level, so a busy higher level can't
+    /// starve them. Only the queue a thread waits in changes, not its
+    /// priority: once it has run it is queued by its own priority again.
+    /// Zero turns aging off.
+    pub fn set_aging_interval(&self, interval: Duration) {
+        self.aging_interval_ns
+            .store(interval.as_nanos(), Ordering::Relaxed);
+    }
 
-            if let Some(thread) = victim_queue.normal_priority.try_pop() {
-                victim_queue.thread_count.fetch_sub(1, Ordering::AcqRel);
-                return Some(thread);
-            }
+    pub fn aging_interval(&self) -> Duration {
+        Duration::from_nanos(self.aging_interval_ns.load(Ordering::Relaxed))
+    }
 
-            if let Some(thread) = victim_queue.low_priority.try_pop() {
-                victim_queue.thread_count.fetch_sub(1, Ordering::AcqRel);
-                return Some(thread);
-            }
+    /// Current tuning, all fields together.
+    pub fn tuning(&self) -> SchedulerTuning {
+        SchedulerTuning {
+            target_latency: self.target_latency(),
+            min_granularity: self.min_granularity(),
+            aging_interval: self.aging_interval(),
         }
+    }
 
-        None
+    /// Apply every field of `tuning`.
+    pub fn set_tuning(&self, tuning: SchedulerTuning) {
+        self.set_target_latency(tuning.target_latency);
+        self.set_min_granularity(tuning.min_granularity);
+        self.set_aging_interval(tuning.aging_interval);
     }
-}
 
-impl Scheduler for RoundRobinScheduler {
-    fn enqueue(&self, thread: ReadyRef) {
-        let priority = thread.priority();
-        let cpu_id = self.select_cpu();
-        let queue = &self.run_queues[cpu_id];
+    /// The longest slice a thread currently gets: target latency divided by
+    /// the running thread plus those queued, but no less than the minimum
+    /// granularity. A thread's own priority-based quantum still applies if
+    /// it is shorter.
+    pub fn adaptive_quantum(&self) -> Duration {
+        let nr_running = self.runnable_threads.load(Ordering::Acquire) as u64 + 1;
+        let target = self.target_latency_ns.load(Ordering::Relaxed);
+        let floor = self.min_granularity_ns.load(Ordering::Relaxed);
+        Duration::from_nanos((target / nr_running).max(floor))
+    }
 
-        let priority_queue = match Self::priority_level(priority) {
-            PriorityLevel::High => &queue.high_priority,
-            PriorityLevel::Normal => &queue.normal_priority,
-            PriorityLevel::Low => &queue.low_priority,
-            PriorityLevel::Idle => &queue.idle_priority,
-        };
+    fn priority_level(priority: u8) -> PriorityLevel {
+        match priority {
+            0 => PriorityLevel::Idle,
+            1..=63 => PriorityLevel::Low,
+            64..=191 => PriorityLevel::Normal,
+            192..=255 => PriorityLevel::High,
+        }
+    }
 
-        priority_queue.push(thread);
-        queue.thread_count.fetch_add(1, Ordering::AcqRel);
-        self.runnable_threads.fetch_add(1, Ordering::AcqRel);
+    /// Move threads in `cpu_id`'s queues that are due for aging up one
+    /// level. Each level is looked at from its oldest thread until one
+    /// isn't due, top level first so no thread moves twice in one call.
+    fn age(&self, cpu_id: CpuId, now: Instant) {
+        let interval = self.aging_interval_ns.load(Ordering::Relaxed);
+        let Some(queue) = self.run_queues.get(cpu_id) else {
+            return;
+        };
+        if interval == 0 {
+            return;
+        }
+        for level in [
+            PriorityLevel::Normal,
+            PriorityLevel::Low,
+            PriorityLevel::Idle,
+        ] {
+            let from = queue.level(level);
+            while let Some(head) = from.peek() {
+                let waited = now
+                    .saturating_duration_since(head.thread().state_since())
+                    .as_nanos();
+                let due = Self::priority_level(head.priority()).raised_by(waited / interval);
+                if due <= level {
+                    break;
+                }
+                let Some(thread) = from.try_pop() else {
+                    break;
+                };
+                crate::klog_trace!("rr: thread {} aged up from {:?}", thread.id(), level);
+                queue.level(level.above()).push(thread);
+                metrics::record_aging_boost();
+            }
+        }
     }
 
-    fn pick_next(&self, cpu_id: CpuId) -> Option<ReadyRef> {
+    /// Pop the next thread for `cpu_id`, from its own queues or stolen.
+    fn take_next(&self, cpu_id: CpuId) -> Option<ReadyRef> {
         if cpu_id >= self.num_cpus {
             return None;
         }
@@ -201,14 +636,113 @@ impl Scheduler for RoundRobinScheduler {
 
         if let Some(thread) = self.try_steal_work(cpu_id) {
             self.runnable_threads.fetch_sub(1, Ordering::AcqRel);
+            metrics::record_steal();
             return Some(thread);
         }
 
         None
     }
 
+    /// Queue `thread` on its CPU and level with `push`, counting it as
+    /// runnable.
+    fn queue_with(
+        &self,
+        thread: ReadyRef,
+        push: impl FnOnce(&LockFreeQueue<R>, ReadyRef) -> Result<(), ReadyRef>,
+    ) -> Result<(), ReadyRef> {
+        let priority = thread.priority();
+        let cpu_id = self.select_cpu(thread.thread());
+        let queue = &self.run_queues[cpu_id];
+
+        let priority_queue = match Self::priority_level(priority) {
+            PriorityLevel::High => &queue.high_priority,
+            PriorityLevel::Normal => &queue.normal_priority,
+            PriorityLevel::Low => &queue.low_priority,
+            PriorityLevel::Idle => &queue.idle_priority,
+        };
+
+        push(priority_queue, thread)?;
+        queue.thread_count.fetch_add(1, Ordering::AcqRel);
+        self.runnable_threads.fetch_add(1, Ordering::AcqRel);
+        Ok(())
+    }
+
+    fn select_cpu(&self, thread: &Thread) -> CpuId {
+        let queued = |cpu: CpuId| self.run_queues[cpu].thread_count.load(Ordering::Acquire);
+        let cpu_id = self
+            .placement
+            .select_cpu(thread, &CpuLoads::new(self.num_cpus, &queued))
+            % self.num_cpus;
+        if thread.last_cpu().is_some_and(|last| last != cpu_id) {
+            metrics::record_migration();
+        }
+        cpu_id
+    }
+
+    fn try_steal_work(&self, requesting_cpu: CpuId) -> Option<ReadyRef> {
+        let start_cpu = (requesting_cpu + 1) % self.num_cpus;
+
+        for i in 0..self.num_cpus {
+            let victim_cpu = (start_cpu + i) % self.num_cpus;
+            if victim_cpu == requesting_cpu {
+                continue; // Don't steal from ourselves
+            }
+
+            let victim_queue = &self.run_queues[victim_cpu];
+            let levels = [
+                &victim_queue.high_priority,
+                &victim_queue.normal_priority,
+                &victim_queue.low_priority,
+                &victim_queue.idle_priority,
+            ];
+            for level in levels {
+                if let Some(thread) = level.try_pop() {
+                    victim_queue.thread_count.fetch_sub(1, Ordering::AcqRel);
+                    return Some(thread);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl<R: ReclamationPolicy> Scheduler for RoundRobinScheduler<R> {
+    fn enqueue(&self, thread: ReadyRef) {
+        if let Err(thread) = self.try_enqueue(thread) {
+            node_alloc_failed(thread);
+        }
+    }
+
+    fn try_enqueue(&self, thread: ReadyRef) -> Result<(), ReadyRef> {
+        self.queue_with(thread, LockFreeQueue::try_push)
+    }
+
+    fn on_spawn(&self, _thread_id: ThreadId) {
+        self.total_threads.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn on_exit(&self, _thread_id: ThreadId) {
+        release(&self.total_threads);
+    }
+
+    fn pick_next(&self, cpu_id: CpuId) -> Option<ReadyRef> {
+        replenish_reserve();
+        let thread = self.take_next(cpu_id)?;
+        metrics::record_run_queue_wait(
+            Instant::now().saturating_duration_since(thread.thread().state_since()),
+        );
+        Some(thread)
+    }
+
     fn on_tick(&self, current: &RunningRef) -> Option<ReadyRef> {
-        if current.time_slice().should_preempt() {
+        let now = Instant::now();
+        self.age(current.last_cpu(), now);
+
+        if current
+            .quantum()
+            .expired_within(now, self.adaptive_quantum())
+        {
             let ready = current.prepare_preemption();
 
             let cpu_id = current.last_cpu();
@@ -237,10 +771,10 @@ impl Scheduler for RoundRobinScheduler {
                         if queue.high_priority.peek().is_some() {
                             return Some(ready);
                         }
-                    },
+                    }
                     PriorityLevel::High => {
                         return Some(ready);
-                    },
+                    }
                 }
             }
         }
@@ -248,6 +782,25 @@ impl Scheduler for RoundRobinScheduler {
         None
     }
 
+    fn pick_specific(&self, thread_id: ThreadId) -> Option<ReadyRef> {
+        for queue in self.run_queues.iter() {
+            let levels = [
+                &queue.high_priority,
+                &queue.normal_priority,
+                &queue.low_priority,
+                &queue.idle_priority,
+            ];
+            for level in levels {
+                if let Some(thread) = level.remove(thread_id) {
+                    queue.thread_count.fetch_sub(1, Ordering::AcqRel);
+                    self.runnable_threads.fetch_sub(1, Ordering::AcqRel);
+                    return Some(thread);
+                }
+            }
+        }
+        None
+    }
+
     fn set_priority(&self, _thread_id: ThreadId, _priority: u8) {}
 
     fn on_yield(&self, current: RunningRef) {
@@ -263,15 +816,47 @@ impl Scheduler for RoundRobinScheduler {
         self.enqueue(thread);
     }
 
+    /// Queued in a node from the reserve, which dispatch keeps topped up.
+    fn try_wake_up(&self, thread: ReadyRef) -> Result<(), ReadyRef> {
+        self.queue_with(thread, LockFreeQueue::try_push_reserved)
+    }
+
     fn stats(&self) -> (usize, usize, usize) {
-        let total = self.total_threads.load(Ordering::Acquire);
         let runnable = self.runnable_threads.load(Ordering::Acquire);
+        // Queued threads count towards the total even if never spawned here
+        let total = self.total_threads.load(Ordering::Acquire).max(runnable);
         let blocked = total.saturating_sub(runnable);
         (total, runnable, blocked)
     }
+
+    fn debug_snapshot(&self) -> Vec<QueueSnapshot> {
+        held_off(|| {
+            let mut snapshot = Vec::with_capacity(self.num_cpus * BAND_PRIORITIES.len());
+            for (cpu, queue) in self.run_queues.iter().enumerate() {
+                let levels = [
+                    &queue.high_priority,
+                    &queue.normal_priority,
+                    &queue.low_priority,
+                    &queue.idle_priority,
+                ];
+                for (level, priorities) in levels.into_iter().zip(BAND_PRIORITIES) {
+                    snapshot.push(QueueSnapshot {
+                        cpu: Some(cpu),
+                        priorities,
+                        threads: level.thread_ids(),
+                    });
+                }
+            }
+            snapshot
+        })
+    }
+
+    fn slice_curves(&self) -> &'static SliceCurves {
+        self.slice_curves
+    }
 }
 
-impl CpuRunQueue {
+impl<R: ReclamationPolicy> CpuRunQueue<R> {
     fn new() -> Self {
         Self {
             high_priority: LockFreeQueue::new(),
@@ -281,9 +866,18 @@ impl CpuRunQueue {
             thread_count: AtomicUsize::new(0),
         }
     }
+
+    fn level(&self, level: PriorityLevel) -> &LockFreeQueue<R> {
+        match level {
+            PriorityLevel::High => &self.high_priority,
+            PriorityLevel::Normal => &self.normal_priority,
+            PriorityLevel::Low => &self.low_priority,
+            PriorityLevel::Idle => &self.idle_priority,
+        }
+    }
 }
 
-impl LockFreeQueue {
+impl<R: ReclamationPolicy> LockFreeQueue<R> {
     fn new() -> Self {
         let dummy = Box::into_raw(Box::new(QueueNode {
             thread: None,
@@ -291,45 +885,69 @@ impl LockFreeQueue {
         }));
 
         Self {
-            head: AtomicPtr::new(dummy),
-            tail: AtomicPtr::new(dummy),
+            head: CachePadded::new(AtomicPtr::new(dummy)),
+            tail: CachePadded::new(AtomicPtr::new(dummy)),
+            _reclaim: PhantomData,
         }
     }
 
-    fn debug_list_threads(&self) -> alloc::vec::Vec<usize> {
-        let mut ids = alloc::vec::Vec::new();
+    /// Ids of the queued threads, front first. The caller keeps other
+    /// threads from popping meanwhile, which could free a node under it.
+    fn thread_ids(&self) -> Vec<ThreadId> {
+        let mut ids = Vec::new();
         let head = self.head.load(Ordering::Acquire);
         let mut current = unsafe { (*head).next.load(Ordering::Acquire) };
         while !current.is_null() {
-            if let Some(ref thread) = unsafe { &(*current).thread } {
-                ids.push(thread.id().get());
-            } else {
-                ids.push(0);
+            if let Some(thread) = unsafe { &(*current).thread } {
+                ids.push(thread.id());
             }
             current = unsafe { (*current).next.load(Ordering::Acquire) };
         }
         ids
     }
 
+    /// Append `thread`, aborting through the allocation error handler if
+    /// no node can be found for it: dropping it would lose the thread.
     fn push(&self, thread: ReadyRef) {
-        let new_node = Box::into_raw(Box::new(QueueNode {
-            thread: Some(thread),
-            next: AtomicPtr::new(ptr::null_mut()),
-        }));
+        if let Err(thread) = self.try_push(thread) {
+            node_alloc_failed(thread);
+        }
+    }
+
+    /// Append `thread`, or hand it back if no node can be allocated for it
+    /// even from the reserve.
+    fn try_push(&self, thread: ReadyRef) -> Result<(), ReadyRef> {
+        self.link(QueueNode::with_thread(thread)?);
+        Ok(())
+    }
 
+    /// Append `thread` in a node from the reserve, without allocating, or
+    /// hand it back if the reserve is empty.
+    fn try_push_reserved(&self, thread: ReadyRef) -> Result<(), ReadyRef> {
+        self.link(QueueNode::reserved(thread)?);
+        Ok(())
+    }
+
+    fn link(&self, new_node: *mut QueueNode) {
+        let mut guard = R::enter();
         loop {
-            let tail = self.tail.load(Ordering::Acquire);
+            let tail = R::protect(&mut guard, 0, &self.tail);
             let next = unsafe { (*tail).next.load(Ordering::Acquire) };
 
             //  (ABA prevention)
             if tail == self.tail.load(Ordering::Acquire) {
                 if next.is_null() {
-                    if unsafe { (*tail).next.compare_exchange_weak(
-                        ptr::null_mut(),
-                        new_node,
-                        Ordering::Release,
-                        Ordering::Relaxed
-                    ).is_ok() } {
+                    if unsafe {
+                        (*tail)
+                            .next
+                            .compare_exchange_weak(
+                                ptr::null_mut(),
+                                new_node,
+                                Ordering::Release,
+                                Ordering::Relaxed,
+                            )
+                            .is_ok()
+                    } {
                         break;
                     }
                 } else {
@@ -337,7 +955,7 @@ impl LockFreeQueue {
                         tail,
                         next,
                         Ordering::Release,
-                        Ordering::Relaxed
+                        Ordering::Relaxed,
                     );
                 }
             }
@@ -347,15 +965,16 @@ impl LockFreeQueue {
             self.tail.load(Ordering::Acquire),
             new_node,
             Ordering::Release,
-            Ordering::Relaxed
+            Ordering::Relaxed,
         );
     }
 
     fn try_pop(&self) -> Option<ReadyRef> {
+        let mut guard = R::enter();
         loop {
-            let head = self.head.load(Ordering::Acquire);
+            let head = R::protect(&mut guard, 0, &self.head);
             let tail = self.tail.load(Ordering::Acquire);
-            let next = unsafe { (*head).next.load(Ordering::Acquire) };
+            let next = R::protect(&mut guard, 1, unsafe { &(*head).next });
 
             // (ABA prevention)
             if head == self.head.load(Ordering::Acquire) {
@@ -367,7 +986,7 @@ impl LockFreeQueue {
                         tail,
                         next,
                         Ordering::Release,
-                        Ordering::Relaxed
+                        Ordering::Relaxed,
                     );
                 } else {
                     if next.is_null() {
@@ -376,16 +995,18 @@ impl LockFreeQueue {
 
                     let thread = unsafe { (*next).thread.take() };
 
-                    if self.head.compare_exchange_weak(
-                        head,
-                        next,
-                        Ordering::Release,
-                        Ordering::Relaxed
-                    ).is_ok() {
+                    if self
+                        .head
+                        .compare_exchange_weak(head, next, Ordering::Release, Ordering::Relaxed)
+                        .is_ok()
+                    {
                         unsafe {
-                            drop(Box::from_raw(head));
+                            R::retire(head);
+                        }
+                        // Emptied by `remove`; keep going past it
+                        if thread.is_some() {
+                            return thread;
                         }
-                        return thread;
                     } else {
                         if let Some(t) = thread {
                             unsafe {
@@ -398,19 +1019,41 @@ impl LockFreeQueue {
         }
     }
 
-    fn peek(&self) -> Option<&ReadyRef> {
+    /// Remove the thread with `id`, keeping the others in order.
+    ///
+    /// The thread is taken out of its node where it sits; the emptied node
+    /// stays linked until a pop skips and frees it. Nothing is allocated or
+    /// moved, so interrupt handlers may call this. Callers must have
+    /// interrupts disabled on the dispatching CPU, which makes every pop,
+    /// so no node is freed under the walk.
+    fn remove(&self, id: ThreadId) -> Option<ReadyRef> {
         let head = self.head.load(Ordering::Acquire);
-        let next = unsafe { (*head).next.load(Ordering::Acquire) };
+        let mut current = unsafe { (*head).next.load(Ordering::Acquire) };
+        while !current.is_null() {
+            let slot = unsafe { &mut (*current).thread };
+            if slot.as_ref().is_some_and(|thread| thread.id() == id) {
+                return slot.take();
+            }
+            current = unsafe { (*current).next.load(Ordering::Acquire) };
+        }
+        None
+    }
 
-        if next.is_null() {
-            None
-        } else {
-            unsafe { (*next).thread.as_ref() }
+    /// The thread a pop would return, past any nodes `remove` emptied.
+    fn peek(&self) -> Option<&ReadyRef> {
+        let head = self.head.load(Ordering::Acquire);
+        let mut current = unsafe { (*head).next.load(Ordering::Acquire) };
+        while !current.is_null() {
+            if let Some(thread) = unsafe { (*current).thread.as_ref() } {
+                return Some(thread);
+            }
+            current = unsafe { (*current).next.load(Ordering::Acquire) };
         }
+        None
     }
 }
 
-impl Drop for LockFreeQueue {
+impl<R: ReclamationPolicy> Drop for LockFreeQueue<R> {
     fn drop(&mut self) {
         while self.try_pop().is_some() {}
 
@@ -423,7 +1066,7 @@ impl Drop for LockFreeQueue {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 enum PriorityLevel {
     Idle,
     Low,
@@ -431,12 +1074,25 @@ enum PriorityLevel {
     High,
 }
 
-unsafe impl Send for RoundRobinScheduler {}
-unsafe impl Sync for RoundRobinScheduler {}
+impl PriorityLevel {
+    const ALL: [PriorityLevel; 4] = [Self::Idle, Self::Low, Self::Normal, Self::High];
 
+    /// `levels` levels up, stopping at the top.
+    fn raised_by(self, levels: u64) -> Self {
+        let index = (self as u64).saturating_add(levels).min(3);
+        Self::ALL[index as usize]
+    }
+
+    fn above(self) -> Self {
+        self.raised_by(1)
+    }
+}
+
+unsafe impl<R: ReclamationPolicy> Send for RoundRobinScheduler<R> {}
+unsafe impl<R: ReclamationPolicy> Sync for RoundRobinScheduler<R> {}
 
-unsafe impl Send for FirstComeFirstServeScheduler {}
-unsafe impl Sync for FirstComeFirstServeScheduler {}
+unsafe impl<R: ReclamationPolicy> Send for FirstComeFirstServeScheduler<R> {}
+unsafe impl<R: ReclamationPolicy> Sync for FirstComeFirstServeScheduler<R> {}
 
 #[cfg(test)]
 mod tests {
@@ -444,10 +1100,11 @@ mod tests {
 
     #[test]
     fn test_priority_level_mapping() {
-        assert_eq!(RoundRobinScheduler::priority_level(0), PriorityLevel::Idle);
-        assert_eq!(RoundRobinScheduler::priority_level(32), PriorityLevel::Low);
-        assert_eq!(RoundRobinScheduler::priority_level(128), PriorityLevel::Normal);
-        assert_eq!(RoundRobinScheduler::priority_level(255), PriorityLevel::High);
+        type Rr = RoundRobinScheduler<Immediate>;
+        assert_eq!(Rr::priority_level(0), PriorityLevel::Idle);
+        assert_eq!(Rr::priority_level(32), PriorityLevel::Low);
+        assert_eq!(Rr::priority_level(128), PriorityLevel::Normal);
+        assert_eq!(Rr::priority_level(255), PriorityLevel::High);
     }
 
     #[test]
@@ -459,12 +1116,239 @@ mod tests {
         assert_eq!(total, 0);
         assert_eq!(runnable, 0);
         assert_eq!(blocked, 0);
+
+        // No two CPUs' queues share a cache line
+        let first = &*scheduler.run_queues[0] as *const CpuRunQueue as usize;
+        let second = &*scheduler.run_queues[1] as *const CpuRunQueue as usize;
+        assert_eq!(first % 64, 0);
+        assert!(second - first >= core::mem::size_of::<CpuRunQueue>());
+        assert_eq!(core::mem::size_of::<LockFreeQueue<Immediate>>() % 64, 0);
+    }
+
+    #[test]
+    fn test_node_reserve_fills_and_drains() {
+        let reserve = NodeReserve::new();
+        reserve.fill();
+        assert_eq!(reserve.len.load(Ordering::Relaxed), RESERVE_NODES);
+        let nodes: Vec<_> = core::iter::from_fn(|| reserve.take()).collect();
+        assert_eq!(nodes.len(), RESERVE_NODES);
+        assert!(reserve.take().is_none());
+
+        // Refills only the slots that were emptied
+        reserve.nodes[0].store(nodes[0], Ordering::Relaxed);
+        reserve.len.store(1, Ordering::Relaxed);
+        reserve.fill();
+        assert_eq!(reserve.len.load(Ordering::Relaxed), RESERVE_NODES);
+        for node in nodes
+            .into_iter()
+            .skip(1)
+            .chain(core::iter::from_fn(|| reserve.take()))
+        {
+            unsafe { QueueNode::free(node) };
+        }
     }
 
     #[test]
     fn test_lock_free_queue_basic() {
-        let queue = LockFreeQueue::new();
+        let queue = LockFreeQueue::<Immediate>::new();
         assert!(queue.try_pop().is_none());
         assert!(queue.peek().is_none());
     }
+
+    #[test]
+    fn test_adaptive_quantum_shrinks_with_load() {
+        let scheduler = RoundRobinScheduler::new(1);
+        scheduler.set_target_latency(Duration::from_millis(6));
+        scheduler.set_min_granularity(Duration::from_micros(750));
+        assert_eq!(scheduler.adaptive_quantum(), Duration::from_millis(6));
+
+        scheduler.runnable_threads.store(2, Ordering::Relaxed);
+        assert_eq!(scheduler.adaptive_quantum(), Duration::from_millis(2));
+
+        scheduler.runnable_threads.store(31, Ordering::Relaxed);
+        assert_eq!(scheduler.adaptive_quantum(), Duration::from_micros(750));
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_pick_specific() {
+        use crate::mem::{StackPool, StackSizeClass};
+        use crate::thread::Thread;
+
+        let pool = StackPool::new();
+        let scheduler = RoundRobinScheduler::new(1);
+        for (id, priority) in [(1, 128), (2, 128), (3, 200)] {
+            let stack = pool.allocate(StackSizeClass::Small).unwrap();
+            let (thread, _) = Thread::new(
+                unsafe { ThreadId::new_unchecked(id) },
+                stack,
+                || {},
+                priority,
+            );
+            scheduler.enqueue(ReadyRef(thread));
+        }
+
+        let target = unsafe { ThreadId::new_unchecked(2) };
+        assert_eq!(
+            scheduler.pick_specific(target).map(|t| t.id()),
+            Some(target)
+        );
+        assert!(scheduler.pick_specific(target).is_none());
+        assert_eq!(scheduler.stats().1, 2);
+        assert_eq!(scheduler.pick_next(0).map(|t| t.id().get()), Some(3));
+        assert_eq!(scheduler.pick_next(0).map(|t| t.id().get()), Some(1));
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_fcfs_priority_order() {
+        use crate::mem::{StackPool, StackSizeClass};
+        use crate::thread::Thread;
+
+        let pool = StackPool::new();
+        let spawn = |scheduler: &FirstComeFirstServeScheduler, id, priority| {
+            let stack = pool.allocate(StackSizeClass::Small).unwrap();
+            let (thread, _) = Thread::new(
+                unsafe { ThreadId::new_unchecked(id) },
+                stack,
+                || {},
+                priority,
+            );
+            scheduler.enqueue(ReadyRef(thread));
+        };
+        let drain = |scheduler: &FirstComeFirstServeScheduler| {
+            core::iter::from_fn(|| scheduler.pick_next(0))
+                .map(|t| t.id().get())
+                .collect::<Vec<_>>()
+        };
+
+        let plain = FirstComeFirstServeScheduler::new();
+        for (id, priority) in [(1, 10), (2, 200), (3, 128)] {
+            spawn(&plain, id, priority);
+        }
+        assert_eq!(plain.stats(), (3, 3, 0));
+        assert_eq!(drain(&plain), [1, 2, 3]);
+
+        let ordered = FirstComeFirstServeScheduler::new().with_priority_order();
+        for (id, priority) in [(1, 10), (2, 200), (3, 128), (4, 130), (5, 0)] {
+            spawn(&ordered, id, priority);
+        }
+        // Thread 1 is raised into the high band, behind thread 2
+        let raised = unsafe { ThreadId::new_unchecked(1) };
+        ordered.queues[2].peek().unwrap().0.set_priority(250);
+        ordered.set_priority(raised, 250);
+        assert_eq!(drain(&ordered), [2, 1, 3, 4, 5]);
+        assert_eq!(ordered.stats(), (0, 0, 0));
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_fcfs_counts_spawned_threads() {
+        use crate::mem::{StackPool, StackSizeClass};
+        use crate::thread::Thread;
+
+        let pool = StackPool::new();
+        let scheduler = FirstComeFirstServeScheduler::new();
+        let id = unsafe { ThreadId::new_unchecked(1) };
+        let stack = pool.allocate(StackSizeClass::Small).unwrap();
+        let (thread, _) = Thread::new(id, stack, || {}, 128);
+        scheduler.on_spawn(id);
+        scheduler.enqueue(ReadyRef(thread));
+        assert_eq!(scheduler.stats(), (1, 1, 0));
+
+        let running = scheduler.pick_next(0).unwrap().start_running();
+        scheduler.on_block(running);
+        assert_eq!(scheduler.stats(), (1, 0, 1));
+
+        scheduler.on_exit(id);
+        assert_eq!(scheduler.stats(), (0, 0, 0));
+        // An exit the scheduler never saw spawn doesn't underflow
+        scheduler.on_exit(id);
+        assert_eq!(scheduler.stats(), (0, 0, 0));
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_debug_snapshot_lists_queues_in_order() {
+        use crate::mem::{StackPool, StackSizeClass};
+        use crate::thread::Thread;
+
+        let pool = StackPool::new();
+        let scheduler = RoundRobinScheduler::new(1);
+        for (id, priority) in [(1, 128), (2, 200), (3, 128)] {
+            let stack = pool.allocate(StackSizeClass::Small).unwrap();
+            let (thread, _) = Thread::new(
+                unsafe { ThreadId::new_unchecked(id) },
+                stack,
+                || {},
+                priority,
+            );
+            scheduler.enqueue(ReadyRef(thread));
+        }
+
+        let lines: Vec<_> = scheduler
+            .debug_snapshot()
+            .iter()
+            .map(|queue| alloc::format!("{}", queue))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "cpu0 192-255: T0002",
+                "cpu0 64-191: T0001 T0003",
+                "cpu0 1-63: (empty)",
+                "cpu0 0-0: (empty)"
+            ]
+        );
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_aging_moves_waiting_threads_up() {
+        use crate::mem::{StackPool, StackSizeClass};
+        use crate::thread::Thread;
+
+        let pool = StackPool::new();
+        let scheduler = RoundRobinScheduler::new(1);
+        scheduler.set_tuning(SchedulerTuning {
+            aging_interval: Duration::from_millis(10),
+            ..SchedulerTuning::default()
+        });
+        let mut threads = Vec::new();
+        for (id, priority) in [(1, 32), (2, 0), (3, 200)] {
+            let stack = pool.allocate(StackSizeClass::Small).unwrap();
+            let (thread, _) = Thread::new(
+                unsafe { ThreadId::new_unchecked(id) },
+                stack,
+                || {},
+                priority,
+            );
+            threads.push(thread.clone());
+            scheduler.enqueue(ReadyRef(thread));
+        }
+        let since = threads[0].state_since();
+        let queued = |level| {
+            scheduler.run_queues[0]
+                .level(level)
+                .thread_ids()
+                .iter()
+                .map(|id| id.get())
+                .collect::<Vec<_>>()
+        };
+
+        scheduler.age(0, since + Duration::from_millis(9));
+        assert_eq!(queued(PriorityLevel::Low), [1]);
+
+        // One level per call, however overdue
+        scheduler.age(0, since + Duration::from_millis(25));
+        assert_eq!(queued(PriorityLevel::Normal), [1]);
+        assert_eq!(queued(PriorityLevel::Low), [2]);
+        scheduler.age(0, since + Duration::from_millis(25));
+        assert_eq!(queued(PriorityLevel::High), [3, 1]);
+        assert_eq!(queued(PriorityLevel::Normal), [2]);
+
+        // Priorities themselves are untouched
+        assert_eq!(threads[0].priority(), 32);
+        assert!(metrics::starvation_stats().aging_boosts >= 3);
+    }
 }
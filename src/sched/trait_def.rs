@@ -1,10 +1,44 @@
 //! Scheduler trait definition for the new lock-free scheduler architecture.
 
 use crate::thread::{ReadyRef, RunningRef, ThreadId};
+use crate::time::SliceCurves;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::RangeInclusive;
 
 /// CPU identifier type.
 pub type CpuId = usize;
 
+/// The threads waiting in one run queue, from
+/// [`Scheduler::debug_snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueSnapshot {
+    /// CPU the queue belongs to, or `None` for a queue every CPU picks from
+    pub cpu: Option<CpuId>,
+    /// Priorities of the threads the queue holds
+    pub priorities: RangeInclusive<u8>,
+    /// Queued threads, the one to be picked next first
+    pub threads: Vec<ThreadId>,
+}
+
+/// One line per queue, e.g. `cpu0 192-255: 4 7` or `any 0-255: (empty)`.
+impl fmt::Display for QueueSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.cpu {
+            Some(cpu) => write!(f, "cpu{}", cpu)?,
+            None => f.write_str("any")?,
+        }
+        write!(f, " {}-{}:", self.priorities.start(), self.priorities.end())?;
+        if self.threads.is_empty() {
+            return f.write_str(" (empty)");
+        }
+        for id in &self.threads {
+            write!(f, " {}", id)?;
+        }
+        Ok(())
+    }
+}
+
 /// New scheduler trait for lock-free implementations.
 ///
 /// This trait defines the interface that all scheduler implementations must
@@ -19,7 +53,43 @@ pub trait Scheduler: Send + Sync {
     ///
     /// * `thread` - Ready thread to enqueue
     fn enqueue(&self, thread: ReadyRef);
-    
+
+    /// Add a newly created thread, handing it back if the scheduler can't
+    /// take it, for instance because it couldn't allocate bookkeeping for
+    /// it. The kernel spawns through this and reports a rejected thread as
+    /// [`SpawnError::SchedulerRejected`](crate::errors::SpawnError::SchedulerRejected).
+    ///
+    /// Schedulers that can't fail to queue a thread keep the default, which
+    /// forwards to [`enqueue`](Self::enqueue).
+    fn try_enqueue(&self, thread: ReadyRef) -> Result<(), ReadyRef> {
+        self.enqueue(thread);
+        Ok(())
+    }
+
+    /// A thread joined the system and is about to be enqueued for the
+    /// first time.
+    ///
+    /// Together with [`on_exit`](Self::on_exit) this lets a scheduler count
+    /// the threads it is responsible for while they are running or
+    /// blocked, neither of which it otherwise sees. The kernel calls it
+    /// once per thread, before [`try_enqueue`](Self::try_enqueue). The
+    /// default does nothing.
+    fn on_spawn(&self, thread_id: ThreadId) {
+        let _ = thread_id;
+    }
+
+    /// A thread exited, was killed, or was rejected by
+    /// [`try_enqueue`](Self::try_enqueue) after [`on_spawn`](Self::on_spawn).
+    ///
+    /// The thread is no longer queued: a ready thread being killed is taken
+    /// out with [`pick_specific`](Self::pick_specific) first. Schedulers
+    /// should tolerate exits for threads they never saw spawn, as happens
+    /// after a [`DynScheduler`](crate::sched::DynScheduler) policy switch.
+    /// The default does nothing.
+    fn on_exit(&self, thread_id: ThreadId) {
+        let _ = thread_id;
+    }
+
     /// Pick the next thread to run on the given CPU.
     ///
     /// This is called by the scheduler when a CPU needs a new thread to run.
@@ -34,7 +104,7 @@ pub trait Scheduler: Send + Sync {
     ///
     /// The next thread to run, or `None` if no threads are ready.
     fn pick_next(&self, cpu_id: CpuId) -> Option<ReadyRef>;
-    
+
     /// Handle a scheduler tick for the currently running thread.
     ///
     /// This is called periodically from timer interrupts to allow the scheduler
@@ -51,7 +121,27 @@ pub trait Scheduler: Send + Sync {
     /// replaced with the returned thread. `None` if the current thread should
     /// continue running.
     fn on_tick(&self, current: &RunningRef) -> Option<ReadyRef>;
-    
+
+    /// Take a specific thread off the run queues so it can run next.
+    ///
+    /// This is used for directed yields, where the running thread hands the
+    /// CPU to a particular thread, and by the IRQ exit to switch to a thread
+    /// an interrupt handler woke. Called with interrupts disabled, possibly
+    /// in interrupt context, so it must not allocate.
+    ///
+    /// # Arguments
+    ///
+    /// * `thread_id` - ID of the thread to take
+    ///
+    /// # Returns
+    ///
+    /// The thread, or `None` if it isn't queued or the scheduler doesn't
+    /// support picking a specific thread.
+    fn pick_specific(&self, thread_id: ThreadId) -> Option<ReadyRef> {
+        let _ = thread_id;
+        None
+    }
+
     /// Set the priority of a thread.
     ///
     /// This updates the scheduling priority of the given thread. The scheduler
@@ -62,7 +152,7 @@ pub trait Scheduler: Send + Sync {
     /// * `thread_id` - ID of the thread to modify
     /// * `priority` - New priority value (0-255, higher = more important)
     fn set_priority(&self, thread_id: ThreadId, priority: u8);
-    
+
     /// Handle a thread yielding the CPU voluntarily.
     ///
     /// This is called when a thread explicitly yields (e.g., via yield_now()).
@@ -76,7 +166,7 @@ pub trait Scheduler: Send + Sync {
         let ready = current.stop_running();
         self.enqueue(ready);
     }
-    
+
     /// Handle a thread blocking (going to sleep).
     ///
     /// This is called when a thread blocks on I/O, synchronization primitives,
@@ -90,7 +180,7 @@ pub trait Scheduler: Send + Sync {
         // When a thread blocks, it's not put back in the ready queue
         current.block();
     }
-    
+
     /// Wake up a blocked thread.
     ///
     /// This is called when a blocked thread should become ready to run again
@@ -102,7 +192,20 @@ pub trait Scheduler: Send + Sync {
     fn wake_up(&self, thread: ReadyRef) {
         self.enqueue(thread);
     }
-    
+
+    /// Wake up a blocked thread without allocating, as from an interrupt
+    /// handler.
+    ///
+    /// Hands the thread back if it can't be queued that way right now; the
+    /// caller keeps it blocked and tries again later. The kernel wakes
+    /// [sleeping](crate::kernel::sleep_for) threads from the tick through
+    /// this. The default forwards to [`wake_up`](Self::wake_up), so
+    /// schedulers that allocate to queue a thread should override it.
+    fn try_wake_up(&self, thread: ReadyRef) -> Result<(), ReadyRef> {
+        self.wake_up(thread);
+        Ok(())
+    }
+
     /// Get scheduler statistics.
     ///
     /// Returns various metrics about the scheduler state for monitoring
@@ -111,28 +214,83 @@ pub trait Scheduler: Send + Sync {
     /// # Returns
     ///
     /// A tuple of (total_threads, runnable_threads, blocked_threads).
+    /// `total_threads` counts threads between [`on_spawn`](Self::on_spawn)
+    /// and [`on_exit`](Self::on_exit); those not queued, including the
+    /// running thread, are counted as blocked.
     fn stats(&self) -> (usize, usize, usize) {
         // Default implementation returns zeros
         (0, 0, 0)
     }
+
+    /// The threads in each of the scheduler's run queues, for debugging.
+    ///
+    /// Implementations hold off preemption and interrupts while they walk
+    /// their queues, so the lists are consistent with each other, though
+    /// stale as soon as this returns. Empty queues may be included. The
+    /// default returns no queues at all, meaning the scheduler can't be
+    /// introspected.
+    fn debug_snapshot(&self) -> Vec<QueueSnapshot> {
+        Vec::new()
+    }
+
+    /// Priorities this scheduler can honour.
+    ///
+    /// The kernel rejects spawns outside this range with
+    /// [`SpawnError::InvalidPriority`](crate::errors::SpawnError::InvalidPriority),
+    /// so a policy with fewer levels can narrow it rather than silently
+    /// folding priorities together. The default accepts every priority.
+    fn priority_range(&self) -> RangeInclusive<u8> {
+        0..=u8::MAX
+    }
+
+    /// How this policy sizes quanta and weights vruntime by priority.
+    ///
+    /// The kernel applies these to each thread it spawns under this
+    /// scheduler. The default is [`SliceCurves::DEFAULT`].
+    fn slice_curves(&self) -> &'static SliceCurves {
+        &SliceCurves::DEFAULT
+    }
 }
 
 /// Priority levels for threads.
 ///
-/// These are convenience constants for common priority levels.
+/// Priorities run from 0 to 255, higher meaning more important, and are
+/// split into ranges:
+///
+/// - `0` ([`IDLE`]): runs only when nothing else is ready;
+/// - `1..=249` ([`MIN_USER`]..=[`MAX_USER`]): application threads;
+/// - `250..=255` ([`KERNEL_MIN`] and up): reserved for the kernel's own
+///   threads and priority boosts. Spawning an application thread there
+///   fails with [`SpawnError::InvalidPriority`](crate::errors::SpawnError::InvalidPriority).
+///
+/// The constants below name common points within those ranges.
 pub mod priority {
     /// Idle priority - only runs when nothing else is ready
     pub const IDLE: u8 = 0;
-    
+
+    /// Lowest priority above idle
+    pub const MIN_USER: u8 = 1;
+
     /// Low priority - background tasks
     pub const LOW: u8 = 64;
-    
+
     /// Normal priority - default for most threads
     pub const NORMAL: u8 = 128;
-    
+
     /// High priority - important system tasks
     pub const HIGH: u8 = 192;
-    
-    /// Real-time priority - critical system operations
-    pub const REALTIME: u8 = 255;
-}
\ No newline at end of file
+
+    /// Real-time priority - the most important application threads
+    pub const REALTIME: u8 = MAX_USER;
+
+    /// Highest priority an application thread may use
+    pub const MAX_USER: u8 = 249;
+
+    /// Start of the range reserved for the kernel
+    pub const KERNEL_MIN: u8 = 250;
+
+    /// Check whether `priority` is reserved for the kernel.
+    pub const fn is_reserved(priority: u8) -> bool {
+        priority >= KERNEL_MIN
+    }
+}
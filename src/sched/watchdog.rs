@@ -0,0 +1,282 @@
+//! Priority inversion and starvation detection.
+//!
+//! A [`StarvationDetector`] watches a set of threads and, each time its
+//! analysis pass runs, flags two situations that usually point at a bug:
+//!
+//! - **Starvation**: a thread has been Ready for longer than the threshold
+//!   while a lower-priority thread holds the CPU.
+//! - **Priority inversion**: a thread is blocked on a lock whose holder has
+//!   been Ready for longer than the threshold without getting to run, so
+//!   the waiter can't make progress either.
+//!
+//! The pass is cheap and neither allocates nor frees, so it can run from
+//! the timer tick through [`check`](StarvationDetector::check), or
+//! periodically from a housekeeping thread through
+//! [`run`](StarvationDetector::run), which logs each finding over the UART.
+//! Finished threads are skipped by the pass and dropped from the set
+//! later, from thread context, since dropping the last reference to one
+//! frees it.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::sched::watchdog::StarvationDetector;
+//! use preemptive_threads::time::Duration;
+//!
+//! static DETECTOR: Lazy<StarvationDetector> =
+//!     Lazy::new(|| StarvationDetector::new(Duration::from_millis(50)));
+//!
+//! DETECTOR.watch(&kernel.spawn(worker, 200)?);
+//! DETECTOR.watch(&kernel.spawn(logger, 64)?);
+//!
+//! kernel.spawn(|| loop {
+//!     DETECTOR.run();
+//!     sleep(Duration::from_millis(100));
+//! }, 1)?;
+//! ```
+
+use crate::arch;
+use crate::kernel;
+use crate::thread::{JoinHandle, Thread, ThreadId, ThreadState};
+use crate::time::{Duration, Instant};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// A problem found by the analysis pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Diagnostic {
+    /// `thread` has been Ready for `ready_for` while the lower-priority
+    /// `running` thread holds the CPU.
+    Starved {
+        thread: ThreadId,
+        priority: u8,
+        ready_for: Duration,
+        running: ThreadId,
+        running_priority: u8,
+    },
+    /// `thread` is blocked on a lock held by `owner`, which has been Ready
+    /// for `owner_ready_for` without being scheduled.
+    Inversion {
+        thread: ThreadId,
+        priority: u8,
+        owner: ThreadId,
+        owner_priority: u8,
+        owner_ready_for: Duration,
+    },
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Diagnostic::Starved { thread, priority, ready_for, running, running_priority } => write!(
+                f,
+                "thread {} (priority {}) ready for {} us while thread {} (priority {}) runs; \
+                 thread {} is not yielding or blocking - add preemption points or lower its priority",
+                thread,
+                priority,
+                ready_for.as_nanos() / 1_000,
+                running,
+                running_priority,
+                running,
+            ),
+            Diagnostic::Inversion { thread, priority, owner, owner_priority, owner_ready_for } => write!(
+                f,
+                "thread {} (priority {}) blocked on a lock held by thread {} (priority {}), \
+                 which has been ready for {} us without running; \
+                 raise thread {}'s priority or shorten the critical section",
+                thread,
+                priority,
+                owner,
+                owner_priority,
+                owner_ready_for.as_nanos() / 1_000,
+                owner,
+            ),
+        }
+    }
+}
+
+/// Watches threads for starvation and priority inversion.
+pub struct StarvationDetector {
+    threshold: Duration,
+    /// Locked with interrupts disabled, so the pass can run from the tick
+    threads: spin::Mutex<Vec<Thread>>,
+}
+
+impl StarvationDetector {
+    /// Flag threads kept from running for longer than `threshold`.
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            threads: spin::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Add the thread behind `handle` to the watched set.
+    ///
+    /// Finished threads are dropped from the set by [`prune`](Self::prune),
+    /// which this and [`run`](Self::run) call.
+    pub fn watch<T>(&self, handle: &JoinHandle<T>) {
+        let thread = handle.thread();
+        self.prune();
+        arch::without_interrupts(|| self.threads.lock().push(thread));
+    }
+
+    /// Number of unfinished threads being watched.
+    pub fn watched(&self) -> usize {
+        arch::without_interrupts(|| {
+            self.threads
+                .lock()
+                .iter()
+                .filter(|thread| thread.state() != ThreadState::Finished)
+                .count()
+        })
+    }
+
+    /// Drop finished threads from the watched set.
+    ///
+    /// Dropping the last reference to a thread frees it, so this must not
+    /// be called from interrupt context.
+    pub fn prune(&self) {
+        arch::without_interrupts(|| {
+            self.threads
+                .lock()
+                .retain(|thread| thread.state() != ThreadState::Finished)
+        });
+    }
+
+    /// Run the analysis pass, passing each finding to `report`.
+    ///
+    /// `running` is the thread on the CPU, if any. Doesn't allocate, free
+    /// or block, so it is safe to call from interrupt context. Finished
+    /// threads are skipped, and left for [`prune`](Self::prune).
+    pub fn check(
+        &self,
+        now: Instant,
+        running: Option<&Thread>,
+        mut report: impl FnMut(&Diagnostic),
+    ) {
+        arch::without_interrupts(|| {
+            let threads = self.threads.lock();
+
+            for thread in threads.iter() {
+                match thread.state() {
+                    ThreadState::Ready => {
+                        let Some(running) = running else { continue };
+                        let ready_for = self.overdue(thread, now);
+                        if let Some(ready_for) =
+                            ready_for.filter(|_| running.priority() < thread.priority())
+                        {
+                            report(&Diagnostic::Starved {
+                                thread: thread.id(),
+                                priority: thread.priority(),
+                                ready_for,
+                                running: running.id(),
+                                running_priority: running.priority(),
+                            });
+                        }
+                    }
+                    ThreadState::Blocked => {
+                        let Some(owner_id) = thread.waiting_on() else {
+                            continue;
+                        };
+                        let Some(owner) = threads.iter().find(|t| t.id() == owner_id) else {
+                            continue;
+                        };
+                        if owner.state() != ThreadState::Ready {
+                            continue;
+                        }
+                        if let Some(owner_ready_for) = self.overdue(owner, now) {
+                            report(&Diagnostic::Inversion {
+                                thread: thread.id(),
+                                priority: thread.priority(),
+                                owner: owner.id(),
+                                owner_priority: owner.priority(),
+                                owner_ready_for,
+                            });
+                        }
+                    }
+                    ThreadState::Running | ThreadState::Suspended | ThreadState::Finished => {}
+                }
+            }
+        })
+    }
+
+    /// Run the analysis pass against the registered kernel, log every
+    /// finding over the UART and publish it on the
+    /// [event bus](crate::kernel::events).
+    ///
+    /// Returns the number of findings.
+    pub fn run(&self) -> usize {
+        self.prune();
+        let running = kernel::global_ops().and_then(|ops| ops.current_thread());
+        let mut findings = 0;
+        self.check(Instant::now(), running.as_ref(), |diagnostic| {
+            findings += 1;
+            crate::pl011_println!("[WATCHDOG] {}", diagnostic);
+            kernel::events::publish(kernel::events::KernelEvent::Watchdog(*diagnostic));
+        });
+        findings
+    }
+
+    /// How long `thread` has been in its state, if beyond the threshold.
+    fn overdue(&self, thread: &Thread, now: Instant) -> Option<Duration> {
+        let since = thread.state_since();
+        if now <= since {
+            return None;
+        }
+        let elapsed = now.duration_since(since);
+        (elapsed > self.threshold).then_some(elapsed)
+    }
+}
+
+#[cfg(all(test, feature = "std-shim"))]
+mod tests {
+    use super::*;
+    use crate::mem::StackPool;
+    use crate::sched::conformance::spawn;
+
+    #[test]
+    fn test_detects_starvation_and_inversion() {
+        let pool = StackPool::new();
+        let detector = StarvationDetector::new(Duration::from_millis(10));
+        let (high, high_handle) = spawn(&pool, 1, 200);
+        let (low, low_handle) = spawn(&pool, 2, 64);
+        let (owner, owner_handle) = spawn(&pool, 3, 128);
+        detector.watch(&high_handle);
+        detector.watch(&low_handle);
+        detector.watch(&owner_handle);
+
+        low.set_state(ThreadState::Running);
+        owner.set_state(ThreadState::Blocked);
+        owner.set_state(ThreadState::Ready);
+        high.set_state(ThreadState::Blocked);
+        high.set_waiting_on(Some(owner.id()));
+
+        // Within the threshold nothing is reported
+        let now = owner.state_since() + Duration::from_millis(5);
+        let mut found = Vec::new();
+        detector.check(now, Some(&low), |d| found.push(*d));
+        assert!(found.is_empty());
+
+        let now = owner.state_since() + Duration::from_millis(20);
+        detector.check(now, Some(&low), |d| found.push(*d));
+        assert_eq!(found.len(), 2);
+        assert!(
+            matches!(found[0], Diagnostic::Inversion { thread, owner: o, .. }
+            if thread == high.id() && o == owner.id())
+        );
+        // The owner outranks the running thread, so it is also starved
+        assert!(
+            matches!(found[1], Diagnostic::Starved { thread, running, .. }
+            if thread == owner.id() && running == low.id())
+        );
+
+        owner.set_state(ThreadState::Finished);
+        detector.check(now, Some(&low), |_| {});
+        assert_eq!(detector.watched(), 2);
+        // The pass leaves the finished thread for prune to drop
+        assert_eq!(detector.threads.lock().len(), 3);
+        detector.prune();
+        assert_eq!(detector.threads.lock().len(), 2);
+    }
+}
@@ -0,0 +1,350 @@
+//! Bounded multi-producer multi-consumer channel.
+//!
+//! [`Channel::bounded`] returns a [`Sender`] and a [`Receiver`]; both can
+//! be cloned to add producers and consumers. Messages come out in the
+//! order they were sent. A sender on a full channel and a receiver on an
+//! empty one give up the CPU until the other side makes progress, as with
+//! [`PriorityChannel`](super::PriorityChannel).
+//!
+//! Once every [`Sender`] is dropped, receivers drain what is left and then
+//! get [`RecvError`]; once every [`Receiver`] is dropped, sends fail and
+//! hand the message back. The non-blocking
+//! [`try_send`](Sender::try_send) and [`try_recv`](Receiver::try_recv)
+//! may be called from interrupt context.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::sync::Channel;
+//!
+//! let (tx, rx) = Channel::bounded(8);
+//! for id in 0..3 {
+//!     let tx = tx.clone();
+//!     kernel.spawn(move || tx.send(read_sensor(id)).unwrap(), 128)?;
+//! }
+//! drop(tx);
+//! while let Ok(sample) = rx.recv() {
+//!     log(sample);
+//! }
+//! ```
+
+use super::{Selectable, WaitQueue};
+use crate::arch;
+use crate::mem::ArcLite;
+use alloc::collections::VecDeque;
+use core::fmt;
+use portable_atomic::{AtomicUsize, Ordering};
+
+/// State shared by all halves of one channel.
+pub struct Channel<T> {
+    /// Only locked with interrupts disabled, so IRQ senders can't deadlock
+    queue: spin::Mutex<VecDeque<T>>,
+    capacity: usize,
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
+    not_empty: WaitQueue,
+    not_full: WaitQueue,
+}
+
+/// The sending half of a [`Channel`].
+pub struct Sender<T> {
+    channel: ArcLite<Channel<T>>,
+}
+
+/// The receiving half of a [`Channel`].
+pub struct Receiver<T> {
+    channel: ArcLite<Channel<T>>,
+}
+
+/// Every [`Receiver`] is gone; the message is handed back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+/// Why [`Sender::try_send`] failed; the message is handed back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity.
+    Full(T),
+    /// Every [`Receiver`] is gone.
+    Disconnected(T),
+}
+
+/// Every [`Sender`] is gone and the channel is drained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+/// Why [`Receiver::try_recv`] returned nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// No message is pending, but senders remain.
+    Empty,
+    /// Every [`Sender`] is gone and the channel is drained.
+    Disconnected,
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("sending on a channel with no receivers")
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("sending on a full channel"),
+            TrySendError::Disconnected(_) => f.write_str("sending on a channel with no receivers"),
+        }
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("receiving on an empty channel with no senders")
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("receiving on an empty channel"),
+            TryRecvError::Disconnected => {
+                f.write_str("receiving on an empty channel with no senders")
+            }
+        }
+    }
+}
+
+impl<T> Channel<T> {
+    /// Create a channel holding at most `capacity` messages, and its first
+    /// sender and receiver.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn bounded(capacity: usize) -> (Sender<T>, Receiver<T>) {
+        assert!(capacity > 0, "channel capacity must be non-zero");
+        let channel = ArcLite::new(Self {
+            queue: spin::Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            senders: AtomicUsize::new(1),
+            receivers: AtomicUsize::new(1),
+            not_empty: WaitQueue::new(),
+            not_full: WaitQueue::new(),
+        });
+        (
+            Sender {
+                channel: channel.clone(),
+            },
+            Receiver { channel },
+        )
+    }
+
+    /// Number of pending messages.
+    pub fn len(&self) -> usize {
+        arch::without_interrupts(|| self.queue.lock().len())
+    }
+
+    /// Check whether no messages are pending.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum number of pending messages.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn has_senders(&self) -> bool {
+        self.senders.load(Ordering::Acquire) != 0
+    }
+
+    fn has_receivers(&self) -> bool {
+        self.receivers.load(Ordering::Acquire) != 0
+    }
+
+    /// Callers must have interrupts disabled.
+    fn push(&self, value: T) -> Result<(), T> {
+        let mut queue = self.queue.lock();
+        if queue.len() >= self.capacity {
+            return Err(value);
+        }
+        queue.push_back(value);
+        Ok(())
+    }
+
+    /// Callers must have interrupts disabled.
+    fn pop(&self) -> Option<T> {
+        self.queue.lock().pop_front()
+    }
+}
+
+impl<T> Sender<T> {
+    /// Send `value`, blocking while the channel is full.
+    ///
+    /// Fails, handing `value` back, once no receiver is left.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let channel = &*self.channel;
+        let mut value = Some(value);
+        channel.not_full.wait_until(|| {
+            if !channel.has_receivers() {
+                return true;
+            }
+            match value.take() {
+                Some(v) => match channel.push(v) {
+                    Ok(()) => true,
+                    Err(v) => {
+                        value = Some(v);
+                        false
+                    }
+                },
+                None => true,
+            }
+        });
+        // Left over only if there was nobody to take it
+        if let Some(value) = value {
+            return Err(SendError(value));
+        }
+        channel.not_empty.notify_one();
+        crate::platform_timer::preemption_checkpoint();
+        Ok(())
+    }
+
+    /// Send `value` if there is room.
+    ///
+    /// Never blocks and is safe to call from interrupt context.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let channel = &*self.channel;
+        if !channel.has_receivers() {
+            return Err(TrySendError::Disconnected(value));
+        }
+        arch::without_interrupts(|| channel.push(value)).map_err(TrySendError::Full)?;
+        channel.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// The channel this sends on, e.g. for its length.
+    pub fn channel(&self) -> &Channel<T> {
+        &self.channel
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receive the oldest message, blocking while the channel is empty.
+    ///
+    /// Fails once the channel is empty and no sender is left.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let channel = &*self.channel;
+        let mut value = None;
+        channel.not_empty.wait_until(|| {
+            value = channel.pop();
+            value.is_some() || !channel.has_senders()
+        });
+        let value = value.ok_or(RecvError)?;
+        channel.not_full.notify_one();
+        crate::platform_timer::preemption_checkpoint();
+        Ok(value)
+    }
+
+    /// Receive the oldest message, if any.
+    ///
+    /// Never blocks and is safe to call from interrupt context.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let channel = &*self.channel;
+        // Checked first, so a message sent just before the last sender
+        // went away isn't missed
+        let connected = channel.has_senders();
+        match arch::without_interrupts(|| channel.pop()) {
+            Some(value) => {
+                channel.not_full.notify_one();
+                Ok(value)
+            }
+            None if connected => Err(TryRecvError::Empty),
+            None => Err(TryRecvError::Disconnected),
+        }
+    }
+
+    /// The channel this receives from, e.g. for its length.
+    pub fn channel(&self) -> &Channel<T> {
+        &self.channel
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Ordering::AcqRel);
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.receivers.fetch_add(1, Ordering::AcqRel);
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.channel.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // Blocked receivers find the channel disconnected
+            self.channel.not_empty.notify_all();
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if self.channel.receivers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.channel.not_full.notify_all();
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for Channel<T> {}
+unsafe impl<T: Send> Sync for Channel<T> {}
+
+/// Ready when there is a message to receive, or the senders are gone.
+impl<T> Selectable for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        !self.channel.is_empty() || !self.channel.has_senders()
+    }
+
+    fn wait_queue(&self) -> &WaitQueue {
+        &self.channel.not_empty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fifo_capacity_and_disconnect() {
+        let (tx, rx) = Channel::bounded(2);
+        let tx2 = tx.clone();
+        tx.send(1).unwrap();
+        tx2.try_send(2).unwrap();
+        assert_eq!(tx.try_send(3), Err(TrySendError::Full(3)));
+        assert_eq!(rx.channel().len(), 2);
+
+        let rx2 = rx.clone();
+        assert_eq!(rx2.recv(), Ok(1));
+        tx.send(3).unwrap();
+        drop((tx, tx2));
+
+        // What was sent is still delivered, then the channel reports closed
+        assert_eq!(rx.try_recv(), Ok(2));
+        assert_eq!(rx2.recv(), Ok(3));
+        assert_eq!(rx.recv(), Err(RecvError));
+        assert_eq!(rx2.try_recv(), Err(TryRecvError::Disconnected));
+
+        let (tx, rx) = Channel::bounded(1);
+        drop(rx);
+        assert_eq!(tx.send("lost"), Err(SendError("lost")));
+        assert_eq!(tx.try_send("lost"), Err(TrySendError::Disconnected("lost")));
+    }
+}
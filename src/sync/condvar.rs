@@ -0,0 +1,136 @@
+//! Condition variable for threads holding a [`Mutex`].
+//!
+//! A thread that finds the state behind a mutex not yet the way it needs
+//! it calls [`Condvar::wait`], which releases the mutex and blocks the
+//! thread until another one changes the state and calls
+//! [`notify_one`](Condvar::notify_one) or
+//! [`notify_all`](Condvar::notify_all). The mutex is locked again before
+//! `wait` returns.
+//!
+//! Wake-ups can be spurious, and another thread may get the mutex first
+//! and change the state back, so always re-check the condition after
+//! waking, or let [`wait_while`](Condvar::wait_while) do it:
+//!
+//! ```ignore
+//! use preemptive_threads::sync::{Condvar, Mutex};
+//!
+//! static JOBS: Mutex<VecDeque<Job>> = Mutex::new(VecDeque::new());
+//! static READY: Condvar = Condvar::new();
+//!
+//! // Worker
+//! let job = READY.wait_while(JOBS.lock(), |jobs| jobs.is_empty()).pop_front();
+//!
+//! // Producer
+//! JOBS.lock().push_back(job);
+//! READY.notify_one();
+//! ```
+
+use super::mutex::{Mutex, MutexGuard};
+use super::WaitQueue;
+use portable_atomic::{AtomicUsize, Ordering};
+
+/// Threads waiting for a change to state behind a [`Mutex`].
+pub struct Condvar {
+    /// Bumped by every notification; a waiter sleeps until it moves
+    notifications: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            notifications: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Release the mutex behind `guard`, block until notified, and lock
+    /// it again.
+    ///
+    /// A notification sent after the mutex is released is never missed,
+    /// so a notifier that changes the state under the mutex can't slip in
+    /// between the caller's check and its sleep. Must not be called from
+    /// interrupt context.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex: &'a Mutex<T> = guard.mutex;
+        // Read before unlocking, so every later notification counts
+        let seen = self.notifications.load(Ordering::Acquire);
+        core::mem::forget(guard);
+        mutex.unlock();
+
+        self.waiters
+            .wait_until(|| self.notifications.load(Ordering::Acquire) != seen);
+        mutex.lock()
+    }
+
+    /// [`wait`](Self::wait) for as long as `condition` holds for the data,
+    /// checked under the mutex before each wait and after each wake-up.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wake one waiting thread, if any.
+    ///
+    /// Safe to call from interrupt context.
+    pub fn notify_one(&self) {
+        self.notifications.fetch_add(1, Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    /// Wake every waiting thread.
+    ///
+    /// Safe to call from interrupt context.
+    pub fn notify_all(&self) {
+        self.notifications.fetch_add(1, Ordering::Release);
+        self.waiters.notify_all();
+    }
+
+    /// Number of threads blocked in [`wait`](Self::wait).
+    pub fn waiters(&self) -> usize {
+        self.waiters.len()
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_wait_while_sees_every_item() {
+        extern crate std;
+
+        let queue = Mutex::new(Vec::new());
+        let ready = Condvar::new();
+        let received: Vec<u32> = std::thread::scope(|scope| {
+            let consumer = scope.spawn(|| {
+                let mut received = Vec::new();
+                while received.len() < 100 {
+                    let mut items = ready.wait_while(queue.lock(), |items| items.is_empty());
+                    received.append(&mut items);
+                }
+                received
+            });
+            for item in 0..100 {
+                queue.lock().push(item);
+                ready.notify_one();
+            }
+            consumer.join().unwrap()
+        });
+        assert_eq!(received, (0..100).collect::<Vec<_>>());
+        assert!(!queue.is_locked());
+    }
+}
@@ -0,0 +1,63 @@
+//! Manual-reset event flag.
+//!
+//! An [`EventFlag`] stays set until explicitly cleared, waking every waiting
+//! thread when it is set. [`set`](EventFlag::set) is safe to call from
+//! interrupt context, which makes the flag a simple way to signal "data
+//! arrived" or "shutdown requested" to one or more threads.
+
+use super::{Selectable, WaitQueue};
+use portable_atomic::{AtomicBool, Ordering};
+
+/// A flag threads can block on until it is set.
+pub struct EventFlag {
+    set: AtomicBool,
+    waiters: WaitQueue,
+}
+
+impl EventFlag {
+    /// Create a cleared flag.
+    pub const fn new() -> Self {
+        Self {
+            set: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Set the flag and wake all waiters.
+    pub fn set(&self) {
+        self.set.store(true, Ordering::Release);
+        self.waiters.notify_all();
+    }
+
+    /// Clear the flag.
+    pub fn clear(&self) {
+        self.set.store(false, Ordering::Release);
+    }
+
+    /// Check whether the flag is set.
+    pub fn is_set(&self) -> bool {
+        self.set.load(Ordering::Acquire)
+    }
+
+    /// Block until the flag is set.
+    pub fn wait(&self) {
+        self.waiters.wait_until(|| self.is_set());
+    }
+}
+
+/// Ready while the flag is set.
+impl Selectable for EventFlag {
+    fn is_ready(&self) -> bool {
+        self.is_set()
+    }
+
+    fn wait_queue(&self) -> &WaitQueue {
+        &self.waiters
+    }
+}
+
+impl Default for EventFlag {
+    fn default() -> Self {
+        Self::new()
+    }
+}
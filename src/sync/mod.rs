@@ -0,0 +1,27 @@
+//! Synchronization primitives integrated with the scheduler.
+//!
+//! These build on the kernel's block/wake mechanism so that waiting threads
+//! give up the CPU instead of spinning.
+
+pub mod channel;
+pub mod condvar;
+pub mod event;
+pub mod mutex;
+pub mod ordering;
+pub mod priority_channel;
+pub mod rcu;
+pub mod rwlock;
+pub mod select;
+pub mod spsc;
+pub mod wait_queue;
+
+pub use channel::{Channel, Receiver, Sender};
+pub use condvar::Condvar;
+pub use event::EventFlag;
+pub use mutex::{Mutex, MutexGuard};
+pub use priority_channel::PriorityChannel;
+pub use rcu::Rcu;
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use select::{Selectable, Selector, Timeout};
+pub use spsc::SpscRing;
+pub use wait_queue::WaitQueue;
@@ -0,0 +1,180 @@
+//! Mutual exclusion lock that blocks instead of spinning.
+//!
+//! A thread that finds a [`Mutex`] held is taken off the CPU and queued
+//! until the holder unlocks it, so a contended lock costs the waiter
+//! nothing but the switch, rather than the rest of its time slice. Use
+//! `spin::Mutex` only for very short sections shared with interrupt
+//! handlers, which can't block.
+
+use super::WaitQueue;
+use crate::kernel;
+use crate::thread::{Thread, ThreadId};
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use portable_atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// A lock protecting a `T`, whose waiters block on a [`WaitQueue`].
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    /// Holder's thread ID, 0 if unknown, for starvation diagnostics
+    owner: AtomicUsize,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+/// Access to a locked [`Mutex`]'s data; unlocks when dropped.
+pub struct MutexGuard<'a, T: ?Sized> {
+    pub(super) mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Mutex<T> {
+    /// Create an unlocked mutex holding `data`.
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            owner: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Lock the mutex, blocking the calling thread while another holds it.
+    ///
+    /// Must not be called from interrupt context, or recursively by the
+    /// holder, which would wait on itself forever.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let current = current_thread();
+        self.waiters.wait_until(|| {
+            let acquired = self.acquire();
+            if let Some(thread) = &current {
+                let owner = self.owner.load(Ordering::Relaxed);
+                thread
+                    .set_waiting_on((!acquired && owner != 0).then(|| ThreadId::new(owner as u64)));
+            }
+            acquired
+        });
+        self.locked_by(current.as_ref())
+    }
+
+    /// Lock the mutex if it is free, without blocking.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.acquire()
+            .then(|| self.locked_by(current_thread().as_ref()))
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// The holder of the lock, if it is held by a known thread.
+    pub fn owner(&self) -> Option<ThreadId> {
+        match self.owner.load(Ordering::Relaxed) {
+            0 => None,
+            id => Some(ThreadId::new(id as u64)),
+        }
+    }
+
+    /// Number of threads blocked waiting for the lock.
+    pub fn waiters(&self) -> usize {
+        self.waiters.len()
+    }
+
+    /// The data, through exclusive access that needs no locking.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Release the lock and wake one waiter; the caller gives up its
+    /// guard without dropping it.
+    pub(super) fn unlock(&self) {
+        self.owner.store(0, Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    fn locked_by(&self, holder: Option<&Thread>) -> MutexGuard<'_, T> {
+        let id = holder.map_or(0, |thread| thread.id().get());
+        self.owner.store(id, Ordering::Relaxed);
+        MutexGuard { mutex: self }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.write_str("Mutex { <locked> }"),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+        // A woken waiter that outranks us runs now
+        crate::platform_timer::preemption_checkpoint();
+    }
+}
+
+/// The running thread, if a kernel is running threads.
+pub(super) fn current_thread() -> Option<Thread> {
+    kernel::global_ops().and_then(|ops| ops.current_thread())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_excludes_until_guard_drops() {
+        let mutex = Mutex::new(alloc::vec![1, 2]);
+        {
+            let mut guard = mutex.lock();
+            guard.push(3);
+            assert!(mutex.is_locked());
+            assert!(mutex.try_lock().is_none());
+        }
+        assert!(!mutex.is_locked());
+        assert_eq!(mutex.owner(), None);
+        assert_eq!(*mutex.try_lock().unwrap(), [1, 2, 3]);
+        assert_eq!(mutex.into_inner(), [1, 2, 3]);
+    }
+}
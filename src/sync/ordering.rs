@@ -0,0 +1,198 @@
+//! The kernel's memory ordering contracts, and an optional runtime audit
+//! of them.
+//!
+//! Each [`Edge`] is one happens-before pair the kernel relies on: a write
+//! made visible with a `Release` operation on one side and picked up with
+//! an `Acquire` operation on the other. Atomics on such an edge pass their
+//! ordering through [`release`] or [`acquire`], naming the edge:
+//!
+//! ```ignore
+//! self.state.store(new as u8, ordering::release(Edge::ThreadState, Ordering::Release));
+//! ```
+//!
+//! Normally both are the identity and compile away. With the
+//! `ordering-audit` feature they check that the ordering is at least as
+//! strong as the edge needs, so weakening one to `Relaxed` fails the test
+//! suite instead of corrupting a context once a month on hardware. Points
+//! where the kernel depends on an edge having been crossed, such as
+//! dispatching a thread, call [`check`] with what they observed; a `false`
+//! is a happens-before violation. [`report`] returns per-edge counts.
+//!
+//! # Edges
+//!
+//! | Edge | Release side | Acquire side | Protects |
+//! |------|--------------|--------------|----------|
+//! | [`ThreadState`](Edge::ThreadState) | `Thread::set_state`, `compare_and_set_state` | `Thread::state` | Everything written to a thread before its state changed, e.g. its exit result |
+//! | [`ContextPublish`](Edge::ContextPublish) | State set to `Ready` after the context is saved | Dispatch (`Kernel::install_next`) | The saved registers of the thread being switched in |
+//! | [`PreemptDepth`](Edge::PreemptDepth) | `preempt_enable` | `preempt_disable`, the tick | Data touched inside a preempt-disabled section |
+//! | [`IrqHandler`](Edge::IrqHandler) | `irq::register_handler` | `irq::dispatch` | The handler's static state, set up before registration |
+
+use portable_atomic::Ordering;
+
+/// A happens-before pair the kernel depends on; see the
+/// [module docs](self#edges).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Edge {
+    ThreadState = 0,
+    ContextPublish = 1,
+    PreemptDepth = 2,
+    IrqHandler = 3,
+}
+
+/// Number of [`Edge`]s.
+pub const EDGES: usize = 4;
+
+/// Counts for one edge since boot; all zero without `ordering-audit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EdgeStats {
+    /// Release-side operations checked.
+    pub releases: u64,
+    /// Acquire-side operations checked.
+    pub acquires: u64,
+    /// Orderings too weak for the edge, or failed [`check`]s.
+    pub violations: u64,
+}
+
+/// Pass `order` through as the release side of `edge`.
+#[inline(always)]
+#[track_caller]
+pub fn release(edge: Edge, order: Ordering) -> Ordering {
+    #[cfg(feature = "ordering-audit")]
+    audit::record(
+        edge,
+        audit::Side::Release,
+        matches!(
+            order,
+            Ordering::Release | Ordering::AcqRel | Ordering::SeqCst
+        ),
+    );
+    #[cfg(not(feature = "ordering-audit"))]
+    let _ = edge;
+    order
+}
+
+/// Pass `order` through as the acquire side of `edge`.
+#[inline(always)]
+#[track_caller]
+pub fn acquire(edge: Edge, order: Ordering) -> Ordering {
+    #[cfg(feature = "ordering-audit")]
+    audit::record(
+        edge,
+        audit::Side::Acquire,
+        matches!(
+            order,
+            Ordering::Acquire | Ordering::AcqRel | Ordering::SeqCst
+        ),
+    );
+    #[cfg(not(feature = "ordering-audit"))]
+    let _ = edge;
+    order
+}
+
+/// Record whether the acquire side of `edge` observed what the release
+/// side published.
+#[inline(always)]
+#[track_caller]
+pub fn check(edge: Edge, observed: bool) {
+    #[cfg(feature = "ordering-audit")]
+    audit::record(edge, audit::Side::Observe, observed);
+    #[cfg(not(feature = "ordering-audit"))]
+    let _ = (edge, observed);
+}
+
+/// Per-edge counts, indexed by `Edge as usize`.
+pub fn report() -> [EdgeStats; EDGES] {
+    #[cfg(feature = "ordering-audit")]
+    {
+        audit::report()
+    }
+    #[cfg(not(feature = "ordering-audit"))]
+    {
+        [EdgeStats::default(); EDGES]
+    }
+}
+
+#[cfg(feature = "ordering-audit")]
+mod audit {
+    use super::{Edge, EdgeStats, EDGES};
+    use portable_atomic::{AtomicU64, Ordering};
+
+    pub(super) enum Side {
+        Release,
+        Acquire,
+        Observe,
+    }
+
+    struct Counters {
+        releases: AtomicU64,
+        acquires: AtomicU64,
+        violations: AtomicU64,
+    }
+
+    static COUNTERS: [Counters; EDGES] = {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: Counters = Counters {
+            releases: AtomicU64::new(0),
+            acquires: AtomicU64::new(0),
+            violations: AtomicU64::new(0),
+        };
+        [ZERO; EDGES]
+    };
+
+    #[track_caller]
+    pub(super) fn record(edge: Edge, side: Side, ok: bool) {
+        let counters = &COUNTERS[edge as usize];
+        match side {
+            Side::Release => counters.releases.fetch_add(1, Ordering::Relaxed),
+            Side::Acquire | Side::Observe => counters.acquires.fetch_add(1, Ordering::Relaxed),
+        };
+        if !ok {
+            counters.violations.fetch_add(1, Ordering::Relaxed);
+            // Host runs are where this is meant to be caught
+            #[cfg(feature = "std-shim")]
+            panic!(
+                "memory ordering audit: {:?} violated at {}",
+                edge,
+                core::panic::Location::caller()
+            );
+        }
+    }
+
+    pub(super) fn report() -> [EdgeStats; EDGES] {
+        let mut stats = [EdgeStats::default(); EDGES];
+        for (stats, counters) in stats.iter_mut().zip(COUNTERS.iter()) {
+            *stats = EdgeStats {
+                releases: counters.releases.load(Ordering::Relaxed),
+                acquires: counters.acquires.load(Ordering::Relaxed),
+                violations: counters.violations.load(Ordering::Relaxed),
+            };
+        }
+        stats
+    }
+}
+
+#[cfg(all(test, feature = "ordering-audit", feature = "std-shim"))]
+mod tests {
+    use super::*;
+    extern crate std;
+
+    #[test]
+    fn test_audit_counts_and_rejects_weak_orderings() {
+        let before = report()[Edge::IrqHandler as usize];
+        assert_eq!(
+            release(Edge::IrqHandler, Ordering::AcqRel),
+            Ordering::AcqRel
+        );
+        assert_eq!(
+            acquire(Edge::IrqHandler, Ordering::Acquire),
+            Ordering::Acquire
+        );
+        let after = report()[Edge::IrqHandler as usize];
+        assert!(after.releases > before.releases && after.acquires > before.acquires);
+
+        let weak = std::panic::catch_unwind(|| acquire(Edge::IrqHandler, Ordering::Relaxed));
+        assert!(weak.is_err());
+        assert!(report()[Edge::IrqHandler as usize].violations > before.violations);
+    }
+}
@@ -0,0 +1,234 @@
+//! Bounded channel that delivers the most urgent message first.
+//!
+//! Each message carries a `u8` priority, with the same convention as thread
+//! priorities: higher is more urgent. [`recv`](PriorityChannel::recv) returns
+//! the highest-priority pending message; messages of equal priority come out
+//! in the order they were sent. Useful for command queues where an urgent
+//! command (stop, reset) must overtake a backlog of routine ones.
+//!
+//! Blocking follows the wait queue model: a receiver on an empty channel and
+//! a sender on a full one give up the CPU until the other side makes
+//! progress. The non-blocking [`try_send`](PriorityChannel::try_send) and
+//! [`try_recv`](PriorityChannel::try_recv) may be called from interrupt
+//! context.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::sync::PriorityChannel;
+//! use spin::Lazy;
+//!
+//! static COMMANDS: Lazy<PriorityChannel<Command>> = Lazy::new(|| PriorityChannel::new(16));
+//!
+//! COMMANDS.send(64, Command::Refresh);
+//! COMMANDS.send(255, Command::EmergencyStop); // received first
+//!
+//! let next = COMMANDS.recv();
+//! ```
+
+use super::{Selectable, WaitQueue};
+use crate::arch;
+use alloc::collections::BinaryHeap;
+use core::cmp::Ordering;
+
+struct Entry<T> {
+    priority: u8,
+    /// Send order, to keep equal priorities FIFO
+    seq: u64,
+    value: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Max-heap: higher priority first, then the earlier message
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+struct State<T> {
+    heap: BinaryHeap<Entry<T>>,
+    next_seq: u64,
+}
+
+/// A bounded multi-producer multi-consumer priority queue.
+pub struct PriorityChannel<T> {
+    /// Only locked with interrupts disabled, so IRQ senders can't deadlock
+    state: spin::Mutex<State<T>>,
+    capacity: usize,
+    not_empty: WaitQueue,
+    not_full: WaitQueue,
+}
+
+impl<T> PriorityChannel<T> {
+    /// Create a channel holding at most `capacity` messages.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "channel capacity must be non-zero");
+        Self {
+            state: spin::Mutex::new(State {
+                heap: BinaryHeap::with_capacity(capacity),
+                next_seq: 0,
+            }),
+            capacity,
+            not_empty: WaitQueue::new(),
+            not_full: WaitQueue::new(),
+        }
+    }
+
+    /// Send `value`, blocking while the channel is full.
+    pub fn send(&self, priority: u8, value: T) {
+        let mut value = Some(value);
+        self.not_full.wait_until(|| match value.take() {
+            Some(v) => match self.push(priority, v) {
+                Ok(()) => true,
+                Err(v) => {
+                    value = Some(v);
+                    false
+                }
+            },
+            None => true,
+        });
+        self.not_empty.notify_one();
+        crate::platform_timer::preemption_checkpoint();
+    }
+
+    /// Send `value` if there is room.
+    ///
+    /// Never blocks and is safe to call from interrupt context. Returns the
+    /// value back if the channel is full.
+    pub fn try_send(&self, priority: u8, value: T) -> Result<(), T> {
+        arch::without_interrupts(|| self.push(priority, value))?;
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Receive the most urgent message, blocking while the channel is empty.
+    pub fn recv(&self) -> T {
+        let mut value = None;
+        self.not_empty.wait_until(|| {
+            value = self.pop();
+            value.is_some()
+        });
+        self.not_full.notify_one();
+        crate::platform_timer::preemption_checkpoint();
+        // wait_until only returns once the condition saw a message
+        value.expect("woken without a message")
+    }
+
+    /// Receive the most urgent message, if any.
+    ///
+    /// Never blocks and is safe to call from interrupt context.
+    pub fn try_recv(&self) -> Option<T> {
+        let value = arch::without_interrupts(|| self.pop())?;
+        self.not_full.notify_one();
+        Some(value)
+    }
+
+    /// Priority of the message [`recv`](Self::recv) would return next.
+    pub fn peek_priority(&self) -> Option<u8> {
+        arch::without_interrupts(|| self.state.lock().heap.peek().map(|e| e.priority))
+    }
+
+    /// Number of pending messages.
+    pub fn len(&self) -> usize {
+        arch::without_interrupts(|| self.state.lock().heap.len())
+    }
+
+    /// Check whether no messages are pending.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum number of pending messages.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Callers must have interrupts disabled.
+    fn push(&self, priority: u8, value: T) -> Result<(), T> {
+        let mut state = self.state.lock();
+        if state.heap.len() >= self.capacity {
+            return Err(value);
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.heap.push(Entry {
+            priority,
+            seq,
+            value,
+        });
+        Ok(())
+    }
+
+    /// Callers must have interrupts disabled.
+    fn pop(&self) -> Option<T> {
+        self.state.lock().heap.pop().map(|e| e.value)
+    }
+}
+
+/// Ready when there is a message to receive.
+impl<T> Selectable for PriorityChannel<T> {
+    fn is_ready(&self) -> bool {
+        !self.is_empty()
+    }
+
+    fn wait_queue(&self) -> &WaitQueue {
+        &self.not_empty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_priority_order_and_fifo_ties() {
+        let channel = PriorityChannel::new(4);
+        channel.send(64, "routine 1");
+        channel.send(64, "routine 2");
+        channel.send(255, "urgent");
+        assert_eq!(channel.try_send(0, "idle"), Ok(()));
+        assert_eq!(channel.try_send(0, "overflow"), Err("overflow"));
+
+        assert_eq!(channel.peek_priority(), Some(255));
+        assert_eq!(channel.recv(), "urgent");
+        assert_eq!(channel.recv(), "routine 1");
+        assert_eq!(channel.recv(), "routine 2");
+        assert_eq!(channel.try_recv(), Some("idle"));
+        assert_eq!(channel.try_recv(), None);
+    }
+
+    #[test]
+    fn test_blocking_send_and_recv() {
+        let channel = PriorityChannel::new(1);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..100u32 {
+                    channel.send(1, i);
+                }
+            });
+            let received: u32 = (0..100).map(|_| channel.recv()).sum();
+            assert_eq!(received, (0..100).sum());
+        });
+        assert!(channel.is_empty());
+    }
+}
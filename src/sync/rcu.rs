@@ -0,0 +1,193 @@
+//! Read-copy-update for read-mostly data.
+//!
+//! An [`Rcu<T>`] holds a value that is read on hot paths and replaced
+//! rarely, such as the event bus's subscriber list. Readers take no lock:
+//! [`read`](Rcu::read) holds off preemption and loads the current version
+//! with `Acquire`. A writer copies the current version, changes the copy
+//! and publishes it with `Release`; the old version is retired to the
+//! kernel's [QSBR](crate::mem::reclaim::Qsbr) domain and freed after the
+//! next context switch, the grace period after which no reader can still
+//! hold it.
+//!
+//! ```ignore
+//! use preemptive_threads::sync::rcu::Rcu;
+//!
+//! static ROUTES: Rcu<Vec<Route>> = Rcu::empty();
+//!
+//! // Hot path, interrupt handlers included
+//! if let Some(routes) = ROUTES.read() {
+//!     forward(&routes, packet);
+//! }
+//!
+//! // Rare update, from a thread
+//! ROUTES.update(|routes| {
+//!     let mut routes = routes.cloned().unwrap_or_default();
+//!     routes.push(new_route);
+//!     routes
+//! });
+//! ```
+//!
+//! Read sections must not block, yield or otherwise switch threads, since
+//! a switch ends the grace period under them. Updates are serialized by a
+//! writer lock and must come from thread context. As with
+//! [`Qsbr`](crate::mem::reclaim::Qsbr), grace periods are counted
+//! kernel-wide, which is sound while the kernel dispatches on one CPU.
+
+use crate::arch;
+use crate::mem::reclaim::{Qsbr, ReclamationPolicy};
+use crate::platform_timer;
+use alloc::boxed::Box;
+use core::ops::Deref;
+use portable_atomic::{AtomicPtr, Ordering};
+
+/// A value read without locks and updated by copying, see the
+/// [module docs](self).
+pub struct Rcu<T> {
+    current: AtomicPtr<T>,
+    /// Serializes writers; taken with interrupts disabled
+    writer: spin::Mutex<()>,
+}
+
+// Readers on any thread share the value, writers move versions between
+// threads through the reclamation domain
+unsafe impl<T: Send + Sync> Send for Rcu<T> {}
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+
+impl<T: Send + Sync + 'static> Rcu<T> {
+    /// Start out with `value`.
+    pub fn new(value: T) -> Self {
+        Self {
+            current: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            writer: spin::Mutex::new(()),
+        }
+    }
+
+    /// Start out with no value, e.g. in a `static`.
+    pub const fn empty() -> Self {
+        Self {
+            current: AtomicPtr::new(core::ptr::null_mut()),
+            writer: spin::Mutex::new(()),
+        }
+    }
+
+    /// The current version, or `None` if there is none. Holds off
+    /// preemption until the returned reference is dropped, which is a
+    /// preemption point like [`preempt_enable`](platform_timer::preempt_enable).
+    pub fn read(&self) -> Option<RcuRef<'_, T>> {
+        platform_timer::preempt_disable();
+        let value = unsafe { self.current.load(Ordering::Acquire).as_ref() };
+        match value {
+            Some(value) => Some(RcuRef { value }),
+            None => {
+                platform_timer::preempt_enable();
+                None
+            }
+        }
+    }
+
+    /// Publish `f(current)` as the new version.
+    ///
+    /// `f` runs under the writer lock with interrupts disabled, so it
+    /// should only copy and adjust.
+    pub fn update(&self, f: impl FnOnce(Option<&T>) -> T) {
+        debug_assert!(!crate::irq::in_irq(), "RCU update from interrupt context");
+        // Versions retired by earlier updates are usually past their grace
+        // period by now
+        Qsbr::reclaim();
+        let old = arch::without_interrupts(|| {
+            let _writer = self.writer.lock();
+            let old = self.current.load(Ordering::Acquire);
+            let new = Box::into_raw(Box::new(f(unsafe { old.as_ref() })));
+            self.current.swap(new, Ordering::AcqRel)
+        });
+        self.retire(old);
+    }
+
+    /// Publish `value` as the new version.
+    pub fn replace(&self, value: T) {
+        self.update(|_| value);
+    }
+
+    /// Remove the current version, leaving none.
+    pub fn clear(&self) {
+        let old = arch::without_interrupts(|| {
+            let _writer = self.writer.lock();
+            self.current.swap(core::ptr::null_mut(), Ordering::AcqRel)
+        });
+        self.retire(old);
+    }
+
+    fn retire(&self, old: *mut T) {
+        if !old.is_null() {
+            // Unlinked above and retired once, by whoever swapped it out
+            unsafe { Qsbr::retire(old) };
+        }
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        let current = *self.current.get_mut();
+        if !current.is_null() {
+            // No reader can outlive a borrow of `self`
+            drop(unsafe { Box::from_raw(current) });
+        }
+    }
+}
+
+/// A version of an [`Rcu`] value, kept alive while preemption is held off.
+pub struct RcuRef<'a, T> {
+    value: &'a T,
+}
+
+impl<T> Deref for RcuRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> Drop for RcuRef<'_, T> {
+    fn drop(&mut self) {
+        platform_timer::preempt_enable();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::reclaim::quiescent_state;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_update_publishes_new_version() {
+        let rcu: Rcu<Vec<u32>> = Rcu::empty();
+        assert!(rcu.read().is_none());
+        rcu.update(|old| {
+            assert!(old.is_none());
+            alloc::vec![1]
+        });
+
+        let before = rcu.read().unwrap();
+        assert_eq!(*before, [1]);
+        assert!(platform_timer::preemption_disabled());
+        let old = &*before as *const Vec<u32>;
+        drop(before);
+        rcu.update(|old| {
+            let mut new = old.cloned().unwrap_or_default();
+            new.push(2);
+            new
+        });
+        // Retired rather than freed; other tests' quiescent states may
+        // reclaim it at any point, so it isn't read again
+        let after = rcu.read().unwrap();
+        assert_ne!(&*after as *const Vec<u32>, old);
+        assert_eq!(*after, [1, 2]);
+        drop(after);
+
+        quiescent_state();
+        rcu.clear();
+        assert!(rcu.read().is_none());
+    }
+}
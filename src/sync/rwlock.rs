@@ -0,0 +1,246 @@
+//! Reader-writer lock that blocks instead of spinning.
+//!
+//! Any number of readers or one writer hold an [`RwLock`] at a time;
+//! threads that can't get it are taken off the CPU until it is released.
+//! A waiting writer holds back new readers, so a steady stream of readers
+//! can't starve it.
+
+use super::mutex::current_thread;
+use super::WaitQueue;
+use crate::thread::ThreadId;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use portable_atomic::{AtomicUsize, Ordering};
+
+/// `state` while a writer holds the lock; otherwise it counts readers
+const WRITER: usize = usize::MAX;
+
+/// A lock protecting a `T`, shared by readers or held by one writer.
+pub struct RwLock<T: ?Sized> {
+    state: AtomicUsize,
+    /// Writers blocked in [`write`](Self::write)
+    writers_waiting: AtomicUsize,
+    /// Writer's thread ID, 0 if none or unknown, for starvation diagnostics
+    writer: AtomicUsize,
+    readers: WaitQueue,
+    writers: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+/// Shared access to a read-locked [`RwLock`]'s data.
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+/// Exclusive access to a write-locked [`RwLock`]'s data.
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<T> RwLock<T> {
+    /// Create an unlocked lock holding `data`.
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            writers_waiting: AtomicUsize::new(0),
+            writer: AtomicUsize::new(0),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Lock for reading, blocking while a writer holds or waits for the
+    /// lock.
+    ///
+    /// Must not be called from interrupt context, or by a thread that
+    /// already holds the lock for writing.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let current = current_thread();
+        self.readers.wait_until(|| {
+            let acquired = self.acquire_read();
+            if let Some(thread) = &current {
+                let writer = self.writer.load(Ordering::Relaxed);
+                thread.set_waiting_on(
+                    (!acquired && writer != 0).then(|| ThreadId::new(writer as u64)),
+                );
+            }
+            acquired
+        });
+        RwLockReadGuard { lock: self }
+    }
+
+    /// Lock for writing, blocking while anyone else holds the lock.
+    ///
+    /// Must not be called from interrupt context, or by a thread that
+    /// already holds the lock.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let current = current_thread();
+        self.writers_waiting.fetch_add(1, Ordering::Relaxed);
+        self.writers.wait_until(|| {
+            let acquired = self.acquire_write();
+            if let Some(thread) = &current {
+                let writer = self.writer.load(Ordering::Relaxed);
+                thread.set_waiting_on(
+                    (!acquired && writer != 0).then(|| ThreadId::new(writer as u64)),
+                );
+            }
+            acquired
+        });
+        self.writers_waiting.fetch_sub(1, Ordering::Relaxed);
+        let id = current.map_or(0, |thread| thread.id().get());
+        self.writer.store(id, Ordering::Relaxed);
+        RwLockWriteGuard { lock: self }
+    }
+
+    /// Lock for reading if no writer holds or waits for the lock.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.acquire_read().then(|| RwLockReadGuard { lock: self })
+    }
+
+    /// Lock for writing if the lock is free.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        if !self.acquire_write() {
+            return None;
+        }
+        let id = current_thread().map_or(0, |thread| thread.id().get());
+        self.writer.store(id, Ordering::Relaxed);
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    /// Number of readers holding the lock.
+    pub fn readers(&self) -> usize {
+        match self.state.load(Ordering::Relaxed) {
+            WRITER => 0,
+            readers => readers,
+        }
+    }
+
+    pub fn is_write_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) == WRITER
+    }
+
+    /// The data, through exclusive access that needs no locking.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn acquire_read(&self) -> bool {
+        if self.writers_waiting.load(Ordering::Relaxed) != 0 {
+            return false;
+        }
+        self.state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |readers| {
+                (readers < WRITER - 1).then(|| readers + 1)
+            })
+            .is_ok()
+    }
+
+    fn acquire_write(&self) -> bool {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Hand the free lock on: to one writer if any wait, since readers
+    /// defer to them, otherwise to every reader.
+    fn wake_next(&self) {
+        if self.writers_waiting.load(Ordering::Relaxed) == 0 || !self.writers.notify_one() {
+            self.readers.notify_all();
+        }
+        crate::platform_timer::preemption_checkpoint();
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f.debug_struct("RwLock").field("data", &&*guard).finish(),
+            None => f.write_str("RwLock { <locked> }"),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.lock.wake_next();
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.writer.store(0, Ordering::Relaxed);
+        self.lock.state.store(0, Ordering::Release);
+        self.lock.wake_next();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readers_share_writers_exclude() {
+        let lock = RwLock::new(5);
+        let first = lock.read();
+        let second = lock.try_read().unwrap();
+        assert_eq!((*first, *second, lock.readers()), (5, 5, 2));
+        assert!(lock.try_write().is_none());
+        drop((first, second));
+
+        let mut writer = lock.try_write().unwrap();
+        *writer += 1;
+        assert!(lock.is_write_locked());
+        assert!(lock.try_read().is_none());
+        drop(writer);
+
+        // A waiting writer holds back new readers
+        lock.writers_waiting.fetch_add(1, Ordering::Relaxed);
+        assert!(lock.try_read().is_none());
+        lock.writers_waiting.fetch_sub(1, Ordering::Relaxed);
+        assert_eq!(*lock.read(), 6);
+    }
+}
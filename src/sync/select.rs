@@ -0,0 +1,288 @@
+//! Waiting on several event sources at once.
+//!
+//! A [`Selector`] blocks the calling thread until any of its sources is
+//! ready: a [`PriorityChannel`](super::PriorityChannel) with a pending
+//! message, a set [`EventFlag`](super::EventFlag), or an expired
+//! [`Timeout`]. The thread sleeps on every source's wait queue at once and
+//! removes itself from all of them when it wakes, so multiplexing threads
+//! don't need polling loops.
+//!
+//! A notification that wakes the selector for a source it doesn't end up
+//! returning is passed on to the next waiter of that source, so other
+//! threads waiting on a shared source don't miss it.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::sync::{EventFlag, Selector, Timeout};
+//! use preemptive_threads::time::Duration;
+//!
+//! loop {
+//!     let timeout = Timeout::after(Duration::from_millis(100));
+//!     let mut selector = Selector::new();
+//!     let commands = selector.add(&*COMMANDS);
+//!     let shutdown = selector.add(&SHUTDOWN);
+//!     selector.add(&timeout);
+//!
+//!     match selector.wait() {
+//!         i if i == commands => handle(COMMANDS.try_recv().unwrap()),
+//!         i if i == shutdown => break,
+//!         _ => heartbeat(),
+//!     }
+//! }
+//! ```
+
+use super::WaitQueue;
+use crate::arch::{self, Arch, DefaultArch};
+use crate::kernel::{self, KernelOps};
+use crate::thread::Thread;
+use crate::time::hrtimer::{self, HrTimerId};
+use crate::time::{Duration, Instant};
+use alloc::vec::Vec;
+use core::cell::Cell;
+
+/// An event source a [`Selector`] can wait on.
+pub trait Selectable {
+    /// Whether the source is ready. Called with interrupts disabled.
+    fn is_ready(&self) -> bool;
+
+    /// The queue notified when the source may have become ready.
+    fn wait_queue(&self) -> &WaitQueue;
+
+    /// Prepare to wake a waiter on [`wait_queue`](Self::wait_queue), e.g.
+    /// by starting a timer. Called with interrupts disabled before the
+    /// selecting thread blocks.
+    ///
+    /// Returns `false` if the source can't deliver a wake-up; the selector
+    /// then polls instead of blocking.
+    fn arm(&self) -> bool {
+        true
+    }
+
+    /// Undo [`arm`](Self::arm) once the selecting thread has woken.
+    fn disarm(&self) {}
+}
+
+/// A source that becomes ready at a deadline.
+///
+/// The wake-up comes from a [`hrtimer`], so [`hrtimer::init`] must have been
+/// called for a blocked selector to notice the deadline.
+pub struct Timeout {
+    deadline: Instant,
+    slack: Option<Duration>,
+    waiters: WaitQueue,
+    timer: Cell<Option<HrTimerId>>,
+}
+
+impl Timeout {
+    /// A timeout that expires at `deadline`.
+    pub fn at(deadline: Instant) -> Self {
+        Self {
+            deadline,
+            slack: None,
+            waiters: WaitQueue::new(),
+            timer: Cell::new(None),
+        }
+    }
+
+    /// A timeout that expires `delay` from now.
+    pub fn after(delay: Duration) -> Self {
+        Self::at(Instant::now() + delay)
+    }
+
+    /// Let the wake-up come up to `slack` after the deadline, instead of
+    /// the [global slack](hrtimer::slack), so it can share a timer
+    /// interrupt with other wake-ups due around then.
+    ///
+    /// Readiness is still judged against the deadline; only the wake-up of
+    /// a blocked selector may be late.
+    pub fn with_slack(mut self, slack: Duration) -> Self {
+        self.slack = Some(slack);
+        self
+    }
+
+    /// When the timeout expires.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+fn timeout_expired(waiters: usize) {
+    // The timer is cancelled before the Timeout can move or drop
+    let waiters = unsafe { &*(waiters as *const WaitQueue) };
+    waiters.notify_all();
+}
+
+impl Selectable for Timeout {
+    fn is_ready(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    fn wait_queue(&self) -> &WaitQueue {
+        &self.waiters
+    }
+
+    fn arm(&self) -> bool {
+        let waiters = &self.waiters as *const WaitQueue as usize;
+        let slack = self.slack.unwrap_or_else(hrtimer::slack);
+        match hrtimer::start_at_with_slack(self.deadline, slack, timeout_expired, waiters) {
+            Ok(id) => {
+                self.timer.set(Some(id));
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn disarm(&self) {
+        if let Some(id) = self.timer.take() {
+            hrtimer::cancel(id);
+        }
+    }
+}
+
+impl Drop for Timeout {
+    fn drop(&mut self) {
+        self.disarm();
+    }
+}
+
+/// A set of sources to wait on together.
+pub struct Selector<'a> {
+    sources: Vec<&'a dyn Selectable>,
+}
+
+impl<'a> Selector<'a> {
+    /// Create an empty selector.
+    pub fn new() -> Self {
+        Self {
+            sources: Vec::new(),
+        }
+    }
+
+    /// Add a source, returning the index [`wait`](Self::wait) reports for it.
+    pub fn add(&mut self, source: &'a dyn Selectable) -> usize {
+        self.sources.push(source);
+        self.sources.len() - 1
+    }
+
+    /// Index of the first ready source, without blocking.
+    pub fn ready(&self) -> Option<usize> {
+        arch::without_interrupts(|| self.first_ready())
+    }
+
+    /// Block until a source is ready and return its index.
+    ///
+    /// When several sources are ready the one added first wins.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the selector has no sources.
+    pub fn wait(&self) -> usize {
+        assert!(!self.sources.is_empty(), "selector has no sources");
+
+        loop {
+            let was_enabled = DefaultArch::interrupts_enabled();
+            DefaultArch::disable_interrupts();
+
+            let mut selected = self.first_ready();
+            let mut blocked = false;
+            if selected.is_none() {
+                let current =
+                    kernel::global_ops().and_then(|ops| Some((ops, ops.current_thread()?)));
+                if let Some((ops, thread)) = current {
+                    (selected, blocked) = self.block_on_all(ops, thread);
+                }
+            }
+
+            if was_enabled {
+                DefaultArch::enable_interrupts();
+            }
+
+            if let Some(index) = selected {
+                return index;
+            }
+            if !blocked {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    fn first_ready(&self) -> Option<usize> {
+        self.sources.iter().position(|source| source.is_ready())
+    }
+
+    /// Sleep on every source's queue, then deregister from all of them.
+    ///
+    /// Returns the ready source, if any, and whether the thread blocked.
+    fn block_on_all(&self, ops: &dyn KernelOps, thread: Thread) -> (Option<usize>, bool) {
+        let id = thread.id();
+        let mut armed = true;
+        for source in &self.sources {
+            armed &= source.arm();
+            source.wait_queue().register(thread.clone());
+        }
+
+        if armed {
+            ops.block_current();
+        }
+
+        let selected = self.first_ready();
+        for (index, source) in self.sources.iter().enumerate() {
+            let notified = !source.wait_queue().remove(id);
+            source.disarm();
+            if notified && selected != Some(index) {
+                source.wait_queue().notify_one();
+            }
+        }
+        (selected, armed)
+    }
+}
+
+impl Default for Selector<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::{EventFlag, PriorityChannel};
+
+    #[test]
+    fn test_selects_first_ready_source() {
+        let channel = PriorityChannel::new(2);
+        let flag = EventFlag::new();
+        let expired = Timeout::at(Instant::from_nanos(0));
+
+        let mut selector = Selector::new();
+        let chan = selector.add(&channel);
+        let event = selector.add(&flag);
+        assert_eq!(selector.ready(), None);
+
+        flag.set();
+        assert_eq!(selector.wait(), event);
+        channel.send(1, 'x');
+        assert_eq!(selector.wait(), chan);
+
+        let timeout = selector.add(&expired);
+        assert_eq!(channel.recv(), 'x');
+        flag.clear();
+        assert_eq!(selector.wait(), timeout);
+    }
+
+    #[test]
+    fn test_wait_returns_when_another_thread_signals() {
+        let channel: PriorityChannel<u32> = PriorityChannel::new(1);
+        let flag = EventFlag::new();
+        let mut selector = Selector::new();
+        selector.add(&channel);
+        let event = selector.add(&flag);
+
+        std::thread::scope(|s| {
+            s.spawn(|| flag.set());
+            assert_eq!(selector.wait(), event);
+        });
+    }
+}
@@ -0,0 +1,126 @@
+//! Single-producer single-consumer ring buffer.
+//!
+//! Used to move data between an interrupt handler and thread context without
+//! locks or heap allocation: the producer only writes `tail`, the consumer only
+//! writes `head`, so neither side can block the other.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use portable_atomic::{AtomicUsize, Ordering};
+
+/// A fixed-capacity lock-free SPSC ring buffer.
+///
+/// Exactly one context may call [`push`](Self::push) and exactly one context
+/// may call [`pop`](Self::pop) at a time. Callers sharing a side must
+/// serialize among themselves.
+pub struct SpscRing<T: Copy, const N: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    /// Index of the next slot to read (only advanced by the consumer)
+    head: AtomicUsize,
+    /// Index of the next slot to write (only advanced by the producer)
+    tail: AtomicUsize,
+}
+
+impl<T: Copy, const N: usize> SpscRing<T, N> {
+    /// Create an empty ring.
+    pub const fn new() -> Self {
+        Self {
+            // An array of uninitialised slots needs no initialisation
+            slots: unsafe { MaybeUninit::uninit().assume_init() },
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Append a value.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the ring is full and the value was dropped.
+    pub fn push(&self, value: T) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+
+        if tail.wrapping_sub(head) >= N {
+            return false;
+        }
+
+        unsafe {
+            (*self.slots[tail % N].get()).write(value);
+        }
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Remove the oldest value, if any.
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+
+        if head == tail {
+            return None;
+        }
+
+        let value = unsafe { (*self.slots[head % N].get()).assume_init_read() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    /// Number of values currently buffered.
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+
+    /// Check whether the ring holds no values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total number of values the ring can hold.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T: Copy, const N: usize> Default for SpscRing<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl<T: Copy + Send, const N: usize> Send for SpscRing<T, N> {}
+unsafe impl<T: Copy + Send, const N: usize> Sync for SpscRing<T, N> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spsc_fifo_order() {
+        let ring: SpscRing<u8, 4> = SpscRing::new();
+        assert!(ring.is_empty());
+
+        assert!(ring.push(1));
+        assert!(ring.push(2));
+        assert_eq!(ring.len(), 2);
+
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn test_spsc_full_and_wraparound() {
+        let ring: SpscRing<u8, 2> = SpscRing::new();
+        assert!(ring.push(1));
+        assert!(ring.push(2));
+        assert!(!ring.push(3));
+
+        for i in 0..10u8 {
+            assert_eq!(ring.pop(), Some(i + 1));
+            assert!(ring.push(i + 3));
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Wait queues for blocking threads until an event occurs.
+//!
+//! A wait queue records the threads waiting on some condition. Waiters are
+//! removed from the scheduler (marked `Blocked`) instead of spinning, and are
+//! made runnable again when the event source calls `notify_one`/`notify_all`,
+//! which is safe to do from interrupt context.
+
+use crate::arch::{self, Arch, DefaultArch};
+use crate::kernel;
+use crate::thread::{Thread, ThreadId};
+use alloc::collections::VecDeque;
+
+/// A FIFO queue of threads blocked on a condition.
+pub struct WaitQueue {
+    waiters: spin::Mutex<VecDeque<Thread>>,
+}
+
+impl WaitQueue {
+    /// Create an empty wait queue.
+    pub const fn new() -> Self {
+        Self {
+            waiters: spin::Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Block the current thread until `condition` returns `true`.
+    ///
+    /// The condition is evaluated with interrupts disabled, so a notification
+    /// from an interrupt handler cannot be lost between the check and the
+    /// thread going to sleep. If no kernel is registered or no thread is
+    /// running yet (e.g. during boot), this degrades to polling.
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut condition: F) {
+        loop {
+            let was_enabled = DefaultArch::interrupts_enabled();
+            DefaultArch::disable_interrupts();
+
+            if condition() {
+                if was_enabled {
+                    DefaultArch::enable_interrupts();
+                }
+                return;
+            }
+
+            let parked = match kernel::global_ops() {
+                Some(ops) => match ops.current_thread() {
+                    Some(thread) => {
+                        self.waiters.lock().push_back(thread);
+                        ops.block_current();
+                        true
+                    }
+                    None => false,
+                },
+                None => false,
+            };
+
+            if was_enabled {
+                DefaultArch::enable_interrupts();
+            }
+
+            if !parked {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Wake the longest-waiting thread.
+    ///
+    /// # Returns
+    ///
+    /// `true` if a thread was woken.
+    pub fn notify_one(&self) -> bool {
+        let thread = arch::without_interrupts(|| self.waiters.lock().pop_front());
+
+        match thread {
+            Some(thread) => {
+                if let Some(ops) = kernel::global_ops() {
+                    ops.wake(thread);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake every waiting thread.
+    ///
+    /// # Returns
+    ///
+    /// The number of threads woken.
+    pub fn notify_all(&self) -> usize {
+        let mut woken = 0;
+        while self.notify_one() {
+            woken += 1;
+        }
+        woken
+    }
+
+    /// Add `thread` to the queue without blocking it.
+    ///
+    /// Used by waiters that sleep on several queues at once; they must
+    /// [`remove`](Self::remove) themselves again once woken.
+    pub(crate) fn register(&self, thread: Thread) {
+        arch::without_interrupts(|| self.waiters.lock().push_back(thread));
+    }
+
+    /// Remove the thread `id` from the queue.
+    ///
+    /// # Returns
+    ///
+    /// `false` if it was no longer queued, i.e. a notification consumed it.
+    pub(crate) fn remove(&self, id: ThreadId) -> bool {
+        arch::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            match waiters.iter().position(|t| t.id() == id) {
+                Some(index) => {
+                    waiters.remove(index);
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Number of threads currently waiting.
+    pub fn len(&self) -> usize {
+        arch::without_interrupts(|| self.waiters.lock().len())
+    }
+
+    /// Check whether no threads are waiting.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,56 +1,241 @@
-use super::{Thread, JoinHandle, ThreadId};
-use crate::mem::{StackPool, StackSizeClass};
-use crate::errors::SpawnError;
+use super::{FpConfig, JoinHandle, Thread};
+use crate::arch::Arch;
+use crate::errors::{NameError, SpawnError};
+use crate::kernel::{Kernel, KernelConfig};
+use crate::mem::{Stack, StackClass, StackPool, StackScrub, StackSizeClass, StackSpec};
+use crate::sched::{priority, Scheduler};
 
 extern crate alloc;
 use alloc::string::String;
 
+/// Longest thread name accepted, in bytes.
+pub const MAX_NAME_LEN: usize = 32;
+
 pub struct ThreadBuilder {
-    stack_size: StackSizeClass,
+    stack_size: StackSpec,
+    stack_scrub: Option<StackScrub>,
     priority: u8,
     name: Option<String>,
+    no_fpu: bool,
+    fp_config: FpConfig,
 }
 
 impl ThreadBuilder {
     pub fn new() -> Self {
         Self {
-            stack_size: StackSizeClass::Medium,
+            stack_size: StackSizeClass::Medium.into(),
+            stack_scrub: None,
             priority: 128,
             name: None,
+            no_fpu: false,
+            fp_config: FpConfig::new(),
         }
     }
-    
+
     pub fn stack_size(mut self, size: StackSizeClass) -> Self {
-        self.stack_size = size;
+        self.stack_size = size.into();
+        self
+    }
+
+    /// Take the stack from class `class` of the pool's table.
+    pub fn stack_class(mut self, class: StackClass) -> Self {
+        self.stack_size = StackSpec::Class(class);
+        self
+    }
+
+    /// Take the stack from the smallest class of the pool's table with at
+    /// least `bytes`.
+    pub fn stack_bytes(mut self, bytes: usize) -> Self {
+        self.stack_size = StackSpec::Bytes(bytes);
         self
     }
-    
+
+    /// Scrub this thread's stack as `scrub` says instead of following the
+    /// pool's policy for its size class.
+    pub fn stack_scrub(mut self, scrub: StackScrub) -> Self {
+        self.stack_scrub = Some(scrub);
+        self
+    }
+
     pub fn priority(mut self, priority: u8) -> Self {
         self.priority = priority;
         self
     }
-    
+
     pub fn name<T: Into<String>>(mut self, name: T) -> Self {
         self.name = Some(name.into());
         self
     }
-    
-    pub fn spawn<F>(self, _f: F, pool: &StackPool, next_id: ThreadId) -> Result<(Thread, JoinHandle), SpawnError>
+
+    /// Promise that the thread never uses FPU or NEON instructions.
+    ///
+    /// Context switches then skip saving and restoring its FPU registers,
+    /// and FPU instructions trap while it runs: a thread that breaks the
+    /// promise is terminated and its join reports failure. The compiler
+    /// uses NEON for some copies and integer code too, so only set this
+    /// for threads known not to touch it.
+    pub fn no_fpu(mut self, no_fpu: bool) -> Self {
+        self.no_fpu = no_fpu;
+        self
+    }
+
+    /// Start the thread with the floating-point rounding mode and
+    /// flush-to-zero settings in `config`, kept across its context
+    /// switches; see [`fp`](super::fp).
+    pub fn fp_config(mut self, config: FpConfig) -> Self {
+        self.fp_config = config;
+        self
+    }
+
+    /// Check the options and turn them into a [`ThreadConfig`].
+    ///
+    /// Lets callers reject a bad configuration up front instead of at
+    /// spawn time, and reuse the result for any number of spawns.
+    ///
+    /// Priorities in the kernel's reserved range (see
+    /// [`priority`](crate::sched::priority)) are rejected here; whether the
+    /// scheduler supports the priority is checked when spawning.
+    pub fn validate(self) -> Result<ThreadConfig, SpawnError> {
+        if priority::is_reserved(self.priority) {
+            return Err(SpawnError::InvalidPriority(self.priority));
+        }
+        if let Some(name) = &self.name {
+            let problem = if name.is_empty() {
+                Some(NameError::Empty)
+            } else if name.len() > MAX_NAME_LEN {
+                Some(NameError::TooLong(MAX_NAME_LEN))
+            } else if name.chars().any(char::is_control) {
+                Some(NameError::ControlCharacter)
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                return Err(SpawnError::InvalidName(problem));
+            }
+        }
+
+        Ok(ThreadConfig {
+            stack_size: self.stack_size,
+            stack_scrub: self.stack_scrub,
+            priority: self.priority,
+            name: self.name,
+            no_fpu: self.no_fpu,
+            fp_config: self.fp_config,
+        })
+    }
+
+    /// Validate the options and spawn one thread with them on `kernel`;
+    /// see [`Kernel::spawn_with`].
+    pub fn spawn<A, S, C, F, T>(
+        self,
+        kernel: &Kernel<A, S, C>,
+        f: F,
+    ) -> Result<JoinHandle<T>, SpawnError>
     where
-        F: FnOnce() + Send + 'static,
+        A: Arch,
+        S: Scheduler,
+        C: KernelConfig,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
     {
-        let stack = pool
-            .allocate(self.stack_size)
-            .ok_or(SpawnError::OutOfMemory)?;
+        kernel.spawn_with(self, f)
+    }
+}
 
-        let entry_fn: fn() = || {};
-        let (thread, handle) = Thread::new(next_id, stack, entry_fn, self.priority);
+/// Thread options checked by [`ThreadBuilder::validate`].
+///
+/// Cheap to clone, so one config can serve as a template for many threads
+/// (see [`Kernel::spawn_batch`](crate::Kernel::spawn_batch)).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadConfig {
+    stack_size: StackSpec,
+    stack_scrub: Option<StackScrub>,
+    priority: u8,
+    name: Option<String>,
+    no_fpu: bool,
+    fp_config: FpConfig,
+}
 
-        if let Some(name) = self.name {
-            thread.set_name(name);
-        }
+impl ThreadConfig {
+    /// The stack asked for, resolved against the pool's classes at spawn.
+    pub fn stack_size(&self) -> StackSpec {
+        self.stack_size
+    }
 
-        Ok((thread, handle))
+    /// Scrub policy overriding the pool's, if one was set.
+    pub fn stack_scrub(&self) -> Option<StackScrub> {
+        self.stack_scrub
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Whether threads are spawned [without the FPU](ThreadBuilder::no_fpu).
+    pub fn no_fpu(&self) -> bool {
+        self.no_fpu
+    }
+
+    /// Floating-point settings threads start with.
+    pub fn fp_config(&self) -> FpConfig {
+        self.fp_config
+    }
+
+    /// Spawn a thread with this configuration on `kernel`; see
+    /// [`Kernel::spawn_configured`].
+    pub fn spawn<A, S, C, F, T>(
+        &self,
+        kernel: &Kernel<A, S, C>,
+        f: F,
+    ) -> Result<JoinHandle<T>, SpawnError>
+    where
+        A: Arch,
+        S: Scheduler,
+        C: KernelConfig,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        kernel.spawn_configured(self, f)
+    }
+
+    /// The pool class and scrub policy the stack is taken with.
+    ///
+    /// Fails with [`SpawnError::InvalidStackSize`] if no class of the pool
+    /// fits the stack asked for.
+    pub(crate) fn stack_class<const N: usize>(
+        &self,
+        pool: &StackPool<N>,
+    ) -> Result<(StackClass, StackScrub), SpawnError> {
+        let class = pool
+            .resolve(self.stack_size)
+            .ok_or_else(|| self.stack_size.invalid())?;
+        Ok((class, self.stack_scrub.unwrap_or_else(|| pool.scrub(class))))
+    }
+
+    /// Take one thread's stack from `pool`, failing as
+    /// [`stack_class`](Self::stack_class) does or with
+    /// [`SpawnError::OutOfMemory`] if the pool is out of stacks.
+    pub(crate) fn allocate_stack<const N: usize>(
+        &self,
+        pool: &StackPool<N>,
+    ) -> Result<Stack, SpawnError> {
+        let (class, scrub) = self.stack_class(pool)?;
+        pool.allocate_with(class, scrub)
+            .ok_or(SpawnError::OutOfMemory)
+    }
+
+    /// Give a new thread the settings that live on it rather than on its
+    /// stack.
+    pub(crate) fn apply(&self, thread: &Thread) {
+        if let Some(name) = &self.name {
+            thread.set_name(name.clone());
+        }
+        thread.set_no_fpu(self.no_fpu);
+        thread.set_fp_config(self.fp_config);
     }
 }
 
@@ -58,4 +243,94 @@ impl Default for ThreadBuilder {
     fn default() -> Self {
         Self::new()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_rejects_bad_names() {
+        let config = ThreadBuilder::new()
+            .priority(200)
+            .stack_size(StackSizeClass::Small)
+            .name("sensor")
+            .validate()
+            .unwrap();
+        assert_eq!(config.priority(), 200);
+        assert_eq!(
+            config.stack_size(),
+            StackSpec::Bytes(StackSizeClass::Small.size())
+        );
+        assert_eq!(config.name(), Some("sensor"));
+
+        let rejected = [
+            ("", NameError::Empty),
+            ("tab\there", NameError::ControlCharacter),
+            (
+                "a-name-well-over-thirty-two-bytes-long",
+                NameError::TooLong(MAX_NAME_LEN),
+            ),
+        ];
+        for (name, reason) in rejected {
+            assert_eq!(
+                ThreadBuilder::new().name(name).validate(),
+                Err(SpawnError::InvalidName(reason))
+            );
+        }
+        assert_eq!(
+            ThreadBuilder::new()
+                .priority(priority::KERNEL_MIN)
+                .validate(),
+            Err(SpawnError::InvalidPriority(priority::KERNEL_MIN))
+        );
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_config_spawns_repeatedly() {
+        use crate::arch::NoOpArch;
+        use crate::sched::RoundRobinScheduler;
+
+        let kernel: Kernel<NoOpArch, RoundRobinScheduler> =
+            Kernel::new(RoundRobinScheduler::new(1));
+        kernel.init().unwrap();
+        let config = ThreadBuilder::new().name("worker").validate().unwrap();
+        for _ in 1..=3 {
+            let handle = config.spawn(&kernel, || {}).unwrap();
+            assert_eq!(handle.name().as_deref(), Some("worker"));
+            assert!(handle.thread().uses_fpu());
+        }
+
+        let config = ThreadBuilder::new().no_fpu(true).validate().unwrap();
+        let handle = config.spawn(&kernel, || {}).unwrap();
+        assert!(config.no_fpu());
+        assert!(!handle.thread().uses_fpu());
+
+        let dsp = FpConfig::new().flush_to_zero(true);
+        let handle = ThreadBuilder::new()
+            .fp_config(dsp)
+            .priority(200)
+            .spawn(&kernel, || {})
+            .unwrap();
+        assert_eq!(handle.thread().fp_config(), dsp);
+        assert_eq!(handle.priority(), 200);
+
+        // Resolved against the pool's table
+        let config = ThreadBuilder::new()
+            .stack_bytes(1 << 20)
+            .validate()
+            .unwrap();
+        assert!(matches!(
+            config.spawn(&kernel, || {}),
+            Err(SpawnError::InvalidStackSize(1_048_576))
+        ));
+        let handle = kernel
+            .spawn_with(ThreadBuilder::new().stack_class(StackClass::new(3)), || {})
+            .unwrap();
+        assert!(handle
+            .thread()
+            .stack_bounds()
+            .is_some_and(|bounds| bounds.len() > 200_000));
+        assert_eq!(kernel.live_threads(), 6);
+    }
+}
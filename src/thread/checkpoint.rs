@@ -0,0 +1,231 @@
+//! Experimental checkpoint and restore of threads.
+//!
+//! [`Kernel::checkpoint`](crate::Kernel::checkpoint) copies a suspended
+//! thread's saved registers and the used part of its stack into a buffer
+//! the caller provides. [`Kernel::restore`](crate::Kernel::restore) later
+//! starts a fresh thread from that image, on a new stack from the
+//! [pool](crate::mem::StackPool) of the same size class, resuming exactly
+//! where the original was suspended. It is `unsafe`, for the reasons
+//! below. A control loop can checkpoint itself
+//! at a known-good point and, when it faults, be killed and restarted from
+//! there instead of from scratch.
+//!
+//! # Soundness
+//!
+//! This bypasses everything Rust knows about ownership, so it is only
+//! sound under constraints the kernel cannot check:
+//!
+//! - **At most one live copy.** The image owns whatever the thread owned
+//!   at the checkpoint: heap allocations referenced from its stack, locks
+//!   it held, its claims on wait queues. The original thread must be
+//!   killed before a restored copy runs, and an image restored at most
+//!   once, or those resources are freed twice.
+//! - **Nothing changed underneath.** Heap data the thread pointed to at
+//!   the checkpoint must still be there, unmoved and in a state it can
+//!   resume with. Rewinding a loop whose state lives on its stack is fine;
+//!   rewinding past a `Vec` push that reallocated is not.
+//! - **Stack pointers are relocated by guess.** The new stack is at a
+//!   different address, so every saved register and every aligned stack
+//!   word whose value lies inside the old stack is moved along with it.
+//!   An integer that merely looks like such an address is corrupted, and a
+//!   pointer into the old stack held anywhere else (a static, the heap) is
+//!   left dangling.
+//! - **Not while holding kernel state.** Checkpoint threads suspended at a
+//!   point of their own choosing (e.g. a
+//!   [`suspend`](crate::Kernel::suspend) of themselves), not inside a
+//!   driver or with a spinlock held.
+
+use super::{FpConfig, Thread, ThreadId};
+use crate::arch::{Arch, DefaultArch};
+use crate::errors::CheckpointError;
+use crate::mem::StackClass;
+use core::ops::Range;
+
+type Context = <DefaultArch as Arch>::SavedContext;
+
+/// A thread's registers and stack image, see the [module docs](self).
+pub struct Checkpoint<'a> {
+    thread: ThreadId,
+    priority: u8,
+    no_fpu: bool,
+    fp_config: FpConfig,
+    class: StackClass,
+    context: Context,
+    /// Bounds of the stack the image was taken from
+    stack: Range<usize>,
+    /// The used, high end of that stack
+    image: &'a [u8],
+}
+
+impl<'a> Checkpoint<'a> {
+    /// Copy the state of `thread`, which must be suspended, into `buf`.
+    pub(crate) fn capture(thread: &Thread, buf: &'a mut [u8]) -> Result<Self, CheckpointError> {
+        if thread.state() != super::ThreadState::Suspended {
+            return Err(CheckpointError::NotSuspended);
+        }
+        let stack = thread
+            .inner
+            .stack
+            .as_ref()
+            .ok_or(CheckpointError::NoStack)?;
+        let bounds = stack.bounds();
+        // Switched out, so the context stays put while we copy it
+        #[allow(clippy::let_unit_value)] // SavedContext is `()` on host builds
+        let context = unsafe { core::ptr::read(thread.context_ptr()) };
+        let used = used_from(&context, &bounds);
+        if buf.len() < used.len() {
+            return Err(CheckpointError::BufferTooSmall { needed: used.len() });
+        }
+        let image = &mut buf[..used.len()];
+        unsafe {
+            core::ptr::copy_nonoverlapping(used.start as *const u8, image.as_mut_ptr(), image.len())
+        };
+
+        Ok(Self {
+            thread: thread.id(),
+            priority: thread.priority(),
+            no_fpu: !thread.uses_fpu(),
+            fp_config: thread.fp_config(),
+            class: stack.class(),
+            context,
+            stack: bounds,
+            image,
+        })
+    }
+
+    /// The thread the checkpoint was taken from.
+    pub fn thread(&self) -> ThreadId {
+        self.thread
+    }
+
+    /// Its priority at the time.
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub(crate) fn no_fpu(&self) -> bool {
+        self.no_fpu
+    }
+
+    pub(crate) fn fp_config(&self) -> FpConfig {
+        self.fp_config
+    }
+
+    /// Class of the stack a restored thread needs, in the kernel's pool.
+    pub fn class(&self) -> StackClass {
+        self.class
+    }
+
+    /// Bytes of stack saved.
+    pub fn stack_len(&self) -> usize {
+        self.image.len()
+    }
+
+    /// Write the image onto the stack of `thread`, a fresh one of
+    /// [`class`](Self::class), and load the relocated registers
+    /// into it.
+    ///
+    /// # Safety
+    ///
+    /// As for [`Kernel::restore`](crate::Kernel::restore): the
+    /// [soundness rules](self#soundness) must hold for `thread`.
+    pub(crate) unsafe fn restore_into(&self, thread: &Thread) {
+        let Some(top) = thread.stack_bounds().map(|bounds| bounds.end) else {
+            return;
+        };
+        let delta = top.wrapping_sub(self.stack.end);
+        let start = top - self.image.len();
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.image.as_ptr(), start as *mut u8, self.image.len())
+        };
+
+        // Saved frame pointers, references to locals and the like
+        let words =
+            unsafe { core::slice::from_raw_parts_mut(start as *mut usize, self.image.len() / 8) };
+        for word in words.iter_mut() {
+            *word = relocate(*word, &self.stack, delta);
+        }
+
+        let context = unsafe { &mut *thread.context_ptr() };
+        *context = unsafe { core::ptr::read(&self.context) };
+        #[cfg(target_arch = "aarch64")]
+        {
+            context.sp = relocate(context.sp as usize, &self.stack, delta) as u64;
+            for reg in context.x.iter_mut() {
+                *reg = relocate(*reg as usize, &self.stack, delta) as u64;
+            }
+        }
+    }
+}
+
+/// Part of `stack` in use by a thread switched out with `context`.
+fn used_from(context: &Context, stack: &Range<usize>) -> Range<usize> {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let sp = (context.sp as usize).clamp(stack.start, stack.end);
+        sp..stack.end
+    }
+    // No saved stack pointer: take all of it
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        let _ = context;
+        stack.clone()
+    }
+}
+
+fn relocate(value: usize, old: &Range<usize>, delta: usize) -> usize {
+    if old.contains(&value) {
+        value.wrapping_add(delta)
+    } else {
+        value
+    }
+}
+
+#[cfg(all(test, feature = "std-shim"))]
+mod tests {
+    use super::*;
+    use crate::mem::{StackPool, StackSizeClass};
+    use crate::thread::ThreadState;
+
+    #[test]
+    fn test_checkpoint_restores_relocated_stack() {
+        let pool = StackPool::new();
+        let stack = pool.allocate(StackSizeClass::Small).unwrap();
+        let old = stack.bounds();
+        // A local and a pointer to it, as a suspended frame would hold
+        let frame = (old.end - 16) as *mut usize;
+        unsafe {
+            frame.write(0x1234);
+            frame.add(1).write(frame as usize);
+        }
+        let id = ThreadId::new(5);
+        let (thread, _handle) = Thread::new(id, stack, || {}, 42);
+
+        let mut buf = alloc::vec![0u8; 64];
+        assert!(matches!(
+            Checkpoint::capture(&thread, &mut buf),
+            Err(CheckpointError::NotSuspended)
+        ));
+        thread.set_state(ThreadState::Suspended);
+        let needed = match Checkpoint::capture(&thread, &mut buf) {
+            Err(CheckpointError::BufferTooSmall { needed }) => needed,
+            _ => panic!("small buffer accepted"),
+        };
+
+        let mut buf = alloc::vec![0u8; needed];
+        let checkpoint = Checkpoint::capture(&thread, &mut buf).unwrap();
+        assert_eq!((checkpoint.thread(), checkpoint.priority()), (id, 42));
+
+        let fresh = pool.allocate(StackSizeClass::Small).unwrap();
+        let new_end = fresh.bounds().end;
+        let (copy, _handle) = Thread::new(ThreadId::new(6), fresh, || {}, 42);
+        // The original never runs, and the image is restored once
+        unsafe { checkpoint.restore_into(&copy) };
+
+        let moved = (new_end - 16) as *const usize;
+        unsafe {
+            assert_eq!(moved.read(), 0x1234);
+            assert_eq!(moved.add(1).read(), moved as usize);
+        }
+    }
+}
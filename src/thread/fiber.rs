@@ -0,0 +1,364 @@
+//! Fibers: cooperative tasks multiplexed on one kernel thread.
+//!
+//! A [`FiberExecutor`] runs any number of stackful coroutines on the kernel
+//! thread that calls [`run`](FiberExecutor::run). Each fiber gets a small
+//! stack of its own ([`FIBER_STACK_SIZE`]) and runs until it gives the CPU
+//! back explicitly, with [`Fiber::yield_now`] or by waiting:
+//!
+//! - [`Fiber::wait`] suspends just the fiber until a
+//!   [`Selectable`] source (a channel, an event flag, a timeout) is ready,
+//!   instead of blocking the whole kernel thread;
+//! - when every remaining fiber is waiting, the executor blocks its kernel
+//!   thread on all of their sources at once with a
+//!   [`Selector`](crate::sync::Selector), so an idle executor costs nothing.
+//!
+//! Fibers suit protocol state machines and similar code that mostly waits:
+//! they are much cheaper than threads, but never preempt each other, so a
+//! fiber that loops without yielding starves its siblings. The kernel
+//! thread running them is still preempted as usual.
+//!
+//! Only AArch64 can switch stacks. Elsewhere (host tests) a fiber runs to
+//! completion on the executor's stack the first time it is resumed, yields
+//! are no-ops and waits block the kernel thread.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::thread::fiber::FiberExecutor;
+//!
+//! KERNEL.spawn(|| {
+//!     let executor = FiberExecutor::new();
+//!     for port in 0..4 {
+//!         executor.spawn(move |fiber| loop {
+//!             fiber.wait(&RX_READY[port]);
+//!             handle_frame(port);
+//!         });
+//!     }
+//!     executor.run();
+//! }, 100)?;
+//! ```
+
+use crate::arch::{Arch, DefaultArch};
+use crate::sync::{Selectable, Selector};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell, UnsafeCell};
+
+/// Stack size of every fiber, in bytes.
+pub const FIBER_STACK_SIZE: usize = 8 * 1024;
+
+type Context = <DefaultArch as Arch>::SavedContext;
+type Entry = Box<dyn FnOnce(&Fiber<'_>)>;
+
+/// Where a fiber is in its life.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FiberState {
+    /// Runnable; it runs when the executor next gets to it.
+    Ready,
+    /// Suspended in [`Fiber::wait`] until its source is ready.
+    Waiting,
+    /// Returned from its entry function.
+    Finished,
+}
+
+struct Slot {
+    // Only switched to on AArch64
+    #[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+    context: UnsafeCell<Context>,
+    /// 16-byte aligned, as AArch64 requires of the stack pointer
+    #[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+    stack: Box<[u128]>,
+    entry: Cell<Option<Entry>>,
+    state: Cell<FiberState>,
+    /// Source the fiber waits on; only set while it is suspended in `wait`,
+    /// which keeps the borrow alive
+    waiting_on: Cell<Option<*const (dyn Selectable + 'static)>>,
+    /// Executor resuming the fiber, set on every resume
+    executor: Cell<*const FiberExecutor>,
+    index: usize,
+}
+
+/// Runs fibers on the calling kernel thread. See the [module docs](self).
+///
+/// The executor is not `Sync`: it and its fibers belong to one kernel
+/// thread.
+pub struct FiberExecutor {
+    /// Boxed so slots keep their address while fibers are added
+    #[allow(clippy::vec_box)]
+    slots: RefCell<Vec<Box<Slot>>>,
+    /// The executor's own context while a fiber runs
+    #[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+    main: UnsafeCell<Context>,
+    current: Cell<Option<usize>>,
+}
+
+/// A running fiber's handle to itself, passed to its entry function.
+pub struct Fiber<'a> {
+    executor: &'a FiberExecutor,
+    index: usize,
+}
+
+impl FiberExecutor {
+    #[allow(clippy::unit_arg)] // SavedContext is `()` on host builds
+    pub fn new() -> Self {
+        Self {
+            slots: RefCell::new(Vec::new()),
+            main: UnsafeCell::new(Context::default()),
+            current: Cell::new(None),
+        }
+    }
+
+    /// Add a fiber running `entry`, returning its index. It first runs
+    /// once [`run`](Self::run) gets to it.
+    #[allow(clippy::unit_arg)] // SavedContext is `()` on host builds
+    pub fn spawn<F>(&self, entry: F) -> usize
+    where
+        F: FnOnce(&Fiber<'_>) + 'static,
+    {
+        let mut slots = self.slots.borrow_mut();
+        let index = slots.len();
+        let slot = Box::new(Slot {
+            context: UnsafeCell::new(Context::default()),
+            stack: alloc::vec![0u128; FIBER_STACK_SIZE / 16].into_boxed_slice(),
+            entry: Cell::new(Some(Box::new(entry))),
+            state: Cell::new(FiberState::Ready),
+            waiting_on: Cell::new(None),
+            executor: Cell::new(core::ptr::null()),
+            index,
+        });
+        slot.prepare();
+        slots.push(slot);
+        index
+    }
+
+    /// Fibers spawned so far, finished ones included.
+    pub fn len(&self) -> usize {
+        self.slots.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Index of the fiber running now, if [`run`](Self::run) is in one.
+    pub fn current(&self) -> Option<usize> {
+        self.current.get()
+    }
+
+    /// State of fiber `index`, or `None` if there is no such fiber.
+    pub fn state(&self, index: usize) -> Option<FiberState> {
+        self.slots.borrow().get(index).map(|slot| slot.state.get())
+    }
+
+    /// Run fibers until all of them have finished.
+    ///
+    /// Fibers take turns in spawn order. When none is ready the kernel
+    /// thread blocks until one of the sources the fibers wait on is.
+    pub fn run(&self) {
+        loop {
+            let mut ran = false;
+            let mut waiting = false;
+            let mut index = 0;
+            while let Some(slot) = self.slot(index) {
+                if slot.state.get() == FiberState::Waiting && slot.source_ready() {
+                    slot.state.set(FiberState::Ready);
+                }
+                match slot.state.get() {
+                    FiberState::Ready => {
+                        self.resume(slot);
+                        ran = true;
+                    }
+                    FiberState::Waiting => waiting = true,
+                    FiberState::Finished => {}
+                }
+                index += 1;
+            }
+
+            if !ran {
+                if !waiting {
+                    return;
+                }
+                self.block_until_ready();
+            }
+        }
+    }
+
+    fn slot(&self, index: usize) -> Option<&Slot> {
+        // Slots are boxed and never removed, so the reference outlives the borrow
+        self.slots
+            .borrow()
+            .get(index)
+            .map(|slot| unsafe { &*(&**slot as *const Slot) })
+    }
+
+    /// Block the kernel thread until some waiting fiber's source is ready.
+    fn block_until_ready(&self) {
+        let slots = self.slots.borrow();
+        let mut selector = Selector::new();
+        for slot in slots.iter() {
+            if let Some(source) = slot.waiting_on.get() {
+                // The waiting fiber keeps the source borrowed until it resumes
+                selector.add(unsafe { &*source });
+            }
+        }
+        selector.wait();
+    }
+
+    fn resume(&self, slot: &Slot) {
+        slot.executor.set(self);
+        self.current.set(Some(slot.index));
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            DefaultArch::context_switch(self.main.get(), slot.context.get());
+        }
+        #[cfg(not(target_arch = "aarch64"))]
+        slot.run_entry();
+        self.current.set(None);
+    }
+
+    /// Switch from the running fiber back to [`run`](Self::run).
+    fn suspend(&self, slot: &Slot) {
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            DefaultArch::context_switch(slot.context.get(), self.main.get());
+        }
+        #[cfg(not(target_arch = "aarch64"))]
+        let _ = slot;
+    }
+}
+
+impl Default for FiberExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Slot {
+    /// Point the fiber's context at the trampoline on its own stack.
+    fn prepare(&self) {
+        #[cfg(target_arch = "aarch64")]
+        {
+            let ctx = unsafe { &mut *self.context.get() };
+            let stack_top = self.stack.as_ptr_range().end as u64;
+            ctx.x = [0; 31];
+            ctx.x[0] = self as *const Slot as u64;
+            ctx.sp = stack_top;
+            ctx.pc = fiber_trampoline as usize as u64;
+        }
+    }
+
+    /// Run the entry function and mark the fiber finished.
+    fn run_entry(&self) {
+        if let Some(entry) = self.entry.take() {
+            // Only called by `resume`, which sets the executor
+            let executor = unsafe { &*self.executor.get() };
+            entry(&Fiber {
+                executor,
+                index: self.index,
+            });
+        }
+        self.state.set(FiberState::Finished);
+    }
+
+    fn source_ready(&self) -> bool {
+        self.waiting_on.get().map_or(true, |source| {
+            crate::arch::without_interrupts(|| unsafe { &*source }.is_ready())
+        })
+    }
+}
+
+/// First code a fiber runs, on its own stack.
+#[cfg(target_arch = "aarch64")]
+extern "C" fn fiber_trampoline(slot: *const Slot) -> ! {
+    // The slot is boxed and outlives the fiber
+    let slot = unsafe { &*slot };
+    slot.run_entry();
+    let executor = unsafe { &*slot.executor.get() };
+    executor.suspend(slot);
+    unreachable!("finished fiber resumed");
+}
+
+impl Fiber<'_> {
+    /// Index of this fiber in its executor.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Let the other fibers run, resuming after them.
+    pub fn yield_now(&self) {
+        if let Some(slot) = self.executor.slot(self.index) {
+            self.executor.suspend(slot);
+        }
+    }
+
+    /// Suspend this fiber until `source` is ready.
+    ///
+    /// Other fibers keep running meanwhile; the kernel thread only blocks
+    /// once every fiber is waiting.
+    pub fn wait(&self, source: &dyn Selectable) {
+        let Some(slot) = self.executor.slot(self.index) else {
+            return;
+        };
+        if !cfg!(target_arch = "aarch64") {
+            // No stack to switch to: wait on the kernel thread instead
+            let mut selector = Selector::new();
+            selector.add(source);
+            selector.wait();
+            return;
+        }
+        while !crate::arch::without_interrupts(|| source.is_ready()) {
+            // Cleared again before `wait` returns and the borrow ends
+            let source: *const (dyn Selectable + '_) = source;
+            slot.waiting_on.set(Some(unsafe {
+                core::mem::transmute::<
+                    *const (dyn Selectable + '_),
+                    *const (dyn Selectable + 'static),
+                >(source)
+            }));
+            slot.state.set(FiberState::Waiting);
+            self.executor.suspend(slot);
+            slot.waiting_on.set(None);
+        }
+    }
+
+    /// Add another fiber to the executor running this one.
+    pub fn spawn<F>(&self, entry: F) -> usize
+    where
+        F: FnOnce(&Fiber<'_>) + 'static,
+    {
+        self.executor.spawn(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::EventFlag;
+    use alloc::rc::Rc;
+
+    #[test]
+    fn test_fibers_run_to_completion() {
+        let executor = FiberExecutor::new();
+        let done = Rc::new(Cell::new(0));
+        for _ in 0..3 {
+            let done = done.clone();
+            executor.spawn(move |fiber| {
+                assert_eq!(fiber.executor.current(), Some(fiber.index()));
+                fiber.yield_now();
+                let done = done.clone();
+                fiber.spawn(move |_| done.set(done.get() + 10));
+            });
+        }
+        let flag = Rc::new(EventFlag::new());
+        flag.set();
+        let waited = flag.clone();
+        executor.spawn(move |fiber| fiber.wait(&*waited));
+
+        assert_eq!(executor.len(), 4);
+        assert_eq!(executor.state(0), Some(FiberState::Ready));
+        executor.run();
+        assert_eq!(done.get(), 30);
+        assert_eq!(executor.len(), 7);
+        assert!((0..7).all(|i| executor.state(i) == Some(FiberState::Finished)));
+    }
+}
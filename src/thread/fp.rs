@@ -0,0 +1,248 @@
+//! Per-thread floating-point control: rounding mode, flush-to-zero and
+//! default NaN.
+//!
+//! The FPCR register holds these settings and is switched with the rest of
+//! a thread's FPU state, so each thread keeps its own. A thread starts with
+//! the [`FpConfig`] it was spawned with, the IEEE 754 defaults unless
+//! [`ThreadBuilder::fp_config`](crate::ThreadBuilder::fp_config) says
+//! otherwise:
+//!
+//! ```ignore
+//! use preemptive_threads::thread::fp::{FpConfig, RoundingMode};
+//!
+//! // Denormals cost a filter dozens of cycles each; zero them instead
+//! let dsp = FpConfig::new().flush_to_zero(true).rounding(RoundingMode::TowardZero);
+//! let config = ThreadBuilder::new().fp_config(dsp).validate()?;
+//! ```
+//!
+//! The Cortex-A53 and A72 don't trap floating-point exceptions; they only
+//! record them in FPSR's cumulative flags, which are switched per thread
+//! too. [`exceptions`] reads the calling thread's and [`clear_exceptions`]
+//! resets them, e.g. to check a block of samples for overflow.
+//!
+//! Threads only keep separate settings with the `full-fpu` feature;
+//! without it FPCR and FPSR are not switched and every thread shares the
+//! last values written.
+
+use core::fmt;
+
+/// How results are rounded, FPCR.RMode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u32)]
+pub enum RoundingMode {
+    /// To nearest, ties to even.
+    #[default]
+    Nearest = 0,
+    TowardPlusInfinity = 1,
+    TowardMinusInfinity = 2,
+    TowardZero = 3,
+}
+
+impl RoundingMode {
+    const fn from_bits(bits: u32) -> Self {
+        match bits & 0b11 {
+            0 => RoundingMode::Nearest,
+            1 => RoundingMode::TowardPlusInfinity,
+            2 => RoundingMode::TowardMinusInfinity,
+            _ => RoundingMode::TowardZero,
+        }
+    }
+}
+
+/// A thread's floating-point control settings, see the [module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FpConfig {
+    rounding: RoundingMode,
+    flush_to_zero: bool,
+    default_nan: bool,
+}
+
+impl FpConfig {
+    const RMODE_SHIFT: u32 = 22;
+    const FZ: u32 = 1 << 24;
+    const DN: u32 = 1 << 25;
+
+    /// Round to nearest, keep denormals, propagate NaN payloads: what a
+    /// thread gets unless told otherwise.
+    pub const fn new() -> Self {
+        Self {
+            rounding: RoundingMode::Nearest,
+            flush_to_zero: false,
+            default_nan: false,
+        }
+    }
+
+    pub const fn rounding(mut self, rounding: RoundingMode) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    /// Replace denormal inputs and results with zero.
+    pub const fn flush_to_zero(mut self, enabled: bool) -> Self {
+        self.flush_to_zero = enabled;
+        self
+    }
+
+    /// Return the default NaN instead of propagating an input's payload.
+    pub const fn default_nan(mut self, enabled: bool) -> Self {
+        self.default_nan = enabled;
+        self
+    }
+
+    pub const fn rounding_mode(&self) -> RoundingMode {
+        self.rounding
+    }
+
+    pub const fn flushes_to_zero(&self) -> bool {
+        self.flush_to_zero
+    }
+
+    pub const fn uses_default_nan(&self) -> bool {
+        self.default_nan
+    }
+
+    /// The FPCR value with these settings.
+    pub const fn to_fpcr(self) -> u32 {
+        let mut fpcr = (self.rounding as u32) << Self::RMODE_SHIFT;
+        if self.flush_to_zero {
+            fpcr |= Self::FZ;
+        }
+        if self.default_nan {
+            fpcr |= Self::DN;
+        }
+        fpcr
+    }
+
+    /// The settings in an FPCR value; other bits are ignored.
+    pub const fn from_fpcr(fpcr: u32) -> Self {
+        Self {
+            rounding: RoundingMode::from_bits(fpcr >> Self::RMODE_SHIFT),
+            flush_to_zero: fpcr & Self::FZ != 0,
+            default_nan: fpcr & Self::DN != 0,
+        }
+    }
+}
+
+/// Floating-point exceptions raised since the flags were last cleared,
+/// FPSR's cumulative bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FpExceptions(u32);
+
+impl FpExceptions {
+    pub const INVALID: Self = Self(1 << 0);
+    pub const DIVIDE_BY_ZERO: Self = Self(1 << 1);
+    pub const OVERFLOW: Self = Self(1 << 2);
+    pub const UNDERFLOW: Self = Self(1 << 3);
+    pub const INEXACT: Self = Self(1 << 4);
+    /// A denormal input was flushed to zero.
+    pub const INPUT_DENORMAL: Self = Self(1 << 7);
+    /// A saturating NEON instruction saturated.
+    pub const SATURATION: Self = Self(1 << 27);
+
+    const ALL: u32 = 0b1001_1111 | (1 << 27);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// The flags in an FPSR value; other bits are ignored.
+    pub const fn from_fpsr(fpsr: u32) -> Self {
+        Self(fpsr & Self::ALL)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for FpExceptions {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for FpExceptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const NAMES: [(FpExceptions, &str); 7] = [
+            (FpExceptions::INVALID, "invalid"),
+            (FpExceptions::DIVIDE_BY_ZERO, "divide-by-zero"),
+            (FpExceptions::OVERFLOW, "overflow"),
+            (FpExceptions::UNDERFLOW, "underflow"),
+            (FpExceptions::INEXACT, "inexact"),
+            (FpExceptions::INPUT_DENORMAL, "input-denormal"),
+            (FpExceptions::SATURATION, "saturation"),
+        ];
+        let mut names = NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| name);
+        match names.next() {
+            None => f.write_str("none"),
+            Some(first) => {
+                f.write_str(first)?;
+                names.try_for_each(|name| write!(f, ", {}", name))
+            }
+        }
+    }
+}
+
+/// Exceptions the calling thread has raised since it started or last
+/// called [`clear_exceptions`]. Always empty off AArch64.
+pub fn exceptions() -> FpExceptions {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let fpsr: u64;
+        unsafe {
+            core::arch::asm!("mrs {}, fpsr", out(reg) fpsr, options(nomem, nostack, preserves_flags))
+        };
+        FpExceptions::from_fpsr(fpsr as u32)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    FpExceptions::empty()
+}
+
+/// Reset the calling thread's exception flags.
+pub fn clear_exceptions() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        core::arch::asm!("msr fpsr, xzr", options(nomem, nostack, preserves_flags));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_fpcr_roundtrip() {
+        assert_eq!(FpConfig::new().to_fpcr(), 0);
+        let dsp = FpConfig::new()
+            .rounding(RoundingMode::TowardZero)
+            .flush_to_zero(true);
+        assert_eq!(dsp.to_fpcr(), 0b11 << 22 | 1 << 24);
+        assert_eq!(FpConfig::from_fpcr(dsp.to_fpcr() | 1 << 26), dsp);
+        assert!(FpConfig::from_fpcr(1 << 25).uses_default_nan());
+
+        let raised = FpExceptions::from_fpsr(0b1_0110 | 1 << 31);
+        assert_eq!(
+            raised,
+            FpExceptions::DIVIDE_BY_ZERO | FpExceptions::OVERFLOW | FpExceptions::INEXACT
+        );
+        assert_eq!(
+            alloc::format!("{}", raised),
+            "divide-by-zero, overflow, inexact"
+        );
+        assert_eq!(alloc::format!("{}", FpExceptions::empty()), "none");
+    }
+}
@@ -1,95 +1,191 @@
-
-
-use super::{ThreadInner, ThreadState};
+use super::{Thread, ThreadInner, ThreadState};
+use crate::errors::JoinError;
 use crate::mem::ArcLite;
+use crate::time::Instant;
+use alloc::string::String;
+use core::marker::PhantomData;
 
-pub struct JoinHandle {
+/// Owned permission to join a thread, yielding the `T` its entry closure
+/// returned.
+pub struct JoinHandle<T = ()> {
     pub(super) inner: ArcLite<ThreadInner>,
+    pub(super) result: PhantomData<fn() -> T>,
 }
 
-impl JoinHandle {
-    pub fn join(self) -> Result<(), ()> {
-        loop {
-            let state = self.inner.state.load(portable_atomic::Ordering::Acquire);
-            if state == ThreadState::Finished as u8 {
-                break;
-            }
-
-  
-            crate::yield_now();
+impl JoinHandle<()> {
+    /// The same thread, joined for a `T` stored by its entry closure.
+    pub(crate) fn returning<T: 'static>(self) -> JoinHandle<T> {
+        JoinHandle {
+            inner: self.inner,
+            result: PhantomData,
         }
+    }
+}
 
-        if let Some(join_result) = self.inner.join_result.try_lock() {
-            if join_result.is_some() {
-                Ok(())
-            } else {
-                Err(())
-            }
-        } else {
-            Err(())
+impl<T: 'static> JoinHandle<T> {
+    /// Block until the thread finishes, then take what it returned.
+    ///
+    /// The calling thread waits off the CPU and is woken when the thread
+    /// exits; before the kernel runs threads this degrades to polling.
+    /// Fails with [`JoinError::Terminated`] if the thread was killed, and
+    /// with [`JoinError::JoinSelf`] if called from the thread itself.
+    pub fn join(self) -> Result<T, JoinError> {
+        let current = crate::kernel::global_ops().and_then(|ops| ops.current_thread());
+        if current.is_some_and(|current| current.id() == self.inner.id) {
+            return Err(JoinError::JoinSelf);
         }
+        self.inner.joiners.wait_until(|| self.is_finished());
+
+        let value = self
+            .inner
+            .result
+            .lock()
+            .take()
+            .ok_or(JoinError::Terminated)?;
+        value
+            .downcast::<T>()
+            .map(|value| *value)
+            .map_err(|_| JoinError::InvalidHandle)
     }
-    
-    pub fn try_join(&self) -> Option<Result<(), ()>> {
-        let state = self.inner.state.load(portable_atomic::Ordering::Acquire);
-        if state == ThreadState::Finished as u8 {
-            if let Some(join_result) = self.inner.join_result.try_lock() {
-                if join_result.is_some() {
-                    Some(Ok(()))
-                } else {
-                    Some(Err(()))
-                }
-            } else {
-                Some(Err(()))
-            }
-        } else {
-            None
+}
+
+impl<T> JoinHandle<T> {
+    /// How the thread ended, without blocking: `None` while it runs,
+    /// `Ok` once it returned, [`JoinError::Terminated`] if it was killed.
+    /// The result stays for [`join`](Self::join).
+    pub fn try_join(&self) -> Option<Result<(), JoinError>> {
+        if !self.is_finished() {
+            return None;
+        }
+        match self.inner.result.lock().is_some() {
+            true => Some(Ok(())),
+            false => Some(Err(JoinError::Terminated)),
         }
     }
-    
+
     pub fn thread_id(&self) -> super::ThreadId {
         self.inner.id
     }
-    
+
+    /// The thread this handle refers to.
+    pub fn thread(&self) -> Thread {
+        Thread {
+            inner: self.inner.clone(),
+        }
+    }
+
     pub fn is_alive(&self) -> bool {
         let state = self.inner.state.load(portable_atomic::Ordering::Acquire);
         state != ThreadState::Finished as u8
     }
+
+    /// Check whether the thread has finished, without blocking.
+    pub fn is_finished(&self) -> bool {
+        !self.is_alive()
+    }
+
+    /// The thread's current state.
+    pub fn state(&self) -> ThreadState {
+        ThreadState::from_u8(self.inner.state.load(portable_atomic::Ordering::Acquire))
+    }
+
+    /// The thread's current priority.
+    pub fn priority(&self) -> u8 {
+        self.inner.priority.load(portable_atomic::Ordering::Acquire)
+    }
+
+    /// When the thread was created.
+    pub fn created_at(&self) -> Instant {
+        self.inner.created_at()
+    }
+
+    /// When the thread was first dispatched, `None` if it hasn't run yet.
+    pub fn first_run_at(&self) -> Option<Instant> {
+        self.inner.first_run_at()
+    }
+
+    /// When the thread finished or was killed, `None` while it lives.
+    pub fn exited_at(&self) -> Option<Instant> {
+        self.inner.exited_at()
+    }
+
+    /// The thread's name, if one was set.
+    pub fn name(&self) -> Option<String> {
+        self.inner.name.try_lock().and_then(|name| name.clone())
+    }
 }
 
-unsafe impl Send for JoinHandle {}
-unsafe impl Sync for JoinHandle {}
+// Joining moves the `T` to whichever thread joins, through `&self` too
+unsafe impl<T: Send> Send for JoinHandle<T> {}
+unsafe impl<T: Send> Sync for JoinHandle<T> {}
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::thread::{Thread, ThreadId};
     use crate::mem::{StackPool, StackSizeClass};
-    
+    use crate::thread::{RunningRef, Thread, ThreadId};
+
     #[cfg(feature = "std-shim")]
     #[test]
     fn test_join_handle_basic() {
         let pool = StackPool::new();
         let stack = pool.allocate(StackSizeClass::Small).unwrap();
         let thread_id = unsafe { ThreadId::new_unchecked(1) };
-        
-        let (thread, join_handle) = Thread::new(
-            thread_id,
-            stack,
-            || {},
-            128,
-        );
-        
+
+        let (thread, join_handle) = Thread::new(thread_id, stack, || {}, 128);
+
         assert_eq!(join_handle.thread_id(), thread_id);
         assert!(join_handle.is_alive());
-        assert!(join_handle.try_join().is_none()); 
-        
-        thread.set_state(ThreadState::Finished);
-        if let Some(mut join_result) = thread.inner.join_result.try_lock() {
-            *join_result = Some(());
-        }
-        
+        assert!(join_handle.try_join().is_none());
+
+        RunningRef(thread).finish();
+
         assert!(!join_handle.is_alive());
         assert_eq!(join_handle.try_join(), Some(Ok(())));
+        assert_eq!(join_handle.join(), Ok(()));
     }
-}
\ No newline at end of file
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_join_takes_result() {
+        let pool = StackPool::new();
+        let spawn = |id| {
+            let stack = pool.allocate(StackSizeClass::Small).unwrap();
+            let (thread, handle) =
+                Thread::new(unsafe { ThreadId::new_unchecked(id) }, stack, || {}, 128);
+            (thread, handle.returning::<alloc::vec::Vec<u32>>())
+        };
+
+        let (thread, handle) = spawn(3);
+        thread.set_result(alloc::boxed::Box::new(alloc::vec![1u32, 2, 3]));
+        RunningRef(thread).finish();
+        assert_eq!(handle.try_join(), Some(Ok(())));
+        assert_eq!(handle.join(), Ok(alloc::vec![1, 2, 3]));
+
+        // Killed threads leave nothing to take
+        let (thread, handle) = spawn(4);
+        RunningRef(thread).kill();
+        assert_eq!(handle.join(), Err(JoinError::Terminated));
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_join_handle_introspection() {
+        use alloc::string::ToString;
+
+        let pool = StackPool::new();
+        let stack = pool.allocate(StackSizeClass::Small).unwrap();
+        let (thread, handle) = Thread::new(unsafe { ThreadId::new_unchecked(2) }, stack, || {}, 64);
+        thread.set_name("worker".to_string());
+        thread.set_priority(200);
+        thread.set_state(ThreadState::Blocked);
+
+        assert_eq!(handle.state(), ThreadState::Blocked);
+        assert_eq!(handle.priority(), 200);
+        assert_eq!(handle.name().as_deref(), Some("worker"));
+        assert!(!handle.is_finished());
+
+        thread.set_state(ThreadState::Finished);
+        assert!(handle.is_finished());
+    }
+}
@@ -1,32 +1,113 @@
-
-
+use crate::arch::context_check::CorruptContext;
 use crate::arch::Arch;
-use crate::mem::{ArcLite, Stack};
-use crate::time::{Instant, TimeSlice};
-use portable_atomic::{AtomicU8, Ordering};
+use crate::kernel::metrics::{ExceptionCounters, ExceptionKind, ExceptionStats};
+use crate::mem::{ArcLite, Stack, ThreadArena, RED_ZONE_SIZE};
+use crate::sync::ordering::{self, Edge};
+use crate::sync::WaitQueue;
+use crate::time::{CpuAccounting, Duration, Instant, Quantum, SliceCurves};
+use core::any::Any;
+use portable_atomic::{
+    AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering,
+};
 
 extern crate alloc;
+use alloc::boxed::Box;
 use alloc::string::String;
 
-pub mod handle;
 pub mod builder;
+pub mod checkpoint;
+pub mod fiber;
+pub mod fp;
+pub mod handle;
+pub mod slab;
 
+pub use builder::{ThreadBuilder, ThreadConfig};
+pub use checkpoint::Checkpoint;
+pub use fp::FpConfig;
 pub use handle::JoinHandle;
-pub use builder::ThreadBuilder;
+pub use slab::ThreadSlab;
 
-static CURRENT_THREAD_ID: portable_atomic::AtomicU64 = portable_atomic::AtomicU64::new(1);
+crate::percpu! {
+    /// Id of the thread each CPU is running, kept up to date by the kernel
+    static CURRENT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+}
 
+/// Id of the thread running on the calling CPU.
 pub fn current_thread_id() -> ThreadId {
-    let id = CURRENT_THREAD_ID.load(portable_atomic::Ordering::Relaxed);
-    ThreadId::new(id)
+    ThreadId::new(CURRENT_THREAD_ID.get().load(Ordering::Relaxed))
+}
+
+/// Record that `id` now runs on the calling CPU.
+pub(crate) fn set_current_thread_id(id: ThreadId) {
+    CURRENT_THREAD_ID
+        .get()
+        .store(id.as_u64(), Ordering::Relaxed);
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Bytes of stack the calling thread has left before its red zone.
+///
+/// Lets deeply recursive code bail out gracefully instead of overflowing.
+/// Returns `None` if no kernel is registered or the caller isn't running
+/// on a thread stack.
+pub fn stack_remaining() -> Option<usize> {
+    let current = crate::kernel::global_ops()?.current_thread()?;
+    current.inner.stack.as_ref()?.remaining()
+}
+
+/// A thread's identity: its [`ThreadSlab`] slot and the slot's generation.
+///
+/// Displays in a compact form for logs and traces, `T` and the slot
+/// number (one-based, at least four digits) followed by `.` and the
+/// generation once the slot has been reused: `T0042`, `T0042.3`. The same
+/// notation, or the plain numeric id, parses back with [`FromStr`](core::str::FromStr).
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct ThreadId(core::num::NonZeroUsize);
 
 impl core::fmt::Display for ThreadId {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{}", self.0)
+        write!(f, "T{:04}", self.get() & Self::MAX_SLOTS)?;
+        match self.generation() {
+            0 => Ok(()),
+            generation => write!(f, ".{}", generation),
+        }
+    }
+}
+
+impl core::fmt::Debug for ThreadId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "ThreadId({}, generation {})", self, self.generation())
+    }
+}
+
+/// Text that is neither a `T0042`-style thread id nor a non-zero number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseThreadIdError;
+
+impl core::fmt::Display for ParseThreadIdError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("invalid thread id, expected e.g. T0042, T0042.3 or 42")
+    }
+}
+
+impl core::str::FromStr for ThreadId {
+    type Err = ParseThreadIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let id = match s.strip_prefix(['T', 't']) {
+            Some(compact) => {
+                let (slot, generation) = compact.split_once('.').unwrap_or((compact, "0"));
+                let slot: usize = slot.parse().map_err(|_| ParseThreadIdError)?;
+                let generation: usize = generation.parse().map_err(|_| ParseThreadIdError)?;
+                if slot > Self::MAX_SLOTS || generation > usize::MAX >> Self::SLOT_BITS {
+                    return Err(ParseThreadIdError);
+                }
+                generation << Self::SLOT_BITS | slot
+            }
+            None => s.parse().map_err(|_| ParseThreadIdError)?,
+        };
+        core::num::NonZeroUsize::new(id)
+            .map(Self)
+            .ok_or(ParseThreadIdError)
     }
 }
 
@@ -58,6 +139,25 @@ impl ThreadId {
     pub fn as_u64(self) -> u64 {
         self.0.get() as u64
     }
+
+    /// Slots a [`ThreadSlab`] can have.
+    pub const MAX_SLOTS: usize = (1 << Self::SLOT_BITS) - 1;
+    const SLOT_BITS: u32 = 16;
+
+    /// The id of the thread in slab slot `slot` at `generation`.
+    pub(crate) fn from_parts(slot: usize, generation: usize) -> Self {
+        Self::new(((generation << Self::SLOT_BITS) | (slot + 1)) as u64)
+    }
+
+    /// Index of the [`ThreadSlab`] slot the thread occupies.
+    pub fn slot(self) -> usize {
+        (self.get() & Self::MAX_SLOTS).wrapping_sub(1)
+    }
+
+    /// How many earlier threads the slot has held.
+    pub fn generation(self) -> usize {
+        self.get() >> Self::SLOT_BITS
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -67,6 +167,22 @@ pub enum ThreadState {
     Running = 1,
     Blocked = 2,
     Finished = 3,
+    /// Held off the CPU by [`Kernel::suspend`](crate::Kernel::suspend)
+    /// until resumed.
+    Suspended = 4,
+}
+
+impl ThreadState {
+    pub(crate) fn from_u8(value: u8) -> Self {
+        match value {
+            0 => ThreadState::Ready,
+            1 => ThreadState::Running,
+            2 => ThreadState::Blocked,
+            3 => ThreadState::Finished,
+            4 => ThreadState::Suspended,
+            _ => ThreadState::Ready, // Default fallback
+        }
+    }
 }
 
 pub struct Thread {
@@ -80,9 +196,78 @@ pub struct ThreadInner {
     pub stack: Option<Stack>,
     pub context: spin::Mutex<<crate::arch::DefaultArch as Arch>::SavedContext>,
     pub entry_point: Option<fn()>,
-    pub join_result: spin::Mutex<Option<()>>,
-    pub time_slice: TimeSlice,
+    /// What the entry closure returned, until [`JoinHandle::join`] takes
+    /// it; set only when the thread finished normally
+    pub result: spin::Mutex<Option<Box<dyn Any + Send>>>,
+    /// Threads blocked in [`JoinHandle::join`] on this one
+    pub joiners: WaitQueue,
+    /// CPU time consumed, charged each time the thread stops running
+    pub accounting: CpuAccounting,
+    /// Scheduling quantum and the current slice's use of it
+    pub quantum: Quantum,
+    /// Curves `accounting` and `quantum` follow, always a `&'static`
+    pub slice_curves: AtomicPtr<SliceCurves>,
     pub name: spin::Mutex<Option<String>>,
+    /// When `state` last changed, in nanoseconds
+    pub state_since: AtomicU64,
+    /// Thread holding the lock this thread is waiting for, 0 if none
+    pub waiting_on: AtomicUsize,
+    /// Lowest stack pointer sampled at ticks and switches
+    pub lowest_sp: AtomicUsize,
+    /// Promised never to use the FPU; see [`ThreadBuilder::no_fpu`]
+    pub no_fpu: AtomicBool,
+    /// FPCR bits of the thread's [`FpConfig`], loaded into its context
+    pub fpcr: AtomicU32,
+    /// Suspended, or to be suspended instead of becoming ready; see
+    /// [`Kernel::suspend`](crate::Kernel::suspend)
+    pub suspended: AtomicBool,
+    /// Bump arena for transient allocations; see [`mem::arena`](crate::mem::arena)
+    pub arena: spin::Mutex<Option<ThreadArena>>,
+    /// CPU exceptions taken while this thread ran
+    pub exceptions: ExceptionCounters,
+    /// Lifecycle timestamps in nanoseconds, `NOT_YET` until they happen
+    pub created_at: AtomicU64,
+    pub first_run_at: AtomicU64,
+    pub exited_at: AtomicU64,
+    /// CPU the thread was last dispatched on, `NO_CPU` before its first run
+    pub last_cpu: AtomicUsize,
+}
+
+/// Lifecycle timestamp that hasn't happened yet
+const NOT_YET: u64 = u64::MAX;
+
+/// `last_cpu` of a thread that hasn't run
+const NO_CPU: usize = usize::MAX;
+
+/// A thread wrote into the red zone at the low end of its stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackOverflow {
+    /// The offending thread.
+    pub thread: ThreadId,
+    /// Lowest address of the stack, where the red zone starts.
+    pub stack_top: usize,
+    /// Deepest red zone address that was written.
+    pub breach: usize,
+    /// Lowest stack pointer sampled before the overflow was caught.
+    pub deepest_sp: Option<usize>,
+}
+
+impl core::fmt::Display for StackOverflow {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "thread {} overflowed its stack: red zone {:#x}..{:#x} written down to {:#x}",
+            self.thread,
+            self.stack_top,
+            self.stack_top + RED_ZONE_SIZE,
+            self.breach,
+        )?;
+        match self.deepest_sp {
+            Some(sp) => write!(f, ", deepest sampled SP {:#x}", sp)?,
+            None => write!(f, ", SP never sampled")?,
+        }
+        write!(f, "; spawn it with a larger stack class")
+    }
 }
 
 impl Thread {
@@ -98,12 +283,8 @@ impl Thread {
     /// # Returns
     ///
     /// A new Thread instance and corresponding JoinHandle.
-    pub fn new(
-        id: ThreadId,
-        stack: Stack,
-        entry_point: fn(),
-        priority: u8,
-    ) -> (Self, JoinHandle) {
+    #[allow(clippy::unit_arg)] // SavedContext is `()` on host builds
+    pub fn new(id: ThreadId, stack: Stack, entry_point: fn(), priority: u8) -> (Self, JoinHandle) {
         let inner = ThreadInner {
             id,
             state: AtomicU8::new(ThreadState::Ready as u8),
@@ -111,14 +292,37 @@ impl Thread {
             stack: Some(stack),
             context: spin::Mutex::new(Default::default()),
             entry_point: Some(entry_point),
-            join_result: spin::Mutex::new(None),
-            time_slice: TimeSlice::new(priority),
+            result: spin::Mutex::new(None),
+            joiners: WaitQueue::new(),
+            accounting: CpuAccounting::new(priority),
+            quantum: Quantum::new(priority),
+            slice_curves: AtomicPtr::new(
+                &SliceCurves::DEFAULT as *const SliceCurves as *mut SliceCurves,
+            ),
             name: spin::Mutex::new(None),
+            state_since: AtomicU64::new(Instant::now().as_nanos()),
+            waiting_on: AtomicUsize::new(0),
+            lowest_sp: AtomicUsize::new(usize::MAX),
+            no_fpu: AtomicBool::new(false),
+            fpcr: AtomicU32::new(0),
+            suspended: AtomicBool::new(false),
+            arena: spin::Mutex::new(None),
+            exceptions: ExceptionCounters::new(),
+            created_at: AtomicU64::new(Instant::now().as_nanos()),
+            first_run_at: AtomicU64::new(NOT_YET),
+            exited_at: AtomicU64::new(NOT_YET),
+            last_cpu: AtomicUsize::new(NO_CPU),
         };
 
+        if let Some(stack) = inner.stack.as_ref() {
+            stack.install_red_zone();
+        }
+
         let inner_arc = ArcLite::new(inner);
 
-        let thread = Self { inner: inner_arc.clone() };
+        let thread = Self {
+            inner: inner_arc.clone(),
+        };
 
         if let Some(stack_bottom) = thread.stack_bottom() {
             let entry = entry_point as usize;
@@ -127,9 +331,9 @@ impl Thread {
             thread.setup_initial_context(entry, stack_top, 0);
         }
 
-
         let join_handle = JoinHandle {
             inner: inner_arc,
+            result: core::marker::PhantomData,
         };
 
         (thread, join_handle)
@@ -142,14 +346,11 @@ impl Thread {
 
     /// Get the thread's current state.
     pub fn state(&self) -> ThreadState {
-        let state_val = self.inner.state.load(Ordering::Acquire);
-        match state_val {
-            0 => ThreadState::Ready,
-            1 => ThreadState::Running,
-            2 => ThreadState::Blocked,
-            3 => ThreadState::Finished,
-            _ => ThreadState::Ready, // Default fallback
-        }
+        ThreadState::from_u8(
+            self.inner
+                .state
+                .load(ordering::acquire(Edge::ThreadState, Ordering::Acquire)),
+        )
     }
 
     /// Set the thread's state.
@@ -157,8 +358,101 @@ impl Thread {
     /// # Arguments
     ///
     /// * `new_state` - The new state to set
-    pub fn set_state(&self, new_state: ThreadState) {
-        self.inner.state.store(new_state as u8, Ordering::Release);
+    pub(crate) fn set_state(&self, new_state: ThreadState) {
+        let now = Instant::now();
+        self.inner
+            .state_since
+            .store(now.as_nanos(), Ordering::Relaxed);
+        let old = self.inner.state.swap(
+            new_state as u8,
+            ordering::release(Edge::ThreadState, Ordering::AcqRel),
+        );
+        if old == ThreadState::Running as u8 && new_state != ThreadState::Running {
+            self.inner.accounting.charge(now);
+        }
+    }
+
+    /// Atomically change the thread's state from `current` to `new`.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the thread was in `current` and has been moved to `new`.
+    pub(crate) fn compare_and_set_state(&self, current: ThreadState, new: ThreadState) -> bool {
+        let changed = self
+            .inner
+            .state
+            .compare_exchange(
+                current as u8,
+                new as u8,
+                ordering::release(Edge::ThreadState, Ordering::AcqRel),
+                ordering::acquire(Edge::ThreadState, Ordering::Acquire),
+            )
+            .is_ok();
+        if changed {
+            self.inner
+                .state_since
+                .store(Instant::now().as_nanos(), Ordering::Relaxed);
+        }
+        changed
+    }
+
+    /// Whether the thread may use the FPU, so its FPU registers have to be
+    /// switched with it.
+    pub fn uses_fpu(&self) -> bool {
+        !self.inner.no_fpu.load(Ordering::Relaxed)
+    }
+
+    /// Mark the thread as never using the FPU; see
+    /// [`ThreadBuilder::no_fpu`]. Only takes effect from its next switch in.
+    pub fn set_no_fpu(&self, no_fpu: bool) {
+        self.inner.no_fpu.store(no_fpu, Ordering::Relaxed);
+    }
+
+    /// The floating-point settings the thread was given; see
+    /// [`ThreadBuilder::fp_config`].
+    pub fn fp_config(&self) -> FpConfig {
+        FpConfig::from_fpcr(self.inner.fpcr.load(Ordering::Relaxed))
+    }
+
+    /// Give the thread the floating-point settings `config`. Must be set
+    /// before it first runs: later, its saved FPCR is whatever it last
+    /// ran with.
+    pub fn set_fp_config(&self, config: FpConfig) {
+        self.inner.fpcr.store(config.to_fpcr(), Ordering::Relaxed);
+        #[cfg(all(target_arch = "aarch64", feature = "full-fpu"))]
+        {
+            self.inner.context.lock().fpcr = config.to_fpcr();
+        }
+    }
+
+    /// Whether the thread is suspended or will be as soon as it would
+    /// otherwise become ready.
+    pub fn suspend_requested(&self) -> bool {
+        self.inner.suspended.load(Ordering::Acquire)
+    }
+
+    /// Set or clear the suspend request, returning the previous value.
+    pub(crate) fn request_suspend(&self, suspend: bool) -> bool {
+        self.inner.suspended.swap(suspend, Ordering::AcqRel)
+    }
+
+    /// When the thread entered its current state.
+    pub fn state_since(&self) -> Instant {
+        Instant::from_nanos(self.inner.state_since.load(Ordering::Relaxed))
+    }
+
+    /// The thread holding the lock this thread is blocked on, if known.
+    pub fn waiting_on(&self) -> Option<ThreadId> {
+        match self.inner.waiting_on.load(Ordering::Relaxed) {
+            0 => None,
+            id => Some(ThreadId::new(id as u64)),
+        }
+    }
+
+    /// Record which thread holds the lock this thread is about to wait for.
+    pub(crate) fn set_waiting_on(&self, owner: Option<ThreadId>) {
+        let id = owner.map_or(0, ThreadId::get);
+        self.inner.waiting_on.store(id, Ordering::Relaxed);
     }
 
     /// Get the thread's priority.
@@ -173,7 +467,28 @@ impl Thread {
     /// * `new_priority` - The new priority (0-255, higher = more important)
     pub fn set_priority(&self, new_priority: u8) {
         self.inner.priority.store(new_priority, Ordering::Release);
-        self.inner.time_slice.set_priority(new_priority);
+        self.apply_slice_curves(self.slice_curves(), new_priority);
+    }
+
+    /// Curves this thread's quantum and vruntime weight follow, those of
+    /// the scheduler that ran it last.
+    pub fn slice_curves(&self) -> &'static SliceCurves {
+        // Only ever set from a `&'static SliceCurves`
+        unsafe { &*self.inner.slice_curves.load(Ordering::Acquire) }
+    }
+
+    /// Follow `curves` from now on, applying them to the current priority.
+    pub(crate) fn set_slice_curves(&self, curves: &'static SliceCurves) {
+        self.inner.slice_curves.store(
+            curves as *const SliceCurves as *mut SliceCurves,
+            Ordering::Release,
+        );
+        self.apply_slice_curves(curves, self.priority());
+    }
+
+    fn apply_slice_curves(&self, curves: &SliceCurves, priority: u8) {
+        self.inner.quantum.set_length((curves.quantum)(priority));
+        self.inner.accounting.set_weight((curves.weight)(priority));
     }
 
     /// Check if this thread is runnable (ready or running).
@@ -235,7 +550,7 @@ impl Thread {
             #[cfg(feature = "full-fpu")]
             {
                 ctx_guard.neon_state = [0; 32];
-                ctx_guard.fpcr = 0;
+                ctx_guard.fpcr = self.inner.fpcr.load(Ordering::Relaxed);
                 ctx_guard.fpsr = 0;
             }
         }
@@ -248,45 +563,116 @@ impl Thread {
         }
     }
 
+    /// Address range of the thread's stack, lowest address first.
+    pub fn stack_bounds(&self) -> Option<core::ops::Range<usize>> {
+        self.inner.stack.as_ref().map(Stack::bounds)
+    }
+
     /// Get the thread's stack bottom (initial stack pointer).
     pub fn stack_bottom(&self) -> Option<*mut u8> {
         self.inner.stack.as_ref().map(|stack| stack.stack_bottom())
     }
 
-    /// Check if the thread's stack canary is intact (stack overflow detection).
+    /// Check if the thread's stack red zone is intact (stack overflow detection).
     pub fn check_stack_integrity(&self) -> bool {
-        if let Some(ref stack) = self.inner.stack {
-            // Use a fixed canary value for now
-            let canary = 0xDEADBEEFCAFEBABE;
-            stack.check_canary(canary)
-        } else {
-            false
+        self.inner.stack.is_some() && self.check_stack().is_ok()
+    }
+
+    /// Check the red zone at the low end of the thread's stack.
+    ///
+    /// Threads without a stack of their own always pass.
+    pub fn check_stack(&self) -> Result<(), StackOverflow> {
+        let Some(stack) = self.inner.stack.as_ref() else {
+            return Ok(());
+        };
+        match stack.red_zone_breach() {
+            None => Ok(()),
+            Some(breach) => Err(StackOverflow {
+                thread: self.id(),
+                stack_top: stack.stack_top() as usize,
+                breach,
+                deepest_sp: self.deepest_sp(),
+            }),
         }
     }
 
+    /// Check the thread's saved registers before switching to it, see
+    /// [`context_check`](crate::arch::context_check).
+    pub fn check_context(&self) -> Result<(), CorruptContext> {
+        #[cfg(target_arch = "aarch64")]
+        {
+            let stack = self
+                .inner
+                .stack
+                .as_ref()
+                .map(|stack| stack.stack_top() as usize..stack.stack_bottom() as usize);
+            let ctx = self.inner.context.lock();
+            crate::arch::context_check::check(
+                self.id(),
+                ctx.pc,
+                ctx.sp,
+                ctx.pstate,
+                crate::arch::context_check::kernel_text(),
+                stack,
+            )
+        }
+
+        // The host's contexts hold no registers
+        #[cfg(not(target_arch = "aarch64"))]
+        Ok(())
+    }
+
+    /// Record a sample of the thread's stack pointer.
+    pub(crate) fn record_sp(&self, sp: usize) {
+        self.inner.lowest_sp.fetch_min(sp, Ordering::Relaxed);
+    }
+
+    /// The lowest stack pointer sampled so far, if any.
+    pub fn deepest_sp(&self) -> Option<usize> {
+        let sp = self.inner.lowest_sp.load(Ordering::Relaxed);
+        (sp != usize::MAX).then_some(sp)
+    }
+
     /// Start a new time slice for this thread.
     ///
     /// This should be called when the thread is scheduled to run.
     pub fn start_time_slice(&self) {
         let current_time = Instant::now();
-        self.inner.time_slice.start_slice(current_time);
+        self.inner.quantum.start_slice(current_time);
+        self.inner.accounting.start(current_time);
     }
 
-    /// Update the thread's virtual runtime and check if preemption is needed.
+    /// Check if the thread's quantum has run out.
     ///
     /// # Returns
     ///
     /// `true` if the thread's time slice has expired and it should be preempted.
     pub fn should_preempt(&self) -> bool {
-        let current_time = Instant::now();
-        self.inner.time_slice.update_vruntime(current_time)
+        self.inner.quantum.expired()
     }
 
     /// Get the thread's current virtual runtime.
     ///
-    /// This is used by the scheduler for fair scheduling decisions.
+    /// This is used by the scheduler for fair scheduling decisions. It
+    /// advances each time the thread stops running, not during a run.
     pub fn vruntime(&self) -> u64 {
-        self.inner.time_slice.vruntime()
+        self.inner.accounting.vruntime()
+    }
+
+    /// CPU time the thread has consumed, up to when it last stopped
+    /// running.
+    pub fn cpu_time(&self) -> Duration {
+        self.inner.accounting.consumed()
+    }
+
+    /// Time left of the thread's current slice.
+    pub fn quantum_remaining(&self) -> Duration {
+        self.inner.quantum.remaining(Instant::now())
+    }
+
+    /// Time the thread's current slice has run so far.
+    pub fn quantum_used(&self) -> Duration {
+        self.inner.quantum.used(Instant::now())
     }
 
     /// Set the thread name for debugging purposes.
@@ -296,6 +682,69 @@ impl Thread {
         }
     }
 
+    /// The thread's arena slot, see [`mem::arena`](crate::mem::arena).
+    pub(crate) fn arena(&self) -> &spin::Mutex<Option<ThreadArena>> {
+        &self.inner.arena
+    }
+
+    /// When the thread was created.
+    pub fn created_at(&self) -> Instant {
+        self.inner.created_at()
+    }
+
+    /// When the thread was first dispatched, `None` if it hasn't run yet.
+    pub fn first_run_at(&self) -> Option<Instant> {
+        self.inner.first_run_at()
+    }
+
+    /// The CPU the thread was last dispatched on, `None` if it hasn't run
+    /// yet.
+    pub fn last_cpu(&self) -> Option<usize> {
+        match self.inner.last_cpu.load(Ordering::Relaxed) {
+            NO_CPU => None,
+            cpu => Some(cpu),
+        }
+    }
+
+    /// When the thread finished or was killed, `None` while it lives.
+    pub fn exited_at(&self) -> Option<Instant> {
+        self.inner.exited_at()
+    }
+
+    /// Time from creation to first dispatch, `None` if it hasn't run yet.
+    pub fn spawn_latency(&self) -> Option<Duration> {
+        Some(
+            self.first_run_at()?
+                .saturating_duration_since(self.created_at()),
+        )
+    }
+
+    pub(crate) fn mark_exited(&self) {
+        self.inner
+            .exited_at
+            .store(Instant::now().as_nanos(), Ordering::Relaxed);
+    }
+
+    /// Keep `value` for [`JoinHandle::join`], replacing any earlier one.
+    pub(crate) fn set_result(&self, value: Box<dyn Any + Send>) {
+        *self.inner.result.lock() = Some(value);
+    }
+
+    /// Wake the threads joining this one, once it is `Finished`.
+    pub(crate) fn wake_joiners(&self) {
+        self.inner.joiners.notify_all();
+    }
+
+    /// CPU exceptions taken while this thread was running.
+    pub fn exception_stats(&self) -> ExceptionStats {
+        self.inner.exceptions.snapshot()
+    }
+
+    #[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+    pub(crate) fn record_exception(&self, kind: ExceptionKind) {
+        self.inner.exceptions.record(kind);
+    }
+
     /// Get the thread name.
     pub fn name(&self) -> Option<String> {
         self.inner.name.try_lock().and_then(|name| name.clone())
@@ -316,26 +765,66 @@ unsafe impl Sync for Thread {}
 unsafe impl Send for ThreadInner {}
 unsafe impl Sync for ThreadInner {}
 
+impl ThreadInner {
+    pub(super) fn created_at(&self) -> Instant {
+        Instant::from_nanos(self.created_at.load(Ordering::Relaxed))
+    }
+
+    pub(super) fn first_run_at(&self) -> Option<Instant> {
+        match self.first_run_at.load(Ordering::Relaxed) {
+            NOT_YET => None,
+            nanos => Some(Instant::from_nanos(nanos)),
+        }
+    }
+
+    pub(super) fn exited_at(&self) -> Option<Instant> {
+        match self.exited_at.load(Ordering::Relaxed) {
+            NOT_YET => None,
+            nanos => Some(Instant::from_nanos(nanos)),
+        }
+    }
+}
+
 /// A reference to a thread that is currently ready to run.
 ///
 /// This type represents a thread that is in the scheduler's ready queue
 /// and can be selected to run on a CPU.
-#[derive(Clone)]
-pub struct ReadyRef(pub Thread);
+///
+/// Only the kernel creates these, one per ready thread, and they can't be
+/// cloned: a [`Scheduler`](crate::sched::Scheduler) holding one owns the
+/// right to have that thread dispatched, and hands it back exactly once
+/// from [`pick_next`](crate::sched::Scheduler::pick_next). Schedulers
+/// outside the crate see the thread through [`thread`](Self::thread) and
+/// the accessors here; [`conformance`](crate::sched::conformance) mints
+/// handles for testing them.
+pub struct ReadyRef(pub(crate) Thread);
 
 /// A reference to a thread that is currently running on a CPU.
 ///
 /// This type represents a thread that is actively executing on a CPU.
-#[derive(Clone)]
-pub struct RunningRef(pub Thread);
+/// Like [`ReadyRef`] it is created by the kernel only.
+pub struct RunningRef(pub(crate) Thread);
 
 impl ReadyRef {
     /// Convert this ready reference to a running reference.
     ///
     /// This should be called when the scheduler selects this thread to run.
-    pub fn start_running(self) -> RunningRef {
+    pub(crate) fn start_running(self) -> RunningRef {
+        // Every context switch passes through here
+        crate::mem::reclaim::quiescent_state();
         self.0.set_state(ThreadState::Running);
         self.0.start_time_slice();
+        let inner = &self.0.inner;
+        inner
+            .last_cpu
+            .store(crate::arch::cpu_id(), Ordering::Relaxed);
+        if inner.first_run_at.load(Ordering::Relaxed) == NOT_YET {
+            let now = Instant::now();
+            inner.first_run_at.store(now.as_nanos(), Ordering::Relaxed);
+            crate::kernel::metrics::record_spawn_latency(
+                now.saturating_duration_since(inner.created_at()),
+            );
+        }
         RunningRef(self.0)
     }
 
@@ -348,6 +837,12 @@ impl ReadyRef {
     pub fn id(&self) -> ThreadId {
         self.0.id()
     }
+
+    /// The thread, for reading scheduling inputs such as its
+    /// [`vruntime`](Thread::vruntime) or [`state_since`](Thread::state_since).
+    pub fn thread(&self) -> &Thread {
+        &self.0
+    }
 }
 
 impl RunningRef {
@@ -377,19 +872,33 @@ impl RunningRef {
     /// Mark this thread as finished.
     ///
     /// This should be called when the thread's entry point returns.
-    pub fn finish(self) {
+    pub(crate) fn finish(self) {
+        self.0.mark_exited();
+        // Threads started from a plain `fn()` return nothing to store
+        self.0
+            .inner
+            .result
+            .lock()
+            .get_or_insert_with(|| Box::new(()));
         self.0.set_state(ThreadState::Finished);
+        self.0.wake_joiners();
+    }
 
-        // Signal any joiners that we're done
-        if let Some(mut join_result) = self.0.inner.join_result.try_lock() {
-            *join_result = Some(());
-        }
+    /// Mark this thread as finished without a result.
+    ///
+    /// Used when the kernel terminates the thread, e.g. after a stack
+    /// overflow; joiners see it as having failed.
+    pub(crate) fn kill(self) {
+        self.0.mark_exited();
+        self.0.inner.result.lock().take();
+        self.0.set_state(ThreadState::Finished);
+        self.0.wake_joiners();
     }
 
     /// Prepare this thread for preemption.
     ///
     /// This saves the current state and returns a ReadyRef that can be re-enqueued.
-    pub fn prepare_preemption(&self) -> ReadyRef {
+    pub(crate) fn prepare_preemption(&self) -> ReadyRef {
         let ready = ReadyRef(self.0.clone());
         ready.0.set_state(ThreadState::Ready);
         ready
@@ -405,17 +914,19 @@ impl RunningRef {
         self.0.id()
     }
 
-    /// Get the CPU this thread last ran on.
-    ///
-    /// For now, return 0 as a placeholder. In a real implementation,
-    /// this would track the actual CPU assignment.
+    /// The CPU this thread is running on.
     pub fn last_cpu(&self) -> usize {
-        0 // TODO: Track actual CPU assignment
+        self.0.last_cpu().unwrap_or(0)
     }
 
-    /// Get access to the thread's time slice for scheduler decisions.
-    pub fn time_slice(&self) -> &TimeSlice {
-        &self.0.inner.time_slice
+    /// Get access to the thread's quantum for scheduler decisions.
+    pub fn quantum(&self) -> &Quantum {
+        &self.0.inner.quantum
+    }
+
+    /// The thread, for reading scheduling inputs.
+    pub fn thread(&self) -> &Thread {
+        &self.0
     }
 }
 
@@ -435,7 +946,9 @@ mod tests {
         let (thread, _join_handle) = Thread::new(
             thread_id,
             stack,
-            || { println!("Hello from thread!"); },
+            || {
+                println!("Hello from thread!");
+            },
             128,
         );
 
@@ -452,12 +965,7 @@ mod tests {
         let stack = pool.allocate(StackSizeClass::Small).unwrap();
         let thread_id = unsafe { ThreadId::new_unchecked(1) };
 
-        let (thread, _join_handle) = Thread::new(
-            thread_id,
-            stack,
-            || {},
-            128,
-        );
+        let (thread, _join_handle) = Thread::new(thread_id, stack, || {}, 128);
 
         // Test state transitions
         assert_eq!(thread.state(), ThreadState::Ready);
@@ -473,4 +981,95 @@ mod tests {
         assert_eq!(thread.state(), ThreadState::Finished);
         assert!(!thread.is_runnable());
     }
+
+    #[test]
+    fn test_thread_id_compact_form_roundtrip() {
+        use alloc::format;
+
+        let fresh = ThreadId::from_parts(41, 0);
+        let reused = ThreadId::from_parts(41, 3);
+        assert_eq!(format!("{}", fresh), "T0042");
+        assert_eq!(format!("{}", reused), "T0042.3");
+        assert_eq!(format!("{:?}", reused), "ThreadId(T0042.3, generation 3)");
+
+        assert_eq!("T0042".parse(), Ok(fresh));
+        assert_eq!("t42.3".parse(), Ok(reused));
+        assert_eq!(format!("{}", reused.get()).parse(), Ok(reused));
+        for bad in ["", "T", "T0", "0", "T65536", "T0042.", "T-1", "thread"] {
+            assert_eq!(
+                bad.parse::<ThreadId>(),
+                Err(ParseThreadIdError),
+                "{:?}",
+                bad
+            );
+        }
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_stack_overflow_report() {
+        let pool = StackPool::new();
+        let stack = pool.allocate(StackSizeClass::Small).unwrap();
+        let (thread, join_handle) =
+            Thread::new(unsafe { ThreadId::new_unchecked(1) }, stack, || {}, 128);
+        assert!(thread.check_stack_integrity());
+        assert_eq!(thread.deepest_sp(), None);
+
+        let top = thread.inner.stack.as_ref().unwrap().stack_top() as usize;
+        thread.record_sp(top + 512);
+        thread.record_sp(top + 1024);
+        unsafe { ((top + 128) as *mut u64).write(0) };
+
+        let overflow = thread.check_stack().unwrap_err();
+        assert_eq!(overflow.breach, top + 128);
+        assert_eq!(overflow.deepest_sp, Some(top + 512));
+
+        RunningRef(thread).kill();
+        assert!(join_handle.try_join().is_some_and(|result| result.is_err()));
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_slice_curves_follow_priority() {
+        static STEEP: SliceCurves = SliceCurves {
+            quantum: |priority| Duration::from_micros(priority as u64 * 10),
+            weight: |priority| priority as u32 * 4,
+        };
+        let pool = StackPool::new();
+        let stack = pool.allocate(StackSizeClass::Small).unwrap();
+        let (thread, _handle) =
+            Thread::new(unsafe { ThreadId::new_unchecked(4) }, stack, || {}, 100);
+        assert_eq!(thread.inner.quantum.length(), Duration::from_millis(1));
+
+        thread.set_slice_curves(&STEEP);
+        assert_eq!(thread.inner.quantum.length(), Duration::from_micros(1000));
+        assert_eq!(thread.inner.accounting.weight(), 400);
+        thread.set_priority(50);
+        assert_eq!(thread.inner.quantum.length(), Duration::from_micros(500));
+        assert_eq!(thread.inner.accounting.weight(), 200);
+    }
+
+    #[cfg(feature = "std-shim")]
+    #[test]
+    fn test_lifecycle_timestamps() {
+        let pool = StackPool::new();
+        let stack = pool.allocate(StackSizeClass::Small).unwrap();
+        let (thread, handle) = Thread::new(unsafe { ThreadId::new_unchecked(3) }, stack, || {}, 64);
+        assert_eq!((handle.first_run_at(), handle.exited_at()), (None, None));
+        assert_eq!(thread.spawn_latency(), None);
+        let before = crate::kernel::metrics::spawn_latency_stats().spawns;
+
+        let running = ReadyRef(thread.clone()).start_running();
+        let first = handle.first_run_at().unwrap();
+        assert!(first >= handle.created_at());
+        assert!(thread.spawn_latency().is_some());
+        assert!(crate::kernel::metrics::spawn_latency_stats().spawns > before);
+
+        // Only the first dispatch is recorded
+        let running = running.stop_running().start_running();
+        assert_eq!(handle.first_run_at(), Some(first));
+
+        running.finish();
+        assert!(handle.exited_at().is_some_and(|exited| exited >= first));
+    }
 }
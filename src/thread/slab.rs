@@ -0,0 +1,235 @@
+//! Fixed-capacity table of live threads, indexed by [`ThreadId`].
+//!
+//! Every thread the kernel spawns gets a slot here; its id encodes the slot
+//! index and the slot's generation (see [`ThreadId::slot`]). Looking a
+//! thread up is a single array access, so interrupt handlers can go from an
+//! id to the thread without scanning anything, and an id kept past its
+//! thread's exit is recognised as stale because the slot's generation has
+//! moved on.
+//!
+//! Neither lookups nor updates take locks:
+//!
+//! - free slots form a Treiber stack whose head carries an ABA tag;
+//! - each slot has one state word holding its generation, an occupied bit
+//!   and a count of lookups in progress. A lookup registers itself in that
+//!   word before touching the thread, and [`remove`](ThreadSlab::remove)
+//!   only takes a thread out once no lookup is looking at it.
+
+use super::{Thread, ThreadId};
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use portable_atomic::{AtomicU64, Ordering};
+
+/// Bits of a slot's state word counting lookups in progress.
+const READER_BITS: u32 = 16;
+const READER_MASK: u64 = (1 << READER_BITS) - 1;
+const OCCUPIED: u64 = 1 << READER_BITS;
+const GENERATION_SHIFT: u32 = READER_BITS + 1;
+
+/// End-of-list marker in the free list.
+const NIL: u64 = u32::MAX as u64;
+
+struct Slot {
+    /// generation << GENERATION_SHIFT | occupied | readers
+    state: AtomicU64,
+    /// Next free slot while this one is free
+    next_free: AtomicU64,
+    thread: UnsafeCell<Option<Thread>>,
+}
+
+/// Lock-free map from [`ThreadId`] to [`Thread`] with O(1) operations.
+pub struct ThreadSlab {
+    slots: Box<[Slot]>,
+    /// tag << 32 | index of the first free slot
+    free_head: AtomicU64,
+}
+
+// The thread cells are only written by the owner of a vacant slot and only
+// read under a registered lookup, see `get` and `remove`
+unsafe impl Send for ThreadSlab {}
+unsafe impl Sync for ThreadSlab {}
+
+impl ThreadSlab {
+    /// Bytes of table each thread the slab has room for takes.
+    pub(crate) const SLOT_SIZE: usize = core::mem::size_of::<Slot>();
+
+    /// A slab with room for `capacity` threads.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` exceeds [`ThreadId::MAX_SLOTS`].
+    pub fn new(capacity: usize) -> Self {
+        assert!(
+            capacity <= ThreadId::MAX_SLOTS,
+            "thread slab capacity {} too large",
+            capacity
+        );
+        let slots = (0..capacity)
+            .map(|index| Slot {
+                state: AtomicU64::new(0),
+                next_free: AtomicU64::new(if index + 1 < capacity {
+                    index as u64 + 1
+                } else {
+                    NIL
+                }),
+                thread: UnsafeCell::new(None),
+            })
+            .collect();
+        Self {
+            slots,
+            free_head: AtomicU64::new(if capacity > 0 { 0 } else { NIL }),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Claim a free slot and return the id a thread in it will have.
+    ///
+    /// The slot stays empty, and the id unresolvable, until
+    /// [`insert`](Self::insert) fills it. Returns `None` if the slab is full.
+    pub fn allocate(&self) -> Option<ThreadId> {
+        let mut head = self.free_head.load(Ordering::Acquire);
+        loop {
+            let index = head & u32::MAX as u64;
+            if index == NIL {
+                return None;
+            }
+            let next = self.slots[index as usize].next_free.load(Ordering::Relaxed);
+            let tag = (head >> 32).wrapping_add(1);
+            match self.free_head.compare_exchange_weak(
+                head,
+                tag << 32 | next,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    let generation = self.slots[index as usize].state.load(Ordering::Acquire)
+                        >> GENERATION_SHIFT;
+                    return Some(ThreadId::from_parts(index as usize, generation as usize));
+                }
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    /// Put `thread` into the slot its id was [allocated](Self::allocate) for.
+    pub fn insert(&self, thread: Thread) {
+        let id = thread.id();
+        let slot = &self.slots[id.slot()];
+        debug_assert_eq!(
+            slot.state.load(Ordering::Relaxed) >> GENERATION_SHIFT,
+            id.generation() as u64
+        );
+        // Vacant and claimed by the caller, so no one else touches the cell
+        unsafe { *slot.thread.get() = Some(thread) };
+        slot.state.fetch_or(OCCUPIED, Ordering::Release);
+    }
+
+    /// The thread with `id`, or `None` if it has exited (or never existed).
+    pub fn get(&self, id: ThreadId) -> Option<Thread> {
+        let slot = self.slots.get(id.slot())?;
+        let seen = slot.state.fetch_add(1, Ordering::Acquire);
+        let live = seen & OCCUPIED != 0 && seen >> GENERATION_SHIFT == id.generation() as u64;
+        // Registered as a reader, so the thread can't be removed meanwhile
+        let thread = if live {
+            unsafe { (*slot.thread.get()).clone() }
+        } else {
+            None
+        };
+        slot.state.fetch_sub(1, Ordering::Release);
+        thread
+    }
+
+    /// Take the thread with `id` out and free its slot, bumping the slot's
+    /// generation so `id` goes stale.
+    pub fn remove(&self, id: ThreadId) -> Option<Thread> {
+        let slot = self.slots.get(id.slot())?;
+        let occupied = (id.generation() as u64) << GENERATION_SHIFT | OCCUPIED;
+        let vacated = (id.generation() as u64 + 1) << GENERATION_SHIFT;
+        loop {
+            match slot.state.compare_exchange_weak(
+                occupied,
+                vacated,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                // Lookups finish in a few instructions
+                Err(state) if state & !READER_MASK == occupied => core::hint::spin_loop(),
+                Err(_) => return None,
+            }
+        }
+
+        // New lookups now see a vacant slot and leave the cell alone
+        let thread = unsafe { (*slot.thread.get()).take() };
+        self.release(id.slot());
+        thread
+    }
+
+    /// Threads currently in the slab.
+    pub fn len(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.state.load(Ordering::Relaxed) & OCCUPIED != 0)
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn release(&self, index: usize) {
+        let mut head = self.free_head.load(Ordering::Acquire);
+        loop {
+            self.slots[index]
+                .next_free
+                .store(head & u32::MAX as u64, Ordering::Relaxed);
+            let tag = (head >> 32).wrapping_add(1);
+            match self.free_head.compare_exchange_weak(
+                head,
+                tag << 32 | index as u64,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return,
+                Err(actual) => head = actual,
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std-shim"))]
+mod tests {
+    use super::*;
+    use crate::mem::{StackPool, StackSizeClass};
+
+    #[test]
+    fn test_stale_ids_rejected_after_reuse() {
+        let pool = StackPool::new();
+        let slab = ThreadSlab::new(2);
+        let spawn = |id: ThreadId| {
+            let stack = pool.allocate(StackSizeClass::Small).unwrap();
+            slab.insert(Thread::new(id, stack, || {}, 128).0);
+        };
+
+        let first = slab.allocate().unwrap();
+        let second = slab.allocate().unwrap();
+        assert_eq!((first.get(), second.get()), (1, 2));
+        assert!(slab.allocate().is_none());
+        assert!(slab.get(first).is_none());
+        spawn(first);
+        spawn(second);
+        assert_eq!(slab.get(first).unwrap().id(), first);
+
+        assert_eq!(slab.remove(first).unwrap().id(), first);
+        assert!(slab.remove(first).is_none());
+        let reused = slab.allocate().unwrap();
+        assert_eq!((reused.slot(), reused.generation()), (first.slot(), 1));
+        spawn(reused);
+        assert!(slab.get(first).is_none());
+        assert_eq!(slab.get(reused).unwrap().id(), reused);
+        assert_eq!(slab.len(), 2);
+    }
+}
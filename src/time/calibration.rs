@@ -0,0 +1,159 @@
+//! Generic timer frequency validation.
+//!
+//! Everything time-related converts counter ticks with the frequency in
+//! `CNTFRQ_EL0`, which the kernel can't set: firmware running at a higher
+//! exception level has to. Some firmware leaves it zero, which made
+//! [`Instant::now`](super::Instant::now) return 0 forever, or wrong, which
+//! stretches every timeout and tick.
+//!
+//! [`Kernel::init_timer`](crate::Kernel::init_timer) therefore cross-checks
+//! it at boot against the BCM2837 system timer, a free-running 1 MHz
+//! counter clocked independently of the CPU:
+//!
+//! - a register that agrees within [`TOLERANCE_PERCENT`] is used as is;
+//! - a zero register is replaced by the measured frequency, with a warning;
+//! - a register that disagrees fails with
+//!   [`KernelError::TimerFrequency`], and the kernel falls back to
+//!   cooperative scheduling.
+//!
+//! A platform that knows better sets
+//! [`KernelConfig::TIMER_FREQ_HZ`](crate::kernel::KernelConfig::TIMER_FREQ_HZ),
+//! which is used without checking. On QEMU `virt` there is no system timer
+//! to compare against, so only a zero register is rejected.
+
+use crate::errors::KernelError;
+use portable_atomic::{AtomicU64, Ordering};
+
+/// How far the register may be from the measured frequency.
+pub const TOLERANCE_PERCENT: u64 = 2;
+
+/// Frequency in use; zero until calibrated, when the register is read
+static FREQUENCY_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Generic timer ticks per second, as calibrated at boot.
+///
+/// Before calibration this is whatever `CNTFRQ_EL0` holds.
+#[inline]
+pub fn counter_frequency() -> u64 {
+    match FREQUENCY_HZ.load(Ordering::Relaxed) {
+        0 => reported(),
+        hz => hz,
+    }
+}
+
+/// Settle on the counter frequency, from `override_hz` if given.
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+pub(crate) fn calibrate(override_hz: Option<u64>) -> Result<u64, KernelError> {
+    let hz = match override_hz {
+        Some(hz) => hz,
+        None => {
+            let reported = reported();
+            let hz = check(reported, measure())?;
+            if hz != reported {
+                crate::klog!(
+                    crate::kernel::log::Level::Warn,
+                    "CNTFRQ_EL0 is zero, using measured {} Hz",
+                    hz
+                );
+            }
+            hz
+        }
+    };
+    FREQUENCY_HZ.store(hz, Ordering::Relaxed);
+    Ok(hz)
+}
+
+/// Decide on a frequency from the register and a measurement, if one
+/// could be taken.
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+fn check(reported: u64, measured: Option<u64>) -> Result<u64, KernelError> {
+    match measured {
+        None if reported == 0 => Err(KernelError::TimerInitFailed("CNTFRQ_EL0 is zero")),
+        None => Ok(reported),
+        Some(measured) if reported == 0 => Ok(measured),
+        Some(measured) if reported.abs_diff(measured) * 100 > measured * TOLERANCE_PERCENT => {
+            Err(KernelError::TimerFrequency { reported, measured })
+        }
+        Some(_) => Ok(reported),
+    }
+}
+
+/// The frequency firmware put in `CNTFRQ_EL0`.
+fn reported() -> u64 {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let freq: u64;
+        unsafe { core::arch::asm!("mrs {}, cntfrq_el0", out(reg) freq, options(nostack, nomem)) };
+        freq
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    0
+}
+
+/// Count generic timer ticks over 10 ms of the 1 MHz system timer.
+///
+/// `None` where there is no system timer, or it isn't running.
+#[cfg(all(target_arch = "aarch64", not(feature = "qemu-virt")))]
+fn measure() -> Option<u64> {
+    use crate::drivers::{mmio_read, PERIPHERAL_BASE};
+
+    const SYSTEM_TIMER_CLO: usize = PERIPHERAL_BASE + 0x3004;
+    const SPAN_US: u32 = 10_000;
+
+    let count = || {
+        let ticks: u64;
+        unsafe {
+            core::arch::asm!("isb", "mrs {}, cntpct_el0", out(reg) ticks, options(nostack, nomem))
+        };
+        ticks
+    };
+
+    // Start on an edge of the slower clock
+    let first = mmio_read(SYSTEM_TIMER_CLO);
+    let mut spins = 0u32;
+    let start_us = loop {
+        let now = mmio_read(SYSTEM_TIMER_CLO);
+        if now != first {
+            break now;
+        }
+        spins += 1;
+        if spins == 1_000_000 {
+            return None;
+        }
+    };
+    let start = count();
+    while mmio_read(SYSTEM_TIMER_CLO).wrapping_sub(start_us) < SPAN_US {}
+    let ticks = count() - start;
+    Some(ticks * (1_000_000 / SPAN_US as u64))
+}
+
+#[cfg(not(all(target_arch = "aarch64", not(feature = "qemu-virt"))))]
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+fn measure() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_frequency() {
+        const HZ: u64 = 19_200_000;
+        assert_eq!(check(HZ, Some(HZ + HZ / 100)), Ok(HZ));
+        assert_eq!(check(0, Some(HZ)), Ok(HZ));
+        assert_eq!(check(HZ, None), Ok(HZ));
+        assert!(matches!(
+            check(0, None),
+            Err(KernelError::TimerInitFailed(_))
+        ));
+        assert_eq!(
+            check(62_500_000, Some(HZ)),
+            Err(KernelError::TimerFrequency {
+                reported: 62_500_000,
+                measured: HZ
+            })
+        );
+    }
+}
@@ -0,0 +1,392 @@
+//! High-resolution one-shot timers.
+//!
+//! Timers run on the EL1 virtual timer, independently of the scheduler tick
+//! on the physical timer: the comparator is programmed for the earliest
+//! pending deadline, so a timer fires at its deadline rather than at the
+//! next tick. Callbacks run in IRQ context and may re-arm themselves, which
+//! is how periodic work (e.g. software PWM) is built on top.
+//!
+//! # Coalescing
+//!
+//! Every timer may fire up to its slack after its deadline. The comparator
+//! is programmed for the earliest deadline plus slack, and that interrupt
+//! runs every timer whose deadline has passed, so timers with nearby
+//! deadlines share one interrupt instead of each taking their own. The
+//! default slack is [`DEFAULT_SLACK`]; change it with [`set_slack`], or
+//! give a single timer its own with [`start_at_with_slack`] (zero for
+//! timers that must fire on time). Sleeping threads get the same treatment
+//! through [`sleep_until_with_slack`](crate::kernel::sleep_until_with_slack)
+//! and [`Timeout::with_slack`](crate::sync::Timeout::with_slack).
+//!
+//! [`stats`] counts the callbacks that rode along on an interrupt taken for
+//! an earlier one, so the effect of a slack setting on the number of
+//! wake-ups can be measured rather than guessed.
+
+use super::{Duration, Instant};
+use crate::arch;
+use crate::errors::{ArchError, ResourceError};
+use portable_atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Timer callback, called in IRQ context with the argument given at start.
+///
+/// Callbacks must not block; they may start or cancel timers.
+pub type HrTimerCallback = fn(usize);
+
+/// Number of timers that can be pending at once.
+pub const MAX_HRTIMERS: usize = 32;
+
+/// EL1 virtual timer interrupt (PPI 27).
+pub const HRTIMER_IRQ: u32 = 27;
+
+/// Slack given to timers started without one.
+pub const DEFAULT_SLACK: Duration = Duration::from_nanos(50_000);
+
+/// Handle to a started timer, used to cancel it.
+///
+/// Handles are generation-tagged, so cancelling a timer that already fired
+/// cannot cancel an unrelated timer that reused its slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HrTimerId {
+    slot: u16,
+    generation: u16,
+}
+
+/// Interrupt and expiry counts, for judging how well timers coalesce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HrTimerStats {
+    /// Timer interrupts taken.
+    pub interrupts: u64,
+    /// Callbacks run.
+    pub fired: u64,
+    /// Callbacks that shared an interrupt with another, i.e. interrupts
+    /// saved by coalescing.
+    pub coalesced: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    deadline: u64,
+    slack: u64,
+    callback: Option<HrTimerCallback>,
+    arg: usize,
+    generation: u16,
+}
+
+impl Slot {
+    const EMPTY: Slot = Slot {
+        deadline: 0,
+        slack: 0,
+        callback: None,
+        arg: 0,
+        generation: 0,
+    };
+}
+
+static TIMERS: spin::Mutex<[Slot; MAX_HRTIMERS]> = spin::Mutex::new([Slot::EMPTY; MAX_HRTIMERS]);
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static SLACK_NS: AtomicU64 = AtomicU64::new(DEFAULT_SLACK.as_nanos());
+static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+static TIMERS_FIRED: AtomicU64 = AtomicU64::new(0);
+static COALESCED: AtomicU64 = AtomicU64::new(0);
+
+/// Register the virtual timer interrupt handler.
+///
+/// Must be called once, after the interrupt controller is initialized.
+pub fn init() -> Result<(), ArchError> {
+    if INITIALIZED.swap(true, Ordering::AcqRel) {
+        return Ok(());
+    }
+    program_comparator(None);
+    crate::irq::register_handler(HRTIMER_IRQ, handle_interrupt)?;
+    crate::irq::enable(HRTIMER_IRQ)
+}
+
+/// Whether [`init`] has registered the interrupt, so timers fire.
+pub(crate) fn initialized() -> bool {
+    INITIALIZED.load(Ordering::Acquire)
+}
+
+/// Set the slack for timers started from now on without their own.
+pub fn set_slack(slack: Duration) {
+    SLACK_NS.store(slack.as_nanos(), Ordering::Relaxed);
+}
+
+/// The slack timers are started with by default.
+pub fn slack() -> Duration {
+    Duration::from_nanos(SLACK_NS.load(Ordering::Relaxed))
+}
+
+/// Start a timer that calls `callback(arg)` at `deadline`, or up to
+/// [`slack`] later if that lets it share an interrupt.
+///
+/// A deadline in the past fires on the next timer interrupt.
+pub fn start_at(
+    deadline: Instant,
+    callback: HrTimerCallback,
+    arg: usize,
+) -> Result<HrTimerId, ResourceError> {
+    start_at_with_slack(deadline, slack(), callback, arg)
+}
+
+/// Like [`start_at`], with `slack` instead of the default.
+pub fn start_at_with_slack(
+    deadline: Instant,
+    slack: Duration,
+    callback: HrTimerCallback,
+    arg: usize,
+) -> Result<HrTimerId, ResourceError> {
+    arch::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let slot = timers
+            .iter()
+            .position(|t| t.callback.is_none())
+            .ok_or(ResourceError::ResourceUnavailable)?;
+
+        let timer = &mut timers[slot];
+        timer.deadline = deadline.as_nanos();
+        timer.slack = slack.as_nanos();
+        timer.callback = Some(callback);
+        timer.arg = arg;
+        let id = HrTimerId {
+            slot: slot as u16,
+            generation: timer.generation,
+        };
+
+        program_comparator(next_expiry(&timers[..]));
+        Ok(id)
+    })
+}
+
+/// Start a timer that calls `callback(arg)` after `delay`.
+pub fn start_after(
+    delay: Duration,
+    callback: HrTimerCallback,
+    arg: usize,
+) -> Result<HrTimerId, ResourceError> {
+    start_at(Instant::now() + delay, callback, arg)
+}
+
+/// Cancel a pending timer.
+///
+/// # Returns
+///
+/// `true` if the timer was pending and will no longer fire.
+pub fn cancel(id: HrTimerId) -> bool {
+    arch::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let timer = &mut timers[id.slot as usize];
+        if timer.callback.is_none() || timer.generation != id.generation {
+            return false;
+        }
+
+        release(timer);
+        program_comparator(next_expiry(&timers[..]));
+        true
+    })
+}
+
+/// Number of pending timers.
+pub fn pending() -> usize {
+    arch::without_interrupts(|| {
+        TIMERS
+            .lock()
+            .iter()
+            .filter(|t| t.callback.is_some())
+            .count()
+    })
+}
+
+fn release(timer: &mut Slot) {
+    timer.callback = None;
+    timer.generation = timer.generation.wrapping_add(1);
+}
+
+/// Interrupt and expiry counts since boot.
+pub fn stats() -> HrTimerStats {
+    HrTimerStats {
+        interrupts: INTERRUPTS.load(Ordering::Relaxed),
+        fired: TIMERS_FIRED.load(Ordering::Relaxed),
+        coalesced: COALESCED.load(Ordering::Relaxed),
+    }
+}
+
+/// When the next interrupt is due: the latest point at which no pending
+/// timer is past its deadline plus slack.
+fn next_expiry(timers: &[Slot]) -> Option<u64> {
+    timers
+        .iter()
+        .filter(|t| t.callback.is_some())
+        .map(|t| t.deadline.saturating_add(t.slack))
+        .min()
+}
+
+/// Run every timer whose deadline is at or before `now`, earliest
+/// deadline first; timers due at the same time run in the order of their
+/// slots.
+///
+/// Expired timers are removed before their callbacks run, so callbacks can
+/// re-arm without running out of slots.
+fn expire(now: Instant) -> usize {
+    let mut expired = [(0u64, 0usize, None::<HrTimerCallback>, 0usize); MAX_HRTIMERS];
+    let mut count = 0;
+
+    {
+        let mut timers = TIMERS.lock();
+        for (slot, timer) in timers.iter_mut().enumerate() {
+            if timer.callback.is_some() && timer.deadline <= now.as_nanos() {
+                expired[count] = (timer.deadline, slot, timer.callback, timer.arg);
+                count += 1;
+                release(timer);
+            }
+        }
+        program_comparator(next_expiry(&timers[..]));
+    }
+    // Slots are reused in any order; in place, as this runs in IRQ context
+    expired[..count].sort_unstable_by_key(|&(deadline, slot, _, _)| (deadline, slot));
+    TIMERS_FIRED.fetch_add(count as u64, Ordering::Relaxed);
+    if count > 1 {
+        COALESCED.fetch_add(count as u64 - 1, Ordering::Relaxed);
+    }
+
+    for (_, _, callback, arg) in expired.iter().take(count) {
+        if let Some(callback) = callback {
+            callback(*arg);
+        }
+    }
+    count
+}
+
+fn handle_interrupt(_irq: u32) {
+    INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    expire(Instant::now());
+}
+
+/// Program the virtual timer comparator, or mask it when nothing is pending.
+fn program_comparator(deadline_ns: Option<u64>) {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        use core::arch::asm;
+
+        match deadline_ns {
+            Some(deadline) => {
+                let delta_ns = deadline.saturating_sub(Instant::now().as_nanos());
+                let freq = super::calibration::counter_frequency();
+                let count: u64;
+                asm!("mrs {}, cntvct_el0", out(reg) count, options(nostack, nomem));
+
+                // Relative to the virtual count so a non-zero CNTVOFF doesn't matter
+                let delta = ((delta_ns as u128 * freq as u128) / 1_000_000_000) as u64;
+                asm!("msr cntv_cval_el0, {}", in(reg) count.wrapping_add(delta), options(nostack));
+                asm!("msr cntv_ctl_el0, {}", in(reg) 1u64, options(nostack)); // ENABLE
+            }
+            None => {
+                asm!("msr cntv_ctl_el0, {}", in(reg) 0b11u64, options(nostack));
+                // ENABLE | IMASK
+            }
+        }
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    let _ = deadline_ns;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Args of the callbacks run, in order
+    static FIRED: spin::Mutex<Vec<usize>> = spin::Mutex::new(Vec::new());
+    /// The timers are global, so tests that expire them take turns
+    static SERIAL: spin::Mutex<()> = spin::Mutex::new(());
+
+    fn record(arg: usize) {
+        FIRED.lock().push(arg);
+    }
+
+    #[test]
+    fn test_expire_in_deadline_order() {
+        let _serial = SERIAL.lock();
+        FIRED.lock().clear();
+        let base = 1_000_000_000;
+        // Started latest first, so their slots are in reverse order
+        let late = start_at(Instant::from_nanos(base + 200), record, 2).unwrap();
+        let cancelled = start_at(Instant::from_nanos(base + 150), record, 100).unwrap();
+        let early = start_at(Instant::from_nanos(base + 100), record, 1).unwrap();
+
+        assert!(cancel(cancelled));
+        assert!(!cancel(cancelled));
+
+        assert_eq!(expire(Instant::from_nanos(base + 150)), 1);
+        assert_eq!(*FIRED.lock(), [1]);
+        assert!(!cancel(early));
+
+        // Takes the cancelled timer's slot, ahead of the later deadline
+        start_at(Instant::from_nanos(base + 190), record, 3).unwrap();
+        assert_eq!(expire(Instant::from_nanos(base + 200)), 2);
+        assert_eq!(*FIRED.lock(), [1, 3, 2]);
+        assert!(!cancel(late));
+    }
+
+    #[test]
+    fn test_overlapping_slack_shares_one_deadline() {
+        let _serial = SERIAL.lock();
+        FIRED.lock().clear();
+        let base = 2_000_000_000;
+        let before = stats().coalesced;
+        start_at_with_slack(
+            Instant::from_nanos(base + 300),
+            Duration::from_nanos(500),
+            record,
+            1,
+        )
+        .unwrap();
+        start_at_with_slack(
+            Instant::from_nanos(base + 400),
+            Duration::from_nanos(100),
+            record,
+            2,
+        )
+        .unwrap();
+
+        // The comparator is programmed once, for the tighter of the two
+        let programmed = next_expiry(&TIMERS.lock()[..]).unwrap();
+        assert_eq!(programmed, base + 500);
+
+        // and that one interrupt runs both
+        assert_eq!(expire(Instant::from_nanos(programmed)), 2);
+        assert_eq!(*FIRED.lock(), [1, 2]);
+        assert_eq!(stats().coalesced - before, 1);
+    }
+
+    #[test]
+    fn test_slack_coalesces_nearby_deadlines() {
+        let timer = |deadline, slack| Slot {
+            deadline,
+            slack,
+            callback: Some(record as HrTimerCallback),
+            ..Slot::EMPTY
+        };
+        let mut timers = [
+            timer(100, 100),
+            timer(150, 100),
+            timer(400, 100),
+            timer(180, 0),
+        ];
+
+        // The on-time timer forces the interrupt before the slack runs out
+        assert_eq!(next_expiry(&timers), Some(180));
+        timers[3].callback = None;
+
+        // One interrupt at 200 serves the timers due at 100 and 150
+        let at = next_expiry(&timers).unwrap();
+        assert_eq!(at, 200);
+        assert_eq!(
+            timers
+                .iter()
+                .filter(|t| t.callback.is_some() && t.deadline <= at)
+                .count(),
+            2
+        );
+    }
+}
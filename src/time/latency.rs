@@ -0,0 +1,279 @@
+//! Latency tracing for interrupt-disabled and preemption-disabled sections.
+//!
+//! With the `latency-trace` feature, every transition into and out of an
+//! interrupts-off section (through [`Arch`](crate::arch::Arch) or
+//! [`without_interrupts`](crate::arch::without_interrupts)) and every
+//! outermost [`preempt_disable`](crate::platform_timer::preempt_disable) /
+//! [`preempt_enable`](crate::platform_timer::preempt_enable) pair is
+//! timestamped. The longest section of each kind is kept together with the
+//! source location that opened it, so the worst offender can be found in
+//! application code or in the kernel itself.
+//!
+//! Without the feature the hooks compile to nothing and [`report`] stays
+//! empty.
+//!
+//! Interrupt sections entered by the hardware (exception handlers) are not
+//! traced; only sections opened by software count.
+//!
+//! # Example
+//!
+//! ```ignore
+//! use preemptive_threads::time::latency;
+//!
+//! let report = latency::report();
+//! if let Some(worst) = report.irq_disabled {
+//!     pl011_println!("IRQs off for {} ns at {}", worst.duration.as_nanos(), worst.location);
+//! }
+//! latency::reset();
+//! ```
+//!
+//! # Budgets
+//!
+//! [`debug_assert_latency!`](crate::debug_assert_latency) times a block
+//! with the generic timer counter and, in debug builds, logs a warning and
+//! fails a debug assertion if it ran over its budget. Release builds run
+//! the block untimed:
+//!
+//! ```ignore
+//! use preemptive_threads::debug_assert_latency;
+//! use preemptive_threads::time::Duration;
+//!
+//! let next = debug_assert_latency!(Duration::from_micros(20), {
+//!     run_queue.pop()
+//! });
+//! ```
+//!
+//! Interrupt handlers get budgets of their own through
+//! [`irq::set_handler_budget`](crate::irq::set_handler_budget).
+
+use super::{Duration, Instant};
+use core::panic::Location;
+use portable_atomic::{AtomicPtr, AtomicU64, Ordering};
+
+/// The longest critical section seen of one kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CriticalSection {
+    /// How long the section lasted.
+    pub duration: Duration,
+    /// Where the section was opened.
+    pub location: &'static Location<'static>,
+}
+
+/// Worst-case critical sections since boot or the last [`reset`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LatencyReport {
+    /// Longest stretch with interrupts disabled.
+    pub irq_disabled: Option<CriticalSection>,
+    /// Longest stretch with preemption disabled.
+    pub preempt_disabled: Option<CriticalSection>,
+}
+
+/// Start time marking "no section open".
+const IDLE: u64 = u64::MAX;
+
+struct Tracker {
+    start: AtomicU64,
+    start_location: AtomicPtr<Location<'static>>,
+    max: AtomicU64,
+    max_location: AtomicPtr<Location<'static>>,
+}
+
+impl Tracker {
+    const fn new() -> Self {
+        Self {
+            start: AtomicU64::new(IDLE),
+            start_location: AtomicPtr::new(core::ptr::null_mut()),
+            max: AtomicU64::new(0),
+            max_location: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    /// Called with the section already entered, so it can't race itself.
+    fn enter(&self, now: u64, location: &'static Location<'static>) {
+        self.start_location
+            .store(location as *const _ as *mut _, Ordering::Relaxed);
+        self.start.store(now, Ordering::Relaxed);
+    }
+
+    fn exit(&self, now: u64) {
+        let start = self.start.swap(IDLE, Ordering::Relaxed);
+        if start == IDLE {
+            return;
+        }
+        let duration = now.saturating_sub(start);
+        if duration > self.max.load(Ordering::Relaxed) {
+            self.max_location.store(
+                self.start_location.load(Ordering::Relaxed),
+                Ordering::Relaxed,
+            );
+            self.max.store(duration, Ordering::Relaxed);
+        }
+    }
+
+    fn worst(&self) -> Option<CriticalSection> {
+        let location = self.max_location.load(Ordering::Relaxed);
+        if location.is_null() {
+            return None;
+        }
+        Some(CriticalSection {
+            duration: Duration::from_nanos(self.max.load(Ordering::Relaxed)),
+            // Only ever set from a &'static Location
+            location: unsafe { &*location },
+        })
+    }
+
+    fn reset(&self) {
+        self.max_location
+            .store(core::ptr::null_mut(), Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
+}
+
+static IRQ_DISABLED: Tracker = Tracker::new();
+static PREEMPT_DISABLED: Tracker = Tracker::new();
+
+/// Interrupts were just disabled at `location`.
+#[cfg(target_arch = "aarch64")]
+#[inline]
+pub(crate) fn irq_disabled(location: &'static Location<'static>) {
+    if cfg!(feature = "latency-trace") {
+        IRQ_DISABLED.enter(Instant::now().as_nanos(), location);
+    }
+}
+
+/// Interrupts are about to be re-enabled.
+#[cfg(target_arch = "aarch64")]
+#[inline]
+pub(crate) fn irq_enabled() {
+    if cfg!(feature = "latency-trace") {
+        IRQ_DISABLED.exit(Instant::now().as_nanos());
+    }
+}
+
+/// Preemption was just disabled at `location`.
+#[inline]
+pub(crate) fn preempt_disabled(location: &'static Location<'static>) {
+    if cfg!(feature = "latency-trace") {
+        PREEMPT_DISABLED.enter(Instant::now().as_nanos(), location);
+    }
+}
+
+/// Preemption is about to be re-enabled.
+#[inline]
+pub(crate) fn preempt_enabled() {
+    if cfg!(feature = "latency-trace") {
+        PREEMPT_DISABLED.exit(Instant::now().as_nanos());
+    }
+}
+
+/// The longest critical sections recorded so far.
+pub fn report() -> LatencyReport {
+    LatencyReport {
+        irq_disabled: IRQ_DISABLED.worst(),
+        preempt_disabled: PREEMPT_DISABLED.worst(),
+    }
+}
+
+/// Forget the recorded maxima, e.g. once boot is over.
+pub fn reset() {
+    IRQ_DISABLED.reset();
+    PREEMPT_DISABLED.reset();
+}
+
+/// Whether a section started at `start` stayed within `budget`; logs a
+/// warning naming `location` if it didn't.
+///
+/// Used by [`debug_assert_latency!`](crate::debug_assert_latency).
+pub fn within_budget(
+    start: Instant,
+    budget: Duration,
+    location: &'static Location<'static>,
+) -> bool {
+    let elapsed = Instant::now().saturating_duration_since(start);
+    if elapsed <= budget {
+        return true;
+    }
+    crate::klog!(
+        crate::kernel::log::Level::Warn,
+        "{} took {} ns, budget {} ns",
+        location,
+        elapsed.as_nanos(),
+        budget.as_nanos()
+    );
+    false
+}
+
+/// Run a block and, in debug builds, assert it finished within a
+/// [`Duration`](crate::time::Duration), see the
+/// [latency module docs](crate::time::latency#budgets).
+///
+/// Evaluates to the block's value.
+#[macro_export]
+macro_rules! debug_assert_latency {
+    ($budget:expr, $body:block) => {{
+        let start = if cfg!(debug_assertions) {
+            $crate::time::Instant::now()
+        } else {
+            $crate::time::Instant::from_nanos(0)
+        };
+        let value = $body;
+        let budget: $crate::time::Duration = $budget;
+        debug_assert!(
+            $crate::time::latency::within_budget(start, budget, ::core::panic::Location::caller()),
+            "section over its {} ns latency budget",
+            budget.as_nanos()
+        );
+        value
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracker_keeps_longest_section() {
+        let tracker = Tracker::new();
+        assert_eq!(tracker.worst(), None);
+
+        // Unmatched exit is ignored
+        tracker.exit(50);
+        assert_eq!(tracker.worst(), None);
+
+        let short = Location::caller();
+        tracker.enter(100, short);
+        tracker.exit(150);
+        let long = Location::caller();
+        tracker.enter(200, long);
+        tracker.exit(500);
+        tracker.enter(600, short);
+        tracker.exit(700);
+
+        let worst = tracker.worst().unwrap();
+        assert_eq!(worst.duration, Duration::from_nanos(300));
+        assert_eq!(worst.location.line(), long.line());
+
+        tracker.reset();
+        assert_eq!(tracker.worst(), None);
+    }
+
+    #[test]
+    fn test_budget_checks_elapsed_time() {
+        let now = Instant::now();
+        assert_eq!(
+            crate::debug_assert_latency!(Duration::from_millis(1), { 2 + 2 }),
+            4
+        );
+        assert!(within_budget(
+            now,
+            Duration::from_millis(1_000),
+            Location::caller()
+        ));
+        // Started in the future as far as the check can tell: nothing elapsed
+        assert!(within_budget(
+            Instant::from_nanos(u64::MAX),
+            Duration::from_nanos(0),
+            Location::caller()
+        ));
+    }
+}
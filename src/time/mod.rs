@@ -1,86 +1,204 @@
-//! Time management and time slice accounting.
- 
+//! Time management: instants and durations, per-thread CPU accounting
+//! and quanta.
+
+pub mod calibration;
+pub mod hrtimer;
+pub mod latency;
+pub mod switch_latency;
+
 use portable_atomic::{AtomicU32, AtomicU64, Ordering};
 
-pub struct TimeSlice {
+/// How a scheduling policy turns priorities into quanta and vruntime
+/// weights.
+///
+/// A scheduler hands its curves to the kernel through
+/// [`Scheduler::slice_curves`](crate::sched::Scheduler::slice_curves) and
+/// each thread it runs applies them, at spawn and whenever its priority
+/// changes. The curves are plain functions, so a closure that captures
+/// nothing works as well as a table lookup:
+///
+/// ```ignore
+/// static FLAT: SliceCurves = SliceCurves {
+///     quantum: |_| Duration::from_millis(2),
+///     weight: |_| 1000,
+/// };
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct SliceCurves {
+    /// Quantum length for a priority.
+    pub quantum: fn(u8) -> Duration,
+    /// vruntime weight for a priority, in thousandths: a run of `t` adds
+    /// `t * 1000 / weight` to the thread's vruntime, so heavier threads
+    /// age more slowly. Zero is treated as one.
+    pub weight: fn(u8) -> u32,
+}
+
+impl SliceCurves {
+    /// The kernel's stock curves: four priority bands, each with twice the
+    /// quantum of the one below, weighted 0.5, 1, 1.5 and 2.
+    pub const DEFAULT: SliceCurves = SliceCurves {
+        quantum: default_quantum,
+        weight: default_weight,
+    };
+}
+
+impl Default for SliceCurves {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+fn default_quantum(priority: u8) -> Duration {
+    let base_quantum = DEFAULT_QUANTUM_NS;
+    Duration::from_nanos(match priority {
+        0..=63 => base_quantum / 2,
+        64..=127 => base_quantum,
+        128..=191 => base_quantum * 2,
+        192..=255 => base_quantum * 4,
+    })
+}
+
+fn default_weight(priority: u8) -> u32 {
+    match priority {
+        0..=63 => 500,
+        64..=127 => 1000,
+        128..=191 => 1500,
+        192..=255 => 2000,
+    }
+}
+
+/// CPU time a thread has consumed.
+///
+/// Charged whenever the thread stops running, from when it was last
+/// dispatched or charged, so it is exact across preemption, blocking and
+/// exit without a tick ever touching it. The charge also advances the
+/// thread's virtual runtime, weighted by priority, for fair scheduling.
+pub struct CpuAccounting {
     vruntime: AtomicU64,
-    slice_start: AtomicU64,
-    quantum: AtomicU64,
-    priority: AtomicU32,
+    consumed: AtomicU64,
+    /// Start of the uncharged run, 0 while the thread isn't running
+    running_since: AtomicU64,
+    /// vruntime weight, see [`SliceCurves::weight`]
+    weight: AtomicU32,
 }
 
-impl TimeSlice {
+impl CpuAccounting {
     pub fn new(priority: u8) -> Self {
-        let quantum = Self::calculate_quantum(priority);
         Self {
             vruntime: AtomicU64::new(0),
-            slice_start: AtomicU64::new(0),
-            quantum: AtomicU64::new(quantum),
-            priority: AtomicU32::new(priority as u32),
+            consumed: AtomicU64::new(0),
+            running_since: AtomicU64::new(0),
+            weight: AtomicU32::new(default_weight(priority)),
         }
     }
 
-    pub fn start_slice(&self, current_time: Instant) {
-        self.slice_start.store(current_time.as_nanos(), Ordering::Release);
+    /// The thread was dispatched at `now`.
+    pub fn start(&self, now: Instant) {
+        self.running_since.store(now.as_nanos(), Ordering::Release);
     }
 
-    pub fn update_vruntime(&self, current_time: Instant) -> bool {
-        let slice_start = self.slice_start.load(Ordering::Acquire);
-        let quantum = self.quantum.load(Ordering::Acquire);
-        let priority = self.priority.load(Ordering::Acquire);
-
-        if slice_start == 0 {
-            return false;
+    /// Charge the run up to `now` and stop the clock.
+    ///
+    /// # Returns
+    ///
+    /// The time charged, zero if the thread wasn't running.
+    pub fn charge(&self, now: Instant) -> Duration {
+        let since = self.running_since.swap(0, Ordering::AcqRel);
+        if since == 0 {
+            return Duration::from_nanos(0);
         }
+        let elapsed = now.as_nanos().saturating_sub(since);
+        let weight = self.weight.load(Ordering::Acquire).max(1) as u64;
+        self.consumed.fetch_add(elapsed, Ordering::AcqRel);
+        self.vruntime
+            .fetch_add(elapsed * 1000 / weight, Ordering::AcqRel);
+        Duration::from_nanos(elapsed)
+    }
 
-        let elapsed = current_time.as_nanos().saturating_sub(slice_start);
-        let priority_factor = Self::calculate_priority_factor(priority as u8);
-        let virtual_elapsed = (elapsed * 1000) / priority_factor as u64;
-
-        self.vruntime.fetch_add(virtual_elapsed, Ordering::AcqRel);
-        elapsed >= quantum
+    /// CPU time charged so far; the run in progress isn't included.
+    pub fn consumed(&self) -> Duration {
+        Duration::from_nanos(self.consumed.load(Ordering::Acquire))
     }
 
     pub fn vruntime(&self) -> u64 {
         self.vruntime.load(Ordering::Acquire)
     }
 
-    pub fn set_priority(&self, new_priority: u8) {
-        self.priority.store(new_priority as u32, Ordering::Release);
-        let new_quantum = Self::calculate_quantum(new_priority);
-        self.quantum.store(new_quantum, Ordering::Release);
+    /// Weight later charges with `weight`, see [`SliceCurves::weight`].
+    pub fn set_weight(&self, weight: u32) {
+        self.weight.store(weight, Ordering::Release);
     }
 
-    pub fn set_custom_duration(&self, duration: Duration) {
-        self.quantum.store(duration.as_nanos(), Ordering::Release);
+    pub fn weight(&self) -> u32 {
+        self.weight.load(Ordering::Acquire)
     }
+}
 
-    pub fn priority(&self) -> u8 {
-        self.priority.load(Ordering::Acquire) as u8
-    }
+/// A thread's quantum: how long it may run before the scheduler's policy
+/// preempts it, and how much of it the current slice has used.
+///
+/// The length comes from the scheduler's [`SliceCurves`]; schedulers that
+/// size slices dynamically cap it with [`expired_within`](Self::expired_within).
+/// Nothing here feeds back into [`CpuAccounting`].
+pub struct Quantum {
+    slice_start: AtomicU64,
+    length: AtomicU64,
+}
 
-    fn calculate_quantum(priority: u8) -> u64 {
-        let base_quantum = DEFAULT_QUANTUM_NS;
-        match priority {
-            0..=63 => base_quantum / 2,
-            64..=127 => base_quantum,
-            128..=191 => base_quantum * 2,
-            192..=255 => base_quantum * 4,
+impl Quantum {
+    pub fn new(priority: u8) -> Self {
+        Self {
+            slice_start: AtomicU64::new(0),
+            length: AtomicU64::new(default_quantum(priority).as_nanos()),
         }
     }
 
-    fn calculate_priority_factor(priority: u8) -> u32 {
-        match priority {
-            0..=63 => 500,
-            64..=127 => 1000,
-            128..=191 => 1500,
-            192..=255 => 2000,
+    pub fn start_slice(&self, current_time: Instant) {
+        self.slice_start
+            .store(current_time.as_nanos(), Ordering::Release);
+    }
+
+    /// When the current slice started.
+    pub fn slice_start(&self) -> Instant {
+        Instant::from_nanos(self.slice_start.load(Ordering::Acquire))
+    }
+
+    /// Length of a full slice.
+    pub fn length(&self) -> Duration {
+        Duration::from_nanos(self.length.load(Ordering::Acquire))
+    }
+
+    /// Use `length` for slices from now on.
+    pub fn set_length(&self, length: Duration) {
+        self.length.store(length.as_nanos(), Ordering::Release);
+    }
+
+    /// Time the current slice has run by `now`, zero before the first.
+    pub fn used(&self, now: Instant) -> Duration {
+        match self.slice_start.load(Ordering::Acquire) {
+            0 => Duration::from_nanos(0),
+            start => Duration::from_nanos(now.as_nanos().saturating_sub(start)),
         }
     }
- 
-    pub fn should_preempt(&self) -> bool {
-        let current_time = Instant::now();
-        self.update_vruntime(current_time)
+
+    /// Time left of the current slice at `now`.
+    pub fn remaining(&self, now: Instant) -> Duration {
+        Duration::from_nanos(
+            self.length()
+                .as_nanos()
+                .saturating_sub(self.used(now).as_nanos()),
+        )
+    }
+
+    /// Whether the slice has run out at `now`, ending it after at most
+    /// `limit` even if the quantum is longer.
+    pub fn expired_within(&self, now: Instant, limit: Duration) -> bool {
+        self.slice_start.load(Ordering::Acquire) != 0 && self.used(now) >= self.length().min(limit)
+    }
+
+    /// Whether the slice has run out by now.
+    pub fn expired(&self) -> bool {
+        self.expired_within(Instant::now(), Duration::from_nanos(u64::MAX))
     }
 }
 
@@ -101,39 +219,37 @@ impl Instant {
     pub fn from_nanos(nanos: u64) -> Self {
         Self(nanos)
     }
-    
+
     /// Get nanoseconds since epoch.
     pub fn as_nanos(self) -> u64 {
         self.0
     }
-    
+
     /// Get nanoseconds since epoch as u128 for calculations.
     pub fn as_nanos_u128(self) -> u128 {
         self.0 as u128
     }
-    
+
     /// Get the current instant.
     ///
     /// This reads the current time from the ARM Generic Timer and converts
     /// to nanoseconds for consistent time calculations.
+    ///
+    /// Never earlier than an instant previously returned on the same CPU,
+    /// even across the frequency change at [calibration](calibration).
     pub fn now() -> Self {
         #[cfg(target_arch = "aarch64")]
         {
-            // Read ARM Generic Timer counter and frequency
+            // Read ARM Generic Timer counter; the frequency is validated at boot
             let cnt: u64;
-            let freq: u64;
             unsafe {
                 core::arch::asm!(
                     "mrs {}, cntpct_el0",
                     out(reg) cnt,
                     options(nostack, nomem, preserves_flags)
                 );
-                core::arch::asm!(
-                    "mrs {}, cntfrq_el0",
-                    out(reg) freq,
-                    options(nostack, nomem, preserves_flags)
-                );
             }
+            let freq = calibration::counter_frequency();
             // Convert ticks to nanoseconds: ns = ticks * 1_000_000_000 / freq
             // Use u128 to avoid overflow
             let nanos = if freq > 0 {
@@ -141,7 +257,7 @@ impl Instant {
             } else {
                 0
             };
-            Self(nanos)
+            Self(monotonic(LAST_NOW.get(), nanos))
         }
 
         #[cfg(not(target_arch = "aarch64"))]
@@ -150,7 +266,7 @@ impl Instant {
             Self(0)
         }
     }
-    
+
     /// Calculate duration since another instant.
     ///
     /// # Panics
@@ -159,7 +275,96 @@ impl Instant {
     pub fn duration_since(self, earlier: Instant) -> Duration {
         Duration::from_nanos(self.0 - earlier.0)
     }
-    
+
+    /// Duration since `earlier`, or `None` if `earlier` is after `self`,
+    /// e.g. an instant taken on another CPU.
+    pub fn checked_duration_since(self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    /// Duration since `earlier`, zero if `earlier` is after `self`.
+    pub fn saturating_duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+}
+
+crate::percpu! {
+    /// Latest instant returned by `Instant::now` on each CPU
+    #[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+    static LAST_NOW: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Return `nanos`, or `last` if that is later, and remember the result.
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+fn monotonic(last: &AtomicU64, nanos: u64) -> u64 {
+    last.fetch_max(nanos, Ordering::Relaxed).max(nanos)
+}
+
+/// Extends a free-running hardware counter narrower than 64 bits, such as
+/// the BCM2837 system timer's 32-bit `CLO`, to 64 bits that don't wrap.
+///
+/// Each [`extend`](Self::extend) adds the ticks since the previous one,
+/// modulo the counter width, so a wrap between two calls is counted
+/// correctly as long as the counter is read at least once per wrap period
+/// (71 minutes for 32 bits at 1 MHz). Calls may race from several CPUs;
+/// the result never goes backwards.
+pub struct WrappingCounter {
+    mask: u64,
+    /// Extended count, whose low bits are the last raw value seen, or
+    /// `UNSET` before the first reading
+    extended: AtomicU64,
+}
+
+const UNSET: u64 = u64::MAX;
+
+impl WrappingCounter {
+    /// A counter `bits` wide (1 to 64).
+    pub const fn new(bits: u32) -> Self {
+        assert!(bits >= 1 && bits <= 64);
+        Self {
+            mask: u64::MAX >> (64 - bits),
+            extended: AtomicU64::new(UNSET),
+        }
+    }
+
+    /// The 64-bit count for the hardware reading `raw`.
+    ///
+    /// The count starts at the first reading, so it matches the hardware
+    /// until the first wrap.
+    pub fn extend(&self, raw: u64) -> u64 {
+        let raw = raw & self.mask;
+        let mut current = self.extended.load(Ordering::Acquire);
+        loop {
+            if current == UNSET {
+                match self.extended.compare_exchange(
+                    UNSET,
+                    raw,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => return raw,
+                    Err(seen) => current = seen,
+                }
+                continue;
+            }
+            let delta = raw.wrapping_sub(current) & self.mask;
+            // A reading older than the last one (lost race) reads as a
+            // near-complete wrap; keep what we have instead
+            if delta > self.mask / 2 {
+                return current;
+            }
+            let next = current.wrapping_add(delta);
+            match self.extended.compare_exchange_weak(
+                current,
+                next,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return next,
+                Err(seen) => current = seen,
+            }
+        }
+    }
 }
 
 impl core::ops::Add<Duration> for Instant {
@@ -171,40 +376,40 @@ impl core::ops::Add<Duration> for Instant {
 }
 
 /// A duration of time.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
 pub struct Duration(u64);
 
 impl Duration {
     /// Create a duration from nanoseconds.
-    pub fn from_nanos(nanos: u64) -> Self {
+    pub const fn from_nanos(nanos: u64) -> Self {
         Self(nanos)
     }
-    
+
     /// Create a duration from microseconds.
-    pub fn from_micros(micros: u64) -> Self {
+    pub const fn from_micros(micros: u64) -> Self {
         Self(micros * 1_000)
     }
-    
+
     /// Create a duration from milliseconds.
-    pub fn from_millis(millis: u64) -> Self {
+    pub const fn from_millis(millis: u64) -> Self {
         Self(millis * 1_000_000)
     }
-    
+
     /// Get nanoseconds in this duration.
-    pub fn as_nanos(self) -> u64 {
+    pub const fn as_nanos(self) -> u64 {
         self.0
     }
-    
+
     /// Get nanoseconds as u128 for calculations.
     pub fn as_nanos_u128(self) -> u128 {
         self.0 as u128
     }
-    
+
     /// Get microseconds in this duration.
     pub fn as_micros(self) -> u64 {
         self.0 / 1_000
     }
-    
+
     /// Get milliseconds in this duration.
     pub fn as_millis(self) -> u64 {
         self.0 / 1_000_000
@@ -215,4 +420,65 @@ impl Duration {
 pub const TIMER_FREQUENCY_HZ: u32 = 1000; // 1 kHz = 1ms time slices
 
 /// Default quantum duration in nanoseconds (1ms).
-pub const DEFAULT_QUANTUM_NS: u64 = 1_000_000;
\ No newline at end of file
+pub const DEFAULT_QUANTUM_NS: u64 = 1_000_000;
+
+/// Default period in which every runnable thread should get to run (6ms).
+pub const DEFAULT_TARGET_LATENCY_NS: u64 = 6_000_000;
+
+/// Default shortest slice the adaptive quantum shrinks to (0.75ms).
+pub const DEFAULT_MIN_GRANULARITY_NS: u64 = 750_000;
+
+/// Default wait after which a queued thread is aged up a priority level (50ms).
+pub const DEFAULT_AGING_INTERVAL_NS: u64 = 50_000_000;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrapping_counter_and_monotonic_clamp() {
+        let counter = WrappingCounter::new(32);
+        assert_eq!(counter.extend(0xFFFF_FFF0), 0xFFFF_FFF0);
+        assert_eq!(counter.extend(0x10), 0x1_0000_0010);
+        // A stale reading from a racing CPU doesn't go back
+        assert_eq!(counter.extend(0xFFFF_FFF8), 0x1_0000_0010);
+
+        let last = AtomicU64::new(0);
+        assert_eq!(monotonic(&last, 500), 500);
+        assert_eq!(monotonic(&last, 400), 500);
+
+        let (early, late) = (Instant::from_nanos(10), Instant::from_nanos(25));
+        assert_eq!(
+            late.checked_duration_since(early),
+            Some(Duration::from_nanos(15))
+        );
+        assert_eq!(early.checked_duration_since(late), None);
+        assert_eq!(
+            early.saturating_duration_since(late),
+            Duration::from_nanos(0)
+        );
+    }
+
+    #[test]
+    fn test_accounting_charged_apart_from_quantum() {
+        let at = |us: u64| Instant::from_nanos(us * 1000);
+        let quantum = Quantum::new(100);
+        let accounting = CpuAccounting::new(100);
+        assert_eq!(quantum.remaining(at(5)), Duration::from_millis(1));
+
+        quantum.start_slice(at(10));
+        accounting.start(at(10));
+        assert_eq!(quantum.remaining(at(310)), Duration::from_micros(700));
+        assert!(!quantum.expired_within(at(310), Duration::from_millis(1)));
+        assert!(quantum.expired_within(at(310), Duration::from_micros(200)));
+        // Checking the quantum charges nothing
+        assert_eq!(accounting.consumed(), Duration::from_nanos(0));
+
+        assert_eq!(accounting.charge(at(410)), Duration::from_micros(400));
+        assert_eq!(accounting.charge(at(900)), Duration::from_nanos(0));
+        assert_eq!(
+            (accounting.consumed(), accounting.vruntime()),
+            (Duration::from_micros(400), 400_000)
+        );
+        assert_eq!(quantum.remaining(at(2000)), Duration::from_nanos(0));
+    }
+}
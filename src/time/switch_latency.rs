@@ -0,0 +1,253 @@
+//! Timer-to-thread latency of the two context switch paths.
+//!
+//! A tick that preempts the running thread hands the CPU over in one of
+//! two ways:
+//!
+//! - [`SwitchPath::Irq`]: in [`PreemptionMode::Full`](crate::PreemptionMode::Full)
+//!   the tick picks the next thread itself and the IRQ vector's return
+//!   sequence loads its context from `IRQ_LOAD_CTX`.
+//! - [`SwitchPath::Synchronous`]: otherwise the tick only requests
+//!   preemption, and the thread gives up the CPU at its next preemption
+//!   point through `Arch::context_switch`.
+//!
+//! With the `switch-bench` feature, each switch that answers a tick is
+//! timed from the moment the timer fired (its compare value) to the last
+//! instruction before the next thread runs, the `eret` of the IRQ vector or
+//! the branch at the end of `context_switch`; both stamp `CNTPCT_EL0` for
+//! this. [`report`] returns a histogram per path:
+//!
+//! ```ignore
+//! use preemptive_threads::time::switch_latency::{self, LatencyHistogram, SwitchPath};
+//!
+//! let irq = switch_latency::report(SwitchPath::Irq);
+//! pl011_println!("IRQ path: {} switches, mean {} ns, max {} ns",
+//!     irq.samples, irq.mean().as_nanos(), irq.max.as_nanos());
+//! for (bucket, count) in irq.buckets.iter().enumerate() {
+//!     pl011_println!("  < {} ns: {}", LatencyHistogram::bucket_limit(bucket), count);
+//! }
+//! ```
+//!
+//! The synchronous path includes however long the thread took to reach a
+//! preemption point, which is usually most of it. Without the feature the
+//! switch code still checks its stamp slot, a load and a branch, but
+//! nothing is recorded.
+
+use super::Duration;
+use portable_atomic::{AtomicU64, Ordering};
+
+/// How a preempting tick switched threads, see the [module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum SwitchPath {
+    Irq = 0,
+    Synchronous = 1,
+}
+
+/// Number of histogram buckets; the last one takes everything from
+/// about 8 ms up.
+pub const BUCKETS: usize = 24;
+
+/// Latencies of one path since boot or the last [`reset`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// `buckets[i]` counts latencies below
+    /// [`bucket_limit(i)`](Self::bucket_limit) and not in an earlier bucket.
+    pub buckets: [u64; BUCKETS],
+    /// Switches measured.
+    pub samples: u64,
+    /// Shortest latency; zero without samples.
+    pub min: Duration,
+    /// Longest latency.
+    pub max: Duration,
+    /// All latencies added up.
+    pub total: Duration,
+}
+
+impl LatencyHistogram {
+    /// Exclusive upper bound of bucket `index` in nanoseconds: 1 µs for
+    /// the first, doubling from there.
+    pub const fn bucket_limit(index: usize) -> u64 {
+        if index + 1 >= BUCKETS {
+            u64::MAX
+        } else {
+            1000 << index
+        }
+    }
+
+    /// Average latency.
+    pub fn mean(&self) -> Duration {
+        match self.samples {
+            0 => Duration::from_nanos(0),
+            n => Duration::from_nanos(self.total.as_nanos() / n),
+        }
+    }
+}
+
+/// Stamp value meaning "don't record"; the switch code only stamps an
+/// armed (zero) slot, so the first switch after arming wins
+const DISARMED: u64 = u64::MAX;
+
+/// `CNTPCT_EL0` just before the `eret` of an IRQ that switched threads,
+/// written by the IRQ vector
+pub(crate) static SWITCH_BENCH_IRQ_EXIT: AtomicU64 = AtomicU64::new(DISARMED);
+
+/// `CNTPCT_EL0` just before `context_switch` jumps to the next thread
+pub(crate) static SWITCH_BENCH_SYNC_EXIT: AtomicU64 = AtomicU64::new(DISARMED);
+
+/// Counter value at the last tick not yet answered by a switch, or zero
+static FIRED: AtomicU64 = AtomicU64::new(0);
+
+/// Fire time of the switch in flight on each path, or zero
+static IN_FLIGHT: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    samples: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+    total: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: {
+                #[allow(clippy::declare_interior_mutable_const)]
+                const ZERO: AtomicU64 = AtomicU64::new(0);
+                [ZERO; BUCKETS]
+            },
+            samples: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, nanos: u64) {
+        let bucket = (0..BUCKETS)
+            .find(|&i| nanos < LatencyHistogram::bucket_limit(i))
+            .unwrap_or(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.samples.fetch_add(1, Ordering::Relaxed);
+        self.min.fetch_min(nanos, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+        self.total.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        let mut buckets = [0; BUCKETS];
+        for (count, bucket) in buckets.iter_mut().zip(self.buckets.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        let samples = self.samples.load(Ordering::Relaxed);
+        LatencyHistogram {
+            buckets,
+            samples,
+            min: Duration::from_nanos(if samples == 0 {
+                0
+            } else {
+                self.min.load(Ordering::Relaxed)
+            }),
+            max: Duration::from_nanos(self.max.load(Ordering::Relaxed)),
+            total: Duration::from_nanos(self.total.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.samples.store(0, Ordering::Relaxed);
+        self.min.store(u64::MAX, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+        self.total.store(0, Ordering::Relaxed);
+    }
+}
+
+static HISTOGRAMS: [Histogram; 2] = [Histogram::new(), Histogram::new()];
+
+fn exit_stamp(path: SwitchPath) -> &'static AtomicU64 {
+    match path {
+        SwitchPath::Irq => &SWITCH_BENCH_IRQ_EXIT,
+        SwitchPath::Synchronous => &SWITCH_BENCH_SYNC_EXIT,
+    }
+}
+
+/// Record the switch in flight on `path` if its exit has been stamped.
+fn complete(path: SwitchPath) {
+    let fired = IN_FLIGHT[path as usize].load(Ordering::Relaxed);
+    let exit = exit_stamp(path).load(Ordering::Relaxed);
+    if fired == 0 || exit == 0 || exit == DISARMED {
+        return;
+    }
+    IN_FLIGHT[path as usize].store(0, Ordering::Relaxed);
+    exit_stamp(path).store(DISARMED, Ordering::Relaxed);
+    HISTOGRAMS[path as usize].record(ticks_to_nanos(exit.saturating_sub(fired)));
+}
+
+fn ticks_to_nanos(ticks: u64) -> u64 {
+    match super::calibration::counter_frequency() {
+        0 => 0,
+        freq => (ticks as u128 * 1_000_000_000 / freq as u128) as u64,
+    }
+}
+
+/// The scheduler timer fired at counter value `fired`.
+#[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+#[inline]
+pub(crate) fn timer_fired(fired: u64) {
+    if cfg!(feature = "switch-bench") {
+        complete(SwitchPath::Irq);
+        complete(SwitchPath::Synchronous);
+        FIRED.store(fired, Ordering::Relaxed);
+    }
+}
+
+/// The kernel is about to switch threads on `path`; if a tick is waiting
+/// for a switch, time this one.
+#[inline]
+pub(crate) fn switching(path: SwitchPath) {
+    if cfg!(feature = "switch-bench") {
+        complete(SwitchPath::Irq);
+        complete(SwitchPath::Synchronous);
+        let fired = FIRED.swap(0, Ordering::Relaxed);
+        if fired != 0 {
+            IN_FLIGHT[path as usize].store(fired, Ordering::Relaxed);
+            // Armed: the switch code stamps it
+            exit_stamp(path).store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Latencies recorded for `path`.
+pub fn report(path: SwitchPath) -> LatencyHistogram {
+    complete(path);
+    HISTOGRAMS[path as usize].snapshot()
+}
+
+/// Forget everything recorded.
+pub fn reset() {
+    for histogram in &HISTOGRAMS {
+        histogram.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_and_summary() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.snapshot().min, Duration::from_nanos(0));
+        for nanos in [500, 1500, 1999, 3000, u64::MAX / 2] {
+            histogram.record(nanos);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(&snapshot.buckets[..3], &[1, 2, 1]);
+        assert_eq!(snapshot.buckets[BUCKETS - 1], 1);
+        assert_eq!((snapshot.samples, snapshot.min.as_nanos()), (5, 500));
+        assert_eq!(snapshot.max.as_nanos(), u64::MAX / 2);
+        assert_eq!(LatencyHistogram::bucket_limit(2), 4000);
+    }
+}